@@ -213,7 +213,10 @@ mod tests {
         let flow = block_on(plugin.on_request(&mut RequestCtx {
             state: &mut state,
             path: "/",
+            query: None,
+            scheme: "http",
             host: Some("example.com"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut headers,
@@ -237,7 +240,10 @@ mod tests {
         let flow = block_on(plugin.on_request(&mut RequestCtx {
             state: &mut state,
             path: "/",
+            query: None,
+            scheme: "http",
             host: Some("example.com"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut headers,
@@ -255,7 +255,10 @@ mod tests {
         let flow = block_on(plugin.on_request(&mut RequestCtx {
             state: &mut state,
             path: "/",
+            query: None,
+            scheme: "http",
             host: Some("api.com"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut headers,
@@ -268,14 +271,14 @@ mod tests {
             let origin = res
                 .headers
                 .iter()
-                .find(|(k, _)| k == &header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .find(|(k, _)| *k == header::ACCESS_CONTROL_ALLOW_ORIGIN)
                 .unwrap();
             assert_eq!(origin.1.to_str().unwrap(), "*");
 
             let max_age = res
                 .headers
                 .iter()
-                .find(|(k, _)| k == &header::ACCESS_CONTROL_MAX_AGE)
+                .find(|(k, _)| *k == header::ACCESS_CONTROL_MAX_AGE)
                 .unwrap();
             assert_eq!(max_age.1.to_str().unwrap(), "3600");
         } else {
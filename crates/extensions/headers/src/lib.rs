@@ -1,16 +1,22 @@
 use http::{HeaderName, HeaderValue};
 use ipnet::IpNet;
+use ngxora_compile::variables::{Template, VariableSource};
 use ngxora_plugin_api::{
     HeaderMapMut, HttpPlugin, PluginBuildError, PluginError, PluginFactory, PluginFlow, PluginSpec,
-    RequestCtx, ResponseCtx, UpstreamRequestCtx, async_trait,
+    PluginState, RequestCtx, ResponseCtx, UpstreamRequestCtx, async_trait,
 };
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
 
+// Distinguishes headers plugins sharing one request's PluginState.
+static NEXT_PLUGIN_ID: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HeadersPluginConfig {
     #[serde(default)]
@@ -38,13 +44,24 @@ pub struct HeaderPatchConfig {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HeaderEntry {
     pub name: String,
+    /// Header value; may reference request variables such as `$host`.
     pub value: String,
 }
 
+#[derive(Debug, Clone)]
+enum HeaderValueSource {
+    Static(HeaderValue),
+    /// Rendered per request; `slot` indexes the patch's rendered values.
+    Dynamic {
+        template: Template,
+        slot: usize,
+    },
+}
+
 #[derive(Debug, Clone)]
 struct HeaderValueOp {
     name: HeaderName,
-    value: HeaderValue,
+    value: HeaderValueSource,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -56,15 +73,16 @@ struct HeaderPatch {
 
 impl HeaderPatch {
     fn compile(plugin: &str, raw: HeaderPatchConfig) -> Result<Self, PluginBuildError> {
-        let add = raw
-            .add
-            .into_iter()
-            .map(|entry| compile_entry(plugin, entry))
-            .collect::<Result<Vec<_>, _>>()?;
+        let mut slots = 0;
         let set = raw
             .set
             .into_iter()
-            .map(|entry| compile_entry(plugin, entry))
+            .map(|entry| compile_entry(plugin, entry, &mut slots))
+            .collect::<Result<Vec<_>, _>>()?;
+        let add = raw
+            .add
+            .into_iter()
+            .map(|entry| compile_entry(plugin, entry, &mut slots))
             .collect::<Result<Vec<_>, _>>()?;
         let remove = raw
             .remove
@@ -79,42 +97,160 @@ impl HeaderPatch {
         Ok(Self { add, set, remove })
     }
 
+    fn is_dynamic(&self) -> bool {
+        self.set
+            .iter()
+            .chain(&self.add)
+            .any(|op| matches!(op.value, HeaderValueSource::Dynamic { .. }))
+    }
+
+    // Values are rendered in slot order: `set` entries first, then `add`.
+    fn render(
+        &self,
+        plugin: &'static str,
+        vars: &dyn VariableSource,
+    ) -> Result<Vec<HeaderValue>, PluginError> {
+        self.set
+            .iter()
+            .chain(&self.add)
+            .filter_map(|op| match &op.value {
+                HeaderValueSource::Dynamic { template, .. } => Some((&op.name, template)),
+                HeaderValueSource::Static(_) => None,
+            })
+            .map(|(name, template)| {
+                HeaderValue::from_str(&template.render(vars)).map_err(|err| {
+                    PluginError::new(
+                        plugin,
+                        format!("invalid rendered value for header `{name}`: {err}"),
+                    )
+                })
+            })
+            .collect()
+    }
+
     fn apply(
         &self,
-        _plugin: &'static str,
+        plugin: &'static str,
         headers: &mut dyn HeaderMapMut,
+        rendered: &[HeaderValue],
     ) -> Result<(), PluginError> {
+        let value = |op: &HeaderValueOp| match &op.value {
+            HeaderValueSource::Static(value) => Ok(value.clone()),
+            HeaderValueSource::Dynamic { slot, .. } => {
+                rendered.get(*slot).cloned().ok_or_else(|| {
+                    PluginError::new(
+                        plugin,
+                        format!("header `{}` was not rendered for this request", op.name),
+                    )
+                })
+            }
+        };
+
         for op in &self.remove {
             headers.remove(op);
         }
 
         for op in &self.set {
-            headers.set(&op.name, op.value.clone())?;
+            headers.set(&op.name, value(op)?)?;
         }
 
         for op in &self.add {
-            headers.add(&op.name, op.value.clone())?;
+            headers.add(&op.name, value(op)?)?;
         }
 
         Ok(())
     }
 }
 
-fn compile_entry(plugin: &str, entry: HeaderEntry) -> Result<HeaderValueOp, PluginBuildError> {
+fn compile_entry(
+    plugin: &str,
+    entry: HeaderEntry,
+    slots: &mut usize,
+) -> Result<HeaderValueOp, PluginBuildError> {
     let name = entry.name.parse::<HeaderName>().map_err(|err| {
         PluginBuildError::new(
             plugin,
             format!("invalid header name `{}`: {err}", entry.name),
         )
     })?;
-    let value = entry.value.parse::<HeaderValue>().map_err(|err| {
+    let template = Template::parse(&entry.value).map_err(|err| {
+        PluginBuildError::new(
+            plugin,
+            format!("invalid header value for `{}`: {err}", entry.name),
+        )
+    })?;
+    let Some(literal) = template.literal() else {
+        let slot = *slots;
+        *slots += 1;
+        return Ok(HeaderValueOp {
+            name,
+            value: HeaderValueSource::Dynamic { template, slot },
+        });
+    };
+
+    let value = literal.parse::<HeaderValue>().map_err(|err| {
         PluginBuildError::new(
             plugin,
             format!("invalid header value for `{}`: {err}", entry.name),
         )
     })?;
 
-    Ok(HeaderValueOp { name, value })
+    Ok(HeaderValueOp {
+        name,
+        value: HeaderValueSource::Static(value),
+    })
+}
+
+// Request variables are only visible in on_request, so dynamic values for the
+// later hooks are rendered there and parked in the per-request plugin state.
+#[derive(Debug, Default, Clone)]
+struct RenderedHeaders(HashMap<u64, RenderedPatches>);
+
+#[derive(Debug, Default, Clone)]
+struct RenderedPatches {
+    upstream_request: Vec<HeaderValue>,
+    response: Vec<HeaderValue>,
+}
+
+struct RequestVariables<'a> {
+    path: &'a str,
+    query: Option<&'a str>,
+    scheme: &'a str,
+    host: Option<&'a str>,
+    server_name: Option<&'a str>,
+    client_ip: Option<IpAddr>,
+    headers: &'a dyn HeaderMapMut,
+}
+
+impl VariableSource for RequestVariables<'_> {
+    fn host(&self) -> Option<&str> {
+        self.host
+    }
+
+    fn path(&self) -> &str {
+        self.path
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.query
+    }
+
+    fn scheme(&self) -> &str {
+        self.scheme
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.server_name
+    }
+
+    fn remote_addr(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+        self.headers.get(&name)?.to_str().ok()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -219,12 +355,22 @@ fn compile_trusted_proxy(plugin: &str, raw: String) -> Result<IpNet, PluginBuild
 
 #[derive(Debug, Clone)]
 pub struct HeadersPlugin {
+    id: u64,
     client_ip_forwarding: Option<ClientIpForwarding>,
     request: HeaderPatch,
     upstream_request: HeaderPatch,
     response: HeaderPatch,
 }
 
+impl HeadersPlugin {
+    fn rendered<'a>(&self, state: &'a PluginState) -> Option<&'a RenderedPatches> {
+        state
+            .extensions
+            .get::<RenderedHeaders>()
+            .and_then(|rendered| rendered.0.get(&self.id))
+    }
+}
+
 #[async_trait]
 impl HttpPlugin for HeadersPlugin {
     fn name(&self) -> &'static str {
@@ -236,7 +382,32 @@ impl HttpPlugin for HeadersPlugin {
             .client_ip_forwarding
             .as_ref()
             .and_then(|forwarding| forwarding.resolve_chain(ctx.client_ip, ctx.headers));
-        self.request.apply(self.name(), ctx.headers)?;
+
+        // Variables see the request as received, before any patch applies.
+        let vars = RequestVariables {
+            path: ctx.path,
+            query: ctx.query,
+            scheme: ctx.scheme,
+            host: ctx.host,
+            server_name: ctx.server_name,
+            client_ip: ctx.client_ip,
+            headers: &*ctx.headers,
+        };
+        let request_values = self.request.render(self.name(), &vars)?;
+        if self.upstream_request.is_dynamic() || self.response.is_dynamic() {
+            let rendered = RenderedPatches {
+                upstream_request: self.upstream_request.render(self.name(), &vars)?,
+                response: self.response.render(self.name(), &vars)?,
+            };
+            ctx.state
+                .extensions
+                .get_or_insert_default::<RenderedHeaders>()
+                .0
+                .insert(self.id, rendered);
+        }
+
+        self.request
+            .apply(self.name(), ctx.headers, &request_values)?;
         if let Some(forwarding) = &self.client_ip_forwarding {
             forwarding.apply(self.name(), client_ip_chain, ctx.headers)?;
         }
@@ -247,12 +418,21 @@ impl HttpPlugin for HeadersPlugin {
         &self,
         ctx: &mut UpstreamRequestCtx<'_>,
     ) -> Result<PluginFlow, PluginError> {
-        self.upstream_request.apply(self.name(), ctx.headers)?;
+        let rendered = self
+            .rendered(ctx.state)
+            .map(|rendered| rendered.upstream_request.as_slice())
+            .unwrap_or_default();
+        self.upstream_request
+            .apply(self.name(), ctx.headers, rendered)?;
         Ok(PluginFlow::Continue)
     }
 
     async fn on_response(&self, ctx: &mut ResponseCtx<'_>) -> Result<PluginFlow, PluginError> {
-        self.response.apply(self.name(), ctx.headers)?;
+        let rendered = self
+            .rendered(ctx.state)
+            .map(|rendered| rendered.response.as_slice())
+            .unwrap_or_default();
+        self.response.apply(self.name(), ctx.headers, rendered)?;
         Ok(PluginFlow::Continue)
     }
 }
@@ -284,6 +464,7 @@ impl PluginFactory for HeadersPluginFactory {
             .then_some(ClientIpForwarding { trusted_proxies });
 
         Ok(Arc::new(HeadersPlugin {
+            id: NEXT_PLUGIN_ID.fetch_add(1, Ordering::Relaxed),
             client_ip_forwarding,
             request: HeaderPatch::compile(self.name(), config.request)?,
             upstream_request: HeaderPatch::compile(self.name(), config.upstream_request)?,
@@ -403,7 +584,10 @@ mod tests {
         let mut ctx = RequestCtx {
             state: &mut state,
             path: "/",
+            query: None,
+            scheme: "http",
             host: Some("example.com"),
+            server_name: None,
             method: &method,
             client_ip,
             headers,
@@ -435,7 +619,10 @@ mod tests {
         let mut request_ctx = RequestCtx {
             state: &mut state,
             path: "/",
+            query: None,
+            scheme: "http",
             host: Some("example.com"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut request_headers,
@@ -463,6 +650,122 @@ mod tests {
         assert_eq!(response_headers.removed.len(), 1);
     }
 
+    #[test]
+    fn header_values_render_request_variables_in_every_hook() {
+        let entry = |name: &str, value: &str| HeaderEntry {
+            name: name.into(),
+            value: value.into(),
+        };
+        let plugin = HeadersPluginFactory
+            .build(&PluginSpec {
+                name: "headers".into(),
+                config: json!(HeadersPluginConfig {
+                    request: HeaderPatchConfig {
+                        set: vec![entry("x-original-uri", "$request_uri")],
+                        ..HeaderPatchConfig::default()
+                    },
+                    upstream_request: HeaderPatchConfig {
+                        add: vec![entry("x-client", "$remote_addr via $http_user_agent")],
+                        ..HeaderPatchConfig::default()
+                    },
+                    response: HeaderPatchConfig {
+                        set: vec![entry("x-served-for", "$scheme://$host")],
+                        ..HeaderPatchConfig::default()
+                    },
+                    ..HeadersPluginConfig::default()
+                }),
+            })
+            .expect("headers plugin build should succeed");
+        let method = Method::GET;
+        let mut state = PluginState::default();
+
+        let mut request_headers = FakeHeaders::default();
+        request_headers
+            .inner
+            .insert(http::header::USER_AGENT, HeaderValue::from_static("curl"));
+        block_on(plugin.on_request(&mut RequestCtx {
+            state: &mut state,
+            path: "/search",
+            query: Some("q=1"),
+            scheme: "https",
+            host: Some("example.com"),
+            server_name: None,
+            method: &method,
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            headers: &mut request_headers,
+        }))
+        .expect("request hook should succeed");
+        assert_eq!(
+            header_value(&request_headers, &HeaderName::from_static("x-original-uri")),
+            "/search?q=1"
+        );
+
+        let mut upstream_headers = FakeHeaders::default();
+        block_on(plugin.on_upstream_request(&mut UpstreamRequestCtx {
+            state: &mut state,
+            headers: &mut upstream_headers,
+        }))
+        .expect("upstream hook should succeed");
+        assert_eq!(
+            header_value(&upstream_headers, &HeaderName::from_static("x-client")),
+            "203.0.113.7 via curl"
+        );
+
+        let mut response_headers = FakeHeaders::default();
+        let mut status = StatusCode::OK;
+        block_on(plugin.on_response(&mut ResponseCtx {
+            state: &mut state,
+            status: &mut status,
+            headers: &mut response_headers,
+        }))
+        .expect("response hook should succeed");
+        assert_eq!(
+            header_value(&response_headers, &HeaderName::from_static("x-served-for")),
+            "https://example.com"
+        );
+    }
+
+    #[test]
+    fn escaped_and_bare_dollars_render_literally() {
+        let plugin = HeadersPluginFactory
+            .build(&PluginSpec {
+                name: "headers".into(),
+                config: json!({
+                    "request": { "set": [
+                        { "name": "x-price", "value": "$5" },
+                        { "name": "x-raw", "value": "\\$notvar and $$host" }
+                    ] }
+                }),
+            })
+            .expect("literal dollars should not fail plugin build");
+        let mut headers = FakeHeaders::default();
+        run_request(plugin.as_ref(), None, &mut headers);
+
+        assert_eq!(
+            header_value(&headers, &HeaderName::from_static("x-price")),
+            "$5"
+        );
+        assert_eq!(
+            header_value(&headers, &HeaderName::from_static("x-raw")),
+            "$notvar and $host"
+        );
+    }
+
+    #[test]
+    fn unknown_header_variable_is_rejected() {
+        let error = match HeadersPluginFactory.build(&PluginSpec {
+            name: "headers".into(),
+            config: json!({
+                "response": { "set": [{ "name": "x-host", "value": "$hostname" }] }
+            }),
+        }) {
+            Ok(_) => panic!("unknown variable should fail plugin build"),
+            Err(error) => error,
+        };
+
+        assert!(error.message.contains("unknown variable `$hostname`"));
+    }
+
     #[test]
     fn untrusted_peer_cannot_spoof_forwarded_for() {
         let plugin = forwarding_plugin(&["10.0.0.0/8"], HeaderPatchConfig::default());
@@ -2,7 +2,8 @@ use http::StatusCode;
 use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
 use log::{debug, error};
 use ngxora_plugin_api::{
-    HttpPlugin, LocalResponse, PluginBuildError, PluginFactory, PluginFlow, PluginSpec, RequestCtx,
+    AuthenticatedSubject, HttpPlugin, LocalResponse, PluginBuildError, PluginFactory, PluginFlow,
+    PluginSpec, RequestCtx,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -73,13 +74,19 @@ impl HttpPlugin for JwtAuthPlugin {
             )));
         };
 
-        // Dummy payload to validate signature and expiry without parsing custom claims
+        // Only `sub` is read; signature and expiry are checked by `decode`.
         #[derive(Debug, Deserialize)]
-        struct DummyClaims {}
+        struct SubjectClaims {
+            #[serde(default)]
+            sub: Option<String>,
+        }
 
-        match decode::<DummyClaims>(token, &self.decoding_key, &self.validation) {
-            Ok(_) => {
+        match decode::<SubjectClaims>(token, &self.decoding_key, &self.validation) {
+            Ok(data) => {
                 debug!("jwt_auth: Token is valid");
+                if let Some(sub) = data.claims.sub {
+                    ctx.state.extensions.insert(AuthenticatedSubject(sub));
+                }
                 Ok(PluginFlow::Continue)
             }
             Err(e) => {
@@ -214,9 +221,11 @@ mod tests {
         #[derive(Debug, Serialize, Deserialize)]
         struct Claims {
             exp: usize,
+            sub: String,
         }
         let my_claims = Claims {
             exp: 2000000000, // Year 2033
+            sub: "user-42".into(),
         };
         let token = encode(
             &Header::default(),
@@ -234,7 +243,10 @@ mod tests {
         let mut ctx = RequestCtx {
             state: &mut state,
             path: "/test",
+            query: None,
+            scheme: "http",
             host: Some("localhost"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut mock_headers,
@@ -242,6 +254,10 @@ mod tests {
 
         let res = plugin.on_request(&mut ctx).await.unwrap();
         assert!(matches!(res, PluginFlow::Continue));
+        assert_eq!(
+            state.extensions.get::<AuthenticatedSubject>(),
+            Some(&AuthenticatedSubject("user-42".into()))
+        );
     }
 
     #[tokio::test]
@@ -263,7 +279,10 @@ mod tests {
         let mut ctx = RequestCtx {
             state: &mut state,
             path: "/test",
+            query: None,
+            scheme: "http",
             host: Some("localhost"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut mock_headers,
@@ -298,7 +317,10 @@ mod tests {
         let mut ctx = RequestCtx {
             state: &mut state,
             path: "/test",
+            query: None,
+            scheme: "http",
             host: Some("localhost"),
+            server_name: None,
             method: &method,
             client_ip: None,
             headers: &mut mock_headers,
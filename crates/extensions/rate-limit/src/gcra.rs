@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+/// Token bucket expressed as a generic cell rate algorithm (GCRA).
+///
+/// Each key keeps only a theoretical arrival time (TAT): the instant at which
+/// its bucket would be full again. A request is admitted while the TAT is at
+/// most `tolerance` ahead of now, and admitting it pushes the TAT forward by
+/// one `interval`. All values are microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Gcra {
+    pub(crate) interval: u64,
+    pub(crate) tolerance: u64,
+    burst: u32,
+}
+
+/// Outcome of one request against a bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Allow {
+        /// How long to hold the request before proxying it; zero in
+        /// `nodelay` mode.
+        delay: Duration,
+        remaining: u64,
+        /// Time until the bucket is full again.
+        reset: Duration,
+    },
+    Reject {
+        retry_after: Duration,
+    },
+}
+
+impl Gcra {
+    /// `requests` per `period`, with up to `burst` requests above the rate.
+    pub(crate) fn new(requests: u32, period: Duration, burst: u32) -> Self {
+        let interval = (period.as_micros() as u64 / u64::from(requests.max(1))).max(1);
+        Self {
+            interval,
+            tolerance: interval.saturating_mul(u64::from(burst)),
+            burst,
+        }
+    }
+
+    /// Requests a full bucket admits back to back.
+    pub(crate) fn capacity(&self) -> u64 {
+        u64::from(self.burst) + 1
+    }
+
+    /// Whether a request at `now` fits, given the key's TAT clamped to `now`.
+    pub(crate) fn admits(&self, tat: u64, now: u64) -> bool {
+        tat.saturating_sub(now) <= self.tolerance
+    }
+
+    /// Decision for a request at `now`; `tat` is the key's arrival time
+    /// clamped to `now`, before this request is counted.
+    pub(crate) fn decision(&self, tat: u64, now: u64, admitted: bool, nodelay: bool) -> Decision {
+        if !admitted {
+            let wait = tat.saturating_sub(self.tolerance).saturating_sub(now);
+            return Decision::Reject {
+                retry_after: Duration::from_micros(wait),
+            };
+        }
+
+        let ahead = tat.saturating_sub(now);
+        let reset = ahead + self.interval;
+        Decision::Allow {
+            delay: if nodelay {
+                Duration::ZERO
+            } else {
+                Duration::from_micros(ahead)
+            },
+            remaining: (self.tolerance + self.interval).saturating_sub(reset) / self.interval,
+            reset: Duration::from_micros(reset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decision, Gcra};
+    use std::time::Duration;
+
+    const SECOND: u64 = 1_000_000;
+
+    /// Run one request through `gcra`, returning the decision and new TAT.
+    fn step(gcra: &Gcra, stored: u64, now: u64, nodelay: bool) -> (Decision, u64) {
+        let tat = stored.max(now);
+        let admitted = gcra.admits(tat, now);
+        let decision = gcra.decision(tat, now, admitted, nodelay);
+        let next = if admitted {
+            tat + gcra.interval
+        } else {
+            stored
+        };
+        (decision, next)
+    }
+
+    #[test]
+    fn burst_admits_back_to_back_requests_then_rejects() {
+        let gcra = Gcra::new(2, Duration::from_secs(1), 2);
+        assert_eq!(gcra.capacity(), 3);
+
+        let mut tat = 0;
+        let mut remaining = Vec::new();
+        for _ in 0..3 {
+            let (decision, next) = step(&gcra, tat, 0, true);
+            match decision {
+                Decision::Allow {
+                    remaining: left, ..
+                } => remaining.push(left),
+                Decision::Reject { .. } => panic!("burst should admit the request"),
+            }
+            tat = next;
+        }
+        assert_eq!(remaining, vec![2, 1, 0]);
+
+        let (decision, _) = step(&gcra, tat, 0, true);
+        assert_eq!(
+            decision,
+            Decision::Reject {
+                retry_after: Duration::from_millis(500)
+            }
+        );
+
+        // Half a second later one token has come back.
+        let (decision, _) = step(&gcra, tat, SECOND / 2, true);
+        assert!(matches!(decision, Decision::Allow { remaining: 0, .. }));
+    }
+
+    #[test]
+    fn queueing_mode_spaces_requests_at_the_rate() {
+        let gcra = Gcra::new(60, Duration::from_secs(60), 2);
+
+        let mut tat = 0;
+        let mut delays = Vec::new();
+        for _ in 0..3 {
+            let (decision, next) = step(&gcra, tat, 0, false);
+            let Decision::Allow { delay, .. } = decision else {
+                panic!("burst should queue the request");
+            };
+            delays.push(delay);
+            tat = next;
+        }
+        assert_eq!(
+            delays,
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(1),
+                Duration::from_secs(2)
+            ]
+        );
+        assert!(matches!(
+            step(&gcra, tat, 0, false).0,
+            Decision::Reject { .. }
+        ));
+    }
+
+    #[test]
+    fn idle_bucket_refills_completely() {
+        let gcra = Gcra::new(10, Duration::from_secs(3600), 4);
+
+        let (decision, _) = step(&gcra, 3 * SECOND, 10 * 3600 * SECOND, true);
+        assert_eq!(
+            decision,
+            Decision::Allow {
+                delay: Duration::ZERO,
+                remaining: 4,
+                reset: Duration::from_secs(360),
+            }
+        );
+    }
+}
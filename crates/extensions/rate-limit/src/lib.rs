@@ -1,98 +1,319 @@
-use http::{HeaderValue, StatusCode, header};
+mod gcra;
+mod shared;
+
+use gcra::{Decision, Gcra};
+use http::{HeaderName, HeaderValue, StatusCode, header};
+use log::warn;
 use ngxora_plugin_api::{
-    HttpPlugin, LocalResponse, PluginBuildError, PluginFactory, PluginFlow, PluginSpec, RequestCtx,
-    async_trait,
+    AuthenticatedSubject, HttpPlugin, LocalResponse, PluginBuildError, PluginError, PluginFactory,
+    PluginFlow, PluginSpec, RequestCtx, ResponseCtx, async_trait,
 };
 use serde::{Deserialize, Serialize};
+use shared::SharedLimiter;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 const PLUGIN_NAME: &str = "rate-limit";
-const BUCKET_TTL_SECS: u64 = 120;
 const SWEEP_INTERVAL_REQUESTS: u64 = 1024;
 
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RateLimitPluginConfig {
+    /// Per-second budget: the same as `rate` of this many requests per second
+    /// with a burst of one second's worth, in `nodelay` mode. Before token
+    /// buckets this was a fixed one-second window that refilled at once on the
+    /// window edge; the bucket refills one request every `1/n` seconds.
+    #[serde(default)]
     pub max_requests_per_second: isize,
+    /// Token bucket refill rate; replaces `max_requests_per_second`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<RateConfig>,
+    /// Requests allowed above `rate`; requires `rate`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+    /// Serve burst requests at once instead of spacing them out at `rate`.
+    #[serde(default)]
+    pub nodelay: bool,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Shared bucket store; buckets stay in process memory when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<RateLimitStoreConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateConfig {
+    pub requests: u32,
+    #[serde(default)]
+    pub per: RatePeriod,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-struct Bucket {
-    window: u64,
-    count: u32,
-    last_seen: u64,
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RatePeriod {
+    #[default]
+    Second,
+    Minute,
+    Hour,
 }
 
-impl Bucket {
-    fn new(window: u64) -> Self {
-        Self {
-            window,
-            count: 0,
-            last_seen: window,
+impl RatePeriod {
+    fn duration(self) -> Duration {
+        match self {
+            RatePeriod::Second => Duration::from_secs(1),
+            RatePeriod::Minute => Duration::from_secs(60),
+            RatePeriod::Hour => Duration::from_secs(3600),
         }
     }
 }
 
+/// What requests are grouped by; requests without a key are not limited.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Client IP address.
+    #[default]
+    Ip,
+    /// Client network: IPv4 and IPv6 addresses masked to these prefix lengths.
+    IpPrefix {
+        #[serde(default = "default_ipv4_prefix")]
+        ipv4: u8,
+        #[serde(default = "default_ipv6_prefix")]
+        ipv6: u8,
+    },
+    /// Value of a request header, such as an API key.
+    Header { name: String },
+    /// Subject of a JWT verified by an earlier `jwt_auth` plugin.
+    JwtSubject,
+    /// One bucket for the whole location.
+    Route,
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+/// Redis-protocol server holding buckets shared by all replicas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitStoreConfig {
+    pub redis_url: String,
+    /// Key namespace; locations with the same prefix share buckets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_prefix: Option<String>,
+    #[serde(default)]
+    pub on_error: StoreFailureMode,
+    /// Per-request store timeout; defaults to 100ms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// What to do with a request when the shared store cannot be reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreFailureMode {
+    /// Let the request through unlimited.
+    #[default]
+    Open,
+    /// Reject the request with `503 Service Unavailable`.
+    Closed,
+}
+
+/// Resolved [`RateLimitKey`] for one request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum LimitKey {
+    Ip(IpAddr),
+    Network(IpAddr, u8),
+    Header(String),
+    Subject(String),
+    Route,
+}
+
+impl Display for LimitKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitKey::Ip(ip) => write!(f, "ip:{ip}"),
+            LimitKey::Network(ip, len) => write!(f, "net:{ip}/{len}"),
+            LimitKey::Header(value) => write!(f, "header:{value}"),
+            LimitKey::Subject(sub) => write!(f, "sub:{sub}"),
+            LimitKey::Route => f.write_str("route"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum KeySource {
+    Ip,
+    IpPrefix { ipv4: u8, ipv6: u8 },
+    Header(HeaderName),
+    JwtSubject,
+    Route,
+}
+
+impl KeySource {
+    fn from_config(key: &RateLimitKey) -> Result<Self, String> {
+        match key {
+            RateLimitKey::Ip => Ok(KeySource::Ip),
+            RateLimitKey::IpPrefix { ipv4, ipv6 } => {
+                if *ipv4 > 32 || *ipv6 > 128 {
+                    return Err(format!(
+                        "ip_prefix lengths must be at most 32 and 128, got {ipv4} and {ipv6}"
+                    ));
+                }
+                Ok(KeySource::IpPrefix {
+                    ipv4: *ipv4,
+                    ipv6: *ipv6,
+                })
+            }
+            RateLimitKey::Header { name } => HeaderName::from_bytes(name.as_bytes())
+                .map(KeySource::Header)
+                .map_err(|_| format!("invalid key header name `{name}`")),
+            RateLimitKey::JwtSubject => Ok(KeySource::JwtSubject),
+            RateLimitKey::Route => Ok(KeySource::Route),
+        }
+    }
+
+    fn resolve(&self, ctx: &RequestCtx<'_>) -> Option<LimitKey> {
+        match self {
+            KeySource::Ip => ctx.client_ip.map(LimitKey::Ip),
+            KeySource::IpPrefix { ipv4, ipv6 } => ctx.client_ip.map(|ip| match ip {
+                IpAddr::V4(v4) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*ipv4)).unwrap_or(0);
+                    LimitKey::Network(IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask)), *ipv4)
+                }
+                IpAddr::V6(v6) => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(*ipv6)).unwrap_or(0);
+                    LimitKey::Network(IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask)), *ipv6)
+                }
+            }),
+            KeySource::Header(name) => ctx
+                .headers
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .filter(|value| !value.is_empty())
+                .map(LimitKey::Header),
+            KeySource::JwtSubject => ctx
+                .state
+                .extensions
+                .get::<AuthenticatedSubject>()
+                .map(|subject| LimitKey::Subject(subject.0.clone())),
+            KeySource::Route => Some(LimitKey::Route),
+        }
+    }
+}
+
+/// Headers describing the admitted request's bucket, added to the response.
+#[derive(Debug, Clone)]
+struct RateLimitHeaders {
+    remaining: u64,
+    reset: Duration,
+}
+
 pub struct RateLimitPlugin {
-    max_requests_per_second: u32,
+    gcra: Gcra,
+    nodelay: bool,
+    key: KeySource,
+    policy: HeaderValue,
     started_at: Instant,
-    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    buckets: Mutex<HashMap<LimitKey, u64>>,
     requests_since_sweep: AtomicU64,
+    shared: Option<SharedLimiter>,
+    on_store_error: StoreFailureMode,
 }
 
 impl std::fmt::Debug for RateLimitPlugin {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("RateLimitPlugin")
-            .field("max_requests_per_second", &self.max_requests_per_second)
+            .field("gcra", &self.gcra)
+            .field("nodelay", &self.nodelay)
+            .field("key", &self.key)
+            .field("shared", &self.shared.is_some())
+            .field("on_store_error", &self.on_store_error)
             .finish()
     }
 }
 
+/// Whole seconds for a header, rounding up so clients never retry early.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_micros().div_ceil(1_000_000) as u64
+}
+
+fn numeric_header(value: u64) -> HeaderValue {
+    HeaderValue::from_str(&value.to_string()).expect("numeric header value should be valid")
+}
+
 impl RateLimitPlugin {
-    fn current_window(&self) -> u64 {
-        self.started_at.elapsed().as_secs()
+    fn now_micros(&self) -> u64 {
+        self.started_at.elapsed().as_micros() as u64
     }
 
-    fn lock_buckets(&self) -> MutexGuard<'_, HashMap<IpAddr, Bucket>> {
+    fn lock_buckets(&self) -> MutexGuard<'_, HashMap<LimitKey, u64>> {
         match self.buckets.lock() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         }
     }
 
-    fn maybe_sweep(&self, current_window: u64) {
+    /// Drop buckets that have refilled completely; they behave like new ones.
+    fn maybe_sweep(&self, now: u64) {
         let requests = self.requests_since_sweep.fetch_add(1, Ordering::Relaxed) + 1;
-        if requests % SWEEP_INTERVAL_REQUESTS != 0 {
+        if !requests.is_multiple_of(SWEEP_INTERVAL_REQUESTS) {
             return;
         }
 
-        let oldest_allowed_window = current_window.saturating_sub(BUCKET_TTL_SECS);
         let mut buckets = self.lock_buckets();
-        buckets.retain(|_, bucket| bucket.last_seen >= oldest_allowed_window);
+        buckets.retain(|_, tat| *tat > now);
     }
 
-    fn allow_request(&self, client_ip: IpAddr) -> bool {
-        let current_window = self.current_window();
-        let allowed = {
+    fn check_local(&self, key: LimitKey) -> Decision {
+        let now = self.now_micros();
+        let decision = {
             let mut buckets = self.lock_buckets();
-            let bucket = buckets
-                .entry(client_ip)
-                .or_insert_with(|| Bucket::new(current_window));
-            if bucket.window != current_window {
-                *bucket = Bucket::new(current_window);
+            let stored = buckets.entry(key).or_insert(now);
+            let tat = (*stored).max(now);
+            let admitted = self.gcra.admits(tat, now);
+            if admitted {
+                *stored = tat + self.gcra.interval;
             }
-            bucket.last_seen = current_window;
-            bucket.count = bucket.count.saturating_add(1);
-            bucket.count <= self.max_requests_per_second
+            self.gcra.decision(tat, now, admitted, self.nodelay)
         };
-        self.maybe_sweep(current_window);
-        allowed
+        self.maybe_sweep(now);
+        decision
+    }
+
+    /// Count the request against the shared store, applying the configured
+    /// failure mode when the store is unavailable.
+    async fn check_shared(
+        &self,
+        shared: &SharedLimiter,
+        key: &LimitKey,
+    ) -> Result<Option<Decision>, PluginFlow> {
+        match shared.check(&self.gcra, key, self.nodelay).await {
+            Ok(decision) => Ok(Some(decision)),
+            Err(err) => {
+                warn!("rate limit store unavailable: {err}");
+                match self.on_store_error {
+                    StoreFailureMode::Open => Ok(None),
+                    StoreFailureMode::Closed => Err(store_unavailable_response()),
+                }
+            }
+        }
     }
 
-    fn rate_limited_response(&self) -> PluginFlow {
+    fn rate_limited_response(&self, retry_after: Duration) -> PluginFlow {
+        let retry_after = numeric_header(ceil_secs(retry_after).max(1));
+        let limit = numeric_header(self.gcra.capacity());
         let mut response = LocalResponse::new(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
         response.headers.push((
             header::CONTENT_TYPE,
@@ -100,12 +321,18 @@ impl RateLimitPlugin {
         ));
         response
             .headers
-            .push((header::RETRY_AFTER, HeaderValue::from_static("1")));
-        response.headers.push((
-            header::HeaderName::from_static("x-ratelimit-limit"),
-            HeaderValue::from_str(&self.max_requests_per_second.to_string())
-                .expect("numeric rate limit header value should be valid"),
-        ));
+            .push((header::RETRY_AFTER, retry_after.clone()));
+        response.headers.push((RATELIMIT_LIMIT, limit.clone()));
+        response
+            .headers
+            .push((RATELIMIT_REMAINING, HeaderValue::from_static("0")));
+        response.headers.push((RATELIMIT_RESET, retry_after));
+        response
+            .headers
+            .push((RATELIMIT_POLICY, self.policy.clone()));
+        response
+            .headers
+            .push((header::HeaderName::from_static("x-ratelimit-limit"), limit));
         response.headers.push((
             header::HeaderName::from_static("x-ratelimit-remaining"),
             HeaderValue::from_static("0"),
@@ -114,31 +341,107 @@ impl RateLimitPlugin {
     }
 }
 
+fn store_unavailable_response() -> PluginFlow {
+    let mut response = LocalResponse::new(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable");
+    response.headers.push((
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    ));
+    response
+        .headers
+        .push((header::RETRY_AFTER, HeaderValue::from_static("1")));
+    PluginFlow::Respond(response)
+}
+
 #[async_trait]
 impl HttpPlugin for RateLimitPlugin {
     fn name(&self) -> &'static str {
         PLUGIN_NAME
     }
 
-    async fn on_request(
-        &self,
-        ctx: &mut RequestCtx<'_>,
-    ) -> Result<PluginFlow, ngxora_plugin_api::PluginError> {
-        let Some(client_ip) = ctx.client_ip else {
+    async fn on_request(&self, ctx: &mut RequestCtx<'_>) -> Result<PluginFlow, PluginError> {
+        let Some(key) = self.key.resolve(ctx) else {
             return Ok(PluginFlow::Continue);
         };
 
-        if self.allow_request(client_ip) {
-            Ok(PluginFlow::Continue)
-        } else {
-            Ok(self.rate_limited_response())
+        let decision = match &self.shared {
+            Some(shared) => match self.check_shared(shared, &key).await {
+                Ok(Some(decision)) => decision,
+                Ok(None) => return Ok(PluginFlow::Continue),
+                Err(flow) => return Ok(flow),
+            },
+            None => self.check_local(key),
+        };
+
+        match decision {
+            Decision::Allow {
+                delay,
+                remaining,
+                reset,
+            } => {
+                ctx.state
+                    .extensions
+                    .insert(RateLimitHeaders { remaining, reset });
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(PluginFlow::Continue)
+            }
+            Decision::Reject { retry_after } => Ok(self.rate_limited_response(retry_after)),
         }
     }
+
+    async fn on_response(&self, ctx: &mut ResponseCtx<'_>) -> Result<PluginFlow, PluginError> {
+        let Some(headers) = ctx.state.extensions.get::<RateLimitHeaders>().cloned() else {
+            return Ok(PluginFlow::Continue);
+        };
+        ctx.headers
+            .set(&RATELIMIT_LIMIT, numeric_header(self.gcra.capacity()))?;
+        ctx.headers
+            .set(&RATELIMIT_REMAINING, numeric_header(headers.remaining))?;
+        ctx.headers
+            .set(&RATELIMIT_RESET, numeric_header(ceil_secs(headers.reset)))?;
+        ctx.headers.set(&RATELIMIT_POLICY, self.policy.clone())?;
+        Ok(PluginFlow::Continue)
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct RateLimitPluginFactory;
 
+impl RateLimitPluginFactory {
+    /// Rate, burst and mode from either the `rate` or the legacy
+    /// `max_requests_per_second` form.
+    fn limits(&self, config: &RateLimitPluginConfig) -> Result<(RateConfig, u32, bool), String> {
+        match (config.rate, config.max_requests_per_second) {
+            (Some(_), requests) if requests != 0 => {
+                Err("use either `rate` or `max_requests_per_second`, not both".to_string())
+            }
+            (Some(rate), _) => {
+                if rate.requests == 0 {
+                    return Err("rate requests must be positive".to_string());
+                }
+                Ok((rate, config.burst.unwrap_or(0), config.nodelay))
+            }
+            (None, requests) => {
+                if requests <= 0 {
+                    return Err("max_requests_per_second must be positive".to_string());
+                }
+                if config.burst.is_some() {
+                    return Err("burst requires `rate`".to_string());
+                }
+                let requests = u32::try_from(requests)
+                    .map_err(|_| "max_requests_per_second exceeds supported range".to_string())?;
+                let rate = RateConfig {
+                    requests,
+                    per: RatePeriod::Second,
+                };
+                Ok((rate, requests - 1, true))
+            }
+        }
+    }
+}
+
 impl PluginFactory for RateLimitPluginFactory {
     fn name(&self) -> &'static str {
         PLUGIN_NAME
@@ -149,43 +452,57 @@ impl PluginFactory for RateLimitPluginFactory {
             |err| PluginBuildError::new(self.name(), format!("invalid plugin config: {err}")),
         )?;
 
-        if config.max_requests_per_second <= 0 {
-            return Err(PluginBuildError::new(
-                self.name(),
-                "max_requests_per_second must be positive",
-            ));
-        }
+        let (rate, burst, nodelay) = self
+            .limits(&config)
+            .map_err(|message| PluginBuildError::new(self.name(), message))?;
+        let key = KeySource::from_config(&config.key)
+            .map_err(|message| PluginBuildError::new(self.name(), message))?;
+        let shared = config
+            .store
+            .as_ref()
+            .map(SharedLimiter::new)
+            .transpose()
+            .map_err(|message| PluginBuildError::new(self.name(), message))?;
+        let on_store_error = config
+            .store
+            .as_ref()
+            .map(|store| store.on_error)
+            .unwrap_or_default();
 
-        let max_requests_per_second =
-            u32::try_from(config.max_requests_per_second).map_err(|_| {
-                PluginBuildError::new(
-                    self.name(),
-                    "max_requests_per_second exceeds supported range",
-                )
-            })?;
+        let period = rate.per.duration();
+        let policy = HeaderValue::from_str(&format!(
+            "{};w={};burst={burst}",
+            rate.requests,
+            period.as_secs()
+        ))
+        .expect("numeric policy header value should be valid");
 
         Ok(Arc::new(RateLimitPlugin {
-            max_requests_per_second,
+            gcra: Gcra::new(rate.requests, period, burst),
+            nodelay,
+            key,
+            policy,
             started_at: Instant::now(),
             buckets: Mutex::new(HashMap::new()),
             requests_since_sweep: AtomicU64::new(0),
+            shared,
+            on_store_error,
         }))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{RateLimitPlugin, RateLimitPluginFactory};
+    use super::{RateConfig, RateLimitPluginConfig, RateLimitPluginFactory, RatePeriod};
     use futures::executor::block_on;
-    use http::{Extensions, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header};
+    use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header};
     use ngxora_plugin_api::{
-        HeaderMapMut, HttpPlugin, PluginFactory, PluginFlow, PluginSpec, PluginState, RequestCtx,
+        AuthenticatedSubject, HeaderMapMut, HttpPlugin, PluginFactory, PluginFlow, PluginSpec,
+        PluginState, RequestCtx, ResponseCtx,
     };
-    use serde_json::json;
-    use std::collections::HashMap;
+    use serde_json::{Value, json};
     use std::net::{IpAddr, Ipv4Addr};
-    use std::sync::Mutex;
-    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
     use std::time::{Duration, Instant};
 
     struct FakeHeaders {
@@ -228,31 +545,54 @@ mod tests {
         }
     }
 
-    fn test_plugin(limit: u32) -> RateLimitPlugin {
-        RateLimitPlugin {
-            max_requests_per_second: limit,
-            started_at: Instant::now(),
-            buckets: Mutex::new(HashMap::new()),
-            requests_since_sweep: AtomicU64::new(0),
-        }
+    fn build(config: Value) -> Arc<dyn HttpPlugin> {
+        RateLimitPluginFactory
+            .build(&PluginSpec {
+                name: "rate-limit".into(),
+                config,
+            })
+            .expect("build should succeed")
     }
 
-    fn run_request(plugin: &RateLimitPlugin, client_ip: Option<IpAddr>) -> PluginFlow {
-        let method = Method::GET;
-        let mut state = PluginState {
-            extensions: Extensions::new(),
-        };
-        let mut headers = FakeHeaders::default();
+    fn test_plugin(limit: u32) -> Arc<dyn HttpPlugin> {
+        build(json!({ "max_requests_per_second": limit }))
+    }
 
-        block_on(plugin.on_request(&mut RequestCtx {
-            state: &mut state,
-            path: "/",
-            host: Some("example.com"),
-            method: &method,
+    fn run_request(plugin: &dyn HttpPlugin, client_ip: Option<IpAddr>) -> PluginFlow {
+        block_on(run_request_async(plugin, client_ip))
+    }
+
+    async fn run_request_async(plugin: &dyn HttpPlugin, client_ip: Option<IpAddr>) -> PluginFlow {
+        run_request_with(
+            plugin,
             client_ip,
-            headers: &mut headers,
-        }))
-        .expect("request hook should succeed")
+            &mut PluginState::default(),
+            &mut FakeHeaders::default(),
+        )
+        .await
+    }
+
+    async fn run_request_with(
+        plugin: &dyn HttpPlugin,
+        client_ip: Option<IpAddr>,
+        state: &mut PluginState,
+        headers: &mut FakeHeaders,
+    ) -> PluginFlow {
+        let method = Method::GET;
+        plugin
+            .on_request(&mut RequestCtx {
+                state,
+                path: "/",
+                query: None,
+                scheme: "http",
+                host: Some("example.com"),
+                server_name: None,
+                method: &method,
+                client_ip,
+                headers,
+            })
+            .await
+            .expect("request hook should succeed")
     }
 
     #[test]
@@ -294,9 +634,18 @@ mod tests {
     fn request_without_client_ip_fails_open() {
         let plugin = test_plugin(1);
 
-        assert!(matches!(run_request(&plugin, None), PluginFlow::Continue));
-        assert!(matches!(run_request(&plugin, None), PluginFlow::Continue));
-        assert!(matches!(run_request(&plugin, None), PluginFlow::Continue));
+        assert!(matches!(
+            run_request(plugin.as_ref(), None),
+            PluginFlow::Continue
+        ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), None),
+            PluginFlow::Continue
+        ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), None),
+            PluginFlow::Continue
+        ));
     }
 
     #[test]
@@ -305,15 +654,15 @@ mod tests {
         let client_ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 10));
 
         assert!(matches!(
-            run_request(&plugin, Some(client_ip)),
+            run_request(plugin.as_ref(), Some(client_ip)),
             PluginFlow::Continue
         ));
         assert!(matches!(
-            run_request(&plugin, Some(client_ip)),
+            run_request(plugin.as_ref(), Some(client_ip)),
             PluginFlow::Continue
         ));
 
-        let flow = run_request(&plugin, Some(client_ip));
+        let flow = run_request(plugin.as_ref(), Some(client_ip));
         match flow {
             PluginFlow::Respond(response) => {
                 assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
@@ -322,7 +671,7 @@ mod tests {
                     response
                         .headers
                         .iter()
-                        .find(|(name, _)| name == &header::RETRY_AFTER)
+                        .find(|(name, _)| *name == header::RETRY_AFTER)
                         .map(|(_, value)| value),
                     Some(&HeaderValue::from_static("1"))
                 );
@@ -330,8 +679,8 @@ mod tests {
                     response
                         .headers
                         .iter()
-                        .find(|(name, _)| name
-                            == &header::HeaderName::from_static("x-ratelimit-limit"))
+                        .find(|(name, _)| *name
+                            == header::HeaderName::from_static("x-ratelimit-limit"))
                         .map(|(_, value)| value),
                     Some(&HeaderValue::from_static("2"))
                 );
@@ -346,20 +695,63 @@ mod tests {
         let client_ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 20));
 
         assert!(matches!(
-            run_request(&plugin, Some(client_ip)),
+            run_request(plugin.as_ref(), Some(client_ip)),
             PluginFlow::Continue
         ));
         assert!(matches!(
-            run_request(&plugin, Some(client_ip)),
+            run_request(plugin.as_ref(), Some(client_ip)),
             PluginFlow::Respond(_)
         ));
 
         std::thread::sleep(Duration::from_millis(1100));
 
         assert!(matches!(
-            run_request(&plugin, Some(client_ip)),
+            run_request(plugin.as_ref(), Some(client_ip)),
+            PluginFlow::Continue
+        ));
+    }
+
+    #[test]
+    fn legacy_budget_maps_to_a_nodelay_bucket_of_one_seconds_worth() {
+        let config: RateLimitPluginConfig =
+            serde_json::from_value(json!({ "max_requests_per_second": 4 })).unwrap();
+        assert_eq!(
+            RateLimitPluginFactory.limits(&config),
+            Ok((
+                RateConfig {
+                    requests: 4,
+                    per: RatePeriod::Second,
+                },
+                3,
+                true
+            ))
+        );
+
+        // The budget comes back one request every 250ms rather than all at
+        // once on a window edge.
+        let plugin = test_plugin(4);
+        let client_ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 25)));
+        for _ in 0..4 {
+            assert!(matches!(
+                run_request(plugin.as_ref(), client_ip),
+                PluginFlow::Continue
+            ));
+        }
+        assert!(matches!(
+            run_request(plugin.as_ref(), client_ip),
+            PluginFlow::Respond(_)
+        ));
+
+        std::thread::sleep(Duration::from_millis(300));
+
+        assert!(matches!(
+            run_request(plugin.as_ref(), client_ip),
             PluginFlow::Continue
         ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), client_ip),
+            PluginFlow::Respond(_)
+        ));
     }
 
     #[test]
@@ -369,16 +761,306 @@ mod tests {
         let client_b = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 31));
 
         assert!(matches!(
-            run_request(&plugin, Some(client_a)),
+            run_request(plugin.as_ref(), Some(client_a)),
+            PluginFlow::Continue
+        ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), Some(client_a)),
+            PluginFlow::Respond(_)
+        ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), Some(client_b)),
+            PluginFlow::Continue
+        ));
+    }
+
+    async fn unreachable_store_url() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("redis://{addr}")
+    }
+
+    #[test]
+    fn factory_rejects_invalid_store_config() {
+        let spec = PluginSpec {
+            name: "rate-limit".into(),
+            config: json!({
+                "max_requests_per_second": 10,
+                "store": { "redis_url": "redis://127.0.0.1", "on_error": "sideways" }
+            }),
+        };
+        assert!(RateLimitPluginFactory.build(&spec).is_err());
+
+        let spec = PluginSpec {
+            name: "rate-limit".into(),
+            config: json!({
+                "max_requests_per_second": 10,
+                "store": { "redis_url": "redis://127.0.0.1", "key_prefix": "" }
+            }),
+        };
+        match RateLimitPluginFactory.build(&spec) {
+            Ok(_) => panic!("expected build to fail"),
+            Err(e) => assert!(e.message.contains("key_prefix")),
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_store_fails_open_by_default() {
+        let spec = PluginSpec {
+            name: "rate-limit".into(),
+            config: json!({
+                "max_requests_per_second": 1,
+                "store": { "redis_url": unreachable_store_url().await }
+            }),
+        };
+        let plugin = RateLimitPluginFactory.build(&spec).unwrap();
+        let client_ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 40)));
+
+        for _ in 0..3 {
+            assert!(matches!(
+                run_request_async(plugin.as_ref(), client_ip).await,
+                PluginFlow::Continue
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_store_fails_closed_when_configured() {
+        let spec = PluginSpec {
+            name: "rate-limit".into(),
+            config: json!({
+                "max_requests_per_second": 1,
+                "store": { "redis_url": unreachable_store_url().await, "on_error": "closed" }
+            }),
+        };
+        let plugin = RateLimitPluginFactory.build(&spec).unwrap();
+        let client_ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 41)));
+
+        match run_request_async(plugin.as_ref(), client_ip).await {
+            PluginFlow::Respond(response) => {
+                assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+            }
+            PluginFlow::Continue => panic!("store failure should reject the request"),
+        }
+    }
+
+    fn header<'a>(flow: &'a PluginFlow, name: &str) -> Option<&'a HeaderValue> {
+        let PluginFlow::Respond(response) = flow else {
+            return None;
+        };
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header.as_str() == name)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn factory_rejects_conflicting_limits() {
+        for config in [
+            json!({ "max_requests_per_second": 5, "rate": { "requests": 5 } }),
+            json!({ "max_requests_per_second": 5, "burst": 2 }),
+            json!({ "rate": { "requests": 0, "per": "minute" } }),
+            json!({ "rate": { "requests": 5 }, "key": { "type": "ip_prefix", "ipv4": 33 } }),
+            json!({ "rate": { "requests": 5 }, "key": { "type": "header", "name": "bad header" } }),
+        ] {
+            let spec = PluginSpec {
+                name: "rate-limit".into(),
+                config: config.clone(),
+            };
+            assert!(RateLimitPluginFactory.build(&spec).is_err(), "{config}");
+        }
+    }
+
+    #[test]
+    fn burst_allows_extra_requests_and_reports_retry_after() {
+        let plugin = build(json!({
+            "rate": { "requests": 3, "per": "minute" },
+            "burst": 2,
+            "nodelay": true
+        }));
+        let client_ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 50)));
+
+        for _ in 0..3 {
+            assert!(matches!(
+                run_request(plugin.as_ref(), client_ip),
+                PluginFlow::Continue
+            ));
+        }
+        let flow = run_request(plugin.as_ref(), client_ip);
+        assert!(
+            matches!(&flow, PluginFlow::Respond(r) if r.status == StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(
+            header(&flow, "retry-after"),
+            Some(&HeaderValue::from_static("20"))
+        );
+        assert_eq!(
+            header(&flow, "ratelimit-limit"),
+            Some(&HeaderValue::from_static("3"))
+        );
+        assert_eq!(
+            header(&flow, "ratelimit-policy"),
+            Some(&HeaderValue::from_static("3;w=60;burst=2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn queueing_mode_delays_burst_requests() {
+        let plugin = build(json!({ "rate": { "requests": 10 }, "burst": 1 }));
+        let client_ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 51)));
+
+        let started = Instant::now();
+        assert!(matches!(
+            run_request_async(plugin.as_ref(), client_ip).await,
             PluginFlow::Continue
         ));
+        assert!(started.elapsed() < Duration::from_millis(50));
         assert!(matches!(
-            run_request(&plugin, Some(client_a)),
+            run_request_async(plugin.as_ref(), client_ip).await,
+            PluginFlow::Continue
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[test]
+    fn admitted_requests_get_rate_limit_response_headers() {
+        let plugin = build(json!({ "rate": { "requests": 5 }, "burst": 4, "nodelay": true }));
+        let mut state = PluginState::default();
+        let flow = block_on(run_request_with(
+            plugin.as_ref(),
+            Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 52))),
+            &mut state,
+            &mut FakeHeaders::default(),
+        ));
+        assert!(matches!(flow, PluginFlow::Continue));
+
+        let mut status = StatusCode::OK;
+        let mut headers = FakeHeaders::default();
+        block_on(plugin.on_response(&mut ResponseCtx {
+            state: &mut state,
+            status: &mut status,
+            headers: &mut headers,
+        }))
+        .expect("response hook should succeed");
+
+        let value = |name: &str| {
+            headers
+                .inner
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        assert_eq!(value("ratelimit-limit").as_deref(), Some("5"));
+        assert_eq!(value("ratelimit-remaining").as_deref(), Some("4"));
+        assert_eq!(value("ratelimit-reset").as_deref(), Some("1"));
+        assert_eq!(value("ratelimit-policy").as_deref(), Some("5;w=1;burst=4"));
+    }
+
+    #[test]
+    fn header_key_limits_per_value_and_skips_requests_without_it() {
+        let plugin = build(json!({
+            "rate": { "requests": 1, "per": "hour" },
+            "nodelay": true,
+            "key": { "type": "header", "name": "x-api-key" }
+        }));
+        let run = |api_key: Option<&'static str>| {
+            let mut headers = FakeHeaders::default();
+            if let Some(api_key) = api_key {
+                headers
+                    .inner
+                    .insert("x-api-key", HeaderValue::from_static(api_key));
+            }
+            block_on(run_request_with(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 53))),
+                &mut PluginState::default(),
+                &mut headers,
+            ))
+        };
+
+        assert!(matches!(run(Some("alpha")), PluginFlow::Continue));
+        assert!(matches!(run(Some("alpha")), PluginFlow::Respond(_)));
+        assert!(matches!(run(Some("beta")), PluginFlow::Continue));
+        assert!(matches!(run(None), PluginFlow::Continue));
+        assert!(matches!(run(None), PluginFlow::Continue));
+    }
+
+    #[test]
+    fn ip_prefix_key_groups_clients_by_network() {
+        let plugin = build(json!({
+            "rate": { "requests": 1, "per": "hour" },
+            "key": { "type": "ip_prefix", "ipv4": 24 }
+        }));
+
+        assert!(matches!(
+            run_request(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)))
+            ),
+            PluginFlow::Continue
+        ));
+        assert!(matches!(
+            run_request(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2)))
+            ),
             PluginFlow::Respond(_)
         ));
         assert!(matches!(
-            run_request(&plugin, Some(client_b)),
+            run_request(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(198, 51, 101, 1)))
+            ),
+            PluginFlow::Continue
+        ));
+    }
+
+    #[test]
+    fn jwt_subject_key_uses_the_verified_subject() {
+        let plugin = build(json!({
+            "rate": { "requests": 1, "per": "hour" },
+            "key": { "type": "jwt_subject" }
+        }));
+        let run = |subject: Option<&str>| {
+            let mut state = PluginState::default();
+            if let Some(subject) = subject {
+                state
+                    .extensions
+                    .insert(AuthenticatedSubject(subject.to_string()));
+            }
+            block_on(run_request_with(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 54))),
+                &mut state,
+                &mut FakeHeaders::default(),
+            ))
+        };
+
+        assert!(matches!(run(Some("alice")), PluginFlow::Continue));
+        assert!(matches!(run(Some("alice")), PluginFlow::Respond(_)));
+        assert!(matches!(run(Some("bob")), PluginFlow::Continue));
+        assert!(matches!(run(None), PluginFlow::Continue));
+    }
+
+    #[test]
+    fn route_key_shares_one_bucket_across_clients() {
+        let plugin = build(json!({
+            "rate": { "requests": 1, "per": "hour" },
+            "key": { "type": "route" }
+        }));
+
+        assert!(matches!(
+            run_request(
+                plugin.as_ref(),
+                Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 55)))
+            ),
             PluginFlow::Continue
         ));
+        assert!(matches!(
+            run_request(plugin.as_ref(), None),
+            PluginFlow::Respond(_)
+        ));
     }
 }
@@ -0,0 +1,314 @@
+use crate::gcra::{Decision, Gcra};
+use crate::{LimitKey, RateLimitStoreConfig};
+use ngxora_redis::LazyConnection;
+use redis::Script;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_KEY_PREFIX: &str = "ngxora:ratelimit";
+const DEFAULT_TIMEOUT_MS: u64 = 100;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// GCRA step run atomically on the server. Returns `{admitted, tat}` where
+/// `tat` is the stored arrival time clamped to `now`, before this request.
+/// Keys expire one second after their bucket is full again.
+const GCRA_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local interval = tonumber(ARGV[2])
+local tolerance = tonumber(ARGV[3])
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then
+  tat = now
+end
+if tat - now > tolerance then
+  return {0, tat}
+end
+local next_tat = tat + interval
+redis.call('SET', KEYS[1], next_tat, 'PX', math.ceil((next_tat - now) / 1000) + 1000)
+return {1, tat}
+"#;
+
+/// Token buckets kept on a Redis-protocol server, so every replica draws from
+/// the same per-key budget.
+///
+/// Each key `<prefix>:<limit key>` holds the bucket's theoretical arrival
+/// time in Unix microseconds, updated by one Lua script per request. Times
+/// come from the replicas' wall clocks.
+pub(crate) struct SharedLimiter {
+    key_prefix: String,
+    script: Script,
+    connection: LazyConnection,
+}
+
+impl SharedLimiter {
+    pub(crate) fn new(config: &RateLimitStoreConfig) -> Result<Self, String> {
+        let client = redis::Client::open(config.redis_url.as_str())
+            .map_err(|err| format!("invalid store redis_url `{}`: {err}", config.redis_url))?;
+        let key_prefix = match config.key_prefix.as_deref() {
+            Some(prefix) if prefix.is_empty() || prefix.contains(char::is_whitespace) => {
+                return Err("store key_prefix must be non-empty without whitespace".to_string());
+            }
+            Some(prefix) => prefix.to_string(),
+            None => DEFAULT_KEY_PREFIX.to_string(),
+        };
+        let timeout_ms = config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS);
+        if timeout_ms == 0 {
+            return Err("store timeout_ms must be positive".to_string());
+        }
+
+        let timeout = Duration::from_millis(timeout_ms);
+
+        Ok(Self {
+            key_prefix,
+            script: Script::new(GCRA_SCRIPT),
+            connection: LazyConnection::new(
+                client,
+                "rate limit store",
+                CONNECT_TIMEOUT.max(timeout),
+                timeout,
+            ),
+        })
+    }
+
+    fn key(&self, key: &LimitKey) -> String {
+        format!("{}:{key}", self.key_prefix)
+    }
+
+    /// Count one request against `key`'s shared bucket.
+    pub(crate) async fn check(
+        &self,
+        gcra: &Gcra,
+        key: &LimitKey,
+        nodelay: bool,
+    ) -> redis::RedisResult<Decision> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut conn = self.connection.get().await?;
+        let (admitted, tat): (u8, u64) = self
+            .script
+            .key(self.key(key))
+            .arg(now)
+            .arg(gcra.interval)
+            .arg(gcra.tolerance)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(gcra.decision(tat, now, admitted == 1, nodelay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedLimiter;
+    use crate::gcra::{Decision, Gcra};
+    use crate::{LimitKey, RateLimitStoreConfig, StoreFailureMode};
+    use mlua::{Lua, MultiValue, Value};
+    use ngxora_redis::test_support::{Keyspace, StandIn, bulk};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    /// Stand-in that also runs scripts in Lua 5.1, the version Redis
+    /// embeds, with `redis.call` backed by an in-memory keyspace. Scripts
+    /// must be loaded first, so `EVALSHA` fails with `NOSCRIPT` until the
+    /// client sends `SCRIPT LOAD`, as on a fresh server.
+    async fn spawn_resp_stand_in() -> (String, Keyspace) {
+        let scripts: Mutex<HashMap<String, String>> = Mutex::default();
+        let stand_in = StandIn::spawn_with(move |keyspace, args| {
+            match String::from_utf8_lossy(&args[0])
+                .to_ascii_uppercase()
+                .as_str()
+            {
+                "SCRIPT" => {
+                    let body = String::from_utf8_lossy(&args[2]).into_owned();
+                    let hash = redis::Script::new(&body).get_hash().to_string();
+                    scripts.lock().unwrap().insert(hash.clone(), body);
+                    Some(bulk(hash.as_bytes()))
+                }
+                "EVALSHA" => {
+                    let sha = String::from_utf8_lossy(&args[1]).into_owned();
+                    let body = scripts.lock().unwrap().get(&sha).cloned();
+                    Some(match body {
+                        Some(body) => eval(keyspace, &body, &args[2..]),
+                        None => b"-NOSCRIPT No matching script\r\n".to_vec(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .await;
+        (stand_in.url, stand_in.keyspace)
+    }
+
+    // Redis formats Lua numbers passed to `redis.call` with `%.17g`.
+    fn redis_arg(value: &Value) -> Vec<u8> {
+        match value {
+            Value::Integer(value) => value.to_string().into_bytes(),
+            Value::Number(value) if value.fract() == 0.0 => {
+                (*value as i64).to_string().into_bytes()
+            }
+            Value::Number(value) => value.to_string().into_bytes(),
+            Value::String(value) => value.as_bytes().to_vec(),
+            other => panic!("unsupported redis.call argument {other:?}"),
+        }
+    }
+
+    /// Run `EVALSHA <sha> <numkeys> <keys..> <args..>` after the sha.
+    fn eval(keyspace: &Keyspace, body: &str, args: &[Vec<u8>]) -> Vec<u8> {
+        let numkeys: usize = String::from_utf8_lossy(&args[0]).parse().unwrap();
+        let (keys, argv) = args[1..].split_at(numkeys);
+        let lua = Lua::new();
+        let table = |items: &[Vec<u8>]| {
+            let table = lua.create_table().unwrap();
+            for (index, item) in items.iter().enumerate() {
+                table
+                    .set(index + 1, lua.create_string(item).unwrap())
+                    .unwrap();
+            }
+            table
+        };
+        lua.globals().set("KEYS", table(keys)).unwrap();
+        lua.globals().set("ARGV", table(argv)).unwrap();
+
+        let store = Arc::clone(keyspace);
+        let call = lua
+            .create_function(move |lua, args: MultiValue| {
+                let args: Vec<Vec<u8>> = args.iter().map(redis_arg).collect();
+                let mut keys = store.lock().unwrap();
+                keys.retain(|_, (_, expires)| expires.is_none_or(|at| at > Instant::now()));
+                match String::from_utf8_lossy(&args[0])
+                    .to_ascii_uppercase()
+                    .as_str()
+                {
+                    "GET" => match keys.get(&args[1]) {
+                        Some((value, _)) => Ok(Value::String(lua.create_string(value)?)),
+                        None => Ok(Value::Boolean(false)),
+                    },
+                    "SET" => {
+                        let expires = match args.get(3).map(|arg| arg.to_ascii_uppercase()) {
+                            Some(option) if option == b"PX" => {
+                                let ms: u64 =
+                                    String::from_utf8_lossy(&args[4]).parse().map_err(|_| {
+                                        mlua::Error::RuntimeError(
+                                            "ERR value is not an integer or out of range".into(),
+                                        )
+                                    })?;
+                                Some(Instant::now() + Duration::from_millis(ms))
+                            }
+                            _ => None,
+                        };
+                        keys.insert(args[1].clone(), (args[2].clone(), expires));
+                        let ok = lua.create_table()?;
+                        ok.set("ok", "OK")?;
+                        Ok(Value::Table(ok))
+                    }
+                    other => Err(mlua::Error::RuntimeError(format!("unsupported {other}"))),
+                }
+            })
+            .unwrap();
+        let redis = lua.create_table().unwrap();
+        redis.set("call", call).unwrap();
+        lua.globals().set("redis", redis).unwrap();
+
+        let result: Value = match lua.load(body).eval() {
+            Ok(result) => result,
+            Err(err) => {
+                let message = err.to_string().replace(['\r', '\n'], " ");
+                return format!("-ERR {message}\r\n").into_bytes();
+            }
+        };
+        // Lua tables become arrays and numbers are truncated to integers,
+        // as in Redis' Lua-to-RESP conversion.
+        let Value::Table(result) = result else {
+            panic!("GCRA script returns a table");
+        };
+        let items: Vec<Value> = result.sequence_values().map(Result::unwrap).collect();
+        let mut out = format!("*{}\r\n", items.len()).into_bytes();
+        for item in items {
+            let number = match item {
+                Value::Integer(value) => value,
+                Value::Number(value) => value as i64,
+                other => panic!("unexpected script result {other:?}"),
+            };
+            out.extend_from_slice(format!(":{number}\r\n").as_bytes());
+        }
+        out
+    }
+
+    fn store_config(redis_url: &str, key_prefix: Option<&str>) -> RateLimitStoreConfig {
+        RateLimitStoreConfig {
+            redis_url: redis_url.to_string(),
+            key_prefix: key_prefix.map(str::to_string),
+            on_error: StoreFailureMode::Open,
+            timeout_ms: None,
+        }
+    }
+
+    #[test]
+    fn new_validates_store_config() {
+        assert!(SharedLimiter::new(&store_config("not a url", None)).is_err());
+        assert!(SharedLimiter::new(&store_config("redis://127.0.0.1", Some(""))).is_err());
+        assert!(SharedLimiter::new(&store_config("redis://127.0.0.1", Some("a b"))).is_err());
+
+        let mut config = store_config("redis://127.0.0.1", None);
+        config.timeout_ms = Some(0);
+        assert!(SharedLimiter::new(&config).is_err());
+
+        let limiter = SharedLimiter::new(&store_config("redis://127.0.0.1", None)).unwrap();
+        let key = LimitKey::Ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)));
+        assert_eq!(limiter.key(&key), "ngxora:ratelimit:ip:203.0.113.1");
+    }
+
+    #[tokio::test]
+    async fn replicas_share_one_bucket_per_key() {
+        let (url, keyspace) = spawn_resp_stand_in().await;
+        let first = SharedLimiter::new(&store_config(&url, Some("edge"))).unwrap();
+        let second = SharedLimiter::new(&store_config(&url, Some("edge"))).unwrap();
+        let gcra = Gcra::new(1, Duration::from_secs(60), 1);
+        let client = LimitKey::Ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 2)));
+        let other = LimitKey::Ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 3)));
+
+        let decisions = [
+            first.check(&gcra, &client, true).await.unwrap(),
+            second.check(&gcra, &client, true).await.unwrap(),
+            second.check(&gcra, &client, true).await.unwrap(),
+        ];
+        assert!(matches!(decisions[0], Decision::Allow { remaining: 1, .. }));
+        assert!(matches!(decisions[1], Decision::Allow { remaining: 0, .. }));
+        assert!(matches!(decisions[2], Decision::Reject { .. }));
+        assert!(matches!(
+            first.check(&gcra, &other, true).await.unwrap(),
+            Decision::Allow { .. }
+        ));
+
+        let keys = keyspace.lock().unwrap();
+        let mut names: Vec<String> = keys
+            .keys()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["edge:ip:203.0.113.2", "edge:ip:203.0.113.3"]);
+
+        // Two admitted requests put the arrival time two intervals ahead; the
+        // key lives until then plus one second.
+        let (_, expires) = keys[b"edge:ip:203.0.113.2".as_slice()];
+        let ttl = expires.expect("PX expiry") - Instant::now();
+        assert!(ttl > Duration::from_secs(120) && ttl <= Duration::from_secs(121));
+    }
+
+    #[tokio::test]
+    async fn unreachable_store_reports_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let limiter = SharedLimiter::new(&store_config(&format!("redis://{addr}"), None)).unwrap();
+        let gcra = Gcra::new(1, Duration::from_secs(1), 0);
+        let client = LimitKey::Ip(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 4)));
+        assert!(limiter.check(&gcra, &client, true).await.is_err());
+        // The second attempt is refused by the backoff without a connect.
+        assert!(limiter.check(&gcra, &client, true).await.is_err());
+    }
+}
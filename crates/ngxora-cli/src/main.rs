@@ -1,17 +1,23 @@
 use ngxora_compile::ir::Ir;
-use ngxora_config::{Ast, include::IncludeResolver};
+use ngxora_config::diagnostic::render_all;
+use ngxora_config::include::{IncludeResolver, IncludeSources};
+use ngxora_config::{Ast, Diagnostic, ParseError};
+use ngxora_runtime::access_log::AccessLogReopener;
+use ngxora_runtime::cache::{CacheBackend, DEFAULT_CACHE_MAX_SIZE};
 use ngxora_runtime::control::{
     ConfigSnapshot, InProcessControlPlane, RuntimeState, RuntimeUpstreamHealthChecks,
 };
 use ngxora_runtime::grpc::{spawn_control_plane, spawn_control_plane_uds};
 use ngxora_runtime::le::{self, LeReconcilerService};
-use ngxora_runtime::metrics::spawn_metrics_service_with_state;
-use ngxora_runtime::server::bind_listeners_from_state;
+use ngxora_runtime::metrics::spawn_metrics_service_with_cache;
+use ngxora_runtime::reload::{ConfigReloader, LoadedConfig};
+use ngxora_runtime::server::{bind_listeners_from_state, proxy_protocol_service_from_state};
 use ngxora_runtime::upstreams::{CompiledRouter, DynamicProxy};
 use pingora::server::Server;
 use pingora::server::configuration::Opt;
 use pingora::services::background::background_service;
 use std::env;
+use std::ffi::OsString;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
@@ -20,12 +26,14 @@ use std::sync::Arc;
 struct CliArgs {
     config_path: PathBuf,
     check_only: bool,
+    watch: bool,
     grpc_addr: Option<SocketAddr>,
     grpc_uds: Option<PathBuf>,
     metrics_addr: Option<SocketAddr>,
     otel_endpoint: Option<String>,
     unsafe_grpc_listen: bool,
     unsafe_admin_listen: bool,
+    admin_write: bool,
 }
 
 fn main() -> ExitCode {
@@ -37,7 +45,23 @@ fn main() -> ExitCode {
         .install_default()
         .expect("failed to install rustls crypto provider");
 
-    let cli = match parse_cli_args(env::args_os()) {
+    let args: Vec<OsString> = env::args_os().collect();
+    let subcommand = match args.get(1).map(|arg| arg.to_string_lossy()).as_deref() {
+        Some("fmt") => Some(run_fmt(&args[2..])),
+        Some("dump-config") => Some(run_dump_config(&args[2..])),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        return match result {
+            Ok(code) => code,
+            Err(message) => {
+                eprintln!("{message}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let cli = match parse_cli_args(args) {
         Ok(Some(cli)) => cli,
         Ok(None) => return ExitCode::SUCCESS,
         Err(message) => {
@@ -58,7 +82,7 @@ fn main() -> ExitCode {
 }
 
 fn run(cli: CliArgs) -> Result<(), String> {
-    let router = load_router(&cli.config_path)?;
+    let LoadedConfig { router, sources } = load_router(&cli.config_path)?;
     let version = format!("file:{}", cli.config_path.display());
     let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(version, router)));
     let control = InProcessControlPlane::new(Arc::clone(&state));
@@ -84,7 +108,16 @@ fn run(cli: CliArgs) -> Result<(), String> {
         println!("OpenTelemetry tracing enabled, exporting to {endpoint}");
     }
 
-    let mut dynamic_proxy = DynamicProxy::new(Arc::clone(control.state()));
+    // `proxy_cache_store` is read once here; changing it requires a restart.
+    let cache_backend = CacheBackend::from_config(
+        &snapshot.router.http_options.proxy_cache_store,
+        DEFAULT_CACHE_MAX_SIZE,
+    )
+    .map_err(|err| format!("failed to configure proxy_cache_store: {err}"))?;
+    let mut dynamic_proxy =
+        DynamicProxy::new_with_cache(Arc::clone(control.state()), cache_backend);
+    let cache = Arc::clone(dynamic_proxy.cache_backend());
+    let control = control.with_cache(Arc::clone(&cache));
 
     // Shared token store for Let's Encrypt HTTP-01 challenges.
     let le_tokens: le::ChallengeTokens = Arc::new(dashmap::DashMap::new());
@@ -98,6 +131,36 @@ fn run(cli: CliArgs) -> Result<(), String> {
     );
     server.add_service(le_service);
 
+    // SIGHUP always reloads the text config; `--watch` also reloads on edits.
+    let reloader = background_service(
+        "config reloader",
+        ConfigReloader::new(
+            Arc::clone(&state),
+            cli.config_path.clone(),
+            Arc::new(load_router),
+        )
+        .with_watch(cli.watch)
+        .with_sources(sources),
+    );
+    server.add_service(reloader);
+
+    // SIGUSR1 reopens access log files after rotation.
+    server.add_service(background_service("access log reopener", AccessLogReopener));
+
+    // `listen ... proxy_protocol` sockets run their own accept loop in front of
+    // a clone of the proxy that shares its state and cache.
+    let proxy_protocol = proxy_protocol_service_from_state(
+        &server.configuration,
+        dynamic_proxy.clone(),
+        Arc::clone(control.state()),
+    )
+    .map_err(|err| format!("failed to bind PROXY protocol listeners: {err}"))?
+    .map(|service| {
+        let mut service = background_service("proxy protocol listeners", service);
+        service.threads = Some(server.configuration.threads);
+        service
+    });
+
     let mut proxy = pingora_proxy::http_proxy_service(&server.configuration, dynamic_proxy);
     let upstream_health_checks = background_service(
         "upstream health checks",
@@ -133,43 +196,117 @@ fn run(cli: CliArgs) -> Result<(), String> {
                 "WARNING: unauthenticated admin HTTP is exposed on non-loopback address {addr}"
             );
         }
-        spawn_metrics_service_with_state(&mut server, addr, Arc::clone(&state))
-            .map_err(|err| format!("failed to spawn metrics service: {err}"))?;
-        println!("Prometheus metrics listening on {addr}");
+        spawn_metrics_service_with_cache(
+            &mut server,
+            addr,
+            Arc::clone(&state),
+            cache,
+            cli.admin_write,
+        )
+        .map_err(|err| format!("failed to spawn metrics service: {err}"))?;
+        println!("Prometheus metrics and admin API listening on {addr}");
     }
 
     server.add_service(proxy);
+    if let Some(proxy_protocol) = proxy_protocol {
+        server.add_service(proxy_protocol);
+    }
     server.add_service(upstream_health_checks);
     server.run_forever();
 }
 
-fn load_router(path: &Path) -> Result<CompiledRouter, String> {
-    let text = std::fs::read_to_string(path)
-        .map_err(|err| format!("failed to read config {}: {err}", path.display()))?;
-    let ast = Ast::parse_config(&text)
-        .map_err(|err| format!("failed to parse config {}: {}", path.display(), err.message))?;
+/// `ngxora fmt [--check] <config-path>...`: rewrites each file in the
+/// canonical layout. With `--check`, lists the files that would change and
+/// fails instead.
+fn run_fmt(args: &[OsString]) -> Result<ExitCode, String> {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.to_string_lossy().as_ref() {
+            "--check" => check = true,
+            value if value.starts_with('-') => return Err(format!("unknown flag: {value}")),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        return Err("fmt: missing config path".into());
+    }
+
+    let mut unformatted = false;
+    for path in &paths {
+        let text = read_config(path)?;
+        let formatted = Ast::parse_config_all(&text, path)
+            .map_err(render_parse_errors)?
+            .to_string();
+        if formatted == text {
+            continue;
+        }
+        if check {
+            println!("{}", path.display());
+            unformatted = true;
+        } else {
+            std::fs::write(path, formatted)
+                .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+        }
+    }
+
+    Ok(if unformatted {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// `ngxora dump-config <config-path>`: prints the config with every
+/// `include` expanded.
+fn run_dump_config(args: &[OsString]) -> Result<ExitCode, String> {
+    let [path] = args else {
+        return Err("dump-config: expected exactly one config path".into());
+    };
+    print!("{}", resolve_config(Path::new(path))?.0);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn read_config(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map_err(|err| format!("failed to read config {}: {err}", path.display()))
+}
+
+/// Parses the config at `path` and expands its includes.
+fn resolve_config(path: &Path) -> Result<(Ast, IncludeSources), String> {
+    let text = read_config(path)?;
+    let ast = Ast::parse_config_all(&text, path).map_err(render_parse_errors)?;
     let root_dir = path
         .parent()
         .map(std::path::Path::to_path_buf)
         .unwrap_or_else(|| PathBuf::from("."));
-    let ast = IncludeResolver::new(&ast, root_dir)
-        .resolve(&ast)
-        .map_err(|err| {
-            format!(
-                "failed to resolve includes in {}: {}",
-                path.display(),
-                err.message
-            )
-        })?;
-    let ir = Ir::from_ast(&ast)
-        .map_err(|err| format!("failed to lower config {}: {}", path.display(), err.message))?;
-    ir.validate().map_err(|err| {
-        format!(
-            "failed to validate config {}: {}",
-            path.display(),
-            err.message
-        )
+    IncludeResolver::new(&ast, root_dir)
+        .resolve_with_sources(&ast)
+        .map_err(|err| render_all(&[Diagnostic::from(err)]))
+}
+
+fn render_parse_errors(errors: Vec<ParseError>) -> String {
+    render_all(&errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())
+}
+
+fn load_router(path: &Path) -> Result<LoadedConfig, String> {
+    // Every syntax error in the main file is reported, skipping past each
+    // broken statement; include errors, including syntax errors inside an
+    // included file, stop at the first one. Lowering and validation only
+    // run on a config that parsed, and report everything they find.
+    let (ast, sources) = resolve_config(path)?;
+    let ir = Ir::lower_ast(&ast).map_err(|errors| {
+        render_all(&errors.into_iter().map(Diagnostic::from).collect::<Vec<_>>())
     })?;
+    let errors = ir.validate_all();
+    if !errors.is_empty() {
+        return Err(render_all(
+            &errors
+                .into_iter()
+                .map(|err| err.into_diagnostic(&ast))
+                .collect::<Vec<_>>(),
+        ));
+    }
     let http = ir
         .http
         .ok_or_else(|| format!("config {} does not contain an http block", path.display()))?;
@@ -181,34 +318,40 @@ fn load_router(path: &Path) -> Result<CompiledRouter, String> {
         ));
     }
 
-    CompiledRouter::from_http(&http).map_err(|err| {
+    let router = CompiledRouter::from_http(&http).map_err(|err| {
         format!(
             "failed to compile router from config {}: {err}",
             path.display()
         )
-    })
+    })?;
+
+    Ok(LoadedConfig { router, sources })
 }
 
 fn parse_cli_args<I, T>(args: I) -> Result<Option<CliArgs>, String>
 where
     I: IntoIterator<Item = T>,
-    T: Into<std::ffi::OsString>,
+    T: Into<OsString>,
 {
     let mut config_path: Option<PathBuf> = None;
     let mut check_only = false;
+    let mut watch = false;
     let mut grpc_addr: Option<SocketAddr> = None;
     let mut grpc_uds: Option<PathBuf> = None;
     let mut metrics_addr: Option<SocketAddr> = None;
     let mut otel_endpoint: Option<String> = None;
     let mut unsafe_grpc_listen = false;
     let mut unsafe_admin_listen = false;
+    let mut admin_write = false;
 
     let mut args = args.into_iter().skip(1).map(Into::into);
     while let Some(arg) = args.next() {
         match arg.to_string_lossy().as_ref() {
             "--check" => check_only = true,
+            "--watch" => watch = true,
             "--unsafe-grpc-listen" => unsafe_grpc_listen = true,
             "--unsafe-admin-listen" => unsafe_admin_listen = true,
+            "--admin-write" => admin_write = true,
             "--grpc-addr" => {
                 let value = args
                     .next()
@@ -276,6 +419,9 @@ where
     if unsafe_admin_listen && metrics_addr.is_none() {
         return Err("--unsafe-admin-listen requires --metrics-addr".into());
     }
+    if admin_write && metrics_addr.is_none() {
+        return Err("--admin-write requires --metrics-addr".into());
+    }
     if grpc_addr.is_some_and(|addr| !addr.ip().is_loopback()) && !unsafe_grpc_listen {
         return Err("non-loopback --grpc-addr requires explicit --unsafe-grpc-listen".into());
     }
@@ -286,17 +432,21 @@ where
     Ok(Some(CliArgs {
         config_path,
         check_only,
+        watch,
         grpc_addr,
         grpc_uds,
         metrics_addr,
         otel_endpoint,
         unsafe_grpc_listen,
         unsafe_admin_listen,
+        admin_write,
     }))
 }
 
 fn print_usage() {
     eprintln!(
-        "Usage: ngxora [--check] [--metrics-addr <host:port> [--unsafe-admin-listen]] [--otel-endpoint <url>] [--grpc-addr <host:port> [--unsafe-grpc-listen] | --grpc-uds <path>] <config-path>"
+        "Usage: ngxora [--check] [--watch] [--metrics-addr <host:port> [--unsafe-admin-listen] [--admin-write]] [--otel-endpoint <url>] [--grpc-addr <host:port> [--unsafe-grpc-listen] | --grpc-uds <path>] <config-path>"
     );
+    eprintln!("       ngxora fmt [--check] <config-path>...");
+    eprintln!("       ngxora dump-config <config-path>");
 }
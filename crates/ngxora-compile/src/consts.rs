@@ -7,6 +7,12 @@ pub const BASIC_AUTH: &str = "basic-auth";
 pub const BASIC_AUTH_ALIAS: &str = "basic_auth";
 pub const RATE_LIMIT: &str = "rate-limit";
 pub const RATE: &str = "rate";
+pub const BURST: &str = "burst";
+pub const NODELAY: &str = "nodelay";
+pub const KEY: &str = "key";
+pub const STORE: &str = "store";
+pub const STORE_FAILURE: &str = "store_failure";
+pub const STORE_TIMEOUT: &str = "store_timeout";
 
 pub const CORS: &str = "cors";
 pub const ALLOW_ORIGIN: &str = "allow_origin";
@@ -29,6 +35,11 @@ pub const SECRET_FILE: &str = "secret_file";
 pub const LISTEN: &str = "listen";
 pub const SERVER_NAME: &str = "server_name";
 pub const POLICY: &str = "policy";
+pub const LEAST_CONN: &str = "least_conn";
+pub const HASH: &str = "hash";
+pub const KEEPALIVE: &str = "keepalive";
+pub const KEEPALIVE_CONNS: &str = "keepalive_conns";
+pub const QUEUE: &str = "queue";
 pub const HEALTH_CHECK: &str = "health_check";
 pub const TYPE: &str = "type";
 pub const TIMEOUT: &str = "timeout";
@@ -38,6 +49,16 @@ pub const CONSECUTIVE_FAILURE: &str = "consecutive_failure";
 pub const HOST: &str = "host";
 pub const PATH: &str = "path";
 pub const USE_TLS: &str = "use_tls";
+pub const PORT: &str = "port";
+pub const METHOD: &str = "method";
+pub const HEADER: &str = "header";
+pub const STATUS: &str = "status";
+pub const BODY: &str = "body";
+pub const SERVICE: &str = "service";
+pub const OUTLIER_DETECTION: &str = "outlier_detection";
+pub const BASE_EJECTION_TIME: &str = "base_ejection_time";
+pub const MAX_EJECTION_TIME: &str = "max_ejection_time";
+pub const MAX_EJECTION_PERCENT: &str = "max_ejection_percent";
 
 // Inner directives in blocs
 pub const TCP_NODELAY: &str = "tcp_nodelay";
@@ -46,22 +67,38 @@ pub const KEEPALIVE_REQUESTS: &str = "keepalive_requests";
 pub const CLIENT_MAX_BODY_SIZE: &str = "client_max_body_size";
 pub const ALLOW_CONNECT_METHOD_PROXYING: &str = "allow_connect_method_proxying";
 pub const H2C: &str = "h2c";
+pub const METRICS_LABEL_LIMIT: &str = "metrics_label_limit";
+pub const LOG_FORMAT: &str = "log_format";
+pub const ACCESS_LOG: &str = "access_log";
+pub const REQUEST_ID: &str = "request_id";
 pub const HTTP2: &str = "http2";
 pub const HTTP2_ONLY: &str = "http2_only";
+pub const PROXY_PROTOCOL: &str = "proxy_protocol";
+pub const PROXY_PROTOCOL_TRUSTED: &str = "proxy_protocol_trusted";
 
 // Listener directives
 pub const PROXY_PASS: &str = "proxy_pass";
 pub const RETURN: &str = "return";
+pub const ROOT: &str = "root";
+pub const ALIAS: &str = "alias";
+pub const INDEX: &str = "index";
+pub const TRY_FILES: &str = "try_files";
+pub const ROUTE_NAME: &str = "route_name";
 pub const PROXY_CONNECT_TIMEOUT: &str = "proxy_connect_timeout";
 pub const PROXY_READ_TIMEOUT: &str = "proxy_read_timeout";
 pub const PROXY_WRITE_TIMEOUT: &str = "proxy_write_timeout";
 pub const PROXY_UPSTREAM_PROTOCOL: &str = "proxy_upstream_protocol";
+pub const PROXY_NEXT_UPSTREAM: &str = "proxy_next_upstream";
+pub const PROXY_NEXT_UPSTREAM_TRIES: &str = "proxy_next_upstream_tries";
+pub const PROXY_NEXT_UPSTREAM_TIMEOUT: &str = "proxy_next_upstream_timeout";
 pub const PROXY_SSL_VERIFY: &str = "proxy_ssl_verify";
 pub const PROXY_SSL_TRUSTED_CERTIFICATE: &str = "proxy_ssl_trusted_certificate";
 pub const PROXY_SSL_CERTIFICATE: &str = "proxy_ssl_certificate";
 pub const PROXY_SSL_CERTIFICATE_KEY: &str = "proxy_ssl_certificate_key";
 pub const PROXY_CACHE: &str = "proxy_cache";
 pub const PROXY_CACHE_MAX_SIZE: &str = "proxy_cache_max_size";
+pub const PROXY_CACHE_STORE: &str = "proxy_cache_store";
+pub const RESOLVER: &str = "resolver";
 pub const PROXY_CACHE_TTL: &str = "proxy_cache_ttl";
 pub const PROXY_CACHE_STALE_IF_ERROR: &str = "proxy_cache_stale_if_error";
 pub const PROXY_CACHE_KEY: &str = "proxy_cache_key";
@@ -95,6 +132,149 @@ pub const SSL_CLIENT_CERTIFICATE: &str = "ssl_client_certificate";
 // SSL Provider (Let's Encrypt / ACME)
 pub const SSL_PROVIDER: &str = "ssl_provider";
 pub const LETSENCRYPT: &str = "letsencrypt";
+pub const ACME: &str = "acme";
 pub const ACME_DIRECTORY: &str = "acme_directory";
 pub const EMAIL: &str = "email";
 pub const CACHE_DIR: &str = "cache_dir";
+pub const CHALLENGE: &str = "challenge";
+pub const DNS_PROVIDER: &str = "dns_provider";
+pub const DNS_ZONE: &str = "dns_zone";
+pub const DNS_TSIG_KEY: &str = "dns_tsig_key";
+pub const DNS_PROPAGATION_TIMEOUT: &str = "dns_propagation_timeout";
+pub const RFC2136: &str = "rfc2136";
+pub const EXTERNAL_ACCOUNT_BINDING: &str = "external_account_binding";
+pub const KEY_TYPE: &str = "key_type";
+pub const SSL_TRUSTED_CERTIFICATE: &str = "ssl_trusted_certificate";
+pub const ACME_CERTIFICATE: &str = "acme_certificate";
+
+/// Every directive and block name, for "did you mean" suggestions.
+pub const DIRECTIVE_NAMES: &[&str] = &[
+    HTTP,
+    SERVER,
+    UPSTREAM,
+    LOCATION,
+    HEADERS,
+    BASIC_AUTH,
+    BASIC_AUTH_ALIAS,
+    RATE_LIMIT,
+    RATE,
+    BURST,
+    NODELAY,
+    KEY,
+    STORE,
+    STORE_FAILURE,
+    STORE_TIMEOUT,
+    CORS,
+    ALLOW_ORIGIN,
+    ALLOW_METHODS,
+    ALLOW_HEADERS,
+    EXPOSE_HEADERS,
+    ALLOW_CREDENTIALS,
+    MAX_AGE,
+    EXT_AUTHZ,
+    URI,
+    PASS_REQUEST_HEADER,
+    PASS_RESPONSE_HEADER,
+    JWT_AUTH,
+    ALGORITHM,
+    SECRET,
+    SECRET_FILE,
+    LISTEN,
+    SERVER_NAME,
+    POLICY,
+    LEAST_CONN,
+    HASH,
+    KEEPALIVE,
+    KEEPALIVE_CONNS,
+    QUEUE,
+    HEALTH_CHECK,
+    TYPE,
+    TIMEOUT,
+    INTERVAL,
+    CONSECUTIVE_SUCCESS,
+    CONSECUTIVE_FAILURE,
+    HOST,
+    PATH,
+    USE_TLS,
+    PORT,
+    METHOD,
+    HEADER,
+    STATUS,
+    BODY,
+    SERVICE,
+    OUTLIER_DETECTION,
+    BASE_EJECTION_TIME,
+    MAX_EJECTION_TIME,
+    MAX_EJECTION_PERCENT,
+    TCP_NODELAY,
+    KEEPALIVE_TIMEOUT,
+    KEEPALIVE_REQUESTS,
+    CLIENT_MAX_BODY_SIZE,
+    ALLOW_CONNECT_METHOD_PROXYING,
+    H2C,
+    METRICS_LABEL_LIMIT,
+    LOG_FORMAT,
+    ACCESS_LOG,
+    REQUEST_ID,
+    PROXY_PASS,
+    RETURN,
+    ROOT,
+    ALIAS,
+    INDEX,
+    TRY_FILES,
+    ROUTE_NAME,
+    PROXY_CONNECT_TIMEOUT,
+    PROXY_READ_TIMEOUT,
+    PROXY_WRITE_TIMEOUT,
+    PROXY_UPSTREAM_PROTOCOL,
+    PROXY_NEXT_UPSTREAM,
+    PROXY_NEXT_UPSTREAM_TRIES,
+    PROXY_NEXT_UPSTREAM_TIMEOUT,
+    PROXY_SSL_VERIFY,
+    PROXY_SSL_TRUSTED_CERTIFICATE,
+    PROXY_SSL_CERTIFICATE,
+    PROXY_SSL_CERTIFICATE_KEY,
+    PROXY_CACHE,
+    PROXY_CACHE_MAX_SIZE,
+    PROXY_CACHE_STORE,
+    RESOLVER,
+    PROXY_CACHE_TTL,
+    PROXY_CACHE_STALE_IF_ERROR,
+    PROXY_CACHE_KEY,
+    PROXY_CACHE_MIN_USES,
+    PROXY_CACHE_VALID,
+    REQUEST_ADD,
+    REQUEST_SET,
+    REQUEST_REMOVE,
+    UPSTREAM_REQUEST_ADD,
+    UPSTREAM_REQUEST_SET,
+    UPSTREAM_REQUEST_REMOVE,
+    RESPONSE_ADD,
+    RESPONSE_SET,
+    RESPONSE_REMOVE,
+    FORWARD_CLIENT_IP,
+    TRUSTED_PROXY,
+    ALLOW,
+    DENY,
+    USERNAME,
+    PASSWORD,
+    REALM,
+    SSL_CERTIFICATE,
+    SSL_CERTIFICATE_KEY,
+    SSL_PROTOCOLS,
+    SSL_VERIFY_CLIENT,
+    SSL_CLIENT_CERTIFICATE,
+    SSL_PROVIDER,
+    ACME_DIRECTORY,
+    EMAIL,
+    CACHE_DIR,
+    CHALLENGE,
+    DNS_PROVIDER,
+    DNS_ZONE,
+    DNS_TSIG_KEY,
+    DNS_PROPAGATION_TIMEOUT,
+    EXTERNAL_ACCOUNT_BINDING,
+    KEY_TYPE,
+    SSL_TRUSTED_CERTIFICATE,
+    ACME_CERTIFICATE,
+];
@@ -1,33 +1,275 @@
 use ngxora_plugin_api::PluginSpec;
-use std::fmt::Error;
+use std::fmt::{self, Display, Error};
 // Intermediate Representation layer
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
 use ipnet::IpNet;
 
 use url::Url;
 
+use crate::variables::Template;
+
 /// How the server obtains its TLS certificate.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SslProvider {
     /// Manually provided certificate and key paths (ssl_certificate / ssl_certificate_key).
     Custom(TlsIdentity),
-    /// Automatically issued and renewed via ACME (Let's Encrypt).
-    LetsEncrypt,
+    /// Automatically issued and renewed via ACME by the named issuers, tried
+    /// in order until one succeeds.
+    Acme(Vec<String>),
 }
 
-/// Global Let's Encrypt / ACME configuration declared inside `ssl_provider letsencrypt { ... }`.
+/// Issuer name of the `ssl_provider letsencrypt { ... }` block.
+pub const LETSENCRYPT_ISSUER: &str = "letsencrypt";
+
+/// Default directory for ACME accounts and issued certificates.
+pub const DEFAULT_ACME_CACHE_DIR: &str = "/var/lib/ngxora/certs";
+
+/// How long a DNS-01 record may take to reach every authoritative server.
+pub const DEFAULT_DNS_PROPAGATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An ACME issuer declared by `ssl_provider letsencrypt { ... }` or
+/// `ssl_provider acme <name> { ... }`.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct LetsEncryptConfig {
+pub struct AcmeIssuer {
+    /// Name servers refer to in `acme_certificate`.
+    pub name: String,
     /// ACME directory URL.  If omitted the Let's Encrypt production endpoint is used.
     pub acme_directory: Option<String>,
-    /// Contact email registered with the ACME account (required).
+    /// Contact email registered with the ACME account.
     pub email: Option<String>,
     /// Directory where obtained certificates are stored on disk.
     /// Default: `/var/lib/ngxora/certs`.
     pub cache_dir: Option<PathBuf>,
+    /// How domain control is proven to the ACME server.
+    pub challenge: AcmeChallenge,
+    /// External Account Binding required by CAs such as ZeroSSL.
+    pub external_account_binding: Option<ExternalAccountBinding>,
+    /// Key type of issued certificates.
+    pub key_type: AcmeKeyType,
+    /// CA bundle trusted for the directory's HTTPS endpoint, for private CAs
+    /// such as step-ca or Pebble.
+    pub trusted_certificate: Option<PathBuf>,
+}
+
+impl AcmeIssuer {
+    /// An issuer with every optional setting left at its default.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            acme_directory: None,
+            email: None,
+            cache_dir: None,
+            challenge: AcmeChallenge::Http01,
+            external_account_binding: None,
+            key_type: AcmeKeyType::Ecdsa,
+            trusted_certificate: None,
+        }
+    }
+
+    pub fn cache_dir_or_default(&self) -> PathBuf {
+        self.cache_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ACME_CACHE_DIR))
+    }
+
+    /// Issuer names end up in account file names, so they are kept to
+    /// letters, digits, `-` and `_`.
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(format!(
+                "invalid ACME issuer name `{name}`, expected letters, digits, `-` or `_`"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `external_account_binding` inside `ssl_provider acme <name> { ... }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalAccountBinding {
+    pub key_id: String,
+    /// Base64url encoded HMAC key, as handed out by the CA.
+    pub hmac_key: String,
+}
+
+impl ExternalAccountBinding {
+    pub fn new(key_id: &str, hmac_key: &str) -> Result<Self, String> {
+        if key_id.is_empty() {
+            return Err("external account binding key ID must not be empty".into());
+        }
+        let is_base64url = !hmac_key.trim_end_matches('=').is_empty()
+            && hmac_key
+                .trim_end_matches('=')
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !is_base64url {
+            return Err(format!(
+                "external account binding `{key_id}` HMAC key must be base64url"
+            ));
+        }
+        Ok(Self {
+            key_id: key_id.to_string(),
+            hmac_key: hmac_key.to_string(),
+        })
+    }
+}
+
+/// `key_type` inside an ACME issuer block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AcmeKeyType {
+    /// ECDSA P-256.
+    #[default]
+    Ecdsa,
+    /// RSA 2048.
+    Rsa,
+}
+
+impl AcmeKeyType {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ecdsa => "ecdsa",
+            Self::Rsa => "rsa",
+        }
+    }
+}
+
+impl FromStr for AcmeKeyType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "ecdsa" => Ok(Self::Ecdsa),
+            "rsa" => Ok(Self::Rsa),
+            _ => Err(format!("unknown key type `{value}`, expected ecdsa or rsa")),
+        }
+    }
+}
+
+/// `challenge` inside an ACME issuer block.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum AcmeChallenge {
+    /// Token served at `/.well-known/acme-challenge/` on port 80.
+    #[default]
+    Http01,
+    /// Self-signed certificate served for the `acme-tls/1` ALPN on port 443.
+    TlsAlpn01,
+    /// TXT record at `_acme-challenge.<domain>`, published by a DNS provider.
+    Dns01(AcmeDnsProvider),
+}
+
+impl AcmeChallenge {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+            Self::Dns01(_) => "dns-01",
+        }
+    }
+}
+
+/// Where DNS-01 TXT records are published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AcmeDnsProvider {
+    /// RFC 2136 dynamic updates sent to an authoritative server.
+    Rfc2136(Rfc2136Provider),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rfc2136Provider {
+    /// Primary server accepting UPDATE messages.
+    pub server: SocketAddr,
+    /// Zone the `_acme-challenge` records are added to.
+    pub zone: String,
+    /// Key signing the updates; unsigned updates when `None`.
+    pub tsig_key: Option<TsigKey>,
+    /// How long to wait for the zone's name servers to serve a record.
+    pub propagation_timeout: Duration,
+}
+
+impl Rfc2136Provider {
+    /// Parses `ip`, `[ipv6]` or `ip:port`; the port defaults to 53.
+    pub fn parse_server(value: &str) -> Result<SocketAddr, String> {
+        value
+            .parse::<SocketAddr>()
+            .ok()
+            .or_else(|| {
+                let ip = value.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+                let ip = ip.unwrap_or(value).parse::<IpAddr>().ok()?;
+                Some(SocketAddr::new(ip, 53))
+            })
+            .ok_or_else(|| format!("invalid DNS server address `{value}`, expected ip[:port]"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TsigKey {
+    pub name: String,
+    pub algorithm: TsigAlgorithm,
+    /// Base64 encoded shared secret.
+    pub secret: String,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> Result<Self, String> {
+        if name.is_empty() {
+            return Err("TSIG key name must not be empty".into());
+        }
+        let is_base64 = !secret.is_empty()
+            && secret.len().is_multiple_of(4)
+            && secret
+                .trim_end_matches('=')
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+        if !is_base64 {
+            return Err(format!("TSIG key `{name}` secret must be base64"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            algorithm: algorithm.parse()?,
+            secret: secret.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha384,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha384 => "hmac-sha384",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+}
+
+impl FromStr for TsigAlgorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Ok(Self::HmacSha256),
+            "hmac-sha384" => Ok(Self::HmacSha384),
+            "hmac-sha512" => Ok(Self::HmacSha512),
+            _ => Err(format!(
+                "unknown TSIG algorithm `{value}`, expected hmac-sha256, hmac-sha384 or hmac-sha512"
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Default)]
@@ -44,10 +286,22 @@ pub struct Http {
     pub keepalive_requests: Option<u32>,
     pub client_max_body_size: Option<u64>,
     pub proxy_cache_max_size: Option<u64>,
+    pub proxy_cache_store: CacheStoreConfig,
     pub tcp_nodelay: Switch,
     pub allow_connect_method_proxying: Switch,
     pub h2c: Switch,
-    pub ssl_provider: Option<LetsEncryptConfig>,
+    /// ACME issuers in declaration order; the first is the default.
+    pub acme_issuers: Vec<AcmeIssuer>,
+    /// Name servers for upstream `server ... resolve;` entries.
+    pub resolver: Option<DnsResolver>,
+    /// Distinct values kept per metric label before new ones are reported
+    /// as `other`; `None` uses the built-in default.
+    pub metrics_label_limit: Option<usize>,
+    /// `log_format` definitions, in config order.
+    pub log_formats: Vec<LogFormat>,
+    /// `access_log` at http level; empty logs to stdout in the `default`
+    /// format.
+    pub access_logs: Vec<AccessLog>,
 }
 
 impl Default for Http {
@@ -59,10 +313,15 @@ impl Default for Http {
             keepalive_requests: None,
             client_max_body_size: None,
             proxy_cache_max_size: None,
+            proxy_cache_store: CacheStoreConfig::default(),
             tcp_nodelay: Switch::On,
             allow_connect_method_proxying: Switch::Off,
             h2c: Switch::Off,
-            ssl_provider: None,
+            acme_issuers: Vec::new(),
+            resolver: None,
+            metrics_label_limit: None,
+            log_formats: Vec::new(),
+            access_logs: Vec::new(),
         }
     }
 }
@@ -94,6 +353,10 @@ pub struct Server {
     /// `None` means the server does not require TLS (no `ssl` listener).
     pub tls: Option<SslProvider>,
     pub tls_options: DownstreamTlsOptions,
+    /// `access_log` at server level; empty inherits the http level.
+    pub access_logs: Vec<AccessLog>,
+    /// `request_id`; `None` leaves request IDs alone.
+    pub request_id: Option<RequestId>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -102,19 +365,539 @@ pub struct UpstreamBlock {
     pub policy: UpstreamSelectionPolicy,
     pub servers: Vec<UpstreamServer>,
     pub health_check: Option<UpstreamHealthCheck>,
+    pub outlier_detection: Option<UpstreamOutlierDetection>,
+    pub keepalive: UpstreamKeepalive,
+    pub queue: Option<UpstreamQueue>,
 }
 
+/// Connection reuse towards one upstream group. Unset fields keep Pingora's
+/// connection pool defaults.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct UpstreamKeepalive {
+    /// `keepalive_conns`: connections to the group kept open for reuse, busy
+    /// or idle.
+    pub connections: Option<usize>,
+    /// `keepalive_timeout`: how long a connection may stay idle in the pool.
+    pub timeout: Option<Duration>,
+    /// `keepalive_requests`: requests served over one connection before it
+    /// is closed.
+    pub requests: Option<usize>,
+}
+
+/// `queue`: requests that find every server at its `max_conns` wait for a
+/// free one instead of failing right away.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UpstreamQueue {
+    /// Requests allowed to wait at once.
+    pub size: usize,
+    pub timeout: Duration,
+}
+
+pub const DEFAULT_UPSTREAM_QUEUE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct UpstreamServer {
     pub host: String,
     pub port: u16,
+    /// Relative share of traffic, `1..=MAX_UPSTREAM_SERVER_WEIGHT`.
+    pub weight: u32,
+    /// `host` is a DNS name re-resolved at runtime; every address it
+    /// resolves to becomes its own backend.
+    pub resolve: bool,
+    /// In-flight requests the server takes at once; `None` is unlimited.
+    pub max_conns: Option<usize>,
+    /// Only used while no primary server is available.
+    pub backup: bool,
+    /// Drained: kept in the group but never selected.
+    pub down: bool,
+    /// Ramps the server's share of traffic up over this long after it
+    /// recovers from a failed health check.
+    pub slow_start: Option<Duration>,
+}
+
+pub const MAX_UPSTREAM_SERVER_WEIGHT: u32 = 100;
+
+/// `resolver` directive: where `resolve` upstream servers are looked up.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DnsResolver {
+    /// Name servers, tried in order.
+    pub addrs: Vec<SocketAddr>,
+    /// Overrides the record TTL as the re-resolution interval.
+    pub valid: Option<Duration>,
+}
+
+/// `log_format <name> json|text <field>...;`: the layout of an access log
+/// line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogFormat {
+    pub name: String,
+    pub encoding: LogEncoding,
+    pub fields: Vec<LogField>,
+}
+
+/// Name of the built-in format used when `access_log` names none.
+pub const DEFAULT_LOG_FORMAT: &str = "default";
+
+impl LogFormat {
+    /// The built-in `default` format: the JSON line written before
+    /// `log_format` existed.
+    pub fn builtin_default() -> Self {
+        Self {
+            name: DEFAULT_LOG_FORMAT.into(),
+            encoding: LogEncoding::Json,
+            fields: vec![
+                LogField::Method,
+                LogField::Path,
+                LogField::Status,
+                LogField::LatencySecs,
+                LogField::Upstream,
+                LogField::CacheStatus,
+                LogField::BytesSent,
+                LogField::ClientIp,
+                LogField::RouteId,
+                LogField::RequestId,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LogEncoding {
+    /// One JSON object per line; missing values are left out.
+    Json,
+    /// Space-separated values in field order; missing values are `-`.
+    Text,
+}
+
+impl LogEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Text => "text",
+        }
+    }
+}
+
+/// A value written to the access log. The config spelling doubles as the
+/// JSON key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LogField {
+    /// Request completion time, RFC 3339 in UTC.
+    Time,
+    Method,
+    Path,
+    Query,
+    /// `HTTP/1.1`, `HTTP/2.0`, ...
+    Protocol,
+    Scheme,
+    Host,
+    Status,
+    LatencySecs,
+    /// Response body bytes sent to the client.
+    BytesSent,
+    /// Request body bytes read from the client.
+    BytesReceived,
+    ClientIp,
+    /// `host:port` of the backend the request went to.
+    Upstream,
+    UpstreamStatus,
+    UpstreamConnectSecs,
+    UpstreamTtfbSecs,
+    CacheStatus,
+    TlsVersion,
+    TlsCipher,
+    /// `server_name` of the matched server.
+    Server,
+    /// `route_name`, or the location matcher.
+    Route,
+    RouteId,
+    RequestId,
+    /// `request_header:<name>`; the name is lowercase.
+    RequestHeader(String),
+    /// `response_header:<name>`; the name is lowercase.
+    ResponseHeader(String),
+}
+
+const LOG_FIELD_NAMES: &[(&str, LogField)] = &[
+    ("time", LogField::Time),
+    ("method", LogField::Method),
+    ("path", LogField::Path),
+    ("query", LogField::Query),
+    ("protocol", LogField::Protocol),
+    ("scheme", LogField::Scheme),
+    ("host", LogField::Host),
+    ("status", LogField::Status),
+    ("latency_secs", LogField::LatencySecs),
+    ("bytes_sent", LogField::BytesSent),
+    ("bytes_received", LogField::BytesReceived),
+    ("client_ip", LogField::ClientIp),
+    ("upstream", LogField::Upstream),
+    ("upstream_status", LogField::UpstreamStatus),
+    ("upstream_connect_secs", LogField::UpstreamConnectSecs),
+    ("upstream_ttfb_secs", LogField::UpstreamTtfbSecs),
+    ("cache_status", LogField::CacheStatus),
+    ("tls_version", LogField::TlsVersion),
+    ("tls_cipher", LogField::TlsCipher),
+    ("server", LogField::Server),
+    ("route", LogField::Route),
+    ("route_id", LogField::RouteId),
+    ("request_id", LogField::RequestId),
+];
+
+const REQUEST_HEADER_FIELD: &str = "request_header:";
+const RESPONSE_HEADER_FIELD: &str = "response_header:";
+
+impl FromStr for LogField {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let header = |name: &str| {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+                return Err(format!("invalid header name in log field `{value}`"));
+            }
+            Ok(name.to_ascii_lowercase())
+        };
+        if let Some(name) = value.strip_prefix(REQUEST_HEADER_FIELD) {
+            return header(name).map(Self::RequestHeader);
+        }
+        if let Some(name) = value.strip_prefix(RESPONSE_HEADER_FIELD) {
+            return header(name).map(Self::ResponseHeader);
+        }
+        LOG_FIELD_NAMES
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|(_, field)| field.clone())
+            .ok_or_else(|| format!("unknown log field `{value}`"))
+    }
+}
+
+impl Display for LogField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RequestHeader(name) => write!(f, "{REQUEST_HEADER_FIELD}{name}"),
+            Self::ResponseHeader(name) => write!(f, "{RESPONSE_HEADER_FIELD}{name}"),
+            field => {
+                let (name, _) = LOG_FIELD_NAMES
+                    .iter()
+                    .find(|(_, known)| known == field)
+                    .expect("every plain log field has a name");
+                f.write_str(name)
+            }
+        }
+    }
+}
+
+/// One `access_log` directive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AccessLog {
+    /// `access_log off;`: nothing is logged at this level.
+    Off,
+    To(AccessLogTarget),
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccessLogTarget {
+    pub sink: AccessLogSink,
+    /// `log_format` name; `None` uses `default`.
+    pub format: Option<String>,
+    /// Only responses with these statuses are logged; empty logs all.
+    pub statuses: Vec<RangeInclusive<u16>>,
+    /// Share of requests logged, in 1/10000; `None` logs every request.
+    pub sample: Option<u32>,
+}
+
+/// `sample=` is kept in basis points so the IR stays `Eq`.
+pub const ACCESS_LOG_SAMPLE_SCALE: u32 = 10_000;
+
+impl AccessLogTarget {
+    pub fn format_name(&self) -> &str {
+        self.format.as_deref().unwrap_or(DEFAULT_LOG_FORMAT)
+    }
+
+    /// Directive arguments, as accepted by `access_log`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![self.sink.to_string()];
+        if let Some(format) = &self.format {
+            args.push(format.clone());
+        }
+        if !self.statuses.is_empty() {
+            let statuses: Vec<String> = self
+                .statuses
+                .iter()
+                .map(|range| match (range.start(), range.end()) {
+                    (start, end) if start == end => start.to_string(),
+                    (start, end) if start % 100 == 0 && *end == start + 99 => {
+                        format!("{}xx", start / 100)
+                    }
+                    (start, end) => format!("{start}-{end}"),
+                })
+                .collect();
+            args.push(format!("status={}", statuses.join(",")));
+        }
+        if let Some(sample) = self.sample {
+            args.push(format!(
+                "sample={}",
+                f64::from(sample) / f64::from(ACCESS_LOG_SAMPLE_SCALE)
+            ));
+        }
+        args
+    }
+}
+
+/// Where access log lines are written.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AccessLogSink {
+    Stdout,
+    /// Appended to; reopened on `SIGUSR1` so it can be rotated.
+    File(PathBuf),
+    Syslog(SyslogTarget),
+}
+
+/// `syslog:server=<addr>[,facility=<name>][,tag=<tag>]`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SyslogTarget {
+    pub server: SyslogServer,
+    /// Facility code, `local7` (23) by default.
+    pub facility: u8,
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum SyslogServer {
+    /// UDP; the port defaults to 514.
+    Udp(SocketAddr),
+    /// `unix:<path>`: a datagram socket such as `/dev/log`.
+    Unix(PathBuf),
+}
+
+pub const DEFAULT_SYSLOG_FACILITY: u8 = 23;
+pub const DEFAULT_SYSLOG_TAG: &str = "ngxora";
+const DEFAULT_SYSLOG_PORT: u16 = 514;
+
+const SYSLOG_FACILITIES: &[&str] = &[
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+const SYSLOG_PREFIX: &str = "syslog:";
+
+impl FromStr for AccessLogSink {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "stdout" {
+            return Ok(Self::Stdout);
+        }
+        if let Some(options) = value.strip_prefix(SYSLOG_PREFIX) {
+            return parse_syslog_target(options).map(Self::Syslog);
+        }
+        let path = PathBuf::from(value);
+        if !path.is_absolute() {
+            return Err(format!(
+                "access log sink `{value}` must be stdout, an absolute file path or syslog:server=..."
+            ));
+        }
+        Ok(Self::File(path))
+    }
+}
+
+fn parse_syslog_target(options: &str) -> Result<SyslogTarget, String> {
+    let mut server = None;
+    let mut facility = DEFAULT_SYSLOG_FACILITY;
+    let mut tag = DEFAULT_SYSLOG_TAG.to_string();
+    for option in options.split(',') {
+        match option.split_once('=') {
+            Some(("server", addr)) => {
+                server = Some(if let Some(path) = addr.strip_prefix("unix:") {
+                    if !path.starts_with('/') {
+                        return Err(format!("syslog server `{addr}` must be an absolute path"));
+                    }
+                    SyslogServer::Unix(PathBuf::from(path))
+                } else if let Ok(addr) = addr.parse::<SocketAddr>() {
+                    SyslogServer::Udp(addr)
+                } else if let Ok(ip) = addr.parse::<IpAddr>() {
+                    SyslogServer::Udp(SocketAddr::new(ip, DEFAULT_SYSLOG_PORT))
+                } else {
+                    return Err(format!(
+                        "syslog server `{addr}` must be an IP address, ip:port or unix:<path>"
+                    ));
+                });
+            }
+            Some(("facility", name)) => {
+                facility = SYSLOG_FACILITIES
+                    .iter()
+                    .position(|known| *known == name)
+                    .ok_or_else(|| format!("unknown syslog facility `{name}`"))?
+                    as u8;
+            }
+            Some(("tag", value)) => {
+                if value.is_empty()
+                    || !value
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                {
+                    return Err(format!(
+                        "syslog tag `{value}` may only contain letters, digits and `_`"
+                    ));
+                }
+                tag = value.to_string();
+            }
+            _ => return Err(format!("unknown syslog option `{option}`")),
+        }
+    }
+    let server = server.ok_or("syslog access log needs server=")?;
+    Ok(SyslogTarget {
+        server,
+        facility,
+        tag,
+    })
+}
+
+impl Display for AccessLogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdout => f.write_str("stdout"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Syslog(target) => {
+                f.write_str(SYSLOG_PREFIX)?;
+                match &target.server {
+                    SyslogServer::Udp(addr) => write!(f, "server={addr}")?,
+                    SyslogServer::Unix(path) => write!(f, "server=unix:{}", path.display())?,
+                }
+                if target.facility != DEFAULT_SYSLOG_FACILITY {
+                    write!(
+                        f,
+                        ",facility={}",
+                        SYSLOG_FACILITIES[target.facility as usize]
+                    )?;
+                }
+                if target.tag != DEFAULT_SYSLOG_TAG {
+                    write!(f, ",tag={}", target.tag)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `request_id on ...` in a server block: every request carries an ID that is
+/// forwarded upstream, returned to the client and written to access logs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RequestId {
+    /// Header carrying the ID, lowercased.
+    pub header: String,
+    /// Peers whose incoming ID is kept; every other request gets a new one.
+    pub trusted: Vec<IpNet>,
+    pub format: RequestIdFormat,
+}
+
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self {
+            header: DEFAULT_REQUEST_ID_HEADER.to_string(),
+            trusted: Vec::new(),
+            format: RequestIdFormat::default(),
+        }
+    }
+}
+
+impl RequestId {
+    /// Checks and lowercases a `header=` value.
+    pub fn header_name(value: &str) -> Result<String, String> {
+        if value.is_empty()
+            || !value
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(format!("invalid request ID header name `{value}`"));
+        }
+        Ok(value.to_ascii_lowercase())
+    }
+
+    /// Directive arguments, as accepted by `request_id`.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["on".to_string()];
+        if self.header != DEFAULT_REQUEST_ID_HEADER {
+            args.push(format!("header={}", self.header));
+        }
+        if !self.trusted.is_empty() {
+            let trusted: Vec<String> = self.trusted.iter().map(ToString::to_string).collect();
+            args.push(format!("trusted={}", trusted.join(",")));
+        }
+        if self.format != RequestIdFormat::default() {
+            args.push(format!("format={}", self.format.name()));
+        }
+        args
+    }
+}
+
+/// How new request IDs are generated. Both sort by creation time.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum RequestIdFormat {
+    /// RFC 9562 UUID version 7, such as `01890a5d-ac96-774b-bcce-b302099a8057`.
+    #[default]
+    UuidV7,
+    /// 26 character Crockford base32 ULID.
+    Ulid,
+}
+
+impl RequestIdFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UuidV7 => "uuidv7",
+            Self::Ulid => "ulid",
+        }
+    }
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "uuidv7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            _ => Err(format!(
+                "unknown request ID format `{value}`, expected uuidv7 or ulid"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub enum UpstreamSelectionPolicy {
     #[default]
     RoundRobin,
     Random,
+    /// Fewest in-flight requests relative to the server weight.
+    LeastConn,
+    /// Requests with the same rendered `key` go to the same server.
+    /// `consistent` uses a ketama ring, so adding or removing a server only
+    /// remaps the keys that belonged to it.
+    Hash {
+        key: Template,
+        consistent: bool,
+    },
+}
+
+impl UpstreamSelectionPolicy {
+    /// Config spelling, as accepted by the `policy` directive.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RoundRobin => "round_robin",
+            Self::Random => "random",
+            Self::LeastConn => "least_conn",
+            Self::Hash {
+                consistent: false, ..
+            } => "hash",
+            Self::Hash {
+                consistent: true, ..
+            } => "consistent_hash",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -124,18 +907,68 @@ pub enum UpstreamHealthCheckType {
         host: String,
         path: String,
         use_tls: bool,
+        method: String,
+        /// Extra request headers, in configuration order.
+        headers: Vec<(String, String)>,
+        /// Response statuses that count as healthy.
+        statuses: Vec<RangeInclusive<u16>>,
+        body: Option<HealthCheckBodyMatch>,
     },
+    /// `grpc.health.v1.Health/Check` over HTTP/2.
+    Grpc {
+        /// Service to ask about; empty for the server as a whole.
+        service: String,
+        /// `:authority` and TLS SNI; defaults to the server address.
+        host: Option<String>,
+        use_tls: bool,
+    },
+}
+
+/// `health_check { body ...; }`: what a healthy response body contains.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HealthCheckBodyMatch {
+    Contains(String),
+    Regex(String),
 }
 
+pub const DEFAULT_HEALTH_CHECK_STATUSES: RangeInclusive<u16> = 200..=200;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct UpstreamHealthCheck {
     pub check_type: UpstreamHealthCheckType,
+    /// Checks this port instead of the one serving traffic.
+    pub port: Option<u16>,
     pub timeout: Duration,
     pub interval: Duration,
     pub consecutive_success: usize,
     pub consecutive_failure: usize,
 }
 
+/// Passive health checking: a server that fails `consecutive_failure`
+/// requests in a row is taken out of selection for a while.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UpstreamOutlierDetection {
+    pub consecutive_failure: usize,
+    /// Length of the first ejection; each further ejection in a row adds
+    /// another `base_ejection_time`, up to `max_ejection_time`.
+    pub base_ejection_time: Duration,
+    pub max_ejection_time: Duration,
+    /// Share of the group's servers that may be ejected at once. Above `0`,
+    /// at least one server can always be ejected; `0` never ejects.
+    pub max_ejection_percent: u8,
+}
+
+impl Default for UpstreamOutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failure: 5,
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 10,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TlsIdentity {
     pub cert: PemSource,
@@ -173,6 +1006,10 @@ pub struct Listen {
     pub default_server: bool,
     pub http2: bool,
     pub http2_only: bool,
+    /// Expect a PROXY protocol (v1 or v2) header before any other bytes.
+    pub proxy_protocol: bool,
+    /// Peers allowed to send a PROXY protocol header; others are refused.
+    pub proxy_protocol_trusted: Vec<IpNet>,
 }
 
 impl Default for Listen {
@@ -184,6 +1021,8 @@ impl Default for Listen {
             default_server: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
         }
     }
 }
@@ -271,6 +1110,57 @@ impl Default for CacheConfig {
     }
 }
 
+/// Where `proxy_cache` responses are stored, set by `proxy_cache_store`.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub enum CacheStoreConfig {
+    /// Per-process store; each replica warms its own cache.
+    #[default]
+    Memory,
+    /// Shared store on a Redis-protocol server.
+    Redis {
+        url: String,
+        /// Namespace for the keys, so several deployments can share a server.
+        key_prefix: Option<String>,
+    },
+}
+
+impl CacheStoreConfig {
+    /// Validate a `redis://host[:port][/db]` URL and optional key prefix.
+    pub fn redis(url: &str, key_prefix: Option<String>) -> Result<Self, String> {
+        validate_redis_url(url)?;
+        if let Some(prefix) = &key_prefix
+            && !is_valid_redis_key_prefix(prefix)
+        {
+            return Err(format!("invalid cache key prefix `{prefix}`"));
+        }
+        Ok(Self::Redis {
+            url: url.to_string(),
+            key_prefix,
+        })
+    }
+}
+
+/// Check that `url` is a `redis://host[:port][/db]` URL.
+pub fn validate_redis_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|err| format!("invalid redis URL `{url}`: {err}"))?;
+    if parsed.scheme() != "redis" {
+        return Err(format!("unsupported store URL `{url}`: expected redis://"));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err(format!("redis URL `{url}` is missing a host"));
+    }
+    let db = parsed.path().trim_start_matches('/');
+    if !db.is_empty() && db.parse::<u32>().is_err() {
+        return Err(format!("redis URL `{url}` has an invalid database `{db}`"));
+    }
+    Ok(())
+}
+
+/// Key prefixes are non-empty and free of whitespace.
+pub fn is_valid_redis_key_prefix(prefix: &str) -> bool {
+    !prefix.is_empty() && !prefix.chars().any(|c| c.is_whitespace())
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub enum CacheKeyMode {
     #[default]
@@ -286,6 +1176,38 @@ pub struct UpstreamTimeouts {
     pub write: Option<Duration>,
 }
 
+/// Failures `proxy_next_upstream` retries on another backend. All `false`
+/// (the default, or `off`) disables retries.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct NextUpstream {
+    /// Connect failures and connection errors before a response header.
+    pub error: bool,
+    /// Connect, read or write timeouts before a response header.
+    pub timeout: bool,
+    pub http_502: bool,
+    pub http_503: bool,
+    pub http_504: bool,
+    /// Also retry non-idempotent methods, such as `POST`, after the request
+    /// was sent. Connect failures are retried for every method.
+    pub non_idempotent: bool,
+}
+
+impl NextUpstream {
+    pub fn is_enabled(&self) -> bool {
+        self.error || self.timeout || self.http_502 || self.http_503 || self.http_504
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct UpstreamRetry {
+    pub next_upstream: NextUpstream,
+    /// Attempts per request including the first; `0` means no limit beyond
+    /// the number of backends.
+    pub tries: u32,
+    /// Time after the first attempt during which retries may start.
+    pub timeout: Option<Duration>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Default)]
 pub struct UpstreamSslOptions {
     pub verify_cert: Switch,
@@ -316,13 +1238,35 @@ pub enum LocationDirective {
     ProxyReadTimeout(Duration),
     ProxyWriteTimeout(Duration),
     ProxyUpstreamProtocol(UpstreamHttpProtocol),
+    ProxyNextUpstream(NextUpstream),
+    ProxyNextUpstreamTries(u32),
+    ProxyNextUpstreamTimeout(Duration),
     ProxySslVerify(Switch),
     ProxySslTrustedCertificate(PemSource),
     ProxySslCertificate(PemSource),
     ProxySslCertificateKey(PemSource),
     Root(String),
-    TryFiles(String),
-    Return { status: u16, location: String },
+    Alias(String),
+    Index(Vec<String>),
+    TryFiles {
+        files: Vec<String>,
+        fallback: TryFilesFallback,
+    },
+    Return {
+        status: u16,
+        location: String,
+    },
+    /// `route_name`: stable name of the route in metrics.
+    RouteName(String),
+    AccessLog(AccessLog),
+}
+
+// Last `try_files` argument, used when none of the listed files exist.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TryFilesFallback {
+    Status(u16),   // `=404`
+    Named(String), // `@name`
+    Uri(String),   // `/index.html`, re-matched against the server's locations
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -1,6 +1,8 @@
 pub mod block_names;
 pub mod consts;
 pub mod ir;
+mod print;
 mod tests;
 pub mod transform;
 pub mod validate;
+pub mod variables;
@@ -1,26 +1,65 @@
 use ipnet::IpNet;
-use ngxora_config::{Ast, Block, Directive, Node};
+use ngxora_config::diagnostic::suggest;
+use ngxora_config::{Ast, Block, Diagnostic, Directive, Node, Span};
 use ngxora_plugin_api::PluginSpec;
 use serde::Serialize;
 use std::collections::BTreeSet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use url::Url;
 
 use crate::{
     consts,
     ir::{
-        CacheConfig, Http, Ir, KeepaliveTimeout, LetsEncryptConfig, Listen, Location,
-        LocationDirective, LocationIpRule, LocationMatcher, PemSource, ProxyPassTarget, Server,
-        SslProvider, Switch, TlsIdentity, TlsProtocolBounds, TlsProtocolVersion, TlsVerifyClient,
+        ACCESS_LOG_SAMPLE_SCALE, AccessLog, AccessLogTarget, AcmeChallenge, AcmeDnsProvider,
+        AcmeIssuer, CacheConfig, CacheStoreConfig, DEFAULT_DNS_PROPAGATION_TIMEOUT,
+        DEFAULT_HEALTH_CHECK_STATUSES, DEFAULT_LOG_FORMAT, DEFAULT_UPSTREAM_QUEUE_TIMEOUT,
+        DnsResolver, ExternalAccountBinding, HealthCheckBodyMatch, Http, Ir, KeepaliveTimeout,
+        LETSENCRYPT_ISSUER, Listen, Location, LocationDirective, LocationIpRule, LocationMatcher,
+        LogEncoding, LogFormat, MAX_UPSTREAM_SERVER_WEIGHT, NextUpstream, PemSource,
+        ProxyPassTarget, RequestId, Rfc2136Provider, Server, SslProvider, Switch, TlsIdentity,
+        TlsProtocolBounds, TlsProtocolVersion, TlsVerifyClient, TryFilesFallback, TsigKey,
         UpstreamBlock, UpstreamHealthCheck, UpstreamHealthCheckType, UpstreamHttpProtocol,
-        UpstreamSelectionPolicy, UpstreamServer,
+        UpstreamKeepalive, UpstreamOutlierDetection, UpstreamQueue, UpstreamSelectionPolicy,
+        UpstreamServer, is_valid_redis_key_prefix, validate_redis_url,
     },
+    variables::Template,
 };
 
 #[derive(Debug)]
 pub struct LowerErr {
     pub message: String,
+    /// The directive or block the error is about.
+    pub span: Option<Span>,
+    /// A hint such as the directive the author probably meant.
+    pub help: Option<String>,
+}
+
+impl LowerErr {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            span: None,
+            help: None,
+        }
+    }
+
+    /// Points the error at `span` unless a nested node already claimed it.
+    fn at(mut self, span: &Span) -> Self {
+        self.span.get_or_insert_with(|| span.clone());
+        self
+    }
+}
+
+impl From<LowerErr> for Diagnostic {
+    fn from(e: LowerErr) -> Self {
+        Diagnostic {
+            message: e.message,
+            span: e.span,
+            help: e.help,
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -43,7 +82,45 @@ struct BasicAuthPluginConfig {
 
 #[derive(Debug, Default, Serialize)]
 struct RateLimitPluginConfig {
-    max_requests_per_second: isize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_requests_per_second: Option<isize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate: Option<RateLimitRate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    nodelay: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<RateLimitKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    store: Option<RateLimitStoreConfig>,
+}
+
+#[derive(Debug, Serialize)]
+struct RateLimitRate {
+    requests: u32,
+    per: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RateLimitKey {
+    Ip,
+    IpPrefix { ipv4: u8, ipv6: u8 },
+    Header { name: String },
+    JwtSubject,
+    Route,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RateLimitStoreConfig {
+    redis_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -96,123 +173,196 @@ struct HeaderEntry {
 }
 
 impl Ir {
+    /// Lowers `ast`, stopping at the first error. See [`Ir::lower_ast`].
     pub fn from_ast(ast: &Ast) -> Result<Self, LowerErr> {
+        Self::lower_ast(ast).map_err(|errors| {
+            errors
+                .into_iter()
+                .next()
+                .expect("lowering fails with at least one error")
+        })
+    }
+
+    /// Lowers `ast` and reports every broken directive and block, each
+    /// pointing at where it was written.
+    pub fn lower_ast(ast: &Ast) -> Result<Self, Vec<LowerErr>> {
+        let mut errors = Errors::default();
         let mut ir = Ir::default();
-        let mut http: Option<Http> = None;
         for node in &ast.items {
             match node {
                 Node::Directive(_directive) => {}
                 Node::Block(block) => {
                     if block.name.as_str() == consts::HTTP {
-                        match lower_http(block) {
-                            Ok(h) => http = Some(h),
-                            Err(e) => return Err(e),
-                        }
+                        ir.http = errors.extend(lower_http(block));
                     }
                 }
             }
         }
 
-        ir.http = http;
-        Ok(ir)
+        errors.finish(ir)
+    }
+}
+
+/// Errors gathered while lowering a block, so a single run reports every
+/// broken child instead of only the first.
+#[derive(Default)]
+struct Errors(Vec<LowerErr>);
+
+impl Errors {
+    /// The value of `result`, or `None` after recording its error at `span`.
+    fn check<T>(&mut self, result: Result<T, LowerErr>, span: &Span) -> Option<T> {
+        result.map_err(|e| self.0.push(e.at(span))).ok()
+    }
+
+    /// Same as [`Errors::check`] for a nested block that collects its own.
+    fn extend<T>(&mut self, result: Result<T, Vec<LowerErr>>) -> Option<T> {
+        result.map_err(|errors| self.0.extend(errors)).ok()
+    }
+
+    fn finish<T>(self, value: T) -> Result<T, Vec<LowerErr>> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.0)
+        }
     }
 }
 
-fn lower_http(block: &Block) -> Result<Http, LowerErr> {
+fn lower_http(block: &Block) -> Result<Http, Vec<LowerErr>> {
+    let mut errors = Errors::default();
     let mut http: Http = Http::default();
+    let mut server_spans: Vec<&Span> = Vec::new();
 
     for children_block in &block.children {
         match children_block {
-            Node::Directive(directive) => apply_http_directive(&mut http, directive)?,
+            Node::Directive(directive) => {
+                errors.check(apply_http_directive(&mut http, directive), &directive.span);
+            }
             Node::Block(block) => match block.name.as_str() {
                 consts::SERVER => {
-                    let server = lower_server(block)?;
-                    http.servers.push(server);
+                    if let Some(server) = errors.extend(lower_server(block)) {
+                        http.servers.push(server);
+                        server_spans.push(&block.span);
+                    }
                 }
                 consts::UPSTREAM => {
-                    let upstream = lower_upstream(block)?;
-                    http.upstreams.push(upstream);
+                    if let Some(upstream) = errors.extend(lower_upstream(block)) {
+                        http.upstreams.push(upstream);
+                    }
                 }
                 consts::SSL_PROVIDER => {
-                    if http.ssl_provider.is_some() {
-                        return Err(LowerErr {
-                            message: "duplicate ssl_provider block".into(),
-                        });
+                    let issuer = lower_ssl_provider(block).and_then(|issuer| {
+                        if http
+                            .acme_issuers
+                            .iter()
+                            .any(|known| known.name == issuer.name)
+                        {
+                            Err(LowerErr::new(format!(
+                                "duplicate ssl_provider block for issuer `{}`",
+                                issuer.name
+                            )))
+                        } else {
+                            Ok(issuer)
+                        }
+                    });
+                    if let Some(issuer) = errors.check(issuer, &block.span) {
+                        http.acme_issuers.push(issuer);
                     }
-                    http.ssl_provider = Some(lower_ssl_provider(block)?);
                 }
                 _ => {
-                    return Err(LowerErr {
-                        message: format!("Unknown block name: {:?}", block.name),
-                    });
+                    errors.check::<()>(
+                        Err(unknown_name(
+                            format!("Unknown block name: {:?}", block.name),
+                            &block.name,
+                        )),
+                        &block.span,
+                    );
                 }
             },
         }
     }
 
-    // Assign LetsEncrypt to servers that have an SSL listener but no explicit
-    // ssl_certificate when a global ssl_provider letsencrypt is configured.
-    if http.ssl_provider.is_some() {
+    // Servers with an SSL listener but neither ssl_certificate nor
+    // acme_certificate use the first declared ACME issuer.
+    if let Some(default_issuer) = http.acme_issuers.first() {
         for server in &mut http.servers {
             let has_ssl_listener = server.listens.iter().any(|l| l.ssl);
             if has_ssl_listener && server.tls.is_none() {
-                server.tls = Some(SslProvider::LetsEncrypt);
+                server.tls = Some(SslProvider::Acme(vec![default_issuer.name.clone()]));
             }
         }
     }
 
-    for server in &http.servers {
-        let has_ssl_listener = server.listens.iter().any(|l| l.ssl);
-        if !has_ssl_listener {
-            continue;
+    for (server, span) in http.servers.iter().zip(server_spans) {
+        errors.check(validate_ssl_listener(server, &http.acme_issuers), span);
+    }
+
+    errors.finish(http)
+}
+
+fn validate_ssl_listener(server: &Server, issuers: &[AcmeIssuer]) -> Result<(), LowerErr> {
+    let has_ssl_listener = server.listens.iter().any(|l| l.ssl);
+    if !has_ssl_listener {
+        if matches!(server.tls, Some(SslProvider::Acme(_))) {
+            return Err(LowerErr::new(
+                "acme_certificate requires a `listen ... ssl` listener",
+            ));
         }
+        return Ok(());
+    }
 
-        match &server.tls {
-            Some(SslProvider::Custom(tls)) => {
-                if tls.cert == PemSource::Path(PathBuf::new()) {
-                    return Err(LowerErr {
-                        message: "ssl listener requires ssl_certificate".into(),
-                    });
-                }
-                if tls.key == PemSource::Path(PathBuf::new()) {
-                    return Err(LowerErr {
-                        message: "ssl listener requires ssl_certificate_key".into(),
-                    });
-                }
+    match &server.tls {
+        Some(SslProvider::Custom(tls)) => {
+            if tls.cert == PemSource::Path(PathBuf::new()) {
+                return Err(LowerErr::new("ssl listener requires ssl_certificate"));
             }
-            Some(SslProvider::LetsEncrypt) => {
-                if http.ssl_provider.is_none() {
-                    return Err(LowerErr {
-                        message:
-                            "ssl listener with LetsEncrypt provider requires an ssl_provider letsencrypt { ... } block in http"
-                                .into(),
-                    });
-                }
-                if server.server_names.is_empty() {
-                    return Err(LowerErr {
-                        message:
-                            "ssl listener with LetsEncrypt requires at least one server_name for certificate issuance"
-                                .into(),
-                    });
-                }
-                if server.server_names.len() > 1 {
-                    return Err(LowerErr {
-                        message:
-                            "ssl listener with LetsEncrypt currently supports exactly one server_name; split aliases into separate server blocks or use a manual certificate"
-                                .into(),
-                    });
-                }
+            if tls.key == PemSource::Path(PathBuf::new()) {
+                return Err(LowerErr::new("ssl listener requires ssl_certificate_key"));
+            }
+        }
+        Some(SslProvider::Acme(names)) => {
+            if let Some(unknown) = names
+                .iter()
+                .find(|name| !issuers.iter().any(|issuer| &issuer.name == *name))
+            {
+                return Err(LowerErr::new(format!(
+                    "acme_certificate: unknown issuer `{unknown}`; declare it with ssl_provider acme {unknown} {{ ... }} in http"
+                )));
             }
-            None => {
-                return Err(LowerErr {
-                    message: "ssl listener requires a certificate: use ssl_certificate / ssl_certificate_key, or enable ssl_provider letsencrypt"
-                        .into(),
-                });
+            if server.server_names.is_empty() {
+                return Err(LowerErr::new(
+                    "ssl listener with ACME requires at least one server_name for certificate issuance",
+                ));
             }
+            if server.server_names.len() > 1 {
+                return Err(LowerErr::new(
+                    "ssl listener with ACME currently supports exactly one server_name; split aliases into separate server blocks or use a manual certificate",
+                ));
+            }
+        }
+        None => {
+            return Err(LowerErr::new(
+                "ssl listener requires a certificate: use ssl_certificate / ssl_certificate_key, or declare an ssl_provider",
+            ));
         }
     }
 
-    Ok(http)
+    Ok(())
+}
+
+/// An unknown-name error with a "did you mean" hint when `name` looks like a
+/// typo of a directive or block name.
+fn unknown_name(message: String, name: &str) -> LowerErr {
+    let mut err = LowerErr::new(message);
+    err.help = suggest(
+        name,
+        consts::DIRECTIVE_NAMES
+            .iter()
+            .copied()
+            .filter(|known| *known != name),
+    )
+    .map(|known| format!("did you mean `{known}`?"));
+    err
 }
 
 fn apply_http_directive(http: &mut Http, d: &Directive) -> Result<(), LowerErr> {
@@ -236,45 +386,96 @@ fn apply_http_directive(http: &mut Http, d: &Directive) -> Result<(), LowerErr>
             http.proxy_cache_max_size =
                 Some(parse_size_literal(&raw, consts::PROXY_CACHE_MAX_SIZE)?);
         }
+        consts::PROXY_CACHE_STORE => {
+            http.proxy_cache_store = parse_proxy_cache_store(&d.args)?;
+        }
+        consts::RESOLVER => set_once(&mut http.resolver, parse_resolver(&d.args)?, "resolver")?,
+        consts::METRICS_LABEL_LIMIT => {
+            let raw = parse_exactly_one_argument(&d.args, consts::METRICS_LABEL_LIMIT)?;
+            let limit = raw
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| {
+                    LowerErr::new(format!(
+                        "metrics_label_limit: expected a positive integer, got `{raw}`"
+                    ))
+                })?;
+            set_once(
+                &mut http.metrics_label_limit,
+                limit,
+                consts::METRICS_LABEL_LIMIT,
+            )?;
+        }
+        consts::LOG_FORMAT => {
+            let format = parse_log_format(&d.args)?;
+            if http
+                .log_formats
+                .iter()
+                .any(|known| known.name == format.name)
+            {
+                return Err(LowerErr::new(format!(
+                    "log_format: `{}` is defined more than once",
+                    format.name
+                )));
+            }
+            http.log_formats.push(format);
+        }
+        consts::ACCESS_LOG => {
+            let log = parse_access_log(&d.args)?;
+            ensure_access_log_combinable(&http.access_logs, &log)?;
+            http.access_logs.push(log);
+        }
 
         _ => {
-            return Err(LowerErr {
-                message: format!("unsupported http directive: {}", d.name),
-            });
+            return Err(unknown_name(
+                format!("unsupported http directive: {}", d.name),
+                &d.name,
+            ));
         }
     }
 
     Ok(())
 }
 
-fn lower_ssl_provider(block: &Block) -> Result<LetsEncryptConfig, LowerErr> {
-    match block.args.as_slice() {
-        [name] if name == consts::LETSENCRYPT => {}
+fn lower_ssl_provider(block: &Block) -> Result<AcmeIssuer, LowerErr> {
+    let mut config = match block.args.as_slice() {
+        [provider] if provider == consts::LETSENCRYPT => AcmeIssuer::new(LETSENCRYPT_ISSUER),
+        [provider, name] if provider == consts::ACME => {
+            AcmeIssuer::validate_name(name)
+                .map_err(|err| LowerErr::new(format!("ssl_provider: {err}")))?;
+            AcmeIssuer::new(name.as_str())
+        }
+        [provider] if provider == consts::ACME => {
+            return Err(LowerErr::new(
+                "ssl_provider: expected `ssl_provider acme <name> { ... }`",
+            ));
+        }
         [] => {
-            return Err(LowerErr {
-                message: "ssl_provider: expected provider name (letsencrypt)".into(),
-            });
+            return Err(LowerErr::new(
+                "ssl_provider: expected provider name (letsencrypt or acme <name>)",
+            ));
         }
-        _ => {
-            return Err(LowerErr {
-                message: "ssl_provider: only 'letsencrypt' is supported as a provider name".into(),
-            });
+        [provider, ..] => {
+            return Err(LowerErr::new(format!(
+                "ssl_provider: unknown provider `{provider}`, expected `letsencrypt` or `acme <name>`"
+            )));
         }
-    }
-
-    let mut config = LetsEncryptConfig {
-        acme_directory: None,
-        email: None,
-        cache_dir: None,
     };
+    let mut challenge = None;
+    let mut dns_server = None;
+    let mut dns_zone = None;
+    let mut tsig_key = None;
+    let mut propagation_timeout = None;
 
     for child in &block.children {
         let directive = match child {
             Node::Directive(d) => d,
             Node::Block(b) => {
-                return Err(LowerErr {
-                    message: format!("ssl_provider: unexpected block `{}`", b.name),
-                });
+                return Err(LowerErr::new(format!(
+                    "ssl_provider: unexpected block `{}`",
+                    b.name
+                )));
             }
         };
 
@@ -291,53 +492,182 @@ fn lower_ssl_provider(block: &Block) -> Result<LetsEncryptConfig, LowerErr> {
                 let raw = parse_exactly_one_argument(&directive.args, consts::CACHE_DIR)?;
                 config.cache_dir = Some(PathBuf::from(raw));
             }
+            consts::CHALLENGE => {
+                let raw = parse_exactly_one_argument(&directive.args, consts::CHALLENGE)?;
+                challenge = Some(raw);
+            }
+            consts::DNS_PROVIDER => match directive.args.as_slice() {
+                [provider, server] if provider == consts::RFC2136 => {
+                    dns_server = Some(
+                        Rfc2136Provider::parse_server(server)
+                            .map_err(|err| LowerErr::new(format!("dns_provider: {err}")))?,
+                    );
+                }
+                [provider, _] => {
+                    return Err(LowerErr::new(format!(
+                        "dns_provider: unknown provider `{provider}`, expected rfc2136"
+                    )));
+                }
+                _ => {
+                    return Err(LowerErr::new(
+                        "dns_provider: expected `dns_provider rfc2136 <ip[:port]>`",
+                    ));
+                }
+            },
+            consts::DNS_ZONE => {
+                let raw = parse_exactly_one_argument(&directive.args, consts::DNS_ZONE)?;
+                dns_zone = Some(raw.trim_end_matches('.').to_ascii_lowercase());
+            }
+            consts::DNS_PROPAGATION_TIMEOUT => {
+                let timeout = parse_single_duration_directive(
+                    &directive.args,
+                    consts::DNS_PROPAGATION_TIMEOUT,
+                )?;
+                if timeout.is_zero() {
+                    return Err(LowerErr::new(
+                        "dns_propagation_timeout: must be greater than 0",
+                    ));
+                }
+                propagation_timeout = Some(timeout);
+            }
+            consts::EXTERNAL_ACCOUNT_BINDING => match directive.args.as_slice() {
+                [key_id, hmac_key] => {
+                    config.external_account_binding = Some(
+                        ExternalAccountBinding::new(key_id, hmac_key).map_err(|err| {
+                            LowerErr::new(format!("external_account_binding: {err}"))
+                        })?,
+                    );
+                }
+                _ => {
+                    return Err(LowerErr::new(
+                        "external_account_binding: expected `external_account_binding <key_id> <base64url hmac_key>`",
+                    ));
+                }
+            },
+            consts::KEY_TYPE => {
+                let raw = parse_exactly_one_argument(&directive.args, consts::KEY_TYPE)?;
+                config.key_type = raw
+                    .parse()
+                    .map_err(|err| LowerErr::new(format!("key_type: {err}")))?;
+            }
+            consts::SSL_TRUSTED_CERTIFICATE => {
+                let raw =
+                    parse_exactly_one_argument(&directive.args, consts::SSL_TRUSTED_CERTIFICATE)?;
+                config.trusted_certificate = Some(PathBuf::from(raw));
+            }
+            consts::DNS_TSIG_KEY => match directive.args.as_slice() {
+                [name, algorithm, secret] => {
+                    tsig_key = Some(
+                        TsigKey::new(name, algorithm, secret)
+                            .map_err(|err| LowerErr::new(format!("dns_tsig_key: {err}")))?,
+                    );
+                }
+                _ => {
+                    return Err(LowerErr::new(
+                        "dns_tsig_key: expected `dns_tsig_key <name> <algorithm> <base64 secret>`",
+                    ));
+                }
+            },
             _ => {
-                return Err(LowerErr {
-                    message: format!("ssl_provider: unsupported directive `{}`", directive.name),
-                });
+                return Err(unknown_name(
+                    format!("ssl_provider: unsupported directive `{}`", directive.name),
+                    &directive.name,
+                ));
             }
         }
     }
 
+    let uses_dns = dns_server.is_some()
+        || dns_zone.is_some()
+        || tsig_key.is_some()
+        || propagation_timeout.is_some();
+    config.challenge = match challenge.as_deref() {
+        None | Some("http-01") | Some("tls-alpn-01") if uses_dns => {
+            return Err(LowerErr::new(
+                "ssl_provider: dns_provider, dns_zone, dns_tsig_key and dns_propagation_timeout require `challenge dns-01`",
+            ));
+        }
+        None | Some("http-01") => AcmeChallenge::Http01,
+        Some("tls-alpn-01") => AcmeChallenge::TlsAlpn01,
+        Some("dns-01") => {
+            let server = dns_server.ok_or_else(|| {
+                LowerErr::new("ssl_provider: challenge dns-01 requires dns_provider")
+            })?;
+            let zone = dns_zone.ok_or_else(|| {
+                LowerErr::new("ssl_provider: dns_provider rfc2136 requires dns_zone")
+            })?;
+            AcmeChallenge::Dns01(AcmeDnsProvider::Rfc2136(Rfc2136Provider {
+                server,
+                zone,
+                tsig_key,
+                propagation_timeout: propagation_timeout.unwrap_or(DEFAULT_DNS_PROPAGATION_TIMEOUT),
+            }))
+        }
+        Some(other) => {
+            return Err(LowerErr::new(format!(
+                "challenge: unknown challenge `{other}`, expected http-01, tls-alpn-01 or dns-01"
+            )));
+        }
+    };
+
+    if config.acme_directory.is_none() && config.name != LETSENCRYPT_ISSUER {
+        return Err(LowerErr::new(format!(
+            "ssl_provider acme {}: acme_directory is required",
+            config.name
+        )));
+    }
+
     Ok(config)
 }
 
-fn lower_server(block: &Block) -> Result<Server, LowerErr> {
+fn lower_server(block: &Block) -> Result<Server, Vec<LowerErr>> {
+    let mut errors = Errors::default();
     let mut server = Server::default();
     for children in &block.children {
         match children {
-            Node::Directive(directive) => apply_server_directive(&mut server, directive)?,
+            Node::Directive(directive) => {
+                errors.check(
+                    apply_server_directive(&mut server, directive),
+                    &directive.span,
+                );
+            }
 
             Node::Block(block) => match block_named(children, consts::LOCATION) {
                 Some(b) => {
-                    let location = lower_location(b)?;
-                    server.locations.push(location);
+                    if let Some(location) = errors.extend(lower_location(b)) {
+                        server.locations.push(location);
+                    }
                 }
                 None => {
-                    return Err(LowerErr {
-                        message: format!("Unknown name of block: {:?}", block.name),
-                    });
+                    errors.check::<()>(
+                        Err(unknown_name(
+                            format!("Unknown name of block: {:?}", block.name),
+                            &block.name,
+                        )),
+                        &block.span,
+                    );
                 }
             },
         }
     }
 
-    validate_server(&server)?;
-    Ok(server)
+    errors.check(validate_server(&server), &block.span);
+    errors.finish(server)
 }
 
-fn lower_upstream(block: &Block) -> Result<UpstreamBlock, LowerErr> {
+fn lower_upstream(block: &Block) -> Result<UpstreamBlock, Vec<LowerErr>> {
+    let mut errors = Errors::default();
     let name = match block.args.as_slice() {
         [name] if !name.trim().is_empty() => name.clone(),
         [] => {
-            return Err(LowerErr {
-                message: "upstream block: expected upstream name".into(),
-            });
+            return Err(vec![
+                LowerErr::new("upstream block: expected upstream name").at(&block.span),
+            ]);
         }
         _ => {
-            return Err(LowerErr {
-                message: "upstream block: expected exactly 1 argument".into(),
-            });
+            return Err(vec![
+                LowerErr::new("upstream block: expected exactly 1 argument").at(&block.span),
+            ]);
         }
     };
 
@@ -346,33 +676,61 @@ fn lower_upstream(block: &Block) -> Result<UpstreamBlock, LowerErr> {
         policy: UpstreamSelectionPolicy::RoundRobin,
         servers: Vec::new(),
         health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
     };
 
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_upstream_directive(&mut upstream, directive)?,
+            Node::Directive(directive) => {
+                errors.check(
+                    apply_upstream_directive(&mut upstream, directive),
+                    &directive.span,
+                );
+            }
             Node::Block(nested) => match nested.name.as_str() {
                 consts::HEALTH_CHECK => {
-                    if upstream.health_check.is_some() {
-                        return Err(LowerErr {
-                            message: "upstream block: health_check block is duplicated".into(),
-                        });
+                    let health_check = if upstream.health_check.is_some() {
+                        Err(LowerErr::new(
+                            "upstream block: health_check block is duplicated",
+                        ))
+                    } else {
+                        lower_upstream_health_check(nested)
+                    };
+                    if let Some(health_check) = errors.check(health_check, &nested.span) {
+                        upstream.health_check = Some(health_check);
+                    }
+                }
+                consts::OUTLIER_DETECTION => {
+                    let outlier_detection = if upstream.outlier_detection.is_some() {
+                        Err(LowerErr::new(
+                            "upstream block: outlier_detection block is duplicated",
+                        ))
+                    } else {
+                        lower_upstream_outlier_detection(nested)
+                    };
+                    if let Some(outlier_detection) = errors.check(outlier_detection, &nested.span) {
+                        upstream.outlier_detection = Some(outlier_detection);
                     }
-                    upstream.health_check = Some(lower_upstream_health_check(nested)?);
                 }
                 _ => {
-                    return Err(LowerErr {
-                        message: format!(
-                            "upstream block: nested blocks are not supported: {}",
-                            nested.name
-                        ),
-                    });
+                    errors.check::<()>(
+                        Err(unknown_name(
+                            format!(
+                                "upstream block: nested blocks are not supported: {}",
+                                nested.name
+                            ),
+                            &nested.name,
+                        )),
+                        &nested.span,
+                    );
                 }
             },
         }
     }
 
-    Ok(upstream)
+    errors.finish(upstream)
 }
 
 fn apply_server_directive(server: &mut Server, d: &Directive) -> Result<(), LowerErr> {
@@ -385,9 +743,7 @@ fn apply_server_directive(server: &mut Server, d: &Directive) -> Result<(), Lowe
 
         consts::SERVER_NAME => match d.args.as_slice() {
             [] => {
-                return Err(LowerErr {
-                    message: "server_name: expected at least 1 argument".into(),
-                });
+                return Err(LowerErr::new("server_name: expected at least 1 argument"));
             }
             names => {
                 server.server_names.extend(names.iter().cloned());
@@ -396,53 +752,55 @@ fn apply_server_directive(server: &mut Server, d: &Directive) -> Result<(), Lowe
 
         consts::SSL_CERTIFICATE => match d.args.as_slice() {
             [cert] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(cert), false).map_err(|_| LowerErr {
-                        message: "ssl_certificate: invalid certificate source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(cert), false)
+                    .map_err(|_| LowerErr::new("ssl_certificate: invalid certificate source"))?;
 
                 let provider = server
                     .tls
                     .get_or_insert_with(|| SslProvider::Custom(TlsIdentity::default()));
-                if let SslProvider::Custom(tls) = provider {
-                    tls.cert = ps;
+                match provider {
+                    SslProvider::Custom(tls) => tls.cert = ps,
+                    SslProvider::Acme(_) => {
+                        return Err(LowerErr::new(
+                            "ssl_certificate: cannot be combined with acme_certificate",
+                        ));
+                    }
                 }
             }
             [] => {
-                return Err(LowerErr {
-                    message: "ssl_certificate: expected 1 argument".into(),
-                });
+                return Err(LowerErr::new("ssl_certificate: expected 1 argument"));
             }
             _ => {
-                return Err(LowerErr {
-                    message: "ssl_certificate: expected exactly 1 argument".into(),
-                });
+                return Err(LowerErr::new(
+                    "ssl_certificate: expected exactly 1 argument",
+                ));
             }
         },
 
         consts::SSL_CERTIFICATE_KEY => match d.args.as_slice() {
             [key] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(key), false).map_err(|_| LowerErr {
-                        message: "ssl_certificate_key: invalid key source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(key), false)
+                    .map_err(|_| LowerErr::new("ssl_certificate_key: invalid key source"))?;
 
                 let provider = server
                     .tls
                     .get_or_insert_with(|| SslProvider::Custom(TlsIdentity::default()));
-                if let SslProvider::Custom(tls) = provider {
-                    tls.key = ps;
+                match provider {
+                    SslProvider::Custom(tls) => tls.key = ps,
+                    SslProvider::Acme(_) => {
+                        return Err(LowerErr::new(
+                            "ssl_certificate_key: cannot be combined with acme_certificate",
+                        ));
+                    }
                 }
             }
             [] => {
-                return Err(LowerErr {
-                    message: "ssl_certificate_key: expected 1 argument".into(),
-                });
+                return Err(LowerErr::new("ssl_certificate_key: expected 1 argument"));
             }
             _ => {
-                return Err(LowerErr {
-                    message: "ssl_certificate_key: expected exactly 1 argument".into(),
-                });
+                return Err(LowerErr::new(
+                    "ssl_certificate_key: expected exactly 1 argument",
+                ));
             }
         },
 
@@ -450,34 +808,72 @@ fn apply_server_directive(server: &mut Server, d: &Directive) -> Result<(), Lowe
             server.tls_options.protocols = Some(parse_ssl_protocols(&d.args)?);
         }
 
+        consts::ACME_CERTIFICATE => {
+            if d.args.is_empty() {
+                return Err(LowerErr::new(
+                    "acme_certificate: expected `acme_certificate <issuer> [<fallback> ...]`",
+                ));
+            }
+            for (index, issuer) in d.args.iter().enumerate() {
+                if d.args[..index].contains(issuer) {
+                    return Err(LowerErr::new(format!(
+                        "acme_certificate: issuer `{issuer}` is listed twice"
+                    )));
+                }
+            }
+            match &server.tls {
+                None => server.tls = Some(SslProvider::Acme(d.args.clone())),
+                Some(SslProvider::Acme(_)) => {
+                    return Err(LowerErr::new("acme_certificate: duplicated directive"));
+                }
+                Some(SslProvider::Custom(_)) => {
+                    return Err(LowerErr::new(
+                        "acme_certificate: cannot be combined with ssl_certificate",
+                    ));
+                }
+            }
+        }
+
+        consts::ACCESS_LOG => {
+            let log = parse_access_log(&d.args)?;
+            ensure_access_log_combinable(&server.access_logs, &log)?;
+            server.access_logs.push(log);
+        }
+
+        consts::REQUEST_ID => match parse_request_id(&d.args)? {
+            Some(request_id) => set_once(&mut server.request_id, request_id, consts::REQUEST_ID)?,
+            None if server.request_id.is_some() => {
+                return Err(LowerErr::new("request_id: duplicated directive"));
+            }
+            None => {}
+        },
+
         consts::SSL_VERIFY_CLIENT => {
             server.tls_options.verify_client = parse_ssl_verify_client(&d.args)?;
         }
 
         consts::SSL_CLIENT_CERTIFICATE => match d.args.as_slice() {
             [path] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(path), false).map_err(|_| LowerErr {
-                        message: "ssl_client_certificate: invalid certificate source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(path), false).map_err(|_| {
+                    LowerErr::new("ssl_client_certificate: invalid certificate source")
+                })?;
                 server.tls_options.client_certificate = Some(ps);
             }
             [] => {
-                return Err(LowerErr {
-                    message: "ssl_client_certificate: expected 1 argument".into(),
-                });
+                return Err(LowerErr::new("ssl_client_certificate: expected 1 argument"));
             }
             _ => {
-                return Err(LowerErr {
-                    message: "ssl_client_certificate: expected exactly 1 argument".into(),
-                });
+                return Err(LowerErr::new(
+                    "ssl_client_certificate: expected exactly 1 argument",
+                ));
             }
         },
 
         _ => {
-            return Err(LowerErr {
-                message: format!("unsupported server directive: {}", d.name),
-            });
+            return Err(unknown_name(
+                format!("unsupported server directive: {}", d.name),
+                &d.name,
+            ));
         }
     }
 
@@ -497,16 +893,81 @@ fn apply_upstream_directive(
         consts::POLICY => {
             upstream.policy = parse_upstream_policy(&directive.args)?;
         }
+        consts::LEAST_CONN => {
+            if !directive.args.is_empty() {
+                return Err(LowerErr::new("least_conn: expected no arguments"));
+            }
+            upstream.policy = UpstreamSelectionPolicy::LeastConn;
+        }
+        consts::HASH => {
+            upstream.policy = parse_upstream_hash(&directive.args)?;
+        }
+        // nginx's `keepalive` bounds idle connections only, which Pingora's
+        // shared pool cannot enforce per group; see `keepalive_conns`.
+        consts::KEEPALIVE => {
+            return Err(LowerErr::new(
+                "upstream keepalive: not supported, use keepalive_conns, which also counts busy connections",
+            ));
+        }
+        consts::KEEPALIVE_CONNS => {
+            let name = "upstream keepalive_conns";
+            let value = parse_positive_usize(&directive.args, name)?;
+            set_once(&mut upstream.keepalive.connections, value, name)?;
+        }
+        consts::KEEPALIVE_TIMEOUT => {
+            let name = "upstream keepalive_timeout";
+            let value = parse_single_duration_directive(&directive.args, name)?;
+            ensure_non_zero_duration(value, name)?;
+            set_once(&mut upstream.keepalive.timeout, value, name)?;
+        }
+        consts::KEEPALIVE_REQUESTS => {
+            let name = "upstream keepalive_requests";
+            let value = parse_positive_usize(&directive.args, name)?;
+            set_once(&mut upstream.keepalive.requests, value, name)?;
+        }
+        consts::QUEUE => {
+            set_once(
+                &mut upstream.queue,
+                parse_upstream_queue(&directive.args)?,
+                "upstream queue",
+            )?;
+        }
         _ => {
-            return Err(LowerErr {
-                message: format!("unsupported upstream directive: {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("unsupported upstream directive: {}", directive.name),
+                &directive.name,
+            ));
         }
     }
 
     Ok(())
 }
 
+fn parse_upstream_queue(args: &[String]) -> Result<UpstreamQueue, LowerErr> {
+    let (size, params) = match args {
+        [size, params @ ..] if params.len() <= 1 => (size, params),
+        _ => {
+            return Err(LowerErr::new(
+                "upstream queue: expected a size and optional timeout=<time>",
+            ));
+        }
+    };
+    let size = parse_positive_usize(std::slice::from_ref(size), "upstream queue")?;
+    let timeout = match params {
+        [param] => {
+            let raw = param.strip_prefix("timeout=").ok_or_else(|| {
+                LowerErr::new(format!("upstream queue: unsupported parameter `{param}`"))
+            })?;
+            let timeout = parse_duration_literal(raw, "upstream queue timeout")?;
+            ensure_non_zero_duration(timeout, "upstream queue timeout")?;
+            timeout
+        }
+        _ => DEFAULT_UPSTREAM_QUEUE_TIMEOUT,
+    };
+
+    Ok(UpstreamQueue { size, timeout })
+}
+
 #[derive(Default)]
 struct UpstreamHealthCheckDraft {
     check_type: Option<UpstreamHealthCheckKind>,
@@ -514,71 +975,128 @@ struct UpstreamHealthCheckDraft {
     interval: Option<std::time::Duration>,
     consecutive_success: Option<usize>,
     consecutive_failure: Option<usize>,
+    port: Option<u16>,
     host: Option<String>,
     path: Option<String>,
     use_tls: Option<bool>,
+    method: Option<String>,
+    headers: Vec<(String, String)>,
+    statuses: Option<Vec<RangeInclusive<u16>>>,
+    body: Option<HealthCheckBodyMatch>,
+    service: Option<String>,
+}
+
+impl UpstreamHealthCheckDraft {
+    /// Directives set in the block that only some check types take.
+    fn type_specific_directives(&self) -> Vec<&'static str> {
+        [
+            (self.host.is_some(), consts::HOST),
+            (self.path.is_some(), consts::PATH),
+            (self.use_tls.is_some(), consts::USE_TLS),
+            (self.method.is_some(), consts::METHOD),
+            (!self.headers.is_empty(), consts::HEADER),
+            (self.statuses.is_some(), consts::STATUS),
+            (self.body.is_some(), consts::BODY),
+            (self.service.is_some(), consts::SERVICE),
+        ]
+        .into_iter()
+        .filter_map(|(set, directive)| set.then_some(directive))
+        .collect()
+    }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 enum UpstreamHealthCheckKind {
     Tcp,
     Http,
+    Grpc,
+}
+
+impl UpstreamHealthCheckKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Http => "http",
+            Self::Grpc => "grpc",
+        }
+    }
+
+    fn accepts(self, directive: &str) -> bool {
+        match self {
+            Self::Tcp => false,
+            Self::Http => directive != consts::SERVICE,
+            Self::Grpc => [consts::HOST, consts::USE_TLS, consts::SERVICE].contains(&directive),
+        }
+    }
 }
 
 fn lower_upstream_health_check(block: &Block) -> Result<UpstreamHealthCheck, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: "health_check block: does not accept arguments".into(),
-        });
+        return Err(LowerErr::new(
+            "health_check block: does not accept arguments",
+        ));
     }
 
     let mut draft = UpstreamHealthCheckDraft::default();
     for child in &block.children {
         match child {
             Node::Directive(directive) => {
-                apply_upstream_health_check_directive(&mut draft, directive)?
+                apply_upstream_health_check_directive(&mut draft, directive)
+                    .map_err(|e| e.at(&directive.span))?
             }
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "health_check block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "health_check block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
 
-    let check_type = match draft.check_type.unwrap_or(UpstreamHealthCheckKind::Tcp) {
-        UpstreamHealthCheckKind::Tcp => {
-            if draft.host.is_some() || draft.path.is_some() || draft.use_tls.is_some() {
-                return Err(LowerErr {
-                    message: "health_check: host/path/use_tls are only supported for type http"
-                        .into(),
-                });
-            }
-            UpstreamHealthCheckType::Tcp
-        }
+    let kind = draft.check_type.unwrap_or(UpstreamHealthCheckKind::Tcp);
+    if let Some(directive) = draft
+        .type_specific_directives()
+        .into_iter()
+        .find(|directive| !kind.accepts(directive))
+    {
+        return Err(LowerErr::new(format!(
+            "health_check: `{directive}` is not supported for type {}",
+            kind.name()
+        )));
+    }
+
+    let check_type = match kind {
+        UpstreamHealthCheckKind::Tcp => UpstreamHealthCheckType::Tcp,
         UpstreamHealthCheckKind::Http => {
-            let host = draft.host.ok_or_else(|| LowerErr {
-                message: "health_check: host is required for type http".into(),
-            })?;
+            let host = draft
+                .host
+                .ok_or_else(|| LowerErr::new("health_check: host is required for type http"))?;
             let path = draft.path.unwrap_or_else(|| "/".into());
             if path.is_empty() {
-                return Err(LowerErr {
-                    message: "health_check: path cannot be empty".into(),
-                });
+                return Err(LowerErr::new("health_check: path cannot be empty"));
             }
             UpstreamHealthCheckType::Http {
                 host,
                 path,
                 use_tls: draft.use_tls.unwrap_or(false),
+                method: draft.method.unwrap_or_else(|| "GET".into()),
+                headers: draft.headers,
+                statuses: draft
+                    .statuses
+                    .unwrap_or_else(|| vec![DEFAULT_HEALTH_CHECK_STATUSES]),
+                body: draft.body,
             }
         }
+        UpstreamHealthCheckKind::Grpc => UpstreamHealthCheckType::Grpc {
+            service: draft.service.unwrap_or_default(),
+            host: draft.host,
+            use_tls: draft.use_tls.unwrap_or(false),
+        },
     };
 
     Ok(UpstreamHealthCheck {
         check_type,
+        port: draft.port,
         timeout: draft
             .timeout
             .unwrap_or_else(|| std::time::Duration::from_secs(1)),
@@ -600,12 +1118,11 @@ fn apply_upstream_health_check_directive(
             let kind = match value.as_str() {
                 "tcp" => UpstreamHealthCheckKind::Tcp,
                 "http" => UpstreamHealthCheckKind::Http,
+                "grpc" => UpstreamHealthCheckKind::Grpc,
                 _ => {
-                    return Err(LowerErr {
-                        message: format!(
-                            "health_check type: unsupported value `{value}`; expected tcp|http"
-                        ),
-                    });
+                    return Err(LowerErr::new(format!(
+                        "health_check type: unsupported value `{value}`; expected tcp|http|grpc"
+                    )));
                 }
             };
             set_once(&mut draft.check_type, kind, "health_check type")?;
@@ -639,9 +1156,7 @@ fn apply_upstream_health_check_directive(
         consts::HOST => {
             let value = parse_exactly_one_argument(&directive.args, "health_check host")?;
             if value.trim().is_empty() {
-                return Err(LowerErr {
-                    message: "health_check host: value cannot be empty".into(),
-                });
+                return Err(LowerErr::new("health_check host: value cannot be empty"));
             }
             set_once(&mut draft.host, value, "health_check host")?;
         }
@@ -653,93 +1168,553 @@ fn apply_upstream_health_check_directive(
             let value = matches!(get_directive_switch(directive)?, Switch::On);
             set_once(&mut draft.use_tls, value, "health_check use_tls")?;
         }
-        _ => {
-            return Err(LowerErr {
-                message: format!("unsupported health_check directive: {}", directive.name),
-            });
+        consts::PORT => {
+            let value = parse_exactly_one_argument(&directive.args, "health_check port")?;
+            let port = value
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port > 0)
+                .ok_or_else(|| {
+                    LowerErr::new(format!("health_check port: invalid port `{value}`"))
+                })?;
+            set_once(&mut draft.port, port, "health_check port")?;
+        }
+        consts::METHOD => {
+            let value = parse_exactly_one_argument(&directive.args, "health_check method")?;
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_uppercase()) {
+                return Err(LowerErr::new(format!(
+                    "health_check method: expected an upper-case method such as GET, got `{value}`"
+                )));
+            }
+            set_once(&mut draft.method, value, "health_check method")?;
+        }
+        consts::HEADER => match directive.args.as_slice() {
+            [name, value @ ..] if !value.is_empty() => {
+                draft.headers.push((name.clone(), value.join(" ")));
+            }
+            _ => {
+                return Err(LowerErr::new(
+                    "health_check header: expected header name and value",
+                ));
+            }
+        },
+        consts::STATUS => {
+            let statuses = parse_health_check_statuses(&directive.args)?;
+            set_once(&mut draft.statuses, statuses, "health_check status")?;
+        }
+        consts::BODY => {
+            let body = match directive.args.as_slice() {
+                [op, regex] if op == "~" && !directive.is_quoted(0) => {
+                    HealthCheckBodyMatch::Regex(regex.clone())
+                }
+                [text] if !text.is_empty() && (text != "~" || directive.is_quoted(0)) => {
+                    HealthCheckBodyMatch::Contains(text.clone())
+                }
+                _ => {
+                    return Err(LowerErr::new(
+                        "health_check body: expected a substring or `~ <regex>`",
+                    ));
+                }
+            };
+            set_once(&mut draft.body, body, "health_check body")?;
+        }
+        consts::SERVICE => {
+            let value = parse_exactly_one_argument(&directive.args, "health_check service")?;
+            set_once(&mut draft.service, value, "health_check service")?;
+        }
+        _ => {
+            return Err(unknown_name(
+                format!("unsupported health_check directive: {}", directive.name),
+                &directive.name,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// `status 200 204 300-399;`
+fn parse_health_check_statuses(args: &[String]) -> Result<Vec<RangeInclusive<u16>>, LowerErr> {
+    if args.is_empty() {
+        return Err(LowerErr::new(
+            "health_check status: expected at least one status or range",
+        ));
+    }
+    args.iter()
+        .map(|arg| {
+            let (start, end) = arg.split_once('-').unwrap_or((arg, arg));
+            let parse = |value: &str| {
+                value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|status| (100..=599).contains(status))
+            };
+            match (parse(start), parse(end)) {
+                (Some(start), Some(end)) if start <= end => Ok(start..=end),
+                _ => Err(LowerErr::new(format!(
+                    "health_check status: invalid status or range `{arg}`"
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// `log_format <name> json|text <field>...;`
+fn parse_log_format(args: &[String]) -> Result<LogFormat, LowerErr> {
+    let [name, encoding, fields @ ..] = args else {
+        return Err(LowerErr::new(
+            "log_format: expected a name, json or text, and at least one field",
+        ));
+    };
+    if fields.is_empty() {
+        return Err(LowerErr::new(format!(
+            "log_format {name}: expected at least one field"
+        )));
+    }
+    if name == DEFAULT_LOG_FORMAT {
+        return Err(LowerErr::new(format!(
+            "log_format: `{DEFAULT_LOG_FORMAT}` is built in and cannot be redefined"
+        )));
+    }
+    let encoding = match encoding.as_str() {
+        "json" => LogEncoding::Json,
+        "text" => LogEncoding::Text,
+        other => {
+            return Err(LowerErr::new(format!(
+                "log_format {name}: expected json or text, got `{other}`"
+            )));
+        }
+    };
+    let fields = fields
+        .iter()
+        .map(|field| {
+            field
+                .parse()
+                .map_err(|err| LowerErr::new(format!("log_format {name}: {err}")))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(LogFormat {
+        name: name.clone(),
+        encoding,
+        fields,
+    })
+}
+
+/// `access_log off;` or
+/// `access_log <sink> [<format>] [status=4xx,500-599] [sample=0.1];`
+fn parse_access_log(args: &[String]) -> Result<AccessLog, LowerErr> {
+    let (sink, rest) = match args {
+        [off] if off == "off" => return Ok(AccessLog::Off),
+        [sink, rest @ ..] => (sink, rest),
+        [] => {
+            return Err(LowerErr::new(
+                "access_log: expected off or a sink such as stdout",
+            ));
+        }
+    };
+    let mut target = AccessLogTarget {
+        sink: sink
+            .parse()
+            .map_err(|err| LowerErr::new(format!("access_log: {err}")))?,
+        format: None,
+        statuses: Vec::new(),
+        sample: None,
+    };
+    for (index, arg) in rest.iter().enumerate() {
+        match arg.split_once('=') {
+            Some(("status", statuses)) if target.statuses.is_empty() => {
+                target.statuses = parse_access_log_statuses(statuses)?;
+            }
+            Some(("sample", rate)) if target.sample.is_none() => {
+                let sample = rate
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                    .map(|rate| (rate * f64::from(ACCESS_LOG_SAMPLE_SCALE)).round() as u32)
+                    .filter(|sample| *sample > 0)
+                    .ok_or_else(|| {
+                        LowerErr::new(format!(
+                            "access_log: sample must be a number in (0, 1], got `{rate}`"
+                        ))
+                    })?;
+                target.sample = Some(sample);
+            }
+            None if index == 0 => target.format = Some(arg.clone()),
+            _ => {
+                return Err(LowerErr::new(format!(
+                    "access_log: unexpected argument `{arg}`"
+                )));
+            }
+        }
+    }
+    Ok(AccessLog::To(target))
+}
+
+/// `404,4xx,500-599`
+fn parse_access_log_statuses(raw: &str) -> Result<Vec<RangeInclusive<u16>>, LowerErr> {
+    raw.split(',')
+        .map(|item| {
+            let parse = |value: &str| {
+                value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|status| (100..=599).contains(status))
+            };
+            let range = match item
+                .strip_suffix("xx")
+                .map(|class| parse(&format!("{class}00")))
+            {
+                Some(Some(start)) => Some(start..=start + 99),
+                Some(None) => None,
+                None => {
+                    let (start, end) = item.split_once('-').unwrap_or((item, item));
+                    match (parse(start), parse(end)) {
+                        (Some(start), Some(end)) if start <= end => Some(start..=end),
+                        _ => None,
+                    }
+                }
+            };
+            range.ok_or_else(|| {
+                LowerErr::new(format!(
+                    "access_log: invalid status, class or range `{item}`"
+                ))
+            })
+        })
+        .collect()
+}
+
+/// `request_id on [header=<name>] [trusted=<cidr>,...] [format=uuidv7|ulid]`
+/// or `request_id off`.
+fn parse_request_id(args: &[String]) -> Result<Option<RequestId>, LowerErr> {
+    let options = match args {
+        [off] if off == "off" => return Ok(None),
+        [on, options @ ..] if on == "on" => options,
+        _ => {
+            return Err(LowerErr::new(
+                "request_id: expected on [header=<name>] [trusted=<cidr>,...] [format=uuidv7|ulid] or off",
+            ));
+        }
+    };
+    let mut request_id = RequestId::default();
+    let mut seen = BTreeSet::new();
+    for option in options {
+        let Some((key, value)) = option.split_once('=') else {
+            return Err(LowerErr::new(format!(
+                "request_id: unexpected argument `{option}`"
+            )));
+        };
+        if !seen.insert(key) {
+            return Err(LowerErr::new(format!("request_id: duplicated `{key}=`")));
+        }
+        match key {
+            "header" => {
+                request_id.header = RequestId::header_name(value)
+                    .map_err(|err| LowerErr::new(format!("request_id: {err}")))?;
+            }
+            "trusted" => {
+                for network in value.split(',') {
+                    request_id.trusted.push(
+                        network
+                            .parse::<IpNet>()
+                            .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                            .map_err(|_| {
+                                LowerErr::new(format!(
+                                    "request_id: invalid trusted network `{network}`"
+                                ))
+                            })?,
+                    );
+                }
+            }
+            "format" => {
+                request_id.format = value
+                    .parse()
+                    .map_err(|err| LowerErr::new(format!("request_id: {err}")))?;
+            }
+            _ => {
+                return Err(LowerErr::new(format!(
+                    "request_id: unexpected argument `{option}`"
+                )));
+            }
+        }
+    }
+    Ok(Some(request_id))
+}
+
+fn ensure_access_log_combinable(current: &[AccessLog], log: &AccessLog) -> Result<(), LowerErr> {
+    let conflict = match log {
+        AccessLog::Off => !current.is_empty(),
+        AccessLog::To(_) => current.contains(&AccessLog::Off),
+    };
+    if conflict {
+        return Err(LowerErr::new(
+            "access_log: `off` cannot be combined with other access_log directives at the same level",
+        ));
+    }
+    Ok(())
+}
+
+fn lower_upstream_outlier_detection(block: &Block) -> Result<UpstreamOutlierDetection, LowerErr> {
+    if !block.args.is_empty() {
+        return Err(LowerErr::new(
+            "outlier_detection block: does not accept arguments",
+        ));
+    }
+
+    let mut consecutive_failure = None;
+    let mut base_ejection_time = None;
+    let mut max_ejection_time = None;
+    let mut max_ejection_percent = None;
+    for child in &block.children {
+        let directive = match child {
+            Node::Directive(directive) => directive,
+            Node::Block(nested) => {
+                return Err(LowerErr::new(format!(
+                    "outlier_detection block: nested blocks are not supported: {}",
+                    nested.name
+                )));
+            }
+        };
+        let name = format!("outlier_detection {}", directive.name);
+        match directive.name.as_str() {
+            consts::CONSECUTIVE_FAILURE => {
+                let value = parse_positive_usize(&directive.args, &name)?;
+                set_once(&mut consecutive_failure, value, &name)?;
+            }
+            consts::BASE_EJECTION_TIME => {
+                let value = parse_single_duration_directive(&directive.args, &name)?;
+                ensure_non_zero_duration(value, &name)?;
+                set_once(&mut base_ejection_time, value, &name)?;
+            }
+            consts::MAX_EJECTION_TIME => {
+                let value = parse_single_duration_directive(&directive.args, &name)?;
+                ensure_non_zero_duration(value, &name)?;
+                set_once(&mut max_ejection_time, value, &name)?;
+            }
+            consts::MAX_EJECTION_PERCENT => {
+                let value = parse_exactly_one_argument(&directive.args, &name)?;
+                let value = value
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|value| *value <= 100)
+                    .ok_or_else(|| {
+                        LowerErr::new(format!("{name}: expected a value between 0 and 100"))
+                    })?;
+                set_once(&mut max_ejection_percent, value, &name)?;
+            }
+            _ => {
+                return Err(unknown_name(
+                    format!(
+                        "unsupported outlier_detection directive: {}",
+                        directive.name
+                    ),
+                    &directive.name,
+                ));
+            }
+        }
+    }
+
+    let defaults = UpstreamOutlierDetection::default();
+    let base_ejection_time = base_ejection_time.unwrap_or(defaults.base_ejection_time);
+    let max_ejection_time =
+        max_ejection_time.unwrap_or(defaults.max_ejection_time.max(base_ejection_time));
+    if max_ejection_time < base_ejection_time {
+        return Err(LowerErr::new(
+            "outlier_detection: max_ejection_time is shorter than base_ejection_time",
+        ));
+    }
+
+    Ok(UpstreamOutlierDetection {
+        consecutive_failure: consecutive_failure.unwrap_or(defaults.consecutive_failure),
+        base_ejection_time,
+        max_ejection_time,
+        max_ejection_percent: max_ejection_percent.unwrap_or(defaults.max_ejection_percent),
+    })
+}
+
+fn parse_upstream_policy(args: &[String]) -> Result<UpstreamSelectionPolicy, LowerErr> {
+    match args {
+        [value] => match value.as_str() {
+            "round_robin" => Ok(UpstreamSelectionPolicy::RoundRobin),
+            "random" => Ok(UpstreamSelectionPolicy::Random),
+            "least_conn" => Ok(UpstreamSelectionPolicy::LeastConn),
+            _ => Err(LowerErr::new(format!(
+                "policy: unsupported upstream selection policy `{value}`; expected round_robin|random|least_conn, or use `hash <key> [consistent]`"
+            ))),
+        },
+        [] => Err(LowerErr::new("policy: expected 1 argument")),
+        _ => Err(LowerErr::new("policy: expected exactly 1 argument")),
+    }
+}
+
+// `hash <key> [consistent]`, where the key is a template such as
+// `$remote_addr`, `$http_x_tenant` or `$cookie_session`.
+fn parse_upstream_hash(args: &[String]) -> Result<UpstreamSelectionPolicy, LowerErr> {
+    let (key, consistent) = match args {
+        [key] => (key, false),
+        [key, flag] if flag == "consistent" => (key, true),
+        [_, flag] => {
+            return Err(LowerErr::new(format!(
+                "hash: unexpected argument `{flag}`; expected `consistent`"
+            )));
+        }
+        [] => {
+            return Err(LowerErr::new("hash: expected a key such as `$remote_addr`"));
+        }
+        _ => {
+            return Err(LowerErr::new(
+                "hash: expected a key and an optional `consistent`",
+            ));
+        }
+    };
+    let key = Template::parse(key).map_err(|err| LowerErr::new(format!("hash: {err}")))?;
+    if key.is_static() {
+        return Err(LowerErr::new(format!(
+            "hash: key `{key}` has no variables, so every request would pick the same server"
+        )));
+    }
+
+    Ok(UpstreamSelectionPolicy::Hash { key, consistent })
+}
+
+fn parse_exactly_one_argument(args: &[String], directive: &str) -> Result<String, LowerErr> {
+    match args {
+        [value] => Ok(value.clone()),
+        [] => Err(LowerErr::new(format!("{directive}: expected 1 argument"))),
+        _ => Err(LowerErr::new(format!(
+            "{directive}: expected exactly 1 argument"
+        ))),
+    }
+}
+
+fn parse_proxy_cache_store(args: &[String]) -> Result<CacheStoreConfig, LowerErr> {
+    let directive = consts::PROXY_CACHE_STORE;
+    let (target, options) = match args {
+        [] => {
+            return Err(LowerErr::new(format!(
+                "{directive}: expected `memory` or a redis:// URL"
+            )));
+        }
+        [target, options @ ..] => (target, options),
+    };
+
+    if target == "memory" {
+        if !options.is_empty() {
+            return Err(LowerErr::new(format!(
+                "{directive}: memory takes no options"
+            )));
         }
+        return Ok(CacheStoreConfig::Memory);
     }
 
-    Ok(())
+    let mut key_prefix = None;
+    for option in options {
+        match option.split_once('=') {
+            Some(("prefix", value)) if key_prefix.is_none() => {
+                key_prefix = Some(value.to_string());
+            }
+            _ => {
+                return Err(LowerErr::new(format!(
+                    "{directive}: unsupported option `{option}`"
+                )));
+            }
+        }
+    }
+    CacheStoreConfig::redis(target, key_prefix)
+        .map_err(|message| LowerErr::new(format!("{directive}: {message}")))
 }
 
-fn parse_upstream_policy(args: &[String]) -> Result<UpstreamSelectionPolicy, LowerErr> {
-    match args {
-        [value] => match value.as_str() {
-            "round_robin" => Ok(UpstreamSelectionPolicy::RoundRobin),
-            "random" => Ok(UpstreamSelectionPolicy::Random),
-            _ => Err(LowerErr {
-                message: format!(
-                    "policy: unsupported upstream selection policy `{value}`; expected round_robin|random"
-                ),
-            }),
-        },
-        [] => Err(LowerErr {
-            message: "policy: expected 1 argument".into(),
-        }),
-        _ => Err(LowerErr {
-            message: "policy: expected exactly 1 argument".into(),
-        }),
+fn parse_resolver(args: &[String]) -> Result<DnsResolver, LowerErr> {
+    let directive = consts::RESOLVER;
+    let mut resolver = DnsResolver {
+        addrs: Vec::new(),
+        valid: None,
+    };
+
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("valid=") {
+            set_once(
+                &mut resolver.valid,
+                parse_duration_literal(value, directive)?,
+                "resolver valid",
+            )?;
+            continue;
+        }
+        // `addr:port`, or a bare address on the standard DNS port.
+        let addr = arg
+            .parse::<SocketAddr>()
+            .ok()
+            .or_else(|| {
+                let ip = arg.strip_prefix('[').and_then(|ip| ip.strip_suffix(']'));
+                let ip = ip.unwrap_or(arg).parse::<IpAddr>().ok()?;
+                Some(SocketAddr::new(ip, 53))
+            })
+            .ok_or_else(|| {
+                LowerErr::new(format!(
+                    "{directive}: expected an IP address or valid=<time>, got `{arg}`"
+                ))
+            })?;
+        resolver.addrs.push(addr);
     }
-}
 
-fn parse_exactly_one_argument(args: &[String], directive: &str) -> Result<String, LowerErr> {
-    match args {
-        [value] => Ok(value.clone()),
-        [] => Err(LowerErr {
-            message: format!("{directive}: expected 1 argument"),
-        }),
-        _ => Err(LowerErr {
-            message: format!("{directive}: expected exactly 1 argument"),
-        }),
+    if resolver.addrs.is_empty() {
+        return Err(LowerErr::new(format!(
+            "{directive}: expected at least one name server address"
+        )));
     }
+    if resolver.valid.is_some_and(|valid| valid.is_zero()) {
+        return Err(LowerErr::new(format!(
+            "{directive}: valid must be greater than zero"
+        )));
+    }
+
+    Ok(resolver)
 }
 
 fn parse_positive_usize(args: &[String], directive: &str) -> Result<usize, LowerErr> {
     let value = parse_exactly_one_argument(args, directive)?;
-    let parsed = value.parse::<usize>().map_err(|_| LowerErr {
-        message: format!("{directive}: invalid integer `{value}`"),
-    })?;
+    let parsed = value
+        .parse::<usize>()
+        .map_err(|_| LowerErr::new(format!("{directive}: invalid integer `{value}`")))?;
     if parsed == 0 {
-        return Err(LowerErr {
-            message: format!("{directive}: value must be greater than zero"),
-        });
+        return Err(LowerErr::new(format!(
+            "{directive}: value must be greater than zero"
+        )));
     }
     Ok(parsed)
 }
 
 fn ensure_non_zero_duration(value: std::time::Duration, directive: &str) -> Result<(), LowerErr> {
     if value.is_zero() {
-        return Err(LowerErr {
-            message: format!("{directive}: value must be greater than zero"),
-        });
+        return Err(LowerErr::new(format!(
+            "{directive}: value must be greater than zero"
+        )));
     }
     Ok(())
 }
 
 fn set_once<T>(slot: &mut Option<T>, value: T, directive: &str) -> Result<(), LowerErr> {
     if slot.replace(value).is_some() {
-        return Err(LowerErr {
-            message: format!("{directive}: duplicated directive"),
-        });
+        return Err(LowerErr::new(format!("{directive}: duplicated directive")));
     }
     Ok(())
 }
 
-fn lower_location(block: &Block) -> Result<Location, LowerErr> {
-    let matcher = parse_location_matcher(&block.args)?;
-    let (directives, plugins, cache, access_rules) = parse_location_contents(&block.children)?;
-
-    Ok(Location {
-        matcher,
-        access_rules,
-        directives,
-        plugins,
-        cache,
-    })
+fn lower_location(block: &Block) -> Result<Location, Vec<LowerErr>> {
+    let matcher = parse_location_matcher(block).map_err(|e| vec![e.at(&block.span)])?;
+    parse_location_contents(matcher, &block.children)
 }
 
-fn parse_location_matcher(args: &[String]) -> Result<LocationMatcher, LowerErr> {
+fn parse_location_matcher(block: &Block) -> Result<LocationMatcher, LowerErr> {
+    let args = block.args.as_slice();
+    // Modifiers and `@name` only count when written bare: `location "=" {}`
+    // is a prefix location for `=`.
+    if block.is_quoted(0) {
+        return match args {
+            [path] => Ok(LocationMatcher::Prefix(path.clone())),
+            _ => Err(LowerErr::new(format!("invalid location args: {:?}", args))),
+        };
+    }
+
     match args {
         [op, path] if op == "=" => Ok(LocationMatcher::Exact(path.clone())),
 
@@ -761,23 +1736,15 @@ fn parse_location_matcher(args: &[String]) -> Result<LocationMatcher, LowerErr>
 
         [path] => Ok(LocationMatcher::Prefix(path.clone())),
 
-        _ => Err(LowerErr {
-            message: format!("invalid location args: {:?}", args),
-        }),
+        _ => Err(LowerErr::new(format!("invalid location args: {:?}", args))),
     }
 }
 
 fn parse_location_contents(
+    matcher: LocationMatcher,
     nodes: &[Node],
-) -> Result<
-    (
-        Vec<LocationDirective>,
-        Vec<PluginSpec>,
-        Option<CacheConfig>,
-        Vec<LocationIpRule>,
-    ),
-    LowerErr,
-> {
+) -> Result<Location, Vec<LowerErr>> {
+    let mut errors = Errors::default();
     let mut directives: Vec<LocationDirective> = Vec::new();
     let mut plugins: Vec<PluginSpec> = Vec::new();
     let mut cache: Option<CacheConfig> = None;
@@ -785,39 +1752,69 @@ fn parse_location_contents(
     for node in nodes {
         match node {
             Node::Directive(directive) => {
-                if let Some(cache_field) = apply_cache_directive(directive, cache.as_ref())? {
-                    if cache.replace(cache_field).is_some() {
-                        return Err(LowerErr {
-                            message: "duplicate proxy_cache directive in location".into(),
-                        });
-                    }
-                    continue;
-                }
-
-                if let Some(rule) = apply_location_access_rule(directive)? {
-                    access_rules.push(rule);
-                    continue;
-                }
-
-                let location_directive = apply_location_directive(directive)?;
-                directives.push(location_directive);
+                let result = apply_location_node_directive(
+                    directive,
+                    &mut cache,
+                    &mut access_rules,
+                    &mut directives,
+                );
+                errors.check(result, &directive.span);
             }
             Node::Block(block) => {
-                if block.name.as_str() == consts::PROXY_CACHE {
+                let result = if block.name.as_str() == consts::PROXY_CACHE {
                     if cache.is_some() {
-                        return Err(LowerErr {
-                            message: "duplicate proxy_cache block in location".into(),
-                        });
+                        Err(LowerErr::new("duplicate proxy_cache block in location"))
+                    } else {
+                        parse_proxy_cache_block(block).map(|config| cache = Some(config))
                     }
-                    cache = Some(parse_proxy_cache_block(block)?);
                 } else {
-                    plugins.push(parse_location_plugin_block(block)?);
-                }
+                    parse_location_plugin_block(block).map(|plugin| plugins.push(plugin))
+                };
+                errors.check(result, &block.span);
             }
         }
     }
 
-    Ok((directives, plugins, cache, access_rules))
+    errors.finish(Location {
+        matcher,
+        access_rules,
+        directives,
+        plugins,
+        cache,
+    })
+}
+
+fn apply_location_node_directive(
+    directive: &Directive,
+    cache: &mut Option<CacheConfig>,
+    access_rules: &mut Vec<LocationIpRule>,
+    directives: &mut Vec<LocationDirective>,
+) -> Result<(), LowerErr> {
+    if let Some(cache_field) = apply_cache_directive(directive, cache.as_ref())? {
+        if cache.replace(cache_field).is_some() {
+            return Err(LowerErr::new("duplicate proxy_cache directive in location"));
+        }
+        return Ok(());
+    }
+
+    if let Some(rule) = apply_location_access_rule(directive)? {
+        access_rules.push(rule);
+        return Ok(());
+    }
+
+    let location_directive = apply_location_directive(directive)?;
+    if let LocationDirective::AccessLog(log) = &location_directive {
+        let current: Vec<AccessLog> = directives
+            .iter()
+            .filter_map(|directive| match directive {
+                LocationDirective::AccessLog(log) => Some(log.clone()),
+                _ => None,
+            })
+            .collect();
+        ensure_access_log_combinable(&current, log)?;
+    }
+    directives.push(location_directive);
+    Ok(())
 }
 
 fn apply_location_access_rule(directive: &Directive) -> Result<Option<LocationIpRule>, LowerErr> {
@@ -829,9 +1826,9 @@ fn apply_location_access_rule(directive: &Directive) -> Result<Option<LocationIp
             return Ok(IpNet::from(address));
         }
 
-        Err(LowerErr {
-            message: format!("{directive}: expected an IP address or CIDR, got `{value}`"),
-        })
+        Err(LowerErr::new(format!(
+            "{directive}: expected an IP address or CIDR, got `{value}`"
+        )))
     }
 
     match directive.name.as_str() {
@@ -841,12 +1838,8 @@ fn apply_location_access_rule(directive: &Directive) -> Result<Option<LocationIp
                 value,
                 consts::ALLOW,
             )?))),
-            [] => Err(LowerErr {
-                message: "allow: expected <ip>|<cidr>|all".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "allow: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new("allow: expected <ip>|<cidr>|all")),
+            _ => Err(LowerErr::new("allow: expected exactly 1 argument")),
         },
         consts::DENY => match directive.args.as_slice() {
             [value] if value == consts::ALL => Ok(Some(LocationIpRule::DenyAll)),
@@ -854,12 +1847,8 @@ fn apply_location_access_rule(directive: &Directive) -> Result<Option<LocationIp
                 value,
                 consts::DENY,
             )?))),
-            [] => Err(LowerErr {
-                message: "deny: expected <ip>|<cidr>|all".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "deny: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new("deny: expected <ip>|<cidr>|all")),
+            _ => Err(LowerErr::new("deny: expected exactly 1 argument")),
         },
         _ => Ok(None),
     }
@@ -876,10 +1865,9 @@ fn apply_cache_directive(
             [value] if value == "on" || value == "off" => {
                 if value == "off" {
                     if existing.is_some() {
-                        return Err(LowerErr {
-                            message: "proxy_cache off cannot be used with other cache directives"
-                                .into(),
-                        });
+                        return Err(LowerErr::new(
+                            "proxy_cache off cannot be used with other cache directives",
+                        ));
                     }
                     // off = no cache for this location, represented as None
                     // but we keep it as a disabled CacheConfig to distinguish from "not configured"
@@ -892,12 +1880,10 @@ fn apply_cache_directive(
                 Ok(Some(CacheConfig::default()))
             }
             [] => Ok(Some(CacheConfig::default())),
-            _ => Err(LowerErr {
-                message: format!(
-                    "proxy_cache: expected 'on' or 'off', got {:?}",
-                    directive.args
-                ),
-            }),
+            _ => Err(LowerErr::new(format!(
+                "proxy_cache: expected 'on' or 'off', got {:?}",
+                directive.args
+            ))),
         },
         consts::PROXY_CACHE_TTL => {
             let ttl = parse_single_duration_directive(&directive.args, consts::PROXY_CACHE_TTL)?;
@@ -923,11 +1909,9 @@ fn apply_cache_directive(
                     "uri_and_method" => CacheKeyMode::UriAndMethod,
                     "normalized_uri" => CacheKeyMode::NormalizedUri,
                     _ => {
-                        return Err(LowerErr {
-                            message: format!(
-                                "proxy_cache_key: expected uri, uri_and_method, or normalized_uri, got {mode}"
-                            ),
-                        });
+                        return Err(LowerErr::new(format!(
+                            "proxy_cache_key: expected uri, uri_and_method, or normalized_uri, got {mode}"
+                        )));
                     }
                 };
                 Ok(existing.map(|c| CacheConfig {
@@ -935,12 +1919,10 @@ fn apply_cache_directive(
                     ..c.clone()
                 }))
             }
-            [] => Err(LowerErr {
-                message: "proxy_cache_key: expected argument".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "proxy_cache_key: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new("proxy_cache_key: expected argument")),
+            _ => Err(LowerErr::new(
+                "proxy_cache_key: expected exactly 1 argument",
+            )),
         },
         consts::PROXY_CACHE_MIN_USES => {
             let min = parse_positive_usize(&directive.args, consts::PROXY_CACHE_MIN_USES)?;
@@ -954,15 +1936,15 @@ fn apply_cache_directive(
                 .args
                 .iter()
                 .map(|s| {
-                    s.parse::<u16>().map_err(|_| LowerErr {
-                        message: format!("proxy_cache_valid: invalid status code `{s}`"),
+                    s.parse::<u16>().map_err(|_| {
+                        LowerErr::new(format!("proxy_cache_valid: invalid status code `{s}`"))
                     })
                 })
                 .collect::<Result<_, _>>()?;
             if statuses.is_empty() {
-                return Err(LowerErr {
-                    message: "proxy_cache_valid: expected at least one status code".into(),
-                });
+                return Err(LowerErr::new(
+                    "proxy_cache_valid: expected at least one status code",
+                ));
             }
             Ok(existing.map(|c| CacheConfig {
                 valid_statuses: statuses,
@@ -983,9 +1965,7 @@ fn apply_cache_directive(
 
 fn parse_proxy_cache_block(block: &Block) -> Result<CacheConfig, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: "proxy_cache block does not accept arguments".into(),
-        });
+        return Err(LowerErr::new("proxy_cache block does not accept arguments"));
     }
 
     let mut cache = CacheConfig::default();
@@ -998,12 +1978,10 @@ fn parse_proxy_cache_block(block: &Block) -> Result<CacheConfig, LowerErr> {
                 }
             }
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "proxy_cache block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "proxy_cache block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
@@ -1012,35 +1990,90 @@ fn parse_proxy_cache_block(block: &Block) -> Result<CacheConfig, LowerErr> {
 }
 
 fn parse_upstream_server(args: &[String]) -> Result<UpstreamServer, LowerErr> {
-    let raw = match args {
-        [value] => value,
+    let (raw, params) = match args {
+        [value, params @ ..] => (value, params),
         [] => {
-            return Err(LowerErr {
-                message: "upstream server: expected host:port".into(),
-            });
-        }
-        _ => {
-            return Err(LowerErr {
-                message: "upstream server: expected exactly 1 argument".into(),
-            });
+            return Err(LowerErr::new("upstream server: expected host:port"));
         }
     };
 
-    let (host, port) = split_upstream_host_port(raw).ok_or_else(|| LowerErr {
-        message: format!("upstream server: expected host:port, got `{raw}`"),
+    let mut weight = None;
+    let mut resolve = false;
+    let mut max_conns = None;
+    let mut backup = false;
+    let mut down = false;
+    let mut slow_start = None;
+    for param in params {
+        let flag = match param.as_str() {
+            "resolve" => Some(&mut resolve),
+            "backup" => Some(&mut backup),
+            "down" => Some(&mut down),
+            _ => None,
+        };
+        if let Some(flag) = flag {
+            if *flag {
+                return Err(LowerErr::new(format!(
+                    "upstream server: `{param}` is duplicated"
+                )));
+            }
+            *flag = true;
+            continue;
+        }
+        if let Some(value) = param.strip_prefix("slow_start=") {
+            let value = parse_duration_literal(value, "upstream server slow_start")?;
+            ensure_non_zero_duration(value, "upstream server slow_start")?;
+            set_once(&mut slow_start, value, "upstream server slow_start")?;
+            continue;
+        }
+        if let Some(value) = param.strip_prefix("max_conns=") {
+            let value = value.parse::<usize>().map_err(|_| {
+                LowerErr::new(format!("upstream server: invalid max_conns `{value}`"))
+            })?;
+            // nginx reads `max_conns=0` as "no limit".
+            set_once(&mut max_conns, value, "upstream server max_conns")?;
+            continue;
+        }
+        let Some(value) = param.strip_prefix("weight=") else {
+            return Err(LowerErr::new(format!(
+                "upstream server: unsupported parameter `{param}`; expected weight=N, max_conns=N, slow_start=T, backup, down or resolve"
+            )));
+        };
+        let value = value
+            .parse::<u32>()
+            .ok()
+            .filter(|weight| (1..=MAX_UPSTREAM_SERVER_WEIGHT).contains(weight))
+            .ok_or_else(|| LowerErr::new(format!(
+                    "upstream server: weight must be between 1 and {MAX_UPSTREAM_SERVER_WEIGHT}, got `{value}`"
+                )))?;
+        set_once(&mut weight, value, "upstream server weight")?;
+    }
+
+    let (host, port) = split_upstream_host_port(raw).ok_or_else(|| {
+        LowerErr::new(format!("upstream server: expected host:port, got `{raw}`"))
     })?;
     if host.is_empty() {
-        return Err(LowerErr {
-            message: format!("upstream server: missing host in `{raw}`"),
-        });
+        return Err(LowerErr::new(format!(
+            "upstream server: missing host in `{raw}`"
+        )));
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| LowerErr::new(format!("upstream server: invalid port in `{raw}`")))?;
+    if resolve && host.parse::<IpAddr>().is_ok() {
+        return Err(LowerErr::new(format!(
+            "upstream server: `resolve` needs a host name, got `{raw}`"
+        )));
     }
-    let port = port.parse::<u16>().map_err(|_| LowerErr {
-        message: format!("upstream server: invalid port in `{raw}`"),
-    })?;
 
     Ok(UpstreamServer {
         host: host.to_string(),
         port,
+        weight: weight.unwrap_or(1),
+        resolve,
+        max_conns: max_conns.filter(|max_conns| *max_conns > 0),
+        backup,
+        down,
+        slow_start,
     })
 }
 
@@ -1075,30 +2108,28 @@ fn parse_location_plugin_block(block: &Block) -> Result<PluginSpec, LowerErr> {
         consts::CORS => lower_cors_plugin(block),
         consts::EXT_AUTHZ => lower_ext_authz_plugin(block),
         consts::JWT_AUTH => lower_jwt_auth_plugin(block),
-        _ => Err(LowerErr {
-            message: format!("unexpected inner block in location: {}", block.name),
-        }),
+        _ => Err(unknown_name(
+            format!("unexpected inner block in location: {}", block.name),
+            &block.name,
+        )),
     }
 }
 
 fn lower_headers_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: "headers block: does not accept arguments".into(),
-        });
+        return Err(LowerErr::new("headers block: does not accept arguments"));
     }
 
     let mut config = HeadersPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_headers_directive(&mut config, directive)?,
+            Node::Directive(directive) => apply_headers_directive(&mut config, directive)
+                .map_err(|e| e.at(&directive.span))?,
             Node::Block(block) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "headers block: nested blocks are not supported: {}",
-                        block.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "headers block: nested blocks are not supported: {}",
+                    block.name
+                )));
             }
         }
     }
@@ -1112,22 +2143,22 @@ fn lower_headers_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
 
 fn lower_basic_auth_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: format!("{} block: does not accept arguments", block.name),
-        });
+        return Err(LowerErr::new(format!(
+            "{} block: does not accept arguments",
+            block.name
+        )));
     }
 
     let mut config = BasicAuthPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_basic_auth_directive(&mut config, directive)?,
+            Node::Directive(directive) => apply_basic_auth_directive(&mut config, directive)
+                .map_err(|e| e.at(&directive.span))?,
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "basic-auth block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "basic-auth block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
@@ -1200,9 +2231,9 @@ fn apply_headers_directive(
         }
         consts::FORWARD_CLIENT_IP => {
             if config.forward_client_ip.is_some() {
-                return Err(LowerErr {
-                    message: "headers block: duplicate forward_client_ip directive".into(),
-                });
+                return Err(LowerErr::new(
+                    "headers block: duplicate forward_client_ip directive",
+                ));
             }
             config.forward_client_ip = Some(get_directive_switch(directive)? == Switch::On);
         }
@@ -1213,9 +2244,10 @@ fn apply_headers_directive(
             )?);
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("headers block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("headers block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
 
@@ -1256,15 +2288,14 @@ fn apply_basic_auth_directive(
                 "expected at least 1 argument",
             )?;
             if config.realm.replace(realm).is_some() {
-                return Err(LowerErr {
-                    message: "basic-auth block: duplicate realm directive".into(),
-                });
+                return Err(LowerErr::new("basic-auth block: duplicate realm directive"));
             }
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("basic-auth block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("basic-auth block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
 
@@ -1273,30 +2304,44 @@ fn apply_basic_auth_directive(
 
 fn lower_rate_limit_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: format!("{} block: does not accept arguments", block.name),
-        });
+        return Err(LowerErr::new(format!(
+            "{} block: does not accept arguments",
+            block.name
+        )));
     }
 
     let mut config = RateLimitPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_rate_limit_directive(&mut config, directive)?,
+            Node::Directive(directive) => apply_rate_limit_directive(&mut config, directive)
+                .map_err(|e| e.at(&directive.span))?,
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "rate-limit block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "rate-limit block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
 
-    if config.max_requests_per_second <= 0 {
-        return Err(LowerErr {
-            message: "rate-limit block: must specify positive `rate`".into(),
-        });
+    if config.max_requests_per_second.is_none() && config.rate.is_none() {
+        return Err(LowerErr::new(
+            "rate-limit block: must specify positive `rate`",
+        ));
+    }
+    if config.rate.is_none() && (config.burst.is_some() || config.nodelay) {
+        return Err(LowerErr::new(
+            "rate-limit block: `burst` and `nodelay` require a `rate` with a unit, such as `10r/s`",
+        ));
+    }
+    if config
+        .store
+        .as_ref()
+        .is_some_and(|store| store.redis_url.is_empty())
+    {
+        return Err(LowerErr::new(
+            "rate-limit block: `store_failure` and `store_timeout` require `store`",
+        ));
     }
 
     let config = serde_json::to_value(config).expect("rate-limit plugin config serializes");
@@ -1313,48 +2358,203 @@ fn apply_rate_limit_directive(
     match directive.name.as_str() {
         consts::RATE => {
             let val = parse_exactly_one_argument(&directive.args, consts::RATE)?;
-            let rate = val.parse::<isize>().map_err(|_| LowerErr {
-                message: format!("rate-limit block: rate must be an integer, got `{val}`"),
+            if config.max_requests_per_second.is_some() || config.rate.is_some() {
+                return Err(LowerErr::new(
+                    "rate-limit block: duplicate `rate` directive",
+                ));
+            }
+            // `rate 10;` keeps the per-second budget; `rate 10r/m;` is a token bucket.
+            match val.split_once("r/") {
+                Some((requests, unit)) => {
+                    let per = match unit {
+                        "s" => "second",
+                        "m" => "minute",
+                        "h" => "hour",
+                        _ => {
+                            return Err(LowerErr::new(format!(
+                                "rate-limit block: rate unit must be r/s, r/m or r/h, got `{val}`"
+                            )));
+                        }
+                    };
+                    let requests =
+                        requests
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .ok_or_else(|| {
+                                LowerErr::new(format!(
+                                    "rate-limit block: rate must be positive, got `{val}`"
+                                ))
+                            })?;
+                    config.rate = Some(RateLimitRate { requests, per });
+                }
+                None => {
+                    let rate = val.parse::<isize>().map_err(|_| {
+                        LowerErr::new(format!(
+                            "rate-limit block: rate must be an integer, got `{val}`"
+                        ))
+                    })?;
+                    if rate <= 0 {
+                        return Err(LowerErr::new(format!(
+                            "rate-limit block: rate must be positive, got `{rate}`"
+                        )));
+                    }
+                    config.max_requests_per_second = Some(rate);
+                }
+            }
+        }
+        consts::BURST => {
+            let val = parse_exactly_one_argument(&directive.args, consts::BURST)?;
+            let burst = val.parse::<u32>().map_err(|_| {
+                LowerErr::new(format!(
+                    "rate-limit block: burst must be a non-negative integer, got `{val}`"
+                ))
             })?;
-            if rate <= 0 {
-                return Err(LowerErr {
-                    message: format!("rate-limit block: rate must be positive, got `{rate}`"),
-                });
+            if config.burst.replace(burst).is_some() {
+                return Err(LowerErr::new(
+                    "rate-limit block: duplicate `burst` directive",
+                ));
+            }
+        }
+        consts::NODELAY => {
+            if !directive.args.is_empty() {
+                return Err(LowerErr::new(
+                    "rate-limit block: `nodelay` takes no arguments",
+                ));
             }
-            if config.max_requests_per_second > 0 {
-                return Err(LowerErr {
-                    message: "rate-limit block: duplicate `rate` directive".into(),
-                });
+            config.nodelay = true;
+        }
+        consts::KEY => {
+            let key = parse_rate_limit_key(&directive.args)?;
+            if config.key.replace(key).is_some() {
+                return Err(LowerErr::new("rate-limit block: duplicate `key` directive"));
+            }
+        }
+        consts::STORE => {
+            let store = config.store.get_or_insert_with(Default::default);
+            if !store.redis_url.is_empty() {
+                return Err(LowerErr::new(
+                    "rate-limit block: duplicate `store` directive",
+                ));
+            }
+            let (url, options) = match directive.args.as_slice() {
+                [url, options @ ..] => (url, options),
+                [] => {
+                    return Err(LowerErr::new(
+                        "rate-limit block: `store` expects a redis:// URL",
+                    ));
+                }
+            };
+            validate_redis_url(url)
+                .map_err(|message| LowerErr::new(format!("rate-limit block: {message}")))?;
+            for option in options {
+                match option.split_once('=') {
+                    Some(("prefix", value))
+                        if store.key_prefix.is_none() && is_valid_redis_key_prefix(value) =>
+                    {
+                        store.key_prefix = Some(value.to_string());
+                    }
+                    _ => {
+                        return Err(LowerErr::new(format!(
+                            "rate-limit block: unsupported `store` option `{option}`"
+                        )));
+                    }
+                }
+            }
+            store.redis_url = url.clone();
+        }
+        consts::STORE_FAILURE => {
+            let val = parse_exactly_one_argument(&directive.args, consts::STORE_FAILURE)?;
+            if val != "open" && val != "closed" {
+                return Err(LowerErr::new(format!(
+                    "rate-limit block: store_failure must be `open` or `closed`, got `{val}`"
+                )));
+            }
+            let store = config.store.get_or_insert_with(Default::default);
+            if store.on_error.replace(val).is_some() {
+                return Err(LowerErr::new(
+                    "rate-limit block: duplicate `store_failure` directive",
+                ));
+            }
+        }
+        consts::STORE_TIMEOUT => {
+            let value = parse_single_duration_directive(
+                &directive.args,
+                "rate-limit block: store_timeout",
+            )?;
+            ensure_non_zero_duration(value, "rate-limit block: store_timeout")?;
+            let store = config.store.get_or_insert_with(Default::default);
+            if store
+                .timeout_ms
+                .replace(value.as_millis().max(1) as u64)
+                .is_some()
+            {
+                return Err(LowerErr::new(
+                    "rate-limit block: duplicate `store_timeout` directive",
+                ));
             }
-            config.max_requests_per_second = rate;
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("rate-limit block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("rate-limit block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
     Ok(())
 }
 
+fn parse_rate_limit_key(args: &[String]) -> Result<RateLimitKey, LowerErr> {
+    let prefix_len = |raw: &str, max: u8| {
+        raw.parse::<u8>()
+            .ok()
+            .filter(|len| *len <= max)
+            .ok_or_else(|| {
+                LowerErr::new(format!(
+                    "rate-limit block: ip_prefix length must be 0-{max}, got `{raw}`"
+                ))
+            })
+    };
+    match args {
+        [kind] if kind == "ip" => Ok(RateLimitKey::Ip),
+        [kind] if kind == "jwt_sub" => Ok(RateLimitKey::JwtSubject),
+        [kind] if kind == "route" => Ok(RateLimitKey::Route),
+        [kind, name] if kind == "header" => Ok(RateLimitKey::Header {
+            name: name.to_ascii_lowercase(),
+        }),
+        [kind, ipv4] if kind == "ip_prefix" => Ok(RateLimitKey::IpPrefix {
+            ipv4: prefix_len(ipv4, 32)?,
+            ipv6: 64,
+        }),
+        [kind, ipv4, ipv6] if kind == "ip_prefix" => Ok(RateLimitKey::IpPrefix {
+            ipv4: prefix_len(ipv4, 32)?,
+            ipv6: prefix_len(ipv6, 128)?,
+        }),
+        _ => Err(LowerErr::new(
+            "rate-limit block: key must be `ip`, `ip_prefix <v4_len> [v6_len]`, `header <name>`, `jwt_sub` or `route`",
+        )),
+    }
+}
+
 fn lower_cors_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: format!("{} block: does not accept arguments", block.name),
-        });
+        return Err(LowerErr::new(format!(
+            "{} block: does not accept arguments",
+            block.name
+        )));
     }
 
     let mut config = CorsPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_cors_directive(&mut config, directive)?,
+            Node::Directive(directive) => {
+                apply_cors_directive(&mut config, directive).map_err(|e| e.at(&directive.span))?
+            }
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "cors block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "cors block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
@@ -1372,9 +2572,9 @@ fn apply_cors_directive(
 ) -> Result<(), LowerErr> {
     let check_dup = |opt: &Option<String>, name: &str| -> Result<(), LowerErr> {
         if opt.is_some() {
-            Err(LowerErr {
-                message: format!("cors block: duplicate `{name}` directive"),
-            })
+            Err(LowerErr::new(format!(
+                "cors block: duplicate `{name}` directive"
+            )))
         } else {
             Ok(())
         }
@@ -1382,9 +2582,9 @@ fn apply_cors_directive(
 
     let join_args = |args: &[String], name: &str| -> Result<String, LowerErr> {
         if args.is_empty() {
-            return Err(LowerErr {
-                message: format!("cors block: `{name}` requires at least 1 argument"),
-            });
+            return Err(LowerErr::new(format!(
+                "cors block: `{name}` requires at least 1 argument"
+            )));
         }
         let mut joined = args.join(" ");
         if (joined.starts_with('"') && joined.ends_with('"') && joined.len() >= 2)
@@ -1414,47 +2614,45 @@ fn apply_cors_directive(
         }
         consts::ALLOW_CREDENTIALS => {
             if config.allow_credentials.is_some() {
-                return Err(LowerErr {
-                    message: format!(
-                        "cors block: duplicate `{}` directive",
-                        consts::ALLOW_CREDENTIALS
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "cors block: duplicate `{}` directive",
+                    consts::ALLOW_CREDENTIALS
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::ALLOW_CREDENTIALS)?;
             let b = match val.as_str() {
                 "on" => true,
                 "off" => false,
                 _ => {
-                    return Err(LowerErr {
-                        message: format!(
-                            "cors block: {} must be `on` or `off`, got `{val}`",
-                            consts::ALLOW_CREDENTIALS
-                        ),
-                    });
+                    return Err(LowerErr::new(format!(
+                        "cors block: {} must be `on` or `off`, got `{val}`",
+                        consts::ALLOW_CREDENTIALS
+                    )));
                 }
             };
             config.allow_credentials = Some(b);
         }
         consts::MAX_AGE => {
             if config.max_age.is_some() {
-                return Err(LowerErr {
-                    message: format!("cors block: duplicate `{}` directive", consts::MAX_AGE),
-                });
+                return Err(LowerErr::new(format!(
+                    "cors block: duplicate `{}` directive",
+                    consts::MAX_AGE
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::MAX_AGE)?;
-            let age = val.parse::<u64>().map_err(|_| LowerErr {
-                message: format!(
+            let age = val.parse::<u64>().map_err(|_| {
+                LowerErr::new(format!(
                     "cors block: {} must be an integer, got `{val}`",
                     consts::MAX_AGE
-                ),
+                ))
             })?;
             config.max_age = Some(age);
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("cors block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("cors block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
     Ok(())
@@ -1462,30 +2660,28 @@ fn apply_cors_directive(
 
 fn lower_ext_authz_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: format!("{} block: does not accept arguments", block.name),
-        });
+        return Err(LowerErr::new(format!(
+            "{} block: does not accept arguments",
+            block.name
+        )));
     }
 
     let mut config = ExtAuthzPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_ext_authz_directive(&mut config, directive)?,
+            Node::Directive(directive) => apply_ext_authz_directive(&mut config, directive)
+                .map_err(|e| e.at(&directive.span))?,
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "ext_authz block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "ext_authz block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
 
     if config.uri.is_empty() {
-        return Err(LowerErr {
-            message: "ext_authz block: missing `uri` directive".into(),
-        });
+        return Err(LowerErr::new("ext_authz block: missing `uri` directive"));
     }
 
     let config_val = serde_json::to_value(config).expect("ext_authz plugin config serializes");
@@ -1502,33 +2698,33 @@ fn apply_ext_authz_directive(
     match directive.name.as_str() {
         consts::URI => {
             if !config.uri.is_empty() {
-                return Err(LowerErr {
-                    message: format!("ext_authz block: duplicate `{}` directive", consts::URI),
-                });
+                return Err(LowerErr::new(format!(
+                    "ext_authz block: duplicate `{}` directive",
+                    consts::URI
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::URI)?;
             config.uri = val;
         }
         consts::TIMEOUT => {
             if config.timeout_ms.is_some() {
-                return Err(LowerErr {
-                    message: format!("ext_authz block: duplicate `{}` directive", consts::TIMEOUT),
-                });
+                return Err(LowerErr::new(format!(
+                    "ext_authz block: duplicate `{}` directive",
+                    consts::TIMEOUT
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::TIMEOUT)?;
             if val.ends_with("ms") || val.ends_with('s') {
-                return Err(LowerErr {
-                    message: format!(
-                        "ext_authz block: `{}` expects integer ms, got `{val}`. Strip suffix.",
-                        consts::TIMEOUT
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "ext_authz block: `{}` expects integer ms, got `{val}`. Strip suffix.",
+                    consts::TIMEOUT
+                )));
             }
-            let ms = val.parse::<u64>().map_err(|_| LowerErr {
-                message: format!(
+            let ms = val.parse::<u64>().map_err(|_| {
+                LowerErr::new(format!(
                     "ext_authz block: `{}` must be an integer (ms), got `{val}`",
                     consts::TIMEOUT
-                ),
+                ))
             })?;
             config.timeout_ms = Some(ms);
         }
@@ -1541,9 +2737,10 @@ fn apply_ext_authz_directive(
             config.pass_response_headers.push(val);
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("ext_authz block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("ext_authz block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
     Ok(())
@@ -1551,35 +2748,35 @@ fn apply_ext_authz_directive(
 
 fn lower_jwt_auth_plugin(block: &Block) -> Result<PluginSpec, LowerErr> {
     if !block.args.is_empty() {
-        return Err(LowerErr {
-            message: format!("{} block: does not accept arguments", block.name),
-        });
+        return Err(LowerErr::new(format!(
+            "{} block: does not accept arguments",
+            block.name
+        )));
     }
 
     let mut config = JwtAuthPluginConfig::default();
     for child in &block.children {
         match child {
-            Node::Directive(directive) => apply_jwt_auth_directive(&mut config, directive)?,
+            Node::Directive(directive) => apply_jwt_auth_directive(&mut config, directive)
+                .map_err(|e| e.at(&directive.span))?,
             Node::Block(nested) => {
-                return Err(LowerErr {
-                    message: format!(
-                        "jwt_auth block: nested blocks are not supported: {}",
-                        nested.name
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "jwt_auth block: nested blocks are not supported: {}",
+                    nested.name
+                )));
             }
         }
     }
 
     if config.algorithm.is_empty() {
-        return Err(LowerErr {
-            message: "jwt_auth block: missing `algorithm` directive".into(),
-        });
+        return Err(LowerErr::new(
+            "jwt_auth block: missing `algorithm` directive",
+        ));
     }
     if config.secret.is_none() && config.secret_file.is_none() {
-        return Err(LowerErr {
-            message: "jwt_auth block: either `secret` or `secret_file` must be provided".into(),
-        });
+        return Err(LowerErr::new(
+            "jwt_auth block: either `secret` or `secret_file` must be provided",
+        ));
     }
 
     let config_val = serde_json::to_value(config).expect("jwt_auth plugin config serializes");
@@ -1596,41 +2793,39 @@ fn apply_jwt_auth_directive(
     match directive.name.as_str() {
         consts::ALGORITHM => {
             if !config.algorithm.is_empty() {
-                return Err(LowerErr {
-                    message: format!(
-                        "jwt_auth block: duplicate `{}` directive",
-                        consts::ALGORITHM
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "jwt_auth block: duplicate `{}` directive",
+                    consts::ALGORITHM
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::ALGORITHM)?;
             config.algorithm = val;
         }
         consts::SECRET => {
             if config.secret.is_some() {
-                return Err(LowerErr {
-                    message: format!("jwt_auth block: duplicate `{}` directive", consts::SECRET),
-                });
+                return Err(LowerErr::new(format!(
+                    "jwt_auth block: duplicate `{}` directive",
+                    consts::SECRET
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::SECRET)?;
             config.secret = Some(val);
         }
         consts::SECRET_FILE => {
             if config.secret_file.is_some() {
-                return Err(LowerErr {
-                    message: format!(
-                        "jwt_auth block: duplicate `{}` directive",
-                        consts::SECRET_FILE
-                    ),
-                });
+                return Err(LowerErr::new(format!(
+                    "jwt_auth block: duplicate `{}` directive",
+                    consts::SECRET_FILE
+                )));
             }
             let val = parse_exactly_one_argument(&directive.args, consts::SECRET_FILE)?;
             config.secret_file = Some(val);
         }
         _ => {
-            return Err(LowerErr {
-                message: format!("jwt_auth block: unsupported directive {}", directive.name),
-            });
+            return Err(unknown_name(
+                format!("jwt_auth block: unsupported directive {}", directive.name),
+                &directive.name,
+            ));
         }
     }
     Ok(())
@@ -1638,24 +2833,29 @@ fn apply_jwt_auth_directive(
 
 fn parse_header_entry(args: &[String], directive: &str) -> Result<HeaderEntry, LowerErr> {
     match args {
-        [] => Err(LowerErr {
-            message: format!("{directive}: expected header name and value"),
-        }),
-        [name] => Err(LowerErr {
-            message: format!("{directive}: expected header value for `{name}`"),
-        }),
-        [name, value @ ..] => Ok(HeaderEntry {
-            name: name.clone(),
-            value: value.join(" "),
-        }),
+        [] => Err(LowerErr::new(format!(
+            "{directive}: expected header name and value"
+        ))),
+        [name] => Err(LowerErr::new(format!(
+            "{directive}: expected header value for `{name}`"
+        ))),
+        [name, value @ ..] => {
+            let value = value.join(" ");
+            Template::parse(&value).map_err(|err| LowerErr::new(format!("{directive}: {err}")))?;
+
+            Ok(HeaderEntry {
+                name: name.clone(),
+                value,
+            })
+        }
     }
 }
 
 fn assign_basic_auth_string(slot: &mut String, field: &str, value: String) -> Result<(), LowerErr> {
     if !slot.is_empty() {
-        return Err(LowerErr {
-            message: format!("basic-auth block: duplicate {field} directive"),
-        });
+        return Err(LowerErr::new(format!(
+            "basic-auth block: duplicate {field} directive"
+        )));
     }
 
     *slot = value;
@@ -1669,9 +2869,7 @@ fn parse_basic_auth_single_value(
 ) -> Result<String, LowerErr> {
     match args {
         [value] => Ok(value.clone()),
-        _ => Err(LowerErr {
-            message: format!("{directive}: {expected_message}"),
-        }),
+        _ => Err(LowerErr::new(format!("{directive}: {expected_message}"))),
     }
 }
 
@@ -1681,9 +2879,7 @@ fn parse_basic_auth_joined_value(
     expected_message: &str,
 ) -> Result<String, LowerErr> {
     match args {
-        [] => Err(LowerErr {
-            message: format!("{directive}: {expected_message}"),
-        }),
+        [] => Err(LowerErr::new(format!("{directive}: {expected_message}"))),
         values => Ok(values.join(" ")),
     }
 }
@@ -1691,12 +2887,10 @@ fn parse_basic_auth_joined_value(
 fn parse_header_remove(args: &[String], directive: &str) -> Result<String, LowerErr> {
     match args {
         [name] => Ok(name.clone()),
-        [] => Err(LowerErr {
-            message: format!("{directive}: expected header name"),
-        }),
-        _ => Err(LowerErr {
-            message: format!("{directive}: expected exactly 1 argument"),
-        }),
+        [] => Err(LowerErr::new(format!("{directive}: expected header name"))),
+        _ => Err(LowerErr::new(format!(
+            "{directive}: expected exactly 1 argument"
+        ))),
     }
 }
 
@@ -1706,36 +2900,71 @@ fn parse_proxy_upstream_protocol(args: &[String]) -> Result<UpstreamHttpProtocol
             "h1" => Ok(UpstreamHttpProtocol::H1),
             "h2" => Ok(UpstreamHttpProtocol::H2),
             "h2c" => Ok(UpstreamHttpProtocol::H2c),
-            _ => Err(LowerErr {
-                message: "proxy_upstream_protocol: expected h1|h2|h2c".into(),
-            }),
+            _ => Err(LowerErr::new("proxy_upstream_protocol: expected h1|h2|h2c")),
         },
-        [] => Err(LowerErr {
-            message: "proxy_upstream_protocol: expected h1|h2|h2c".into(),
-        }),
-        _ => Err(LowerErr {
-            message: "proxy_upstream_protocol: expected exactly 1 argument".into(),
-        }),
+        [] => Err(LowerErr::new("proxy_upstream_protocol: expected h1|h2|h2c")),
+        _ => Err(LowerErr::new(
+            "proxy_upstream_protocol: expected exactly 1 argument",
+        )),
+    }
+}
+
+// `proxy_next_upstream error timeout http_502 ... [non_idempotent] | off`.
+fn parse_proxy_next_upstream(args: &[String]) -> Result<NextUpstream, LowerErr> {
+    if let [value] = args
+        && value == "off"
+    {
+        return Ok(NextUpstream::default());
+    }
+    if args.is_empty() {
+        return Err(LowerErr::new(
+            "proxy_next_upstream: expected at least 1 argument",
+        ));
+    }
+
+    let mut next = NextUpstream::default();
+    for value in args {
+        let flag = match value.as_str() {
+            "error" => &mut next.error,
+            "timeout" => &mut next.timeout,
+            "http_502" => &mut next.http_502,
+            "http_503" => &mut next.http_503,
+            "http_504" => &mut next.http_504,
+            "non_idempotent" => &mut next.non_idempotent,
+            "off" => {
+                return Err(LowerErr::new(
+                    "proxy_next_upstream: `off` cannot be combined with other values",
+                ));
+            }
+            _ => {
+                return Err(LowerErr::new(format!(
+                    "proxy_next_upstream: unsupported value `{value}`; expected error|timeout|http_502|http_503|http_504|non_idempotent|off"
+                )));
+            }
+        };
+        *flag = true;
+    }
+    if !next.is_enabled() {
+        return Err(LowerErr::new(
+            "proxy_next_upstream: `non_idempotent` needs at least one failure condition",
+        ));
     }
+
+    Ok(next)
 }
 
 fn apply_location_directive(directive: &Directive) -> Result<LocationDirective, LowerErr> {
     match directive.name.as_str() {
         consts::PROXY_PASS => match directive.args.as_slice() {
             [raw_url] => {
-                let parsed_url = Url::parse(raw_url).map_err(|e| LowerErr {
-                    message: format!("proxy_pass: invalid URL: {:?}", e),
-                })?;
+                let parsed_url = Url::parse(raw_url)
+                    .map_err(|e| LowerErr::new(format!("proxy_pass: invalid URL: {:?}", e)))?;
                 Ok(LocationDirective::ProxyPass(ProxyPassTarget::Url(
                     parsed_url,
                 )))
             }
-            [] => Err(LowerErr {
-                message: "proxy_pass: expected URL".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "proxy_pass: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new("proxy_pass: expected URL")),
+            _ => Err(LowerErr::new("proxy_pass: expected exactly 1 argument")),
         },
         consts::PROXY_CONNECT_TIMEOUT => Ok(LocationDirective::ProxyConnectTimeout(
             parse_single_duration_directive(&directive.args, consts::PROXY_CONNECT_TIMEOUT)?,
@@ -1749,89 +2978,182 @@ fn apply_location_directive(directive: &Directive) -> Result<LocationDirective,
         consts::PROXY_UPSTREAM_PROTOCOL => Ok(LocationDirective::ProxyUpstreamProtocol(
             parse_proxy_upstream_protocol(&directive.args)?,
         )),
+        consts::PROXY_NEXT_UPSTREAM => Ok(LocationDirective::ProxyNextUpstream(
+            parse_proxy_next_upstream(&directive.args)?,
+        )),
+        consts::PROXY_NEXT_UPSTREAM_TRIES => match directive.args.as_slice() {
+            [value] => value
+                .parse::<u32>()
+                .map(LocationDirective::ProxyNextUpstreamTries)
+                .map_err(|_| {
+                    LowerErr::new(format!(
+                        "proxy_next_upstream_tries: expected a non-negative integer, got `{value}`"
+                    ))
+                }),
+            _ => Err(LowerErr::new(
+                "proxy_next_upstream_tries: expected exactly 1 argument",
+            )),
+        },
+        consts::PROXY_NEXT_UPSTREAM_TIMEOUT => Ok(LocationDirective::ProxyNextUpstreamTimeout(
+            parse_single_duration_directive(&directive.args, consts::PROXY_NEXT_UPSTREAM_TIMEOUT)?,
+        )),
         consts::PROXY_SSL_VERIFY => Ok(LocationDirective::ProxySslVerify(get_directive_switch(
             directive,
         )?)),
         consts::PROXY_SSL_TRUSTED_CERTIFICATE => match directive.args.as_slice() {
             [path] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(path), false).map_err(|_| LowerErr {
-                        message: "proxy_ssl_trusted_certificate: invalid certificate source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(path), false).map_err(|_| {
+                    LowerErr::new("proxy_ssl_trusted_certificate: invalid certificate source")
+                })?;
                 Ok(LocationDirective::ProxySslTrustedCertificate(ps))
             }
-            [] => Err(LowerErr {
-                message: "proxy_ssl_trusted_certificate: expected 1 argument".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "proxy_ssl_trusted_certificate: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new(
+                "proxy_ssl_trusted_certificate: expected 1 argument",
+            )),
+            _ => Err(LowerErr::new(
+                "proxy_ssl_trusted_certificate: expected exactly 1 argument",
+            )),
         },
 
         consts::PROXY_SSL_CERTIFICATE => match directive.args.as_slice() {
             [path] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(path), false).map_err(|_| LowerErr {
-                        message: "proxy_ssl_certificate: invalid certificate source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(path), false).map_err(|_| {
+                    LowerErr::new("proxy_ssl_certificate: invalid certificate source")
+                })?;
                 Ok(LocationDirective::ProxySslCertificate(ps))
             }
-            [] => Err(LowerErr {
-                message: "proxy_ssl_certificate: expected 1 argument".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "proxy_ssl_certificate: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new("proxy_ssl_certificate: expected 1 argument")),
+            _ => Err(LowerErr::new(
+                "proxy_ssl_certificate: expected exactly 1 argument",
+            )),
         },
 
         consts::PROXY_SSL_CERTIFICATE_KEY => match directive.args.as_slice() {
             [path] => {
-                let ps =
-                    PemSource::new(std::slice::from_ref(path), false).map_err(|_| LowerErr {
-                        message: "proxy_ssl_certificate_key: invalid key source".into(),
-                    })?;
+                let ps = PemSource::new(std::slice::from_ref(path), false)
+                    .map_err(|_| LowerErr::new("proxy_ssl_certificate_key: invalid key source"))?;
                 Ok(LocationDirective::ProxySslCertificateKey(ps))
             }
-            [] => Err(LowerErr {
-                message: "proxy_ssl_certificate_key: expected 1 argument".into(),
-            }),
-            _ => Err(LowerErr {
-                message: "proxy_ssl_certificate_key: expected exactly 1 argument".into(),
-            }),
+            [] => Err(LowerErr::new(
+                "proxy_ssl_certificate_key: expected 1 argument",
+            )),
+            _ => Err(LowerErr::new(
+                "proxy_ssl_certificate_key: expected exactly 1 argument",
+            )),
         },
 
         consts::RETURN => match directive.args.as_slice() {
             [code, location] => {
-                let status = code.parse().map_err(|_| LowerErr {
-                    message: format!("return: invalid status code `{code}`"),
-                })?;
+                let status = code
+                    .parse()
+                    .map_err(|_| LowerErr::new(format!("return: invalid status code `{code}`")))?;
                 if !(300..=399).contains(&status) {
-                    return Err(LowerErr {
-                        message: format!(
-                            "return: status {status} is not a redirect (expected 3xx)"
-                        ),
-                    });
+                    return Err(LowerErr::new(format!(
+                        "return: status {status} is not a redirect (expected 3xx)"
+                    )));
                 }
 
+                Template::parse(location).map_err(|err| LowerErr::new(format!("return: {err}")))?;
+
                 Ok(LocationDirective::Return {
                     status,
                     location: location.clone(),
                 })
             }
 
-            [] => Err(LowerErr {
-                message: "return: expected 2 arguments: <status> <location>".into(),
-            }),
+            [] => Err(LowerErr::new(
+                "return: expected 2 arguments: <status> <location>",
+            )),
 
-            _ => Err(LowerErr {
-                message: "return: expected exactly 2 arguments: <status> <location>".into(),
-            }),
+            _ => Err(LowerErr::new(
+                "return: expected exactly 2 arguments: <status> <location>",
+            )),
         },
 
-        _ => Err(LowerErr {
-            message: format!("unknown directive in location: {}", directive.name),
-        }),
+        consts::ROOT => Ok(LocationDirective::Root(parse_single_path(directive)?)),
+        consts::ALIAS => Ok(LocationDirective::Alias(parse_single_path(directive)?)),
+
+        consts::INDEX => {
+            if directive.args.is_empty() || directive.args.iter().any(|file| file.is_empty()) {
+                return Err(LowerErr::new("index: expected at least 1 file name"));
+            }
+            Ok(LocationDirective::Index(directive.args.clone()))
+        }
+
+        consts::TRY_FILES => match directive.args.as_slice() {
+            [files @ .., fallback] if !files.is_empty() => {
+                for file in files {
+                    Template::parse(file)
+                        .map_err(|err| LowerErr::new(format!("try_files: {err}")))?;
+                }
+
+                Ok(LocationDirective::TryFiles {
+                    files: files.to_vec(),
+                    fallback: parse_try_files_fallback(fallback)?,
+                })
+            }
+            _ => Err(LowerErr::new(
+                "try_files: expected at least 2 arguments: <file>... <fallback>",
+            )),
+        },
+
+        consts::ACCESS_LOG => Ok(LocationDirective::AccessLog(parse_access_log(
+            &directive.args,
+        )?)),
+
+        consts::ROUTE_NAME => match directive.args.as_slice() {
+            [name] if !name.is_empty() => Ok(LocationDirective::RouteName(name.clone())),
+            _ => Err(LowerErr::new(
+                "route_name: expected exactly 1 name argument",
+            )),
+        },
+
+        _ => Err(unknown_name(
+            format!("unknown directive in location: {}", directive.name),
+            &directive.name,
+        )),
+    }
+}
+
+fn parse_single_path(directive: &Directive) -> Result<String, LowerErr> {
+    match directive.args.as_slice() {
+        [path] if !path.is_empty() => Ok(path.clone()),
+        _ => Err(LowerErr::new(format!(
+            "{}: expected exactly 1 path argument",
+            directive.name
+        ))),
+    }
+}
+
+fn parse_try_files_fallback(value: &str) -> Result<TryFilesFallback, LowerErr> {
+    if let Some(code) = value.strip_prefix('=') {
+        let status = code
+            .parse::<u16>()
+            .ok()
+            .filter(|status| (200..=599).contains(status))
+            .ok_or_else(|| {
+                LowerErr::new(format!("try_files: invalid fallback status `{value}`"))
+            })?;
+        return Ok(TryFilesFallback::Status(status));
+    }
+
+    if let Some(name) = value.strip_prefix('@') {
+        if name.is_empty() {
+            return Err(LowerErr::new(
+                "try_files: named location fallback cannot be empty",
+            ));
+        }
+        return Ok(TryFilesFallback::Named(name.to_string()));
+    }
+
+    if !value.starts_with('/') {
+        return Err(LowerErr::new(format!(
+            "try_files: fallback `{value}` must be `=<status>`, `@<name>` or a URI starting with `/`"
+        )));
     }
+    Template::parse(value).map_err(|err| LowerErr::new(format!("try_files: {err}")))?;
+
+    Ok(TryFilesFallback::Uri(value.to_string()))
 }
 
 fn block_named<'a>(node: &'a Node, name: &'a str) -> Option<&'a Block> {
@@ -1846,24 +3168,21 @@ fn get_directive_switch(d: &Directive) -> Result<Switch, LowerErr> {
         [value] => match value.as_str() {
             "on" => Ok(Switch::On),
             "off" => Ok(Switch::Off),
-            _ => Err(LowerErr {
-                message: format!("{}: expected on|off", d.name),
-            }),
+            _ => Err(LowerErr::new(format!("{}: expected on|off", d.name))),
         },
-        [] => Err(LowerErr {
-            message: format!("{}: expected on|off", d.name),
-        }),
-        _ => Err(LowerErr {
-            message: format!("{}: expected exactly one argument on|off", d.name),
-        }),
+        [] => Err(LowerErr::new(format!("{}: expected on|off", d.name))),
+        _ => Err(LowerErr::new(format!(
+            "{}: expected exactly one argument on|off",
+            d.name
+        ))),
     }
 }
 
 fn parse_keepalive_timeout(args: &[String]) -> Result<KeepaliveTimeout, LowerErr> {
     match args {
-        [] => Err(LowerErr {
-            message: "keepalive_timeout: expected 1 or 2 arguments".into(),
-        }),
+        [] => Err(LowerErr::new(
+            "keepalive_timeout: expected 1 or 2 arguments",
+        )),
         [idle] => {
             let idle = parse_duration_literal(idle, "keepalive_timeout")?;
             if idle.is_zero() {
@@ -1884,23 +3203,21 @@ fn parse_keepalive_timeout(args: &[String]) -> Result<KeepaliveTimeout, LowerErr
                 })
             }
         }
-        _ => Err(LowerErr {
-            message: "keepalive_timeout: expected 1 or 2 arguments".into(),
-        }),
+        _ => Err(LowerErr::new(
+            "keepalive_timeout: expected 1 or 2 arguments",
+        )),
     }
 }
 
 fn parse_keepalive_requests(args: &[String]) -> Result<u32, LowerErr> {
     match args {
-        [value] => value.parse::<u32>().map_err(|_| LowerErr {
-            message: format!("keepalive_requests: invalid integer `{value}`"),
-        }),
-        [] => Err(LowerErr {
-            message: "keepalive_requests: expected 1 argument".into(),
-        }),
-        _ => Err(LowerErr {
-            message: "keepalive_requests: expected exactly 1 argument".into(),
-        }),
+        [value] => value
+            .parse::<u32>()
+            .map_err(|_| LowerErr::new(format!("keepalive_requests: invalid integer `{value}`"))),
+        [] => Err(LowerErr::new("keepalive_requests: expected 1 argument")),
+        _ => Err(LowerErr::new(
+            "keepalive_requests: expected exactly 1 argument",
+        )),
     }
 }
 
@@ -1910,12 +3227,10 @@ fn parse_client_max_body_size(args: &[String]) -> Result<Option<u64>, LowerErr>
             let size = parse_size_literal(value, consts::CLIENT_MAX_BODY_SIZE)?;
             if size == 0 { Ok(None) } else { Ok(Some(size)) }
         }
-        [] => Err(LowerErr {
-            message: "client_max_body_size: expected 1 argument".into(),
-        }),
-        _ => Err(LowerErr {
-            message: "client_max_body_size: expected exactly 1 argument".into(),
-        }),
+        [] => Err(LowerErr::new("client_max_body_size: expected 1 argument")),
+        _ => Err(LowerErr::new(
+            "client_max_body_size: expected exactly 1 argument",
+        )),
     }
 }
 
@@ -1925,12 +3240,10 @@ fn parse_single_duration_directive(
 ) -> Result<std::time::Duration, LowerErr> {
     match args {
         [value] => parse_duration_literal(value, directive),
-        [] => Err(LowerErr {
-            message: format!("{directive}: expected 1 argument"),
-        }),
-        _ => Err(LowerErr {
-            message: format!("{directive}: expected exactly 1 argument"),
-        }),
+        [] => Err(LowerErr::new(format!("{directive}: expected 1 argument"))),
+        _ => Err(LowerErr::new(format!(
+            "{directive}: expected exactly 1 argument"
+        ))),
     }
 }
 
@@ -1950,9 +3263,9 @@ fn parse_duration_literal(raw: &str, directive: &str) -> Result<std::time::Durat
     }
 
     if raw.is_empty() {
-        return Err(LowerErr {
-            message: format!("{directive}: invalid time value `{raw}`"),
-        });
+        return Err(LowerErr::new(format!(
+            "{directive}: invalid time value `{raw}`"
+        )));
     }
 
     let mut idx = 0usize;
@@ -1967,20 +3280,20 @@ fn parse_duration_literal(raw: &str, directive: &str) -> Result<std::time::Durat
         }
 
         if start == idx {
-            return Err(LowerErr {
-                message: format!("{directive}: invalid time value `{raw}`"),
-            });
+            return Err(LowerErr::new(format!(
+                "{directive}: invalid time value `{raw}`"
+            )));
         }
 
-        let value = raw[start..idx].parse::<u128>().map_err(|_| LowerErr {
-            message: format!("{directive}: invalid time value `{raw}`"),
-        })?;
+        let value = raw[start..idx]
+            .parse::<u128>()
+            .map_err(|_| LowerErr::new(format!("{directive}: invalid time value `{raw}`")))?;
 
         let unit = if idx == bytes.len() {
             if saw_segment {
-                return Err(LowerErr {
-                    message: format!("{directive}: missing unit in `{raw}`"),
-                });
+                return Err(LowerErr::new(format!(
+                    "{directive}: missing unit in `{raw}`"
+                )));
             }
             "s"
         } else if raw[idx..].starts_with("ms") {
@@ -1992,44 +3305,43 @@ fn parse_duration_literal(raw: &str, directive: &str) -> Result<std::time::Durat
             unit
         };
 
-        let multiplier = unit_multiplier_millis(unit).ok_or_else(|| LowerErr {
-            message: format!("{directive}: unsupported time unit `{unit}` in `{raw}`"),
+        let multiplier = unit_multiplier_millis(unit).ok_or_else(|| {
+            LowerErr::new(format!(
+                "{directive}: unsupported time unit `{unit}` in `{raw}`"
+            ))
         })?;
 
-        let segment_millis = value.checked_mul(multiplier).ok_or_else(|| LowerErr {
-            message: format!("{directive}: time value `{raw}` is too large"),
+        let segment_millis = value.checked_mul(multiplier).ok_or_else(|| {
+            LowerErr::new(format!("{directive}: time value `{raw}` is too large"))
+        })?;
+        total_millis = total_millis.checked_add(segment_millis).ok_or_else(|| {
+            LowerErr::new(format!("{directive}: time value `{raw}` is too large"))
         })?;
-        total_millis = total_millis
-            .checked_add(segment_millis)
-            .ok_or_else(|| LowerErr {
-                message: format!("{directive}: time value `{raw}` is too large"),
-            })?;
         saw_segment = true;
     }
 
-    let total_millis = u64::try_from(total_millis).map_err(|_| LowerErr {
-        message: format!("{directive}: time value `{raw}` is too large"),
-    })?;
+    let total_millis = u64::try_from(total_millis)
+        .map_err(|_| LowerErr::new(format!("{directive}: time value `{raw}` is too large")))?;
     Ok(std::time::Duration::from_millis(total_millis))
 }
 
 fn parse_size_literal(raw: &str, directive: &str) -> Result<u64, LowerErr> {
     if raw.is_empty() {
-        return Err(LowerErr {
-            message: format!("{directive}: invalid size value `{raw}`"),
-        });
+        return Err(LowerErr::new(format!(
+            "{directive}: invalid size value `{raw}`"
+        )));
     }
 
     let digits_len = raw.bytes().take_while(|byte| byte.is_ascii_digit()).count();
     if digits_len == 0 {
-        return Err(LowerErr {
-            message: format!("{directive}: invalid size value `{raw}`"),
-        });
+        return Err(LowerErr::new(format!(
+            "{directive}: invalid size value `{raw}`"
+        )));
     }
 
-    let value = raw[..digits_len].parse::<u64>().map_err(|_| LowerErr {
-        message: format!("{directive}: invalid size value `{raw}`"),
-    })?;
+    let value = raw[..digits_len]
+        .parse::<u64>()
+        .map_err(|_| LowerErr::new(format!("{directive}: invalid size value `{raw}`")))?;
     let suffix = &raw[digits_len..];
     let multiplier = match suffix {
         "" => 1u64,
@@ -2037,15 +3349,15 @@ fn parse_size_literal(raw: &str, directive: &str) -> Result<u64, LowerErr> {
         "m" | "M" => 1024 * 1024,
         "g" | "G" => 1024 * 1024 * 1024,
         _ => {
-            return Err(LowerErr {
-                message: format!("{directive}: unsupported size unit `{suffix}` in `{raw}`"),
-            });
+            return Err(LowerErr::new(format!(
+                "{directive}: unsupported size unit `{suffix}` in `{raw}`"
+            )));
         }
     };
 
-    value.checked_mul(multiplier).ok_or_else(|| LowerErr {
-        message: format!("{directive}: size value `{raw}` is too large"),
-    })
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| LowerErr::new(format!("{directive}: size value `{raw}` is too large")))
 }
 
 fn parse_listen_directives(args: &[String]) -> Result<Listen, LowerErr> {
@@ -2053,21 +3365,17 @@ fn parse_listen_directives(args: &[String]) -> Result<Listen, LowerErr> {
 
     match args {
         [] => {
-            return Err(LowerErr {
-                message: "listen: expected endpoint".into(),
-            });
+            return Err(LowerErr::new("listen: expected endpoint"));
         }
         [endpoint, params @ ..] => {
             if let Some(port_str) = endpoint.strip_prefix("*:") {
-                let port = port_str.parse::<u16>().map_err(|_| LowerErr {
-                    message: format!("listen: invalid port {:?}", port_str),
-                })?;
+                let port = port_str
+                    .parse::<u16>()
+                    .map_err(|_| LowerErr::new(format!("listen: invalid port {:?}", port_str)))?;
                 listen.addr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
                 listen.port = port;
             } else if endpoint.starts_with("unix:") {
-                return Err(LowerErr {
-                    message: "listen: unix sockets not supported".into(),
-                });
+                return Err(LowerErr::new("listen: unix sockets not supported"));
             } else if let Ok(sa) = endpoint.parse::<SocketAddr>() {
                 listen.addr = sa.ip();
                 listen.port = sa.port();
@@ -2075,9 +3383,10 @@ fn parse_listen_directives(args: &[String]) -> Result<Listen, LowerErr> {
                 listen.addr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
                 listen.port = port;
             } else {
-                return Err(LowerErr {
-                    message: format!("Failed parse address: {:?}", endpoint),
-                });
+                return Err(LowerErr::new(format!(
+                    "Failed parse address: {:?}",
+                    endpoint
+                )));
             }
 
             for p in params {
@@ -2089,30 +3398,61 @@ fn parse_listen_directives(args: &[String]) -> Result<Listen, LowerErr> {
                         listen.http2 = true;
                         listen.http2_only = true;
                     }
+                    consts::PROXY_PROTOCOL => listen.proxy_protocol = true,
+                    param
+                        if param
+                            .strip_prefix(consts::PROXY_PROTOCOL_TRUSTED)
+                            .is_some_and(|rest| rest.starts_with('=')) =>
+                    {
+                        let value = &param[consts::PROXY_PROTOCOL_TRUSTED.len() + 1..];
+                        for network in value.split(',') {
+                            listen
+                                .proxy_protocol_trusted
+                                .push(parse_listen_network(network)?);
+                        }
+                    }
                     _ => {
-                        return Err(LowerErr {
-                            message: format!("Unknow params: {:?}", params),
-                        });
+                        return Err(LowerErr::new(format!("Unknow params: {:?}", params)));
                     }
                 }
             }
         }
     }
 
+    if listen.proxy_protocol && listen.proxy_protocol_trusted.is_empty() {
+        return Err(LowerErr::new(
+            "listen: proxy_protocol requires proxy_protocol_trusted=<cidr>[,<cidr>...]",
+        ));
+    }
+    if !listen.proxy_protocol && !listen.proxy_protocol_trusted.is_empty() {
+        return Err(LowerErr::new(
+            "listen: proxy_protocol_trusted requires proxy_protocol",
+        ));
+    }
+
     if listen.http2 && !listen.ssl {
-        return Err(LowerErr {
-            message: "listen: http2/http2_only requires ssl; use h2c for plaintext HTTP/2".into(),
-        });
+        return Err(LowerErr::new(
+            "listen: http2/http2_only requires ssl; use h2c for plaintext HTTP/2",
+        ));
     }
 
     Ok(listen)
 }
 
+fn parse_listen_network(value: &str) -> Result<IpNet, LowerErr> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| {
+            LowerErr::new(format!(
+                "listen: invalid proxy_protocol_trusted network `{value}`"
+            ))
+        })
+}
+
 fn parse_ssl_protocols(args: &[String]) -> Result<TlsProtocolBounds, LowerErr> {
     if args.is_empty() {
-        return Err(LowerErr {
-            message: "ssl_protocols: expected at least 1 argument".into(),
-        });
+        return Err(LowerErr::new("ssl_protocols: expected at least 1 argument"));
     }
 
     let mut versions = BTreeSet::new();
@@ -2122,9 +3462,9 @@ fn parse_ssl_protocols(args: &[String]) -> Result<TlsProtocolBounds, LowerErr> {
             "TLSv1.2" => TlsProtocolVersion::Tls1_2,
             "TLSv1.3" => TlsProtocolVersion::Tls1_3,
             _ => {
-                return Err(LowerErr {
-                    message: format!("ssl_protocols: unsupported protocol `{arg}`"),
-                });
+                return Err(LowerErr::new(format!(
+                    "ssl_protocols: unsupported protocol `{arg}`"
+                )));
             }
         };
         versions.insert(version);
@@ -2140,9 +3480,9 @@ fn parse_ssl_protocols(args: &[String]) -> Result<TlsProtocolBounds, LowerErr> {
             min: TlsProtocolVersion::Tls1_2,
             max: TlsProtocolVersion::Tls1_3,
         }),
-        _ => Err(LowerErr {
-            message: "ssl_protocols: supported combinations are `TLSv1`, `TLSv1.2`, `TLSv1.3`, or `TLSv1.2 TLSv1.3`".into(),
-        }),
+        _ => Err(LowerErr::new(
+            "ssl_protocols: supported combinations are `TLSv1`, `TLSv1.2`, `TLSv1.3`, or `TLSv1.2 TLSv1.3`",
+        )),
     }
 }
 
@@ -2152,16 +3492,16 @@ fn parse_ssl_verify_client(args: &[String]) -> Result<TlsVerifyClient, LowerErr>
             "off" => Ok(TlsVerifyClient::Off),
             "optional" => Ok(TlsVerifyClient::Optional),
             "required" => Ok(TlsVerifyClient::Required),
-            _ => Err(LowerErr {
-                message: "ssl_verify_client: expected off|optional|required".into(),
-            }),
+            _ => Err(LowerErr::new(
+                "ssl_verify_client: expected off|optional|required",
+            )),
         },
-        [] => Err(LowerErr {
-            message: "ssl_verify_client: expected off|optional|required".into(),
-        }),
-        _ => Err(LowerErr {
-            message: "ssl_verify_client: expected exactly one argument".into(),
-        }),
+        [] => Err(LowerErr::new(
+            "ssl_verify_client: expected off|optional|required",
+        )),
+        _ => Err(LowerErr::new(
+            "ssl_verify_client: expected exactly one argument",
+        )),
     }
 }
 
@@ -2169,9 +3509,9 @@ fn validate_server(server: &Server) -> Result<(), LowerErr> {
     if server.tls_options.verify_client != TlsVerifyClient::Off
         && server.tls_options.client_certificate.is_none()
     {
-        return Err(LowerErr {
-            message: "ssl_verify_client: requires ssl_client_certificate".into(),
-        });
+        return Err(LowerErr::new(
+            "ssl_verify_client: requires ssl_client_certificate",
+        ));
     }
 
     Ok(())
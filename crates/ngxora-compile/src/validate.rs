@@ -1,81 +1,168 @@
-use crate::ir::{Ir, LocationDirective};
+use crate::{
+    consts,
+    ir::{AccessLog, DEFAULT_LOG_FORMAT, Http, Ir, LocationDirective},
+};
+use ngxora_config::{Ast, Block, Diagnostic, Node, Span};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct ValidateErr {
     pub message: String,
+    /// Zero-based `(server, location)` the error is about, if any.
+    pub location: Option<(usize, usize)>,
+}
+
+impl ValidateErr {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            location: None,
+        }
+    }
+
+    /// Where the offending `location` block was written in `ast`, the AST
+    /// this IR was lowered from.
+    pub fn span(&self, ast: &Ast) -> Option<Span> {
+        let (server_index, location_index) = self.location?;
+        let http = named_blocks(&ast.items, consts::HTTP).next()?;
+        let server = named_blocks(&http.children, consts::SERVER).nth(server_index)?;
+        let location = named_blocks(&server.children, consts::LOCATION).nth(location_index)?;
+        Some(location.span.clone())
+    }
+
+    pub fn into_diagnostic(self, ast: &Ast) -> Diagnostic {
+        let span = self.span(ast);
+        Diagnostic::new(self.message, span)
+    }
+}
+
+fn named_blocks<'a>(nodes: &'a [Node], name: &'a str) -> impl Iterator<Item = &'a Block> {
+    nodes.iter().filter_map(move |node| match node {
+        Node::Block(block) if block.name == name => Some(block),
+        _ => None,
+    })
 }
 
 impl Ir {
     pub fn validate(&self) -> Result<(), ValidateErr> {
-        let http = self.http.as_ref().ok_or_else(|| ValidateErr {
-            message: "configuration does not contain an http block".into(),
-        })?;
+        match self.validate_all().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Every validation error, in config order.
+    pub fn validate_all(&self) -> Vec<ValidateErr> {
+        let Some(http) = self.http.as_ref() else {
+            return vec![ValidateErr::new(
+                "configuration does not contain an http block",
+            )];
+        };
         if http.servers.is_empty() {
-            return Err(ValidateErr {
-                message: "http block does not contain any server blocks".into(),
-            });
+            return vec![ValidateErr::new(
+                "http block does not contain any server blocks",
+            )];
         }
 
+        let mut errors = Vec::new();
+        if let Some(format) = unknown_log_format(http, &http.access_logs) {
+            errors.push(ValidateErr::new(format!(
+                "http access_log uses unknown log_format `{format}`"
+            )));
+        }
         for (server_index, server) in http.servers.iter().enumerate() {
+            if let Some(format) = unknown_log_format(http, &server.access_logs) {
+                errors.push(ValidateErr::new(format!(
+                    "server {} access_log uses unknown log_format `{format}`",
+                    server_index + 1
+                )));
+            }
             for (location_index, location) in server.locations.iter().enumerate() {
                 let mut action_count = 0;
+                let mut serves_files = false;
+                let mut file_option = None;
+                let mut names = 0;
+                let mut access_logs = Vec::new();
                 for directive in &location.directives {
                     match directive {
                         LocationDirective::ProxyPass(_) | LocationDirective::Return { .. } => {
                             action_count += 1;
                         }
-                        LocationDirective::Root(_) => {
-                            return Err(unsupported_location_directive(
-                                server_index,
-                                location_index,
-                                "root",
-                            ));
-                        }
-                        LocationDirective::TryFiles(_) => {
-                            return Err(unsupported_location_directive(
-                                server_index,
-                                location_index,
-                                "try_files",
-                            ));
+                        LocationDirective::Root(_) | LocationDirective::Alias(_) => {
+                            action_count += 1;
+                            serves_files = true;
                         }
+                        LocationDirective::Index(_) => file_option = Some("index"),
+                        LocationDirective::TryFiles { .. } => file_option = Some("try_files"),
+                        LocationDirective::RouteName(_) => names += 1,
+                        LocationDirective::AccessLog(log) => access_logs.push(log.clone()),
                         _ => {}
                     }
                 }
 
+                let mut error = |message: String| {
+                    errors.push(ValidateErr {
+                        message,
+                        location: Some((server_index, location_index)),
+                    })
+                };
+
+                if let Some(directive) = file_option
+                    && !serves_files
+                {
+                    error(format!(
+                        "server {} location {} uses `{directive}` without root or alias",
+                        server_index + 1,
+                        location_index + 1
+                    ));
+                }
+
+                if names > 1 {
+                    error(format!(
+                        "server {} location {} has more than one route_name",
+                        server_index + 1,
+                        location_index + 1
+                    ));
+                }
+
+                if let Some(format) = unknown_log_format(http, &access_logs) {
+                    error(format!(
+                        "server {} location {} access_log uses unknown log_format `{format}`",
+                        server_index + 1,
+                        location_index + 1
+                    ));
+                }
+
                 if action_count != 1 {
-                    return Err(ValidateErr {
-                        message: format!(
-                            "server {} location {} must contain exactly one proxy_pass or return directive",
-                            server_index + 1,
-                            location_index + 1
-                        ),
-                    });
+                    error(format!(
+                        "server {} location {} must contain exactly one proxy_pass, return, root or alias directive",
+                        server_index + 1,
+                        location_index + 1
+                    ));
                 }
             }
         }
 
-        Ok(())
+        errors
     }
 }
 
-fn unsupported_location_directive(
-    server_index: usize,
-    location_index: usize,
-    directive: &str,
-) -> ValidateErr {
-    ValidateErr {
-        message: format!(
-            "server {} location {} uses unsupported directive `{directive}`",
-            server_index + 1,
-            location_index + 1
-        ),
-    }
+/// The first `log_format` named by `logs` that is neither built in nor
+/// defined in `http`.
+fn unknown_log_format<'a>(http: &Http, logs: &'a [AccessLog]) -> Option<&'a str> {
+    logs.iter()
+        .filter_map(|log| match log {
+            AccessLog::To(target) => Some(target.format_name()),
+            AccessLog::Off => None,
+        })
+        .find(|name| {
+            *name != DEFAULT_LOG_FORMAT && !http.log_formats.iter().any(|known| known.name == *name)
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ir::{Http, Location, LocationMatcher, Server};
+    use crate::ir::{Http, Location, LocationMatcher, Server, TryFilesFallback};
 
     fn ir_with_directive(directive: LocationDirective) -> Ir {
         Ir {
@@ -96,11 +183,24 @@ mod tests {
     }
 
     #[test]
-    fn rejects_unsupported_root_without_panicking() {
-        let err = ir_with_directive(LocationDirective::Root("/srv/www".into()))
+    fn accepts_root_as_location_action() {
+        ir_with_directive(LocationDirective::Root("/srv/www".into()))
             .validate()
-            .expect_err("root must be rejected");
-        assert!(err.message.contains("unsupported directive `root`"));
+            .expect("root is a location action");
+    }
+
+    #[test]
+    fn rejects_try_files_without_root() {
+        let err = ir_with_directive(LocationDirective::TryFiles {
+            files: vec!["$uri".into()],
+            fallback: TryFilesFallback::Status(404),
+        })
+        .validate()
+        .expect_err("try_files needs a root");
+        assert!(
+            err.message
+                .contains("uses `try_files` without root or alias")
+        );
     }
 
     #[test]
@@ -110,6 +210,29 @@ mod tests {
         ))
         .validate()
         .expect_err("location without action must be rejected");
-        assert!(err.message.contains("exactly one proxy_pass or return"));
+        assert!(
+            err.message
+                .contains("exactly one proxy_pass, return, root or alias")
+        );
+    }
+
+    #[test]
+    fn reports_every_invalid_location_with_its_span() {
+        let input = "http {\n  server {\n    location / { index index.html; }\n    location /ok { return 301 /; }\n    location /api { proxy_read_timeout 1s; }\n  }\n}";
+        let ast = Ast::parse_config(input).unwrap();
+        let errors = Ir::from_ast(&ast).unwrap().validate_all();
+
+        let found: Vec<_> = errors
+            .iter()
+            .map(|err| (err.location, err.span(&ast).map(|span| span.line)))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Some((0, 0)), Some(3)),
+                (Some((0, 0)), Some(3)),
+                (Some((0, 2)), Some(5)),
+            ]
+        );
     }
 }
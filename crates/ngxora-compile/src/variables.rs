@@ -0,0 +1,408 @@
+// nginx-style `$variable` interpolation for config values that are rendered
+// per request (`return` locations, header values, upstream hash keys).
+//
+// Templates are parsed once when the config is compiled so unknown variables
+// are rejected up front and request-time rendering is a plain segment walk.
+//
+// `$$` and `\$` produce a literal `$`, and so does a `$` that is not followed
+// by `{`, a letter or `_`, so values such as `$5` or `US$` need no escaping.
+
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TemplateErr {
+    pub message: String,
+}
+
+impl Display for TemplateErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TemplateErr {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Variable {
+    /// `$host`: normalized request host, falling back to `$server_name`.
+    Host,
+    /// `$request_uri`: original path plus query string.
+    RequestUri,
+    /// `$uri`: request path without the query string.
+    Uri,
+    /// `$args`: query string without the leading `?`.
+    Args,
+    /// `$arg_<name>`: first query argument named `<name>`.
+    Arg(String),
+    /// `$http_<name>`: request header, with `_` mapped to `-`.
+    Http(String),
+    /// `$cookie_<name>`: value of the request cookie named `<name>`.
+    Cookie(String),
+    /// `$remote_addr`: downstream client address.
+    RemoteAddr,
+    /// `$scheme`: `http` or `https`.
+    Scheme,
+    /// `$server_name`: primary `server_name` of the matched server block.
+    ServerName,
+}
+
+impl Variable {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "host" => Some(Self::Host),
+            "request_uri" => Some(Self::RequestUri),
+            "uri" => Some(Self::Uri),
+            "args" | "query_string" => Some(Self::Args),
+            "remote_addr" => Some(Self::RemoteAddr),
+            "scheme" => Some(Self::Scheme),
+            "server_name" => Some(Self::ServerName),
+            _ => {
+                if let Some(arg) = name.strip_prefix("arg_").filter(|arg| !arg.is_empty()) {
+                    return Some(Self::Arg(arg.to_string()));
+                }
+                if let Some(header) = name.strip_prefix("http_").filter(|h| !h.is_empty()) {
+                    return Some(Self::Http(header.to_ascii_lowercase().replace('_', "-")));
+                }
+                if let Some(cookie) = name.strip_prefix("cookie_").filter(|c| !c.is_empty()) {
+                    return Some(Self::Cookie(cookie.to_string()));
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Request facts a template can be rendered against.
+pub trait VariableSource {
+    fn host(&self) -> Option<&str>;
+    fn path(&self) -> &str;
+    fn query(&self) -> Option<&str>;
+    fn scheme(&self) -> &str;
+    fn server_name(&self) -> Option<&str>;
+    fn remote_addr(&self) -> Option<IpAddr>;
+    /// Looks up a request header by its lowercase name.
+    fn header(&self, name: &str) -> Option<&str>;
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, TemplateErr> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = source;
+
+        while let Some(index) = rest.find('$') {
+            if rest[..index].ends_with('\\') {
+                literal.push_str(&rest[..index - 1]);
+                literal.push('$');
+                rest = &rest[index + 1..];
+                continue;
+            }
+            literal.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            if !rest.starts_with(|ch: char| ch == '{' || ch == '_' || ch.is_ascii_alphabetic()) {
+                literal.push('$');
+                continue;
+            }
+
+            let (name, consumed) = if let Some(braced) = rest.strip_prefix('{') {
+                let end = braced.find('}').ok_or_else(|| TemplateErr {
+                    message: format!("unterminated `${{` in `{source}`"),
+                })?;
+                (&braced[..end], end + 2)
+            } else {
+                let end = rest
+                    .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            };
+
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+            {
+                return Err(TemplateErr {
+                    message: format!("invalid variable name in `{source}`"),
+                });
+            }
+            let variable = Variable::from_name(name).ok_or_else(|| TemplateErr {
+                message: format!("unknown variable `${name}`"),
+            })?;
+
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Variable(variable));
+            rest = &rest[consumed..];
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            segments,
+        })
+    }
+
+    /// The template exactly as written in the config.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// True when the template does not reference any variable.
+    pub fn is_static(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    /// The rendered text of a template without variables, escapes resolved.
+    pub fn literal(&self) -> Option<&str> {
+        match self.segments.as_slice() {
+            [] => Some(""),
+            [Segment::Literal(text)] => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = &Variable> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable(variable) => Some(variable),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    // Missing values render as an empty string, like nginx.
+    pub fn render(&self, vars: &dyn VariableSource) -> String {
+        let mut out = String::with_capacity(self.source.len());
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Variable(variable) => render_variable(&mut out, variable, vars),
+            }
+        }
+
+        out
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn render_variable(out: &mut String, variable: &Variable, vars: &dyn VariableSource) {
+    match variable {
+        Variable::Host => {
+            if let Some(host) = vars.host().or_else(|| vars.server_name()) {
+                out.push_str(host);
+            }
+        }
+        Variable::RequestUri => {
+            out.push_str(vars.path());
+            if let Some(query) = vars.query() {
+                out.push('?');
+                out.push_str(query);
+            }
+        }
+        Variable::Uri => out.push_str(vars.path()),
+        Variable::Args => out.push_str(vars.query().unwrap_or_default()),
+        Variable::Arg(name) => {
+            if let Some(value) = vars.query().and_then(|query| query_arg(query, name)) {
+                out.push_str(value);
+            }
+        }
+        Variable::Http(name) => {
+            if let Some(value) = vars.header(name) {
+                out.push_str(value);
+            }
+        }
+        Variable::Cookie(name) => {
+            if let Some(value) = vars
+                .header("cookie")
+                .and_then(|header| cookie(header, name))
+            {
+                out.push_str(value);
+            }
+        }
+        Variable::RemoteAddr => {
+            if let Some(addr) = vars.remote_addr() {
+                out.push_str(&addr.to_string());
+            }
+        }
+        Variable::Scheme => out.push_str(vars.scheme()),
+        Variable::ServerName => out.push_str(vars.server_name().unwrap_or_default()),
+    }
+}
+
+// Argument names match case-insensitively and values are returned as sent,
+// without percent-decoding.
+fn query_arg<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        key.eq_ignore_ascii_case(name).then_some(value)
+    })
+}
+
+// Cookie names are case-sensitive; only the first `Cookie` header is read.
+fn cookie<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Template, Variable, VariableSource};
+    use std::net::IpAddr;
+
+    struct FakeRequest;
+
+    impl VariableSource for FakeRequest {
+        fn host(&self) -> Option<&str> {
+            Some("example.com")
+        }
+
+        fn path(&self) -> &str {
+            "/docs/index.html"
+        }
+
+        fn query(&self) -> Option<&str> {
+            Some("page=2&Lang=en&flag")
+        }
+
+        fn scheme(&self) -> &str {
+            "https"
+        }
+
+        fn server_name(&self) -> Option<&str> {
+            Some("primary.example.com")
+        }
+
+        fn remote_addr(&self) -> Option<IpAddr> {
+            Some("203.0.113.7".parse().unwrap())
+        }
+
+        fn header(&self, name: &str) -> Option<&str> {
+            match name {
+                "user-agent" => Some("curl/8.0"),
+                "cookie" => Some("theme=dark; session=abc123"),
+                _ => None,
+            }
+        }
+    }
+
+    fn render(source: &str) -> String {
+        Template::parse(source)
+            .expect("template parses")
+            .render(&FakeRequest)
+    }
+
+    #[test]
+    fn renders_request_variables() {
+        assert_eq!(
+            render("https://$host$request_uri"),
+            "https://example.com/docs/index.html?page=2&Lang=en&flag"
+        );
+        assert_eq!(
+            render("$scheme://$server_name$uri"),
+            "https://primary.example.com/docs/index.html"
+        );
+        assert_eq!(render("$args"), "page=2&Lang=en&flag");
+        assert_eq!(render("$remote_addr"), "203.0.113.7");
+        assert_eq!(render("ua=$http_user_agent"), "ua=curl/8.0");
+    }
+
+    #[test]
+    fn renders_query_arguments() {
+        assert_eq!(render("$arg_page"), "2");
+        assert_eq!(render("$arg_lang"), "en");
+        assert_eq!(render("[$arg_flag]"), "[]");
+        assert_eq!(render("[$arg_missing]"), "[]");
+    }
+
+    #[test]
+    fn renders_cookies() {
+        assert_eq!(render("$cookie_session"), "abc123");
+        assert_eq!(render("$cookie_theme"), "dark");
+        assert_eq!(render("[$cookie_Session]"), "[]");
+    }
+
+    #[test]
+    fn braced_variables_can_be_followed_by_name_characters() {
+        assert_eq!(render("${arg_page}0"), "20");
+    }
+
+    #[test]
+    fn static_templates_keep_their_source() {
+        let template = Template::parse("https://example.com/").unwrap();
+        assert!(template.is_static());
+        assert_eq!(template.render(&FakeRequest), "https://example.com/");
+        assert_eq!(template.source(), "https://example.com/");
+    }
+
+    #[test]
+    fn header_variables_are_normalized() {
+        let template = Template::parse("$http_X_Forwarded_Proto").unwrap();
+        assert_eq!(
+            template.variables().collect::<Vec<_>>(),
+            vec![&Variable::Http("x-forwarded-proto".into())]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_variables() {
+        let err = Template::parse("https://$hostname/").expect_err("unknown variable");
+        assert_eq!(err.message, "unknown variable `$hostname`");
+    }
+
+    #[test]
+    fn escaped_and_bare_dollars_stay_literal() {
+        for (source, expected) in [
+            ("$$host", "$host"),
+            ("\\$notvar", "$notvar"),
+            ("$5", "$5"),
+            ("price: $", "price: $"),
+            ("US$ 10 / $-1", "US$ 10 / $-1"),
+            ("$$$host", "$example.com"),
+        ] {
+            let template = Template::parse(source).expect("template parses");
+            assert_eq!(template.render(&FakeRequest), expected, "{source}");
+            assert_eq!(template.source(), source);
+        }
+        assert_eq!(Template::parse("$$").unwrap().literal(), Some("$"));
+        assert_eq!(Template::parse("$host").unwrap().literal(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_variables() {
+        assert!(Template::parse("${host").is_err());
+        assert!(Template::parse("${}").is_err());
+        assert!(Template::parse("$http_").is_err());
+    }
+}
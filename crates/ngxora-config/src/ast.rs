@@ -1,8 +1,12 @@
-use crate::{ParseError, lexer::Token, parser::Parser};
+use crate::{ParseError, Span, lexer::Token, parser::Parser};
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Ast {
     pub items: Vec<Node>,
+    /// Comments after the last item.
+    pub comments: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -14,39 +18,154 @@ pub enum Node {
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Directive {
     pub name: String,
+    /// Argument values, unquoted and unescaped.
     pub args: Vec<String>,
+    /// Whether each of `args` was written as a quoted string.
+    pub quoted: Vec<bool>,
+    /// Where the directive name was written.
+    pub span: Span,
+    pub comments: Comments,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct Block {
     pub name: String,
     pub args: Vec<String>,
+    pub quoted: Vec<bool>,
     pub children: Vec<Node>,
+    pub span: Span,
+    /// Comments above the block and after its `{`.
+    pub comments: Comments,
+    /// Comments before the closing `}` and after it.
+    pub closing: Comments,
+}
+
+/// Comments written around a node, kept so `ngxora fmt` can put them back.
+///
+/// Like spans, comments never take part in equality.
+#[derive(Debug, Clone, Default)]
+pub struct Comments {
+    /// Comment lines above the node, `#` included. An empty string stands
+    /// for a blank line.
+    pub leading: Vec<String>,
+    /// A comment on the same line, after the `;`, `{` or `}`.
+    pub trailing: Option<String>,
+}
+
+impl PartialEq for Comments {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Comments {}
+
+impl Directive {
+    /// Whether argument `index` was quoted; a quoted `~` is a value, not an
+    /// operator.
+    pub fn is_quoted(&self, index: usize) -> bool {
+        self.quoted.get(index).copied().unwrap_or(false)
+    }
+}
+
+impl Block {
+    /// Same as [`Directive::is_quoted`].
+    pub fn is_quoted(&self, index: usize) -> bool {
+        self.quoted.get(index).copied().unwrap_or(false)
+    }
 }
 
 impl Node {
+    /// A directive with bare arguments.
     pub fn directive(name: String, args: Vec<String>) -> Self {
-        Self::Directive(Directive { name, args })
+        let quoted = vec![false; args.len()];
+        Self::Directive(Directive {
+            name,
+            args,
+            quoted,
+            ..Default::default()
+        })
     }
 
+    /// A block with bare arguments.
     pub fn block(name: String, args: Vec<String>, children: Vec<Node>) -> Self {
+        let quoted = vec![false; args.len()];
         Self::Block(Block {
             name,
             args,
+            quoted,
             children,
+            ..Default::default()
         })
     }
+
+    pub fn span(&self) -> &Span {
+        match self {
+            Self::Directive(directive) => &directive.span,
+            Self::Block(block) => &block.span,
+        }
+    }
+
+    fn set_file(&mut self, file: &Arc<Path>) {
+        match self {
+            Self::Directive(directive) => directive.span.file = Some(Arc::clone(file)),
+            Self::Block(block) => {
+                block.span.file = Some(Arc::clone(file));
+                for child in &mut block.children {
+                    child.set_file(file);
+                }
+            }
+        }
+    }
 }
 
 impl Ast {
+    /// Parses `input`, stopping at the first syntax error. See
+    /// [`Ast::parse_config_all`].
     pub fn parse_config(input: &str) -> Result<Self, ParseError> {
-        let tokens = Token::tokenize(input);
-        Self::parse_tokens(&tokens)
+        Self::parse(input).map_err(first_error)
     }
 
-    fn parse_tokens<'a>(tokens: &[Token<'a>]) -> Result<Self, ParseError> {
-        let mut parser = Parser::new(tokens);
-        let items = parser.parse_items(false)?;
-        Ok(Ast { items })
+    /// Like [`Ast::parse_config`], with `path` recorded in every span so
+    /// errors can point into the file.
+    pub fn parse_config_with_path(input: &str, path: &Path) -> Result<Self, ParseError> {
+        Self::parse_config_all(input, path).map_err(first_error)
     }
+
+    /// Like [`Ast::parse_config_with_path`], reporting every syntax error.
+    /// A broken statement is skipped up to its `;` or the end of its block.
+    pub fn parse_config_all(input: &str, path: &Path) -> Result<Self, Vec<ParseError>> {
+        let file: Arc<Path> = Arc::from(path);
+        let mut ast = Self::parse(input).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|mut e| {
+                    e.span = e.span.map(|span| span.with_file(Some(&file)));
+                    e
+                })
+                .collect::<Vec<_>>()
+        })?;
+        for node in &mut ast.items {
+            node.set_file(&file);
+        }
+        Ok(ast)
+    }
+
+    fn parse(input: &str) -> Result<Self, Vec<ParseError>> {
+        let tokens = Token::tokenize_with_comments(input).map_err(|e| vec![e])?;
+        let mut parser = Parser::new(&tokens);
+        let (items, comments) = parser.parse_body(false);
+        let errors = parser.into_errors();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Ast { items, comments })
+    }
+}
+
+fn first_error(errors: Vec<ParseError>) -> ParseError {
+    errors
+        .into_iter()
+        .next()
+        .expect("failed parses report an error")
 }
@@ -0,0 +1,157 @@
+// Source positions for config nodes and rustc-style rendering of the errors
+// that point at them.
+
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Where a token or node starts in the config source.
+///
+/// Spans never take part in equality: two ASTs that differ only in where
+/// their nodes were written compare equal.
+#[derive(Debug, Clone, Default)]
+pub struct Span {
+    /// `None` for text parsed without a path.
+    pub file: Option<Arc<Path>>,
+    /// 1-based line.
+    pub line: usize,
+    /// 1-based column, in characters.
+    pub column: usize,
+    /// Length of the highlighted token, in characters.
+    pub len: usize,
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl Span {
+    pub fn with_file(mut self, file: Option<&Arc<Path>>) -> Self {
+        if self.file.is_none() {
+            self.file = file.cloned();
+        }
+        self
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file.display(), self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+/// One error, ready to be shown to whoever wrote the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            help: None,
+        }
+    }
+
+    /// Renders the error like rustc does, quoting the offending line when
+    /// the span names a readable file.
+    pub fn render(&self) -> String {
+        let mut out = format!("error: {}\n", self.message);
+        let snippet = self.span.as_ref().and_then(|span| {
+            let file = span.file.as_ref()?;
+            let text = std::fs::read_to_string(file).ok()?;
+            let line = text.lines().nth(span.line.checked_sub(1)?)?.to_string();
+            Some((span, line))
+        });
+
+        let gutter = match &snippet {
+            Some((span, _)) => span.line.to_string().len(),
+            None => 0,
+        };
+        if let Some(span) = &self.span {
+            let _ = writeln!(out, "{:gutter$}--> {span}", "");
+        }
+        if let Some((span, line)) = &snippet {
+            let prefix: String = line.chars().take(span.column.saturating_sub(1)).collect();
+            let _ = writeln!(out, "{:gutter$} |", "");
+            let _ = writeln!(out, "{} | {}", span.line, expand_tabs(line));
+            let _ = writeln!(
+                out,
+                "{:gutter$} | {}{}",
+                "",
+                " ".repeat(expand_tabs(&prefix).chars().count()),
+                "^".repeat(span.len.max(1))
+            );
+        }
+        if let Some(help) = &self.help {
+            let _ = writeln!(out, "{:gutter$} = help: {help}", "");
+        }
+        out
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{span}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Renders every diagnostic followed by a summary line.
+pub fn render_all(diagnostics: &[Diagnostic]) -> String {
+    let mut out = String::new();
+    for diagnostic in diagnostics {
+        out.push_str(&diagnostic.render());
+        out.push('\n');
+    }
+    match diagnostics.len() {
+        0 => {}
+        1 => out.push_str("error: aborting due to 1 previous error"),
+        n => {
+            let _ = write!(out, "error: aborting due to {n} previous errors");
+        }
+    }
+    out
+}
+
+/// The candidate closest to `name`, if it is close enough to be a typo.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', "    ")
+}
@@ -0,0 +1,186 @@
+// Shell-style wildcards for `include` paths: `*`, `?` and `[...]` within one
+// path component, like glob(3) as used by nginx.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Whether `path` has a wildcard and must be expanded.
+pub(crate) fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Files matching `pattern` relative to `base`, sorted by path so includes
+/// are applied in a deterministic order. Names starting with `.` only match a
+/// component that starts with `.` too.
+pub(crate) fn expand(base: &Path, pattern: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut candidates = vec![base.to_path_buf()];
+    for component in pattern.components() {
+        let Component::Normal(part) = component else {
+            for candidate in &mut candidates {
+                candidate.push(component);
+            }
+            continue;
+        };
+        let part = part.to_string_lossy();
+        if !is_pattern(&part) {
+            for candidate in &mut candidates {
+                candidate.push(part.as_ref());
+            }
+            continue;
+        }
+
+        let mut next = Vec::new();
+        for dir in &candidates {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries {
+                let name = entry?.file_name();
+                let name = name.to_string_lossy();
+                if name.starts_with('.') && !part.starts_with('.') {
+                    continue;
+                }
+                if matches(&part, &name) {
+                    next.push(dir.join(name.as_ref()));
+                }
+            }
+        }
+        candidates = next;
+    }
+
+    let mut files: Vec<PathBuf> = candidates
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The directory `pattern` expands under: `base` joined with the components
+/// before the first wildcard, normalized. `None` when a `..` follows a
+/// wildcard, since the files it reaches are only known after expanding.
+pub(crate) fn literal_prefix(base: &Path, pattern: &Path) -> Option<PathBuf> {
+    let mut prefix = base.to_path_buf();
+    let mut wildcard = false;
+    for component in pattern.components() {
+        match component {
+            Component::ParentDir if wildcard => return None,
+            Component::Normal(part) if is_pattern(&part.to_string_lossy()) => wildcard = true,
+            component if !wildcard => prefix.push(component),
+            _ => {}
+        }
+    }
+    Some(normalize(&prefix))
+}
+
+/// Whether `path` is one of the files `pattern` can expand to. Both are
+/// compared component by component, so they must be absolute and normalized.
+pub(crate) fn matches_path(pattern: &Path, path: &Path) -> bool {
+    let (mut pattern, mut path) = (pattern.components(), path.components());
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(Component::Normal(part)), Some(Component::Normal(name))) => {
+                let (part, name) = (part.to_string_lossy(), name.to_string_lossy());
+                let hidden = name.starts_with('.') && !part.starts_with('.');
+                if hidden || !matches(&part, &name) {
+                    return false;
+                }
+            }
+            (Some(part), Some(name)) if part == name => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Resolves `.` and `..` lexically, without touching the filesystem.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            component => out.push(component),
+        }
+    }
+    out
+}
+
+/// Matches one path component against a pattern.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position to resume from when a `*` has to swallow one more character.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    star = Some((p, n));
+                    p += 1;
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+                '[' => {
+                    if let Some((matched, end)) = match_class(&pattern[p + 1..], name[n]) {
+                        if matched {
+                            p += end + 2;
+                            n += 1;
+                            continue;
+                        }
+                    } else if name[n] == '[' {
+                        p += 1;
+                        n += 1;
+                        continue;
+                    }
+                }
+                c if c == name[n] => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match star {
+            Some((star_p, star_n)) => {
+                star = Some((star_p, star_n + 1));
+                p = star_p + 1;
+                n = star_n + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the class after a `[`. Returns whether it matched and
+/// the index of the closing `]`, or `None` for an unclosed `[`.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        if class[i] == ']' && !first {
+            return Some((matched != negated, i));
+        }
+        first = false;
+        if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            matched |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
@@ -1,4 +1,4 @@
-use crate::{Ast, Node};
+use crate::{Ast, Block, Diagnostic, Node, Span, glob};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
@@ -17,9 +17,53 @@ pub struct IncludeFile {
     pub text: String,
 }
 
+/// Files an include resolution read, and the glob patterns it expanded, so a
+/// watcher can tell which file changes affect the config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncludeSources {
+    /// Canonical paths of every included file.
+    pub files: Vec<PathBuf>,
+    /// Absolute glob patterns; files created later may match them.
+    pub patterns: Vec<PathBuf>,
+}
+
+impl IncludeSources {
+    /// Whether a change to `path` can change the resolved config.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.iter().any(|file| file == path)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob::matches_path(pattern, path))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IncludeError {
     pub message: String,
+    /// The failing `include`, or the syntax error inside the included file.
+    pub span: Option<Span>,
+}
+
+impl IncludeError {
+    fn new(message: String) -> Self {
+        Self {
+            message,
+            span: None,
+        }
+    }
+
+    /// Points the error at `span` unless it already points somewhere.
+    fn at(mut self, span: &Span) -> Self {
+        self.span.get_or_insert_with(|| span.clone());
+        self
+    }
+}
+
+impl From<IncludeError> for Diagnostic {
+    fn from(e: IncludeError) -> Self {
+        Diagnostic::new(e.message, e.span)
+    }
 }
 
 impl IncludeResolver {
@@ -34,16 +78,41 @@ impl IncludeResolver {
     }
 
     pub fn resolve(&self, ast: &Ast) -> Result<Ast, IncludeError> {
-        let root_dir = std::fs::canonicalize(&self.root_dir).map_err(|e| IncludeError {
-            message: format!(
+        self.resolve_with_sources(ast).map(|(ast, _)| ast)
+    }
+
+    /// Like [`resolve`](Self::resolve), also returning the files and glob
+    /// patterns the includes were read from.
+    pub fn resolve_with_sources(&self, ast: &Ast) -> Result<(Ast, IncludeSources), IncludeError> {
+        let root_dir = std::fs::canonicalize(&self.root_dir).map_err(|e| {
+            IncludeError::new(format!(
                 "failed to canonicalize include root {}: {e}",
                 self.root_dir.display()
-            ),
+            ))
         })?;
 
         let mut resolving = HashSet::new();
-        let nodes = resolve_nodes(&ast.items, &root_dir, &root_dir, &mut resolving, 0)?;
-        Ok(Ast { items: nodes })
+        let mut sources = IncludeSources::default();
+        let nodes = resolve_nodes(
+            &ast.items,
+            None,
+            &root_dir,
+            &root_dir,
+            &mut resolving,
+            &mut sources,
+            0,
+        )?;
+        sources.files.sort();
+        sources.files.dedup();
+        sources.patterns.sort();
+        sources.patterns.dedup();
+        Ok((
+            Ast {
+                items: nodes,
+                comments: ast.comments.clone(),
+            },
+            sources,
+        ))
     }
 }
 
@@ -65,17 +134,22 @@ fn collect_includes(nodes: &[Node], out: &mut Vec<IncludeFile>) {
     }
 }
 
+/// Blocks whose children may `include` files; `None` is the top level.
+const INCLUDE_CONTEXTS: &[&str] = &["http", "server", "upstream", "location"];
+
 fn resolve_nodes(
     nodes: &[Node],
+    context: Option<&str>,
     root_dir: &Path,
     current_dir: &Path,
     resolving: &mut HashSet<PathBuf>,
+    sources: &mut IncludeSources,
     depth: usize,
 ) -> Result<Vec<Node>, IncludeError> {
     if depth > MAX_INCLUDE_DEPTH {
-        return Err(IncludeError {
-            message: format!("include: maximum nested depth {MAX_INCLUDE_DEPTH} exceeded"),
-        });
+        return Err(IncludeError::new(format!(
+            "include: maximum nested depth {MAX_INCLUDE_DEPTH} exceeded"
+        )));
     }
 
     let mut out: Vec<Node> = Vec::new();
@@ -83,50 +157,57 @@ fn resolve_nodes(
     for node in nodes {
         match node {
             Node::Directive(directive) if directive.name == INCLUDE_DIRECTIVE => {
+                if let Some(context) = context
+                    && !INCLUDE_CONTEXTS.contains(&context)
+                {
+                    return Err(IncludeError::new(format!(
+                        "include is not allowed inside `{context}` block"
+                    ))
+                    .at(&directive.span));
+                }
+                if directive.args.is_empty() {
+                    return Err(
+                        IncludeError::new("include: expected at least 1 argument".into())
+                            .at(&directive.span),
+                    );
+                }
                 for path in &directive.args {
-                    let include_path = resolve_include_path(root_dir, current_dir, path)?;
-                    if !resolving.insert(include_path.clone()) {
-                        return Err(IncludeError {
-                            message: format!(
-                                "include cycle detected while resolving {}",
-                                include_path.display()
-                            ),
-                        });
+                    for include_path in include_paths(root_dir, current_dir, path, sources)
+                        .map_err(|e| e.at(&directive.span))?
+                    {
+                        let nodes = resolve_include(
+                            &include_path,
+                            context,
+                            root_dir,
+                            resolving,
+                            sources,
+                            depth,
+                        )
+                        .map_err(|e| e.at(&directive.span))?;
+                        out.extend(nodes);
                     }
-
-                    let text =
-                        std::fs::read_to_string(&include_path).map_err(|e| IncludeError {
-                            message: format!(
-                                "failed to read include {}: {e}",
-                                include_path.display()
-                            ),
-                        })?;
-                    let ast = Ast::parse_config(&text)
-                        .map_err(|e| IncludeError { message: e.message })?;
-                    let next_dir = include_path
-                        .parent()
-                        .map(Path::to_path_buf)
-                        .unwrap_or_else(|| root_dir.to_path_buf());
-
-                    out.extend(resolve_nodes(
-                        &ast.items,
-                        root_dir,
-                        &next_dir,
-                        resolving,
-                        depth + 1,
-                    )?);
-                    resolving.remove(&include_path);
                 }
             }
 
             Node::Block(block) => {
-                let children =
-                    resolve_nodes(&block.children, root_dir, current_dir, resolving, depth)?;
-                out.push(Node::block(
-                    block.name.clone(),
-                    block.args.clone(),
+                let children = resolve_nodes(
+                    &block.children,
+                    Some(&block.name),
+                    root_dir,
+                    current_dir,
+                    resolving,
+                    sources,
+                    depth,
+                )?;
+                out.push(Node::Block(Block {
+                    name: block.name.clone(),
+                    args: block.args.clone(),
+                    quoted: block.quoted.clone(),
                     children,
-                ));
+                    span: block.span.clone(),
+                    comments: block.comments.clone(),
+                    closing: block.closing.clone(),
+                }));
             }
 
             other => out.push(other.clone()),
@@ -136,30 +217,142 @@ fn resolve_nodes(
     Ok(out)
 }
 
-fn resolve_include_path(
+/// The files one `include` argument names. A glob that matches nothing
+/// includes nothing, as in nginx; a missing literal path is an error.
+fn include_paths(
     root_dir: &Path,
     current_dir: &Path,
     raw_path: &str,
+    sources: &mut IncludeSources,
+) -> Result<Vec<PathBuf>, IncludeError> {
+    if !glob::is_pattern(raw_path) {
+        return Ok(vec![resolve_include_path(
+            root_dir,
+            current_dir,
+            Path::new(raw_path),
+        )?]);
+    }
+
+    // Confine the pattern before expanding it, so errors never name files
+    // outside the root.
+    let escapes = || {
+        IncludeError::new(format!(
+            "include pattern {raw_path} escapes root config directory {}",
+            root_dir.display()
+        ))
+    };
+    let prefix = glob::literal_prefix(current_dir, Path::new(raw_path)).ok_or_else(escapes)?;
+    if !std::fs::canonicalize(&prefix)
+        .unwrap_or(prefix)
+        .starts_with(root_dir)
+    {
+        return Err(escapes());
+    }
+
+    sources
+        .patterns
+        .push(glob::normalize(&current_dir.join(raw_path)));
+    glob::expand(current_dir, Path::new(raw_path))
+        .map_err(|e| IncludeError::new(format!("failed to expand include {raw_path}: {e}")))?
+        .iter()
+        .map(|path| resolve_include_path(root_dir, current_dir, path))
+        .collect()
+}
+
+fn resolve_include(
+    include_path: &Path,
+    context: Option<&str>,
+    root_dir: &Path,
+    resolving: &mut HashSet<PathBuf>,
+    sources: &mut IncludeSources,
+    depth: usize,
+) -> Result<Vec<Node>, IncludeError> {
+    if !resolving.insert(include_path.to_path_buf()) {
+        return Err(IncludeError::new(format!(
+            "include cycle detected while resolving {}",
+            include_path.display()
+        )));
+    }
+
+    sources.files.push(include_path.to_path_buf());
+    let text = std::fs::read_to_string(include_path).map_err(|e| {
+        IncludeError::new(format!(
+            "failed to read include {}: {e}",
+            include_path.display()
+        ))
+    })?;
+    let ast = Ast::parse_config_with_path(&text, include_path).map_err(|e| IncludeError {
+        message: e.message,
+        span: e.span,
+    })?;
+    check_context(&ast.items, context)?;
+    let next_dir = include_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| root_dir.to_path_buf());
+
+    let nodes = resolve_nodes(
+        &ast.items,
+        context,
+        root_dir,
+        &next_dir,
+        resolving,
+        sources,
+        depth + 1,
+    )?;
+    resolving.remove(include_path);
+    Ok(nodes)
+}
+
+/// Rejects blocks an included file cannot open where it is included, such as
+/// a `server` block included into a `location`.
+fn check_context(nodes: &[Node], context: Option<&str>) -> Result<(), IncludeError> {
+    for node in nodes {
+        let Node::Block(block) = node else {
+            continue;
+        };
+        let allowed = match block.name.as_str() {
+            "http" => context.is_none(),
+            "server" | "upstream" => context == Some("http"),
+            "location" => context == Some("server"),
+            _ => true,
+        };
+        if !allowed {
+            let place = match context {
+                Some(context) => format!("inside `{context}`"),
+                None => "at the top level".into(),
+            };
+            return Err(IncludeError::new(format!(
+                "included `{}` block is not allowed {place}",
+                block.name
+            ))
+            .at(&block.span));
+        }
+    }
+    Ok(())
+}
+
+fn resolve_include_path(
+    root_dir: &Path,
+    current_dir: &Path,
+    path: &Path,
 ) -> Result<PathBuf, IncludeError> {
-    let path = Path::new(raw_path);
     let path = if path.is_absolute() {
         path.to_path_buf()
     } else {
         current_dir.join(path)
     };
 
-    let canonical = std::fs::canonicalize(&path).map_err(|e| IncludeError {
-        message: format!("failed to resolve include {}: {e}", path.display()),
+    let canonical = std::fs::canonicalize(&path).map_err(|e| {
+        IncludeError::new(format!("failed to resolve include {}: {e}", path.display()))
     })?;
 
     if !canonical.starts_with(root_dir) {
-        return Err(IncludeError {
-            message: format!(
-                "include path {} escapes root config directory {}",
-                canonical.display(),
-                root_dir.display()
-            ),
-        });
+        return Err(IncludeError::new(format!(
+            "include path {} escapes root config directory {}",
+            canonical.display(),
+            root_dir.display()
+        )));
     }
 
     Ok(canonical)
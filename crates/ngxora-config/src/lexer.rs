@@ -1,9 +1,28 @@
+use crate::{ParseError, Span};
+use std::borrow::Cow;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token<'a> {
     pub kind: TokenType,
     pub len: usize,
+    /// Source text of the token, quotes and escapes included.
     pub lexeme: &'a str,
+    /// The argument value: quotes stripped and escapes resolved.
+    pub value: Cow<'a, str>,
     pub number_line: usize,
+    /// 1-based column of the first character.
+    pub column: usize,
+}
+
+impl Token<'_> {
+    pub fn span(&self) -> Span {
+        Span {
+            file: None,
+            line: self.number_line,
+            column: self.column,
+            len: self.lexeme.chars().count(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -12,60 +31,245 @@ pub enum TokenType {
     RBrace,
     Semicolon,
     Ident,
+    /// A `"..."` or `'...'` string.
+    Quoted,
+    /// A `#` comment up to the end of its line, only produced by
+    /// [`Token::tokenize_with_comments`].
+    Comment,
 }
 
 impl<'a> Token<'a> {
-    pub fn tokenize(input: &'a str) -> Vec<Token<'a>> {
+    // Follows nginx: `#` starts a comment and quotes start a string only at
+    // the start of a token, a `\` in a bare word takes the next character
+    // literally, and `${name}` keeps its braces inside a bare word.
+    pub fn tokenize(input: &'a str) -> Result<Vec<Token<'a>>, ParseError> {
+        Self::lex(input, false)
+    }
+
+    /// Like [`Token::tokenize`], keeping comments as [`TokenType::Comment`]
+    /// tokens so they can be written back by the formatter.
+    pub fn tokenize_with_comments(input: &'a str) -> Result<Vec<Token<'a>>, ParseError> {
+        Self::lex(input, true)
+    }
+
+    fn lex(input: &'a str, keep_comments: bool) -> Result<Vec<Token<'a>>, ParseError> {
         let mut tokens: Vec<Token<'a>> = Vec::new();
-        let mut line: usize = 1;
+        let mut line = Line::default();
 
         let mut word_start: Option<usize> = None;
         let mut chars = input.char_indices().peekable();
 
         while let Some((i, c)) = chars.next() {
             if c == '\n' {
-                flush(&mut tokens, input, &mut word_start, i, line);
-                line += 1;
+                flush(&mut tokens, input, &mut word_start, i, &line);
+                line.next(i);
                 continue;
             }
 
             if c.is_whitespace() {
-                flush(&mut tokens, input, &mut word_start, i, line);
+                flush(&mut tokens, input, &mut word_start, i, &line);
                 continue;
             }
 
-            if c == '#' {
-                flush(&mut tokens, input, &mut word_start, i, line);
-                for (_, cc) in chars.by_ref() {
-                    if cc == '\n' {
-                        line += 1;
-                        break;
+            if word_start.is_some() {
+                match c {
+                    '\\' => {
+                        chars.next_if(|&(_, next)| next != '\n');
+                        continue;
+                    }
+                    '{' if input[..i].ends_with('$') => {
+                        read_variable_name(&mut chars, line.span(input, i))?;
+                        continue;
                     }
+                    _ => {}
+                }
+            } else {
+                if c == '#' {
+                    let column = line.column(input, i);
+                    let number_line = line.number;
+                    let mut end = input.len();
+                    for (j, cc) in chars.by_ref() {
+                        if cc == '\n' {
+                            end = j;
+                            line.next(j);
+                            break;
+                        }
+                    }
+                    if keep_comments {
+                        let lexeme = input[i..end].trim_end();
+                        tokens.push(Token {
+                            kind: TokenType::Comment,
+                            len: lexeme.len(),
+                            lexeme,
+                            value: Cow::Borrowed(lexeme),
+                            number_line,
+                            column,
+                        });
+                    }
+                    continue;
+                }
+
+                if c == '"' || c == '\'' {
+                    let start = line.span(input, i);
+                    let end = read_quoted(&mut chars, c, &mut line).ok_or_else(|| ParseError {
+                        message: "unterminated quoted string".into(),
+                        span: Some(start.clone()),
+                    })?;
+                    if let Some(&(j, next)) = chars.peek()
+                        && !next.is_whitespace()
+                        && punct_token_type(next).is_none()
+                    {
+                        return Err(ParseError {
+                            message: format!("unexpected `{next}` after quoted string"),
+                            span: Some(line.span(input, j)),
+                        });
+                    }
+                    tokens.push(Token {
+                        kind: TokenType::Quoted,
+                        len: end - i,
+                        lexeme: &input[i..end],
+                        value: unescape(&input[i + 1..end - 1]),
+                        number_line: start.line,
+                        column: start.column,
+                    });
+                    continue;
                 }
-                continue;
             }
 
             if let Some(kind) = punct_token_type(c) {
-                flush(&mut tokens, input, &mut word_start, i, line);
+                flush(&mut tokens, input, &mut word_start, i, &line);
 
                 let end = i + c.len_utf8();
                 tokens.push(Token {
                     kind,
                     len: end - i,
                     lexeme: &input[i..end],
-                    number_line: line,
+                    value: Cow::Borrowed(&input[i..end]),
+                    number_line: line.number,
+                    column: line.column(input, i),
                 });
                 continue;
             }
 
             if word_start.is_none() {
                 word_start = Some(i);
+                if c == '\\' {
+                    chars.next_if(|&(_, next)| next != '\n');
+                }
             }
         }
 
-        flush(&mut tokens, input, &mut word_start, input.len(), line);
-        tokens
+        flush(&mut tokens, input, &mut word_start, input.len(), &line);
+        Ok(tokens)
+    }
+}
+
+/// The line the lexer is on.
+struct Line {
+    number: usize,
+    /// Byte offset where the line starts.
+    start: usize,
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Self {
+            number: 1,
+            start: 0,
+        }
+    }
+}
+
+impl Line {
+    /// Moves past the newline at `newline`.
+    fn next(&mut self, newline: usize) {
+        self.number += 1;
+        self.start = newline + 1;
+    }
+
+    fn column(&self, input: &str, offset: usize) -> usize {
+        input[self.start..offset].chars().count() + 1
+    }
+
+    fn span(&self, input: &str, offset: usize) -> Span {
+        Span {
+            file: None,
+            line: self.number,
+            column: self.column(input, offset),
+            len: 1,
+        }
+    }
+}
+
+/// Consumes a quoted string after its opening quote and returns the end
+/// offset, closing quote included.
+fn read_quoted(
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    quote: char,
+    line: &mut Line,
+) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in chars.by_ref() {
+        if c == '\n' {
+            line.next(i);
+        }
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return Some(i + c.len_utf8());
+        }
+    }
+    None
+}
+
+/// Consumes `name}` after `${` in a bare word.
+fn read_variable_name(
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    span: Span,
+) -> Result<(), ParseError> {
+    for (_, c) in chars.by_ref() {
+        if c == '}' {
+            return Ok(());
+        }
+        if c.is_whitespace() || c == ';' || c == '{' {
+            break;
+        }
+    }
+    Err(ParseError {
+        message: "missing `}` in variable name".into(),
+        span: Some(span),
+    })
+}
+
+// Same escapes as nginx: `\"`, `\'`, `\\`, `\t`, `\r` and `\n`. Any other
+// backslash is kept, so regexes such as `\d` or `\.` pass through unchanged.
+fn unescape(raw: &str) -> Cow<'_, str> {
+    if !raw.contains('\\') {
+        return Cow::Borrowed(raw);
+    }
+
+    let mut out = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(next @ ('"' | '\'' | '\\')) => out.push(next),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(next) => {
+                out.push('\\');
+                out.push(next);
+            }
+            None => out.push('\\'),
+        }
     }
+    Cow::Owned(out)
 }
 
 fn flush<'a>(
@@ -73,7 +277,7 @@ fn flush<'a>(
     input: &'a str,
     start: &mut Option<usize>,
     end: usize,
-    line: usize,
+    line: &Line,
 ) {
     let Some(s) = start.take() else {
         return;
@@ -82,8 +286,10 @@ fn flush<'a>(
         tokens.push(Token {
             kind: TokenType::Ident,
             lexeme: &input[s..end],
+            value: unescape(&input[s..end]),
             len: end - s,
-            number_line: line,
+            number_line: line.number,
+            column: line.column(input, s),
         });
     }
 }
@@ -1,7 +1,11 @@
 pub mod ast;
+pub mod diagnostic;
+mod glob;
 pub mod include;
 pub mod lexer;
 pub mod parser;
+mod printer;
 mod tests;
-pub use ast::{Ast, Block, Directive, Node};
+pub use ast::{Ast, Block, Comments, Directive, Node};
+pub use diagnostic::{Diagnostic, Span};
 pub use parser::ParseError;
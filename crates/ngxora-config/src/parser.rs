@@ -1,22 +1,44 @@
 use crate::{
-    Node,
+    Block, Diagnostic, Directive, Node, Span,
+    ast::Comments,
     lexer::{Token, TokenType},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParseError {
     pub message: String,
+    /// The offending token, or the last one for an unexpected EOF.
+    pub span: Option<Span>,
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(e: ParseError) -> Self {
+        Diagnostic::new(e.message, e.span)
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Parser<'a> {
     tokens: &'a [Token<'a>],
     pos: usize,
+    /// Line where the last consumed token ends, `0` before the first one.
+    line: usize,
+    /// Syntax errors so far; parsing resumes after each broken statement.
+    errors: Vec<ParseError>,
+    /// Whether an error was recorded at the end of input, which every
+    /// unclosed block would otherwise repeat.
+    eof_reported: bool,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(tokens: &'a [Token<'a>]) -> Self {
-        Self { tokens, pos: 0 }
+        Self {
+            tokens,
+            pos: 0,
+            line: 0,
+            errors: Vec::new(),
+            eof_reported: false,
+        }
     }
 
     fn peek(&self) -> Option<&'a Token<'a>> {
@@ -25,94 +47,212 @@ impl<'a> Parser<'a> {
 
     fn next(&mut self) -> Option<&'a Token<'a>> {
         let tok = self.tokens.get(self.pos);
-        if tok.is_some() {
+        if let Some(tok) = tok {
             self.pos += 1;
+            self.line = tok.number_line + tok.lexeme.matches('\n').count();
         }
         tok
     }
 
-    fn expect(&mut self, kind: TokenType) -> Result<(), ParseError> {
-        let tok = self.next().ok_or(ParseError {
-            message: "unexpected EOF".into(),
-        })?;
-        if tok.kind == kind {
-            Ok(())
-        } else {
-            Err(ParseError {
-                message: format!("expected {:?}, got {:?}", kind, tok.kind),
-            })
+    /// Consumes the comments before the next token. A blank line, before or
+    /// between them, is recorded as an empty string.
+    fn leading_comments(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(tok) = self.peek() {
+            if self.line > 0
+                && tok.number_line > self.line + 1
+                && lines.last() != Some(&String::new())
+            {
+                lines.push(String::new());
+            }
+            if tok.kind != TokenType::Comment {
+                break;
+            }
+            lines.push(tok.lexeme.to_string());
+            self.next();
+        }
+        lines
+    }
+
+    /// Consumes a comment on the line of the last consumed token.
+    fn trailing_comment(&mut self) -> Option<String> {
+        let tok = self
+            .peek()
+            .filter(|tok| tok.kind == TokenType::Comment && tok.number_line == self.line)?;
+        self.next();
+        Some(tok.lexeme.to_string())
+    }
+
+    /// An error pointing at `tok`, or at the last token when input ran out.
+    fn error(&self, tok: Option<&Token<'_>>, message: impl Into<String>) -> ParseError {
+        ParseError {
+            message: message.into(),
+            span: tok.or(self.tokens.last()).map(Token::span),
+        }
+    }
+
+    fn record(&mut self, err: ParseError) {
+        self.eof_reported |= self.peek().is_none();
+        self.errors.push(err);
+    }
+
+    /// Skips the rest of a broken statement: up to and including its `;`
+    /// or, once it opened a block, the matching `}`. A `}` closing the
+    /// enclosing block is left for that block.
+    fn recover(&mut self) {
+        let mut depth = 0usize;
+        while let Some(tok) = self.peek() {
+            match tok.kind {
+                TokenType::Semicolon if depth == 0 => {
+                    self.next();
+                    return;
+                }
+                TokenType::LBrace => depth += 1,
+                TokenType::RBrace if depth == 0 => return,
+                TokenType::RBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.next();
+                        return;
+                    }
+                }
+                _ => {}
+            }
+            self.next();
         }
     }
 
+    /// The first syntax error, if any. See [`Parser::into_errors`].
     pub fn parse_items(&mut self, until_rbrace: bool) -> Result<Vec<Node>, ParseError> {
+        let (items, _) = self.parse_body(until_rbrace);
+        match self.errors.first() {
+            Some(err) => Err(err.clone()),
+            None => Ok(items),
+        }
+    }
+
+    /// Every syntax error met so far, in source order.
+    pub fn into_errors(self) -> Vec<ParseError> {
+        self.errors
+    }
+
+    /// Like [`Parser::parse_items`], also returning the comments after the
+    /// last item. Errors are collected rather than returned.
+    pub(crate) fn parse_body(&mut self, until_rbrace: bool) -> (Vec<Node>, Vec<String>) {
         let mut items: Vec<Node> = Vec::new();
 
-        while let Some(token) = self.peek() {
+        loop {
+            let leading = self.leading_comments();
+            let Some(token) = self.peek() else {
+                if until_rbrace && !self.eof_reported {
+                    self.record(self.error(None, "unexpected EOF: expected '}'"));
+                }
+                return (items, leading);
+            };
             if token.kind == TokenType::RBrace {
                 if until_rbrace {
-                    break;
+                    return (items, leading);
                 }
 
-                return Err(ParseError {
-                    message: "unexpected '}'".into(),
-                });
+                self.record(self.error(Some(token), "unexpected '}'"));
+                self.next();
+                continue;
             }
 
-            items.push(self.parse_stmt()?);
-        }
-
-        if until_rbrace && self.peek().is_none() {
-            return Err(ParseError {
-                message: "unexpected EOF: expected '}'".into(),
-            });
+            match self.parse_stmt(leading) {
+                Ok(node) => items.push(node),
+                Err(err) => {
+                    self.record(err);
+                    self.recover();
+                }
+            }
         }
-
-        Ok(items)
     }
 
-    fn parse_stmt(&mut self) -> Result<Node, ParseError> {
-        let name_tok = self.next().ok_or(ParseError {
-            message: "unexpected EOF".into(),
-        })?;
+    /// Parses one directive or block. On error the offending token is left
+    /// unconsumed for [`Parser::recover`].
+    fn parse_stmt(&mut self, leading: Vec<String>) -> Result<Node, ParseError> {
+        let name_tok = self
+            .peek()
+            .ok_or_else(|| self.error(None, "unexpected EOF"))?;
 
         if name_tok.kind != TokenType::Ident {
-            return Err(ParseError {
-                message: format!("expected Ident, got {:?}", name_tok.kind),
-            });
+            return Err(self.error(
+                Some(name_tok),
+                format!("expected a directive name, got {}", source_text(name_tok)),
+            ));
         };
+        self.next();
 
-        let name = name_tok.lexeme.to_string();
+        let name = name_tok.value.to_string();
+        let span = name_tok.span();
         let mut args = Vec::new();
+        let mut quoted = Vec::new();
+
+        let mut comments = Comments {
+            leading,
+            trailing: None,
+        };
 
         while let Some(tok) = self.peek() {
-            if tok.kind != TokenType::Ident {
+            // A comment between arguments moves above the directive.
+            if tok.kind == TokenType::Comment {
+                comments.leading.push(tok.lexeme.to_string());
+                self.next();
+                continue;
+            }
+            if !matches!(tok.kind, TokenType::Ident | TokenType::Quoted) {
                 break;
             }
-            let tok = self.next().ok_or(ParseError {
-                message: "unexpected EOF".into(),
-            })?;
-            args.push(tok.lexeme.to_string());
+            self.next();
+            args.push(tok.value.to_string());
+            quoted.push(tok.kind == TokenType::Quoted);
         }
 
-        match self.peek().map(|t| &t.kind) {
-            Some(TokenType::Semicolon) => {
+        match self.peek() {
+            Some(tok) if tok.kind == TokenType::Semicolon => {
                 self.next(); // consumed ";"
-                Ok(Node::directive(name, args))
+                comments.trailing = self.trailing_comment();
+                Ok(Node::Directive(Directive {
+                    name,
+                    args,
+                    quoted,
+                    span,
+                    comments,
+                }))
             }
 
-            Some(TokenType::LBrace) => {
+            Some(tok) if tok.kind == TokenType::LBrace => {
                 self.next(); // consumed "{"
-                let children = self.parse_items(true)?;
-                self.expect(TokenType::RBrace)?;
-                Ok(Node::block(name, args, children))
+                comments.trailing = self.trailing_comment();
+                let (children, closing) = self.parse_body(true);
+                // Only EOF ends the body without a "}", and that is recorded.
+                self.next();
+                let closing = Comments {
+                    leading: closing,
+                    trailing: self.trailing_comment(),
+                };
+                Ok(Node::Block(Block {
+                    name,
+                    args,
+                    quoted,
+                    children,
+                    span,
+                    comments,
+                    closing,
+                }))
             }
 
-            Some(kind) => Err(ParseError {
-                message: format!("expected ';' or '{{', got {:?}", kind),
-            }),
-            None => Err(ParseError {
-                message: "unexpected EOF after directive".into(),
-            }),
+            Some(tok) => Err(self.error(
+                Some(tok),
+                format!("expected ';' or '{{', got {}", source_text(tok)),
+            )),
+            None => Err(self.error(None, "unexpected EOF after directive")),
         }
     }
 }
+
+/// `tok` as written, quoted for an error message.
+fn source_text(tok: &Token<'_>) -> String {
+    format!("'{}'", tok.lexeme)
+}
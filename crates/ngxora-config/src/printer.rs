@@ -0,0 +1,139 @@
+// Canonical text form of an `Ast`: four-space indentation, one directive per
+// line, comments and single blank lines kept where they were written.
+
+use crate::{
+    Ast, Node,
+    ast::Comments,
+    lexer::{Token, TokenType},
+};
+use std::fmt::{self, Display, Formatter, Write as _};
+
+const INDENT: &str = "    ";
+
+impl Display for Ast {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        print_nodes(&mut out, &self.items, 0);
+        print_comments(&mut out, &self.comments, 0, !self.items.is_empty(), true);
+        f.write_str(&out)
+    }
+}
+
+fn print_nodes(out: &mut String, nodes: &[Node], depth: usize) {
+    for (i, node) in nodes.iter().enumerate() {
+        let indent = INDENT.repeat(depth);
+        match node {
+            Node::Directive(directive) => {
+                print_comments(out, &directive.comments.leading, depth, i > 0, false);
+                out.push_str(&indent);
+                print_head(out, &directive.name, &directive.args, &directive.quoted);
+                out.push(';');
+                print_trailing(out, &directive.comments);
+            }
+            Node::Block(block) => {
+                print_comments(out, &block.comments.leading, depth, i > 0, false);
+                out.push_str(&indent);
+                print_head(out, &block.name, &block.args, &block.quoted);
+                out.push_str(" {");
+                print_trailing(out, &block.comments);
+                print_nodes(out, &block.children, depth + 1);
+                print_comments(
+                    out,
+                    &block.closing.leading,
+                    depth + 1,
+                    !block.children.is_empty(),
+                    true,
+                );
+                out.push_str(&indent);
+                out.push('}');
+                print_trailing(out, &block.closing);
+            }
+        }
+    }
+}
+
+/// Writes comment lines. Blank lines are dropped at the start of a block
+/// and, for the comments before a `}` or the end of the file, at the end.
+fn print_comments(
+    out: &mut String,
+    lines: &[String],
+    depth: usize,
+    mut started: bool,
+    closing: bool,
+) {
+    let lines = if closing {
+        let end = lines.iter().rposition(|line| !line.is_empty());
+        &lines[..end.map_or(0, |i| i + 1)]
+    } else {
+        lines
+    };
+    for line in lines {
+        if line.is_empty() {
+            if started {
+                out.push('\n');
+            }
+            continue;
+        }
+        started = true;
+        let _ = writeln!(out, "{}{line}", INDENT.repeat(depth));
+    }
+}
+
+fn print_trailing(out: &mut String, comments: &Comments) {
+    if let Some(comment) = &comments.trailing {
+        out.push(' ');
+        out.push_str(comment);
+    }
+    out.push('\n');
+}
+
+fn print_head(out: &mut String, name: &str, args: &[String], quoted: &[bool]) {
+    out.push_str(name);
+    for (i, arg) in args.iter().enumerate() {
+        out.push(' ');
+        if quoted.get(i).copied().unwrap_or(false) || !is_bare_word(arg) {
+            push_quoted(out, arg);
+        } else {
+            out.push_str(arg);
+        }
+    }
+}
+
+/// Whether `arg` reads back as itself when written without quotes, with a
+/// `;` or space after it.
+fn is_bare_word(arg: &str) -> bool {
+    let text = format!("{arg};");
+    match Token::tokenize(&text).as_deref() {
+        Ok([token, end]) => {
+            token.kind == TokenType::Ident
+                && token.value == arg
+                && token.lexeme == arg
+                && end.kind == TokenType::Semicolon
+        }
+        _ => false,
+    }
+}
+
+/// Writes `value` as a `"..."` string that the lexer unescapes back to it.
+/// A backslash is only doubled where it would otherwise start an escape, so
+/// regexes such as `\d` stay readable.
+fn push_quoted(out: &mut String, value: &str) {
+    out.push('"');
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' => match chars.peek() {
+                None | Some('"' | '\'' | '\\' | 't' | 'r' | 'n' | '\n' | '\t' | '\r') => {
+                    out.push_str("\\\\");
+                }
+                Some(_) => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -1,7 +1,10 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::{
-        Ast, Node,
+        Ast, Diagnostic, Node,
+        diagnostic::{render_all, suggest},
+        glob::matches,
         include::IncludeResolver,
         lexer::{Token, TokenType},
     };
@@ -11,7 +14,7 @@ mod tests {
     #[test]
     fn tokenizes_simple_block_and_comment() {
         let input = "server { listen 80; # comment\n}";
-        let tokens = Token::tokenize(input);
+        let tokens = Token::tokenize(input).unwrap();
         let kinds: Vec<_> = tokens.into_iter().map(|t| t.kind).collect();
 
         assert_eq!(
@@ -27,6 +30,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parses_quoted_strings_and_escapes() {
+        let input = r#"
+location ~ "^/v[0-9]{1,2}/(.*);$" {
+    response_add Cache-Control "public, max-age=60";
+    response_add X-Note 'it\'s "fine"\t# not a comment';
+    return 301 https://${host}/a#b;
+    set path\ with\ spaces "C:\\dir\d" "";
+}
+"#;
+        let ast = Ast::parse_config(input).unwrap();
+        let Node::Block(location) = &ast.items[0] else {
+            panic!("expected location block");
+        };
+        assert_eq!(location.args, ["~", "^/v[0-9]{1,2}/(.*);$"]);
+        assert_eq!(location.quoted, [false, true]);
+
+        let directives: Vec<_> = location
+            .children
+            .iter()
+            .map(|node| match node {
+                Node::Directive(directive) => directive,
+                Node::Block(_) => panic!("unexpected block"),
+            })
+            .collect();
+        assert_eq!(directives[0].args, ["Cache-Control", "public, max-age=60"]);
+        assert!(!directives[0].is_quoted(0) && directives[0].is_quoted(1));
+        assert_eq!(
+            directives[1].args,
+            ["X-Note", "it's \"fine\"\t# not a comment"]
+        );
+        assert_eq!(directives[2].args, ["301", "https://${host}/a#b"]);
+        assert_eq!(directives[3].args, [r"path\ with\ spaces", r"C:\dir\d", ""]);
+        assert_eq!(directives[3].quoted, [false, true, true]);
+    }
+
+    #[test]
+    fn rejects_malformed_quoted_strings() {
+        for (input, expected, line, column) in [
+            (
+                "server {\n  return 200 \"ok;\n}\n",
+                "unterminated quoted string",
+                2,
+                14,
+            ),
+            (
+                "return 200 'ok'x;",
+                "unexpected `x` after quoted string",
+                1,
+                16,
+            ),
+            (
+                "return 301 https://${host;",
+                "missing `}` in variable name",
+                1,
+                21,
+            ),
+        ] {
+            let err = Ast::parse_config(input).unwrap_err();
+            assert!(err.message.contains(expected), "{input}: {}", err.message);
+            let span = err.span.expect("lexer errors carry a span");
+            assert_eq!((span.line, span.column), (line, column), "{input}");
+        }
+    }
+
     #[test]
     fn parses_golden_conf() {
         let input = include_str!("fixtures/golden.conf");
@@ -45,6 +113,7 @@ mod tests {
                     ],
                 )],
             )],
+            ..Default::default()
         };
 
         assert_eq!(ast, expected);
@@ -56,6 +125,132 @@ mod tests {
         assert!(err.message.contains("unexpected"));
     }
 
+    #[test]
+    fn records_spans_for_nodes_and_syntax_errors() {
+        let path = std::path::Path::new("/etc/ngxora/ngxora.conf");
+        let ast =
+            Ast::parse_config_with_path("http {\n\tserver {\n  listen 80;\n}}", path).unwrap();
+        let Node::Block(http) = &ast.items[0] else {
+            panic!("expected http block");
+        };
+        let Node::Block(server) = &http.children[0] else {
+            panic!("expected server block");
+        };
+        assert_eq!((server.span.line, server.span.column), (2, 2));
+        assert_eq!(server.span.len, "server".len());
+        let listen = server.children[0].span();
+        assert_eq!(listen.to_string(), "/etc/ngxora/ngxora.conf:3:3");
+
+        let err = Ast::parse_config_with_path("http {\n  listen 80\n}", path).unwrap_err();
+        assert_eq!(err.message, "expected ';' or '{', got '}'");
+        assert_eq!(
+            err.span.map(|span| span.to_string()).as_deref(),
+            Some("/etc/ngxora/ngxora.conf:3:1")
+        );
+    }
+
+    #[test]
+    fn reports_every_syntax_error_after_skipping_broken_statements() {
+        let path = std::path::Path::new("/etc/ngxora/ngxora.conf");
+        let input = "http {\n  ; listen 80;\n  server { listen 81 }\n  location / { a { b; }; }\n  root /srv;\n}\n}";
+        let errors = Ast::parse_config_all(input, path).unwrap_err();
+        let reported: Vec<_> = errors
+            .iter()
+            .map(|err| (err.message.as_str(), err.span.clone().unwrap().to_string()))
+            .collect();
+        assert_eq!(
+            reported,
+            [
+                (
+                    "expected a directive name, got ';'",
+                    "/etc/ngxora/ngxora.conf:2:3".to_string()
+                ),
+                (
+                    "expected ';' or '{', got '}'",
+                    "/etc/ngxora/ngxora.conf:3:22".to_string()
+                ),
+                (
+                    "expected a directive name, got ';'",
+                    "/etc/ngxora/ngxora.conf:4:24".to_string()
+                ),
+                ("unexpected '}'", "/etc/ngxora/ngxora.conf:7:1".to_string()),
+            ]
+        );
+        // The first-error API reports the same first error.
+        assert_eq!(
+            Ast::parse_config_with_path(input, path).unwrap_err(),
+            errors[0]
+        );
+
+        let errors = Ast::parse_config_all("http {\n  server {\n    listen 80", path).unwrap_err();
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert_eq!(errors[0].message, "unexpected EOF after directive");
+    }
+
+    #[test]
+    fn renders_diagnostics_with_source_snippet() {
+        let base = unique_temp_dir("ngxora-diagnostic");
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join("ngxora.conf");
+        fs::write(&path, "http {\n    lisen 80;\n}\n").unwrap();
+
+        let ast = Ast::parse_config_with_path(&fs::read_to_string(&path).unwrap(), &path).unwrap();
+        let Node::Block(http) = &ast.items[0] else {
+            panic!("expected http block");
+        };
+        let mut diagnostic = Diagnostic::new(
+            "unknown directive `lisen`",
+            Some(http.children[0].span().clone()),
+        );
+        diagnostic.help = Some("did you mean `listen`?".into());
+        let rendered = render_all(&[diagnostic.clone(), diagnostic]);
+
+        let expected = format!(
+            "error: unknown directive `lisen`\n --> {}:2:5\n  |\n2 |     lisen 80;\n  |     ^^^^^\n  = help: did you mean `listen`?\n",
+            path.display()
+        );
+        assert!(rendered.starts_with(&expected), "{rendered}");
+        assert!(rendered.ends_with("error: aborting due to 2 previous errors"));
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn suggests_close_directive_names_only() {
+        let names = ["listen", "server_name", "proxy_pass", "location"];
+        assert_eq!(suggest("lisen", names), Some("listen"));
+        assert_eq!(suggest("server_nmae", names), Some("server_name"));
+        assert_eq!(suggest("proxy_pas", names), Some("proxy_pass"));
+        assert_eq!(suggest("upstream", names), None);
+    }
+
+    #[test]
+    fn include_errors_point_into_the_included_file() {
+        let base = unique_temp_dir("ngxora-include-span");
+        fs::create_dir_all(&base).unwrap();
+        let broken = base.join("broken.conf");
+        fs::write(&broken, "server {\n  listen 80;\n").unwrap();
+
+        let input = format!(
+            "http {{\n  include {};\n  include missing.conf;\n}}",
+            broken.display()
+        );
+        let ast = Ast::parse_config(&input).unwrap();
+        let err = IncludeResolver::new(&ast, &base).resolve(&ast).unwrap_err();
+        let span = err.span.expect("syntax error span");
+        assert_eq!(
+            span.file.as_deref(),
+            Some(fs::canonicalize(&broken).unwrap().as_path())
+        );
+        assert_eq!(span.line, 2);
+
+        let ast = Ast::parse_config("http {\n  include missing.conf;\n}").unwrap();
+        let err = IncludeResolver::new(&ast, &base).resolve(&ast).unwrap_err();
+        assert!(err.message.contains("failed to resolve include"));
+        let span = err.span.expect("include directive span");
+        assert_eq!((span.line, span.column), (2, 3));
+        let _ = fs::remove_dir_all(base);
+    }
+
     #[test]
     fn resolves_include_directive() {
         let include_path =
@@ -80,6 +275,7 @@ mod tests {
                     )],
                 )],
             )],
+            ..Default::default()
         };
 
         assert_eq!(resolved, expected);
@@ -123,6 +319,246 @@ mod tests {
         let _ = fs::remove_dir_all(outside);
     }
 
+    #[test]
+    fn rejects_glob_patterns_outside_root_without_naming_files() {
+        let root = unique_temp_dir("ngxora-include-glob-root");
+        let outside = unique_temp_dir("ngxora-include-glob-outside");
+        fs::create_dir_all(root.join("conf.d")).unwrap();
+        fs::create_dir_all(outside.join("secrets")).unwrap();
+        fs::write(outside.join("secrets/db-password.conf"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+
+        let mut patterns = vec![
+            format!("{}/secrets/*", outside.display()),
+            format!("{}/*/*.conf", outside.display()),
+            "../*/secrets/*".to_string(),
+            "conf.d/*/../../../*/secrets/*".to_string(),
+        ];
+        if cfg!(unix) {
+            patterns.push("linked/secrets/*".to_string());
+        }
+        for pattern in patterns {
+            let input = format!("http {{ include {pattern}; }}");
+            let ast = Ast::parse_config(&input).unwrap();
+            let err = IncludeResolver::new(&ast, &root).resolve(&ast).unwrap_err();
+            assert!(
+                err.message.contains("escapes root config directory"),
+                "{pattern}: {}",
+                err.message
+            );
+            assert!(!err.message.contains("db-password"), "{}", err.message);
+        }
+
+        let ast = Ast::parse_config("http { include conf.d/*.conf; }").unwrap();
+        assert!(IncludeResolver::new(&ast, &root).resolve(&ast).is_ok());
+        let _ = fs::remove_dir_all(root);
+        let _ = fs::remove_dir_all(outside);
+    }
+
+    #[test]
+    fn matches_glob_wildcards_and_classes() {
+        assert!(matches("*.conf", "api.conf"));
+        assert!(!matches("*.conf", "api.conf.bak"));
+        assert!(matches("site-?", "site-a"));
+        assert!(!matches("site-?", "site-ab"));
+        assert!(matches("[0-9][0-9]-*", "10-default"));
+        assert!(!matches("[!0-9]*", "10-default"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("[]x]", "]"));
+        assert!(matches("[x", "[x"));
+    }
+
+    #[test]
+    fn resolves_glob_includes_in_sorted_order_per_block() {
+        let base = unique_temp_dir("ngxora-include-glob");
+        fs::create_dir_all(base.join("conf.d")).unwrap();
+        fs::create_dir_all(base.join("locations")).unwrap();
+        fs::write(base.join("conf.d/20-b.conf"), "server { listen 8082; }").unwrap();
+        fs::write(
+            base.join("conf.d/10-a.conf"),
+            "server { listen 8081; include ../locations/*; }",
+        )
+        .unwrap();
+        fs::write(base.join("conf.d/.hidden.conf"), "server { listen 1; }").unwrap();
+        fs::write(base.join("conf.d/notes.txt"), "not included").unwrap();
+        fs::write(
+            base.join("locations/api"),
+            "location /api { return 301 /; }",
+        )
+        .unwrap();
+        fs::write(base.join("upstream.conf"), "server 127.0.0.1:9000;").unwrap();
+
+        let input = "http { include conf.d/*.conf empty.d/*.conf; upstream app { include upstream.conf; } }";
+        let ast = Ast::parse_config(input).unwrap();
+        let resolved = IncludeResolver::new(&ast, &base).resolve(&ast).unwrap();
+
+        let location = Node::block(
+            "location".into(),
+            vec!["/api".into()],
+            vec![Node::directive(
+                "return".into(),
+                vec!["301".into(), "/".into()],
+            )],
+        );
+        let expected = Ast {
+            items: vec![Node::block(
+                "http".into(),
+                vec![],
+                vec![
+                    Node::block(
+                        "server".into(),
+                        vec![],
+                        vec![
+                            Node::directive("listen".into(), vec!["8081".into()]),
+                            location,
+                        ],
+                    ),
+                    Node::block(
+                        "server".into(),
+                        vec![],
+                        vec![Node::directive("listen".into(), vec!["8082".into()])],
+                    ),
+                    Node::block(
+                        "upstream".into(),
+                        vec!["app".into()],
+                        vec![Node::directive(
+                            "server".into(),
+                            vec!["127.0.0.1:9000".into()],
+                        )],
+                    ),
+                ],
+            )],
+            ..Default::default()
+        };
+        assert_eq!(resolved, expected);
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn include_sources_cover_included_files_and_glob_patterns() {
+        let base = unique_temp_dir("ngxora-include-sources");
+        fs::create_dir_all(base.join("sites-enabled")).unwrap();
+        let base = fs::canonicalize(base).unwrap();
+        fs::write(base.join("sites-enabled/api"), "server { listen 8081; }").unwrap();
+        fs::write(base.join("upstream.conf"), "server 127.0.0.1:9000;").unwrap();
+
+        let input = "http { include sites-enabled/*; upstream app { include ./upstream.conf; } }";
+        let ast = Ast::parse_config(input).unwrap();
+        let (_, sources) = IncludeResolver::new(&ast, &base)
+            .resolve_with_sources(&ast)
+            .unwrap();
+
+        assert_eq!(
+            sources.files,
+            vec![base.join("sites-enabled/api"), base.join("upstream.conf")]
+        );
+        assert_eq!(sources.patterns, vec![base.join("sites-enabled/*")]);
+        assert!(sources.contains(&base.join("sites-enabled/api")));
+        assert!(sources.contains(&base.join("sites-enabled/shop")));
+        assert!(!sources.contains(&base.join("sites-enabled/.shop.swp")));
+        assert!(!sources.contains(&base.join("sites-enabled/nested/shop")));
+        assert!(!sources.contains(&base.join("access.log")));
+        let _ = fs::remove_dir_all(base);
+    }
+
+    #[test]
+    fn rejects_includes_that_do_not_fit_their_block() {
+        let base = unique_temp_dir("ngxora-include-context");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("server.conf"), "\nserver { listen 80; }").unwrap();
+        let outside = unique_temp_dir("ngxora-include-context-outside");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(
+            outside.join("location.conf"),
+            "location / { return 301 /; }",
+        )
+        .unwrap();
+
+        for (input, expected) in [
+            (
+                "http { server { location / { include server.conf; } } }".to_string(),
+                "included `server` block is not allowed inside `location`",
+            ),
+            (
+                "http { server { location / { headers { include server.conf; } } } }".to_string(),
+                "include is not allowed inside `headers` block",
+            ),
+            (
+                format!(
+                    "http {{ server {{ include {}/*.conf; }} }}",
+                    outside.display()
+                ),
+                "escapes root config directory",
+            ),
+        ] {
+            let ast = Ast::parse_config(&input).unwrap();
+            let err = IncludeResolver::new(&ast, &base).resolve(&ast).unwrap_err();
+            assert!(err.message.contains(expected), "{input}: {}", err.message);
+        }
+
+        let ast =
+            Ast::parse_config("http { server { location / { include server.conf; } } }").unwrap();
+        let err = IncludeResolver::new(&ast, &base).resolve(&ast).unwrap_err();
+        assert_eq!(err.span.map(|span| span.line), Some(2));
+        let _ = fs::remove_dir_all(base);
+        let _ = fs::remove_dir_all(outside);
+    }
+
+    #[test]
+    fn formats_config_keeping_comments_and_blank_lines() {
+        let input = "# top\n\n\nhttp {   # main\n\n  server {\nlisten 80 ;   # plain\n\n\n\
+                     # api\n location /api { return 301 /; }\n  # end of server\n\n }\n\n}\n# eof\n\n";
+        let ast = Ast::parse_config(input).unwrap();
+        let formatted = ast.to_string();
+
+        assert_eq!(
+            formatted,
+            "# top\n\nhttp { # main\n    server {\n        listen 80; # plain\n\n        # api\n\
+             \x20       location /api {\n            return 301 /;\n        }\n        # end of server\n\
+             \x20   }\n}\n# eof\n"
+        );
+        let reparsed = Ast::parse_config(&formatted).unwrap();
+        assert_eq!(reparsed, ast);
+        assert_eq!(reparsed.to_string(), formatted);
+    }
+
+    #[test]
+    fn prints_arguments_that_read_back_unchanged() {
+        let values = [
+            "plain",
+            "${host}/a",
+            "two words",
+            "",
+            "semi;colon",
+            "{",
+            "#hash",
+            "quote\"inside",
+            "tab\there",
+            "line\nbreak",
+            r"^/(\d+)\.png$",
+            r"trailing\",
+            r"escaped\n",
+        ];
+        let directive = Node::directive(
+            "add".into(),
+            values.iter().map(|value| value.to_string()).collect(),
+        );
+        let ast = Ast {
+            items: vec![directive],
+            ..Default::default()
+        };
+
+        let text = ast.to_string();
+        assert!(text.starts_with(r#"add plain ${host}/a "two words" "" "semi;colon""#));
+        assert!(text.contains(r#" ^/(\d+)\.png$ "trailing\\" "escaped\\n";"#));
+        let reparsed = Ast::parse_config(&text).unwrap();
+        let Node::Directive(reparsed) = &reparsed.items[0] else {
+            panic!("expected a directive");
+        };
+        assert_eq!(reparsed.args, values);
+    }
+
     fn unique_temp_dir(prefix: &str) -> std::path::PathBuf {
         let nanos = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -20,6 +20,11 @@ pub struct PluginState {
     pub extensions: Extensions,
 }
 
+/// Subject of a credential verified by an authentication plugin, left in
+/// [`PluginState::extensions`] for plugins later in the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedSubject(pub String);
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PluginError {
     pub plugin: String,
@@ -79,7 +84,11 @@ pub trait HeaderMapMut: Send {
 pub struct RequestCtx<'a> {
     pub state: &'a mut PluginState,
     pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub scheme: &'a str,
     pub host: Option<&'a str>,
+    /// Primary `server_name` of the matched server block.
+    pub server_name: Option<&'a str>,
     pub method: &'a Method,
     pub client_ip: Option<IpAddr>,
     pub headers: &'a mut dyn HeaderMapMut,
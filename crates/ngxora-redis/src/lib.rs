@@ -0,0 +1,116 @@
+//! Connection handling shared by the stores that talk to a Redis-protocol
+//! server: the response cache and the `rate-limit` plugin.
+
+#[cfg(feature = "test-support")]
+pub mod test_support;
+
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+/// After a failed connect, callers skip the server for this long instead of
+/// each paying the connect timeout.
+pub const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A [`ConnectionManager`] opened on first use.
+///
+/// Once connected, the manager reconnects on its own. Until then a failed
+/// connect is remembered for [`RECONNECT_BACKOFF`], so a down server costs
+/// one connect timeout per backoff window rather than one per request.
+pub struct LazyConnection {
+    client: redis::Client,
+    name: &'static str,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+    connection: OnceCell<ConnectionManager>,
+    last_connect_failure: Mutex<Option<Instant>>,
+}
+
+impl LazyConnection {
+    /// Wrap `client`; `name` identifies the store in backoff errors.
+    pub fn new(
+        client: redis::Client,
+        name: &'static str,
+        connect_timeout: Duration,
+        response_timeout: Duration,
+    ) -> Self {
+        Self {
+            client,
+            name,
+            connect_timeout,
+            response_timeout,
+            connection: OnceCell::new(),
+            last_connect_failure: Mutex::new(None),
+        }
+    }
+
+    /// Return the connection, opening it unless a recent connect failed.
+    pub async fn get(&self) -> redis::RedisResult<ConnectionManager> {
+        if let Some(connection) = self.connection.get() {
+            return Ok(connection.clone());
+        }
+        if self
+            .last_connect_failure
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|at| at.elapsed() < RECONNECT_BACKOFF)
+        {
+            return Err(redis::RedisError::from((
+                redis::ErrorKind::IoError,
+                "backing off after a failed connect",
+                self.name.to_string(),
+            )));
+        }
+
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(self.connect_timeout)
+            .set_response_timeout(self.response_timeout)
+            .set_number_of_retries(1);
+        let result = self
+            .connection
+            .get_or_try_init(|| self.client.get_connection_manager_with_config(config))
+            .await
+            .cloned();
+        if result.is_err() {
+            *self
+                .last_connect_failure
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LazyConnection;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn failed_connect_backs_off_without_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let client = redis::Client::open(format!("redis://{addr}")).unwrap();
+        let connection = LazyConnection::new(
+            client,
+            "test store",
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+
+        assert!(connection.get().await.is_err());
+
+        // A listener is back, but the backoff refuses without connecting.
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        let started = Instant::now();
+        let Err(err) = connection.get().await else {
+            panic!("connected during the backoff");
+        };
+        assert!(err.to_string().contains("backing off"), "{err}");
+        assert!(err.to_string().contains("test store"), "{err}");
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
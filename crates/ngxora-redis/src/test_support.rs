@@ -0,0 +1,193 @@
+//! In-process stand-in for a Redis-protocol server, for the tests of the
+//! stores built on this crate.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+/// Keys with their values and expiry times.
+pub type Keyspace = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>>>;
+
+/// Serves commands the built-in ones do not cover; `None` falls through to
+/// them.
+pub type Extension = dyn Fn(&Keyspace, &[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync;
+
+/// Minimal RESP2 server. It implements `GET`, `SET` (with `PX`), `DEL`,
+/// `STRLEN` and `SCAN` (with `MATCH` and `COUNT`) over an in-memory
+/// keyspace, answers `+OK` to anything else, and records the names of the
+/// commands it served.
+pub struct StandIn {
+    /// `redis://` URL of the stand-in, database `0`.
+    pub url: String,
+    pub keyspace: Keyspace,
+    /// Upper-cased command names, in the order they were served.
+    pub commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl StandIn {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|_, _| None).await
+    }
+
+    /// Like [`StandIn::spawn`], with `extension` tried before the built-in
+    /// commands.
+    pub async fn spawn_with(
+        extension: impl Fn(&Keyspace, &[Vec<u8>]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let keyspace: Keyspace = Arc::default();
+        let commands: Arc<Mutex<Vec<String>>> = Arc::default();
+        let extension: Arc<Extension> = Arc::new(extension);
+        let shared = Arc::clone(&keyspace);
+        let log = Arc::clone(&commands);
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let keyspace = Arc::clone(&shared);
+                let log = Arc::clone(&log);
+                let extension = Arc::clone(&extension);
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut read = BufReader::new(read);
+                    while let Some(args) = read_command(&mut read).await {
+                        log.lock()
+                            .unwrap()
+                            .push(String::from_utf8_lossy(&args[0]).to_ascii_uppercase());
+                        let reply = extension(&keyspace, &args)
+                            .unwrap_or_else(|| execute(&keyspace, &args));
+                        if write.write_all(&reply).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Self {
+            url: format!("redis://{addr}/0"),
+            keyspace,
+            commands,
+        }
+    }
+}
+
+/// RESP bulk string reply.
+pub fn bulk(value: &[u8]) -> Vec<u8> {
+    let mut out = format!("${}\r\n", value.len()).into_bytes();
+    out.extend_from_slice(value);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+async fn read_command<R: AsyncBufReadExt + Unpin>(read: &mut R) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    read.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        read.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        read.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Some(args)
+}
+
+fn execute(keyspace: &Keyspace, args: &[Vec<u8>]) -> Vec<u8> {
+    let mut keys = keyspace.lock().unwrap();
+    keys.retain(|_, (_, expires)| expires.is_none_or(|at| at > Instant::now()));
+    let arg = |index: usize| String::from_utf8_lossy(&args[index]).into_owned();
+    match arg(0).to_ascii_uppercase().as_str() {
+        "GET" => match keys.get(&args[1]) {
+            Some((value, _)) => bulk(value),
+            None => b"$-1\r\n".to_vec(),
+        },
+        "SET" => {
+            let expires = option(&args[3..], "PX")
+                .map(|ms| Instant::now() + Duration::from_millis(ms.parse().unwrap()));
+            keys.insert(args[1].clone(), (args[2].clone(), expires));
+            b"+OK\r\n".to_vec()
+        }
+        "DEL" => {
+            let removed = args[1..]
+                .iter()
+                .filter(|key| keys.remove(*key).is_some())
+                .count();
+            format!(":{removed}\r\n").into_bytes()
+        }
+        "STRLEN" => {
+            let len = keys.get(&args[1]).map_or(0, |(value, _)| value.len());
+            format!(":{len}\r\n").into_bytes()
+        }
+        "SCAN" => {
+            // Keys are walked in hash order and the cursor is the next hash,
+            // so like Redis the walk survives deletes between pages; each
+            // page holds at most `COUNT` keys.
+            let cursor: u64 = arg(1).parse().unwrap();
+            let pattern =
+                option(&args[2..], "MATCH").map_or_else(|| "*".into(), |p| p.replace('\\', ""));
+            let count: usize =
+                option(&args[2..], "COUNT").map_or(10, |count| count.parse().unwrap());
+            let mut pending: Vec<(u64, &Vec<u8>)> = keys
+                .keys()
+                .map(|key| (cursor_hash(key), key))
+                .filter(|(hash, _)| *hash >= cursor)
+                .collect();
+            pending.sort();
+            let next = pending.get(count).map_or(0, |(hash, _)| *hash);
+            let matched: Vec<&Vec<u8>> = pending
+                .iter()
+                .take(count)
+                .map(|(_, key)| *key)
+                .filter(|key| glob_matches(&pattern, &String::from_utf8_lossy(key)))
+                .collect();
+            let mut out = b"*2\r\n".to_vec();
+            out.extend_from_slice(&bulk(next.to_string().as_bytes()));
+            out.extend_from_slice(format!("*{}\r\n", matched.len()).as_bytes());
+            for key in matched {
+                out.extend_from_slice(&bulk(key));
+            }
+            out
+        }
+        _ => b"+OK\r\n".to_vec(),
+    }
+}
+
+// Value following the case-insensitive option `name` among `args`.
+fn option(args: &[Vec<u8>], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg.eq_ignore_ascii_case(name.as_bytes()))
+        .and_then(|index| args.get(index + 1))
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+// FNV-1a, kept away from 0, which ends a walk.
+fn cursor_hash(key: &[u8]) -> u64 {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3)
+    });
+    hash.max(1)
+}
+
+// `*` wildcards only; enough for the patterns the stores build.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    pattern.ends_with('*') || rest.is_empty()
+}
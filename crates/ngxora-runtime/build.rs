@@ -1,5 +1,6 @@
 fn main() {
     println!("cargo:rerun-if-changed=proto/control.proto");
+    println!("cargo:rerun-if-changed=proto/grpc/health/v1/health.proto");
 
     let protoc = protoc_bin_vendored::protoc_bin_path().expect("failed to locate vendored protoc");
     unsafe {
@@ -11,4 +12,12 @@ fn main() {
         .build_client(true)
         .compile_protos(&["proto/control.proto"], &["proto"])
         .expect("failed to compile control.proto");
+
+    // Only the messages are needed to probe backends; the server backs the
+    // health check tests.
+    tonic_build::configure()
+        .build_server(true)
+        .build_client(false)
+        .compile_protos(&["proto/grpc/health/v1/health.proto"], &["proto"])
+        .expect("failed to compile health.proto");
 }
@@ -87,6 +87,11 @@ fn build_snapshot(cli: &CliArgs) -> ConfigSnapshot {
             h2c: false,
             client_max_body_size_bytes: 10 * 1024 * 1024,
             proxy_cache_max_size_bytes: 0,
+            proxy_cache_store: None,
+            resolver: None,
+            metrics_label_limit: 0,
+            log_formats: Vec::new(),
+            access_logs: Vec::new(),
         }),
         listeners: vec![Listener {
             name: cli.listener_name.clone(),
@@ -95,6 +100,8 @@ fn build_snapshot(cli: &CliArgs) -> ConfigSnapshot {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: vec![],
@@ -123,6 +130,9 @@ fn build_snapshot(cli: &CliArgs) -> ConfigSnapshot {
                     write_timeout_ms: 15_000,
                 }),
                 cache: None,
+                retry: None,
+                name: String::new(),
+                access_logs: Vec::new(),
                 upstream_protocol: ngxora_runtime::grpc::proto::UpstreamHttpProtocol::Unspecified
                     as i32,
                 tls_options: None,
@@ -131,8 +141,11 @@ fn build_snapshot(cli: &CliArgs) -> ConfigSnapshot {
                     json_config: r#"{"response":{"add":[["x-proxy","ngxora"]]}}"#.into(),
                 }],
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     }
 }
 
@@ -0,0 +1,591 @@
+//! Access logging: `log_format` rendering, `access_log` filters and sinks.
+//!
+//! Lines are rendered once per format on the request path and handed to a
+//! dedicated writer thread over a bounded queue, so a slow disk or a full
+//! socket buffer never stalls a worker. When the queue is full the line is
+//! dropped and counted in `ngxora_access_log_dropped_total`. The writer opens
+//! files on first use and keeps them open until [`reopen`] (`SIGUSR1`), so a
+//! rotated file is released without a restart. Syslog lines are sent as
+//! RFC 5424 datagrams over UDP or a unix socket.
+
+use async_trait::async_trait;
+use ngxora_compile::ir::{
+    ACCESS_LOG_SAMPLE_SCALE, AccessLog, AccessLogSink, AccessLogTarget, LogEncoding, LogField,
+    LogFormat, SyslogServer, SyslogTarget,
+};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{SignalKind, signal};
+
+/// Used when no `access_log` applies to a request.
+static DEFAULT_ACCESS_LOGS: [AccessLog; 1] = [AccessLog::To(AccessLogTarget {
+    sink: AccessLogSink::Stdout,
+    format: None,
+    statuses: Vec::new(),
+    sample: None,
+})];
+
+static DEFAULT_FORMAT: LazyLock<LogFormat> = LazyLock::new(LogFormat::builtin_default);
+
+/// Everything a log line can show about one finished request.
+#[derive(Debug, Default)]
+pub(crate) struct AccessLogRecord<'a> {
+    pub time: Option<SystemTime>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub protocol: Option<String>,
+    pub scheme: &'a str,
+    pub host: Option<&'a str>,
+    pub status: u16,
+    pub latency: Option<Duration>,
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    pub client_ip: Option<String>,
+    pub upstream: Option<&'a str>,
+    pub upstream_status: Option<u16>,
+    pub upstream_connect: Option<Duration>,
+    pub upstream_ttfb: Option<Duration>,
+    pub cache_status: Option<&'a str>,
+    pub tls_version: Option<&'a str>,
+    pub tls_cipher: Option<&'a str>,
+    pub server: Option<&'a str>,
+    pub route: Option<&'a str>,
+    pub route_id: Option<u64>,
+    pub request_id: Option<&'a str>,
+    pub request_headers: Option<&'a http::HeaderMap>,
+    pub response_headers: Option<&'a http::HeaderMap>,
+}
+
+enum LogValue<'a> {
+    Str(std::borrow::Cow<'a, str>),
+    Int(u64),
+    Secs(f64),
+}
+
+impl AccessLogRecord<'_> {
+    fn value(&self, field: &LogField) -> Option<LogValue<'_>> {
+        let text = |value: &str| Some(LogValue::Str(value.to_string().into()));
+        let secs = |value: Option<Duration>| value.map(|d| LogValue::Secs(d.as_secs_f64()));
+        match field {
+            LogField::Time => self.time.map(|time| LogValue::Str(rfc3339(time).into())),
+            LogField::Method => text(self.method),
+            LogField::Path => text(self.path),
+            LogField::Query => self.query.and_then(text),
+            LogField::Protocol => self.protocol.as_deref().and_then(text),
+            LogField::Scheme => text(self.scheme),
+            LogField::Host => self.host.and_then(text),
+            LogField::Status => Some(LogValue::Int(u64::from(self.status))),
+            LogField::LatencySecs => secs(self.latency),
+            LogField::BytesSent => self.bytes_sent.map(LogValue::Int),
+            LogField::BytesReceived => self.bytes_received.map(LogValue::Int),
+            LogField::ClientIp => self.client_ip.as_deref().and_then(text),
+            LogField::Upstream => self.upstream.and_then(text),
+            LogField::UpstreamStatus => self.upstream_status.map(|s| LogValue::Int(s.into())),
+            LogField::UpstreamConnectSecs => secs(self.upstream_connect),
+            LogField::UpstreamTtfbSecs => secs(self.upstream_ttfb),
+            LogField::CacheStatus => self.cache_status.and_then(text),
+            LogField::TlsVersion => self.tls_version.and_then(text),
+            LogField::TlsCipher => self.tls_cipher.and_then(text),
+            LogField::Server => self.server.and_then(text),
+            LogField::Route => self.route.and_then(text),
+            LogField::RouteId => self.route_id.map(LogValue::Int),
+            LogField::RequestId => self.request_id.and_then(text),
+            LogField::RequestHeader(name) => header_value(self.request_headers, name),
+            LogField::ResponseHeader(name) => header_value(self.response_headers, name),
+        }
+    }
+}
+
+fn header_value<'h>(headers: Option<&'h http::HeaderMap>, name: &str) -> Option<LogValue<'h>> {
+    headers
+        .and_then(|headers| headers.get(name))
+        .map(|value| LogValue::Str(String::from_utf8_lossy(value.as_bytes())))
+}
+
+/// Renders `record` as one line, without the trailing newline.
+pub(crate) fn render(format: &LogFormat, record: &AccessLogRecord<'_>) -> String {
+    let mut line = String::new();
+    match format.encoding {
+        LogEncoding::Json => {
+            line.push('{');
+            for field in &format.fields {
+                let Some(value) = record.value(field) else {
+                    continue;
+                };
+                if line.len() > 1 {
+                    line.push(',');
+                }
+                push_json_string(&mut line, &field.to_string());
+                line.push(':');
+                match value {
+                    LogValue::Str(value) => push_json_string(&mut line, &value),
+                    LogValue::Int(value) => {
+                        let _ = write!(line, "{value}");
+                    }
+                    LogValue::Secs(value) => {
+                        let _ = write!(line, "{value}");
+                    }
+                }
+            }
+            line.push('}');
+        }
+        LogEncoding::Text => {
+            for (index, field) in format.fields.iter().enumerate() {
+                if index > 0 {
+                    line.push(' ');
+                }
+                match record.value(field) {
+                    None => line.push('-'),
+                    Some(LogValue::Str(value)) if value.is_empty() => line.push('-'),
+                    Some(LogValue::Str(value))
+                        if value.contains(|c: char| c.is_whitespace() || c == '"') =>
+                    {
+                        push_json_string(&mut line, &value);
+                    }
+                    Some(LogValue::Str(value)) => line.push_str(&value),
+                    Some(LogValue::Int(value)) => {
+                        let _ = write!(line, "{value}");
+                    }
+                    Some(LogValue::Secs(value)) => {
+                        let _ = write!(line, "{value}");
+                    }
+                }
+            }
+        }
+    }
+    line
+}
+
+fn push_json_string(line: &mut String, value: &str) {
+    line.push_str(&serde_json::Value::from(value).to_string());
+}
+
+/// The logs that apply to a request: the route's, else the http level's,
+/// else stdout in the `default` format.
+pub(crate) fn effective<'a>(route: &'a [AccessLog], http: &'a [AccessLog]) -> &'a [AccessLog] {
+    if !route.is_empty() {
+        route
+    } else if !http.is_empty() {
+        http
+    } else {
+        &DEFAULT_ACCESS_LOGS
+    }
+}
+
+/// Whether `target` takes a request that finished with `status`.
+pub(crate) fn accepts(target: &AccessLogTarget, status: u16) -> bool {
+    if !target.statuses.is_empty() && !target.statuses.iter().any(|range| range.contains(&status)) {
+        return false;
+    }
+    target
+        .sample
+        .is_none_or(|sample| fastrand::u32(0..ACCESS_LOG_SAMPLE_SCALE) < sample)
+}
+
+/// Writes `record` to every log in `logs` that accepts it. A format that
+/// is no longer defined falls back to `default`.
+pub(crate) fn write(logs: &[AccessLog], formats: &[LogFormat], record: &AccessLogRecord<'_>) {
+    let mut rendered: Vec<(&str, String)> = Vec::new();
+    for log in logs {
+        let AccessLog::To(target) = log else {
+            continue;
+        };
+        if !accepts(target, record.status) {
+            continue;
+        }
+        let name = target.format_name();
+        let line = match rendered.iter().find(|(known, _)| *known == name) {
+            Some((_, line)) => line,
+            None => {
+                let format = formats
+                    .iter()
+                    .find(|format| format.name == name)
+                    .unwrap_or(&DEFAULT_FORMAT);
+                rendered.push((name, render(format, record)));
+                &rendered.last().expect("just pushed").1
+            }
+        };
+        write_line(&target.sink, line);
+    }
+}
+
+/// Lines queued for the writer before new ones are dropped.
+const QUEUE_CAPACITY: usize = 8192;
+
+enum Sink {
+    Stdout,
+    File(File),
+    Udp(UdpSocket, SyslogTarget),
+    #[cfg(unix)]
+    Unix(UnixDatagram, SyslogTarget),
+}
+
+enum Message {
+    Line(AccessLogSink, String),
+    /// Wakes the writer so a pending [`reopen`] is applied.
+    Reopen,
+    /// Acknowledged once every line queued before it was written.
+    Flush(SyncSender<()>),
+}
+
+/// Set by [`reopen`], applied by the writer before its next message.
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+static WRITER: LazyLock<SyncSender<Message>> = LazyLock::new(|| {
+    let (sender, receiver) = sync_channel(QUEUE_CAPACITY);
+    std::thread::Builder::new()
+        .name("access-log".into())
+        .spawn(move || run_writer(&receiver))
+        .expect("spawn access log writer thread");
+    sender
+});
+
+fn write_line(sink: &AccessLogSink, line: &str) {
+    match WRITER.try_send(Message::Line(sink.clone(), line.to_string())) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => crate::metrics::record_access_log_dropped(),
+        Err(TrySendError::Disconnected(_)) => {
+            log::warn!("access log writer is gone, dropping line for {sink}");
+        }
+    }
+}
+
+// Owns every open sink; nothing else touches them, so no locking is needed.
+fn run_writer(receiver: &Receiver<Message>) {
+    let mut sinks: HashMap<AccessLogSink, Sink> = HashMap::new();
+    while let Ok(message) = receiver.recv() {
+        if REOPEN.swap(false, Ordering::AcqRel) {
+            sinks.retain(|sink, _| !matches!(sink, AccessLogSink::File(_)));
+        }
+        match message {
+            Message::Line(sink, line) => write_to(&mut sinks, &sink, &line),
+            Message::Reopen => {}
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn write_to(sinks: &mut HashMap<AccessLogSink, Sink>, sink: &AccessLogSink, line: &str) {
+    let opened = match sinks.entry(sink.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => match open(sink) {
+            Ok(opened) => entry.insert(opened),
+            Err(err) => {
+                log::warn!("failed to open access log {sink}: {err}");
+                return;
+            }
+        },
+    };
+
+    let result = match opened {
+        // Explicit flush: Docker buffers non-tty stdout.
+        Sink::Stdout => {
+            let mut stdout = std::io::stdout().lock();
+            writeln!(stdout, "{line}").and_then(|()| stdout.flush())
+        }
+        // One `write` per line, so appends from other processes do not
+        // interleave.
+        Sink::File(file) => file.write_all(format!("{line}\n").as_bytes()),
+        Sink::Udp(socket, target) => {
+            let SyslogServer::Udp(addr) = &target.server else {
+                unreachable!("udp sink for a unix syslog server")
+            };
+            socket
+                .send_to(syslog_message(target, line).as_bytes(), addr)
+                .map(drop)
+        }
+        #[cfg(unix)]
+        Sink::Unix(socket, target) => {
+            let SyslogServer::Unix(path) = &target.server else {
+                unreachable!("unix sink for a udp syslog server")
+            };
+            socket
+                .send_to(syslog_message(target, line).as_bytes(), path)
+                .map(drop)
+        }
+    };
+    if let Err(err) = result {
+        log::warn!("failed to write access log {sink}: {err}");
+    }
+}
+
+fn open(sink: &AccessLogSink) -> std::io::Result<Sink> {
+    match sink {
+        AccessLogSink::Stdout => Ok(Sink::Stdout),
+        AccessLogSink::File(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(Sink::File),
+        AccessLogSink::Syslog(target) => match &target.server {
+            SyslogServer::Udp(addr) => {
+                let bind = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                Ok(Sink::Udp(UdpSocket::bind(bind)?, target.clone()))
+            }
+            #[cfg(unix)]
+            SyslogServer::Unix(_) => Ok(Sink::Unix(UnixDatagram::unbound()?, target.clone())),
+            #[cfg(not(unix))]
+            SyslogServer::Unix(_) => Err(std::io::Error::other(
+                "unix syslog sockets are not supported on this platform",
+            )),
+        },
+    }
+}
+
+/// Closes every access log file; each is reopened by its next line. Lines
+/// still queued are written to the reopened file.
+pub fn reopen() {
+    REOPEN.store(true, Ordering::Release);
+    // A full queue already has a message for the writer to wake up on.
+    let _ = WRITER.try_send(Message::Reopen);
+}
+
+/// Waits until every line queued so far was written, e.g. before exiting.
+pub fn flush() {
+    let (done, wait) = sync_channel(1);
+    if WRITER.send(Message::Flush(done)).is_ok() {
+        let _ = wait.recv();
+    }
+}
+
+// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG` at severity info.
+fn syslog_message(target: &SyslogTarget, line: &str) -> String {
+    const SEVERITY_INFO: u8 = 6;
+    format!(
+        "<{}>1 {} - {} - - - {line}",
+        u16::from(target.facility) * 8 + u16::from(SEVERITY_INFO),
+        rfc3339(SystemTime::now()),
+        target.tag,
+    )
+}
+
+/// `2026-10-17T12:00:00.123Z`
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Reopens access log files on `SIGUSR1`, after they were rotated, and
+/// flushes queued lines on shutdown.
+#[derive(Default)]
+pub struct AccessLogReopener;
+
+#[async_trait]
+impl BackgroundService for AccessLogReopener {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(usr1) => usr1,
+            Err(err) => {
+                log::error!(
+                    "failed to install SIGUSR1 handler, access logs are not reopened: {err}"
+                );
+                return;
+            }
+        };
+
+        loop {
+            if *shutdown.borrow() {
+                break;
+            }
+
+            tokio::select! {
+                changed = shutdown.changed() => {
+                    if changed.is_err() || *shutdown.borrow() {
+                        break;
+                    }
+                }
+                Some(()) = usr1.recv() => {
+                    log::info!("reopening access log files (SIGUSR1)");
+                    reopen();
+                }
+            }
+        }
+        // Lines of the requests that drained during shutdown.
+        let _ = tokio::task::spawn_blocking(flush).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ngxora_compile::ir::DEFAULT_LOG_FORMAT;
+
+    fn record() -> AccessLogRecord<'static> {
+        AccessLogRecord {
+            method: "GET",
+            path: "/api/users",
+            scheme: "https",
+            status: 200,
+            latency: Some(Duration::from_millis(42)),
+            upstream: Some("10.0.0.5:8080"),
+            cache_status: Some("miss"),
+            client_ip: Some("192.168.1.1:54321".into()),
+            route_id: Some(1),
+            ..AccessLogRecord::default()
+        }
+    }
+
+    #[test]
+    fn default_format_matches_the_original_json_line() {
+        assert_eq!(
+            render(&LogFormat::builtin_default(), &record()),
+            r#"{"method":"GET","path":"/api/users","status":200,"latency_secs":0.042,"upstream":"10.0.0.5:8080","cache_status":"miss","client_ip":"192.168.1.1:54321","route_id":1}"#
+        );
+    }
+
+    #[test]
+    fn text_format_quotes_spaces_and_dashes_missing_values() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("user-agent", "curl/8 (linux)".parse().unwrap());
+        let record = AccessLogRecord {
+            request_headers: Some(&headers),
+            ..record()
+        };
+        let format = LogFormat {
+            name: "edge".into(),
+            encoding: LogEncoding::Text,
+            fields: vec![
+                LogField::Method,
+                LogField::Status,
+                LogField::TlsVersion,
+                LogField::RequestHeader("user-agent".into()),
+            ],
+        };
+
+        assert_eq!(render(&format, &record), r#"GET 200 - "curl/8 (linux)""#);
+    }
+
+    #[test]
+    fn targets_filter_by_status_and_sample() {
+        let mut target = AccessLogTarget {
+            sink: AccessLogSink::Stdout,
+            format: Some(DEFAULT_LOG_FORMAT.into()),
+            statuses: vec![400..=499, 502..=502],
+            sample: None,
+        };
+        assert!(accepts(&target, 404));
+        assert!(accepts(&target, 502));
+        assert!(!accepts(&target, 200));
+        assert!(!accepts(&target, 503));
+
+        target.statuses.clear();
+        target.sample = Some(ACCESS_LOG_SAMPLE_SCALE);
+        assert!(accepts(&target, 200));
+        target.sample = Some(1);
+        let logged = (0..10_000).filter(|_| accepts(&target, 200)).count();
+        assert!(logged < 100, "{logged} of 10000 logged at 0.0001");
+    }
+
+    #[test]
+    fn effective_logs_fall_back_to_http_then_stdout() {
+        let off = [AccessLog::Off];
+        assert_eq!(effective(&off, &[]), &off);
+        assert_eq!(effective(&[], &off), &off);
+        assert_eq!(effective(&[], &[]), &DEFAULT_ACCESS_LOGS);
+    }
+
+    #[test]
+    fn file_sinks_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = dir.path().join("access.log.1");
+        let logs = [AccessLog::To(AccessLogTarget {
+            sink: AccessLogSink::File(path.clone()),
+            format: None,
+            statuses: Vec::new(),
+            sample: None,
+        })];
+
+        write(&logs, &[], &record());
+        flush();
+        std::fs::rename(&path, &rotated).unwrap();
+        write(&logs, &[], &record());
+        flush();
+        reopen();
+        write(&logs, &[], &record());
+        flush();
+
+        assert_eq!(
+            std::fs::read_to_string(&rotated).unwrap().lines().count(),
+            2
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn syslog_lines_use_rfc5424() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sink: AccessLogSink = format!(
+            "syslog:server={},facility=local0,tag=edge",
+            receiver.local_addr().unwrap()
+        )
+        .parse()
+        .unwrap();
+        let logs = [AccessLog::To(AccessLogTarget {
+            sink,
+            format: None,
+            statuses: Vec::new(),
+            sample: None,
+        })];
+
+        write(&logs, &[], &record());
+
+        let mut buf = [0; 1024];
+        let len = receiver.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("<134>1 "), "{message}");
+        assert!(
+            message.contains(" - edge - - - {\"method\":\"GET\""),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn rfc3339_formats_utc_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_792_195_200_123);
+        assert_eq!(rfc3339(time), "2026-10-17T00:00:00.123Z");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+}
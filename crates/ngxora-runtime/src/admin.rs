@@ -1,26 +1,56 @@
 //! Admin HTTP application.
 //!
 //! Serves `/metrics` (Prometheus exposition), `/healthz` (liveness), and
-//! `/readyz` (configuration and TLS readiness) on one management listener.
+//! `/readyz` (configuration and TLS readiness) on one management listener,
+//! plus read-only JSON views of the active runtime under `/admin/`:
+//!
+//! - `/admin/snapshot`: active snapshot version and generation
+//! - `/admin/routes`: listeners, virtual hosts and their compiled routes
+//! - `/admin/plugins`: the built plugin chain per `route_id`
+//! - `/admin/upstreams`: per-backend health of every upstream group
+//! - `/admin/cache`: response cache entries and bytes per `route_id`
+//! - `/admin/config`: the active snapshot as an equivalent text config, with
+//!   credentials redacted
+//!
+//! `/admin/config` and the only write endpoint, `POST /admin/cache/purge`, are
+//! refused with `403` unless the app was built with [`AdminHttpApp::with_writes`].
 
-use crate::control::RuntimeState;
+use crate::cache::{CacheBackend, CachePurge, CacheRouteStats, router_locations};
+use crate::control::{RuntimeSnapshot, RuntimeState};
+use crate::grpc::render_snapshot_config;
 use crate::server::router_ready;
-use crate::upstreams::CompiledRouter;
+use crate::upstreams::{
+    CompiledLocation, CompiledMatcher, CompiledRouter, RouteTarget, ServerRoutes, StaticFallback,
+    StaticRoot,
+};
 use async_trait::async_trait;
 use http::{Method, Response, StatusCode};
+use ngxora_compile::ir::{LocationIpRule, UpstreamSelectionPolicy};
 use pingora::apps::http_app::{HttpServer, ServeHttp};
 use pingora::apps::prometheus_http_app::PrometheusHttpApp;
 use pingora::protocols::http::ServerSession;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 const HEALTHZ_PATH: &str = "/healthz";
 const READYZ_PATH: &str = "/readyz";
 const METRICS_PATH: &str = "/metrics";
+const SNAPSHOT_PATH: &str = "/admin/snapshot";
+const ROUTES_PATH: &str = "/admin/routes";
+const PLUGINS_PATH: &str = "/admin/plugins";
+const UPSTREAMS_PATH: &str = "/admin/upstreams";
+const CACHE_PATH: &str = "/admin/cache";
+const CONFIG_PATH: &str = "/admin/config";
+const CACHE_PURGE_PATH: &str = "/admin/cache/purge";
 
-/// Admin HTTP app multiplexing Prometheus metrics and a liveness endpoint.
+/// Admin HTTP app multiplexing Prometheus metrics, health probes and the
+/// read-only runtime inspection API.
 pub struct AdminHttpApp {
     metrics: PrometheusHttpApp,
     state: Arc<RuntimeState>,
+    cache: Option<Arc<CacheBackend>>,
+    writes: bool,
 }
 
 impl AdminHttpApp {
@@ -32,8 +62,23 @@ impl AdminHttpApp {
         Self {
             metrics: PrometheusHttpApp,
             state,
+            cache: None,
+            writes: false,
         }
     }
+
+    /// Report occupancy of the proxy's response cache on `/admin/cache`.
+    pub fn with_cache(mut self, cache: Arc<CacheBackend>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Allow state-changing requests such as `POST /admin/cache/purge`, and
+    /// `/admin/config`, which still names users, key ids and backends.
+    pub fn with_writes(mut self, writes: bool) -> Self {
+        self.writes = writes;
+        self
+    }
 }
 
 impl Default for AdminHttpApp {
@@ -47,19 +92,39 @@ enum AdminRoute {
     Healthz,
     Readyz,
     Metrics,
-    MethodNotAllowed,
+    Snapshot,
+    Routes,
+    Plugins,
+    Upstreams,
+    Cache,
+    Config,
+    CachePurge,
+    /// Carries the `Allow` header value for the path.
+    MethodNotAllowed(&'static str),
     NotFound,
 }
 
 fn route_admin(method: &Method, path: &str) -> AdminRoute {
+    if path == CACHE_PURGE_PATH {
+        return match *method {
+            Method::POST => AdminRoute::CachePurge,
+            _ => AdminRoute::MethodNotAllowed("POST"),
+        };
+    }
     if method != Method::GET && method != Method::HEAD {
-        return AdminRoute::MethodNotAllowed;
+        return AdminRoute::MethodNotAllowed("GET, HEAD");
     }
 
     match path {
         HEALTHZ_PATH => AdminRoute::Healthz,
         READYZ_PATH => AdminRoute::Readyz,
         METRICS_PATH => AdminRoute::Metrics,
+        SNAPSHOT_PATH => AdminRoute::Snapshot,
+        ROUTES_PATH => AdminRoute::Routes,
+        PLUGINS_PATH => AdminRoute::Plugins,
+        UPSTREAMS_PATH => AdminRoute::Upstreams,
+        CACHE_PATH => AdminRoute::Cache,
+        CONFIG_PATH => AdminRoute::Config,
         _ => AdminRoute::NotFound,
     }
 }
@@ -69,13 +134,7 @@ impl ServeHttp for AdminHttpApp {
     async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
         let method = http_session.req_header().method.clone();
         let path = http_session.req_header().uri.path();
-        let mut response = respond_for_route(
-            route_admin(&method, path),
-            &self.metrics,
-            &self.state,
-            http_session,
-        )
-        .await;
+        let mut response = respond_for_route(route_admin(&method, path), self, http_session).await;
         if method == Method::HEAD {
             response.body_mut().clear();
         }
@@ -85,10 +144,10 @@ impl ServeHttp for AdminHttpApp {
 
 async fn respond_for_route(
     route: AdminRoute,
-    metrics: &PrometheusHttpApp,
-    state: &RuntimeState,
+    app: &AdminHttpApp,
     http_session: &mut ServerSession,
 ) -> Response<Vec<u8>> {
+    let state = &app.state;
     match route {
         AdminRoute::Healthz => Response::builder()
             .status(StatusCode::OK)
@@ -113,10 +172,54 @@ async fn respond_for_route(
                     .unwrap()
             }
         },
-        AdminRoute::Metrics => metrics.response(http_session).await,
-        AdminRoute::MethodNotAllowed => Response::builder()
+        AdminRoute::Metrics => app.metrics.response(http_session).await,
+        AdminRoute::Snapshot => json_response(&snapshot_view(&state.snapshot())),
+        AdminRoute::Routes => json_response(&routes_view(&state.snapshot())),
+        AdminRoute::Plugins => json_response(&plugins_view(&state.snapshot())),
+        AdminRoute::Upstreams => json_response(&upstreams_view(&state.snapshot())),
+        AdminRoute::Cache => {
+            let snapshot = state.snapshot();
+            let stats = match &app.cache {
+                Some(cache) => cache.route_stats().await,
+                None => Vec::new(),
+            };
+            json_response(&cache_view(&snapshot, stats))
+        }
+        AdminRoute::Config => {
+            if !app.writes {
+                return text_response(
+                    StatusCode::FORBIDDEN,
+                    "admin config is disabled; start with --admin-write",
+                );
+            }
+            admin_config_response(&state.snapshot())
+        }
+        AdminRoute::CachePurge => {
+            if !app.writes {
+                return text_response(
+                    StatusCode::FORBIDDEN,
+                    "admin writes are disabled; start with --admin-write",
+                );
+            }
+            let Some(cache) = &app.cache else {
+                return text_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "response cache is not attached",
+                );
+            };
+            let snapshot = state.snapshot();
+            match parse_cache_purge(&snapshot.router, http_session.req_header().uri.query()) {
+                Ok(purge) => {
+                    let purged = cache.purge(&purge).await;
+                    log::info!("admin API purged {purged} cached responses ({purge:?})");
+                    json_response(&PurgeView { purged })
+                }
+                Err(err) => text_response(StatusCode::BAD_REQUEST, &err),
+            }
+        }
+        AdminRoute::MethodNotAllowed(allow) => Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
-            .header(http::header::ALLOW, "GET, HEAD")
+            .header(http::header::ALLOW, allow)
             .header(http::header::CONTENT_TYPE, "text/plain")
             .body(b"Method Not Allowed".to_vec())
             .unwrap(),
@@ -128,6 +231,516 @@ async fn respond_for_route(
     }
 }
 
+fn admin_config_response(snapshot: &RuntimeSnapshot) -> Response<Vec<u8>> {
+    match render_snapshot_config(snapshot, true) {
+        Ok(config) => text_response(StatusCode::OK, &config),
+        Err(err) => text_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &format!("cannot render the active snapshot as config: {err}"),
+        ),
+    }
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body.as_bytes().to_vec())
+        .unwrap()
+}
+
+/// Parse the purge target from the query string: `host` and `uri` for one
+/// URI, `prefix` with an optional `host`, `route_id`, or `all=true`.
+fn parse_cache_purge(router: &CompiledRouter, query: Option<&str>) -> Result<CachePurge, String> {
+    let mut host = None;
+    let mut uri = None;
+    let mut prefix = None;
+    let mut route_id = None;
+    let mut all = false;
+    for (name, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match name.as_ref() {
+            "host" => host = Some(value.into_owned()),
+            "uri" => uri = Some(value.into_owned()),
+            "prefix" => prefix = Some(value.into_owned()),
+            "route_id" => {
+                let id = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid route_id `{value}`"))?;
+                route_id = Some(id);
+            }
+            "all" => {
+                all = match value.as_ref() {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return Err(format!("invalid all `{value}`")),
+                }
+            }
+            other => return Err(format!("unknown purge parameter `{other}`")),
+        }
+    }
+
+    let targets = [uri.is_some(), prefix.is_some(), route_id.is_some(), all];
+    if targets.iter().filter(|set| **set).count() != 1 {
+        return Err("expected exactly one of uri, prefix, route_id or all=true".into());
+    }
+    if host.is_some() && (route_id.is_some() || all) {
+        return Err("host only applies to uri and prefix purges".into());
+    }
+
+    if let Some(uri) = uri {
+        let host = host.ok_or_else(|| "uri purge requires host".to_string())?;
+        if !uri.starts_with('/') {
+            return Err("uri must start with `/`".into());
+        }
+        return Ok(CachePurge::Exact { host, uri });
+    }
+    if let Some(prefix) = prefix {
+        if !prefix.starts_with('/') {
+            return Err("prefix must start with `/`".into());
+        }
+        return Ok(CachePurge::Prefix { host, prefix });
+    }
+    match route_id {
+        Some(route_id) => CachePurge::route(router, route_id)
+            .ok_or_else(|| format!("unknown route_id {route_id}")),
+        None => Ok(CachePurge::All),
+    }
+}
+
+fn json_response<T: Serialize>(body: &T) -> Response<Vec<u8>> {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(http::header::CONTENT_LENGTH, body.len())
+            .header(http::header::CACHE_CONTROL, "no-store")
+            .body(body)
+            .unwrap(),
+        Err(err) => {
+            log::error!("failed to serialize admin response: {err}");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header(http::header::CONTENT_TYPE, "text/plain")
+                .body(b"Internal Server Error".to_vec())
+                .unwrap()
+        }
+    }
+}
+
+// ---- JSON views ----
+//
+// The views borrow from the active snapshot and are built per request, so the
+// admin API always reports the generation that is serving traffic.
+
+#[derive(Debug, Serialize)]
+struct PurgeView {
+    purged: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotView<'a> {
+    version: &'a str,
+    generation: u64,
+    listeners: usize,
+    routes: usize,
+    upstreams: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RoutesView<'a> {
+    version: &'a str,
+    generation: u64,
+    listeners: Vec<ListenerView<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListenerView<'a> {
+    address: String,
+    ssl: bool,
+    http2: bool,
+    http2_only: bool,
+    proxy_protocol: bool,
+    virtual_hosts: Vec<VirtualHostView<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct VirtualHostView<'a> {
+    /// `None` for the listener's default server.
+    server_name: Option<&'a str>,
+    default_server: bool,
+    routes: Vec<RouteView<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct RouteView<'a> {
+    route_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(rename = "match")]
+    matcher: MatcherView<'a>,
+    target: TargetView<'a>,
+    access_rules: Vec<String>,
+    plugins: Vec<&'a str>,
+    cache: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum MatcherView<'a> {
+    Prefix {
+        path: &'a str,
+    },
+    Exact {
+        path: &'a str,
+    },
+    PreferPrefix {
+        path: &'a str,
+    },
+    Regex {
+        pattern: &'a str,
+        case_insensitive: bool,
+    },
+    Named {
+        name: &'a str,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum TargetView<'a> {
+    ProxyPass {
+        host: &'a str,
+        port: u16,
+        tls: bool,
+    },
+    Upstream {
+        name: &'a str,
+        tls: bool,
+    },
+    Return {
+        status: u16,
+        location: &'a str,
+    },
+    Static {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        root: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        alias: Option<String>,
+        index: &'a [String],
+        try_files: Vec<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fallback: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct PluginsView {
+    generation: u64,
+    routes: Vec<RoutePluginsView>,
+}
+
+#[derive(Debug, Serialize)]
+struct RoutePluginsView {
+    route_id: u64,
+    plugins: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpstreamsView<'a> {
+    generation: u64,
+    upstreams: Vec<UpstreamView<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpstreamView<'a> {
+    name: &'a str,
+    policy: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash_key: Option<&'a str>,
+    health_check: bool,
+    outlier_detection: bool,
+    backends: Vec<BackendView>,
+}
+
+#[derive(Debug, Serialize)]
+struct BackendView {
+    address: String,
+    /// `resolve` server name the address was looked up from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_from: Option<String>,
+    weight: u32,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    backup: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    down: bool,
+    healthy: bool,
+    ejected: bool,
+    /// Still ramping up under `slow_start`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    ramping: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheView {
+    generation: u64,
+    entries: usize,
+    bytes: u64,
+    routes: Vec<CacheRouteView>,
+}
+
+#[derive(Debug, Serialize)]
+struct CacheRouteView {
+    route: String,
+    /// Route id of the location in the active snapshot; `None` for entries
+    /// of a location it no longer has.
+    route_id: Option<u64>,
+    entries: usize,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+fn server_routes(router: &CompiledRouter) -> impl Iterator<Item = &ServerRoutes> {
+    router
+        .listeners
+        .values()
+        .flat_map(|vhosts| vhosts.named.values().chain(vhosts.default.as_ref()))
+}
+
+// A server block with several names (and the listener default) shares one set
+// of locations, so route ids are de-duplicated.
+fn route_ids(router: &CompiledRouter) -> Vec<u64> {
+    let mut ids: Vec<u64> = server_routes(router)
+        .flat_map(|routes| routes.locations.iter().map(|location| location.route_id))
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn snapshot_view(snapshot: &RuntimeSnapshot) -> SnapshotView<'_> {
+    SnapshotView {
+        version: &snapshot.version,
+        generation: snapshot.generation,
+        listeners: snapshot.router.listeners.len(),
+        routes: route_ids(&snapshot.router).len(),
+        upstreams: snapshot.router.upstreams.len(),
+    }
+}
+
+fn routes_view(snapshot: &RuntimeSnapshot) -> RoutesView<'_> {
+    let router = &snapshot.router;
+    let mut keys: Vec<_> = router.listeners.keys().collect();
+    keys.sort();
+
+    let listeners = keys
+        .into_iter()
+        .map(|key| {
+            let vhosts = &router.listeners[key];
+            let protocol = router
+                .listener_protocols
+                .get(key)
+                .cloned()
+                .unwrap_or_default();
+
+            let mut names: Vec<_> = vhosts.named.iter().collect();
+            names.sort_by_key(|(name, _)| name.as_str());
+            let mut virtual_hosts: Vec<_> = names
+                .into_iter()
+                .map(|(name, routes)| VirtualHostView {
+                    server_name: Some(name),
+                    default_server: false,
+                    routes: route_views(snapshot, routes),
+                })
+                .collect();
+            if let Some(routes) = &vhosts.default {
+                virtual_hosts.push(VirtualHostView {
+                    server_name: None,
+                    default_server: true,
+                    routes: route_views(snapshot, routes),
+                });
+            }
+
+            ListenerView {
+                address: std::net::SocketAddr::new(key.addr, key.port).to_string(),
+                ssl: key.ssl,
+                http2: protocol.http2,
+                http2_only: protocol.http2_only,
+                proxy_protocol: protocol.proxy_protocol,
+                virtual_hosts,
+            }
+        })
+        .collect();
+
+    RoutesView {
+        version: &snapshot.version,
+        generation: snapshot.generation,
+        listeners,
+    }
+}
+
+fn route_views<'a>(snapshot: &RuntimeSnapshot, routes: &'a ServerRoutes) -> Vec<RouteView<'a>> {
+    routes
+        .locations
+        .iter()
+        .map(|location| route_view(snapshot, location))
+        .collect()
+}
+
+fn route_view<'a>(snapshot: &RuntimeSnapshot, location: &'a CompiledLocation) -> RouteView<'a> {
+    RouteView {
+        route_id: location.route_id,
+        name: location.name.as_deref(),
+        matcher: matcher_view(&location.matcher),
+        target: target_view(&location.target),
+        access_rules: location.access_rules.iter().map(access_rule).collect(),
+        plugins: plugin_names(snapshot, location.route_id),
+        cache: location.cache.as_ref().is_some_and(|cache| cache.enabled),
+    }
+}
+
+fn matcher_view(matcher: &CompiledMatcher) -> MatcherView<'_> {
+    match matcher {
+        CompiledMatcher::Prefix(path) => MatcherView::Prefix { path },
+        CompiledMatcher::Exact(path) => MatcherView::Exact { path },
+        CompiledMatcher::PreferPrefix(path) => MatcherView::PreferPrefix { path },
+        CompiledMatcher::Regex(regex) => MatcherView::Regex {
+            pattern: &regex.pattern,
+            case_insensitive: regex.case_insensitive,
+        },
+        CompiledMatcher::Named(name) => MatcherView::Named { name },
+    }
+}
+
+fn target_view(target: &RouteTarget) -> TargetView<'_> {
+    match target {
+        RouteTarget::ProxyPass {
+            host, port, tls, ..
+        } => TargetView::ProxyPass {
+            host,
+            port: *port,
+            tls: *tls,
+        },
+        RouteTarget::UpstreamGroup { name, tls } => TargetView::Upstream { name, tls: *tls },
+        RouteTarget::Return { status, location } => TargetView::Return {
+            status: *status,
+            location: location.source(),
+        },
+        RouteTarget::Static(files) => {
+            let (root, alias) = match &files.root {
+                StaticRoot::Root(path) => (Some(path.display().to_string()), None),
+                StaticRoot::Alias { path, .. } => (None, Some(path.display().to_string())),
+            };
+            let try_files = files.try_files.as_ref();
+            TargetView::Static {
+                root,
+                alias,
+                index: &files.index,
+                try_files: try_files
+                    .map(|try_files| try_files.files.iter().map(|file| file.source()).collect())
+                    .unwrap_or_default(),
+                fallback: try_files.map(|try_files| match &try_files.fallback {
+                    StaticFallback::Status(status) => format!("={status}"),
+                    StaticFallback::Named(name) => format!("@{name}"),
+                    StaticFallback::Uri(uri) => uri.source().to_string(),
+                }),
+            }
+        }
+    }
+}
+
+fn access_rule(rule: &LocationIpRule) -> String {
+    match rule {
+        LocationIpRule::Allow(network) => format!("allow {network}"),
+        LocationIpRule::Deny(network) => format!("deny {network}"),
+        LocationIpRule::AllowAll => "allow all".into(),
+        LocationIpRule::DenyAll => "deny all".into(),
+    }
+}
+
+fn plugin_names(snapshot: &RuntimeSnapshot, route_id: u64) -> Vec<&'static str> {
+    snapshot
+        .plugin_chain(route_id)
+        .iter()
+        .map(|plugin| plugin.name())
+        .collect()
+}
+
+fn plugins_view(snapshot: &RuntimeSnapshot) -> PluginsView {
+    PluginsView {
+        generation: snapshot.generation,
+        routes: route_ids(&snapshot.router)
+            .into_iter()
+            .map(|route_id| RoutePluginsView {
+                route_id,
+                plugins: plugin_names(snapshot, route_id),
+            })
+            .collect(),
+    }
+}
+
+fn upstreams_view(snapshot: &RuntimeSnapshot) -> UpstreamsView<'_> {
+    let mut groups: Vec<_> = snapshot.router.upstreams.values().collect();
+    groups.sort_by_key(|group| group.name.as_str());
+
+    UpstreamsView {
+        generation: snapshot.generation,
+        upstreams: groups
+            .into_iter()
+            .map(|group| {
+                let runtime = snapshot.upstream_group(&group.name);
+                UpstreamView {
+                    name: &group.name,
+                    policy: group.policy.name(),
+                    hash_key: match &group.policy {
+                        UpstreamSelectionPolicy::Hash { key, .. } => Some(key.source()),
+                        _ => None,
+                    },
+                    health_check: runtime.is_some_and(|runtime| runtime.has_health_check()),
+                    outlier_detection: runtime
+                        .is_some_and(|runtime| runtime.has_outlier_detection()),
+                    backends: runtime
+                        .map(|runtime| runtime.backend_health())
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|status| BackendView {
+                            address: status.server.to_string(),
+                            resolved_from: status.resolved_from,
+                            weight: status.server.weight,
+                            backup: status.server.backup,
+                            down: status.server.down,
+                            healthy: status.healthy,
+                            ejected: status.ejected,
+                            ramping: status.ramping,
+                        })
+                        .collect(),
+                }
+            })
+            .collect(),
+    }
+}
+
+fn cache_view(snapshot: &RuntimeSnapshot, stats: Vec<CacheRouteStats>) -> CacheView {
+    let route_ids: HashMap<String, u64> = router_locations(&snapshot.router)
+        .map(|(route_id, route, _)| (route, route_id))
+        .collect();
+    CacheView {
+        generation: snapshot.generation,
+        entries: stats.iter().map(|stats| stats.entries).sum(),
+        bytes: stats.iter().map(|stats| stats.bytes).sum(),
+        routes: stats
+            .into_iter()
+            .map(|stats| CacheRouteView {
+                route_id: route_ids.get(&stats.route).copied(),
+                route: stats.route,
+                entries: stats.entries,
+                bytes: stats.bytes,
+                max_bytes: stats.max_bytes,
+            })
+            .collect(),
+    }
+}
+
 /// The [HttpServer] for [AdminHttpApp].
 pub type AdminServer = HttpServer<AdminHttpApp>;
 
@@ -137,15 +750,122 @@ pub fn admin_server() -> AdminServer {
 }
 
 pub fn admin_server_with_state(state: Arc<RuntimeState>) -> AdminServer {
-    let mut server = AdminServer::new_app(AdminHttpApp::with_state(state));
+    admin_server_with_app(AdminHttpApp::with_state(state))
+}
+
+/// Build an [AdminServer] whose `/admin/cache` view reports `cache`; `writes`
+/// enables `POST /admin/cache/purge`.
+pub fn admin_server_with_cache(
+    state: Arc<RuntimeState>,
+    cache: Arc<CacheBackend>,
+    writes: bool,
+) -> AdminServer {
+    admin_server_with_app(
+        AdminHttpApp::with_state(state)
+            .with_cache(cache)
+            .with_writes(writes),
+    )
+}
+
+fn admin_server_with_app(app: AdminHttpApp) -> AdminServer {
+    let mut server = AdminServer::new_app(app);
     server.add_module(pingora::modules::http::compression::ResponseCompressionBuilder::enable(7));
     server
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{AdminRoute, route_admin};
-    use http::Method;
+    use super::{
+        AdminRoute, admin_config_response, cache_view, parse_cache_purge, plugins_view,
+        route_admin, routes_view, snapshot_view, upstreams_view,
+    };
+    use crate::cache::CachePurge;
+    use crate::cache::{CacheBackend, CacheKey, CachedResponse};
+    use crate::control::{ConfigSnapshot, RuntimeState};
+    use crate::upstreams::CompiledRouter;
+    use http::{HeaderMap, Method, StatusCode};
+    use ngxora_compile::ir::{
+        CacheConfig, Http, Listen, Location, LocationDirective, LocationIpRule, LocationMatcher,
+        ProxyPassTarget, Server, UpstreamBlock, UpstreamKeepalive, UpstreamSelectionPolicy,
+        UpstreamServer,
+    };
+    use serde_json::{Value, json};
+    use std::time::Instant;
+
+    fn state() -> RuntimeState {
+        let http = Http {
+            upstreams: vec![UpstreamBlock {
+                name: "backend".into(),
+                policy: UpstreamSelectionPolicy::RoundRobin,
+                servers: vec![
+                    UpstreamServer {
+                        host: "127.0.0.1".into(),
+                        port: 8080,
+                        weight: 1,
+                        resolve: false,
+                        max_conns: None,
+                        backup: false,
+                        down: false,
+                        slow_start: None,
+                    },
+                    UpstreamServer {
+                        host: "127.0.0.1".into(),
+                        port: 8081,
+                        weight: 3,
+                        resolve: false,
+                        max_conns: None,
+                        backup: false,
+                        down: false,
+                        slow_start: None,
+                    },
+                ],
+                health_check: None,
+                outlier_detection: None,
+                keepalive: UpstreamKeepalive::default(),
+                queue: None,
+            }],
+            servers: vec![Server {
+                server_names: vec!["example.com".into()],
+                listens: vec![Listen {
+                    port: 8080,
+                    default_server: true,
+                    ..Listen::default()
+                }],
+                locations: vec![
+                    Location {
+                        matcher: LocationMatcher::Prefix("/api/".into()),
+                        directives: vec![LocationDirective::ProxyPass(ProxyPassTarget::Url(
+                            "http://backend".parse().unwrap(),
+                        ))],
+                        access_rules: vec![
+                            LocationIpRule::Allow("10.0.0.0/8".parse().unwrap()),
+                            LocationIpRule::DenyAll,
+                        ],
+                        plugins: Vec::new(),
+                        cache: Some(CacheConfig::default()),
+                    },
+                    Location {
+                        matcher: LocationMatcher::Exact("/old".into()),
+                        directives: vec![LocationDirective::Return {
+                            status: 301,
+                            location: "https://$host/new".into(),
+                        }],
+                        access_rules: Vec::new(),
+                        plugins: Vec::new(),
+                        cache: None,
+                    },
+                ],
+                ..Server::default()
+            }],
+            ..Http::default()
+        };
+        let router = CompiledRouter::from_http(&http).expect("router compiles");
+        RuntimeState::new(ConfigSnapshot::new("v7", router))
+    }
+
+    fn to_json<T: serde::Serialize>(value: &T) -> Value {
+        serde_json::to_value(value).expect("view serializes")
+    }
 
     #[test]
     fn route_healthz() {
@@ -183,11 +903,292 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn route_admin_api_paths() {
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/snapshot"),
+            AdminRoute::Snapshot
+        ));
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/routes"),
+            AdminRoute::Routes
+        ));
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/plugins"),
+            AdminRoute::Plugins
+        ));
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/upstreams"),
+            AdminRoute::Upstreams
+        ));
+        assert!(matches!(
+            route_admin(&Method::HEAD, "/admin/cache"),
+            AdminRoute::Cache
+        ));
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/config"),
+            AdminRoute::Config
+        ));
+        assert!(matches!(
+            route_admin(&Method::DELETE, "/admin/cache"),
+            AdminRoute::MethodNotAllowed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_serves_ngxora_collectors() {
+        use super::AdminHttpApp;
+        use pingora::apps::http_app::ServeHttp;
+        use pingora::protocols::http::ServerSession;
+        use tokio::io::AsyncWriteExt;
+
+        // Pingora gathers the default registry of the `prometheus` version it
+        // links; ours must be the same crate for this to list anything.
+        crate::metrics::record_upstream_max_conns_rejection("admin-metrics-test");
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut session = ServerSession::new_http1(Box::new(server));
+        session.read_request().await.unwrap();
+
+        let response = AdminHttpApp::new().response(&mut session).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.into_body()).unwrap();
+        assert!(
+            body.contains(
+                "ngxora_upstream_max_conns_rejections_total{upstream=\"admin-metrics-test\"} 1"
+            ),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn admin_config_redacts_acme_credentials() {
+        let ast = ngxora_config::Ast::parse_config(
+            r#"
+http {
+  ssl_provider letsencrypt {
+    challenge dns-01;
+    dns_provider rfc2136 10.0.0.53:5353;
+    dns_zone example.com;
+    dns_tsig_key acme-update hmac-sha256 dHNpZ3NlY3JldA==;
+  }
+  ssl_provider acme zerossl {
+    acme_directory https://acme.zerossl.com/v2/DV90;
+    external_account_binding kid-1 ZWFiaG1hY2tleQ;
+  }
+  server {
+    listen 443 ssl;
+    server_name example.com;
+    acme_certificate zerossl letsencrypt;
+    location / { proxy_pass http://127.0.0.1:8080; }
+  }
+}
+"#,
+        )
+        .unwrap();
+        let ir = ngxora_compile::ir::Ir::from_ast(&ast).unwrap();
+        let router = CompiledRouter::from_http(&ir.http.unwrap()).unwrap();
+        let state = RuntimeState::new(ConfigSnapshot::new("v1", router));
+
+        let response = admin_config_response(&state.snapshot());
+        assert_eq!(response.status(), StatusCode::OK);
+        let config = String::from_utf8(response.into_body()).unwrap();
+        assert!(config.contains("external_account_binding kid-1 <redacted>;"));
+        assert!(config.contains("dns_tsig_key acme-update hmac-sha256 <redacted>;"));
+        assert!(!config.contains("ZWFiaG1hY2tleQ"));
+        assert!(!config.contains("dHNpZ3NlY3JldA=="));
+    }
+
+    #[test]
+    fn snapshot_view_reports_version_and_generation() {
+        let state = state();
+        let view = to_json(&snapshot_view(&state.snapshot()));
+
+        assert_eq!(
+            view,
+            json!({
+                "version": "v7",
+                "generation": 1,
+                "listeners": 1,
+                "routes": 2,
+                "upstreams": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn routes_view_lists_listeners_virtual_hosts_and_routes() {
+        let state = state();
+        let view = to_json(&routes_view(&state.snapshot()));
+
+        let listener = &view["listeners"][0];
+        assert_eq!(listener["address"], "127.0.0.1:8080");
+        assert_eq!(listener["ssl"], false);
+        assert_eq!(listener["proxy_protocol"], false);
+
+        let vhosts = listener["virtual_hosts"].as_array().unwrap();
+        assert_eq!(vhosts.len(), 2);
+        assert_eq!(vhosts[0]["server_name"], "example.com");
+        assert_eq!(vhosts[1]["server_name"], Value::Null);
+        assert_eq!(vhosts[1]["default_server"], true);
+
+        let routes = vhosts[0]["routes"].as_array().unwrap();
+        assert_eq!(
+            routes[0]["match"],
+            json!({"kind": "prefix", "path": "/api/"})
+        );
+        assert_eq!(
+            routes[0]["target"],
+            json!({"kind": "upstream", "name": "backend", "tls": false})
+        );
+        assert_eq!(
+            routes[0]["access_rules"],
+            json!(["allow 10.0.0.0/8", "deny all"])
+        );
+        assert_eq!(routes[0]["cache"], true);
+        assert_eq!(routes[1]["match"], json!({"kind": "exact", "path": "/old"}));
+        assert_eq!(
+            routes[1]["target"],
+            json!({"kind": "return", "status": 301, "location": "https://$host/new"})
+        );
+    }
+
+    #[test]
+    fn plugins_view_lists_each_route_once() {
+        let state = state();
+        let view = to_json(&plugins_view(&state.snapshot()));
+
+        let routes = view["routes"].as_array().unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0]["plugins"], json!([]));
+    }
+
+    #[test]
+    fn upstreams_view_reports_backend_health() {
+        let state = state();
+        let view = to_json(&upstreams_view(&state.snapshot()));
+
+        assert_eq!(
+            view["upstreams"],
+            json!([{
+                "name": "backend",
+                "policy": "round_robin",
+                "health_check": false,
+                "outlier_detection": false,
+                "backends": [
+                    {"address": "127.0.0.1:8080", "weight": 1, "healthy": true, "ejected": false},
+                    {"address": "127.0.0.1:8081", "weight": 3, "healthy": true, "ejected": false},
+                ],
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_view_sums_route_stats() {
+        let state = state();
+        let snapshot = state.snapshot();
+        let route_id = snapshot.router.listeners.values().next().unwrap().named["example.com"]
+            .locations[0]
+            .route_id;
+        let cache = CacheBackend::new(1024 * 1024);
+        for uri in ["/api/a", "/api/b"] {
+            cache
+                .put(
+                    CacheKey {
+                        generation: snapshot.generation,
+                        route_id,
+                        route: "example.com /api/".into(),
+                        host: "example.com".into(),
+                        method: "GET".into(),
+                        uri: uri.into(),
+                    },
+                    CachedResponse {
+                        status: StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: bytes::Bytes::from_static(b"hello"),
+                        created_at: Instant::now(),
+                    },
+                    &CacheConfig::default(),
+                )
+                .await;
+        }
+
+        let view = to_json(&cache_view(&snapshot, cache.route_stats().await));
+
+        assert_eq!(view["entries"], 2);
+        assert_eq!(view["bytes"], 2 * (5 + 128));
+        assert_eq!(view["routes"][0]["route"], "example.com /api/");
+        assert_eq!(view["routes"][0]["route_id"], route_id);
+        assert_eq!(view["routes"][0]["max_bytes"], 1024 * 1024);
+    }
+
     #[test]
     fn route_rejects_non_read_methods() {
         assert!(matches!(
             route_admin(&Method::POST, "/metrics"),
-            AdminRoute::MethodNotAllowed
+            AdminRoute::MethodNotAllowed("GET, HEAD")
+        ));
+    }
+
+    #[test]
+    fn route_cache_purge_requires_post() {
+        assert!(matches!(
+            route_admin(&Method::POST, "/admin/cache/purge"),
+            AdminRoute::CachePurge
+        ));
+        assert!(matches!(
+            route_admin(&Method::GET, "/admin/cache/purge"),
+            AdminRoute::MethodNotAllowed("POST")
         ));
     }
+
+    #[test]
+    fn parse_cache_purge_targets() {
+        let state = state();
+        let router = &state.snapshot().router;
+        let route_id =
+            router.listeners.values().next().unwrap().named["example.com"].locations[0].route_id;
+
+        assert_eq!(
+            parse_cache_purge(router, Some("host=example.com&uri=%2Fnews%3Fpage%3D2")),
+            Ok(CachePurge::Exact {
+                host: "example.com".into(),
+                uri: "/news?page=2".into(),
+            })
+        );
+
+        assert_eq!(
+            parse_cache_purge(router, Some("prefix=/news/")),
+            Ok(CachePurge::Prefix {
+                host: None,
+                prefix: "/news/".into(),
+            })
+        );
+        assert_eq!(
+            parse_cache_purge(router, Some(&format!("route_id={route_id}"))),
+            Ok(CachePurge::Route("example.com /api/".into()))
+        );
+        assert_eq!(
+            parse_cache_purge(router, Some("all=true")),
+            Ok(CachePurge::All)
+        );
+    }
+
+    #[test]
+    fn parse_cache_purge_rejects_ambiguous_or_incomplete_targets() {
+        let router = &CompiledRouter::default();
+        assert!(parse_cache_purge(router, None).is_err());
+        assert!(parse_cache_purge(router, Some("uri=/news")).is_err());
+        assert!(parse_cache_purge(router, Some("prefix=/a&route_id=1")).is_err());
+        assert!(parse_cache_purge(router, Some("host=example.com&all=true")).is_err());
+        assert!(parse_cache_purge(router, Some("prefix=news")).is_err());
+        assert!(parse_cache_purge(router, Some("route_id=abc")).is_err());
+        assert_eq!(
+            parse_cache_purge(router, Some("route_id=42")),
+            Err("unknown route_id 42".into())
+        );
+    }
 }
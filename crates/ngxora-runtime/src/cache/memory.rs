@@ -0,0 +1,212 @@
+use super::{CacheKey, CacheLimits, CachePurge, CacheRouteStats, CacheStore, CachedResponse};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Per-location cache store, protected by an `RwLock`.
+///
+/// Contention is minimal because each location has its own lock, and
+/// `MemoryCacheStore` uses `DashMap` to shard access across locations.
+struct LocationCache {
+    ttl: Duration,
+    max_size: u64,
+    current_size: u64,
+    entries: HashMap<CacheKey, CachedResponse>,
+}
+
+impl LocationCache {
+    fn new(ttl: Duration, max_size: u64) -> Self {
+        Self {
+            ttl,
+            max_size,
+            current_size: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    fn is_fresh(entry: &CachedResponse, ttl: Duration) -> bool {
+        entry.created_at.elapsed() < ttl
+    }
+
+    fn get(&self, key: &CacheKey, max_age: Duration) -> Option<&CachedResponse> {
+        let entry = self.entries.get(key)?;
+        if !Self::is_fresh(entry, max_age) {
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn sync_limits(&mut self, ttl: Duration, max_size: u64) {
+        self.ttl = ttl;
+        self.max_size = max_size;
+        self.evict_until_within_limit();
+    }
+
+    fn evict_until_within_limit(&mut self) {
+        while self.current_size > self.max_size && !self.entries.is_empty() {
+            let Some(key) = self.entries.keys().next().cloned() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&key) {
+                self.current_size = self.current_size.saturating_sub(evicted.estimated_size());
+            }
+        }
+    }
+
+    fn put(&mut self, key: CacheKey, response: CachedResponse) {
+        let entry_size = response.estimated_size();
+
+        // Remove old entry for the same key first
+        if let Some(old) = self.entries.remove(&key) {
+            self.current_size = self.current_size.saturating_sub(old.estimated_size());
+        }
+
+        if entry_size > self.max_size {
+            return;
+        }
+
+        // Evict oldest entries while over capacity (simple FIFO-like eviction)
+        while self.current_size + entry_size > self.max_size && !self.entries.is_empty() {
+            // Take an arbitrary key (HashMap iteration order is not guaranteed
+            // FIFO, but it is deterministic and cheap)
+            if let Some(stale_key) = self.entries.keys().next().cloned()
+                && let Some(evicted) = self.entries.remove(&stale_key)
+            {
+                self.current_size = self.current_size.saturating_sub(evicted.estimated_size());
+            }
+        }
+
+        self.current_size += entry_size;
+        self.entries.insert(key, response);
+    }
+
+    fn purge(&mut self, purge: &CachePurge) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|key, entry| {
+            if purge.matches(key) {
+                self.current_size = self.current_size.saturating_sub(entry.estimated_size());
+                false
+            } else {
+                true
+            }
+        });
+        before - self.entries.len()
+    }
+
+    fn evict_stale(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_key, entry| {
+            if !Self::is_fresh(entry, ttl) {
+                self.current_size = self.current_size.saturating_sub(entry.estimated_size());
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// In-process store with sharded per-location caches; the default.
+///
+/// `DashMap` provides concurrent access across locations without a global lock.
+/// Each location's `LocationCache` is behind its own `RwLock`, so writes to
+/// one location never block reads from another.
+#[derive(Default)]
+pub struct MemoryCacheStore {
+    stores: DashMap<String, RwLock<LocationCache>>,
+}
+
+impl MemoryCacheStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCacheStore {
+    async fn get(&self, key: &CacheKey, max_age: Duration) -> Option<CachedResponse> {
+        let store = self.stores.get(&key.route)?;
+        let guard = store.read().await;
+        guard.get(key, max_age).cloned()
+    }
+
+    async fn put(&self, key: CacheKey, response: CachedResponse, limits: &CacheLimits) {
+        // Get or create the per-location store. `DashMap::entry` locks only
+        // the shard containing this route.
+        let store = self
+            .stores
+            .entry(key.route.clone())
+            .or_insert_with(|| RwLock::new(LocationCache::new(limits.ttl, limits.max_size)));
+
+        let mut guard = store.write().await;
+        guard.sync_limits(limits.ttl, limits.max_size);
+        guard.put(key, response);
+    }
+
+    async fn purge(&self, purge: &CachePurge) -> usize {
+        let routes: Vec<String> = match purge {
+            CachePurge::Route(route) => vec![route.clone()],
+            _ => self
+                .stores
+                .iter()
+                .map(|entry| entry.key().clone())
+                .collect(),
+        };
+
+        let mut purged = 0;
+        for route in routes {
+            loop {
+                let Some(store) = self.stores.get(&route) else {
+                    break;
+                };
+                if let Ok(mut guard) = store.try_write() {
+                    purged += guard.purge(purge);
+                    break;
+                }
+                drop(store);
+                tokio::task::yield_now().await;
+            }
+        }
+        purged
+    }
+
+    async fn evict_stale(&self) {
+        for entry in self.stores.iter() {
+            entry.value().write().await.evict_stale();
+        }
+    }
+
+    async fn route_stats(&self) -> Vec<CacheRouteStats> {
+        let mut routes: Vec<String> = self
+            .stores
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        routes.sort_unstable();
+
+        let mut stats = Vec::with_capacity(routes.len());
+        for route in routes {
+            // Never hold a DashMap shard across an await: retry the location
+            // lock without blocking until the in-flight write finishes.
+            loop {
+                let Some(store) = self.stores.get(&route) else {
+                    break;
+                };
+                if let Ok(guard) = store.try_read() {
+                    stats.push(CacheRouteStats {
+                        route: route.clone(),
+                        entries: guard.entries.len(),
+                        bytes: guard.current_size,
+                        max_bytes: guard.max_size,
+                    });
+                    break;
+                }
+                drop(store);
+                tokio::task::yield_now().await;
+            }
+        }
+        stats
+    }
+}
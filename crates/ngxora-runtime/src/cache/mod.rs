@@ -1,17 +1,38 @@
+//! Response cache for `proxy_cache` locations.
+//!
+//! [`CacheBackend`] applies the location's cache policy (TTL, stale-if-error,
+//! `proxy_cache_min_uses`, size limits) on top of a [`CacheStore`]:
+//! - `memory`: per-process store, the default
+//! - `redis`: shared store on a Redis-protocol server (`proxy_cache_store`)
+
+mod memory;
+mod redis;
+
+pub use memory::MemoryCacheStore;
+pub use redis::RedisCacheStore;
+
+use crate::upstreams::CompiledRouter;
+use async_trait::async_trait;
 use bytes::Bytes;
 use dashmap::DashMap;
 use http::{HeaderMap, StatusCode};
-use ngxora_compile::ir::CacheConfig;
-use std::collections::HashMap;
+use ngxora_compile::ir::{CacheConfig, CacheStoreConfig};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+
+/// Per-location size limit used until `proxy_cache_max_size` overrides it.
+pub const DEFAULT_CACHE_MAX_SIZE: u64 = 50 * 1024 * 1024;
 
 /// Cache key derived from request properties, governed by `CacheKeyMode`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CacheKey {
     pub generation: u64,
     pub route_id: u64,
+    /// Stable name of the location, see [`route_cache_name`]. Unlike
+    /// `generation` and `route_id` it survives reloads and is the same on
+    /// every replica, so shared stores key on it.
+    pub route: String,
     pub host: String,
     pub method: String,
     pub uri: String,
@@ -41,131 +62,186 @@ pub(crate) fn estimated_headers_size(headers: &HeaderMap) -> u64 {
     })
 }
 
-/// Per-location cache store, protected by an `RwLock`.
+/// Selects the cached responses removed by [`CacheBackend::purge`].
 ///
-/// Contention is minimal because each location has its own lock, and
-/// `CacheBackend` uses `DashMap` to shard access across locations.
-struct LocationCache {
-    ttl: Duration,
-    max_size: u64,
-    current_size: u64,
-    entries: HashMap<CacheKey, CachedResponse>,
+/// URIs are compared against the request target (path and query) regardless
+/// of the location's `proxy_cache_key` mode; hosts compare case-insensitively.
+/// Entries from every generation and method are matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CachePurge {
+    /// One URI on one host, whatever the method and request-target form it
+    /// was cached under.
+    Exact {
+        host: String,
+        uri: String,
+    },
+    /// Every URI starting with `prefix`, on one host or on all hosts.
+    Prefix {
+        host: Option<String>,
+        prefix: String,
+    },
+    /// Everything cached for one location, by [`route_cache_name`].
+    Route(String),
+    All,
 }
 
-impl LocationCache {
-    fn new(ttl: Duration, max_size: u64) -> Self {
-        Self {
-            ttl,
-            max_size,
-            current_size: 0,
-            entries: HashMap::new(),
-        }
-    }
-
-    fn is_fresh(entry: &CachedResponse, ttl: Duration) -> bool {
-        entry.created_at.elapsed() < ttl
+impl CachePurge {
+    /// Purge everything cached for route `route_id` of `router`; `None` when
+    /// the router has no such route.
+    pub fn route(router: &CompiledRouter, route_id: u64) -> Option<Self> {
+        router_locations(router)
+            .find(|(id, _, _)| *id == route_id)
+            .map(|(_, route, _)| Self::Route(route))
     }
 
-    fn get(&self, key: &CacheKey, ttl: Duration) -> Option<&CachedResponse> {
-        let entry = self.entries.get(key)?;
-        if !Self::is_fresh(entry, ttl) {
-            return None;
-        }
-        Some(entry)
-    }
-
-    fn get_stale(
-        &self,
-        key: &CacheKey,
-        ttl: Duration,
-        stale_if_error: Duration,
-    ) -> Option<&CachedResponse> {
-        let entry = self.entries.get(key)?;
-        let max_stale_age = ttl.saturating_add(stale_if_error);
-        if entry.created_at.elapsed() >= max_stale_age {
-            return None;
+    fn matches(&self, key: &CacheKey) -> bool {
+        match self {
+            Self::Exact { host, uri, .. } => {
+                key.host.eq_ignore_ascii_case(host) && request_target(key) == uri
+            }
+            Self::Prefix { host, prefix } => {
+                host.as_ref()
+                    .is_none_or(|host| key.host.eq_ignore_ascii_case(host))
+                    && request_target(key).starts_with(prefix.as_str())
+            }
+            Self::Route(route) => key.route == *route,
+            Self::All => true,
         }
-        Some(entry)
     }
+}
 
-    fn sync_limits(&mut self, ttl: Duration, max_size: u64) {
-        self.ttl = ttl;
-        self.max_size = max_size;
-        self.evict_until_within_limit();
+// Recover the request target from a cache key: `uri_and_method` keys carry a
+// method prefix, and HTTP/2 requests may use an absolute URI.
+fn request_target(key: &CacheKey) -> &str {
+    let uri = key
+        .uri
+        .strip_prefix(key.method.as_str())
+        .and_then(|uri| uri.strip_prefix(' '))
+        .unwrap_or(&key.uri);
+    match uri.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |index| &rest[index..]),
+        None => uri,
     }
+}
 
-    fn evict_until_within_limit(&mut self) {
-        while self.current_size > self.max_size && !self.entries.is_empty() {
-            let Some(key) = self.entries.keys().next().cloned() else {
-                break;
-            };
-            if let Some(evicted) = self.entries.remove(&key) {
-                self.current_size = self.current_size.saturating_sub(evicted.estimated_size());
-            }
-        }
-    }
+/// Stable cache name of a location: the first `server_name` of its server
+/// (`_` when it has none) and its `route_name` or matcher, as in the metric
+/// labels. Locations sharing both share cached responses.
+pub fn route_cache_name(server: &str, route: &str) -> String {
+    format!("{server} {route}")
+}
 
-    fn put(&mut self, key: CacheKey, response: CachedResponse) {
-        let entry_size = response.estimated_size();
+/// Every location of `router` once, with its route id and cache name.
+pub(crate) fn router_locations(
+    router: &CompiledRouter,
+) -> impl Iterator<Item = (u64, String, &crate::upstreams::CompiledLocation)> {
+    let mut seen = std::collections::HashSet::new();
+    router
+        .listeners
+        .values()
+        .flat_map(|vhosts| vhosts.named.values().chain(vhosts.default.as_ref()))
+        .flat_map(|routes| {
+            let server = routes.server_name.as_deref().unwrap_or("_");
+            routes.locations.iter().map(move |location| {
+                let route = location
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| location.matcher.to_string());
+                (
+                    location.route_id,
+                    route_cache_name(server, &route),
+                    location,
+                )
+            })
+        })
+        .filter(move |(route_id, _, _)| seen.insert(*route_id))
+}
 
-        // Remove old entry for the same key first
-        if let Some(old) = self.entries.remove(&key) {
-            self.current_size = self.current_size.saturating_sub(old.estimated_size());
-        }
+/// Cache occupancy of one route, as reported by [`CacheBackend::route_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheRouteStats {
+    /// Cache name of the location, see [`route_cache_name`].
+    pub route: String,
+    pub entries: usize,
+    /// Estimated size of the stored responses, headers included.
+    pub bytes: u64,
+    /// Per-location size limit; `0` when the store does not enforce one.
+    pub max_bytes: u64,
+}
 
-        if entry_size > self.max_size {
-            return;
-        }
+/// Limits applied to one stored response, derived from its `CacheConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+    /// Freshness lifetime (`proxy_cache_ttl`).
+    pub ttl: Duration,
+    /// How long the entry stays useful: `ttl` plus `proxy_cache_stale_if_error`.
+    pub retain: Duration,
+    /// Per-location size limit (`proxy_cache_max_size`).
+    pub max_size: u64,
+}
 
-        // Evict oldest entries while over capacity (simple FIFO-like eviction)
-        while self.current_size + entry_size > self.max_size && !self.entries.is_empty() {
-            // Take an arbitrary key (HashMap iteration order is not guaranteed
-            // FIFO, but it is deterministic and cheap)
-            if let Some(stale_key) = self.entries.keys().next().cloned() {
-                if let Some(evicted) = self.entries.remove(&stale_key) {
-                    self.current_size = self.current_size.saturating_sub(evicted.estimated_size());
-                }
-            }
-        }
+/// Storage behind [`CacheBackend`].
+///
+/// Stores are best-effort: a failing store reports misses and drops writes
+/// instead of failing the request.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// Return the entry for `key` if it is younger than `max_age`.
+    async fn get(&self, key: &CacheKey, max_age: Duration) -> Option<CachedResponse>;
 
-        self.current_size += entry_size;
-        self.entries.insert(key, response);
-    }
+    /// Store `response`, replacing any entry for `key`.
+    async fn put(&self, key: CacheKey, response: CachedResponse, limits: &CacheLimits);
 
-    fn evict_stale(&mut self) {
-        let ttl = self.ttl;
-        self.entries.retain(|_key, entry| {
-            if !Self::is_fresh(entry, ttl) {
-                self.current_size = self.current_size.saturating_sub(entry.estimated_size());
-                false
-            } else {
-                true
-            }
-        });
-    }
+    /// Remove the matching entries and return how many were removed.
+    async fn purge(&self, purge: &CachePurge) -> usize;
+
+    /// Drop entries past their TTL. Stores with native expiry need not
+    /// implement it.
+    async fn evict_stale(&self) {}
+
+    /// Entry counts and sizes per route, ordered by route name.
+    async fn route_stats(&self) -> Vec<CacheRouteStats>;
 }
 
-/// Global cache backend with sharded per-location stores.
+/// Global cache backend: per-location cache policy over a [`CacheStore`].
 ///
-/// `DashMap` provides concurrent access across locations without a global lock.
-/// Each location's `LocationCache` is behind its own `RwLock`, so writes to
-/// one location never block reads from another.
+/// `proxy_cache_min_uses` counters always stay in-process, so with a shared
+/// store each replica still counts its own misses before the first store.
 pub struct CacheBackend {
-    stores: DashMap<u64, RwLock<LocationCache>>,
+    store: Arc<dyn CacheStore>,
     request_counts: DashMap<CacheKey, u64>,
     default_max_size: AtomicU64,
 }
 
 impl CacheBackend {
-    /// Create a new cache backend with a default per-location max size.
+    /// Create an in-memory cache backend with a default per-location max size.
     pub fn new(default_max_size: u64) -> Self {
+        Self::with_store(default_max_size, Arc::new(MemoryCacheStore::new()))
+    }
+
+    /// Create a cache backend on top of `store`.
+    pub fn with_store(default_max_size: u64, store: Arc<dyn CacheStore>) -> Self {
         Self {
-            stores: DashMap::new(),
+            store,
             request_counts: DashMap::new(),
             default_max_size: AtomicU64::new(default_max_size),
         }
     }
 
+    /// Create the cache backend selected by `proxy_cache_store`.
+    ///
+    /// The Redis store connects lazily, so an unreachable server degrades to
+    /// cache misses rather than failing startup.
+    pub fn from_config(config: &CacheStoreConfig, default_max_size: u64) -> Result<Self, String> {
+        let store: Arc<dyn CacheStore> = match config {
+            CacheStoreConfig::Memory => Arc::new(MemoryCacheStore::new()),
+            CacheStoreConfig::Redis { url, key_prefix } => {
+                Arc::new(RedisCacheStore::new(url, key_prefix.as_deref())?)
+            }
+        };
+        Ok(Self::with_store(default_max_size, store))
+    }
+
     /// Update the fallback max size used when a location doesn't specify its
     /// own `proxy_cache_max_size`. Safe to call at any time.
     pub fn set_default_max_size(&self, size: u64) {
@@ -188,9 +264,7 @@ impl CacheBackend {
         }
 
         let ttl = cfg.ttl.unwrap_or(Duration::from_secs(60));
-        let store = self.stores.get(&key.route_id)?;
-        let guard = store.read().await;
-        guard.get(key, ttl).cloned()
+        self.store.get(key, ttl).await
     }
 
     /// Record a cache miss and decide whether the next cacheable upstream
@@ -212,9 +286,9 @@ impl CacheBackend {
         usize::try_from(*count).unwrap_or(usize::MAX) >= min_uses
     }
 
-    /// Look up a cached response **ignoring TTL** — used for stale-if-error.
+    /// Look up a cached response past its TTL — used for stale-if-error.
     ///
-    /// Returns any entry regardless of age, as long as the cache is enabled.
+    /// Returns entries no older than TTL plus `stale_if_error`.
     pub async fn get_stale(&self, key: &CacheKey, cfg: &CacheConfig) -> Option<CachedResponse> {
         if !cfg.enabled {
             return None;
@@ -222,70 +296,58 @@ impl CacheBackend {
 
         let ttl = cfg.ttl.unwrap_or(Duration::from_secs(60));
         let stale_if_error = cfg.stale_if_error?;
-        let store = self.stores.get(&key.route_id)?;
-        let guard = store.read().await;
-        guard.get_stale(key, ttl, stale_if_error).cloned()
+        self.store
+            .get(key, ttl.saturating_add(stale_if_error))
+            .await
     }
 
     /// Store a response in the cache for the given key and config.
-    ///
-    /// If the location doesn't have a cache yet, one is created with the
-    /// configured TTL and max size.
     pub async fn put(&self, key: CacheKey, response: CachedResponse, cfg: &CacheConfig) {
         if !cfg.enabled {
             return;
         }
 
         let ttl = cfg.ttl.unwrap_or(Duration::from_secs(60));
-        let max_size = self.max_size(cfg);
-
-        // Get or create the per-location store. `DashMap::entry` locks only
-        // the shard containing this route_id.
-        let store = self
-            .stores
-            .entry(key.route_id)
-            .or_insert_with(|| RwLock::new(LocationCache::new(ttl, max_size)));
-
-        let mut guard = store.write().await;
-        guard.sync_limits(ttl, max_size);
+        let limits = CacheLimits {
+            ttl,
+            retain: ttl.saturating_add(cfg.stale_if_error.unwrap_or_default()),
+            max_size: self.max_size(cfg),
+        };
         self.request_counts.remove(&key);
-        guard.put(key, response);
+        self.store.put(key, response, &limits).await;
     }
 
-    /// Evict stale entries across all locations without blocking reads for
-    /// longer than a single location lock.
+    /// Evict stale entries across all locations.
     pub async fn evict_stale(&self) {
-        for entry in self.stores.iter() {
-            entry.value().write().await.evict_stale();
-        }
+        self.store.evict_stale().await;
     }
 
     /// Invalidate all cache entries for a specific route.
-    pub fn invalidate_route(&self, route_id: u64) {
-        self.stores.remove(&route_id);
+    pub async fn invalidate_route(&self, route: &str) {
+        self.purge(&CachePurge::Route(route.to_string())).await;
+    }
 
-        let keys: Vec<CacheKey> = self
-            .request_counts
-            .iter()
-            .filter(|entry| entry.key().route_id == route_id)
-            .map(|entry| entry.key().clone())
-            .collect();
-        for key in keys {
-            self.request_counts.remove(&key);
-        }
+    /// Remove the matching cached responses and return how many were removed.
+    ///
+    /// Unlike applying a new snapshot, this leaves every other entry warm.
+    pub async fn purge(&self, purge: &CachePurge) -> usize {
+        let purged = self.store.purge(purge).await;
+        self.request_counts.retain(|key, _| !purge.matches(key));
+        purged
+    }
+
+    /// Return entry counts and estimated sizes per route, ordered by route name.
+    pub async fn route_stats(&self) -> Vec<CacheRouteStats> {
+        self.store.route_stats().await
     }
 
     /// Return the total number of cached entries across all locations.
-    pub fn total_entries(&self) -> usize {
-        let mut total = 0;
-        for entry in self.stores.iter() {
-            // We use try_read to avoid blocking. During a write of a tiny
-            // location this will succeed near-instantly.
-            if let Ok(guard) = entry.value().try_read() {
-                total += guard.entries.len();
-            }
-        }
-        total
+    pub async fn total_entries(&self) -> usize {
+        self.route_stats()
+            .await
+            .iter()
+            .map(|stats| stats.entries)
+            .sum()
     }
 }
 
@@ -295,6 +357,7 @@ pub fn build_cache_key(
     uri: &str,
     generation: u64,
     route_id: u64,
+    route: &str,
     host: &str,
     cfg: &CacheConfig,
 ) -> CacheKey {
@@ -310,6 +373,7 @@ pub fn build_cache_key(
     CacheKey {
         generation,
         route_id,
+        route: route.to_string(),
         host: host.to_ascii_lowercase(),
         method: method.as_str().to_string(),
         uri: uri_key,
@@ -410,20 +474,32 @@ mod tests {
             "/api/users?page=1",
             7,
             42,
+            "example.com /api/",
             "Example.COM",
             &cfg,
         );
         assert_eq!(key.generation, 7);
         assert_eq!(key.route_id, 42);
+        assert_eq!(key.route, "example.com /api/");
         assert_eq!(key.host, "example.com");
         assert_eq!(key.uri, "/api/users?page=1");
     }
 
     #[test]
     fn build_cache_key_uri_and_method_mode() {
-        let mut cfg = CacheConfig::default();
-        cfg.cache_key = ngxora_compile::ir::CacheKeyMode::UriAndMethod;
-        let key = build_cache_key(&http::Method::GET, "/api/users", 1, 99, "example.com", &cfg);
+        let cfg = CacheConfig {
+            cache_key: ngxora_compile::ir::CacheKeyMode::UriAndMethod,
+            ..CacheConfig::default()
+        };
+        let key = build_cache_key(
+            &http::Method::GET,
+            "/api/users",
+            1,
+            99,
+            "_ /api/",
+            "example.com",
+            &cfg,
+        );
         assert_eq!(key.uri, "GET /api/users");
     }
 
@@ -438,6 +514,7 @@ mod tests {
             "/search?role=user&role=admin&debug",
             1,
             1,
+            "_ /search",
             "example.com",
             &cfg,
         );
@@ -544,6 +621,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/test".into(),
@@ -572,6 +650,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 3,
+            route: "_ /3".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/gated".into(),
@@ -591,6 +670,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 2,
+            route: "_ /2".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/nope".into(),
@@ -620,6 +700,7 @@ mod tests {
         let key_a = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/a".into(),
@@ -627,6 +708,7 @@ mod tests {
         let key_b = CacheKey {
             generation: 1,
             route_id: 2,
+            route: "_ /2".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/b".into(),
@@ -666,12 +748,12 @@ mod tests {
             backend.get(&key_b, &cfg).await.unwrap().body,
             Bytes::from_static(b"b")
         );
-        assert_eq!(backend.total_entries(), 2);
+        assert_eq!(backend.total_entries().await, 2);
 
-        backend.invalidate_route(1);
+        backend.invalidate_route("_ /1").await;
         assert!(backend.get(&key_a, &cfg).await.is_none());
         assert!(backend.get(&key_b, &cfg).await.is_some());
-        assert_eq!(backend.total_entries(), 1);
+        assert_eq!(backend.total_entries().await, 1);
     }
 
     #[tokio::test]
@@ -681,6 +763,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "a.example".into(),
             method: "GET".into(),
             uri: "/account".into(),
@@ -718,6 +801,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 3,
+            route: "_ /3".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/stale".into(),
@@ -765,6 +849,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 4,
+            route: "_ /4".into(),
             host: "example.com".into(),
             method: "GET".into(),
             uri: "/oversized".into(),
@@ -784,6 +869,62 @@ mod tests {
             .await;
 
         assert!(backend.get(&key, &cfg).await.is_none());
-        assert_eq!(backend.total_entries(), 0);
+        assert_eq!(backend.total_entries().await, 0);
+    }
+
+    #[tokio::test]
+    async fn cache_backend_purge_by_exact_prefix_route_and_all() {
+        let backend = CacheBackend::new(10 * 1024 * 1024);
+        let cfg = CacheConfig::default();
+        let key = |route_id: u64, host: &str, uri: &str| CacheKey {
+            generation: 1,
+            route_id,
+            route: format!("_ /{route_id}"),
+            host: host.into(),
+            method: "GET".into(),
+            uri: uri.into(),
+        };
+        let cached = CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"hello"),
+            created_at: Instant::now(),
+        };
+        let keys = [
+            key(1, "example.com", "/news/1"),
+            key(1, "example.com", "GET /news/2"),
+            key(1, "other.com", "/news/1"),
+            key(2, "example.com", "https://example.com/about"),
+        ];
+        for key in &keys {
+            backend.put(key.clone(), cached.clone(), &cfg).await;
+        }
+
+        let exact = CachePurge::Exact {
+            host: "Example.com".into(),
+            uri: "/news/1".into(),
+        };
+        assert_eq!(backend.purge(&exact).await, 1);
+        assert!(backend.get(&keys[0], &cfg).await.is_none());
+        assert!(backend.get(&keys[2], &cfg).await.is_some());
+
+        let prefix = CachePurge::Prefix {
+            host: None,
+            prefix: "/news/".into(),
+        };
+        assert_eq!(backend.purge(&prefix).await, 2);
+        assert_eq!(backend.total_entries().await, 1);
+
+        assert_eq!(backend.purge(&CachePurge::Route("_ /1".into())).await, 0);
+        let about = CachePurge::Exact {
+            host: "example.com".into(),
+            uri: "/about".into(),
+        };
+        assert_eq!(backend.purge(&about).await, 1);
+
+        backend.put(keys[0].clone(), cached, &cfg).await;
+        assert_eq!(backend.purge(&CachePurge::All).await, 1);
+        assert_eq!(backend.total_entries().await, 0);
+        assert!(backend.route_stats().await.iter().all(|s| s.bytes == 0));
     }
 }
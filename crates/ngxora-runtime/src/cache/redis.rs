@@ -0,0 +1,629 @@
+use super::{CacheKey, CacheLimits, CachePurge, CacheRouteStats, CacheStore, CachedResponse};
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use ngxora_redis::LazyConnection;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use redis::aio::ConnectionManager;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_KEY_PREFIX: &str = "ngxora:cache";
+/// Bumped whenever the key layout changes; entries under an older schema are
+/// never read again and expire on their own.
+const KEY_SCHEMA: &str = "v1";
+const ENCODING_MAGIC: &[u8; 4] = b"NXC1";
+const SCAN_BATCH: usize = 512;
+/// `/admin/cache` stats stop after this many keys, so a large keyspace costs
+/// a bounded number of round trips; the counts are then a lower bound.
+const STATS_MAX_KEYS: usize = 10_000;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shared store on a Redis-protocol server, so replicas serve each other's
+/// cached responses.
+///
+/// Keys are `<prefix>:v1:<route>:<method>:<host> <uri>`, where `<route>` is the
+/// form-encoded [`route_cache_name`](super::route_cache_name). Nothing in the
+/// key depends on the local snapshot, so replicas and restarted processes
+/// share entries as long as the location keeps its server and route name.
+/// Values carry the response and its creation time; each key expires after
+/// TTL plus `stale_if_error`. Eviction under memory pressure is left to the
+/// server's `maxmemory-policy`.
+pub struct RedisCacheStore {
+    key_prefix: String,
+    connection: LazyConnection,
+}
+
+impl RedisCacheStore {
+    /// Create a store for `url`; the connection is opened on first use.
+    pub fn new(url: &str, key_prefix: Option<&str>) -> Result<Self, String> {
+        let client = redis::Client::open(url)
+            .map_err(|err| format!("invalid redis cache store URL `{url}`: {err}"))?;
+        Ok(Self {
+            key_prefix: key_prefix.unwrap_or(DEFAULT_KEY_PREFIX).to_string(),
+            connection: LazyConnection::new(
+                client,
+                "redis cache store",
+                CONNECT_TIMEOUT,
+                RESPONSE_TIMEOUT,
+            ),
+        })
+    }
+
+    fn redis_key(&self, key: &CacheKey) -> String {
+        format!(
+            "{}:{KEY_SCHEMA}:{}:{}:{} {}",
+            self.key_prefix,
+            encode_route(&key.route),
+            key.method,
+            key.host,
+            key.uri
+        )
+    }
+
+    /// Parse a stored key back; `generation` and `route_id` are local to a
+    /// process, are not stored and come back as `0`.
+    fn parse_key(&self, raw: &[u8]) -> Option<CacheKey> {
+        let raw = std::str::from_utf8(raw).ok()?;
+        let rest = raw
+            .strip_prefix(self.key_prefix.as_str())?
+            .strip_prefix(':')?
+            .strip_prefix(KEY_SCHEMA)?
+            .strip_prefix(':')?;
+        let mut parts = rest.splitn(3, ':');
+        let route = percent_decode_str(parts.next()?)
+            .decode_utf8()
+            .ok()?
+            .into_owned();
+        let method = parts.next()?.to_string();
+        let (host, uri) = parts.next()?.split_once(' ')?;
+        Some(CacheKey {
+            generation: 0,
+            route_id: 0,
+            route,
+            host: host.to_string(),
+            method,
+            uri: uri.to_string(),
+        })
+    }
+
+    fn scan_pattern(&self, purge: &CachePurge) -> String {
+        let prefix = escape_glob(&self.key_prefix);
+        match purge {
+            CachePurge::Route(route) => {
+                format!("{prefix}:{KEY_SCHEMA}:{}:*", encode_route(route))
+            }
+            // Keys carry the lowercased host and end with the request target
+            // whatever its form; the glob can over-match, and
+            // `CachePurge::matches` has the final say.
+            CachePurge::Exact { host, uri } => format!(
+                "{prefix}:{KEY_SCHEMA}:*:{} *{}",
+                escape_glob(&host.to_ascii_lowercase()),
+                escape_glob(uri)
+            ),
+            _ => format!("{prefix}:{KEY_SCHEMA}:*"),
+        }
+    }
+
+    /// One `SCAN` page of keys matching `pattern`; a returned cursor of `0`
+    /// means the walk is complete.
+    async fn scan_page(
+        conn: &mut ConnectionManager,
+        pattern: &str,
+        cursor: u64,
+    ) -> redis::RedisResult<(u64, Vec<Vec<u8>>)> {
+        redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(SCAN_BATCH)
+            .query_async(conn)
+            .await
+    }
+
+    async fn try_get(
+        &self,
+        key: &CacheKey,
+        max_age: Duration,
+    ) -> redis::RedisResult<Option<CachedResponse>> {
+        let mut conn = self.connection.get().await?;
+        let value: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(self.redis_key(key))
+            .query_async(&mut conn)
+            .await?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let Some(response) = decode_response(&value) else {
+            log::warn!("redis cache store: discarding undecodable entry");
+            return Ok(None);
+        };
+        Ok((response.created_at.elapsed() < max_age).then_some(response))
+    }
+
+    async fn try_put(
+        &self,
+        key: &CacheKey,
+        response: &CachedResponse,
+        limits: &CacheLimits,
+    ) -> redis::RedisResult<()> {
+        let expire_ms = u64::try_from(limits.retain.as_millis()).unwrap_or(u64::MAX);
+        if expire_ms == 0 || response.estimated_size() > limits.max_size {
+            return Ok(());
+        }
+        let Some(value) = encode_response(response) else {
+            log::debug!("redis cache store: response headers too large to store");
+            return Ok(());
+        };
+        let mut conn = self.connection.get().await?;
+        redis::cmd("SET")
+            .arg(self.redis_key(key))
+            .arg(value)
+            .arg("PX")
+            .arg(expire_ms)
+            .query_async::<()>(&mut conn)
+            .await
+    }
+
+    async fn try_purge(&self, purge: &CachePurge) -> redis::RedisResult<usize> {
+        // Each page is deleted before the next is fetched, so memory stays
+        // bounded by one batch whatever the size of the keyspace.
+        let mut conn = self.connection.get().await?;
+        let pattern = self.scan_pattern(purge);
+        let mut purged = 0;
+        let mut cursor = 0_u64;
+        loop {
+            let (next, batch) = Self::scan_page(&mut conn, &pattern, cursor).await?;
+            let keys: Vec<Vec<u8>> = batch
+                .into_iter()
+                .filter(|raw| self.parse_key(raw).is_some_and(|key| purge.matches(&key)))
+                .collect();
+            if !keys.is_empty() {
+                let removed: usize = redis::cmd("DEL").arg(keys).query_async(&mut conn).await?;
+                purged += removed;
+            }
+            if next == 0 {
+                return Ok(purged);
+            }
+            cursor = next;
+        }
+    }
+
+    async fn try_route_stats(&self) -> redis::RedisResult<Vec<CacheRouteStats>> {
+        let mut conn = self.connection.get().await?;
+        let pattern = self.scan_pattern(&CachePurge::All);
+        let mut routes: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+        let mut seen = 0;
+        let mut cursor = 0_u64;
+        loop {
+            let (next, batch) = Self::scan_page(&mut conn, &pattern, cursor).await?;
+            seen += batch.len();
+            let mut pipe = redis::pipe();
+            for key in &batch {
+                pipe.cmd("STRLEN").arg(key);
+            }
+            let sizes: Vec<u64> = pipe.query_async(&mut conn).await?;
+            for (key, size) in batch.iter().zip(sizes) {
+                // Keys that expired between SCAN and STRLEN report zero.
+                if let Some(key) = self.parse_key(key)
+                    && size > 0
+                {
+                    let route = routes.entry(key.route).or_default();
+                    route.0 += 1;
+                    route.1 += size;
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            if seen >= STATS_MAX_KEYS {
+                log::debug!("redis cache store: stats SCAN stopped after {seen} keys");
+                break;
+            }
+            cursor = next;
+        }
+
+        Ok(routes
+            .into_iter()
+            .map(|(route, (entries, bytes))| CacheRouteStats {
+                route,
+                entries,
+                bytes,
+                max_bytes: 0,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl CacheStore for RedisCacheStore {
+    async fn get(&self, key: &CacheKey, max_age: Duration) -> Option<CachedResponse> {
+        self.try_get(key, max_age).await.unwrap_or_else(|err| {
+            log::warn!("redis cache store: GET failed: {err}");
+            None
+        })
+    }
+
+    async fn put(&self, key: CacheKey, response: CachedResponse, limits: &CacheLimits) {
+        if let Err(err) = self.try_put(&key, &response, limits).await {
+            log::warn!("redis cache store: SET failed: {err}");
+        }
+    }
+
+    async fn purge(&self, purge: &CachePurge) -> usize {
+        self.try_purge(purge).await.unwrap_or_else(|err| {
+            log::warn!("redis cache store: purge failed: {err}");
+            0
+        })
+    }
+
+    async fn route_stats(&self) -> Vec<CacheRouteStats> {
+        self.try_route_stats().await.unwrap_or_else(|err| {
+            log::warn!("redis cache store: stats failed: {err}");
+            Vec::new()
+        })
+    }
+}
+
+// Escaping `:`, spaces and glob characters keeps the key unambiguous to split
+// and the route safe to embed in a `SCAN` pattern.
+const ROUTE_ESCAPES: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'%')
+    .add(b':')
+    .add(b'*')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'\\');
+
+fn encode_route(route: &str) -> String {
+    utf8_percent_encode(route, ROUTE_ESCAPES).to_string()
+}
+
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Value layout: magic, creation time (ms since the Unix epoch, so replicas
+// agree on the age), status, header count, length-prefixed header names and
+// values, then the body. `None` when a length does not fit its prefix; such a
+// response is not cached rather than stored truncated.
+fn encode_response(response: &CachedResponse) -> Option<Vec<u8>> {
+    let created = SystemTime::now()
+        .checked_sub(response.created_at.elapsed())
+        .unwrap_or_else(SystemTime::now);
+    let created_ms = created
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |age| u64::try_from(age.as_millis()).unwrap_or(u64::MAX));
+
+    let mut buf = BytesMut::with_capacity(response.estimated_size() as usize);
+    buf.put_slice(ENCODING_MAGIC);
+    buf.put_u64(created_ms);
+    buf.put_u16(response.status.as_u16());
+    buf.put_u32(u32::try_from(response.headers.len()).ok()?);
+    for (name, value) in &response.headers {
+        buf.put_u16(u16::try_from(name.as_str().len()).ok()?);
+        buf.put_slice(name.as_str().as_bytes());
+        buf.put_u32(u32::try_from(value.as_bytes().len()).ok()?);
+        buf.put_slice(value.as_bytes());
+    }
+    buf.put_slice(&response.body);
+    Some(buf.to_vec())
+}
+
+fn decode_response(value: &[u8]) -> Option<CachedResponse> {
+    let mut buf = value.strip_prefix(ENCODING_MAGIC.as_slice())?;
+    if buf.remaining() < 14 {
+        return None;
+    }
+    let created_ms = buf.get_u64();
+    let status = StatusCode::from_u16(buf.get_u16()).ok()?;
+    let header_count = buf.get_u32();
+
+    let mut headers = HeaderMap::new();
+    for _ in 0..header_count {
+        let name = take_bytes(&mut buf, 2)?;
+        let value = take_bytes(&mut buf, 4)?;
+        headers.append(
+            HeaderName::from_bytes(name).ok()?,
+            HeaderValue::from_bytes(value).ok()?,
+        );
+    }
+
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_millis(created_ms))
+        .unwrap_or_default();
+    Some(CachedResponse {
+        status,
+        headers,
+        body: Bytes::copy_from_slice(buf),
+        created_at: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+    })
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8], len_width: usize) -> Option<&'a [u8]> {
+    if buf.remaining() < len_width {
+        return None;
+    }
+    let len = if len_width == 2 {
+        usize::from(buf.get_u16())
+    } else {
+        buf.get_u32() as usize
+    };
+    if buf.remaining() < len {
+        return None;
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RedisCacheStore, SCAN_BATCH, decode_response, encode_response};
+    use crate::cache::{CacheBackend, CacheKey, CachePurge, CacheStore, CachedResponse};
+    use bytes::Bytes;
+    use http::{HeaderMap, HeaderValue, StatusCode};
+    use ngxora_compile::ir::{CacheConfig, CacheStoreConfig};
+    use ngxora_redis::test_support::StandIn;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    fn key(route_id: u64, uri: &str) -> CacheKey {
+        CacheKey {
+            generation: 1,
+            route_id,
+            route: format!("example.com /r{route_id}/"),
+            host: "example.com".into(),
+            method: "GET".into(),
+            uri: uri.into(),
+        }
+    }
+
+    fn response(body: &'static [u8]) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain"),
+        );
+        headers.append("x-multi", HeaderValue::from_static("a"));
+        headers.append("x-multi", HeaderValue::from_static("b"));
+        CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from_static(body),
+            created_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn encoding_roundtrips_response() {
+        let mut cached = response(b"hello");
+        cached.created_at = Instant::now() - Duration::from_secs(5);
+        let decoded = decode_response(&encode_response(&cached).unwrap()).expect("decodes");
+
+        assert_eq!(decoded.status, StatusCode::OK);
+        assert_eq!(decoded.body, Bytes::from_static(b"hello"));
+        assert_eq!(decoded.headers, cached.headers);
+        let age = decoded.created_at.elapsed();
+        assert!(age >= Duration::from_secs(4) && age < Duration::from_secs(7));
+        assert!(decode_response(b"garbage").is_none());
+    }
+
+    #[test]
+    fn keys_roundtrip_through_the_key_scheme() {
+        let store = RedisCacheStore::new("redis://127.0.0.1:6379", Some("edge")).unwrap();
+        let key = CacheKey {
+            generation: 0,
+            route_id: 0,
+            route: "_ ~ ^/a:b*%".into(),
+            host: "example.com:8080".into(),
+            method: "GET".into(),
+            uri: "GET /a:b?c d".into(),
+        };
+        let raw = store.redis_key(&key);
+
+        assert_eq!(
+            raw,
+            "edge:v1:_%20~%20^/a%3Ab%2A%25:GET:example.com:8080 GET /a:b?c d"
+        );
+        assert_eq!(store.parse_key(raw.as_bytes()), Some(key.clone()));
+
+        // Snapshot-local ids never reach the stored key.
+        let reloaded = CacheKey {
+            generation: 9,
+            route_id: 4,
+            ..key
+        };
+        assert_eq!(store.redis_key(&reloaded), raw);
+        assert_eq!(store.parse_key(b"other:v1:_:GET:example.com /"), None);
+        assert_eq!(store.parse_key(b"edge:3:7:GET:example.com /"), None);
+    }
+
+    #[tokio::test]
+    async fn replicas_share_entries_through_the_store() {
+        let StandIn { url, .. } = StandIn::spawn().await;
+        let config = CacheStoreConfig::redis(&url, None).unwrap();
+        let first = CacheBackend::from_config(&config, 1024 * 1024).unwrap();
+        let second = CacheBackend::from_config(&config, 1024 * 1024).unwrap();
+        let cfg = CacheConfig::default();
+
+        first.put(key(1, "/shared"), response(b"hello"), &cfg).await;
+
+        // The other replica runs a different snapshot sequence.
+        let mut lookup = key(1, "/shared");
+        lookup.generation = 5;
+        lookup.route_id = 12;
+        let found = second.get(&lookup, &cfg).await.expect("shared hit");
+        assert_eq!(found.body, Bytes::from_static(b"hello"));
+        assert_eq!(found.headers.get_all("x-multi").iter().count(), 2);
+        assert!(second.get(&key(2, "/shared"), &cfg).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ttl_and_stale_window_map_to_expiry() {
+        let StandIn { url, keyspace, .. } = StandIn::spawn().await;
+        let backend =
+            CacheBackend::from_config(&CacheStoreConfig::redis(&url, None).unwrap(), 1024 * 1024)
+                .unwrap();
+        let cfg = CacheConfig {
+            ttl: Some(Duration::from_secs(60)),
+            stale_if_error: Some(Duration::from_secs(30)),
+            ..CacheConfig::default()
+        };
+
+        let mut old = response(b"old");
+        old.created_at = Instant::now() - Duration::from_secs(75);
+        backend.put(key(1, "/stale"), old, &cfg).await;
+
+        assert!(backend.get(&key(1, "/stale"), &cfg).await.is_none());
+        assert!(backend.get_stale(&key(1, "/stale"), &cfg).await.is_some());
+
+        let (_, expires) = keyspace.lock().unwrap().values().next().cloned().unwrap();
+        let remaining = expires.unwrap() - Instant::now();
+        assert!(remaining > Duration::from_secs(85) && remaining <= Duration::from_secs(90));
+    }
+
+    #[tokio::test]
+    async fn purge_and_stats_scan_the_key_prefix() {
+        let StandIn { url, keyspace, .. } = StandIn::spawn().await;
+        let config = CacheStoreConfig::redis(&url, Some("edge".into())).unwrap();
+        let backend = CacheBackend::from_config(&config, 1024 * 1024).unwrap();
+        let cfg = CacheConfig::default();
+        for (route_id, uri) in [(1, "/news/1"), (1, "/news/2"), (2, "/about")] {
+            backend
+                .put(key(route_id, uri), response(b"hello"), &cfg)
+                .await;
+        }
+        keyspace.lock().unwrap().insert(
+            b"other:v1:example.com%20/r1/:GET:example.com /news/3".to_vec(),
+            (b"x".to_vec(), None),
+        );
+
+        let stats = backend.route_stats().await;
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            (stats[0].route.as_str(), stats[0].entries),
+            ("example.com /r1/", 2)
+        );
+        assert!(stats[0].bytes > 0);
+        assert_eq!(stats[1].max_bytes, 0);
+
+        let prefix = CachePurge::Prefix {
+            host: Some("EXAMPLE.com".into()),
+            prefix: "/news/".into(),
+        };
+        assert_eq!(backend.purge(&prefix).await, 2);
+        let route = CachePurge::Route("example.com /r2/".into());
+        assert_eq!(backend.purge(&route).await, 1);
+        assert_eq!(backend.total_entries().await, 0);
+        assert_eq!(keyspace.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_each_scan_page_before_fetching_the_next() {
+        let StandIn {
+            url,
+            keyspace,
+            commands,
+        } = StandIn::spawn().await;
+        let store = RedisCacheStore::new(&url, None).unwrap();
+        let entries = SCAN_BATCH * 2 + 10;
+        {
+            let mut keys = keyspace.lock().unwrap();
+            for index in 0..entries {
+                let raw = store.redis_key(&key(1, &format!("/news/{index}")));
+                keys.insert(raw.into_bytes(), (b"x".to_vec(), None));
+            }
+            let other = store.redis_key(&key(2, "/about"));
+            keys.insert(other.into_bytes(), (b"x".to_vec(), None));
+        }
+
+        let purge = CachePurge::Prefix {
+            host: None,
+            prefix: "/news/".into(),
+        };
+        assert_eq!(store.purge(&purge).await, entries);
+        assert_eq!(keyspace.lock().unwrap().len(), 1);
+
+        let commands = commands.lock().unwrap();
+        let walk: Vec<&str> = commands
+            .iter()
+            .map(String::as_str)
+            .filter(|name| matches!(*name, "SCAN" | "DEL"))
+            .collect();
+        assert_eq!(walk, ["SCAN", "DEL", "SCAN", "DEL", "SCAN", "DEL"]);
+    }
+
+    #[tokio::test]
+    async fn exact_purge_matches_the_same_entries_in_both_stores() {
+        let StandIn { url, .. } = StandIn::spawn().await;
+        let config = CacheStoreConfig::redis(&url, None).unwrap();
+        let stores = [
+            CacheBackend::new(1024 * 1024),
+            CacheBackend::from_config(&config, 1024 * 1024).unwrap(),
+        ];
+        let cfg = CacheConfig::default();
+        let entry = |host: &str, method: &str, uri: &str| CacheKey {
+            host: host.into(),
+            method: method.into(),
+            uri: uri.into(),
+            ..key(1, "")
+        };
+        let purged = [
+            entry("example.com", "GET", "/news/1"),
+            entry("example.com", "HEAD", "/news/1"),
+            entry("example.com", "GET", "GET /news/1"),
+            entry("example.com", "GET", "https://example.com/news/1"),
+        ];
+        let kept = [
+            entry("example.com", "GET", "/news/10"),
+            entry("example.com", "GET", "/x/news/1"),
+            entry("example.com", "GET", "/news/1?page=2"),
+            entry("other.example.com", "GET", "/news/1"),
+        ];
+        for store in &stores {
+            for key in purged.iter().chain(&kept) {
+                store.put(key.clone(), response(b"hello"), &cfg).await;
+            }
+        }
+
+        let exact = CachePurge::Exact {
+            host: "Example.com".into(),
+            uri: "/news/1".into(),
+        };
+        for store in &stores {
+            assert_eq!(store.purge(&exact).await, purged.len());
+            for key in &purged {
+                assert!(store.get(key, &cfg).await.is_none(), "{key:?}");
+            }
+            for key in &kept {
+                assert!(store.get(key, &cfg).await.is_some(), "{key:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_degrades_to_misses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = CacheStoreConfig::redis(&format!("redis://{addr}"), None).unwrap();
+        let backend = CacheBackend::from_config(&config, 1024 * 1024).unwrap();
+        let cfg = CacheConfig::default();
+
+        backend.put(key(1, "/down"), response(b"hello"), &cfg).await;
+        assert!(backend.get(&key(1, "/down"), &cfg).await.is_none());
+        assert_eq!(backend.purge(&CachePurge::All).await, 0);
+    }
+}
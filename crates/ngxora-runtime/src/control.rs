@@ -1,3 +1,5 @@
+use crate::cache::{CacheBackend, CachePurge};
+use crate::le::TlsAlpnChallenges;
 use crate::upstreams::{
     ClientIdentityKey, CompiledRouter, ListenKey, ListenerProtocolConfig, ListenerTlsSettings,
     RuntimeClientIdentity, RuntimeTrustedCa, RuntimeUpstreamGroup, ServerRoutes, VirtualHostRoutes,
@@ -5,7 +7,7 @@ use crate::upstreams::{
 };
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use ngxora_compile::ir::PemSource;
+use ngxora_compile::ir::{CacheStoreConfig, PemSource};
 use ngxora_plugin_api::{PluginChain, empty_plugin_chain};
 use ngxora_plugin_registry::PluginRegistry;
 use pingora::services::ServiceReadyNotifier;
@@ -44,6 +46,9 @@ pub struct ApplyResult {
     pub applied: bool,
     pub restart_required: bool,
     pub message: String,
+    /// Human-readable list of the restart-only settings that differ from the
+    /// running process. Empty unless `restart_required` is set.
+    pub restart_changes: Vec<String>,
     pub active_version: String,
     pub active_generation: u64,
 }
@@ -104,6 +109,7 @@ pub struct RuntimeState {
     registry: Arc<PluginRegistry>,
     generation: AtomicU64,
     tls_material_generation: AtomicU64,
+    tls_alpn_challenges: TlsAlpnChallenges,
 }
 
 impl RuntimeState {
@@ -118,12 +124,14 @@ impl RuntimeState {
         let initial_snapshot =
             Self::build_runtime_snapshot(&registry, snapshot.version, snapshot.router, 1)
                 .expect("bootstrap snapshot plugin resolution failed");
+        crate::metrics::reseed_label_values(&initial_snapshot);
         Self {
             current: ArcSwap::from_pointee(initial_snapshot),
             bootstrap_config,
             registry,
             generation: AtomicU64::new(1),
             tls_material_generation: AtomicU64::new(1),
+            tls_alpn_challenges: TlsAlpnChallenges::default(),
         }
     }
 
@@ -150,16 +158,23 @@ impl RuntimeState {
         self.tls_material_generation.fetch_add(1, Ordering::AcqRel) + 1
     }
 
+    /// Pending TLS-ALPN-01 certificates, answered by every TLS listener.
+    pub(crate) fn tls_alpn_challenges(&self) -> &TlsAlpnChallenges {
+        &self.tls_alpn_challenges
+    }
+
     /// Applies a new snapshot if only live-reloadable state changed.
     /// Listener topology and bootstrap transport settings still require restart.
     pub fn apply_snapshot(&self, next: ConfigSnapshot) -> ApplyResult {
-        if restart_fingerprint(&next.router) != self.bootstrap_config {
+        let next_fingerprint = restart_fingerprint(&next.router);
+        if next_fingerprint != self.bootstrap_config {
             let current = self.snapshot();
+            let restart_changes = restart_changes(&self.bootstrap_config, &next_fingerprint);
             return ApplyResult {
                 applied: false,
                 restart_required: true,
-                message: "listener or bootstrap transport settings changed; restart required"
-                    .into(),
+                message: restart_message(&restart_changes),
+                restart_changes,
                 active_version: current.version.clone(),
                 active_generation: current.generation,
             };
@@ -180,15 +195,24 @@ impl RuntimeState {
                     applied: false,
                     restart_required: false,
                     message,
+                    restart_changes: Vec::new(),
                     active_version: current.version.clone(),
                     active_generation: current.generation,
                 };
             }
         };
 
+        let current = self.snapshot();
+        for (name, group) in &runtime_snapshot.upstream_groups {
+            if let Some(previous) = current.upstream_groups.get(name) {
+                group.adopt_resolved(previous);
+            }
+        }
+
         // The generation is only committed after plugin resolution succeeds.
         let active_generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
         let active_version = runtime_snapshot.version.clone();
+        crate::metrics::reseed_label_values(&runtime_snapshot);
         self.current.store(Arc::new(RuntimeSnapshot {
             generation: active_generation,
             ..runtime_snapshot
@@ -198,6 +222,7 @@ impl RuntimeState {
             applied: true,
             restart_required: false,
             message: "snapshot applied".into(),
+            restart_changes: Vec::new(),
             active_version,
             active_generation,
         }
@@ -232,12 +257,19 @@ impl RuntimeState {
 #[derive(Clone)]
 pub struct InProcessControlPlane {
     state: Arc<RuntimeState>,
+    cache: Option<Arc<CacheBackend>>,
 }
 
 impl InProcessControlPlane {
     /// Thin in-process wrapper used by tests and future control-plane adapters.
     pub fn new(state: Arc<RuntimeState>) -> Self {
-        Self { state }
+        Self { state, cache: None }
+    }
+
+    /// Attach the proxy's response cache so it can be purged remotely.
+    pub fn with_cache(mut self, cache: Arc<CacheBackend>) -> Self {
+        self.cache = Some(cache);
+        self
     }
 
     pub fn state(&self) -> &Arc<RuntimeState> {
@@ -251,10 +283,20 @@ impl InProcessControlPlane {
     pub fn apply_snapshot(&self, snapshot: ConfigSnapshot) -> ApplyResult {
         self.state.apply_snapshot(snapshot)
     }
+
+    /// Remove matching cached responses and return how many were removed.
+    pub async fn purge_cache(&self, purge: &CachePurge) -> Result<usize, String> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| "response cache is not attached".to_string())?;
+        Ok(cache.purge(purge).await)
+    }
 }
 
 /// RuntimeUpstreamHealthChecks periodically runs configured active health
-/// checks against compiled upstream groups from the current snapshot.
+/// checks and DNS re-resolution against upstream groups from the current
+/// snapshot.
 pub struct RuntimeUpstreamHealthChecks {
     state: Arc<RuntimeState>,
     snapshot_refresh_interval: Duration,
@@ -283,6 +325,9 @@ impl RuntimeUpstreamHealthChecks {
             let mut next_wake = now + self.snapshot_refresh_interval;
 
             for group in snapshot.upstream_groups.values() {
+                if let Some(next_run) = group.run_due_resolve(now).await {
+                    next_wake = next_wake.min(next_run);
+                }
                 if let Some(next_run) = group.run_due_health_check(now).await {
                     next_wake = next_wake.min(next_run);
                 }
@@ -325,6 +370,7 @@ struct RestartConfigFingerprint {
     allow_connect_method_proxying: bool,
     h2c: bool,
     keepalive_requests: Option<u32>,
+    proxy_cache_store: CacheStoreConfig,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -356,6 +402,75 @@ fn restart_fingerprint(router: &CompiledRouter) -> RestartConfigFingerprint {
         allow_connect_method_proxying: router.http_options.allow_connect_method_proxying,
         h2c: router.http_options.h2c,
         keepalive_requests: router.http_options.keepalive_requests,
+        proxy_cache_store: router.http_options.proxy_cache_store.clone(),
+    }
+}
+
+/// Describes the restart-only differences between the bootstrap fingerprint
+/// and `next`, so operators can see why a snapshot was not applied live.
+/// Only explains the decision: fingerprint inequality is what requires the
+/// restart, even for a difference this list does not name.
+fn restart_changes(
+    bootstrap: &RestartConfigFingerprint,
+    next: &RestartConfigFingerprint,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    for (key, current) in &bootstrap.listeners {
+        match next.listeners.get(key) {
+            None => changes.push(format!("listener {} removed", listener_label(key))),
+            Some(next) => {
+                if next.protocol != current.protocol {
+                    changes.push(format!(
+                        "listener {} protocol settings changed",
+                        listener_label(key)
+                    ));
+                }
+                if next.tls != current.tls {
+                    changes.push(format!(
+                        "listener {} TLS settings changed",
+                        listener_label(key)
+                    ));
+                }
+            }
+        }
+    }
+    for key in next.listeners.keys() {
+        if !bootstrap.listeners.contains_key(key) {
+            changes.push(format!("listener {} added", listener_label(key)));
+        }
+    }
+    if next.allow_connect_method_proxying != bootstrap.allow_connect_method_proxying {
+        changes.push("allow_connect_method_proxying changed".into());
+    }
+    if next.h2c != bootstrap.h2c {
+        changes.push("h2c changed".into());
+    }
+    if next.keepalive_requests != bootstrap.keepalive_requests {
+        changes.push("keepalive_requests changed".into());
+    }
+    if next.proxy_cache_store != bootstrap.proxy_cache_store {
+        changes.push("proxy_cache_store changed".into());
+    }
+
+    changes
+}
+
+fn restart_message(changes: &[String]) -> String {
+    let message = "listener or bootstrap transport settings changed; restart required";
+    if changes.is_empty() {
+        message.into()
+    } else {
+        format!("{message}: {}", changes.join(", "))
+    }
+}
+
+fn listener_label(key: &ListenKey) -> String {
+    let addr = std::net::SocketAddr::new(key.addr, key.port);
+    if key.ssl {
+        format!("{addr} ssl")
+    } else {
+        addr.to_string()
     }
 }
 
@@ -380,7 +495,8 @@ fn build_runtime_upstream_groups(
         .upstreams
         .iter()
         .map(|(name, group)| {
-            RuntimeUpstreamGroup::from_compiled(group).map(|group| (name.clone(), Arc::new(group)))
+            RuntimeUpstreamGroup::with_resolver(group, router.http_options.resolver.as_ref())
+                .map(|group| (name.clone(), Arc::new(group)))
         })
         .collect()
 }
@@ -1,9 +1,12 @@
-use super::{ConfigSnapshot, InProcessControlPlane, RuntimeState};
+use super::{ConfigSnapshot, InProcessControlPlane, RuntimeState, restart_message};
 use crate::upstreams::{
     CompiledLocation, CompiledMatcher, CompiledRouter, ListenKey, RouteTarget, ServerRoutes,
     VirtualHostRoutes,
 };
-use ngxora_compile::ir::{Http, Listen, Server, Switch, UpstreamSslOptions, UpstreamTimeouts};
+use ngxora_compile::ir::{
+    CacheStoreConfig, Http, Listen, Server, Switch, UpstreamBlock, UpstreamKeepalive,
+    UpstreamRetry, UpstreamSelectionPolicy, UpstreamServer, UpstreamSslOptions, UpstreamTimeouts,
+};
 use ngxora_plugin_api::PluginSpec;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr};
@@ -35,6 +38,8 @@ fn router_with_route_plugin(port: u16, plugin_name: &str) -> CompiledRouter {
     };
     let location = CompiledLocation {
         route_id: 1,
+        name: None,
+        access_logs: Vec::new(),
         matcher: CompiledMatcher::Prefix("/".into()),
         access_rules: Vec::new(),
         target: RouteTarget::ProxyPass {
@@ -44,6 +49,7 @@ fn router_with_route_plugin(port: u16, plugin_name: &str) -> CompiledRouter {
             sni: String::new(),
         },
         upstream_timeouts: UpstreamTimeouts::default(),
+        upstream_retry: UpstreamRetry::default(),
         upstream_protocol: None,
         upstream_ssl_options: UpstreamSslOptions::default(),
         plugins: vec![PluginSpec {
@@ -59,7 +65,9 @@ fn router_with_route_plugin(port: u16, plugin_name: &str) -> CompiledRouter {
             VirtualHostRoutes {
                 named: HashMap::new(),
                 default: Some(ServerRoutes {
+                    server_name: None,
                     locations: vec![location],
+                    request_id: None,
                 }),
             },
         )]),
@@ -98,6 +106,69 @@ fn runtime_state_applies_compatible_snapshot() {
     assert_eq!(snapshot.version, "v2");
 }
 
+fn router_with_upstream(port: u16, down: &[u16]) -> CompiledRouter {
+    let mut http = Http {
+        servers: vec![Server {
+            listens: vec![Listen {
+                addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                port,
+                ssl: false,
+                default_server: true,
+                ..Listen::default()
+            }],
+            ..Server::default()
+        }],
+        ..Http::default()
+    };
+    http.upstreams.push(UpstreamBlock {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers: [8080, 8081]
+            .into_iter()
+            .map(|backend_port| UpstreamServer {
+                host: "127.0.0.1".into(),
+                port: backend_port,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: down.contains(&backend_port),
+                slow_start: None,
+            })
+            .collect(),
+        health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    });
+
+    CompiledRouter::from_http(&http).expect("router compiles")
+}
+
+#[test]
+fn runtime_state_drains_down_servers_on_apply() {
+    let state = RuntimeState::new(ConfigSnapshot::new("v1", router_with_upstream(8080, &[])));
+    let selected_ports = |state: &RuntimeState| {
+        let snapshot = state.snapshot();
+        let group = snapshot.upstream_group("backend").expect("upstream group");
+        (0..4)
+            .map(|_| group.select(b"").expect("backend").server.port)
+            .collect::<Vec<_>>()
+    };
+    assert!(selected_ports(&state).contains(&8080));
+
+    let result = state.apply_snapshot(ConfigSnapshot::new(
+        "v2",
+        router_with_upstream(8080, &[8080]),
+    ));
+    assert!(result.applied && !result.restart_required);
+    assert_eq!(selected_ports(&state), vec![8081; 4]);
+
+    let result = state.apply_snapshot(ConfigSnapshot::new("v3", router_with_upstream(8080, &[])));
+    assert!(result.applied);
+    assert!(selected_ports(&state).contains(&8080));
+}
+
 #[test]
 fn tls_material_invalidation_does_not_change_active_snapshot() {
     let state = RuntimeState::new(ConfigSnapshot::new("v1", router_on_listener(8080)));
@@ -121,6 +192,13 @@ fn runtime_state_rejects_listener_topology_change() {
 
     assert!(!result.applied);
     assert!(result.restart_required);
+    assert_eq!(
+        result.restart_changes,
+        vec![
+            "listener 0.0.0.0:8080 removed".to_string(),
+            "listener 0.0.0.0:8443 added".to_string(),
+        ]
+    );
     assert_eq!(result.active_version, "v1");
     assert_eq!(result.active_generation, 1);
     assert_eq!(snapshot.version, "v1");
@@ -140,10 +218,38 @@ fn runtime_state_rejects_bootstrap_transport_change() {
 
     assert!(!result.applied);
     assert!(result.restart_required);
+    assert_eq!(result.restart_changes, vec!["h2c changed".to_string()]);
     assert_eq!(result.active_version, "v1");
     assert_eq!(snapshot.version, "v1");
 }
 
+#[test]
+fn runtime_state_rejects_cache_store_change() {
+    let state = RuntimeState::new(ConfigSnapshot::new("v1", router_on_listener(8080)));
+    let mut next = router_on_listener(8080);
+    next.http_options.proxy_cache_store =
+        CacheStoreConfig::redis("redis://127.0.0.1:6379", None).expect("valid redis URL");
+    let result = state.apply_snapshot(ConfigSnapshot::new("v2", next));
+
+    assert!(!result.applied);
+    assert_eq!(
+        result.restart_changes,
+        vec!["proxy_cache_store changed".to_string()]
+    );
+}
+
+#[test]
+fn restart_message_falls_back_without_named_changes() {
+    assert_eq!(
+        restart_message(&[]),
+        "listener or bootstrap transport settings changed; restart required"
+    );
+    assert_eq!(
+        restart_message(&["h2c changed".to_string()]),
+        "listener or bootstrap transport settings changed; restart required: h2c changed"
+    );
+}
+
 #[test]
 fn in_process_control_plane_delegates_to_runtime_state() {
     let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
@@ -1,18 +1,28 @@
+use crate::cache::CachePurge;
 use crate::control::{
     ApplyResult as RuntimeApplyResult, ConfigSnapshot as RuntimeConfigSnapshot,
     InProcessControlPlane, RuntimeSnapshot,
 };
 use crate::upstreams::{
     CompiledLocation, CompiledMatcher, CompiledRouter, HttpRuntimeOptions, ListenKey, RouteTarget,
-    ServerRoutes, VirtualHostRoutes,
+    ServerRoutes, StaticFallback, StaticFiles, StaticRoot, VirtualHostRoutes,
 };
+use ipnet::IpNet;
 use ngxora_compile::ir::{
-    CacheConfig, CacheKeyMode, DownstreamTlsOptions, Http, KeepaliveTimeout, LetsEncryptConfig,
-    Listen, Location, LocationDirective, LocationMatcher, PemSource, ProxyPassTarget, Server,
-    SslProvider, Switch, TlsIdentity, TlsProtocolBounds, TlsProtocolVersion, TlsVerifyClient,
+    ACCESS_LOG_SAMPLE_SCALE, AccessLog, AccessLogTarget, AcmeChallenge, AcmeDnsProvider,
+    AcmeIssuer, AcmeKeyType, CacheConfig, CacheKeyMode, CacheStoreConfig,
+    DEFAULT_DNS_PROPAGATION_TIMEOUT, DEFAULT_HEALTH_CHECK_STATUSES, DEFAULT_UPSTREAM_QUEUE_TIMEOUT,
+    DnsResolver, DownstreamTlsOptions, ExternalAccountBinding, HealthCheckBodyMatch, Http,
+    KeepaliveTimeout, LETSENCRYPT_ISSUER, Listen, Location, LocationDirective, LocationMatcher,
+    LogEncoding, LogFormat, MAX_UPSTREAM_SERVER_WEIGHT, NextUpstream, PemSource, ProxyPassTarget,
+    RequestId, RequestIdFormat, Rfc2136Provider, Server, SslProvider, Switch, TlsIdentity,
+    TlsProtocolBounds, TlsProtocolVersion, TlsVerifyClient, TryFilesFallback, TsigKey,
     UpstreamBlock, UpstreamHealthCheck, UpstreamHealthCheckType, UpstreamHttpProtocol,
+    UpstreamKeepalive, UpstreamOutlierDetection, UpstreamQueue, UpstreamRetry,
     UpstreamSelectionPolicy, UpstreamServer, UpstreamSslOptions, UpstreamTimeouts,
 };
+use ngxora_compile::variables::Template;
+use ngxora_config::Node;
 use ngxora_plugin_api::PluginSpec;
 use serde_json::Value;
 use std::collections::{BTreeMap, HashMap};
@@ -39,19 +49,30 @@ pub mod proto {
 
 use proto::control_plane_server::{ControlPlane, ControlPlaneServer};
 use proto::{
-    ApplyResult as ProtoApplyResult, CacheKeyMode as ProtoCacheKeyMode,
-    ConfigSnapshot as ProtoConfigSnapshot, GetSnapshotRequest as ProtoGetSnapshotRequest,
-    HttpOptions as ProtoHttpOptions, LetsEncryptConfig as ProtoLetsEncryptConfig,
-    Listener as ProtoListener, ListenerTlsOptions as ProtoListenerTlsOptions, Match as ProtoMatch,
-    PemSource as ProtoPemSource, Plugin as ProtoPlugin, Redirect as ProtoRedirect,
-    Regex as ProtoRegex, Route as ProtoRoute, RouteCache as ProtoRouteCache,
-    RouteTimeouts as ProtoRouteTimeouts, Switch as ProtoSwitch, TlsBinding as ProtoTlsBinding,
+    AccessLog as ProtoAccessLog, AcmeChallenge as ProtoAcmeChallenge,
+    AcmeDnsProvider as ProtoAcmeDnsProvider, AcmeIssuer as ProtoAcmeIssuer,
+    AcmeKeyType as ProtoAcmeKeyType, ApplyResult as ProtoApplyResult,
+    CacheKeyMode as ProtoCacheKeyMode, CacheStore as ProtoCacheStore,
+    ConfigSnapshot as ProtoConfigSnapshot, DnsResolver as ProtoDnsResolver,
+    ExternalAccountBinding as ProtoExternalAccountBinding,
+    GetSnapshotRequest as ProtoGetSnapshotRequest, HealthCheckHeader as ProtoHealthCheckHeader,
+    HttpOptions as ProtoHttpOptions, HttpStatusRange as ProtoHttpStatusRange,
+    Listener as ProtoListener, ListenerTlsOptions as ProtoListenerTlsOptions,
+    LogEncoding as ProtoLogEncoding, LogFormat as ProtoLogFormat, Match as ProtoMatch,
+    PemSource as ProtoPemSource, Plugin as ProtoPlugin,
+    PurgeCacheRequest as ProtoPurgeCacheRequest, PurgeCacheResult as ProtoPurgeCacheResult,
+    Redirect as ProtoRedirect, Regex as ProtoRegex, RequestId as ProtoRequestId,
+    RequestIdFormat as ProtoRequestIdFormat, Rfc2136DnsProvider as ProtoRfc2136DnsProvider,
+    Route as ProtoRoute, RouteCache as ProtoRouteCache, RouteTimeouts as ProtoRouteTimeouts,
+    StaticFiles as ProtoStaticFiles, Switch as ProtoSwitch, TlsBinding as ProtoTlsBinding,
     TlsProtocolVersion as ProtoTlsProtocolVersion, TlsVerifyClient as ProtoTlsVerifyClient,
-    Upstream as ProtoUpstream, UpstreamBackend as ProtoUpstreamBackend,
-    UpstreamGroup as ProtoUpstreamGroup, UpstreamHealthCheck as ProtoUpstreamHealthCheck,
+    TsigKey as ProtoTsigKey, Upstream as ProtoUpstream, UpstreamBackend as ProtoUpstreamBackend,
+    UpstreamGroup as ProtoUpstreamGroup, UpstreamGrpcHealthCheck as ProtoUpstreamGrpcHealthCheck,
+    UpstreamHealthCheck as ProtoUpstreamHealthCheck,
     UpstreamHttpHealthCheck as ProtoUpstreamHttpHealthCheck,
-    UpstreamHttpProtocol as ProtoUpstreamHttpProtocol,
-    UpstreamSelectionPolicy as ProtoUpstreamSelectionPolicy,
+    UpstreamHttpProtocol as ProtoUpstreamHttpProtocol, UpstreamKeepalive as ProtoUpstreamKeepalive,
+    UpstreamOutlierDetection as ProtoUpstreamOutlierDetection, UpstreamQueue as ProtoUpstreamQueue,
+    UpstreamRetry as ProtoUpstreamRetry, UpstreamSelectionPolicy as ProtoUpstreamSelectionPolicy,
     UpstreamTcpHealthCheck as ProtoUpstreamTcpHealthCheck,
     UpstreamTlsOptions as ProtoUpstreamTlsOptions, VirtualHost as ProtoVirtualHost,
 };
@@ -95,6 +116,57 @@ impl ControlPlane for GrpcControlPlane {
             proto_snapshot_from_runtime(snapshot.as_ref()).map_err(Status::failed_precondition)?;
         Ok(Response::new(response))
     }
+
+    async fn purge_cache(
+        &self,
+        request: Request<ProtoPurgeCacheRequest>,
+    ) -> Result<Response<ProtoPurgeCacheResult>, Status> {
+        let purge = cache_purge_from_proto(
+            &self.control.state().snapshot().router,
+            request.into_inner(),
+        )
+        .map_err(Status::invalid_argument)?;
+        let purged = self
+            .control
+            .purge_cache(&purge)
+            .await
+            .map_err(Status::failed_precondition)?;
+        Ok(Response::new(ProtoPurgeCacheResult {
+            purged: purged as u64,
+        }))
+    }
+}
+
+fn cache_purge_from_proto(
+    router: &CompiledRouter,
+    request: ProtoPurgeCacheRequest,
+) -> Result<CachePurge, String> {
+    use proto::purge_cache_request::Target;
+
+    match request.target {
+        Some(Target::Exact(exact)) => {
+            if exact.host.is_empty() || !exact.uri.starts_with('/') {
+                return Err("exact purge requires a host and a URI starting with `/`".into());
+            }
+            Ok(CachePurge::Exact {
+                host: exact.host,
+                uri: exact.uri,
+            })
+        }
+        Some(Target::Prefix(prefix)) => {
+            if !prefix.prefix.starts_with('/') {
+                return Err("prefix purge requires a prefix starting with `/`".into());
+            }
+            Ok(CachePurge::Prefix {
+                host: (!prefix.host.is_empty()).then_some(prefix.host),
+                prefix: prefix.prefix,
+            })
+        }
+        Some(Target::RouteId(route_id)) => CachePurge::route(router, route_id)
+            .ok_or_else(|| format!("unknown route_id {route_id}")),
+        Some(Target::All(true)) => Ok(CachePurge::All),
+        Some(Target::All(false)) | None => Err("purge request requires a target".into()),
+    }
 }
 
 /// Runs the gRPC control plane on the provided socket address.
@@ -227,6 +299,7 @@ fn proto_apply_result(result: RuntimeApplyResult) -> ProtoApplyResult {
         applied: result.applied,
         restart_required: result.restart_required,
         message: result.message,
+        restart_changes: result.restart_changes,
         active_version: result.active_version,
         active_generation: result.active_generation,
     }
@@ -246,7 +319,7 @@ fn runtime_snapshot_from_proto(
 // Reconstruct the shared IR shape from the wire snapshot so protobuf input goes
 // through the same validation and compilation path as other config sources.
 fn http_from_proto_snapshot(snapshot: &ProtoConfigSnapshot) -> Result<Http, String> {
-    let options = snapshot.http.unwrap_or_default();
+    let options = snapshot.http.clone().unwrap_or_default();
     let listener_defs = listener_defs(&snapshot.listeners)?;
     let mut servers = Vec::with_capacity(snapshot.virtual_hosts.len());
 
@@ -274,7 +347,20 @@ fn http_from_proto_snapshot(snapshot: &ProtoConfigSnapshot) -> Result<Http, Stri
         allow_connect_method_proxying: switch_from_bool(options.allow_connect_method_proxying),
         h2c: switch_from_bool(options.h2c),
         proxy_cache_max_size: none_if_zero_u64(options.proxy_cache_max_size_bytes),
-        ssl_provider: snapshot.le_config.as_ref().map(le_config_from_proto),
+        proxy_cache_store: cache_store_from_proto(options.proxy_cache_store.as_ref())?,
+        acme_issuers: acme_issuers_from_proto(snapshot)?,
+        resolver: options
+            .resolver
+            .as_ref()
+            .map(dns_resolver_from_proto)
+            .transpose()?,
+        metrics_label_limit: none_if_zero(options.metrics_label_limit).map(|limit| limit as usize),
+        log_formats: options
+            .log_formats
+            .iter()
+            .map(log_format_from_proto)
+            .collect::<Result<Vec<_>, _>>()?,
+        access_logs: access_logs_from_proto(&options.access_logs)?,
     })
 }
 
@@ -300,13 +386,29 @@ fn upstreams_from_proto(upstreams: &[ProtoUpstreamGroup]) -> Result<Vec<Upstream
 
             Ok(UpstreamBlock {
                 name: name.to_string(),
-                policy: upstream_selection_policy_from_proto(upstream.policy)?,
+                policy: upstream_selection_policy_from_proto(upstream.policy, &upstream.hash_key)
+                    .map_err(|err| format!("upstream `{name}`: {err}"))?,
                 servers,
                 health_check: upstream
                     .health_check
                     .as_ref()
                     .map(upstream_health_check_from_proto)
                     .transpose()?,
+                outlier_detection: upstream
+                    .outlier_detection
+                    .as_ref()
+                    .map(upstream_outlier_detection_from_proto)
+                    .transpose()?,
+                keepalive: upstream
+                    .keepalive
+                    .as_ref()
+                    .map(upstream_keepalive_from_proto)
+                    .unwrap_or_default(),
+                queue: upstream
+                    .queue
+                    .as_ref()
+                    .map(upstream_queue_from_proto)
+                    .transpose()?,
             })
         })
         .collect()
@@ -338,13 +440,20 @@ fn server_from_proto_virtual_host(
     listener: &ListenerDef,
     virtual_host: &ProtoVirtualHost,
 ) -> Result<Server, String> {
-    if listener.listen.ssl && virtual_host.tls.is_none() {
+    let uses_acme = !virtual_host.acme_issuers.is_empty();
+    if listener.listen.ssl && virtual_host.tls.is_none() && !uses_acme {
         return Err(format!(
-            "virtual host on listener `{}` requires a TLS binding",
+            "virtual host on listener `{}` requires a TLS binding or acme_issuers",
             listener.name
         ));
     }
-    if !listener.listen.ssl && virtual_host.tls.is_some() {
+    if uses_acme && virtual_host.tls.is_some() {
+        return Err(format!(
+            "virtual host on listener `{}` cannot define both a TLS binding and acme_issuers",
+            listener.name
+        ));
+    }
+    if !listener.listen.ssl && (virtual_host.tls.is_some() || uses_acme) {
         return Err(format!(
             "virtual host on listener `{}` cannot define TLS binding on a plaintext listener",
             listener.name
@@ -362,16 +471,66 @@ fn server_from_proto_virtual_host(
             default_server: virtual_host.default_server,
             ..listener.listen.clone()
         }],
-        tls: virtual_host
-            .tls
-            .as_ref()
-            .map(tls_identity_from_proto)
-            .transpose()?
-            .map(SslProvider::Custom),
+        tls: if uses_acme {
+            Some(SslProvider::Acme(virtual_host.acme_issuers.clone()))
+        } else {
+            virtual_host
+                .tls
+                .as_ref()
+                .map(tls_identity_from_proto)
+                .transpose()?
+                .map(SslProvider::Custom)
+        },
         tls_options: listener.tls_options.clone(),
+        access_logs: Vec::new(),
+        request_id: virtual_host
+            .request_id
+            .as_ref()
+            .map(request_id_from_proto)
+            .transpose()?,
     })
 }
 
+fn request_id_from_proto(request_id: &ProtoRequestId) -> Result<RequestId, String> {
+    Ok(RequestId {
+        header: if request_id.header.is_empty() {
+            RequestId::default().header
+        } else {
+            RequestId::header_name(&request_id.header)?
+        },
+        trusted: request_id
+            .trusted
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("request_id has invalid trusted network `{network}`"))
+            })
+            .collect::<Result<_, _>>()?,
+        format: match ProtoRequestIdFormat::try_from(request_id.format) {
+            Ok(ProtoRequestIdFormat::Unspecified | ProtoRequestIdFormat::Uuidv7) => {
+                RequestIdFormat::UuidV7
+            }
+            Ok(ProtoRequestIdFormat::Ulid) => RequestIdFormat::Ulid,
+            Err(_) => {
+                return Err(format!("unknown request ID format `{}`", request_id.format));
+            }
+        },
+    })
+}
+
+fn proto_request_id_from_runtime(request_id: &RequestId) -> ProtoRequestId {
+    ProtoRequestId {
+        header: request_id.header.clone(),
+        trusted: request_id.trusted.iter().map(ToString::to_string).collect(),
+        format: match request_id.format {
+            RequestIdFormat::UuidV7 => ProtoRequestIdFormat::Uuidv7,
+            RequestIdFormat::Ulid => ProtoRequestIdFormat::Ulid,
+        } as i32,
+    }
+}
+
 fn location_from_proto_route(route: &ProtoRoute) -> Result<Location, String> {
     let matcher = matcher_from_proto(route.r#match.as_ref())?;
     let mut directives = Vec::with_capacity(7);
@@ -409,6 +568,20 @@ fn location_from_proto_route(route: &ProtoRoute) -> Result<Location, String> {
         directives.push(LocationDirective::ProxyUpstreamProtocol(protocol));
     }
 
+    if let Some(retry) = route.retry.as_ref() {
+        directives.extend(upstream_retry_directives_from_proto(retry)?);
+    }
+
+    if !route.name.is_empty() {
+        directives.push(LocationDirective::RouteName(route.name.clone()));
+    }
+
+    directives.extend(
+        access_logs_from_proto(&route.access_logs)?
+            .into_iter()
+            .map(LocationDirective::AccessLog),
+    );
+
     let action = route
         .action
         .as_ref()
@@ -422,6 +595,9 @@ fn location_from_proto_route(route: &ProtoRoute) -> Result<Location, String> {
         proto::route::Action::Redirect(redirect) => {
             directives.push(return_directive_from_proto(redirect)?);
         }
+        proto::route::Action::StaticFiles(files) => {
+            directives.extend(static_files_directives_from_proto(files)?);
+        }
     }
 
     Ok(Location {
@@ -448,10 +624,23 @@ fn upstream_backend_from_proto(backend: &ProtoUpstreamBackend) -> Result<Upstrea
         ));
     }
 
+    if backend.weight > MAX_UPSTREAM_SERVER_WEIGHT {
+        return Err(format!(
+            "upstream backend `{}` weight must be at most {MAX_UPSTREAM_SERVER_WEIGHT}",
+            backend.host
+        ));
+    }
+
     Ok(UpstreamServer {
         host: backend.host.clone(),
         port: u16::try_from(backend.port)
             .map_err(|_| format!("upstream backend `{}` port is out of range", backend.host))?,
+        weight: backend.weight.max(1),
+        resolve: backend.resolve,
+        max_conns: (backend.max_conns > 0).then_some(backend.max_conns as usize),
+        backup: backend.backup,
+        down: backend.down,
+        slow_start: duration_from_millis(backend.slow_start_ms),
     })
 }
 
@@ -486,16 +675,66 @@ fn upstream_health_check_from_proto(
             if http.path.is_empty() {
                 return Err("upstream health_check http path cannot be empty".into());
             }
+            let statuses = if http.statuses.is_empty() {
+                vec![DEFAULT_HEALTH_CHECK_STATUSES]
+            } else {
+                http.statuses
+                    .iter()
+                    .map(|range| {
+                        let max = if range.max == 0 { range.min } else { range.max };
+                        match (u16::try_from(range.min), u16::try_from(max)) {
+                            (Ok(min), Ok(max)) => Ok(min..=max),
+                            _ => Err(format!(
+                                "upstream health_check status range {}-{max} is out of range",
+                                range.min
+                            )),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?
+            };
             UpstreamHealthCheckType::Http {
                 host: http.host.clone(),
                 path: http.path.clone(),
                 use_tls: http.use_tls,
+                method: if http.method.is_empty() {
+                    "GET".into()
+                } else {
+                    http.method.clone()
+                },
+                headers: http
+                    .headers
+                    .iter()
+                    .map(|header| (header.name.clone(), header.value.clone()))
+                    .collect(),
+                statuses,
+                body: match &http.body {
+                    Some(proto::upstream_http_health_check::Body::BodyContains(text)) => {
+                        Some(HealthCheckBodyMatch::Contains(text.clone()))
+                    }
+                    Some(proto::upstream_http_health_check::Body::BodyRegex(pattern)) => {
+                        Some(HealthCheckBodyMatch::Regex(pattern.clone()))
+                    }
+                    None => None,
+                },
             }
         }
+        proto::upstream_health_check::Kind::Grpc(grpc) => UpstreamHealthCheckType::Grpc {
+            service: grpc.service.clone(),
+            host: (!grpc.host.is_empty()).then(|| grpc.host.clone()),
+            use_tls: grpc.use_tls,
+        },
+    };
+    let port = match health_check.port {
+        0 => None,
+        port => Some(
+            u16::try_from(port)
+                .map_err(|_| "upstream health_check port is out of range".to_string())?,
+        ),
     };
 
     Ok(UpstreamHealthCheck {
         check_type,
+        port,
         timeout,
         interval,
         consecutive_success,
@@ -503,6 +742,61 @@ fn upstream_health_check_from_proto(
     })
 }
 
+fn upstream_outlier_detection_from_proto(
+    outlier: &ProtoUpstreamOutlierDetection,
+) -> Result<UpstreamOutlierDetection, String> {
+    let defaults = UpstreamOutlierDetection::default();
+    let base_ejection_time =
+        duration_from_millis(outlier.base_ejection_time_ms).unwrap_or(defaults.base_ejection_time);
+    let max_ejection_time = duration_from_millis(outlier.max_ejection_time_ms)
+        .unwrap_or(defaults.max_ejection_time.max(base_ejection_time));
+    if max_ejection_time < base_ejection_time {
+        return Err(
+            "upstream outlier_detection max_ejection_time must not be shorter than base_ejection_time"
+                .into(),
+        );
+    }
+    let max_ejection_percent = match outlier.max_ejection_percent {
+        0 => defaults.max_ejection_percent,
+        value @ 1..=100 => value as u8,
+        _ => {
+            return Err(
+                "upstream outlier_detection max_ejection_percent must be between 1 and 100".into(),
+            );
+        }
+    };
+
+    Ok(UpstreamOutlierDetection {
+        consecutive_failure: match outlier.consecutive_failure {
+            0 => defaults.consecutive_failure,
+            value => usize::try_from(value).map_err(|_| {
+                "upstream outlier_detection consecutive_failure is out of range".to_string()
+            })?,
+        },
+        base_ejection_time,
+        max_ejection_time,
+        max_ejection_percent,
+    })
+}
+
+fn upstream_keepalive_from_proto(keepalive: &ProtoUpstreamKeepalive) -> UpstreamKeepalive {
+    UpstreamKeepalive {
+        connections: (keepalive.connections > 0).then_some(keepalive.connections as usize),
+        timeout: duration_from_millis(keepalive.timeout_ms),
+        requests: (keepalive.requests > 0).then_some(keepalive.requests as usize),
+    }
+}
+
+fn upstream_queue_from_proto(queue: &ProtoUpstreamQueue) -> Result<UpstreamQueue, String> {
+    if queue.size == 0 {
+        return Err("upstream queue size must be greater than zero".into());
+    }
+    Ok(UpstreamQueue {
+        size: queue.size as usize,
+        timeout: duration_from_millis(queue.timeout_ms).unwrap_or(DEFAULT_UPSTREAM_QUEUE_TIMEOUT),
+    })
+}
+
 fn matcher_from_proto(value: Option<&ProtoMatch>) -> Result<LocationMatcher, String> {
     let matcher = value.ok_or_else(|| "route match is required".to_string())?;
     let kind = matcher
@@ -572,6 +866,46 @@ fn return_directive_from_proto(redirect: &ProtoRedirect) -> Result<LocationDirec
     })
 }
 
+fn static_files_directives_from_proto(
+    files: &ProtoStaticFiles,
+) -> Result<Vec<LocationDirective>, String> {
+    let mut directives = Vec::with_capacity(3);
+    match (files.root.is_empty(), files.alias.is_empty()) {
+        (false, true) => directives.push(LocationDirective::Root(files.root.clone())),
+        (true, false) => directives.push(LocationDirective::Alias(files.alias.clone())),
+        _ => return Err("static_files requires exactly one of root or alias".into()),
+    }
+
+    if !files.index.is_empty() {
+        directives.push(LocationDirective::Index(files.index.clone()));
+    }
+
+    match (files.try_files.is_empty(), files.fallback.as_ref()) {
+        (true, None) => {}
+        (false, Some(fallback)) => directives.push(LocationDirective::TryFiles {
+            files: files.try_files.clone(),
+            fallback: match fallback {
+                proto::static_files::Fallback::FallbackStatus(status) => TryFilesFallback::Status(
+                    u16::try_from(*status)
+                        .ok()
+                        .filter(|status| (200..=599).contains(status))
+                        .ok_or_else(|| format!("try_files fallback status {status} is invalid"))?,
+                ),
+                proto::static_files::Fallback::FallbackNamedLocation(name) => {
+                    TryFilesFallback::Named(name.clone())
+                }
+                proto::static_files::Fallback::FallbackUri(uri) => {
+                    TryFilesFallback::Uri(uri.clone())
+                }
+            },
+        }),
+        (false, None) => return Err("static_files try_files requires a fallback".into()),
+        (true, Some(_)) => return Err("static_files fallback requires try_files".into()),
+    }
+
+    Ok(directives)
+}
+
 fn route_cache_from_proto(cache: Option<&ProtoRouteCache>) -> Result<Option<CacheConfig>, String> {
     let Some(cache) = cache else {
         return Ok(None);
@@ -751,28 +1085,172 @@ fn upstream_tls_options_from_proto(
     }))
 }
 
-// ── LetsEncryptConfig ↔ proto ──
+// ── AcmeIssuer ↔ proto ──
 
-fn le_config_from_proto(value: &ProtoLetsEncryptConfig) -> LetsEncryptConfig {
-    LetsEncryptConfig {
+/// The legacy `le_config` issuer followed by `acme_issuers`.
+fn acme_issuers_from_proto(snapshot: &ProtoConfigSnapshot) -> Result<Vec<AcmeIssuer>, String> {
+    let mut issuers: Vec<AcmeIssuer> = Vec::new();
+    if let Some(config) = &snapshot.le_config {
+        issuers.push(AcmeIssuer {
+            acme_directory: none_if_empty(config.acme_directory.clone()),
+            email: none_if_empty(config.email.clone()),
+            cache_dir: none_if_empty(config.cache_dir.clone()).map(PathBuf::from),
+            challenge: acme_challenge_from_proto(config.challenge, config.dns_provider.as_ref())?,
+            ..AcmeIssuer::new(LETSENCRYPT_ISSUER)
+        });
+    }
+    for value in &snapshot.acme_issuers {
+        let issuer = acme_issuer_from_proto(value)?;
+        if issuers.iter().any(|known| known.name == issuer.name) {
+            return Err(format!("ACME issuer `{}` is duplicated", issuer.name));
+        }
+        issuers.push(issuer);
+    }
+    Ok(issuers)
+}
+
+fn acme_issuer_from_proto(value: &ProtoAcmeIssuer) -> Result<AcmeIssuer, String> {
+    AcmeIssuer::validate_name(&value.name)?;
+    if value.acme_directory.is_empty() && value.name != LETSENCRYPT_ISSUER {
+        return Err(format!(
+            "ACME issuer `{}` needs an acme_directory",
+            value.name
+        ));
+    }
+    Ok(AcmeIssuer {
+        name: value.name.clone(),
         acme_directory: none_if_empty(value.acme_directory.clone()),
         email: none_if_empty(value.email.clone()),
         cache_dir: none_if_empty(value.cache_dir.clone()).map(PathBuf::from),
+        challenge: acme_challenge_from_proto(value.challenge, value.dns_provider.as_ref())?,
+        external_account_binding: value
+            .external_account_binding
+            .as_ref()
+            .map(|binding| ExternalAccountBinding::new(&binding.key_id, &binding.hmac_key))
+            .transpose()?,
+        key_type: match ProtoAcmeKeyType::try_from(value.key_type) {
+            Ok(ProtoAcmeKeyType::Unspecified | ProtoAcmeKeyType::Ecdsa) => AcmeKeyType::Ecdsa,
+            Ok(ProtoAcmeKeyType::Rsa) => AcmeKeyType::Rsa,
+            Err(_) => return Err(format!("unknown ACME key type `{}`", value.key_type)),
+        },
+        trusted_certificate: none_if_empty(value.trusted_certificate.clone()).map(PathBuf::from),
+    })
+}
+
+fn acme_challenge_from_proto(
+    challenge: i32,
+    dns_provider: Option<&ProtoAcmeDnsProvider>,
+) -> Result<AcmeChallenge, String> {
+    let challenge = match ProtoAcmeChallenge::try_from(challenge) {
+        Ok(ProtoAcmeChallenge::Unspecified | ProtoAcmeChallenge::Http01) => AcmeChallenge::Http01,
+        Ok(ProtoAcmeChallenge::TlsAlpn01) => AcmeChallenge::TlsAlpn01,
+        Ok(ProtoAcmeChallenge::Dns01) => {
+            AcmeChallenge::Dns01(acme_dns_provider_from_proto(dns_provider)?)
+        }
+        Err(_) => return Err(format!("unknown ACME challenge `{challenge}`")),
+    };
+    if dns_provider.is_some() && !matches!(challenge, AcmeChallenge::Dns01(_)) {
+        return Err("ACME dns_provider requires the DNS-01 challenge".into());
     }
+    Ok(challenge)
 }
 
-fn proto_le_config_from_ir(value: &LetsEncryptConfig) -> ProtoLetsEncryptConfig {
-    ProtoLetsEncryptConfig {
+fn acme_dns_provider_from_proto(
+    value: Option<&ProtoAcmeDnsProvider>,
+) -> Result<AcmeDnsProvider, String> {
+    match value.and_then(|provider| provider.provider.as_ref()) {
+        Some(proto::acme_dns_provider::Provider::Rfc2136(rfc2136)) => {
+            if rfc2136.zone.is_empty() {
+                return Err("rfc2136 DNS provider needs a zone".into());
+            }
+            Ok(AcmeDnsProvider::Rfc2136(Rfc2136Provider {
+                server: Rfc2136Provider::parse_server(&rfc2136.server)?,
+                zone: rfc2136.zone.trim_end_matches('.').to_ascii_lowercase(),
+                tsig_key: rfc2136
+                    .tsig_key
+                    .as_ref()
+                    .map(|key| TsigKey::new(&key.name, &key.algorithm, &key.secret))
+                    .transpose()?,
+                propagation_timeout: duration_from_millis(rfc2136.propagation_timeout_ms)
+                    .unwrap_or(DEFAULT_DNS_PROPAGATION_TIMEOUT),
+            }))
+        }
+        None => Err("the DNS-01 challenge needs a dns_provider".into()),
+    }
+}
+
+fn proto_acme_issuer_from_ir(value: &AcmeIssuer) -> ProtoAcmeIssuer {
+    let (challenge, dns_provider) = match &value.challenge {
+        AcmeChallenge::Http01 => (ProtoAcmeChallenge::Http01, None),
+        AcmeChallenge::TlsAlpn01 => (ProtoAcmeChallenge::TlsAlpn01, None),
+        AcmeChallenge::Dns01(AcmeDnsProvider::Rfc2136(rfc2136)) => (
+            ProtoAcmeChallenge::Dns01,
+            Some(ProtoAcmeDnsProvider {
+                provider: Some(proto::acme_dns_provider::Provider::Rfc2136(
+                    ProtoRfc2136DnsProvider {
+                        server: rfc2136.server.to_string(),
+                        zone: rfc2136.zone.clone(),
+                        tsig_key: rfc2136.tsig_key.as_ref().map(|key| ProtoTsigKey {
+                            name: key.name.clone(),
+                            algorithm: key.algorithm.name().to_string(),
+                            secret: key.secret.clone(),
+                        }),
+                        propagation_timeout_ms: duration_to_millis(Some(
+                            rfc2136.propagation_timeout,
+                        )),
+                    },
+                )),
+            }),
+        ),
+    };
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default()
+    };
+    ProtoAcmeIssuer {
+        name: value.name.clone(),
         acme_directory: value.acme_directory.clone().unwrap_or_default(),
         email: value.email.clone().unwrap_or_default(),
-        cache_dir: value
-            .cache_dir
-            .as_ref()
-            .map(|p| p.display().to_string())
-            .unwrap_or_default(),
+        cache_dir: path(&value.cache_dir),
+        challenge: challenge as i32,
+        dns_provider,
+        external_account_binding: value.external_account_binding.as_ref().map(|binding| {
+            ProtoExternalAccountBinding {
+                key_id: binding.key_id.clone(),
+                hmac_key: binding.hmac_key.clone(),
+            }
+        }),
+        key_type: match value.key_type {
+            AcmeKeyType::Ecdsa => ProtoAcmeKeyType::Ecdsa,
+            AcmeKeyType::Rsa => ProtoAcmeKeyType::Rsa,
+        } as i32,
+        trusted_certificate: path(&value.trusted_certificate),
     }
 }
 
+/// Renders `snapshot` as a text config that compiles to the same routes, by
+/// way of the protobuf shape `GetSnapshot` returns. Fails for settings that
+/// only the control plane can express, such as inline PEM certificates.
+/// With `redact`, credentials are printed as `<redacted>` (see
+/// [`Http::redact_secrets`]) and the output no longer compiles back.
+pub fn render_snapshot_config(snapshot: &RuntimeSnapshot, redact: bool) -> Result<String, String> {
+    let proto = proto_snapshot_from_runtime(snapshot)?;
+    let mut http = http_from_proto_snapshot(&proto)?;
+    if redact {
+        http.redact_secrets();
+    }
+    let mut ast = http.to_ast()?;
+    if let Some(Node::Block(block)) = ast.items.first_mut() {
+        block.comments.leading = vec![format!(
+            "# snapshot {} (generation {})",
+            snapshot.version.replace(['\r', '\n'], " "),
+            snapshot.generation
+        )];
+    }
+    Ok(ast.to_string())
+}
+
 // Export the active runtime snapshot back to the protobuf shape used by the
 // local control-plane. This path is primarily for GetSnapshot and tests.
 fn proto_snapshot_from_runtime(snapshot: &RuntimeSnapshot) -> Result<ProtoConfigSnapshot, String> {
@@ -806,6 +1284,7 @@ fn proto_snapshot_from_runtime(snapshot: &RuntimeSnapshot) -> Result<ProtoConfig
             &listener_name,
             routes,
             tls,
+            &snapshot.router.acme_certificates,
         )?);
     }
 
@@ -817,11 +1296,13 @@ fn proto_snapshot_from_runtime(snapshot: &RuntimeSnapshot) -> Result<ProtoConfig
         listeners,
         virtual_hosts,
         upstreams: proto_upstreams_from_runtime(&snapshot.router.upstreams),
-        le_config: snapshot
+        le_config: None,
+        acme_issuers: snapshot
             .router
-            .le_config
-            .as_ref()
-            .map(proto_le_config_from_ir),
+            .acme_issuers
+            .iter()
+            .map(proto_acme_issuer_from_ir)
+            .collect(),
     })
 }
 
@@ -833,21 +1314,58 @@ fn proto_upstreams_from_runtime(
 
     groups
         .into_iter()
-        .map(|group| ProtoUpstreamGroup {
-            name: group.name,
-            backends: group
-                .servers
-                .into_iter()
-                .map(|server| ProtoUpstreamBackend {
-                    host: server.host,
-                    port: u32::from(server.port),
-                })
-                .collect(),
-            policy: proto_upstream_selection_policy_from_runtime(group.policy) as i32,
-            health_check: group
-                .health_check
-                .as_ref()
-                .map(proto_upstream_health_check_from_runtime),
+        .map(|group| {
+            let (policy, hash_key) = proto_upstream_selection_policy_from_runtime(&group.policy);
+            ProtoUpstreamGroup {
+                name: group.name,
+                backends: group
+                    .servers
+                    .into_iter()
+                    .map(|server| ProtoUpstreamBackend {
+                        host: server.host,
+                        port: u32::from(server.port),
+                        weight: server.weight,
+                        resolve: server.resolve,
+                        max_conns: server
+                            .max_conns
+                            .map_or(0, |max| u32::try_from(max).unwrap_or(u32::MAX)),
+                        backup: server.backup,
+                        down: server.down,
+                        slow_start_ms: duration_to_millis(server.slow_start),
+                    })
+                    .collect(),
+                policy: policy as i32,
+                health_check: group
+                    .health_check
+                    .as_ref()
+                    .map(proto_upstream_health_check_from_runtime),
+                hash_key,
+                outlier_detection: group.outlier_detection.as_ref().map(|outlier| {
+                    ProtoUpstreamOutlierDetection {
+                        consecutive_failure: outlier
+                            .consecutive_failure
+                            .try_into()
+                            .unwrap_or(u32::MAX),
+                        base_ejection_time_ms: duration_to_millis(Some(outlier.base_ejection_time)),
+                        max_ejection_time_ms: duration_to_millis(Some(outlier.max_ejection_time)),
+                        max_ejection_percent: u32::from(outlier.max_ejection_percent),
+                    }
+                }),
+                keepalive: (group.keepalive != UpstreamKeepalive::default()).then(|| {
+                    let count = |value: Option<usize>| {
+                        value.map_or(0, |value| u32::try_from(value).unwrap_or(u32::MAX))
+                    };
+                    ProtoUpstreamKeepalive {
+                        connections: count(group.keepalive.connections),
+                        timeout_ms: duration_to_millis(group.keepalive.timeout),
+                        requests: count(group.keepalive.requests),
+                    }
+                }),
+                queue: group.queue.map(|queue| ProtoUpstreamQueue {
+                    size: u32::try_from(queue.size).unwrap_or(u32::MAX),
+                    timeout_ms: duration_to_millis(Some(queue.timeout)),
+                }),
+            }
         })
         .collect()
 }
@@ -863,15 +1381,52 @@ fn proto_upstream_health_check_from_runtime(
             host,
             path,
             use_tls,
+            method,
+            headers,
+            statuses,
+            body,
         } => proto::upstream_health_check::Kind::Http(ProtoUpstreamHttpHealthCheck {
             host: host.clone(),
             path: path.clone(),
             use_tls: *use_tls,
+            method: method.clone(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| ProtoHealthCheckHeader {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            statuses: statuses
+                .iter()
+                .map(|range| ProtoHttpStatusRange {
+                    min: u32::from(*range.start()),
+                    max: u32::from(*range.end()),
+                })
+                .collect(),
+            body: body.as_ref().map(|body| match body {
+                HealthCheckBodyMatch::Contains(text) => {
+                    proto::upstream_http_health_check::Body::BodyContains(text.clone())
+                }
+                HealthCheckBodyMatch::Regex(pattern) => {
+                    proto::upstream_http_health_check::Body::BodyRegex(pattern.clone())
+                }
+            }),
+        }),
+        crate::upstreams::HealthCheckType::Grpc {
+            service,
+            host,
+            use_tls,
+        } => proto::upstream_health_check::Kind::Grpc(ProtoUpstreamGrpcHealthCheck {
+            service: service.clone(),
+            host: host.clone().unwrap_or_default(),
+            use_tls: *use_tls,
         }),
     };
 
     ProtoUpstreamHealthCheck {
         kind: Some(kind),
+        port: value.port.map(u32::from).unwrap_or(0),
         timeout_ms: value.timeout.as_millis().try_into().unwrap_or(u64::MAX),
         interval_ms: value.interval.as_millis().try_into().unwrap_or(u64::MAX),
         consecutive_success: value.consecutive_success.try_into().unwrap_or(u32::MAX),
@@ -914,6 +1469,12 @@ fn proto_listener_from_runtime(
         http2: protocol.http2,
         http2_only: protocol.http2_only,
         tls_options,
+        proxy_protocol: protocol.proxy_protocol,
+        proxy_protocol_trusted: protocol
+            .proxy_protocol_trusted
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
     })
 }
 
@@ -924,6 +1485,7 @@ fn proto_virtual_hosts_from_runtime(
     listener_name: &str,
     routes: &VirtualHostRoutes,
     tls: Option<&crate::upstreams::ListenerTlsConfig>,
+    acme_certificates: &HashMap<String, Vec<String>>,
 ) -> Result<Vec<ProtoVirtualHost>, String> {
     let mut virtual_hosts = Vec::new();
 
@@ -932,6 +1494,10 @@ fn proto_virtual_hosts_from_runtime(
             .and_then(|cfg| cfg.named.get(host))
             .cloned()
             .or_else(|| tls.and_then(|cfg| cfg.default.clone()));
+        let certificate = match acme_certificate_for(tls, identity.as_ref(), acme_certificates) {
+            Some((_, issuers)) => VirtualHostCertificate::Acme(issuers),
+            None => VirtualHostCertificate::Tls(identity),
+        };
 
         merge_or_push_virtual_host(
             &mut virtual_hosts,
@@ -939,19 +1505,34 @@ fn proto_virtual_hosts_from_runtime(
             false,
             host.clone(),
             server_routes,
-            identity,
+            certificate,
         )?;
     }
 
     if let Some(default_routes) = routes.default.as_ref() {
         let default_tls = tls.and_then(|cfg| cfg.default.clone());
+        let default_acme = acme_certificate_for(tls, default_tls.as_ref(), acme_certificates);
         let default_routes_proto = proto_routes_from_runtime(default_routes)?;
-        let default_tls_proto = default_tls.as_ref().map(proto_tls_binding_from_runtime);
+        let default_tls_proto = default_tls
+            .as_ref()
+            .filter(|_| default_acme.is_none())
+            .map(proto_tls_binding_from_runtime);
+        let (default_acme_host, default_acme_issuers) = default_acme.unzip();
+        let default_acme_issuers = default_acme_issuers.unwrap_or_default();
+        let default_request_id = default_routes
+            .request_id
+            .as_ref()
+            .map(proto_request_id_from_runtime);
 
         if let Some(current) = virtual_hosts.iter_mut().find(|current| {
             current.listener == listener_name
                 && current.routes == default_routes_proto
                 && current.tls == default_tls_proto
+                && current.acme_issuers == default_acme_issuers
+                && default_acme_host
+                    .as_ref()
+                    .is_none_or(|host| current.server_names.contains(host))
+                && current.request_id == default_request_id
         }) {
             current.default_server = true;
         } else {
@@ -961,6 +1542,8 @@ fn proto_virtual_hosts_from_runtime(
                 default_server: true,
                 tls: default_tls_proto,
                 routes: default_routes_proto,
+                request_id: default_request_id,
+                acme_issuers: default_acme_issuers,
             });
         }
     }
@@ -968,22 +1551,58 @@ fn proto_virtual_hosts_from_runtime(
     Ok(virtual_hosts)
 }
 
+/// Where a virtual host's certificate comes from.
+enum VirtualHostCertificate {
+    Tls(Option<TlsIdentity>),
+    /// ACME issuers, tried in order.
+    Acme(Vec<String>),
+}
+
+/// The host and ACME issuers owning the certificate `identity`, when that
+/// certificate is ACME-managed.
+fn acme_certificate_for(
+    tls: Option<&crate::upstreams::ListenerTlsConfig>,
+    identity: Option<&TlsIdentity>,
+    acme_certificates: &HashMap<String, Vec<String>>,
+) -> Option<(String, Vec<String>)> {
+    let identity = identity?;
+    tls?.named
+        .iter()
+        .filter(|(_, named)| *named == identity)
+        .filter_map(|(host, _)| Some((host.clone(), acme_certificates.get(host)?.clone())))
+        .min()
+}
+
 fn merge_or_push_virtual_host(
     out: &mut Vec<ProtoVirtualHost>,
     listener_name: &str,
     default_server: bool,
     host: String,
     routes: &ServerRoutes,
-    identity: Option<TlsIdentity>,
+    certificate: VirtualHostCertificate,
 ) -> Result<(), String> {
-    let tls = identity.as_ref().map(proto_tls_binding_from_runtime);
+    let (tls, acme_issuers) = match certificate {
+        VirtualHostCertificate::Tls(identity) => (
+            identity.as_ref().map(proto_tls_binding_from_runtime),
+            Vec::new(),
+        ),
+        VirtualHostCertificate::Acme(issuers) => (None, issuers),
+    };
+    let request_id = routes
+        .request_id
+        .as_ref()
+        .map(proto_request_id_from_runtime);
     let routes = proto_routes_from_runtime(routes)?;
 
+    // ACME hosts are never merged: each certificate covers one server_name.
     if let Some(current) = out.iter_mut().find(|current| {
-        current.listener == listener_name
+        acme_issuers.is_empty()
+            && current.listener == listener_name
             && current.default_server == default_server
             && current.tls == tls
+            && current.acme_issuers == acme_issuers
             && current.routes == routes
+            && current.request_id == request_id
     }) {
         current.server_names.push(host);
         current.server_names.sort();
@@ -996,6 +1615,8 @@ fn merge_or_push_virtual_host(
         default_server,
         tls,
         routes,
+        request_id,
+        acme_issuers,
     });
     Ok(())
 }
@@ -1024,6 +1645,17 @@ fn proto_route_from_runtime(route: &CompiledLocation) -> Result<ProtoRoute, Stri
         upstream_protocol: proto_upstream_http_protocol_from_runtime(route.upstream_protocol)
             as i32,
         cache: route.cache.as_ref().map(proto_route_cache_from_runtime),
+        retry: route
+            .upstream_retry
+            .next_upstream
+            .is_enabled()
+            .then(|| proto_upstream_retry_from_runtime(&route.upstream_retry)),
+        name: route.name.clone().unwrap_or_default(),
+        access_logs: route
+            .access_logs
+            .iter()
+            .map(proto_access_log_from_runtime)
+            .collect(),
     })
 }
 
@@ -1060,8 +1692,44 @@ fn proto_route_action_from_runtime(target: &RouteTarget) -> proto::route::Action
         }),
         RouteTarget::Return { status, location } => proto::route::Action::Redirect(ProtoRedirect {
             status: u32::from(*status),
-            location: location.clone(),
+            location: location.source().to_string(),
         }),
+        RouteTarget::Static(files) => {
+            proto::route::Action::StaticFiles(proto_static_files_from_runtime(files))
+        }
+    }
+}
+
+fn proto_static_files_from_runtime(files: &StaticFiles) -> ProtoStaticFiles {
+    let (root, alias) = match &files.root {
+        StaticRoot::Root(path) => (path.display().to_string(), String::new()),
+        StaticRoot::Alias { path, .. } => (String::new(), path.display().to_string()),
+    };
+
+    ProtoStaticFiles {
+        root,
+        alias,
+        index: files.index.clone(),
+        try_files: files
+            .try_files
+            .iter()
+            .flat_map(|try_files| &try_files.files)
+            .map(|file| file.source().to_string())
+            .collect(),
+        fallback: files
+            .try_files
+            .as_ref()
+            .map(|try_files| match &try_files.fallback {
+                StaticFallback::Status(status) => {
+                    proto::static_files::Fallback::FallbackStatus(u32::from(*status))
+                }
+                StaticFallback::Named(name) => {
+                    proto::static_files::Fallback::FallbackNamedLocation(name.clone())
+                }
+                StaticFallback::Uri(uri) => {
+                    proto::static_files::Fallback::FallbackUri(uri.source().to_string())
+                }
+            }),
     }
 }
 
@@ -1107,6 +1775,45 @@ fn proto_cache_key_mode_from_runtime(value: CacheKeyMode) -> ProtoCacheKeyMode {
     }
 }
 
+fn upstream_retry_directives_from_proto(
+    retry: &ProtoUpstreamRetry,
+) -> Result<Vec<LocationDirective>, String> {
+    let next_upstream = NextUpstream {
+        error: retry.error,
+        timeout: retry.timeout,
+        http_502: retry.http_502,
+        http_503: retry.http_503,
+        http_504: retry.http_504,
+        non_idempotent: retry.non_idempotent,
+    };
+    if next_upstream.non_idempotent && !next_upstream.is_enabled() {
+        return Err("route retry non_idempotent needs at least one failure condition".into());
+    }
+
+    let mut directives = vec![LocationDirective::ProxyNextUpstream(next_upstream)];
+    if retry.tries > 0 {
+        directives.push(LocationDirective::ProxyNextUpstreamTries(retry.tries));
+    }
+    if let Some(timeout) = duration_from_millis(retry.timeout_ms) {
+        directives.push(LocationDirective::ProxyNextUpstreamTimeout(timeout));
+    }
+    Ok(directives)
+}
+
+fn proto_upstream_retry_from_runtime(retry: &UpstreamRetry) -> ProtoUpstreamRetry {
+    let next = retry.next_upstream;
+    ProtoUpstreamRetry {
+        error: next.error,
+        timeout: next.timeout,
+        http_502: next.http_502,
+        http_503: next.http_503,
+        http_504: next.http_504,
+        non_idempotent: next.non_idempotent,
+        tries: retry.tries,
+        timeout_ms: duration_to_millis(retry.timeout),
+    }
+}
+
 fn proto_timeouts_from_runtime(timeouts: &UpstreamTimeouts) -> ProtoRouteTimeouts {
     ProtoRouteTimeouts {
         connect_timeout_ms: duration_to_millis(timeouts.connect),
@@ -1150,6 +1857,166 @@ fn proto_http_options_from_runtime(options: &HttpRuntimeOptions) -> ProtoHttpOpt
         h2c: options.h2c,
         client_max_body_size_bytes: options.client_max_body_size.unwrap_or(0),
         proxy_cache_max_size_bytes: options.proxy_cache_max_size.unwrap_or(0),
+        proxy_cache_store: proto_cache_store_from_runtime(&options.proxy_cache_store),
+        resolver: options.resolver.as_ref().map(|resolver| ProtoDnsResolver {
+            addresses: resolver.addrs.iter().map(ToString::to_string).collect(),
+            valid_ms: duration_to_millis(resolver.valid),
+        }),
+        metrics_label_limit: options
+            .metrics_label_limit
+            .map_or(0, |limit| limit.min(u32::MAX as usize) as u32),
+        log_formats: options
+            .log_formats
+            .iter()
+            .map(|format| ProtoLogFormat {
+                name: format.name.clone(),
+                encoding: match format.encoding {
+                    LogEncoding::Json => ProtoLogEncoding::Json,
+                    LogEncoding::Text => ProtoLogEncoding::Text,
+                } as i32,
+                fields: format.fields.iter().map(ToString::to_string).collect(),
+            })
+            .collect(),
+        access_logs: options
+            .access_logs
+            .iter()
+            .map(proto_access_log_from_runtime)
+            .collect(),
+    }
+}
+
+fn log_format_from_proto(format: &ProtoLogFormat) -> Result<LogFormat, String> {
+    let encoding = match ProtoLogEncoding::try_from(format.encoding) {
+        Ok(ProtoLogEncoding::Unspecified | ProtoLogEncoding::Json) => LogEncoding::Json,
+        Ok(ProtoLogEncoding::Text) => LogEncoding::Text,
+        Err(_) => return Err(format!("unknown log encoding `{}`", format.encoding)),
+    };
+    Ok(LogFormat {
+        name: format.name.clone(),
+        encoding,
+        fields: format
+            .fields
+            .iter()
+            .map(|field| {
+                field
+                    .parse()
+                    .map_err(|err| format!("log_format `{}`: {err}", format.name))
+            })
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn access_logs_from_proto(logs: &[ProtoAccessLog]) -> Result<Vec<AccessLog>, String> {
+    logs.iter().map(access_log_from_proto).collect()
+}
+
+fn access_log_from_proto(log: &ProtoAccessLog) -> Result<AccessLog, String> {
+    if log.off {
+        if !log.sink.is_empty()
+            || !log.format.is_empty()
+            || !log.statuses.is_empty()
+            || log.sample != 0.0
+        {
+            return Err("access log `off` cannot set a sink, format or filter".into());
+        }
+        return Ok(AccessLog::Off);
+    }
+    let sample = if log.sample == 0.0 {
+        None
+    } else if log.sample > 0.0 && log.sample <= 1.0 {
+        Some(((log.sample * f64::from(ACCESS_LOG_SAMPLE_SCALE)).round() as u32).max(1))
+    } else {
+        return Err(format!(
+            "access log sample {} must be in (0, 1]",
+            log.sample
+        ));
+    };
+    Ok(AccessLog::To(AccessLogTarget {
+        sink: log.sink.parse()?,
+        format: none_if_empty(log.format.clone()),
+        statuses: log
+            .statuses
+            .iter()
+            .map(|range| {
+                let max = if range.max == 0 { range.min } else { range.max };
+                match (u16::try_from(range.min), u16::try_from(max)) {
+                    (Ok(min), Ok(max)) if (100..=max).contains(&min) && max <= 599 => Ok(min..=max),
+                    _ => Err(format!(
+                        "access log status range {}-{max} is out of range",
+                        range.min
+                    )),
+                }
+            })
+            .collect::<Result<_, _>>()?,
+        sample,
+    }))
+}
+
+fn proto_access_log_from_runtime(log: &AccessLog) -> ProtoAccessLog {
+    match log {
+        AccessLog::Off => ProtoAccessLog {
+            off: true,
+            ..ProtoAccessLog::default()
+        },
+        AccessLog::To(target) => ProtoAccessLog {
+            off: false,
+            sink: target.sink.to_string(),
+            format: target.format.clone().unwrap_or_default(),
+            statuses: target
+                .statuses
+                .iter()
+                .map(|range| ProtoHttpStatusRange {
+                    min: u32::from(*range.start()),
+                    max: u32::from(*range.end()),
+                })
+                .collect(),
+            sample: target.sample.map_or(0.0, |sample| {
+                f64::from(sample) / f64::from(ACCESS_LOG_SAMPLE_SCALE)
+            }),
+        },
+    }
+}
+
+fn dns_resolver_from_proto(resolver: &ProtoDnsResolver) -> Result<DnsResolver, String> {
+    if resolver.addresses.is_empty() {
+        return Err("resolver needs at least one name server address".into());
+    }
+    let addrs = resolver
+        .addresses
+        .iter()
+        .map(|raw| {
+            raw.parse::<SocketAddr>()
+                .or_else(|_| raw.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map_err(|_| format!("invalid resolver address `{raw}`"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(DnsResolver {
+        addrs,
+        valid: duration_from_millis(resolver.valid_ms),
+    })
+}
+
+fn cache_store_from_proto(store: Option<&ProtoCacheStore>) -> Result<CacheStoreConfig, String> {
+    match store {
+        Some(store) if !store.redis_url.is_empty() => CacheStoreConfig::redis(
+            &store.redis_url,
+            (!store.key_prefix.is_empty()).then(|| store.key_prefix.clone()),
+        ),
+        Some(store) if !store.key_prefix.is_empty() => {
+            Err("cache store key_prefix requires redis_url".into())
+        }
+        _ => Ok(CacheStoreConfig::Memory),
+    }
+}
+
+fn proto_cache_store_from_runtime(store: &CacheStoreConfig) -> Option<ProtoCacheStore> {
+    match store {
+        CacheStoreConfig::Memory => None,
+        CacheStoreConfig::Redis { url, key_prefix } => Some(ProtoCacheStore {
+            redis_url: url.clone(),
+            key_prefix: key_prefix.clone().unwrap_or_default(),
+        }),
     }
 }
 
@@ -1238,23 +2105,55 @@ fn switch_from_proto(value: i32) -> Switch {
     }
 }
 
-fn upstream_selection_policy_from_proto(value: i32) -> Result<UpstreamSelectionPolicy, String> {
-    match ProtoUpstreamSelectionPolicy::try_from(value)
-        .unwrap_or(ProtoUpstreamSelectionPolicy::Unspecified)
-    {
+fn upstream_selection_policy_from_proto(
+    value: i32,
+    hash_key: &str,
+) -> Result<UpstreamSelectionPolicy, String> {
+    let policy = ProtoUpstreamSelectionPolicy::try_from(value)
+        .unwrap_or(ProtoUpstreamSelectionPolicy::Unspecified);
+    let consistent = match policy {
+        ProtoUpstreamSelectionPolicy::Hash => false,
+        ProtoUpstreamSelectionPolicy::ConsistentHash => true,
+        _ if !hash_key.is_empty() => {
+            return Err("hash_key requires a hash or consistent_hash policy".into());
+        }
         ProtoUpstreamSelectionPolicy::Unspecified | ProtoUpstreamSelectionPolicy::RoundRobin => {
-            Ok(UpstreamSelectionPolicy::RoundRobin)
+            return Ok(UpstreamSelectionPolicy::RoundRobin);
         }
-        ProtoUpstreamSelectionPolicy::Random => Ok(UpstreamSelectionPolicy::Random),
+        ProtoUpstreamSelectionPolicy::Random => return Ok(UpstreamSelectionPolicy::Random),
+        ProtoUpstreamSelectionPolicy::LeastConn => return Ok(UpstreamSelectionPolicy::LeastConn),
+    };
+
+    if hash_key.is_empty() {
+        return Err("hash policies require a hash_key".into());
     }
+    let key = Template::parse(hash_key).map_err(|err| format!("invalid hash_key: {err}"))?;
+    if key.is_static() {
+        return Err(format!("hash_key `{key}` must reference a variable"));
+    }
+
+    Ok(UpstreamSelectionPolicy::Hash { key, consistent })
 }
 
 fn proto_upstream_selection_policy_from_runtime(
-    value: UpstreamSelectionPolicy,
-) -> ProtoUpstreamSelectionPolicy {
+    value: &UpstreamSelectionPolicy,
+) -> (ProtoUpstreamSelectionPolicy, String) {
     match value {
-        UpstreamSelectionPolicy::RoundRobin => ProtoUpstreamSelectionPolicy::RoundRobin,
-        UpstreamSelectionPolicy::Random => ProtoUpstreamSelectionPolicy::Random,
+        UpstreamSelectionPolicy::RoundRobin => {
+            (ProtoUpstreamSelectionPolicy::RoundRobin, String::new())
+        }
+        UpstreamSelectionPolicy::Random => (ProtoUpstreamSelectionPolicy::Random, String::new()),
+        UpstreamSelectionPolicy::LeastConn => {
+            (ProtoUpstreamSelectionPolicy::LeastConn, String::new())
+        }
+        UpstreamSelectionPolicy::Hash { key, consistent } => (
+            if *consistent {
+                ProtoUpstreamSelectionPolicy::ConsistentHash
+            } else {
+                ProtoUpstreamSelectionPolicy::Hash
+            },
+            key.source().to_string(),
+        ),
     }
 }
 
@@ -1304,7 +2203,7 @@ fn none_if_empty(value: String) -> Option<String> {
 
 fn sorted_named_routes(routes: &HashMap<String, ServerRoutes>) -> Vec<(&String, &ServerRoutes)> {
     let mut entries = routes.iter().collect::<Vec<_>>();
-    entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+    entries.sort_by_key(|(left, _)| *left);
     entries
 }
 
@@ -1335,6 +2234,33 @@ impl TryFrom<&ProtoListener> for ListenerDef {
                 value.name
             ));
         }
+        let proxy_protocol_trusted = value
+            .proxy_protocol_trusted
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        format!(
+                            "listener `{}` has invalid proxy_protocol_trusted network `{network}`",
+                            value.name
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if value.proxy_protocol && proxy_protocol_trusted.is_empty() {
+            return Err(format!(
+                "listener `{}` enables proxy_protocol without proxy_protocol_trusted networks",
+                value.name
+            ));
+        }
+        if !value.proxy_protocol && !proxy_protocol_trusted.is_empty() {
+            return Err(format!(
+                "listener `{}` sets proxy_protocol_trusted but proxy_protocol=false",
+                value.name
+            ));
+        }
 
         Ok(Self {
             name: value.name.clone(),
@@ -1345,6 +2271,8 @@ impl TryFrom<&ProtoListener> for ListenerDef {
                 default_server: false,
                 http2: value.http2,
                 http2_only: value.http2_only,
+                proxy_protocol: value.proxy_protocol,
+                proxy_protocol_trusted,
             },
             tls_options,
         })
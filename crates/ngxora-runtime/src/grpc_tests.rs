@@ -1,15 +1,27 @@
+use super::proto::control_plane_server::ControlPlane;
 #[cfg(unix)]
 use super::set_uds_permissions;
-use super::{proto, proto_snapshot_from_runtime, runtime_snapshot_from_proto};
+use super::{
+    GrpcControlPlane, proto, proto_snapshot_from_runtime, render_snapshot_config,
+    runtime_snapshot_from_proto,
+};
+use crate::cache::{CacheBackend, CacheKey, CachedResponse};
+use crate::control::InProcessControlPlane;
 use crate::control::{ConfigSnapshot, RuntimeState};
 use crate::upstreams::{CompiledMatcher, CompiledRouter, ListenKey, RouteTarget};
+use ipnet::IpNet;
 use ngxora_compile::ir::{
-    Http, KeepaliveTimeout, Listen, Location, LocationDirective, LocationMatcher, PemSource,
-    ProxyPassTarget, Server, SslProvider, Switch, TlsIdentity, UpstreamBlock, UpstreamHealthCheck,
-    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamSelectionPolicy, UpstreamServer,
+    AccessLog, AccessLogSink, AccessLogTarget, CacheConfig, CacheStoreConfig, DnsResolver,
+    HealthCheckBodyMatch, Http, KeepaliveTimeout, Listen, Location, LocationDirective,
+    LocationMatcher, LogField, NextUpstream, PemSource, ProxyPassTarget, RequestId,
+    RequestIdFormat, Server, SslProvider, Switch, TlsIdentity, UpstreamBlock, UpstreamHealthCheck,
+    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamKeepalive, UpstreamOutlierDetection,
+    UpstreamQueue, UpstreamRetry, UpstreamSelectionPolicy, UpstreamServer,
 };
+use ngxora_compile::variables::Template;
 use ngxora_plugin_api::PluginSpec;
 use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
 use std::time::Duration;
 
 #[cfg(unix)]
@@ -112,6 +124,24 @@ fn proto_snapshot_converts_into_runtime_router() {
             h2c: false,
             client_max_body_size_bytes: 8 * 1024 * 1024,
             proxy_cache_max_size_bytes: 0,
+            proxy_cache_store: Some(proto::CacheStore {
+                redis_url: "redis://cache.internal:6379/1".into(),
+                key_prefix: "edge".into(),
+            }),
+            resolver: None,
+            metrics_label_limit: 50,
+            log_formats: vec![proto::LogFormat {
+                name: "edge".into(),
+                encoding: proto::LogEncoding::Text as i32,
+                fields: vec!["status".into(), "request_header:Host".into()],
+            }],
+            access_logs: vec![proto::AccessLog {
+                off: false,
+                sink: "/var/log/ngxora/access.log".into(),
+                format: "edge".into(),
+                statuses: vec![proto::HttpStatusRange { min: 500, max: 599 }],
+                sample: 0.5,
+            }],
         }),
         listeners: vec![proto::Listener {
             name: "edge".into(),
@@ -120,6 +150,8 @@ fn proto_snapshot_converts_into_runtime_router() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: vec![proto::UpstreamGroup {
@@ -128,26 +160,44 @@ fn proto_snapshot_converts_into_runtime_router() {
                 proto::UpstreamBackend {
                     host: "backend-1.internal".into(),
                     port: 8080,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: 0,
+                    backup: false,
+                    down: false,
+                    slow_start_ms: 0,
                 },
                 proto::UpstreamBackend {
                     host: "backend-2.internal".into(),
                     port: 8081,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: 0,
+                    backup: false,
+                    down: false,
+                    slow_start_ms: 0,
                 },
             ],
             policy: proto::UpstreamSelectionPolicy::Random as i32,
+            hash_key: String::new(),
             health_check: Some(proto::UpstreamHealthCheck {
                 kind: Some(proto::upstream_health_check::Kind::Http(
                     proto::UpstreamHttpHealthCheck {
                         host: "backend.internal".into(),
                         path: "/readyz".into(),
                         use_tls: true,
+                        ..Default::default()
                     },
                 )),
                 timeout_ms: 2_000,
                 interval_ms: 10_000,
                 consecutive_success: 2,
                 consecutive_failure: 3,
+                port: 0,
             }),
+            outlier_detection: None,
+            keepalive: None,
+            queue: None,
         }],
         virtual_hosts: vec![proto::VirtualHost {
             listener: "edge".into(),
@@ -155,6 +205,11 @@ fn proto_snapshot_converts_into_runtime_router() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: "orders-api".into(),
+                access_logs: vec![proto::AccessLog {
+                    off: true,
+                    ..Default::default()
+                }],
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/api".into())),
                 }),
@@ -178,6 +233,7 @@ fn proto_snapshot_converts_into_runtime_router() {
                     min_uses: 2,
                     valid_statuses: vec![200, 301, 302],
                 }),
+                retry: None,
                 tls_options: Some(proto::UpstreamTlsOptions {
                     verify: proto::Switch::Off as i32,
                     trusted_certificate: Some(proto::PemSource {
@@ -194,8 +250,11 @@ fn proto_snapshot_converts_into_runtime_router() {
                     json_config: r#"{"response":{"add":[["x-proxy","ngxora"]]}}"#.into(),
                 }],
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
@@ -224,7 +283,30 @@ fn proto_snapshot_converts_into_runtime_router() {
     );
     assert!(runtime.router.http_options.tcp_nodelay);
     assert!(runtime.router.http_options.allow_connect_method_proxying);
+    assert_eq!(runtime.router.http_options.metrics_label_limit, Some(50));
+    assert_eq!(
+        runtime.router.http_options.log_formats[0].fields,
+        [LogField::Status, LogField::RequestHeader("host".into())]
+    );
+    assert_eq!(
+        runtime.router.http_options.access_logs,
+        [AccessLog::To(AccessLogTarget {
+            sink: AccessLogSink::File("/var/log/ngxora/access.log".into()),
+            format: Some("edge".into()),
+            statuses: vec![500..=599],
+            sample: Some(5000),
+        })]
+    );
+    assert_eq!(route.access_logs, [AccessLog::Off]);
+    assert_eq!(
+        runtime.router.http_options.proxy_cache_store,
+        CacheStoreConfig::Redis {
+            url: "redis://cache.internal:6379/1".into(),
+            key_prefix: Some("edge".into()),
+        }
+    );
     assert_eq!(route.matcher, CompiledMatcher::Prefix("/api".into()));
+    assert_eq!(route.name.as_deref(), Some("orders-api"));
     assert_eq!(
         route.target,
         RouteTarget::UpstreamGroup {
@@ -268,7 +350,12 @@ fn proto_snapshot_converts_into_runtime_router() {
                 host: "backend.internal".into(),
                 path: "/readyz".into(),
                 use_tls: true,
+                method: "GET".into(),
+                headers: Vec::new(),
+                statuses: vec![200..=200],
+                body: None,
             },
+            port: None,
             timeout: Duration::from_secs(2),
             interval: Duration::from_secs(10),
             consecutive_success: 2,
@@ -289,6 +376,8 @@ fn proto_snapshot_defaults_tcp_nodelay_to_on() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -298,6 +387,8 @@ fn proto_snapshot_defaults_tcp_nodelay_to_on() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/".into())),
                 }),
@@ -309,18 +400,715 @@ fn proto_snapshot_defaults_tcp_nodelay_to_on() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: None,
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
     assert!(runtime.router.http_options.tcp_nodelay);
 }
 
+fn snapshot_with_upstream_group(group: proto::UpstreamGroup) -> proto::ConfigSnapshot {
+    proto::ConfigSnapshot {
+        version: "v1".into(),
+        http: Some(proto::HttpOptions::default()),
+        listeners: Vec::new(),
+        upstreams: vec![group],
+        virtual_hosts: Vec::new(),
+        le_config: None,
+        acme_issuers: Vec::new(),
+    }
+}
+
+fn sticky_upstream_group(
+    policy: proto::UpstreamSelectionPolicy,
+    hash_key: &str,
+) -> proto::UpstreamGroup {
+    proto::UpstreamGroup {
+        name: "sticky".into(),
+        backends: vec![
+            proto::UpstreamBackend {
+                host: "backend-1.internal".into(),
+                port: 8080,
+                weight: 0,
+                resolve: false,
+                max_conns: 0,
+                backup: false,
+                down: false,
+                slow_start_ms: 0,
+            },
+            proto::UpstreamBackend {
+                host: "backend-2.internal".into(),
+                port: 8080,
+                weight: 5,
+                resolve: false,
+                max_conns: 0,
+                backup: false,
+                down: false,
+                slow_start_ms: 0,
+            },
+        ],
+        policy: policy as i32,
+        health_check: None,
+        outlier_detection: None,
+        hash_key: hash_key.into(),
+        keepalive: None,
+        queue: None,
+    }
+}
+
+#[test]
+fn proto_upstream_selection_policy_roundtrips() {
+    let snapshot = snapshot_with_upstream_group(sticky_upstream_group(
+        proto::UpstreamSelectionPolicy::ConsistentHash,
+        "$cookie_session",
+    ));
+
+    let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
+    let group = &runtime.router.upstreams["sticky"];
+    assert_eq!(
+        group.policy,
+        UpstreamSelectionPolicy::Hash {
+            key: Template::parse("$cookie_session").unwrap(),
+            consistent: true,
+        }
+    );
+    assert_eq!(
+        group
+            .servers
+            .iter()
+            .map(|server| server.weight)
+            .collect::<Vec<_>>(),
+        vec![1, 5]
+    );
+
+    let state = RuntimeState::new(runtime);
+    let snapshot = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+    assert_eq!(
+        proto.upstreams[0].policy,
+        proto::UpstreamSelectionPolicy::ConsistentHash as i32
+    );
+    assert_eq!(proto.upstreams[0].hash_key, "$cookie_session");
+    assert_eq!(proto.upstreams[0].backends[0].weight, 1);
+    assert_eq!(proto.upstreams[0].backends[1].weight, 5);
+}
+
+#[test]
+fn proto_upstream_selection_policy_validates_hash_key() {
+    for (policy, hash_key, expected) in [
+        (
+            proto::UpstreamSelectionPolicy::Hash,
+            "",
+            "require a hash_key",
+        ),
+        (
+            proto::UpstreamSelectionPolicy::Hash,
+            "static",
+            "must reference a variable",
+        ),
+        (
+            proto::UpstreamSelectionPolicy::ConsistentHash,
+            "$nope",
+            "unknown variable",
+        ),
+        (
+            proto::UpstreamSelectionPolicy::LeastConn,
+            "$remote_addr",
+            "requires a hash or consistent_hash policy",
+        ),
+    ] {
+        let snapshot = snapshot_with_upstream_group(sticky_upstream_group(policy, hash_key));
+        let err = match runtime_snapshot_from_proto(snapshot) {
+            Ok(_) => panic!("{hash_key}: expected an error"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains(expected), "{hash_key}: {err}");
+    }
+}
+
+#[test]
+fn proto_access_logs_roundtrip() {
+    let mut snapshot = snapshot_with_upstream_group(sticky_upstream_group(
+        proto::UpstreamSelectionPolicy::RoundRobin,
+        "",
+    ));
+    let http = snapshot.http.as_mut().unwrap();
+    http.log_formats = vec![proto::LogFormat {
+        name: "edge".into(),
+        encoding: proto::LogEncoding::Json as i32,
+        fields: vec!["time".into(), "response_header:x-cache".into()],
+    }];
+    http.access_logs = vec![
+        proto::AccessLog {
+            off: false,
+            sink: "syslog:server=127.0.0.1:5514,facility=local3,tag=edge".into(),
+            format: "edge".into(),
+            statuses: vec![proto::HttpStatusRange { min: 400, max: 499 }],
+            sample: 0.25,
+        },
+        proto::AccessLog {
+            off: false,
+            sink: "stdout".into(),
+            format: String::new(),
+            statuses: Vec::new(),
+            sample: 0.0,
+        },
+    ];
+    let access_logs = http.access_logs.clone();
+
+    let state = RuntimeState::new(runtime_snapshot_from_proto(snapshot).expect("compiles"));
+    let snapshot = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+    let http = proto.http.expect("http options");
+    assert_eq!(http.access_logs, access_logs);
+    assert_eq!(
+        http.log_formats[0].fields,
+        ["time", "response_header:x-cache"]
+    );
+
+    for (log, expected) in [
+        (
+            proto::AccessLog {
+                off: true,
+                sink: "stdout".into(),
+                ..proto::AccessLog::default()
+            },
+            "cannot set a sink",
+        ),
+        (
+            proto::AccessLog {
+                sink: "stdout".into(),
+                sample: 1.5,
+                ..proto::AccessLog::default()
+            },
+            "sample",
+        ),
+        (
+            proto::AccessLog {
+                sink: "stdout".into(),
+                format: "missing".into(),
+                ..proto::AccessLog::default()
+            },
+            "unknown log_format",
+        ),
+    ] {
+        let mut snapshot = snapshot_with_upstream_group(sticky_upstream_group(
+            proto::UpstreamSelectionPolicy::RoundRobin,
+            "",
+        ));
+        snapshot.http.as_mut().unwrap().access_logs = vec![log];
+        let err = match runtime_snapshot_from_proto(snapshot) {
+            Ok(_) => panic!("{expected}: expected an error"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains(expected), "{expected}: {err}");
+    }
+}
+
+#[test]
+fn proto_upstream_outlier_detection_roundtrips() {
+    let mut group = sticky_upstream_group(proto::UpstreamSelectionPolicy::RoundRobin, "");
+    group.outlier_detection = Some(proto::UpstreamOutlierDetection {
+        consecutive_failure: 3,
+        base_ejection_time_ms: 10_000,
+        max_ejection_time_ms: 0,
+        max_ejection_percent: 50,
+    });
+
+    let runtime = runtime_snapshot_from_proto(snapshot_with_upstream_group(group.clone()))
+        .expect("proto snapshot compiles");
+    assert_eq!(
+        runtime.router.upstreams["sticky"].outlier_detection,
+        Some(UpstreamOutlierDetection {
+            consecutive_failure: 3,
+            base_ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        })
+    );
+
+    let state = RuntimeState::new(runtime);
+    let snapshot = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+    assert_eq!(
+        proto.upstreams[0].outlier_detection,
+        Some(proto::UpstreamOutlierDetection {
+            consecutive_failure: 3,
+            base_ejection_time_ms: 10_000,
+            max_ejection_time_ms: 300_000,
+            max_ejection_percent: 50,
+        })
+    );
+
+    group.outlier_detection = Some(proto::UpstreamOutlierDetection {
+        max_ejection_percent: 101,
+        ..proto::UpstreamOutlierDetection::default()
+    });
+    let err = match runtime_snapshot_from_proto(snapshot_with_upstream_group(group)) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => err.to_string(),
+    };
+    assert!(err.contains("between 1 and 100"), "{err}");
+}
+
+#[test]
+fn proto_dns_resolver_and_resolve_backends_roundtrip() {
+    let mut group = sticky_upstream_group(proto::UpstreamSelectionPolicy::RoundRobin, "");
+    group.backends[0].resolve = true;
+    let mut snapshot = snapshot_with_upstream_group(group);
+    snapshot.http.as_mut().unwrap().resolver = Some(proto::DnsResolver {
+        addresses: vec!["10.0.0.2".into(), "[fd00::53]:5353".into()],
+        valid_ms: 30_000,
+    });
+
+    let runtime = runtime_snapshot_from_proto(snapshot.clone()).expect("proto snapshot compiles");
+    assert_eq!(
+        runtime.router.http_options.resolver,
+        Some(DnsResolver {
+            addrs: vec![
+                "10.0.0.2:53".parse().unwrap(),
+                "[fd00::53]:5353".parse().unwrap(),
+            ],
+            valid: Some(Duration::from_secs(30)),
+        })
+    );
+    assert!(runtime.router.upstreams["sticky"].servers[0].resolve);
+
+    let state = RuntimeState::new(runtime);
+    let snapshot_ref = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot_ref.as_ref()).expect("runtime snapshot serializes");
+    assert_eq!(
+        proto.http.unwrap().resolver,
+        Some(proto::DnsResolver {
+            addresses: vec!["10.0.0.2:53".into(), "[fd00::53]:5353".into()],
+            valid_ms: 30_000,
+        })
+    );
+    assert!(proto.upstreams[0].backends[0].resolve);
+    assert!(!proto.upstreams[0].backends[1].resolve);
+
+    snapshot.http.as_mut().unwrap().resolver = Some(proto::DnsResolver {
+        addresses: vec!["dns.internal".into()],
+        valid_ms: 0,
+    });
+    let err = match runtime_snapshot_from_proto(snapshot) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => err.to_string(),
+    };
+    assert!(err.contains("invalid resolver address"), "{err}");
+}
+
+#[test]
+fn proto_upstream_keepalive_queue_and_max_conns_roundtrip() {
+    let mut group = sticky_upstream_group(proto::UpstreamSelectionPolicy::RoundRobin, "");
+    group.backends[0].max_conns = 8;
+    group.keepalive = Some(proto::UpstreamKeepalive {
+        connections: 16,
+        timeout_ms: 0,
+        requests: 1000,
+    });
+    group.queue = Some(proto::UpstreamQueue {
+        size: 50,
+        timeout_ms: 0,
+    });
+    let mut snapshot = snapshot_with_upstream_group(group);
+
+    let runtime = runtime_snapshot_from_proto(snapshot.clone()).expect("proto snapshot compiles");
+    let upstream = &runtime.router.upstreams["sticky"];
+    assert_eq!(
+        upstream.keepalive,
+        UpstreamKeepalive {
+            connections: Some(16),
+            timeout: None,
+            requests: Some(1000),
+        }
+    );
+    assert_eq!(
+        upstream.queue,
+        Some(UpstreamQueue {
+            size: 50,
+            timeout: Duration::from_secs(60),
+        })
+    );
+    assert_eq!(upstream.servers[0].max_conns, Some(8));
+    assert_eq!(upstream.servers[1].max_conns, None);
+
+    let state = RuntimeState::new(runtime);
+    let snapshot_ref = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot_ref.as_ref()).expect("runtime snapshot serializes");
+    let group = &proto.upstreams[0];
+    assert_eq!(group.keepalive, snapshot.upstreams[0].keepalive);
+    assert_eq!(
+        group.queue,
+        Some(proto::UpstreamQueue {
+            size: 50,
+            timeout_ms: 60_000,
+        })
+    );
+    assert_eq!(group.backends[0].max_conns, 8);
+    assert_eq!(group.backends[1].max_conns, 0);
+
+    snapshot.upstreams[0].queue = Some(proto::UpstreamQueue {
+        size: 0,
+        timeout_ms: 1_000,
+    });
+    let err = match runtime_snapshot_from_proto(snapshot) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => err.to_string(),
+    };
+    assert!(err.contains("upstream queue size"), "{err}");
+}
+
+#[test]
+fn proto_upstream_backup_down_and_slow_start_roundtrip() {
+    let mut group = sticky_upstream_group(proto::UpstreamSelectionPolicy::RoundRobin, "");
+    group.backends[0].weight = 1;
+    group.backends[0].slow_start_ms = 30_000;
+    group.backends[1].backup = true;
+    group.backends[1].down = true;
+    let snapshot = snapshot_with_upstream_group(group.clone());
+
+    let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
+    let servers = &runtime.router.upstreams["sticky"].servers;
+    assert_eq!(servers[0].slow_start, Some(Duration::from_secs(30)));
+    assert!(!servers[0].backup && !servers[0].down);
+    assert!(servers[1].backup && servers[1].down);
+    assert_eq!(servers[1].slow_start, None);
+
+    let state = RuntimeState::new(runtime);
+    let snapshot_ref = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot_ref.as_ref()).expect("runtime snapshot serializes");
+    assert_eq!(proto.upstreams[0].backends, group.backends);
+
+    group.policy = proto::UpstreamSelectionPolicy::Hash as i32;
+    group.hash_key = "$remote_addr".into();
+    let err = match runtime_snapshot_from_proto(snapshot_with_upstream_group(group)) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => err.to_string(),
+    };
+    assert!(
+        err.contains("slow_start is not supported with hash"),
+        "{err}"
+    );
+}
+
+#[test]
+fn proto_upstream_http_and_grpc_health_checks_roundtrip() {
+    let roundtrip = |health_check: proto::UpstreamHealthCheck| {
+        let mut group = sticky_upstream_group(proto::UpstreamSelectionPolicy::RoundRobin, "");
+        group.health_check = Some(health_check);
+        let runtime = runtime_snapshot_from_proto(snapshot_with_upstream_group(group))
+            .expect("proto snapshot compiles");
+        let compiled = runtime.router.upstreams["sticky"]
+            .health_check
+            .clone()
+            .expect("health check compiled");
+        let state = RuntimeState::new(runtime);
+        let snapshot_ref = state.snapshot();
+        let proto = proto_snapshot_from_runtime(snapshot_ref.as_ref())
+            .expect("runtime snapshot serializes");
+        (compiled, proto.upstreams[0].health_check.clone())
+    };
+    let health_check = |kind| proto::UpstreamHealthCheck {
+        kind: Some(kind),
+        timeout_ms: 1_000,
+        interval_ms: 5_000,
+        consecutive_success: 1,
+        consecutive_failure: 2,
+        port: 9901,
+    };
+
+    let http = health_check(proto::upstream_health_check::Kind::Http(
+        proto::UpstreamHttpHealthCheck {
+            host: "backend.internal".into(),
+            path: "/status".into(),
+            use_tls: false,
+            method: "HEAD".into(),
+            headers: vec![proto::HealthCheckHeader {
+                name: "X-Probe".into(),
+                value: "ngxora".into(),
+            }],
+            statuses: vec![
+                proto::HttpStatusRange { min: 200, max: 299 },
+                proto::HttpStatusRange { min: 404, max: 404 },
+            ],
+            body: Some(proto::upstream_http_health_check::Body::BodyRegex(
+                "^ok".into(),
+            )),
+        },
+    ));
+    let (compiled, proto) = roundtrip(http.clone());
+    assert_eq!(compiled.port, Some(9901));
+    assert_eq!(
+        compiled.check_type,
+        crate::upstreams::HealthCheckType::Http {
+            host: "backend.internal".into(),
+            path: "/status".into(),
+            use_tls: false,
+            method: "HEAD".into(),
+            headers: vec![("X-Probe".into(), "ngxora".into())],
+            statuses: vec![200..=299, 404..=404],
+            body: Some(HealthCheckBodyMatch::Regex("^ok".into())),
+        }
+    );
+    assert_eq!(proto, Some(http));
+
+    let grpc = health_check(proto::upstream_health_check::Kind::Grpc(
+        proto::UpstreamGrpcHealthCheck {
+            service: "api.v1.Users".into(),
+            host: "backend.internal".into(),
+            use_tls: true,
+        },
+    ));
+    let (compiled, proto) = roundtrip(grpc.clone());
+    assert_eq!(
+        compiled.check_type,
+        crate::upstreams::HealthCheckType::Grpc {
+            service: "api.v1.Users".into(),
+            host: Some("backend.internal".into()),
+            use_tls: true,
+        }
+    );
+    assert_eq!(proto, Some(grpc));
+
+    // Unset HTTP fields keep the old GET-and-200 probe.
+    let (compiled, _) = roundtrip(health_check(proto::upstream_health_check::Kind::Http(
+        proto::UpstreamHttpHealthCheck {
+            host: "backend.internal".into(),
+            path: "/readyz".into(),
+            ..Default::default()
+        },
+    )));
+    assert!(matches!(
+        compiled.check_type,
+        crate::upstreams::HealthCheckType::Http { method, statuses, .. }
+            if method == "GET" && statuses == vec![200..=200]
+    ));
+}
+
+fn snapshot_with_retry_route(retry: proto::UpstreamRetry) -> proto::ConfigSnapshot {
+    let mut snapshot = snapshot_with_upstream_group(sticky_upstream_group(
+        proto::UpstreamSelectionPolicy::RoundRobin,
+        "",
+    ));
+    snapshot.listeners.push(proto::Listener {
+        name: "edge".into(),
+        address: "0.0.0.0".into(),
+        port: 8080,
+        tls: false,
+        http2: false,
+        http2_only: false,
+        proxy_protocol: false,
+        proxy_protocol_trusted: Vec::new(),
+        tls_options: None,
+    });
+    snapshot.virtual_hosts.push(proto::VirtualHost {
+        listener: "edge".into(),
+        server_names: vec!["example.com".into()],
+        default_server: true,
+        tls: None,
+        routes: vec![proto::Route {
+            name: String::new(),
+            access_logs: Vec::new(),
+            r#match: Some(proto::Match {
+                kind: Some(proto::r#match::Kind::Prefix("/".into())),
+            }),
+            action: Some(proto::route::Action::Upstream(proto::Upstream {
+                scheme: "http".into(),
+                host: String::new(),
+                port: 0,
+                upstream_group: "sticky".into(),
+            })),
+            timeouts: None,
+            cache: None,
+            retry: Some(retry),
+            plugins: Vec::new(),
+            tls_options: None,
+            upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
+        }],
+        request_id: None,
+        acme_issuers: Vec::new(),
+    });
+    snapshot
+}
+
+#[test]
+fn proto_route_retry_roundtrips() {
+    let retry = proto::UpstreamRetry {
+        error: true,
+        timeout: true,
+        http_502: false,
+        http_503: true,
+        http_504: false,
+        non_idempotent: false,
+        tries: 3,
+        timeout_ms: 5_000,
+    };
+
+    let runtime =
+        runtime_snapshot_from_proto(snapshot_with_retry_route(retry)).expect("proto compiles");
+    let route = runtime
+        .router
+        .listeners
+        .values()
+        .next()
+        .unwrap()
+        .default
+        .as_ref()
+        .unwrap()
+        .locations[0]
+        .clone();
+    assert_eq!(
+        route.upstream_retry,
+        UpstreamRetry {
+            next_upstream: NextUpstream {
+                error: true,
+                timeout: true,
+                http_503: true,
+                ..NextUpstream::default()
+            },
+            tries: 3,
+            timeout: Some(Duration::from_secs(5)),
+        }
+    );
+
+    let state = RuntimeState::new(runtime);
+    let snapshot = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+    assert_eq!(proto.virtual_hosts[0].routes[0].retry, Some(retry));
+}
+
+#[test]
+fn proto_route_retry_rejects_non_idempotent_without_condition() {
+    let retry = proto::UpstreamRetry {
+        non_idempotent: true,
+        ..proto::UpstreamRetry::default()
+    };
+
+    let err = match runtime_snapshot_from_proto(snapshot_with_retry_route(retry)) {
+        Ok(_) => panic!("expected an error"),
+        Err(err) => err.to_string(),
+    };
+    assert!(
+        err.contains("needs at least one failure condition"),
+        "{err}"
+    );
+}
+
+#[test]
+fn proto_virtual_host_request_id_converts_into_server_routes() {
+    let snapshot_with = |request_id: proto::RequestId| proto::ConfigSnapshot {
+        version: "v-request-id".into(),
+        http: Some(proto::HttpOptions::default()),
+        listeners: vec![proto::Listener {
+            name: "edge".into(),
+            address: "0.0.0.0".into(),
+            port: 8080,
+            tls: false,
+            http2: false,
+            http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            tls_options: None,
+        }],
+        upstreams: Vec::new(),
+        virtual_hosts: vec![proto::VirtualHost {
+            listener: "edge".into(),
+            server_names: Vec::new(),
+            default_server: true,
+            tls: None,
+            routes: Vec::new(),
+            request_id: Some(request_id),
+            acme_issuers: Vec::new(),
+        }],
+        le_config: None,
+        acme_issuers: Vec::new(),
+    };
+
+    let runtime = runtime_snapshot_from_proto(snapshot_with(proto::RequestId {
+        header: "X-Trace".into(),
+        trusted: vec!["10.0.0.0/8".into(), "192.0.2.7".into()],
+        format: proto::RequestIdFormat::Ulid as i32,
+    }))
+    .expect("proto snapshot compiles");
+    let listen_key = ListenKey {
+        addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        port: 8080,
+        ssl: false,
+    };
+    let server = runtime.router.listeners[&listen_key]
+        .default
+        .as_ref()
+        .expect("default server");
+    assert_eq!(
+        server.request_id,
+        Some(RequestId {
+            header: "x-trace".into(),
+            trusted: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "192.0.2.7/32".parse().unwrap()
+            ],
+            format: RequestIdFormat::Ulid,
+        })
+    );
+
+    let defaults = runtime_snapshot_from_proto(snapshot_with(proto::RequestId::default()))
+        .expect("proto snapshot compiles");
+    assert_eq!(
+        defaults.router.listeners[&listen_key]
+            .default
+            .as_ref()
+            .and_then(|server| server.request_id.clone()),
+        Some(RequestId::default())
+    );
+
+    for (request_id, expected) in [
+        (
+            proto::RequestId {
+                header: "x trace".into(),
+                ..proto::RequestId::default()
+            },
+            "invalid request ID header name",
+        ),
+        (
+            proto::RequestId {
+                trusted: vec!["10.0.0.0/40".into()],
+                ..proto::RequestId::default()
+            },
+            "invalid trusted network",
+        ),
+        (
+            proto::RequestId {
+                format: 9,
+                ..proto::RequestId::default()
+            },
+            "unknown request ID format",
+        ),
+    ] {
+        let err = match runtime_snapshot_from_proto(snapshot_with(request_id)) {
+            Ok(_) => panic!("{expected}: expected an error"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains(expected), "{expected}: {err}");
+    }
+}
+
 #[test]
 fn proto_redirect_route_converts_into_runtime_return_target() {
     let snapshot = proto::ConfigSnapshot {
@@ -333,6 +1121,8 @@ fn proto_redirect_route_converts_into_runtime_return_target() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -342,6 +1132,8 @@ fn proto_redirect_route_converts_into_runtime_return_target() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/old".into())),
                 }),
@@ -351,12 +1143,16 @@ fn proto_redirect_route_converts_into_runtime_return_target() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: None,
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
@@ -377,11 +1173,74 @@ fn proto_redirect_route_converts_into_runtime_return_target() {
         route.target,
         RouteTarget::Return {
             status: 301,
-            location: "https://example.com/new".into(),
+            location: Template::parse("https://example.com/new").unwrap(),
         }
     );
 }
 
+#[tokio::test]
+async fn purge_cache_reports_removed_entries() {
+    let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
+        "v1",
+        router_with_tls_and_plugin(),
+    )));
+    let cache = Arc::new(CacheBackend::new(1024 * 1024));
+    let cfg = CacheConfig::default();
+    for uri in ["/news/1", "/news/2", "/about"] {
+        let key = CacheKey {
+            generation: 1,
+            route_id: 1,
+            route: "_ /1".into(),
+            host: "example.com".into(),
+            method: "GET".into(),
+            uri: uri.into(),
+        };
+        let response = CachedResponse {
+            status: http::StatusCode::OK,
+            headers: http::HeaderMap::new(),
+            body: bytes::Bytes::from_static(b"ok"),
+            created_at: std::time::Instant::now(),
+        };
+        cache.put(key, response, &cfg).await;
+    }
+    let service = GrpcControlPlane::new(
+        InProcessControlPlane::new(Arc::clone(&state)).with_cache(Arc::clone(&cache)),
+    );
+
+    let request = proto::PurgeCacheRequest {
+        target: Some(proto::purge_cache_request::Target::Prefix(
+            proto::PurgePrefix {
+                host: String::new(),
+                prefix: "/news/".into(),
+            },
+        )),
+    };
+    let result = service
+        .purge_cache(tonic::Request::new(request))
+        .await
+        .expect("purge succeeds");
+    assert_eq!(result.into_inner().purged, 2);
+    assert_eq!(cache.total_entries().await, 1);
+
+    let err = service
+        .purge_cache(tonic::Request::new(proto::PurgeCacheRequest {
+            target: None,
+        }))
+        .await
+        .expect_err("missing target is rejected");
+    assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+    let detached = GrpcControlPlane::new(InProcessControlPlane::new(state));
+    let request = proto::PurgeCacheRequest {
+        target: Some(proto::purge_cache_request::Target::All(true)),
+    };
+    let err = detached
+        .purge_cache(tonic::Request::new(request))
+        .await
+        .expect_err("purge without a cache fails");
+    assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+}
+
 #[test]
 fn runtime_snapshot_converts_back_to_proto() {
     let router = router_with_tls_and_plugin();
@@ -490,6 +1349,200 @@ fn runtime_snapshot_converts_back_to_proto() {
     );
 }
 
+fn router_from_config(text: &str) -> CompiledRouter {
+    let ast = ngxora_config::Ast::parse_config(text).expect("config parses");
+    let ir = ngxora_compile::ir::Ir::from_ast(&ast).expect("config lowers");
+    CompiledRouter::from_http(&ir.http.expect("http block")).expect("router compiles")
+}
+
+#[cfg(feature = "plugin-headers")]
+#[test]
+fn grpc_header_plugin_values_keep_literal_dollars() {
+    let router = router_from_config(
+        r#"
+http {
+  server {
+    listen 8080;
+    location / { proxy_pass http://127.0.0.1:8080; }
+  }
+}
+"#,
+    );
+    let state = RuntimeState::new(ConfigSnapshot::new("v1", router));
+    let mut snapshot = proto_snapshot_from_runtime(state.snapshot().as_ref()).unwrap();
+    let with_headers = |snapshot: &mut proto::ConfigSnapshot, json_config: &str| {
+        snapshot.virtual_hosts[0].routes[0].plugins = vec![proto::Plugin {
+            name: "headers".into(),
+            json_config: json_config.into(),
+        }];
+    };
+
+    with_headers(
+        &mut snapshot,
+        r#"{"response":{"set":[{"name":"x-price","value":"$5"},{"name":"x-raw","value":"\\$notvar $$host"}]}}"#,
+    );
+    let result = state.apply_snapshot(runtime_snapshot_from_proto(snapshot.clone()).unwrap());
+    assert!(result.applied, "{}", result.message);
+
+    with_headers(
+        &mut snapshot,
+        r#"{"response":{"set":[{"name":"x-raw","value":"$notvar"}]}}"#,
+    );
+    let result = state.apply_snapshot(runtime_snapshot_from_proto(snapshot).unwrap());
+    assert!(!result.applied);
+    assert!(
+        result.message.contains("unknown variable `$notvar`"),
+        "{}",
+        result.message
+    );
+}
+
+#[test]
+fn snapshot_renders_as_config_that_compiles_to_the_same_snapshot() {
+    let router = router_from_config(
+        r#"
+http {
+  keepalive_timeout 30s;
+  upstream backend {
+    least_conn;
+    server 127.0.0.1:8080 weight=3;
+    server 127.0.0.1:8081 backup;
+    health_check {
+      type http;
+      host backend.internal;
+      path /healthz;
+    }
+  }
+  server {
+    listen 8080 default_server;
+    server_name example.com;
+    request_id on header=X-Trace trusted=10.0.0.0/8 format=ulid;
+    location /api/ {
+      route_name api;
+      proxy_pass http://backend;
+      proxy_read_timeout 5s;
+      proxy_cache {
+        proxy_cache_ttl 2m;
+      }
+    }
+    location = /old {
+      return 301 /new;
+    }
+  }
+}
+"#,
+    );
+    let state = RuntimeState::new(ConfigSnapshot::new("v3", router));
+    let snapshot = state.snapshot();
+
+    let config = render_snapshot_config(snapshot.as_ref(), false).expect("snapshot renders");
+    assert!(config.starts_with("# snapshot v3 (generation 1)\nhttp {\n"));
+    assert!(config.contains("        server 127.0.0.1:8081 backup;\n"));
+    assert!(config.contains("            route_name api;\n"));
+    assert!(
+        config.contains("        request_id on header=x-trace trusted=10.0.0.0/8 format=ulid;\n")
+    );
+
+    let rendered = RuntimeState::new(ConfigSnapshot::new("v3", router_from_config(&config)));
+    assert_eq!(
+        proto_snapshot_from_runtime(rendered.snapshot().as_ref()).unwrap(),
+        proto_snapshot_from_runtime(snapshot.as_ref()).unwrap()
+    );
+}
+
+#[test]
+fn acme_issuers_round_trip_through_proto_and_rendered_config() {
+    let router = router_from_config(
+        r#"
+http {
+  ssl_provider acme step {
+    acme_directory https://ca.internal:9000/acme/acme/directory;
+    key_type rsa;
+    ssl_trusted_certificate /etc/step/root_ca.crt;
+  }
+  ssl_provider acme zerossl {
+    acme_directory https://acme.zerossl.com/v2/DV90;
+    external_account_binding kid-1 aGVsbG8_d29ybGQ;
+  }
+  server {
+    listen 443 ssl default_server;
+    server_name internal.example.com;
+    location / { proxy_pass http://127.0.0.1:8080; }
+  }
+  server {
+    listen 443 ssl;
+    server_name a.example.com;
+    acme_certificate zerossl step;
+    location / { proxy_pass http://127.0.0.1:8080; }
+  }
+  server {
+    listen 443 ssl;
+    server_name b.example.com;
+    acme_certificate zerossl step;
+    location / { proxy_pass http://127.0.0.1:8080; }
+  }
+}
+"#,
+    );
+    let state = RuntimeState::new(ConfigSnapshot::new("v4", router));
+    let snapshot = state.snapshot();
+    let proto_snapshot =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+
+    assert!(proto_snapshot.le_config.is_none());
+    assert_eq!(
+        proto_snapshot
+            .acme_issuers
+            .iter()
+            .map(|issuer| issuer.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["step", "zerossl"]
+    );
+    assert_eq!(
+        proto_snapshot.acme_issuers[0].key_type,
+        proto::AcmeKeyType::Rsa as i32
+    );
+    // Hosts sharing routes and issuers still get one certificate each.
+    assert_eq!(proto_snapshot.virtual_hosts.len(), 3);
+    for virtual_host in &proto_snapshot.virtual_hosts {
+        assert!(virtual_host.tls.is_none());
+        assert_eq!(virtual_host.server_names.len(), 1);
+        let expected: &[&str] = if virtual_host.server_names[0] == "internal.example.com" {
+            assert!(virtual_host.default_server);
+            &["step"]
+        } else {
+            assert!(!virtual_host.default_server);
+            &["zerossl", "step"]
+        };
+        assert_eq!(virtual_host.acme_issuers, expected);
+    }
+
+    let imported =
+        runtime_snapshot_from_proto(proto_snapshot.clone()).expect("proto snapshot compiles");
+    assert_eq!(
+        imported.router.acme_certificates,
+        snapshot.router.acme_certificates
+    );
+    assert_eq!(imported.router.acme_issuers, snapshot.router.acme_issuers);
+
+    let config = render_snapshot_config(snapshot.as_ref(), false).expect("snapshot renders");
+    assert!(config.contains("    ssl_provider acme zerossl {\n"));
+    assert!(config.contains("        acme_certificate zerossl step;\n"));
+    let rendered = RuntimeState::new(ConfigSnapshot::new("v4", router_from_config(&config)));
+    assert_eq!(
+        proto_snapshot_from_runtime(rendered.snapshot().as_ref()).unwrap(),
+        proto_snapshot
+    );
+}
+
+#[test]
+fn snapshot_with_inline_pem_does_not_render_as_config() {
+    let state = RuntimeState::new(ConfigSnapshot::new("v1", router_with_tls_and_plugin()));
+
+    let err = render_snapshot_config(state.snapshot().as_ref(), false).unwrap_err();
+    assert!(err.contains("inline PEM"), "{err}");
+}
+
 #[test]
 fn runtime_return_route_converts_back_to_proto_redirect() {
     let http = Http {
@@ -531,13 +1584,221 @@ fn runtime_return_route_converts_back_to_proto_redirect() {
     );
 }
 
+#[test]
+fn proto_static_files_route_roundtrips() {
+    let static_files = proto::StaticFiles {
+        root: String::new(),
+        alias: "/srv/assets/".into(),
+        index: vec!["index.html".into()],
+        try_files: vec!["$uri".into(), "$uri/".into()],
+        fallback: Some(proto::static_files::Fallback::FallbackStatus(404)),
+    };
+    let snapshot = proto::ConfigSnapshot {
+        version: "v-static".into(),
+        http: Some(proto::HttpOptions::default()),
+        listeners: vec![proto::Listener {
+            name: "edge".into(),
+            address: "0.0.0.0".into(),
+            port: 8080,
+            tls: false,
+            http2: false,
+            http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            tls_options: None,
+        }],
+        upstreams: Vec::new(),
+        virtual_hosts: vec![proto::VirtualHost {
+            listener: "edge".into(),
+            server_names: vec!["example.com".into()],
+            default_server: true,
+            tls: None,
+            routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
+                r#match: Some(proto::Match {
+                    kind: Some(proto::r#match::Kind::Prefix("/assets/".into())),
+                }),
+                action: Some(proto::route::Action::StaticFiles(static_files.clone())),
+                timeouts: None,
+                cache: None,
+                retry: None,
+                plugins: Vec::new(),
+                tls_options: None,
+                upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
+            }],
+            request_id: None,
+            acme_issuers: Vec::new(),
+        }],
+        le_config: None,
+        acme_issuers: Vec::new(),
+    };
+
+    let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
+    let state = RuntimeState::new(runtime);
+    let snapshot = state.snapshot();
+    let proto =
+        proto_snapshot_from_runtime(snapshot.as_ref()).expect("runtime snapshot serializes");
+
+    assert_eq!(
+        proto.virtual_hosts[0].routes[0].action.as_ref(),
+        Some(&proto::route::Action::StaticFiles(static_files))
+    );
+}
+
+#[test]
+fn proto_static_files_requires_fallback_with_try_files() {
+    let mut snapshot = proto::ConfigSnapshot {
+        version: "v-static".into(),
+        http: Some(proto::HttpOptions::default()),
+        listeners: vec![proto::Listener {
+            name: "edge".into(),
+            address: "0.0.0.0".into(),
+            port: 8080,
+            tls: false,
+            http2: false,
+            http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
+            tls_options: None,
+        }],
+        upstreams: Vec::new(),
+        virtual_hosts: vec![proto::VirtualHost {
+            listener: "edge".into(),
+            server_names: vec!["example.com".into()],
+            default_server: true,
+            tls: None,
+            routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
+                r#match: Some(proto::Match {
+                    kind: Some(proto::r#match::Kind::Prefix("/".into())),
+                }),
+                action: Some(proto::route::Action::StaticFiles(proto::StaticFiles {
+                    root: "/srv/www".into(),
+                    try_files: vec!["$uri".into()],
+                    ..proto::StaticFiles::default()
+                })),
+                timeouts: None,
+                cache: None,
+                retry: None,
+                plugins: Vec::new(),
+                tls_options: None,
+                upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
+            }],
+            request_id: None,
+            acme_issuers: Vec::new(),
+        }],
+        le_config: None,
+        acme_issuers: Vec::new(),
+    };
+
+    let err = runtime_snapshot_from_proto(snapshot.clone()).expect_err("fallback is required");
+    assert!(err.to_string().contains("fallback"), "{err}");
+
+    snapshot.virtual_hosts[0].routes[0].action =
+        Some(proto::route::Action::StaticFiles(proto::StaticFiles {
+            root: "/srv/www".into(),
+            alias: "/srv/assets".into(),
+            ..proto::StaticFiles::default()
+        }));
+    let err = runtime_snapshot_from_proto(snapshot).expect_err("root and alias conflict");
+    assert!(err.to_string().contains("root or alias"), "{err}");
+}
+
+#[test]
+fn proto_listener_proxy_protocol_roundtrips() {
+    let mut snapshot = proto::ConfigSnapshot {
+        version: "v-proxy-protocol".into(),
+        http: Some(proto::HttpOptions::default()),
+        listeners: vec![proto::Listener {
+            name: "edge".into(),
+            address: "0.0.0.0".into(),
+            port: 8080,
+            tls: false,
+            http2: false,
+            http2_only: false,
+            proxy_protocol: true,
+            proxy_protocol_trusted: vec!["10.0.0.0/8".into(), "192.0.2.10".into()],
+            tls_options: None,
+        }],
+        upstreams: Vec::new(),
+        virtual_hosts: vec![proto::VirtualHost {
+            listener: "edge".into(),
+            server_names: vec!["example.com".into()],
+            default_server: true,
+            tls: None,
+            routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
+                r#match: Some(proto::Match {
+                    kind: Some(proto::r#match::Kind::Prefix("/".into())),
+                }),
+                action: Some(proto::route::Action::Redirect(proto::Redirect {
+                    status: 302,
+                    location: "/".into(),
+                })),
+                timeouts: None,
+                cache: None,
+                retry: None,
+                plugins: Vec::new(),
+                tls_options: None,
+                upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
+            }],
+            request_id: None,
+            acme_issuers: Vec::new(),
+        }],
+        le_config: None,
+        acme_issuers: Vec::new(),
+    };
+
+    let runtime = runtime_snapshot_from_proto(snapshot.clone()).expect("proto snapshot compiles");
+    let state = RuntimeState::new(runtime);
+    let active = state.snapshot();
+    let protocol = active
+        .router
+        .listener_protocols
+        .values()
+        .next()
+        .expect("listener protocol");
+    assert!(protocol.proxy_protocol);
+    assert_eq!(
+        protocol.proxy_protocol_trusted,
+        vec![
+            "10.0.0.0/8".parse::<IpNet>().unwrap(),
+            "192.0.2.10/32".parse::<IpNet>().unwrap()
+        ]
+    );
+
+    let proto = proto_snapshot_from_runtime(active.as_ref()).expect("runtime snapshot serializes");
+    assert!(proto.listeners[0].proxy_protocol);
+    assert_eq!(
+        proto.listeners[0].proxy_protocol_trusted,
+        vec!["10.0.0.0/8".to_string(), "192.0.2.10/32".to_string()]
+    );
+
+    snapshot.listeners[0].proxy_protocol_trusted.clear();
+    let err = runtime_snapshot_from_proto(snapshot.clone()).expect_err("trusted list is required");
+    assert!(
+        err.to_string().contains("without proxy_protocol_trusted"),
+        "{err}"
+    );
+
+    snapshot.listeners[0].proxy_protocol_trusted = vec!["not-a-network".into()];
+    let err = runtime_snapshot_from_proto(snapshot).expect_err("invalid network");
+    assert!(
+        err.to_string().contains("invalid proxy_protocol_trusted"),
+        "{err}"
+    );
+}
+
 fn test_route_plugins() -> Vec<PluginSpec> {
     #[cfg(feature = "plugin-headers")]
     {
-        return vec![PluginSpec {
+        vec![PluginSpec {
             name: "headers".into(),
             config: serde_json::json!({"response":{"add":[["x-proxy","ngxora"]]}}),
-        }];
+        }]
     }
 
     #[cfg(not(feature = "plugin-headers"))]
@@ -561,6 +1822,8 @@ fn proto_snapshot_roundtrips_upstream_client_certificate() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -570,6 +1833,8 @@ fn proto_snapshot_roundtrips_upstream_client_certificate() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/".into())),
                 }),
@@ -581,6 +1846,7 @@ fn proto_snapshot_roundtrips_upstream_client_certificate() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: Some(proto::UpstreamTlsOptions {
                     verify: proto::Switch::On as i32,
@@ -594,8 +1860,11 @@ fn proto_snapshot_roundtrips_upstream_client_certificate() {
                 }),
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
@@ -636,6 +1905,8 @@ fn proto_snapshot_rejects_client_cert_without_key() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -645,6 +1916,8 @@ fn proto_snapshot_rejects_client_cert_without_key() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/".into())),
                 }),
@@ -656,6 +1929,7 @@ fn proto_snapshot_rejects_client_cert_without_key() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: Some(proto::UpstreamTlsOptions {
                     verify: proto::Switch::On as i32,
@@ -667,8 +1941,11 @@ fn proto_snapshot_rejects_client_cert_without_key() {
                 }),
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let err =
@@ -685,19 +1962,35 @@ fn router_with_tls_and_plugin() -> CompiledRouter {
                 UpstreamServer {
                     host: "backend-1.internal".into(),
                     port: 8443,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
                 },
                 UpstreamServer {
                     host: "backend-2.internal".into(),
                     port: 9443,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
                 },
             ],
             health_check: Some(UpstreamHealthCheck {
                 check_type: UpstreamHealthCheckType::Tcp,
+                port: None,
                 timeout: Duration::from_secs(1),
                 interval: Duration::from_secs(5),
                 consecutive_success: 1,
                 consecutive_failure: 2,
             }),
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
         }],
         servers: vec![Server {
             server_names: vec!["example.com".into()],
@@ -736,6 +2029,8 @@ fn router_with_tls_and_plugin() -> CompiledRouter {
                 default_server: true,
                 http2: true,
                 http2_only: false,
+                proxy_protocol: false,
+                proxy_protocol_trusted: Vec::new(),
             }],
             tls: Some(SslProvider::Custom(TlsIdentity {
                 cert: PemSource::Path("/etc/ngxora/tls/example.crt".into()),
@@ -753,7 +2048,12 @@ fn router_with_tls_and_plugin() -> CompiledRouter {
         allow_connect_method_proxying: Switch::Off,
         h2c: Switch::Off,
         proxy_cache_max_size: None,
-        ssl_provider: None,
+        proxy_cache_store: CacheStoreConfig::Memory,
+        acme_issuers: Vec::new(),
+        resolver: None,
+        metrics_label_limit: None,
+        log_formats: Vec::new(),
+        access_logs: Vec::new(),
     };
 
     CompiledRouter::from_http(&http).expect("router compiles")
@@ -776,6 +2076,8 @@ fn proto_upstream_tls_options_roundtrips_client_certificate() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -785,6 +2087,8 @@ fn proto_upstream_tls_options_roundtrips_client_certificate() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/".into())),
                 }),
@@ -796,6 +2100,7 @@ fn proto_upstream_tls_options_roundtrips_client_certificate() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: Some(proto::UpstreamTlsOptions {
                     verify: proto::Switch::On as i32,
@@ -809,8 +2114,11 @@ fn proto_upstream_tls_options_roundtrips_client_certificate() {
                 }),
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let runtime = runtime_snapshot_from_proto(snapshot).expect("proto snapshot compiles");
@@ -877,6 +2185,8 @@ fn proto_rejects_client_certificate_without_key() {
             tls: false,
             http2: false,
             http2_only: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: Vec::new(),
             tls_options: None,
         }],
         upstreams: Vec::new(),
@@ -886,6 +2196,8 @@ fn proto_rejects_client_certificate_without_key() {
             default_server: true,
             tls: None,
             routes: vec![proto::Route {
+                name: String::new(),
+                access_logs: Vec::new(),
                 r#match: Some(proto::Match {
                     kind: Some(proto::r#match::Kind::Prefix("/".into())),
                 }),
@@ -897,6 +2209,7 @@ fn proto_rejects_client_certificate_without_key() {
                 })),
                 timeouts: None,
                 cache: None,
+                retry: None,
                 plugins: Vec::new(),
                 tls_options: Some(proto::UpstreamTlsOptions {
                     verify: proto::Switch::On as i32,
@@ -910,8 +2223,11 @@ fn proto_rejects_client_certificate_without_key() {
                 }),
                 upstream_protocol: proto::UpstreamHttpProtocol::Unspecified as i32,
             }],
+            request_id: None,
+            acme_issuers: Vec::new(),
         }],
         le_config: None,
+        acme_issuers: Vec::new(),
     };
 
     let err = runtime_snapshot_from_proto(snapshot).expect_err("expected rejection");
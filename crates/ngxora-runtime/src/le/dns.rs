@@ -0,0 +1,515 @@
+//! DNS-01 challenge records.
+//!
+//! A [`DnsProvider`] publishes and removes the `_acme-challenge` TXT record
+//! for a domain. [`Rfc2136`] does it with dynamic updates (RFC 2136) sent to
+//! the zone's primary server, optionally signed with a TSIG key (RFC 8945).
+//! Embedders can pass their own provider to `LeManager::with_dns_provider`.
+//!
+//! Validation only starts once [`await_propagation`] has seen the record on
+//! every authoritative server, so the CA cannot query one that lags behind.
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hickory_proto::dnssec::rdata::tsig::TsigAlgorithm as HickoryTsigAlgorithm;
+use hickory_proto::dnssec::tsig::TSigner;
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode, update_message};
+use hickory_proto::rr::rdata::TXT;
+use hickory_proto::rr::{Name, RData, Record, RecordSet, RecordType};
+use ngxora_compile::ir::{AcmeDnsProvider, Rfc2136Provider, TsigAlgorithm};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::time::{self, Instant};
+
+/// TTL of published challenge records; they only live for one validation.
+const CHALLENGE_TTL: u32 = 60;
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay between checks while a record propagates.
+const PROPAGATION_INTERVAL: Duration = Duration::from_secs(2);
+/// Allowed clock skew between ngxora and the DNS server for TSIG.
+const TSIG_FUDGE: u16 = 300;
+
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// Adds a TXT record holding `value` at the fully qualified `name`.
+    async fn add_txt(&self, name: &str, value: &str) -> Result<(), String>;
+
+    /// Removes the record `add_txt` created.
+    async fn remove_txt(&self, name: &str, value: &str) -> Result<(), String>;
+
+    /// Whether every authoritative server of the zone serves the record
+    /// `add_txt` created. Providers that cannot tell report `true`.
+    async fn is_propagated(&self, _name: &str, _value: &str) -> Result<bool, String> {
+        Ok(true)
+    }
+}
+
+/// Waits until `provider` reports the record as propagated, checking every
+/// couple of seconds for at most `timeout`.
+pub(super) async fn await_propagation(
+    provider: &dyn DnsProvider,
+    name: &str,
+    value: &str,
+    timeout: Duration,
+) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let last_error = match provider.is_propagated(name, value).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => None,
+            Err(e) => Some(e),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(match last_error {
+                Some(e) => format!("{name} not seen on every name server after {timeout:?}: {e}"),
+                None => format!("{name} not seen on every name server after {timeout:?}"),
+            });
+        }
+        time::sleep(PROPAGATION_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Builds the provider selected in `ssl_provider letsencrypt { ... }`.
+pub fn provider_from_config(config: &AcmeDnsProvider) -> Result<Arc<dyn DnsProvider>, String> {
+    match config {
+        AcmeDnsProvider::Rfc2136(rfc2136) => Ok(Arc::new(Rfc2136::new(rfc2136)?)),
+    }
+}
+
+/// RFC 2136 dynamic update client.
+pub struct Rfc2136 {
+    server: SocketAddr,
+    zone: Name,
+    signer: Option<TSigner>,
+}
+
+impl Rfc2136 {
+    pub fn new(config: &Rfc2136Provider) -> Result<Self, String> {
+        let zone = fqdn(&config.zone)?;
+        let signer = config
+            .tsig_key
+            .as_ref()
+            .map(|key| {
+                let secret = STANDARD
+                    .decode(&key.secret)
+                    .map_err(|e| format!("TSIG key `{}` secret is not base64: {e}", key.name))?;
+                let algorithm = match key.algorithm {
+                    TsigAlgorithm::HmacSha256 => HickoryTsigAlgorithm::HmacSha256,
+                    TsigAlgorithm::HmacSha384 => HickoryTsigAlgorithm::HmacSha384,
+                    TsigAlgorithm::HmacSha512 => HickoryTsigAlgorithm::HmacSha512,
+                };
+                TSigner::new(secret, algorithm, fqdn(&key.name)?, TSIG_FUDGE)
+                    .map_err(|e| format!("invalid TSIG key `{}`: {e}", key.name))
+            })
+            .transpose()?;
+        Ok(Self {
+            server: config.server,
+            zone,
+            signer,
+        })
+    }
+
+    fn record_set(&self, name: &str, value: &str) -> Result<RecordSet, String> {
+        let name = fqdn(name)?;
+        if !self.zone.zone_of(&name) {
+            return Err(format!("{name} is outside DNS zone {}", self.zone));
+        }
+        let record = Record::from_rdata(
+            name,
+            CHALLENGE_TTL,
+            RData::TXT(TXT::new(vec![value.to_string()])),
+        );
+        Ok(RecordSet::from(record))
+    }
+
+    async fn send(&self, mut message: Message) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as u32);
+        let verifier = match &self.signer {
+            Some(signer) => message
+                .finalize(signer, now)
+                .map_err(|e| format!("failed to sign DNS update: {e}"))?,
+            None => None,
+        };
+        let request = message
+            .to_vec()
+            .map_err(|e| format!("failed to encode DNS update: {e}"))?;
+        let response = exchange(self.server, &request, UPDATE_TIMEOUT)
+            .await
+            .map_err(|e| format!("DNS update: {e}"))?;
+        let response = match verifier {
+            Some(mut verify) => verify(&response)
+                .map_err(|e| format!("DNS update response from {} failed TSIG: {e}", self.server))?
+                .into_message(),
+            None => Message::from_vec(&response)
+                .map_err(|e| format!("invalid DNS update response: {e}"))?,
+        };
+        if response.id() != message.id() {
+            return Err(format!(
+                "DNS update response from {} has the wrong ID",
+                self.server
+            ));
+        }
+        match response.response_code() {
+            ResponseCode::NoError => Ok(()),
+            code => Err(format!("DNS update rejected by {}: {code}", self.server)),
+        }
+    }
+
+    /// Addresses of the zone's name servers, from the NS records the primary
+    /// serves and their glue, or the system resolver for names without glue.
+    /// The primary stands in when it lists none.
+    async fn name_servers(&self) -> Result<Vec<SocketAddr>, String> {
+        let response = query(self.server, &self.zone, RecordType::NS).await?;
+        let mut servers = Vec::new();
+        for record in response.answers() {
+            let RData::NS(host) = record.data() else {
+                continue;
+            };
+            let glue: Vec<SocketAddr> = response
+                .additionals()
+                .iter()
+                .filter(|glue| glue.name() == &host.0)
+                .filter_map(|glue| match glue.data() {
+                    RData::A(a) => Some(IpAddr::V4(a.0)),
+                    RData::AAAA(aaaa) => Some(IpAddr::V6(aaaa.0)),
+                    _ => None,
+                })
+                .map(|ip| SocketAddr::new(ip, 53))
+                .collect();
+            if glue.is_empty() {
+                let host = host.0.to_ascii();
+                let resolved = lookup_host((host.trim_end_matches('.'), 53))
+                    .await
+                    .map_err(|e| format!("failed to resolve name server {host}: {e}"))?;
+                servers.extend(resolved);
+            } else {
+                servers.extend(glue);
+            }
+        }
+        if servers.is_empty() {
+            servers.push(self.server);
+        }
+        Ok(servers)
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Rfc2136 {
+    async fn add_txt(&self, name: &str, value: &str) -> Result<(), String> {
+        let records = self.record_set(name, value)?;
+        self.send(update_message::append(
+            records,
+            self.zone.clone(),
+            false,
+            false,
+        ))
+        .await
+    }
+
+    async fn remove_txt(&self, name: &str, value: &str) -> Result<(), String> {
+        let records = self.record_set(name, value)?;
+        self.send(update_message::delete_by_rdata(
+            records,
+            self.zone.clone(),
+            false,
+        ))
+        .await
+    }
+
+    async fn is_propagated(&self, name: &str, value: &str) -> Result<bool, String> {
+        let name = fqdn(name)?;
+        for server in self.name_servers().await? {
+            let response = query(server, &name, RecordType::TXT).await?;
+            let served = response.answers().iter().any(|record| match record.data() {
+                RData::TXT(txt) => txt.txt_data().concat() == value.as_bytes(),
+                _ => false,
+            });
+            if !served {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Non-recursive query answered by `server` itself.
+async fn query(
+    server: SocketAddr,
+    name: &Name,
+    record_type: RecordType,
+) -> Result<Message, String> {
+    let mut message = Message::new();
+    message
+        .set_id(fastrand::u16(..))
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(name.clone(), record_type));
+    let request = message
+        .to_vec()
+        .map_err(|e| format!("failed to encode DNS query: {e}"))?;
+    let response = exchange(server, &request, QUERY_TIMEOUT)
+        .await
+        .map_err(|e| format!("{record_type} query for {name}: {e}"))?;
+    let response =
+        Message::from_vec(&response).map_err(|e| format!("invalid DNS response: {e}"))?;
+    if response.id() != message.id() {
+        return Err(format!("DNS response from {server} has the wrong ID"));
+    }
+    match response.response_code() {
+        ResponseCode::NoError | ResponseCode::NXDomain => Ok(response),
+        code => Err(format!(
+            "{record_type} query for {name} failed at {server}: {code}"
+        )),
+    }
+}
+
+// Sends one UDP datagram to `server` and returns its answer.
+async fn exchange(
+    server: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, String> {
+    let local: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|e| format!("failed to bind DNS socket: {e}"))?;
+    socket
+        .connect(server)
+        .await
+        .map_err(|e| format!("failed to connect to DNS server {server}: {e}"))?;
+    socket
+        .send(request)
+        .await
+        .map_err(|e| format!("failed to send to {server}: {e}"))?;
+
+    let mut buf = vec![0; 4096];
+    let len = time::timeout(timeout, socket.recv(&mut buf))
+        .await
+        .map_err(|_| format!("{server} timed out"))?
+        .map_err(|e| format!("failed to read response from {server}: {e}"))?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn fqdn(name: &str) -> Result<Name, String> {
+    let mut parsed =
+        Name::from_ascii(name).map_err(|e| format!("invalid DNS name `{name}`: {e}"))?;
+    parsed.set_fqdn(true);
+    Ok(parsed)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use hickory_proto::dnssec::rdata::tsig::{TSIG, make_tsig_record, message_tbs};
+    use hickory_proto::op::UpdateMessage;
+    use hickory_proto::rr::DNSClass;
+    use ngxora_compile::ir::{DEFAULT_DNS_PROPAGATION_TIMEOUT, TsigKey};
+    use std::sync::Mutex;
+
+    /// Secret of the `acme-update` TSIG key the stub verifies.
+    pub(in crate::le) const SECRET: &str = "c2VjcmV0LWZvci10ZXN0cw==";
+
+    fn config(server: SocketAddr) -> Rfc2136Provider {
+        Rfc2136Provider {
+            server,
+            zone: "example.com".into(),
+            tsig_key: Some(TsigKey::new("acme-update", "hmac-sha256", SECRET).unwrap()),
+            propagation_timeout: DEFAULT_DNS_PROPAGATION_TIMEOUT,
+        }
+    }
+
+    /// Accepts TSIG-signed updates, records `(class, name, txt)` for each
+    /// update RR and answers with `code`, signed like a primary server would.
+    /// Queries are answered from the records the updates left in place; the
+    /// stub lists no NS records, so it is the zone's only name server.
+    pub(in crate::le) async fn stub_primary(
+        code: ResponseCode,
+        updates: Arc<Mutex<Vec<(DNSClass, String, String)>>>,
+    ) -> SocketAddr {
+        let signer = TSigner::new(
+            STANDARD.decode(SECRET).unwrap(),
+            HickoryTsigAlgorithm::HmacSha256,
+            Name::from_ascii("acme-update.").unwrap(),
+            TSIG_FUDGE,
+        )
+        .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+                    return;
+                };
+                if let Ok(request) = Message::from_vec(&buf[..len])
+                    && request.op_code() == OpCode::Query
+                {
+                    let response = answer(&request, &updates.lock().unwrap());
+                    let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+                    continue;
+                }
+                let Ok((request_mac, _, time)) =
+                    signer.verify_message_byte(None, &buf[..len], true)
+                else {
+                    continue;
+                };
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                assert_eq!(request.op_code(), OpCode::Update);
+                for record in request.updates() {
+                    let txt = match record.data() {
+                        RData::TXT(txt) => txt.to_string(),
+                        _ => String::new(),
+                    };
+                    updates.lock().unwrap().push((
+                        record.dns_class(),
+                        record.name().to_string(),
+                        txt,
+                    ));
+                }
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Update)
+                    .set_response_code(code);
+                let pre_tsig = TSIG::new(
+                    HickoryTsigAlgorithm::HmacSha256,
+                    time,
+                    TSIG_FUDGE,
+                    Vec::new(),
+                    request.id(),
+                    0,
+                    Vec::new(),
+                );
+                let tbs = message_tbs(
+                    Some(&request_mac),
+                    &response,
+                    &pre_tsig,
+                    signer.signer_name(),
+                )
+                .unwrap();
+                let mac = signer.sign(&tbs).unwrap();
+                response.add_tsig(make_tsig_record(
+                    signer.signer_name().clone(),
+                    pre_tsig.set_mac(mac),
+                ));
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+        addr
+    }
+
+    // TXT records still published by `updates`, for the queried name.
+    fn answer(request: &Message, updates: &[(DNSClass, String, String)]) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .add_queries(request.queries().to_vec());
+        let query = &request.queries()[0];
+        if query.query_type() != RecordType::TXT {
+            return response;
+        }
+        let name = query.name().to_string();
+        let mut published: Vec<&str> = Vec::new();
+        for (class, updated, txt) in updates {
+            if *updated != name {
+                continue;
+            }
+            match *class {
+                DNSClass::IN => published.push(txt),
+                _ => published.retain(|value| *value != txt.as_str()),
+            }
+        }
+        for txt in published {
+            response.add_answer(Record::from_rdata(
+                query.name().clone(),
+                CHALLENGE_TTL,
+                RData::TXT(TXT::new(vec![txt.to_string()])),
+            ));
+        }
+        response
+    }
+
+    #[tokio::test]
+    async fn rfc2136_adds_and_removes_signed_txt_records() {
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let server = stub_primary(ResponseCode::NoError, Arc::clone(&updates)).await;
+        let provider = Rfc2136::new(&config(server)).unwrap();
+
+        provider
+            .add_txt("_acme-challenge.www.example.com", "digest-value")
+            .await
+            .unwrap();
+        provider
+            .remove_txt("_acme-challenge.www.example.com", "digest-value")
+            .await
+            .unwrap();
+
+        let name = "_acme-challenge.www.example.com.".to_string();
+        assert_eq!(
+            *updates.lock().unwrap(),
+            vec![
+                (DNSClass::IN, name.clone(), "digest-value".to_string()),
+                (DNSClass::NONE, name, "digest-value".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rfc2136_waits_for_the_name_servers_to_serve_the_record() {
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let server = stub_primary(ResponseCode::NoError, Arc::clone(&updates)).await;
+        let provider = Rfc2136::new(&config(server)).unwrap();
+        let name = "_acme-challenge.www.example.com";
+
+        assert!(!provider.is_propagated(name, "digest-value").await.unwrap());
+        let err = await_propagation(&provider, name, "digest-value", Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.contains("not seen on every name server"), "{err}");
+
+        provider.add_txt(name, "digest-value").await.unwrap();
+        assert!(!provider.is_propagated(name, "other-value").await.unwrap());
+        await_propagation(&provider, name, "digest-value", Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        provider.remove_txt(name, "digest-value").await.unwrap();
+        assert!(!provider.is_propagated(name, "digest-value").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rfc2136_reports_refused_updates_and_foreign_names() {
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let server = stub_primary(ResponseCode::Refused, Arc::clone(&updates)).await;
+        let provider = Rfc2136::new(&config(server)).unwrap();
+
+        let err = provider
+            .add_txt("_acme-challenge.example.com", "value")
+            .await
+            .unwrap_err();
+        assert!(err.contains("rejected"), "{err}");
+
+        let err = provider
+            .add_txt("_acme-challenge.example.org", "value")
+            .await
+            .unwrap_err();
+        assert!(err.contains("outside DNS zone"), "{err}");
+        assert_eq!(updates.lock().unwrap().len(), 1);
+    }
+}
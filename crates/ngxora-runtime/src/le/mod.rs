@@ -0,0 +1,753 @@
+//! Let's Encrypt / ACME certificate manager.
+//!
+//! ## Architecture
+//!
+//! 1. `LeManager::new()` — creates or restores the ACME account of one issuer
+//!    (`ssl_provider letsencrypt` or `ssl_provider acme <name>`) via `instant-acme`.
+//! 2. `reconcile()` — checks all ACME-managed domains and (re)issues through
+//!    each domain's issuers in order until one succeeds.
+//! 3. `LeReconcilerService` — background task: immediate reconcile, then every hour.
+//! 4. `ChallengeTokens` — shared store for HTTP-01 responses.  The proxy must check
+//!    `lookup_challenge()` for `/.well-known/acme-challenge/<token>` requests.
+//! 5. `TlsAlpnChallenges` — TLS-ALPN-01 certificates, served by the listener's
+//!    certificate callback to clients offering `acme-tls/1`.
+//! 6. `DnsProvider` — publishes DNS-01 TXT records and reports when the
+//!    zone's name servers serve them; see the `dns` module.
+
+mod dns;
+mod tls_alpn;
+
+pub use dns::{DnsProvider, Rfc2136, provider_from_config};
+pub use tls_alpn::{
+    ACME_TLS_ALPN_WIRE, TlsAlpnCertificate, TlsAlpnChallenges, lookup_tls_alpn_challenge,
+};
+
+use crate::upstreams::CompiledRouter;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use dashmap::DashMap;
+use dns::await_propagation;
+use instant_acme::{
+    Account, AccountBuilder, ChallengeHandle, ChallengeType, ExternalAccountKey, Identifier,
+    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+};
+use ngxora_compile::ir::{
+    AcmeChallenge, AcmeDnsProvider, AcmeIssuer, AcmeKeyType, DEFAULT_DNS_PROPAGATION_TIMEOUT,
+    LETSENCRYPT_ISSUER, PemSource,
+};
+use openssl::pkey::{Id, PKey, Private};
+use pingora::tls::x509;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tls_alpn::TlsAlpnChallengeGuard;
+use tokio::time;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+/// Atomically write `data` to `path` with 0o600 permissions (owner-only).
+fn write_secure(path: &Path, data: &[u8]) -> Result<(), String> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut temp = NamedTempFile::new_in(parent).map_err(|e| {
+        format!(
+            "failed to create temporary file for {}: {e}",
+            path.display()
+        )
+    })?;
+    #[cfg(unix)]
+    {
+        temp.as_file()
+            .set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("failed to set permissions on {}: {e}", path.display()))?;
+    }
+    temp.write_all(data)
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    temp.as_file()
+        .sync_all()
+        .map_err(|e| format!("failed to sync {}: {e}", path.display()))?;
+    temp.persist(path)
+        .map_err(|e| format!("failed to persist {}: {}", path.display(), e.error))?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Challenge token store
+// ---------------------------------------------------------------------------
+
+pub type ChallengeTokens = Arc<DashMap<String, String>>;
+
+pub fn lookup_challenge(tokens: &ChallengeTokens, token: &str) -> Option<String> {
+    tokens.get(token).map(|v| v.clone())
+}
+
+struct ChallengeTokenGuard {
+    tokens: ChallengeTokens,
+    token: String,
+}
+
+impl ChallengeTokenGuard {
+    fn insert(tokens: &ChallengeTokens, token: String, key_authorization: String) -> Self {
+        tokens.insert(token.clone(), key_authorization);
+        Self {
+            tokens: Arc::clone(tokens),
+            token,
+        }
+    }
+}
+
+impl Drop for ChallengeTokenGuard {
+    fn drop(&mut self) {
+        self.tokens.remove(&self.token);
+    }
+}
+
+/// Proof of control kept published until the ACME server has validated it.
+enum PublishedChallenge {
+    Http01(ChallengeTokenGuard),
+    TlsAlpn01(TlsAlpnChallengeGuard),
+    Dns01 { name: String, value: String },
+}
+
+// ---------------------------------------------------------------------------
+// Certificate status
+// ---------------------------------------------------------------------------
+
+enum CertStatus {
+    Fresh,
+    Missing,
+    ExpiringSoon,
+}
+
+// ---------------------------------------------------------------------------
+// Managed certificates
+// ---------------------------------------------------------------------------
+
+/// A certificate the reconciler keeps issued.
+#[derive(Debug, PartialEq, Eq)]
+struct ManagedCertificate {
+    domain: String,
+    cert: PathBuf,
+    key: PemSource,
+    /// Issuer names, tried in order.
+    issuers: Vec<String>,
+}
+
+/// ACME-managed certificates of `router`, sorted by domain.
+///
+/// Snapshots applied over gRPC before per-server issuers existed name the
+/// certificate path directly; those belong to the first issuer whose cache
+/// directory contains the path.
+fn managed_certificates(router: &CompiledRouter) -> Vec<ManagedCertificate> {
+    let mut seen = HashSet::new();
+    let mut managed = Vec::new();
+    for tls in router.listener_tls.values() {
+        for (domain, identity) in &tls.named {
+            let PemSource::Path(cert) = &identity.cert else {
+                continue;
+            };
+            if seen.contains(domain) {
+                continue;
+            }
+            let issuers = router.acme_certificates.get(domain).cloned().or_else(|| {
+                router
+                    .acme_issuers
+                    .iter()
+                    .find(|issuer| cert.starts_with(issuer.cache_dir_or_default()))
+                    .map(|issuer| vec![issuer.name.clone()])
+            });
+            let Some(issuers) = issuers else {
+                continue;
+            };
+            seen.insert(domain.clone());
+            managed.push(ManagedCertificate {
+                domain: domain.clone(),
+                cert: cert.clone(),
+                key: identity.key.clone(),
+                issuers,
+            });
+        }
+    }
+    managed.sort_by(|left, right| left.domain.cmp(&right.domain));
+    managed
+}
+
+/// Reconcile all ACME-managed domains in `router` with the issuer managers
+/// by name. Returns whether any certificate changed.
+pub async fn reconcile(
+    managers: &HashMap<String, Arc<LeManager>>,
+    router: &CompiledRouter,
+) -> bool {
+    let mut updated = false;
+    for managed in managed_certificates(router) {
+        updated |= ensure_certificate(managers, &managed).await;
+    }
+    updated
+}
+
+async fn ensure_certificate(
+    managers: &HashMap<String, Arc<LeManager>>,
+    managed: &ManagedCertificate,
+) -> bool {
+    let domain = &managed.domain;
+    match check_cert(&managed.cert) {
+        CertStatus::Fresh => return false,
+        CertStatus::ExpiringSoon => {
+            log::info!("{domain}: certificate expiring soon, renewing…")
+        }
+        CertStatus::Missing => log::info!("{domain}: no certificate found, obtaining…"),
+    }
+
+    for name in &managed.issuers {
+        let Some(manager) = managers.get(name) else {
+            log::warn!("{domain}: ACME issuer `{name}` is unavailable");
+            continue;
+        };
+        match manager
+            .issue_certificate(domain, &managed.cert, &managed.key)
+            .await
+        {
+            Ok(()) => return true,
+            Err(e) => log::warn!("{domain}: ACME issuer `{name}` failed: {e}"),
+        }
+    }
+    log::error!(
+        "LE certificate error for {domain}: none of the issuers {:?} obtained a certificate",
+        managed.issuers
+    );
+    false
+}
+
+fn check_cert(cert_path: &Path) -> CertStatus {
+    if !cert_path.exists() {
+        return CertStatus::Missing;
+    }
+    let pem = match fs::read(cert_path) {
+        Ok(p) => p,
+        Err(_) => return CertStatus::Missing,
+    };
+    let x509 = match x509::X509::from_pem(&pem) {
+        Ok(x) => x,
+        Err(_) => return CertStatus::Missing,
+    };
+    let not_after = x509.not_after();
+    let renew_before = match openssl::asn1::Asn1Time::days_from_now(30) {
+        Ok(t) => t,
+        Err(_) => return CertStatus::ExpiringSoon,
+    };
+    if not_after < renew_before {
+        CertStatus::ExpiringSoon
+    } else {
+        CertStatus::Fresh
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ACME issuer manager
+// ---------------------------------------------------------------------------
+
+pub struct LeManager {
+    config: AcmeIssuer,
+    account: Account,
+    challenge: ChallengeType,
+    pub tokens: ChallengeTokens,
+    tls_alpn: TlsAlpnChallenges,
+    dns: Option<Arc<dyn DnsProvider>>,
+    dns_propagation_timeout: Duration,
+}
+
+impl LeManager {
+    /// Create a new manager, restoring or creating the issuer's ACME account.
+    pub async fn new(config: &AcmeIssuer) -> Result<Self, String> {
+        Self::with_tokens(config, Arc::new(DashMap::new())).await
+    }
+
+    /// Create a manager that shares an existing token store (for snapshot reloads).
+    pub async fn with_tokens(config: &AcmeIssuer, tokens: ChallengeTokens) -> Result<Self, String> {
+        let (challenge, dns) = match &config.challenge {
+            AcmeChallenge::Http01 => (ChallengeType::Http01, None),
+            AcmeChallenge::TlsAlpn01 => (ChallengeType::TlsAlpn01, None),
+            AcmeChallenge::Dns01(provider) => {
+                (ChallengeType::Dns01, Some(provider_from_config(provider)?))
+            }
+        };
+        let dns_propagation_timeout = match &config.challenge {
+            AcmeChallenge::Dns01(AcmeDnsProvider::Rfc2136(rfc2136)) => rfc2136.propagation_timeout,
+            _ => DEFAULT_DNS_PROPAGATION_TIMEOUT,
+        };
+
+        let directory_url = config
+            .acme_directory
+            .clone()
+            .unwrap_or_else(|| instant_acme::LetsEncrypt::Production.url().to_string());
+
+        let cache_dir = config.cache_dir_or_default();
+
+        fs::create_dir_all(&cache_dir)
+            .map_err(|e| format!("failed to create LE cache dir {}: {e}", cache_dir.display()))?;
+
+        let account = Self::load_or_create_account(&directory_url, config, &cache_dir).await?;
+
+        Ok(Self {
+            config: config.clone(),
+            account,
+            challenge,
+            tokens,
+            tls_alpn: Arc::new(DashMap::new()),
+            dns,
+            dns_propagation_timeout,
+        })
+    }
+
+    /// Share the listeners' TLS-ALPN-01 certificate store.
+    pub fn with_tls_alpn_challenges(mut self, challenges: TlsAlpnChallenges) -> Self {
+        self.tls_alpn = challenges;
+        self
+    }
+
+    /// Publish DNS-01 records through `provider` instead of the configured one.
+    pub fn with_dns_provider(mut self, provider: Arc<dyn DnsProvider>) -> Self {
+        self.challenge = ChallengeType::Dns01;
+        self.dns = Some(provider);
+        self
+    }
+
+    /// The issuer configuration this manager was built from.
+    pub fn config(&self) -> &AcmeIssuer {
+        &self.config
+    }
+
+    // ------------------------------------------------------------------
+    // Account
+    // ------------------------------------------------------------------
+
+    /// `account.json` for the `letsencrypt` issuer, as before named issuers
+    /// existed; `accounts/<name>.json` for the others.
+    fn account_path(cache_dir: &Path, issuer: &str) -> PathBuf {
+        if issuer == LETSENCRYPT_ISSUER {
+            cache_dir.join("account.json")
+        } else {
+            cache_dir.join("accounts").join(format!("{issuer}.json"))
+        }
+    }
+
+    async fn load_or_create_account(
+        directory_url: &str,
+        config: &AcmeIssuer,
+        cache_dir: &Path,
+    ) -> Result<Account, String> {
+        let path = Self::account_path(cache_dir, &config.name);
+
+        let builder = Self::acme_builder(config)?;
+
+        if path.exists() {
+            let raw = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            let credentials: instant_acme::AccountCredentials = serde_json::from_str(&raw)
+                .map_err(|e| format!("failed to parse {}: {e}", path.display()))?;
+            builder
+                .from_credentials(credentials)
+                .await
+                .map_err(|e| format!("failed to restore ACME account: {e}"))
+        } else {
+            let contact = config
+                .email
+                .as_deref()
+                .map(|e| format!("mailto:{e}"))
+                .unwrap_or_default();
+            let contacts: Vec<&str> = vec![contact.as_str()];
+
+            let new_account = NewAccount {
+                contact: if contacts[0].is_empty() {
+                    &[]
+                } else {
+                    &contacts
+                },
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            };
+
+            let external_account = config
+                .external_account_binding
+                .as_ref()
+                .map(|binding| {
+                    URL_SAFE_NO_PAD
+                        .decode(binding.hmac_key.trim_end_matches('='))
+                        .map(|key| ExternalAccountKey::new(binding.key_id.clone(), &key))
+                        .map_err(|e| {
+                            format!(
+                                "external account binding `{}` HMAC key is not base64url: {e}",
+                                binding.key_id
+                            )
+                        })
+                })
+                .transpose()?;
+
+            let (account, credentials) = builder
+                .create(
+                    &new_account,
+                    directory_url.to_string(),
+                    external_account.as_ref(),
+                )
+                .await
+                .map_err(|e| format!("failed to create ACME account: {e}"))?;
+
+            let raw = serde_json::to_string(&credentials)
+                .map_err(|e| format!("failed to serialise ACME credentials: {e}"))?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("failed to create {}: {e}", parent.display()))?;
+            }
+            write_secure(&path, raw.as_bytes())?;
+
+            log::info!("created new ACME account at {}", path.display());
+            Ok(account)
+        }
+    }
+
+    fn acme_builder(config: &AcmeIssuer) -> Result<AccountBuilder, String> {
+        match &config.trusted_certificate {
+            Some(path) => Account::builder_with_root(path),
+            None => Account::builder(),
+        }
+        .map_err(|e| format!("failed to create ACME account builder: {e}"))
+    }
+
+    // ------------------------------------------------------------------
+    // Certificate lifecycle
+    // ------------------------------------------------------------------
+
+    async fn issue_certificate(
+        &self,
+        domain: &str,
+        cert_path: &Path,
+        key_source: &PemSource,
+    ) -> Result<(), String> {
+        // 1. Create ACME order.
+        let mut order = self
+            .account
+            .new_order(&NewOrder::new(&[Identifier::Dns(domain.into())]))
+            .await
+            .map_err(|e| format!("{domain}: failed to create ACME order: {e}"))?;
+
+        // 2. Get the first authorization.
+        let mut authorizations = order.authorizations();
+        let mut auth = authorizations
+            .next()
+            .await
+            .ok_or_else(|| format!("{domain}: order has no authorizations"))?
+            .map_err(|e| format!("{domain}: failed to get authorization: {e}"))?;
+
+        // 3. Get the handle for the configured challenge type.
+        let mut challenge = auth
+            .challenge(self.challenge.clone())
+            .ok_or_else(|| format!("{domain}: no {:?} challenge available", self.challenge))?;
+
+        // 4. Publish the key authorization where the ACME server looks for it.
+        let published = self.publish_challenge(domain, &challenge).await?;
+
+        // 5. Signal readiness, then poll until ready or invalid.
+        let status = match challenge.set_ready().await {
+            Ok(()) => order
+                .poll_ready(&RetryPolicy::default())
+                .await
+                .map_err(|e| format!("{domain}: order failed: {e}")),
+            Err(e) => Err(format!("{domain}: failed to set challenge ready: {e}")),
+        };
+
+        // 6. The proof is no longer needed once validation has finished.
+        self.withdraw_challenge(domain, published).await;
+        let status = status?;
+
+        if status != OrderStatus::Ready {
+            return Err(format!("{domain}: unexpected order status {status:?}"));
+        }
+
+        // 7. Load or generate the key, then finalize with a CSR.
+        let (private_key, new_key) = Self::load_or_generate_key(key_source, self.config.key_type)?;
+        let csr_der = Self::make_csr(&private_key, domain)?;
+
+        order
+            .finalize_csr(&csr_der)
+            .await
+            .map_err(|e| format!("{domain}: failed to finalize order: {e}"))?;
+
+        // 8. Poll for the certificate.
+        let cert_pem = order
+            .poll_certificate(&RetryPolicy::default())
+            .await
+            .map_err(|e| format!("{domain}: failed to get certificate: {e}"))?;
+
+        // 9. Write a new key, then the certificate, so the served pair only
+        //    changes once issuance has succeeded.
+        if let (true, PemSource::Path(key_path)) = (new_key, key_source) {
+            if let Some(parent) = key_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("failed to create key dir {}: {e}", parent.display()))?;
+            }
+            let pem = private_key
+                .private_key_to_pem_pkcs8()
+                .map_err(|e| format!("failed to encode private key: {e}"))?;
+            write_secure(key_path, &pem)?;
+        }
+        let parent = cert_path
+            .parent()
+            .ok_or_else(|| format!("{domain}: cert path {} has no parent", cert_path.display()))?;
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("{domain}: failed to create dir {}: {e}", parent.display()))?;
+        write_secure(cert_path, cert_pem.as_bytes())?;
+
+        log::info!(
+            "{domain}: obtained new certificate from ACME issuer `{}`, wrote to {}",
+            self.config.name,
+            cert_path.display()
+        );
+        Ok(())
+    }
+
+    async fn publish_challenge(
+        &self,
+        domain: &str,
+        challenge: &ChallengeHandle<'_>,
+    ) -> Result<PublishedChallenge, String> {
+        let key_auth = challenge.key_authorization();
+        match self.challenge {
+            ChallengeType::TlsAlpn01 => {
+                let certificate = TlsAlpnCertificate::new(domain, key_auth.digest().as_ref())
+                    .map_err(|e| format!("{domain}: {e}"))?;
+                Ok(PublishedChallenge::TlsAlpn01(
+                    TlsAlpnChallengeGuard::insert(&self.tls_alpn, domain, certificate),
+                ))
+            }
+            ChallengeType::Dns01 => {
+                let dns = self
+                    .dns
+                    .as_ref()
+                    .ok_or_else(|| format!("{domain}: DNS-01 needs a DNS provider"))?;
+                // A wildcard is validated at its parent domain's record.
+                let name = format!(
+                    "_acme-challenge.{}",
+                    domain.strip_prefix("*.").unwrap_or(domain)
+                );
+                let value = key_auth.dns_value();
+                dns.add_txt(&name, &value)
+                    .await
+                    .map_err(|e| format!("{domain}: failed to publish DNS-01 record: {e}"))?;
+                // The CA may ask any authoritative server, so validation only
+                // starts once they all serve the record, or the wait is over.
+                if let Err(e) =
+                    await_propagation(dns.as_ref(), &name, &value, self.dns_propagation_timeout)
+                        .await
+                {
+                    log::warn!("{domain}: {e}; asking the CA to validate anyway");
+                }
+                Ok(PublishedChallenge::Dns01 { name, value })
+            }
+            _ => Ok(PublishedChallenge::Http01(ChallengeTokenGuard::insert(
+                &self.tokens,
+                challenge.token.clone(),
+                key_auth.as_str().to_string(),
+            ))),
+        }
+    }
+
+    async fn withdraw_challenge(&self, domain: &str, published: PublishedChallenge) {
+        match published {
+            PublishedChallenge::Http01(guard) => drop(guard),
+            PublishedChallenge::TlsAlpn01(guard) => drop(guard),
+            PublishedChallenge::Dns01 { name, value } => {
+                if let Some(dns) = &self.dns
+                    && let Err(e) = dns.remove_txt(&name, &value).await
+                {
+                    log::warn!("{domain}: failed to remove DNS-01 record {name}: {e}");
+                }
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // CSR generation (openssl)
+    // ------------------------------------------------------------------
+
+    /// The key at `key_source` when it matches `key_type`, otherwise a new
+    /// one that still has to be written. A configured inline key is always
+    /// used as is.
+    fn load_or_generate_key(
+        key_source: &PemSource,
+        key_type: AcmeKeyType,
+    ) -> Result<(PKey<Private>, bool), String> {
+        match key_source {
+            PemSource::Path(path) => {
+                if !path.as_os_str().is_empty() && path.exists() {
+                    let pem_bytes = fs::read(path)
+                        .map_err(|e| format!("failed to read key {}: {e}", path.display()))?;
+                    let key = PKey::private_key_from_pem(&pem_bytes)
+                        .map_err(|e| format!("failed to parse key {}: {e}", path.display()))?;
+                    if Self::key_matches(&key, key_type) {
+                        return Ok((key, false));
+                    }
+                }
+                Ok((Self::generate_key(key_type)?, true))
+            }
+            PemSource::InlinePem(pem) => PKey::private_key_from_pem(pem.as_bytes())
+                .map(|key| (key, false))
+                .map_err(|e| format!("failed to parse inline key: {e}")),
+        }
+    }
+
+    fn key_matches(key: &PKey<Private>, key_type: AcmeKeyType) -> bool {
+        match key_type {
+            AcmeKeyType::Ecdsa => key.id() == Id::EC,
+            AcmeKeyType::Rsa => key.id() == Id::RSA,
+        }
+    }
+
+    fn generate_key(key_type: AcmeKeyType) -> Result<PKey<Private>, String> {
+        match key_type {
+            AcmeKeyType::Ecdsa => {
+                let ec_group =
+                    openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1)
+                        .map_err(|e| format!("failed to create EC group: {e}"))?;
+                let ec_key = openssl::ec::EcKey::generate(&ec_group)
+                    .map_err(|e| format!("failed to generate ECDSA key: {e}"))?;
+                PKey::from_ec_key(ec_key).map_err(|e| format!("failed to convert EC key: {e}"))
+            }
+            AcmeKeyType::Rsa => {
+                let rsa = openssl::rsa::Rsa::generate(2048)
+                    .map_err(|e| format!("failed to generate RSA key: {e}"))?;
+                PKey::from_rsa(rsa).map_err(|e| format!("failed to convert RSA key: {e}"))
+            }
+        }
+    }
+
+    fn make_csr(key: &PKey<Private>, domain: &str) -> Result<Vec<u8>, String> {
+        let mut req = openssl::x509::X509ReqBuilder::new()
+            .map_err(|e| format!("failed to create X509 req builder: {e}"))?;
+        req.set_pubkey(key)
+            .map_err(|e| format!("failed to set CSR public key: {e}"))?;
+
+        let mut extensions = openssl::stack::Stack::new()
+            .map_err(|e| format!("failed to create extension stack: {e}"))?;
+        let ctx = req.x509v3_context(None);
+        let san = openssl::x509::extension::SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&ctx)
+            .map_err(|e| format!("failed to build SAN extension: {e}"))?;
+        extensions
+            .push(san)
+            .map_err(|e| format!("failed to push SAN: {e}"))?;
+        req.add_extensions(&extensions)
+            .map_err(|e| format!("failed to add extensions: {e}"))?;
+
+        req.sign(key, openssl::hash::MessageDigest::sha256())
+            .map_err(|e| format!("failed to sign CSR: {e}"))?;
+
+        req.build()
+            .to_der()
+            .map_err(|e| format!("failed to DER-encode CSR: {e}"))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Background service (integrates with Pingora lifecycle)
+// ---------------------------------------------------------------------------
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+/// Pingora background service that manages the LE certificate lifecycle.
+///
+/// Created via [`LeReconcilerService::new`] and registered with
+/// `background_service("le-reconciler", LeReconcilerService::new(...))`.
+pub struct LeReconcilerService {
+    state: Arc<crate::control::RuntimeState>,
+    tokens: ChallengeTokens,
+}
+
+impl LeReconcilerService {
+    pub fn new(state: Arc<crate::control::RuntimeState>, tokens: ChallengeTokens) -> Self {
+        Self { state, tokens }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for LeReconcilerService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut managers = HashMap::new();
+
+        // First reconciliation immediately.
+        reconcile_once(&self.state, &self.tokens, &mut managers).await;
+
+        let mut hourly = time::interval(Duration::from_secs(3600));
+
+        loop {
+            if *shutdown.borrow() {
+                log::info!("LE reconciler shutting down");
+                return;
+            }
+
+            tokio::select! {
+                changed = shutdown.changed() => {
+                    if changed.is_err() || *shutdown.borrow() {
+                        log::info!("LE reconciler shutting down");
+                        return;
+                    }
+                }
+                _ = hourly.tick() => {
+                    reconcile_once(&self.state, &self.tokens, &mut managers).await;
+                }
+            }
+        }
+    }
+}
+
+/// Brings `managers` in line with the snapshot's issuers, then reconciles.
+/// Issuers whose manager cannot be built are left out and retried on the
+/// next run; their domains fall back to the remaining issuers meanwhile.
+async fn reconcile_once(
+    state: &crate::control::RuntimeState,
+    tokens: &ChallengeTokens,
+    managers: &mut HashMap<String, Arc<LeManager>>,
+) {
+    let snapshot = state.snapshot();
+    let issuers = &snapshot.router.acme_issuers;
+    managers.retain(|name, manager| {
+        issuers
+            .iter()
+            .any(|issuer| issuer == manager.config() && &issuer.name == name)
+    });
+
+    for issuer in issuers {
+        if managers.contains_key(&issuer.name) {
+            continue;
+        }
+        match LeManager::with_tokens(issuer, Arc::clone(tokens)).await {
+            Ok(m) => {
+                let m = m.with_tls_alpn_challenges(Arc::clone(state.tls_alpn_challenges()));
+                log::info!(
+                    "ACME issuer `{}` ready (cache: {:?})",
+                    issuer.name,
+                    issuer.cache_dir_or_default()
+                );
+                managers.insert(issuer.name.clone(), Arc::new(m));
+            }
+            Err(e) => log::error!("failed to create ACME issuer `{}`: {e}", issuer.name),
+        }
+    }
+
+    if reconcile(managers, &snapshot.router).await {
+        let revision = state.invalidate_tls_material();
+        log::info!("activated renewed TLS certificate material at revision {revision}");
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,458 @@
+//! Tests for the Let's Encrypt / ACME manager live here.
+//! Integration-style tests require a staging ACME endpoint; the Pebble test
+//! runs when `NGXORA_PEBBLE_DIRECTORY` and `NGXORA_PEBBLE_ROOT` are set.
+
+use super::*;
+use crate::control::RuntimeState;
+use crate::upstreams::CompiledRouter;
+use crate::upstreams::{ListenKey, ListenerTlsConfig};
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::DNSClass;
+use ngxora_compile::ir::{AcmeIssuer, Listen, TlsIdentity};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Once;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+fn install_rustls_provider() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .install_default()
+            .expect("install rustls crypto provider");
+    });
+}
+
+fn unique_test_dir(name: &str) -> PathBuf {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("ngxora-{name}-{id}"))
+}
+
+#[test]
+fn write_secure_atomically_replaces_file_with_private_permissions() {
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let path = dir.path().join("certificate.pem");
+    fs::write(&path, b"old").expect("write old content");
+
+    write_secure(&path, b"new certificate").expect("replace file");
+
+    assert_eq!(
+        fs::read(&path).expect("read replaced file"),
+        b"new certificate"
+    );
+    #[cfg(unix)]
+    assert_eq!(
+        fs::metadata(&path)
+            .expect("read file metadata")
+            .permissions()
+            .mode()
+            & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn challenge_token_guard_removes_token_on_early_return() {
+    fn fail_after_insert(tokens: &ChallengeTokens) -> Result<(), ()> {
+        let _guard = ChallengeTokenGuard::insert(
+            tokens,
+            "challenge-token".into(),
+            "key-authorization".into(),
+        );
+        assert_eq!(
+            lookup_challenge(tokens, "challenge-token").as_deref(),
+            Some("key-authorization")
+        );
+        Err(())
+    }
+
+    let tokens: ChallengeTokens = Arc::new(DashMap::new());
+
+    assert!(fail_after_insert(&tokens).is_err());
+    assert!(lookup_challenge(&tokens, "challenge-token").is_none());
+}
+
+#[tokio::test]
+async fn reconcile_once_retries_manager_creation_after_failure() {
+    install_rustls_provider();
+
+    let cache_dir = unique_test_dir("le-retry");
+    fs::create_dir_all(&cache_dir).expect("create cache dir");
+    fs::write(cache_dir.join("account.json"), b"{not-json").expect("write invalid account");
+
+    let state = RuntimeState::bootstrap(CompiledRouter {
+        acme_issuers: vec![AcmeIssuer {
+            email: Some("admin@example.com".into()),
+            cache_dir: Some(cache_dir.clone()),
+            ..AcmeIssuer::new("letsencrypt")
+        }],
+        ..CompiledRouter::default()
+    });
+    let tokens: ChallengeTokens = Arc::new(DashMap::new());
+    let mut managers = HashMap::new();
+
+    reconcile_once(&state, &tokens, &mut managers).await;
+
+    // The broken issuer is left out, so the next run builds it again.
+    assert!(managers.is_empty());
+
+    let _ = fs::remove_dir_all(cache_dir);
+}
+
+#[test]
+fn tls_alpn_certificate_carries_critical_acme_identifier() {
+    let digest = [0xab; 32];
+    let certificate = TlsAlpnCertificate::new("www.example.com", &digest).expect("build cert");
+
+    let names: Vec<String> = certificate
+        .cert
+        .subject_alt_names()
+        .expect("certificate has SANs")
+        .iter()
+        .filter_map(|name| name.dnsname().map(str::to_string))
+        .collect();
+    assert_eq!(names, ["www.example.com"]);
+
+    // OID 1.3.6.1.5.5.7.1.31, critical, then an OCTET STRING wrapping the
+    // DER OCTET STRING of the digest.
+    let mut extension = vec![
+        0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f, 0x01, 0x01, 0xff, 0x04, 0x22,
+        0x04, 0x20,
+    ];
+    extension.extend_from_slice(&digest);
+    let der = certificate.cert.to_der().expect("encode cert");
+    assert!(
+        der.windows(extension.len())
+            .any(|window| window == extension),
+        "acmeIdentifier extension missing"
+    );
+}
+
+#[test]
+fn tls_alpn_challenge_guard_unpublishes_certificate() {
+    let challenges = TlsAlpnChallenges::default();
+    let certificate = TlsAlpnCertificate::new("example.com", &[1; 32]).expect("build cert");
+
+    let guard = TlsAlpnChallengeGuard::insert(&challenges, "Example.COM", certificate);
+    assert!(lookup_tls_alpn_challenge(&challenges, "example.com").is_some());
+    drop(guard);
+    assert!(lookup_tls_alpn_challenge(&challenges, "example.com").is_none());
+}
+
+#[test]
+fn managed_certificates_follow_issuer_order_and_legacy_cache_paths() {
+    let identity = |dir: &str, domain: &str| TlsIdentity {
+        cert: PemSource::Path(PathBuf::from(format!("{dir}/{domain}/fullchain.pem"))),
+        key: PemSource::Path(PathBuf::from(format!("{dir}/{domain}/privkey.pem"))),
+    };
+    let mut tls = ListenerTlsConfig::default();
+    tls.named.insert(
+        "shop.example.com".into(),
+        identity("/certs/step", "shop.example.com"),
+    );
+    tls.named.insert(
+        "api.example.com".into(),
+        identity("/certs/le", "api.example.com"),
+    );
+    tls.named.insert(
+        "manual.example.com".into(),
+        identity("/etc/ssl", "manual.example.com"),
+    );
+    let router = CompiledRouter {
+        listener_tls: HashMap::from([(ListenKey::from(&Listen::default()), tls)]),
+        acme_issuers: vec![
+            AcmeIssuer {
+                cache_dir: Some(PathBuf::from("/certs/le")),
+                ..AcmeIssuer::new("letsencrypt")
+            },
+            AcmeIssuer {
+                acme_directory: Some("https://ca.internal/directory".into()),
+                cache_dir: Some(PathBuf::from("/certs/step")),
+                ..AcmeIssuer::new("step")
+            },
+        ],
+        acme_certificates: HashMap::from([(
+            "shop.example.com".into(),
+            vec!["step".into(), "letsencrypt".into()],
+        )]),
+        ..CompiledRouter::default()
+    };
+
+    let managed = managed_certificates(&router);
+    let issuers: Vec<(&str, Vec<String>)> = managed
+        .iter()
+        .map(|cert| (cert.domain.as_str(), cert.issuers.clone()))
+        .collect();
+    assert_eq!(
+        issuers,
+        [
+            // A gRPC snapshot naming a path in the issuer's cache directory.
+            ("api.example.com", vec!["letsencrypt".to_string()]),
+            (
+                "shop.example.com",
+                vec!["step".to_string(), "letsencrypt".to_string()]
+            ),
+        ]
+    );
+}
+
+#[test]
+fn certificate_key_is_replaced_only_when_the_key_type_changes() {
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let key_path = dir.path().join("privkey.pem");
+    let source = PemSource::Path(key_path.clone());
+
+    let (ecdsa, new_key) =
+        LeManager::load_or_generate_key(&source, AcmeKeyType::Ecdsa).expect("generate key");
+    assert!(new_key);
+    assert!(!key_path.exists(), "new keys are written after issuance");
+    fs::write(&key_path, ecdsa.private_key_to_pem_pkcs8().unwrap()).expect("write key");
+
+    let (reused, new_key) =
+        LeManager::load_or_generate_key(&source, AcmeKeyType::Ecdsa).expect("load key");
+    assert!(!new_key);
+    assert!(reused.public_eq(&ecdsa));
+
+    let (rsa, new_key) =
+        LeManager::load_or_generate_key(&source, AcmeKeyType::Rsa).expect("replace key");
+    assert!(new_key);
+    assert_eq!(rsa.id(), Id::RSA);
+    assert_eq!(rsa.bits(), 2048);
+
+    assert_eq!(
+        LeManager::account_path(Path::new("/certs"), "letsencrypt"),
+        Path::new("/certs/account.json")
+    );
+    assert_eq!(
+        LeManager::account_path(Path::new("/certs"), "zerossl"),
+        Path::new("/certs/accounts/zerossl.json")
+    );
+}
+
+/// End to end against Pebble (see the CI workflow for a validating setup), or
+/// `docker run -p 14000:14000 -e PEBBLE_VA_ALWAYS_VALID=1 ghcr.io/letsencrypt/pebble:v2.7.0`
+/// with `NGXORA_PEBBLE_DIRECTORY=https://localhost:14000/dir` and
+/// `NGXORA_PEBBLE_ROOT` pointing at Pebble's `test/certs/pebble.minica.pem`.
+/// `NGXORA_PEBBLE_EAB_KID` / `NGXORA_PEBBLE_EAB_HMAC` add an External Account
+/// Binding for Pebble instances that require one. With validation enabled,
+/// set `NGXORA_PEBBLE_HTTP01_ADDR` to the address Pebble sends HTTP-01
+/// requests to (its `httpPort`, `127.0.0.1:5002` by default) and point its
+/// `-dnsserver` at `pebble-challtestsrv -defaultIPv4 127.0.0.1`.
+#[tokio::test]
+async fn pebble_issues_through_the_fallback_issuer() {
+    let Some(Pebble {
+        directory,
+        root,
+        eab,
+    }) = pebble()
+    else {
+        return;
+    };
+    install_rustls_provider();
+
+    let cache_dir = tempfile::tempdir().expect("create cache dir");
+    let config = format!(
+        r#"
+http {{
+  ssl_provider acme unreachable {{
+    acme_directory https://127.0.0.1:9/dir;
+    cache_dir {cache};
+  }}
+  ssl_provider acme pebble {{
+    acme_directory {directory};
+    ssl_trusted_certificate {root};
+    email admin@example.com;
+    key_type rsa;
+    cache_dir {cache};
+    {eab}
+  }}
+  server {{
+    listen 8443 ssl;
+    server_name pebble-e2e.example.com;
+    acme_certificate unreachable pebble;
+    location / {{ proxy_pass http://127.0.0.1:8080; }}
+  }}
+}}
+"#,
+        cache = cache_dir.path().display(),
+    );
+    let ast = ngxora_config::Ast::parse_config(&config).expect("config parses");
+    let ir = ngxora_compile::ir::Ir::from_ast(&ast).expect("config lowers");
+    let router = CompiledRouter::from_http(&ir.http.expect("http block")).expect("router");
+    let state = RuntimeState::bootstrap(router);
+    let tokens: ChallengeTokens = Arc::new(DashMap::new());
+    let mut managers = HashMap::new();
+    if let Ok(addr) = std::env::var("NGXORA_PEBBLE_HTTP01_ADDR") {
+        let listener = tokio::net::TcpListener::bind(&addr)
+            .await
+            .expect("bind HTTP-01 responder");
+        tokio::spawn(serve_http01(listener, Arc::clone(&tokens)));
+    }
+
+    reconcile_once(&state, &tokens, &mut managers).await;
+
+    assert!(managers.contains_key("pebble"));
+    assert!(!managers.contains_key("unreachable"));
+    assert!(
+        cache_dir.path().join("accounts/pebble.json").exists(),
+        "pebble account was not stored"
+    );
+    let domain_dir = cache_dir.path().join("pebble-e2e.example.com");
+    let cert = x509::X509::from_pem(&fs::read(domain_dir.join("fullchain.pem")).unwrap())
+        .expect("certificate was issued");
+    let names: Vec<String> = cert
+        .subject_alt_names()
+        .expect("certificate has SANs")
+        .iter()
+        .filter_map(|name| name.dnsname().map(str::to_string))
+        .collect();
+    assert_eq!(names, ["pebble-e2e.example.com"]);
+    let key = PKey::private_key_from_pem(&fs::read(domain_dir.join("privkey.pem")).unwrap())
+        .expect("key was written");
+    assert_eq!(key.id(), Id::RSA);
+    assert!(cert.public_key().unwrap().public_eq(&key));
+
+    // A fresh certificate is left alone.
+    assert!(!reconcile(&managers, &state.snapshot().router).await);
+}
+
+/// Issues a wildcard certificate over DNS-01, publishing the TXT record
+/// through the RFC 2136 stub. Nothing serves the stub's zone to Pebble, so
+/// this needs Pebble started with `PEBBLE_VA_ALWAYS_VALID=1`.
+#[tokio::test]
+async fn pebble_issues_a_wildcard_through_rfc2136() {
+    let Some(Pebble {
+        directory,
+        root,
+        eab,
+    }) = pebble()
+    else {
+        return;
+    };
+    install_rustls_provider();
+
+    let updates = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let dns_server = dns::tests::stub_primary(ResponseCode::NoError, Arc::clone(&updates)).await;
+    let cache_dir = tempfile::tempdir().expect("create cache dir");
+    let config = format!(
+        r#"
+http {{
+  ssl_provider acme pebble {{
+    acme_directory {directory};
+    ssl_trusted_certificate {root};
+    cache_dir {cache};
+    challenge dns-01;
+    dns_provider rfc2136 {dns_server};
+    dns_zone example.com;
+    dns_tsig_key acme-update hmac-sha256 {secret};
+    {eab}
+  }}
+  server {{
+    listen 8443 ssl;
+    server_name *.pebble-wildcard.example.com;
+    acme_certificate pebble;
+    location / {{ proxy_pass http://127.0.0.1:8080; }}
+  }}
+}}
+"#,
+        cache = cache_dir.path().display(),
+        secret = dns::tests::SECRET,
+    );
+    let ast = ngxora_config::Ast::parse_config(&config).expect("config parses");
+    let ir = ngxora_compile::ir::Ir::from_ast(&ast).expect("config lowers");
+    let router = CompiledRouter::from_http(&ir.http.expect("http block")).expect("router");
+    let state = RuntimeState::bootstrap(router);
+    let tokens: ChallengeTokens = Arc::new(DashMap::new());
+    let mut managers = HashMap::new();
+
+    reconcile_once(&state, &tokens, &mut managers).await;
+
+    let domain_dir = cache_dir
+        .path()
+        .join("_wildcard.pebble-wildcard.example.com");
+    let cert = x509::X509::from_pem(&fs::read(domain_dir.join("fullchain.pem")).unwrap())
+        .expect("certificate was issued");
+    let names: Vec<String> = cert
+        .subject_alt_names()
+        .expect("certificate has SANs")
+        .iter()
+        .filter_map(|name| name.dnsname().map(str::to_string))
+        .collect();
+    assert_eq!(names, ["*.pebble-wildcard.example.com"]);
+
+    // Added, then removed once validated, at the parent domain's name.
+    let name = "_acme-challenge.pebble-wildcard.example.com.";
+    let updates = updates.lock().unwrap();
+    let classes: Vec<(DNSClass, &str)> = updates
+        .iter()
+        .map(|(class, record, _)| (*class, record.as_str()))
+        .collect();
+    assert_eq!(classes, [(DNSClass::IN, name), (DNSClass::NONE, name)]);
+}
+
+/// Pebble settings from the environment; `None`, after saying so, when the
+/// Pebble tests should be skipped.
+struct Pebble {
+    directory: String,
+    root: String,
+    /// `external_account_binding` directive, or empty.
+    eab: String,
+}
+
+fn pebble() -> Option<Pebble> {
+    let (Ok(directory), Ok(root)) = (
+        std::env::var("NGXORA_PEBBLE_DIRECTORY"),
+        std::env::var("NGXORA_PEBBLE_ROOT"),
+    ) else {
+        eprintln!("skipping: NGXORA_PEBBLE_DIRECTORY / NGXORA_PEBBLE_ROOT not set");
+        return None;
+    };
+    let eab = match (
+        std::env::var("NGXORA_PEBBLE_EAB_KID"),
+        std::env::var("NGXORA_PEBBLE_EAB_HMAC"),
+    ) {
+        (Ok(kid), Ok(hmac)) => format!("external_account_binding {kid} {hmac};"),
+        _ => String::new(),
+    };
+    Some(Pebble {
+        directory,
+        root,
+        eab,
+    })
+}
+
+/// Answers `/.well-known/acme-challenge/<token>` from `tokens`, the way the
+/// proxy does, one request per connection.
+async fn serve_http01(listener: tokio::net::TcpListener, tokens: ChallengeTokens) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            return;
+        };
+        let mut request = vec![0; 4096];
+        let Ok(len) = stream.read(&mut request).await else {
+            continue;
+        };
+        let request = String::from_utf8_lossy(&request[..len]);
+        let key_auth = request
+            .split_whitespace()
+            .nth(1)
+            .and_then(|path| path.strip_prefix("/.well-known/acme-challenge/"))
+            .and_then(|token| tokens.get(token).map(|v| v.clone()));
+        let response = match key_auth {
+            Some(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            ),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
@@ -0,0 +1,144 @@
+//! TLS-ALPN-01 challenge certificates (RFC 8737).
+//!
+//! While an authorization is pending, the listener's `SniCertResolver` answers
+//! handshakes that offer the `acme-tls/1` ALPN for the domain with a
+//! self-signed certificate carrying the SHA-256 digest of the key
+//! authorization, instead of the configured identity.
+
+use dashmap::DashMap;
+use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509, X509Extension, X509NameBuilder};
+use std::sync::Arc;
+
+/// The `acme-tls/1` protocol ID in ALPN wire format.
+pub const ACME_TLS_ALPN_WIRE: &[u8] = b"\x0aacme-tls/1";
+
+/// id-pe-acmeIdentifier, the critical extension holding the digest.
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// Challenge certificates by lowercased domain.
+pub type TlsAlpnChallenges = Arc<DashMap<String, Arc<TlsAlpnCertificate>>>;
+
+pub fn lookup_tls_alpn_challenge(
+    challenges: &TlsAlpnChallenges,
+    domain: &str,
+) -> Option<Arc<TlsAlpnCertificate>> {
+    challenges
+        .get(&domain.to_ascii_lowercase())
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+pub struct TlsAlpnCertificate {
+    pub cert: X509,
+    pub key: PKey<Private>,
+}
+
+impl TlsAlpnCertificate {
+    /// Builds the certificate for `domain` from the SHA-256 digest of the key
+    /// authorization.
+    pub fn new(domain: &str, digest: &[u8]) -> Result<Self, String> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+            .map_err(|e| format!("failed to create EC group: {e}"))?;
+        let key = EcKey::generate(&group)
+            .and_then(PKey::from_ec_key)
+            .map_err(|e| format!("failed to generate challenge key: {e}"))?;
+
+        let mut name =
+            X509NameBuilder::new().map_err(|e| format!("failed to create X509 name: {e}"))?;
+        name.append_entry_by_nid(Nid::COMMONNAME, domain)
+            .map_err(|e| format!("failed to set challenge subject: {e}"))?;
+        let name = name.build();
+
+        let mut serial = BigNum::new().map_err(|e| format!("failed to create serial: {e}"))?;
+        serial
+            .rand(127, MsbOption::MAYBE_ZERO, false)
+            .map_err(|e| format!("failed to generate serial: {e}"))?;
+        let serial =
+            Asn1Integer::from_bn(&serial).map_err(|e| format!("failed to encode serial: {e}"))?;
+
+        // The extension value is itself a DER OCTET STRING of the digest.
+        let digest_len = u8::try_from(digest.len())
+            .ok()
+            .filter(|len| *len < 0x80)
+            .ok_or_else(|| {
+                format!(
+                    "unexpected key authorization digest length {}",
+                    digest.len()
+                )
+            })?;
+        let mut value = vec![0x04, digest_len];
+        value.extend_from_slice(digest);
+        let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID)
+            .map_err(|e| format!("failed to parse acmeIdentifier OID: {e}"))?;
+        let value = Asn1OctetString::new_from_bytes(&value)
+            .map_err(|e| format!("failed to encode acmeIdentifier: {e}"))?;
+        let acme_identifier = X509Extension::new_from_der(&oid, true, &value)
+            .map_err(|e| format!("failed to build acmeIdentifier extension: {e}"))?;
+
+        let mut builder = X509::builder().map_err(|e| format!("failed to create X509: {e}"))?;
+        builder
+            .set_version(2)
+            .and_then(|()| builder.set_serial_number(&serial))
+            .and_then(|()| builder.set_subject_name(&name))
+            .and_then(|()| builder.set_issuer_name(&name))
+            .and_then(|()| builder.set_pubkey(&key))
+            .map_err(|e| format!("failed to fill challenge certificate: {e}"))?;
+        let not_before =
+            Asn1Time::days_from_now(0).map_err(|e| format!("failed to compute validity: {e}"))?;
+        let not_after =
+            Asn1Time::days_from_now(7).map_err(|e| format!("failed to compute validity: {e}"))?;
+        builder
+            .set_not_before(&not_before)
+            .and_then(|()| builder.set_not_after(&not_after))
+            .map_err(|e| format!("failed to set challenge validity: {e}"))?;
+        let san = SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&builder.x509v3_context(None, None))
+            .map_err(|e| format!("failed to build SAN extension: {e}"))?;
+        builder
+            .append_extension(san)
+            .and_then(|()| builder.append_extension(acme_identifier))
+            .map_err(|e| format!("failed to add challenge extensions: {e}"))?;
+        builder
+            .sign(&key, MessageDigest::sha256())
+            .map_err(|e| format!("failed to sign challenge certificate: {e}"))?;
+
+        Ok(Self {
+            cert: builder.build(),
+            key,
+        })
+    }
+}
+
+/// Keeps a challenge certificate published until validation finishes.
+pub(super) struct TlsAlpnChallengeGuard {
+    challenges: TlsAlpnChallenges,
+    domain: String,
+}
+
+impl TlsAlpnChallengeGuard {
+    pub(super) fn insert(
+        challenges: &TlsAlpnChallenges,
+        domain: &str,
+        certificate: TlsAlpnCertificate,
+    ) -> Self {
+        let domain = domain.to_ascii_lowercase();
+        challenges.insert(domain.clone(), Arc::new(certificate));
+        Self {
+            challenges: Arc::clone(challenges),
+            domain,
+        }
+    }
+}
+
+impl Drop for TlsAlpnChallengeGuard {
+    fn drop(&mut self) {
+        self.challenges.remove(&self.domain);
+    }
+}
@@ -4,16 +4,26 @@
 //! - `grpc`: protobuf wire adapter for snapshots
 //! - `control`: active snapshot state machine and restart boundary
 //! - `server`: listener binding into Pingora services
+//! - `proxy_protocol`: PROXY protocol v1/v2 listeners in front of the proxy
+//! - `reload`: SIGHUP / file-watch live reload of the text config
 //! - `upstreams`: compiled routing model and request-time upstream execution
-//! - `metrics`: Prometheus metrics and JSON access log
+//! - `cache`: response cache policy over in-memory or Redis stores
+//! - `admin`: management HTTP app (metrics, probes, `/admin/` API)
+//! - `metrics`: Prometheus metrics
+//! - `access_log`: `log_format` rendering and access log sinks
+//! - `request_id`: `request_id` generation and propagation
 //! - `tracing`: OpenTelemetry distributed tracing
 
+pub mod access_log;
 pub mod admin;
 pub mod cache;
 pub mod control;
 pub mod grpc;
 pub mod le;
 pub mod metrics;
+pub mod proxy_protocol;
+pub mod reload;
+pub(crate) mod request_id;
 pub mod server;
 pub mod tracing;
 pub mod upstreams;
@@ -3,16 +3,22 @@
 //! Metrics follow the [Prometheus naming conventions](https://prometheus.io/docs/practices/naming/):
 //! - Counter for cumulative counts (requests, bytes, cache events)
 //! - Histogram for distributions (latency, response size)
-
-use crate::admin::{admin_server, admin_server_with_state};
-use crate::control::RuntimeState;
+//!
+//! Labels name the server, listener, route and upstream a request went
+//! through. Each of them keeps at most `metrics_label_limit` distinct values;
+//! later values are reported as `other`.
+
+use crate::admin::{admin_server, admin_server_with_cache, admin_server_with_state};
+use crate::cache::CacheBackend;
+use crate::control::{RuntimeSnapshot, RuntimeState};
+use crate::upstreams::{RouteTarget, location_labels};
 use pingora::services::listening::Service;
-use pingora_proxy::Session;
 use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
-use serde::Serialize;
-use std::io::Write;
+use std::collections::{BTreeSet, HashSet};
 use std::net::SocketAddr;
-use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
 
 // ---- Registry ----
 
@@ -33,6 +39,19 @@ macro_rules! metric {
     }};
 }
 
+/// Labels of the per-request metrics. `route_id` is left out on purpose: it
+/// is renumbered by every snapshot, while these stay stable across reloads.
+const REQUEST_LABELS: &[&str] = &[
+    "method",
+    "status",
+    "cache",
+    "has_upstream",
+    "server",
+    "listener",
+    "route",
+    "upstream",
+];
+
 /// Total number of proxied HTTP requests.
 fn requests_total() -> &'static IntCounterVec {
     metric!(
@@ -42,7 +61,7 @@ fn requests_total() -> &'static IntCounterVec {
             "ngxora_requests_total",
             "Total number of HTTP requests processed."
         ),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
     )
 }
 
@@ -55,7 +74,7 @@ fn request_duration_seconds() -> &'static HistogramVec {
             "ngxora_request_duration_seconds",
             "Request duration from first byte received to final byte sent."
         ),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
     )
 }
 
@@ -68,7 +87,7 @@ fn upstream_request_bytes_total() -> &'static IntCounterVec {
             "ngxora_upstream_request_bytes_total",
             "Total bytes sent to upstream in request bodies."
         ),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
     )
 }
 
@@ -81,7 +100,7 @@ fn upstream_response_bytes_total() -> &'static IntCounterVec {
             "ngxora_upstream_response_bytes_total",
             "Total bytes received from upstream in response bodies."
         ),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
     )
 }
 
@@ -91,7 +110,7 @@ fn cache_hits_total() -> &'static IntCounterVec {
         IntCounterVec,
         IntCounterVec::new,
         Opts::new("ngxora_cache_hits_total", "Total number of cache hits."),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
     )
 }
 
@@ -101,22 +120,334 @@ fn cache_misses_total() -> &'static IntCounterVec {
         IntCounterVec,
         IntCounterVec::new,
         Opts::new("ngxora_cache_misses_total", "Total number of cache misses."),
-        &["method", "status", "cache", "has_upstream", "route_id"]
+        REQUEST_LABELS
+    )
+}
+
+/// Upstream attempts that got a response header, per backend and status.
+fn upstream_requests_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_requests_total",
+            "Total number of upstream attempts that received a response, by backend and status."
+        ),
+        &["upstream", "backend", "status"]
+    )
+}
+
+/// Upstream attempts that failed before or while reading the response.
+fn upstream_errors_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_errors_total",
+            "Total number of failed upstream attempts, by backend and kind (connect, timeout, error)."
+        ),
+        &["upstream", "backend", "kind"]
+    )
+}
+
+/// Time to open a new upstream connection, TLS included.
+fn upstream_connect_duration_seconds() -> &'static HistogramVec {
+    metric!(
+        HistogramVec,
+        HistogramVec::new,
+        HistogramOpts::new(
+            "ngxora_upstream_connect_duration_seconds",
+            "Time to establish a new upstream connection, including the TLS handshake."
+        ),
+        &["upstream", "backend"]
+    )
+}
+
+/// Time from picking the backend to receiving its response header.
+fn upstream_ttfb_seconds() -> &'static HistogramVec {
+    metric!(
+        HistogramVec,
+        HistogramVec::new,
+        HistogramOpts::new(
+            "ngxora_upstream_ttfb_seconds",
+            "Time from selecting an upstream backend to receiving its response header."
+        ),
+        &["upstream", "backend"]
+    )
+}
+
+/// Requests a request plugin answered itself, such as `jwt_auth` or
+/// `rate-limit` rejections.
+fn plugin_responses_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_plugin_responses_total",
+            "Total number of requests answered by a plugin instead of being proxied."
+        ),
+        &["plugin", "status", "server", "route"]
+    )
+}
+
+/// Plugin hooks that failed and turned into an error response.
+fn plugin_errors_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_plugin_errors_total",
+            "Total number of plugin hook failures."
+        ),
+        &["plugin", "stage"]
+    )
+}
+
+/// Backends ejected by passive health checking.
+fn upstream_ejections_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_ejections_total",
+            "Total number of upstream backend ejections by outlier detection."
+        ),
+        &["upstream", "backend"]
+    )
+}
+
+/// Ejections skipped because `max_ejection_percent` was reached.
+fn upstream_ejections_overflow_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_ejections_overflow_total",
+            "Total number of outlier ejections skipped because too many backends were ejected."
+        ),
+        &["upstream"]
+    )
+}
+
+/// Upstream connections taken from the keepalive pool.
+fn upstream_pool_hits_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_pool_hits_total",
+            "Total number of upstream requests sent over a reused connection."
+        ),
+        &["upstream"]
+    )
+}
+
+/// Upstream connections opened because the pool had none to reuse.
+fn upstream_pool_misses_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_pool_misses_total",
+            "Total number of upstream requests that opened a new connection."
+        ),
+        &["upstream"]
+    )
+}
+
+/// Requests turned away because every server was at its `max_conns`.
+fn upstream_max_conns_rejections_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_upstream_max_conns_rejections_total",
+            "Total number of requests rejected because all upstream servers were at max_conns."
+        ),
+        &["upstream"]
     )
 }
 
+/// Access log lines dropped because the writer fell behind.
+fn access_log_dropped_total() -> &'static IntCounterVec {
+    metric!(
+        IntCounterVec,
+        IntCounterVec::new,
+        Opts::new(
+            "ngxora_access_log_dropped_total",
+            "Total number of access log lines dropped because the log writer queue was full."
+        ),
+        &[]
+    )
+}
+
+// ---- Label cardinality ----
+
+/// Distinct values kept per label when `metrics_label_limit` is unset.
+pub(crate) const DEFAULT_LABEL_LIMIT: usize = 100;
+
+/// Reported instead of values past a label's limit.
+const OVERFLOW_LABEL_VALUE: &str = "other";
+
+static LABEL_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_LABEL_LIMIT);
+
+/// Labels whose values come from the config or from DNS and so need a bound.
+#[derive(Debug, Clone, Copy)]
+enum Label {
+    Server,
+    Listener,
+    Route,
+    Upstream,
+    Backend,
+}
+
+const LABELS: [Label; 5] = [
+    Label::Server,
+    Label::Listener,
+    Label::Route,
+    Label::Upstream,
+    Label::Backend,
+];
+
+/// Distinct values admitted so far for one label. Values are admitted once and
+/// then only looked up, so the common case takes the shared read lock.
+#[derive(Default)]
+struct SeenValues(RwLock<HashSet<String>>);
+
+impl SeenValues {
+    fn bounded<'a>(&self, value: &'a str, limit: usize) -> &'a str {
+        if value.is_empty() {
+            return value;
+        }
+        let seen = self
+            .0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if seen.contains(value) {
+            return value;
+        }
+        let full = seen.len() >= limit;
+        drop(seen);
+        if full {
+            return OVERFLOW_LABEL_VALUE;
+        }
+
+        let mut seen = self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Another worker may have filled the last slot in between.
+        if seen.contains(value) {
+            return value;
+        }
+        if seen.len() >= limit {
+            return OVERFLOW_LABEL_VALUE;
+        }
+        seen.insert(value.to_string());
+        value
+    }
+
+    /// Replaces the admitted values with the first `limit` of `values`.
+    fn reseed(&self, values: BTreeSet<String>, limit: usize) {
+        let mut seen = self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        seen.clear();
+        seen.extend(values.into_iter().take(limit));
+    }
+}
+
+fn seen_values(label: Label) -> &'static SeenValues {
+    static SEEN: OnceLock<[SeenValues; 5]> = OnceLock::new();
+    &SEEN.get_or_init(Default::default)[label as usize]
+}
+
+/// Applies `metrics_label_limit` from a snapshot being activated and
+/// re-admits only the label values it produces, so names dropped by a reload
+/// free their slots. Values first seen later, such as addresses a `resolve`
+/// server moves to, are admitted as before while slots are free.
+pub(crate) fn reseed_label_values(snapshot: &RuntimeSnapshot) {
+    let limit = snapshot
+        .router
+        .http_options
+        .metrics_label_limit
+        .unwrap_or(DEFAULT_LABEL_LIMIT);
+    LABEL_LIMIT.store(limit, Ordering::Relaxed);
+    for (label, values) in LABELS.into_iter().zip(snapshot_label_values(snapshot)) {
+        seen_values(label).reseed(values, limit);
+    }
+}
+
+/// Values of each label, in [`LABELS`] order, that `snapshot` produces.
+fn snapshot_label_values(snapshot: &RuntimeSnapshot) -> [BTreeSet<String>; 5] {
+    let mut values: [BTreeSet<String>; 5] = Default::default();
+    let mut insert = |label: Label, value: &str| {
+        if !value.is_empty() {
+            values[label as usize].insert(value.to_string());
+        }
+    };
+    for (listener, vhosts) in &snapshot.router.listeners {
+        for routes in vhosts.named.values().chain(vhosts.default.as_ref()) {
+            for location in &routes.locations {
+                let labels = location_labels(routes.server_name.as_deref(), listener, location);
+                insert(Label::Server, &labels.server);
+                insert(Label::Listener, &labels.listener);
+                insert(Label::Route, &labels.route);
+                insert(Label::Upstream, &labels.upstream);
+                // A direct `proxy_pass` is its own backend.
+                if matches!(location.target, RouteTarget::ProxyPass { .. }) {
+                    insert(Label::Backend, &labels.upstream);
+                }
+            }
+        }
+    }
+    for name in snapshot.router.upstreams.keys() {
+        let Some(group) = snapshot.upstream_group(name) else {
+            continue;
+        };
+        insert(Label::Upstream, group.name());
+        for backend in group.backend_health() {
+            let server = backend.server;
+            insert(Label::Backend, &format!("{}:{}", server.host, server.port));
+        }
+    }
+    values
+}
+
+/// Bounds the series a config-derived label can create: the first
+/// `metrics_label_limit` distinct values of `label` are reported as is,
+/// later ones as `other`.
+fn bounded(label: Label, value: &str) -> &str {
+    seen_values(label).bounded(value, LABEL_LIMIT.load(Ordering::Relaxed))
+}
+
 // ---- Metrics recording ----
 
+/// Where a request was routed, as stable labels. All empty when no route
+/// matched.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RouteLabels {
+    /// First `server_name` of the virtual server, `_` when it has none.
+    pub server: String,
+    /// Configured `address:port` of the listener.
+    pub listener: String,
+    /// `route_name`, or the location matcher as written (`= /login`).
+    pub route: String,
+    /// Upstream group name, `host:port` for a direct `proxy_pass`, empty for
+    /// `return` and static files.
+    pub upstream: String,
+}
+
 /// Common labels attached to every metric.
 #[derive(Debug, Clone)]
-pub(crate) struct RequestLabels {
+pub(crate) struct RequestLabels<'a> {
     pub method: String,
     pub status: String,
     pub cache_status: CacheStatus,
     /// Whether an upstream peer was used (vs. served from cache / redirect / plugin response).
     pub has_upstream: bool,
-    /// Route ID for per-route metrics.
-    pub route_id: u64,
+    pub route: &'a RouteLabels,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -138,18 +469,21 @@ impl CacheStatus {
 
 /// Record metrics at the end of a request.
 pub(crate) fn record_metrics(
-    labels: &RequestLabels,
+    labels: &RequestLabels<'_>,
     latency_secs: f64,
     request_body_bytes: u64,
     response_body_bytes: u64,
 ) {
-    let route_id_str = labels.route_id.to_string();
+    let route = labels.route;
     let label_values = [
         labels.method.as_str(),
         labels.status.as_str(),
         labels.cache_status.as_str(),
         if labels.has_upstream { "true" } else { "false" },
-        route_id_str.as_str(),
+        bounded(Label::Server, &route.server),
+        bounded(Label::Listener, &route.listener),
+        bounded(Label::Route, &route.route),
+        bounded(Label::Upstream, &route.upstream),
     ];
 
     requests_total().with_label_values(&label_values).inc();
@@ -170,69 +504,94 @@ pub(crate) fn record_metrics(
     }
 }
 
-// ---- Structured access log (JSON) ----
-
-#[derive(Debug, Serialize)]
-struct AccessLogEntry {
-    method: String,
-    path: String,
-    status: u16,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    latency_secs: Option<f64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    upstream: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    cache_status: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    bytes_sent: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    client_ip: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    route_id: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    request_id: Option<String>,
-}
-
-/// Write a structured JSON access log line.
-pub(crate) fn write_access_log(
-    session: &Session,
-    method: &str,
-    path: &str,
+pub(crate) fn record_upstream_response(
+    upstream: &str,
+    backend: &str,
     status: u16,
-    latency: Option<std::time::Duration>,
-    upstream: Option<&str>,
-    cache_status: Option<&str>,
-    route_id: Option<u64>,
+    ttfb: Option<Duration>,
 ) {
-    let latency_secs = latency.map(|d| d.as_secs_f64());
-
-    let client_ip = session.as_downstream().client_addr().map(|a| a.to_string());
-
-    let request_id = session
-        .req_header()
-        .headers
-        .get("x-request-id")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
-
-    let entry = AccessLogEntry {
-        method: method.to_string(),
-        path: path.to_string(),
-        status,
-        latency_secs,
-        upstream: upstream.map(|s| s.to_string()),
-        cache_status: cache_status.map(|s| s.to_string()),
-        bytes_sent: None,
-        client_ip,
-        route_id,
-        request_id,
+    let upstream = bounded(Label::Upstream, upstream);
+    let backend = bounded(Label::Backend, backend);
+    upstream_requests_total()
+        .with_label_values(&[upstream, backend, status.to_string().as_str()])
+        .inc();
+    if let Some(ttfb) = ttfb {
+        upstream_ttfb_seconds()
+            .with_label_values(&[upstream, backend])
+            .observe(ttfb.as_secs_f64());
+    }
+}
+
+pub(crate) fn record_upstream_error(upstream: &str, backend: &str, kind: &str) {
+    upstream_errors_total()
+        .with_label_values(&[
+            bounded(Label::Upstream, upstream),
+            bounded(Label::Backend, backend),
+            kind,
+        ])
+        .inc();
+}
+
+pub(crate) fn record_upstream_connect(upstream: &str, backend: &str, duration: Duration) {
+    upstream_connect_duration_seconds()
+        .with_label_values(&[
+            bounded(Label::Upstream, upstream),
+            bounded(Label::Backend, backend),
+        ])
+        .observe(duration.as_secs_f64());
+}
+
+pub(crate) fn record_plugin_response(plugin: &str, status: u16, route: &RouteLabels) {
+    plugin_responses_total()
+        .with_label_values(&[
+            plugin,
+            status.to_string().as_str(),
+            bounded(Label::Server, &route.server),
+            bounded(Label::Route, &route.route),
+        ])
+        .inc();
+}
+
+pub(crate) fn record_plugin_error(plugin: &str, stage: &str) {
+    plugin_errors_total()
+        .with_label_values(&[plugin, stage])
+        .inc();
+}
+
+pub(crate) fn record_upstream_ejection(upstream: &str, backend: &str) {
+    upstream_ejections_total()
+        .with_label_values(&[
+            bounded(Label::Upstream, upstream),
+            bounded(Label::Backend, backend),
+        ])
+        .inc();
+}
+
+pub(crate) fn record_upstream_ejection_overflow(upstream: &str) {
+    upstream_ejections_overflow_total()
+        .with_label_values(&[bounded(Label::Upstream, upstream)])
+        .inc();
+}
+
+pub(crate) fn record_upstream_connection(upstream: &str, reused: bool) {
+    let counter = if reused {
+        upstream_pool_hits_total()
+    } else {
+        upstream_pool_misses_total()
     };
+    counter
+        .with_label_values(&[bounded(Label::Upstream, upstream)])
+        .inc();
+}
 
-    // Write to stdout (explicit flush — Docker buffers non-tty stdout).
-    if let Ok(json) = serde_json::to_string(&entry) {
-        println!("{json}");
-        std::io::stdout().flush().ok();
-    }
+pub(crate) fn record_upstream_max_conns_rejection(upstream: &str) {
+    upstream_max_conns_rejections_total()
+        .with_label_values(&[bounded(Label::Upstream, upstream)])
+        .inc();
+}
+
+pub(crate) fn record_access_log_dropped() {
+    access_log_dropped_total().with_label_values(&[]).inc();
 }
 
 // ---- Prometheus metrics service ----
@@ -262,54 +621,212 @@ pub fn spawn_metrics_service_with_state(
     Ok(())
 }
 
+/// Build the admin service with readiness and the `/admin/` inspection API
+/// reading the active runtime state and the proxy's response cache. `writes`
+/// enables the cache purge endpoint.
+pub fn spawn_metrics_service_with_cache(
+    server: &mut pingora::server::Server,
+    addr: SocketAddr,
+    state: Arc<RuntimeState>,
+    cache: Arc<CacheBackend>,
+    writes: bool,
+) -> pingora::Result<()> {
+    let mut svc = Service::new(
+        "Admin HTTP".to_string(),
+        admin_server_with_cache(state, cache, writes),
+    );
+    svc.add_tcp(&addr.to_string());
+    server.add_service(svc);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{CacheStatus, RequestLabels, record_metrics};
+    use super::{
+        CacheStatus, DEFAULT_LABEL_LIMIT, LABELS, Label, OVERFLOW_LABEL_VALUE, RequestLabels,
+        RouteLabels, SeenValues, bounded, record_metrics, record_upstream_max_conns_rejection,
+        seen_values, snapshot_label_values, upstream_max_conns_rejections_total,
+    };
+    use crate::control::{ConfigSnapshot, RuntimeSnapshot, RuntimeState};
+    use crate::upstreams::CompiledRouter;
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex, PoisonError};
+
+    /// Held by the tests that fill a label, which is shared by the whole
+    /// process, and by those that need room in it.
+    static LABEL_FILL: Mutex<()> = Mutex::new(());
+
+    fn snapshot(config: &str) -> Arc<RuntimeSnapshot> {
+        let ast = ngxora_config::Ast::parse_config(config).expect("config parses");
+        let ir = ngxora_compile::ir::Ir::from_ast(&ast).expect("config lowers");
+        let router = CompiledRouter::from_http(&ir.http.unwrap()).expect("router compiles");
+        RuntimeState::new(ConfigSnapshot::new("v1", router)).snapshot()
+    }
 
     #[test]
     fn record_metrics_registers_collectors_in_default_registry() {
+        let _fill = LABEL_FILL.lock().unwrap_or_else(PoisonError::into_inner);
         record_metrics(
             &RequestLabels {
                 method: "GET".into(),
                 status: "200".into(),
                 cache_status: CacheStatus::Miss,
                 has_upstream: true,
-                route_id: 7,
+                route: &RouteLabels {
+                    server: "metrics.example.com".into(),
+                    listener: "0.0.0.0:8080".into(),
+                    route: "= /login".into(),
+                    upstream: "auth".into(),
+                },
             },
             0.125,
             32,
             64,
         );
 
-        let metric_names = prometheus::gather()
-            .into_iter()
-            .map(|family| family.get_name().to_string())
-            .collect::<Vec<_>>();
+        let families = prometheus::gather();
+        for name in [
+            "ngxora_requests_total",
+            "ngxora_request_duration_seconds",
+            "ngxora_upstream_request_bytes_total",
+            "ngxora_upstream_response_bytes_total",
+            "ngxora_cache_misses_total",
+        ] {
+            assert!(
+                families.iter().any(|family| family.get_name() == name),
+                "{name} is not registered"
+            );
+        }
 
-        assert!(
-            metric_names
+        let requests = families
+            .iter()
+            .find(|family| family.get_name() == "ngxora_requests_total")
+            .unwrap();
+        let labelled = requests.get_metric().iter().any(|metric| {
+            let labels = metric
+                .get_label()
                 .iter()
-                .any(|name| name == "ngxora_requests_total")
-        );
-        assert!(
-            metric_names
+                .map(|pair| (pair.get_name(), pair.get_value()))
+                .collect::<Vec<_>>();
+            labels.contains(&("server", "metrics.example.com"))
+                && labels.contains(&("route", "= /login"))
+                && labels.contains(&("upstream", "auth"))
+        });
+        assert!(labelled, "route labels are missing");
+    }
+
+    #[test]
+    fn bounded_reports_values_past_the_limit_as_other() {
+        let seen = SeenValues::default();
+        let values = (0..DEFAULT_LABEL_LIMIT)
+            .map(|i| format!("value-{i}"))
+            .collect::<Vec<_>>();
+        for value in &values {
+            assert_eq!(seen.bounded(value, DEFAULT_LABEL_LIMIT), value);
+        }
+
+        assert_eq!(seen.bounded("one-too-many", DEFAULT_LABEL_LIMIT), "other");
+        assert_eq!(seen.bounded(&values[0], DEFAULT_LABEL_LIMIT), values[0]);
+        assert_eq!(seen.bounded("", DEFAULT_LABEL_LIMIT), "");
+        // A lowered limit keeps the values already admitted.
+        assert_eq!(seen.bounded(&values[1], 1), values[1]);
+    }
+
+    #[test]
+    fn upstream_pool_metrics_share_the_upstream_label_limit() {
+        let _fill = LABEL_FILL.lock().unwrap_or_else(PoisonError::into_inner);
+        let rejections = upstream_max_conns_rejections_total();
+        let reported = |upstream: &str| {
+            prometheus::gather()
                 .iter()
-                .any(|name| name == "ngxora_request_duration_seconds")
+                .filter(|family| family.get_name() == "ngxora_upstream_max_conns_rejections_total")
+                .flat_map(|family| family.get_metric())
+                .flat_map(|metric| metric.get_label())
+                .any(|pair| pair.get_value() == upstream)
+        };
+        // A snapshot applied by a concurrent test reseeds the label, so fill
+        // it again until a rejection is recorded while it is full.
+        for attempt in 0.. {
+            let mut i = 0;
+            while bounded(Label::Upstream, &format!("filler-{i}")) != OVERFLOW_LABEL_VALUE {
+                i += 1;
+            }
+            let upstream = format!("discovered-upstream-{attempt}");
+            let before = rejections.with_label_values(&[OVERFLOW_LABEL_VALUE]).get();
+            record_upstream_max_conns_rejection(&upstream);
+            if !reported(&upstream) {
+                // Other tests may land in `other` concurrently, so only a
+                // lower bound holds.
+                assert!(rejections.with_label_values(&[OVERFLOW_LABEL_VALUE]).get() > before);
+                break;
+            }
+        }
+        // Leave room for the upstreams of the other tests.
+        seen_values(Label::Upstream).reseed(BTreeSet::new(), DEFAULT_LABEL_LIMIT);
+    }
+
+    #[test]
+    fn reseeding_frees_the_slots_of_names_a_snapshot_no_longer_has() {
+        let first = snapshot(
+            r#"
+http {
+  upstream red { server 127.0.0.1:8081; }
+  server {
+    listen 127.0.0.1:8080;
+    server_name red.example.com;
+    location /red { proxy_pass http://red; }
+    location /direct { proxy_pass http://127.0.0.1:8082; }
+  }
+}
+"#,
         );
-        assert!(
-            metric_names
-                .iter()
-                .any(|name| name == "ngxora_upstream_request_bytes_total")
+        let second = snapshot(
+            r#"
+http {
+  upstream blue { server 127.0.0.1:9091; }
+  server {
+    listen 127.0.0.1:8080;
+    server_name blue.example.com;
+    location /blue { proxy_pass http://blue; }
+    location = /status { return 204; }
+  }
+}
+"#,
         );
-        assert!(
-            metric_names
-                .iter()
-                .any(|name| name == "ngxora_upstream_response_bytes_total")
+        let seen: [SeenValues; 5] = Default::default();
+        let reseed = |snapshot: &RuntimeSnapshot| {
+            for (label, values) in LABELS.into_iter().zip(snapshot_label_values(snapshot)) {
+                seen[label as usize].reseed(values, 2);
+            }
+        };
+        let bounded = |label: Label, value: &'static str| seen[label as usize].bounded(value, 2);
+
+        reseed(&first);
+        assert_eq!(bounded(Label::Route, "/red"), "/red");
+        assert_eq!(bounded(Label::Route, "/blue"), OVERFLOW_LABEL_VALUE);
+        assert_eq!(bounded(Label::Upstream, "127.0.0.1:8082"), "127.0.0.1:8082");
+        assert_eq!(bounded(Label::Backend, "127.0.0.1:8081"), "127.0.0.1:8081");
+
+        reseed(&second);
+        for (label, value) in [
+            (Label::Server, "blue.example.com"),
+            (Label::Listener, "127.0.0.1:8080"),
+            (Label::Route, "/blue"),
+            (Label::Route, "= /status"),
+            (Label::Upstream, "blue"),
+            (Label::Backend, "127.0.0.1:9091"),
+        ] {
+            assert_eq!(bounded(label, value), value);
+        }
+        // The first snapshot's names gave their slots up.
+        assert_eq!(bounded(Label::Route, "/red"), OVERFLOW_LABEL_VALUE);
+        assert_eq!(
+            bounded(Label::Server, "discovered.example.com"),
+            "discovered.example.com"
         );
-        assert!(
-            metric_names
-                .iter()
-                .any(|name| name == "ngxora_cache_misses_total")
+        assert_eq!(
+            bounded(Label::Server, "red.example.com"),
+            OVERFLOW_LABEL_VALUE
         );
     }
 }
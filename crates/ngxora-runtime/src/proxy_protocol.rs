@@ -0,0 +1,733 @@
+//! PROXY protocol (v1 text / v2 binary) support for downstream listeners.
+//!
+//! Pingora's listener stack has no hook between `accept()` and the TLS
+//! handshake, so listeners declared with `listen ... proxy_protocol` are bound
+//! here instead of in the Pingora listening service. Each accepted connection
+//! is checked against the listener's trusted networks, the header is consumed,
+//! TLS is terminated if needed, and the stream is handed to the same HTTP proxy
+//! application. The client address from the header replaces the socket peer in
+//! the stream digest, so `session.client_addr()` — and therefore location
+//! allow/deny rules, plugins and the access log — sees the real client.
+//!
+//! Being outside Pingora's listening service, these sockets are not handed
+//! over on a graceful upgrade (`-u`): the new process binds them afresh, so
+//! the old one must exit first and connections arriving in between are
+//! refused. Pingora's per-listener socket options are not applied either.
+//! Each listener serves at most [`MAX_CONNECTIONS`] connections at a time;
+//! further ones wait in the kernel backlog.
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use pingora::apps::ServerApp;
+use pingora::protocols::l4::socket::SocketAddr as PingoraSocketAddr;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::{GetSocketDigest, SocketDigest, Stream};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsFd, AsRawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, Interest};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+#[cfg(feature = "openssl")]
+use crate::server::ListenerTlsAcceptor;
+
+/// How long a trusted peer gets to send the PROXY header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Matches the handshake timeout Pingora applies to its own TLS listeners.
+#[cfg(feature = "openssl")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header allowed by the spec, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Connections a PROXY protocol listener serves at once, header reads and TLS
+/// handshakes included.
+pub const MAX_CONNECTIONS: usize = 16_384;
+
+/// A stream a PROXY header is read from. Bytes can be inspected before they
+/// are consumed, so the v1 header is taken in one read without running into
+/// the data that follows it.
+pub trait PeekRead: AsyncRead + Unpin {
+    /// Copies the bytes that are ready into `buf` without consuming them,
+    /// waiting until more than `seen` are ready or `buf` is full. `0` means
+    /// the stream ended.
+    fn peek_past(
+        &mut self,
+        buf: &mut [u8],
+        seen: usize,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl PeekRead for TcpStream {
+    async fn peek_past(&mut self, buf: &mut [u8], seen: usize) -> io::Result<usize> {
+        let ready = TcpStream::peek(self, buf).await?;
+        if ready > seen || ready == 0 {
+            return Ok(ready);
+        }
+
+        // Only bytes already seen are there. A peek leaves the socket marked
+        // readable, so peeking again would return at once; instead peek
+        // inside `try_io`, which clears the readiness when nothing new shows
+        // up and no event came in meanwhile, then wait for the next event.
+        // The peek goes through a duplicate of the socket because `try_io`
+        // needs a non-blocking call.
+        let socket = std::net::TcpStream::from(self.as_fd().try_clone_to_owned()?);
+        loop {
+            let peeked = self.try_io(Interest::READABLE, || match socket.peek(buf)? {
+                ready if ready > seen || ready == 0 => Ok(ready),
+                _ => Err(io::ErrorKind::WouldBlock.into()),
+            });
+            match peeked {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.readable().await?,
+                peeked => return peeked,
+            }
+        }
+    }
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of `io`.
+///
+/// Exactly the header bytes are consumed, so the stream can be handed to TLS or
+/// HTTP afterwards. Returns the client source address, or `None` when the
+/// header carries no usable address (`UNKNOWN`, `LOCAL` health checks, unix
+/// sockets); callers then keep the socket peer.
+pub async fn read_proxy_header<R>(io: &mut R) -> Result<Option<SocketAddr>, String>
+where
+    R: PeekRead,
+{
+    let mut prefix = [0u8; 12];
+    io.read_exact(&mut prefix)
+        .await
+        .map_err(|err| format!("failed to read PROXY protocol header: {err}"))?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2_header(io).await
+    } else if prefix.starts_with(V1_PREFIX) {
+        read_v1_header(io, &prefix).await
+    } else {
+        Err("connection did not start with a PROXY protocol header".into())
+    }
+}
+
+async fn read_v1_header<R>(io: &mut R, prefix: &[u8]) -> Result<Option<SocketAddr>, String>
+where
+    R: PeekRead,
+{
+    // The header length is not known up front. Peek at what has arrived and
+    // consume exactly up to the CRLF, so nothing after it is taken.
+    let mut line = [0u8; V1_MAX_LEN];
+    line[..prefix.len()].copy_from_slice(prefix);
+    let mut ready = 0;
+    loop {
+        ready = io
+            .peek_past(&mut line[prefix.len()..], ready)
+            .await
+            .map_err(|err| format!("failed to read PROXY protocol header: {err}"))?;
+        if ready == 0 {
+            return Err("connection closed inside the PROXY protocol header".into());
+        }
+        let seen = prefix.len() + ready;
+        if let Some(end) = line[..seen]
+            .windows(2)
+            .skip(prefix.len() - 1)
+            .position(|pair| pair == b"\r\n")
+            .map(|index| index + prefix.len() + 1)
+        {
+            io.read_exact(&mut line[prefix.len()..end])
+                .await
+                .map_err(|err| format!("failed to read PROXY protocol header: {err}"))?;
+            let line = std::str::from_utf8(&line[..end - 2])
+                .map_err(|_| "PROXY protocol v1 header is not valid ASCII".to_string())?;
+            return parse_v1_line(line);
+        }
+        if seen >= V1_MAX_LEN {
+            return Err("PROXY protocol v1 header is too long".into());
+        }
+    }
+}
+
+fn parse_v1_line(line: &str) -> Result<Option<SocketAddr>, String> {
+    let invalid = || format!("invalid PROXY protocol v1 header `{line}`");
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid());
+    }
+
+    let family = parts.next().ok_or_else(invalid)?;
+    if family == "UNKNOWN" {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = parts.collect();
+    let [src, _dst, src_port, _dst_port] = fields[..] else {
+        return Err(invalid());
+    };
+    let src: IpAddr = src.parse().map_err(|_| invalid())?;
+    let src_port: u16 = src_port.parse().map_err(|_| invalid())?;
+    match (family, src) {
+        ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+            Ok(Some(SocketAddr::new(src, src_port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+async fn read_v2_header<R>(io: &mut R) -> Result<Option<SocketAddr>, String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut fixed = [0u8; 4];
+    io.read_exact(&mut fixed)
+        .await
+        .map_err(|err| format!("failed to read PROXY protocol header: {err}"))?;
+    let [version_command, family, len_hi, len_lo] = fixed;
+
+    if version_command >> 4 != 2 {
+        return Err(format!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        ));
+    }
+    let command = version_command & 0x0f;
+    if command > 1 {
+        return Err(format!("unsupported PROXY protocol v2 command {command}"));
+    }
+
+    // TLVs are skipped along with the rest of the address block.
+    let mut payload = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    io.read_exact(&mut payload)
+        .await
+        .map_err(|err| format!("failed to read PROXY protocol header: {err}"))?;
+
+    // LOCAL connections come from the proxy itself, e.g. health checks.
+    if command == 0 {
+        return Ok(None);
+    }
+    // Only a TCP client can be proxied here; UDP and unspecified transports
+    // would hand out the address of some other protocol's peer.
+    let transport = family & 0x0f;
+    if family >> 4 != 0 && transport != 1 {
+        return Err(format!(
+            "unsupported PROXY protocol v2 transport {transport}"
+        ));
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 => {
+            let addr = payload
+                .get(..12)
+                .ok_or("PROXY protocol v2 IPv4 address block is truncated")?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            let addr = payload
+                .get(..36)
+                .ok_or("PROXY protocol v2 IPv6 address block is truncated")?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC / AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+fn is_trusted(trusted: &[IpNet], peer: IpAddr) -> bool {
+    let peer = match peer {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(peer),
+        IpAddr::V4(_) => peer,
+    };
+    trusted.iter().any(|net| net.contains(&peer))
+}
+
+/// A bound socket that expects a PROXY protocol header on every connection.
+pub struct ProxyProtocolListener {
+    pub(crate) addr: SocketAddr,
+    pub(crate) socket: std::net::TcpListener,
+    pub(crate) trusted: Vec<IpNet>,
+    #[cfg(feature = "openssl")]
+    pub(crate) tls: Option<ListenerTlsAcceptor>,
+}
+
+impl ProxyProtocolListener {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// Accept loop for PROXY protocol listeners, feeding connections to `app`.
+pub struct ProxyProtocolService<A> {
+    app: Arc<A>,
+    listeners: Vec<Arc<ProxyProtocolListener>>,
+    max_connections: usize,
+}
+
+impl<A> ProxyProtocolService<A> {
+    pub(crate) fn new(app: A, listeners: Vec<ProxyProtocolListener>) -> Self {
+        Self {
+            app: Arc::new(app),
+            listeners: listeners.into_iter().map(Arc::new).collect(),
+            max_connections: MAX_CONNECTIONS,
+        }
+    }
+
+    /// Caps the connections each listener serves at once; [`MAX_CONNECTIONS`]
+    /// by default.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn listeners(&self) -> impl Iterator<Item = &ProxyProtocolListener> {
+        self.listeners.iter().map(Arc::as_ref)
+    }
+}
+
+impl<A> ProxyProtocolService<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn run_listener(
+        app: Arc<A>,
+        listener: Arc<ProxyProtocolListener>,
+        max_connections: usize,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let socket = match listener
+            .socket
+            .try_clone()
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .and_then(TcpListener::from_std)
+        {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::error!("failed to listen on {}: {err}", listener.addr);
+                return;
+            }
+        };
+
+        let slots = Arc::new(Semaphore::new(max_connections));
+        loop {
+            // A slot is taken before accepting, so at the limit new
+            // connections queue in the kernel backlog instead of piling up
+            // as tasks.
+            let accepted = tokio::select! {
+                accepted = async {
+                    let slot = Arc::clone(&slots).acquire_owned().await.expect("never closed");
+                    socket.accept().await.map(|accepted| (accepted, slot))
+                } => accepted,
+                changed = shutdown.changed() => {
+                    if changed.is_err() || *shutdown.borrow() {
+                        log::info!("shutting down PROXY protocol listener {}", listener.addr);
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            match accepted {
+                Ok(((tcp, peer), slot)) => {
+                    let app = Arc::clone(&app);
+                    let listener = Arc::clone(&listener);
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        let _slot = slot;
+                        let Some(stream) = accept_connection(&listener, tcp, peer).await else {
+                            return;
+                        };
+                        let mut reuse = app.process_new(stream, &shutdown).await;
+                        while let Some(stream) = reuse {
+                            reuse = app.process_new(stream, &shutdown).await;
+                        }
+                    });
+                }
+                Err(err) => {
+                    log::error!("accept failed on {}: {err}", listener.addr);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        }
+    }
+}
+
+async fn accept_connection(
+    listener: &ProxyProtocolListener,
+    mut tcp: TcpStream,
+    peer: SocketAddr,
+) -> Option<Stream> {
+    if !is_trusted(&listener.trusted, peer.ip()) {
+        log::warn!(
+            "refusing connection from untrusted peer {peer} on PROXY protocol listener {}",
+            listener.addr
+        );
+        return None;
+    }
+
+    let source = match tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut tcp)).await {
+        Ok(Ok(source)) => source.unwrap_or(peer),
+        Ok(Err(err)) => {
+            log::warn!("{err} from {peer} on {}", listener.addr);
+            return None;
+        }
+        Err(_) => {
+            log::warn!(
+                "timed out reading PROXY protocol header from {peer} on {}",
+                listener.addr
+            );
+            return None;
+        }
+    };
+
+    let digest = SocketDigest::from_raw_fd(tcp.as_raw_fd());
+    let _ = digest.peer_addr.set(Some(PingoraSocketAddr::Inet(source)));
+    let mut stream = L4Stream::from(tcp);
+    stream.set_socket_digest(digest);
+
+    #[cfg(feature = "openssl")]
+    if let Some(tls) = &listener.tls {
+        return match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.handshake(stream)).await {
+            Ok(Ok(stream)) => Some(stream),
+            Ok(Err(err)) => {
+                log::debug!(
+                    "TLS handshake with {source} on {} failed: {err}",
+                    listener.addr
+                );
+                None
+            }
+            Err(_) => {
+                log::debug!("TLS handshake with {source} on {} timed out", listener.addr);
+                None
+            }
+        };
+    }
+
+    Some(Box::new(stream))
+}
+
+#[async_trait]
+impl<A> BackgroundService for ProxyProtocolService<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn start(&self, shutdown: ShutdownWatch) {
+        let loops = self.listeners.iter().map(|listener| {
+            log::info!("PROXY protocol listener on {}", listener.addr);
+            Self::run_listener(
+                Arc::clone(&self.app),
+                Arc::clone(listener),
+                self.max_connections,
+                shutdown.clone(),
+            )
+        });
+        futures::future::join_all(loops).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        PeekRead, ProxyProtocolListener, ProxyProtocolService, is_trusted, read_proxy_header,
+    };
+    use async_trait::async_trait;
+    use ipnet::IpNet;
+    use pingora::apps::ServerApp;
+    use pingora::protocols::Stream;
+    use pingora::server::ShutdownWatch;
+    use pingora::services::background::BackgroundService;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    // Everything is ready at once, so there is never more to wait for.
+    impl PeekRead for &[u8] {
+        async fn peek_past(&mut self, buf: &mut [u8], seen: usize) -> std::io::Result<usize> {
+            let len = buf.len().min(self.len());
+            buf[..len].copy_from_slice(&self[..len]);
+            Ok(if len > seen { len } else { 0 })
+        }
+    }
+
+    // Echoes the client address Pingora would expose to the proxy.
+    struct ClientAddrApp;
+
+    #[async_trait]
+    impl ServerApp for ClientAddrApp {
+        async fn process_new(
+            self: &Arc<Self>,
+            mut session: Stream,
+            _shutdown: &ShutdownWatch,
+        ) -> Option<Stream> {
+            let peer = session
+                .get_socket_digest()
+                .and_then(|digest| digest.peer_addr().cloned())
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            session.write_all(peer.as_bytes()).await.ok()?;
+            session.shutdown().await.ok()?;
+            None
+        }
+    }
+
+    fn start_service(trusted: &str) -> (SocketAddr, tokio::sync::watch::Sender<bool>) {
+        start_limited_service(trusted, super::MAX_CONNECTIONS)
+    }
+
+    fn start_limited_service(
+        trusted: &str,
+        max_connections: usize,
+    ) -> (SocketAddr, tokio::sync::watch::Sender<bool>) {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let service = ProxyProtocolService::new(
+            ClientAddrApp,
+            vec![ProxyProtocolListener {
+                addr,
+                socket,
+                trusted: vec![trusted.parse().unwrap()],
+                #[cfg(feature = "openssl")]
+                tls: None,
+            }],
+        )
+        .with_max_connections(max_connections);
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move { service.start(shutdown).await });
+        (addr, shutdown_tx)
+    }
+
+    async fn exchange(addr: SocketAddr, header: &[u8]) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(header).await.unwrap();
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response).await;
+        response
+    }
+
+    async fn read(mut input: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let result = read_proxy_header(&mut input).await;
+        (result, input.to_vec())
+    }
+
+    fn v2_header(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn parses_v1_tcp4_and_leaves_request_bytes() {
+        let (result, rest) =
+            read(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n").await;
+
+        assert_eq!(result, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn parses_v1_tcp6_and_unknown() {
+        let (result, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n").await;
+        assert_eq!(result, Ok(Some("[2001:db8::7]:51234".parse().unwrap())));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1_headers() {
+        let (result, _) = read(b"PROXY TCP4 2001:db8::7 10.0.0.1 51234 443\r\n").await;
+        assert!(
+            result
+                .unwrap_err()
+                .contains("invalid PROXY protocol v1 header")
+        );
+
+        let (result, _) = read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 120]].concat()).await;
+        assert_eq!(result.unwrap_err(), "PROXY protocol v1 header is too long");
+
+        let (result, _) = read(b"GET / HTTP/1.1\r\nHost: example.com\r\n").await;
+        assert!(result.unwrap_err().contains("did not start with a PROXY"));
+    }
+
+    #[tokio::test]
+    async fn parses_v2_inet_and_inet6_with_tlvs() {
+        let mut payload = vec![203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb];
+        payload.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let mut input = v2_header(1, 0x11, &payload);
+        input.extend_from_slice(b"\x16\x03\x01");
+
+        let (result, rest) = read(&input).await;
+        assert_eq!(result, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"\x16\x03\x01");
+
+        let src: std::net::Ipv6Addr = "2001:db8::7".parse().unwrap();
+        let dst: std::net::Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut payload = src.octets().to_vec();
+        payload.extend_from_slice(&dst.octets());
+        payload.extend_from_slice(&[0xc8, 0x22, 0x01, 0xbb]);
+        let (result, _) = read(&v2_header(1, 0x21, &payload)).await;
+        assert_eq!(result, Ok(Some("[2001:db8::7]:51234".parse().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspec_keep_socket_peer() {
+        let (result, rest) = read(&[v2_header(0, 0x00, &[]), b"GET".to_vec()].concat()).await;
+        assert_eq!(result, Ok(None));
+        assert_eq!(rest, b"GET");
+
+        let (result, _) = read(&v2_header(1, 0x00, &[])).await;
+        assert_eq!(result, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_v2_headers() {
+        let mut wrong_version = v2_header(1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        let (result, _) = read(&wrong_version).await;
+        assert_eq!(result.unwrap_err(), "unsupported PROXY protocol version 1");
+
+        let (result, _) = read(&v2_header(1, 0x11, &[0; 4])).await;
+        assert!(result.unwrap_err().contains("truncated"));
+
+        // Datagram and unspecified transports carry no TCP client.
+        for family in [0x12, 0x10, 0x22] {
+            let (result, _) = read(&v2_header(1, family, &[0; 36])).await;
+            assert!(
+                result
+                    .unwrap_err()
+                    .contains("unsupported PROXY protocol v2 transport"),
+                "{family:#x}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn service_exposes_header_source_as_client_addr() {
+        let (addr, _shutdown) = start_service("127.0.0.0/8");
+
+        let response = exchange(addr, b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n").await;
+        assert_eq!(response, "203.0.113.7:51234");
+
+        let response = exchange(addr, b"PROXY UNKNOWN\r\n").await;
+        assert!(response.starts_with("127.0.0.1:"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn service_drops_untrusted_peers_and_missing_headers() {
+        let (addr, _shutdown) = start_service("192.0.2.0/24");
+        let response = exchange(addr, b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n").await;
+        assert_eq!(response, "");
+
+        let (addr, _shutdown) = start_service("127.0.0.0/8");
+        let response = exchange(addr, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert_eq!(response, "");
+    }
+
+    #[tokio::test]
+    async fn service_reads_a_v1_header_split_across_segments() {
+        let (addr, _shutdown) = start_service("127.0.0.0/8");
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"PROXY TCP4 203.0.113.7 ").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        client.write_all(b"10.0.0.1 51234 80\r\n").await.unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "203.0.113.7:51234");
+    }
+
+    #[tokio::test]
+    async fn peek_past_waits_for_new_bytes_and_leaves_them_readable() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        client.write_all(b"PROXY ").await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let ready = server.peek_past(&mut buf, 0).await.unwrap();
+        assert_eq!(&buf[..ready], b"PROXY ");
+
+        let more = tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let ready = server.peek_past(&mut buf, 6).await.unwrap();
+            (server, buf[..ready].to_vec())
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!more.is_finished());
+
+        client.write_all(b"TCP4").await.unwrap();
+        let (mut server, peeked) = tokio::time::timeout(Duration::from_secs(5), more)
+            .await
+            .expect("new bytes wake the peek")
+            .unwrap();
+        assert_eq!(peeked, b"PROXY TCP4");
+
+        // Cleared readiness must not hide bytes that are already buffered.
+        let mut read = [0u8; 10];
+        tokio::time::timeout(Duration::from_secs(5), server.read_exact(&mut read))
+            .await
+            .expect("peeked bytes stay readable")
+            .unwrap();
+        assert_eq!(&read, b"PROXY TCP4");
+    }
+
+    #[tokio::test]
+    async fn service_holds_connections_past_the_limit_in_the_backlog() {
+        let (addr, _shutdown) = start_limited_service("127.0.0.0/8", 1);
+        // Takes the only slot until its header completes.
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"PROXY TCP4 192.0.2.1 ").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let second = tokio::spawn(exchange(
+            addr,
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n",
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!second.is_finished());
+
+        first.write_all(b"10.0.0.1 1 80\r\n").await.unwrap();
+        let mut response = String::new();
+        first.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "192.0.2.1:1");
+        assert_eq!(second.await.unwrap(), "203.0.113.7:51234");
+    }
+
+    #[test]
+    fn trusted_networks_match_mapped_ipv4_peers() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+
+        assert!(is_trusted(&trusted, "10.1.2.3".parse().unwrap()));
+        assert!(is_trusted(
+            &trusted,
+            "::ffff:10.1.2.3".parse::<IpAddr>().unwrap()
+        ));
+        assert!(!is_trusted(&trusted, "192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,418 @@
+//! Live reload of the text config.
+//!
+//! `ConfigReloader` is a Pingora background service that re-runs the text
+//! config pipeline (parse → includes → IR → `CompiledRouter`) on `SIGHUP` and,
+//! optionally, whenever the config file or one of its includes changes. The result is
+//! pushed through [`RuntimeState::apply_snapshot`], so a broken config or a
+//! restart-only change leaves the current generation serving.
+
+use crate::control::{ApplyResult, ConfigSnapshot, RuntimeState};
+use crate::upstreams::CompiledRouter;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use ngxora_config::include::IncludeSources;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Editors usually emit several events per save; wait for them to settle
+/// before re-reading the config.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// A router built from the text config, plus the included files it was read
+/// from.
+pub struct LoadedConfig {
+    pub router: CompiledRouter,
+    pub sources: IncludeSources,
+}
+
+/// What a reload did with a config that loaded.
+#[derive(Debug)]
+pub enum ReloadOutcome {
+    /// The config compiles to the active router, so nothing was applied.
+    Unchanged { active_generation: u64 },
+    /// The router went through [`RuntimeState::apply_snapshot`], which may
+    /// still have rejected it.
+    Submitted(ApplyResult),
+}
+
+/// Builds a router from the config file at the given path.
+pub type RouterLoader = Arc<dyn Fn(&Path) -> Result<LoadedConfig, String> + Send + Sync>;
+
+pub struct ConfigReloader {
+    state: Arc<RuntimeState>,
+    config_path: PathBuf,
+    loader: RouterLoader,
+    watch: bool,
+    sources: Arc<ArcSwap<IncludeSources>>,
+}
+
+impl ConfigReloader {
+    pub fn new(state: Arc<RuntimeState>, config_path: PathBuf, loader: RouterLoader) -> Self {
+        Self {
+            state,
+            config_path,
+            loader,
+            watch: false,
+            sources: Arc::default(),
+        }
+    }
+
+    /// Also reload when the config file or one of its includes changes.
+    pub fn with_watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// Includes of the config the process started with; later reloads keep
+    /// this up to date.
+    pub fn with_sources(self, sources: IncludeSources) -> Self {
+        self.sources.store(Arc::new(sources));
+        self
+    }
+
+    /// Reloads the config once.
+    ///
+    /// The config is read and compiled on the blocking thread pool. Load
+    /// errors are returned as `Err` and leave the active snapshot alone. A
+    /// config that compiles to the active router is reported as
+    /// [`ReloadOutcome::Unchanged`] so unchanged saves do not bump the
+    /// generation.
+    pub async fn reload(&self) -> Result<ReloadOutcome, String> {
+        let loader = Arc::clone(&self.loader);
+        let config_path = self.config_path.clone();
+        let LoadedConfig { router, sources } =
+            tokio::task::spawn_blocking(move || loader(&config_path))
+                .await
+                .map_err(|err| format!("config loader failed: {err}"))??;
+        self.sources.store(Arc::new(sources));
+
+        let current = self.state.snapshot();
+        if router == current.router {
+            return Ok(ReloadOutcome::Unchanged {
+                active_generation: current.generation,
+            });
+        }
+
+        let version = format!("file:{}", self.config_path.display());
+        Ok(ReloadOutcome::Submitted(
+            self.state
+                .apply_snapshot(ConfigSnapshot::new(version, router)),
+        ))
+    }
+
+    async fn reload_and_log(&self, trigger: &str) {
+        log::info!(
+            "reloading config {} ({trigger})",
+            self.config_path.display()
+        );
+        match self.reload().await {
+            Ok(ReloadOutcome::Unchanged { active_generation }) => {
+                log::info!("config unchanged, keeping generation {active_generation}")
+            }
+            Ok(ReloadOutcome::Submitted(result)) if result.applied => log::info!(
+                "config reload applied: version={} generation={}",
+                result.active_version,
+                result.active_generation
+            ),
+            Ok(ReloadOutcome::Submitted(result)) if result.restart_required => log::warn!(
+                "config reload not applied, keeping generation {}: {}",
+                result.active_generation,
+                result.message
+            ),
+            Ok(ReloadOutcome::Submitted(result)) => log::error!(
+                "config reload rejected, keeping generation {}: {}",
+                result.active_generation,
+                result.message
+            ),
+            Err(err) => log::error!(
+                "config reload failed, keeping generation {}: {err}",
+                self.state.generation()
+            ),
+        }
+    }
+
+    /// Watches the directory holding the config, which also holds every
+    /// include. Directories are watched rather than files so atomic
+    /// rename-on-save editors keep triggering events; only the config file,
+    /// the files it includes and new matches of its include globs trigger a
+    /// reload.
+    fn watch_config_dir(
+        &self,
+        events: mpsc::UnboundedSender<()>,
+    ) -> Result<RecommendedWatcher, String> {
+        let dir = self
+            .config_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        // Include paths are canonical, so watch the canonical directory to get
+        // events for the same paths.
+        let dir = std::fs::canonicalize(dir)
+            .map_err(|err| format!("failed to resolve {}: {err}", dir.display()))?;
+        let config_path = dir.join(self.config_path.file_name().unwrap_or_default());
+        let sources = Arc::clone(&self.sources);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event
+                    && matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    )
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| is_config_file(&config_path, &sources.load(), path))
+                {
+                    let _ = events.send(());
+                }
+            })
+            .map_err(|err| format!("failed to create config watcher: {err}"))?;
+        watcher
+            .watch(&dir, RecursiveMode::Recursive)
+            .map_err(|err| format!("failed to watch {}: {err}", dir.display()))?;
+        Ok(watcher)
+    }
+}
+
+fn is_config_file(config_path: &Path, sources: &IncludeSources, path: &Path) -> bool {
+    path == config_path || sources.contains(path)
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::error!("failed to install SIGHUP handler, live reload disabled: {err}");
+                return;
+            }
+        };
+
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        // Keep the watcher alive for the lifetime of the service; dropping the
+        // sender when watching is disabled turns the event branch off.
+        let _watcher = if self.watch {
+            match self.watch_config_dir(events_tx) {
+                Ok(watcher) => Some(watcher),
+                Err(err) => {
+                    log::error!("{err}; only SIGHUP reloads are available");
+                    None
+                }
+            }
+        } else {
+            drop(events_tx);
+            None
+        };
+
+        // Each file event pushes the deadline back, so a burst of events
+        // reloads once; signals and shutdown are still handled meanwhile.
+        let mut debounce: Option<Instant> = None;
+        loop {
+            if *shutdown.borrow() {
+                return;
+            }
+
+            tokio::select! {
+                changed = shutdown.changed() => {
+                    if changed.is_err() || *shutdown.borrow() {
+                        return;
+                    }
+                }
+                Some(()) = hangup.recv() => {
+                    debounce = None;
+                    self.reload_and_log("SIGHUP").await;
+                }
+                Some(()) = events.recv() => debounce = Some(Instant::now() + WATCH_DEBOUNCE),
+                () = tokio::time::sleep_until(debounce.unwrap_or_else(Instant::now)),
+                    if debounce.is_some() =>
+                {
+                    debounce = None;
+                    self.reload_and_log("file change").await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigReloader, LoadedConfig, ReloadOutcome, RouterLoader, is_config_file};
+    use crate::control::{ConfigSnapshot, RuntimeState};
+    use crate::upstreams::CompiledRouter;
+    use ngxora_compile::ir::{Http, Listen, Location, LocationDirective, LocationMatcher, Server};
+    use ngxora_config::include::IncludeSources;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    fn router(port: u16, redirect: &str) -> CompiledRouter {
+        let http = Http {
+            servers: vec![Server {
+                listens: vec![Listen {
+                    addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    port,
+                    default_server: true,
+                    ..Listen::default()
+                }],
+                locations: vec![Location {
+                    matcher: LocationMatcher::Prefix("/".into()),
+                    directives: vec![LocationDirective::Return {
+                        status: 302,
+                        location: redirect.into(),
+                    }],
+                    access_rules: Vec::new(),
+                    plugins: Vec::new(),
+                    cache: None,
+                }],
+                ..Server::default()
+            }],
+            ..Http::default()
+        };
+        CompiledRouter::from_http(&http).expect("router compiles")
+    }
+
+    fn reloader(
+        state: &Arc<RuntimeState>,
+        next: &Arc<Mutex<Result<CompiledRouter, String>>>,
+    ) -> ConfigReloader {
+        let next = Arc::clone(next);
+        let loader: RouterLoader = Arc::new(move |_| {
+            let router = next.lock().unwrap().clone()?;
+            Ok(LoadedConfig {
+                router,
+                sources: IncludeSources::default(),
+            })
+        });
+        ConfigReloader::new(
+            Arc::clone(state),
+            PathBuf::from("/etc/ngxora/ngxora.conf"),
+            loader,
+        )
+    }
+
+    #[test]
+    fn watch_filter_matches_config_and_include_files() {
+        let config = Path::new("/etc/ngxora/ngxora.conf");
+        let sources = IncludeSources {
+            files: vec![
+                PathBuf::from("/etc/ngxora/upstreams"),
+                PathBuf::from("/etc/ngxora/sites-enabled/api"),
+            ],
+            patterns: vec![PathBuf::from("/etc/ngxora/sites-enabled/*")],
+        };
+        let watched = |path: &str| is_config_file(config, &sources, Path::new(path));
+
+        assert!(watched("/etc/ngxora/ngxora.conf"));
+        assert!(watched("/etc/ngxora/upstreams"));
+        assert!(watched("/etc/ngxora/sites-enabled/api"));
+        assert!(watched("/etc/ngxora/sites-enabled/shop"));
+        assert!(!watched("/etc/ngxora/access.log"));
+        assert!(!watched("/etc/ngxora/other.conf"));
+        assert!(!watched("/etc/ngxora/.ngxora.conf.swp"));
+        assert!(!watched("/etc/ngxora/sites-enabled/.api.swp"));
+    }
+
+    #[tokio::test]
+    async fn reload_records_the_loaded_include_sources() {
+        let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
+            "v1",
+            router(8080, "/a"),
+        )));
+        let loader: RouterLoader = Arc::new(|_| {
+            Ok(LoadedConfig {
+                router: router(8080, "/b"),
+                sources: IncludeSources {
+                    files: vec![PathBuf::from("/etc/ngxora/sites-enabled/api")],
+                    patterns: Vec::new(),
+                },
+            })
+        });
+        let reloader = ConfigReloader::new(
+            Arc::clone(&state),
+            PathBuf::from("/etc/ngxora/ngxora.conf"),
+            loader,
+        );
+
+        reloader.reload().await.expect("config loads");
+
+        assert_eq!(
+            reloader.sources.load().files,
+            [PathBuf::from("/etc/ngxora/sites-enabled/api")]
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_applies_changed_routes() {
+        let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
+            "v1",
+            router(8080, "/a"),
+        )));
+        let next = Arc::new(Mutex::new(Ok(router(8080, "/b"))));
+
+        let Ok(ReloadOutcome::Submitted(result)) = reloader(&state, &next).reload().await else {
+            panic!("changed config is submitted");
+        };
+
+        assert!(result.applied);
+        assert_eq!(result.active_generation, 2);
+        assert_eq!(result.active_version, "file:/etc/ngxora/ngxora.conf");
+        assert_eq!(state.snapshot().router, router(8080, "/b"));
+    }
+
+    #[tokio::test]
+    async fn reload_skips_unchanged_config() {
+        let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
+            "v1",
+            router(8080, "/a"),
+        )));
+        let next = Arc::new(Mutex::new(Ok(router(8080, "/a"))));
+
+        let outcome = reloader(&state, &next)
+            .reload()
+            .await
+            .expect("config loads");
+
+        assert!(matches!(
+            outcome,
+            ReloadOutcome::Unchanged {
+                active_generation: 1
+            }
+        ));
+        assert_eq!(state.generation(), 1);
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_serving_on_load_error_and_restart_changes() {
+        let state = Arc::new(RuntimeState::new(ConfigSnapshot::new(
+            "v1",
+            router(8080, "/a"),
+        )));
+        let next = Arc::new(Mutex::new(Err("failed to parse config".to_string())));
+        let reloader = reloader(&state, &next);
+
+        let err = reloader.reload().await.expect_err("load error is surfaced");
+        assert_eq!(err, "failed to parse config");
+        assert_eq!(state.generation(), 1);
+
+        *next.lock().unwrap() = Ok(router(8443, "/a"));
+        let Ok(ReloadOutcome::Submitted(result)) = reloader.reload().await else {
+            panic!("changed config is submitted");
+        };
+        assert!(!result.applied);
+        assert!(result.restart_required);
+        assert!(
+            result
+                .restart_changes
+                .contains(&"listener 0.0.0.0:8443 added".to_string())
+        );
+        assert_eq!(state.snapshot().version, "v1");
+    }
+}
@@ -0,0 +1,288 @@
+//! Request IDs: `request_id on` in a server block.
+//!
+//! The ID is chosen in `request_filter`. An incoming header is kept only when
+//! the peer is one of the server's `trusted` networks; every other request
+//! gets a new UUIDv7 or ULID. The ID is written back into the request header,
+//! so plugins and the upstream see it, and [`RequestIdModule`] copies it onto
+//! every response the proxy writes.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use ngxora_compile::ir::{RequestId, RequestIdFormat};
+use pingora::http::ResponseHeader;
+use pingora::modules::http::{HttpModule, HttpModuleBuilder, Module};
+use pingora::protocols::http::ServerSession;
+use pingora::{Error, ErrorType, Result};
+use pingora_proxy::Session;
+use std::any::Any;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Incoming IDs longer than this are replaced even from trusted peers.
+const MAX_INCOMING_LEN: usize = 200;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// Picks the request ID, stores it in the request header and arranges for it
+/// to be returned in the response.
+pub(crate) fn assign(
+    session: &mut Session,
+    config: &RequestId,
+    client_ip: Option<IpAddr>,
+) -> Result<String> {
+    let header = http::HeaderName::from_bytes(config.header.as_bytes()).map_err(|err| {
+        Error::explain(
+            ErrorType::InternalError,
+            format!("invalid request ID header `{}`: {err}", config.header),
+        )
+    })?;
+    // Dual-stack listeners report IPv4 peers as `::ffff:a.b.c.d`.
+    let trusted = client_ip.is_some_and(|ip| {
+        let ip = ip.to_canonical();
+        config.trusted.iter().any(|network| network.contains(&ip))
+    });
+    let id = session
+        .req_header()
+        .headers
+        .get(&header)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| trusted && is_acceptable(value))
+        .map_or_else(
+            || generate(config.format, SystemTime::now()),
+            ToString::to_string,
+        );
+
+    let value = http::HeaderValue::from_str(&id).map_err(|err| {
+        Error::explain(
+            ErrorType::InternalError,
+            format!("invalid request ID `{id}`: {err}"),
+        )
+    })?;
+    session
+        .req_header_mut()
+        .insert_header(header.clone(), &value)?;
+    if let Some(module) = session.downstream_modules_ctx.get_mut::<RequestIdModule>() {
+        module.header = Some((header, value));
+    }
+    Ok(id)
+}
+
+/// `Session::respond_error` that keeps the request ID header, which the
+/// plain error writer would skip.
+pub(crate) async fn respond_error(session: &mut Session, status: u16) -> Result<()> {
+    let mut response = ServerSession::generate_error(status);
+    if let Some((name, value)) = session
+        .downstream_modules_ctx
+        .get::<RequestIdModule>()
+        .and_then(|module| module.header.clone())
+    {
+        response.insert_header(name, value)?;
+    }
+    session
+        .as_downstream_mut()
+        .write_error_response(response, Bytes::new())
+        .await
+}
+
+fn is_acceptable(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_INCOMING_LEN
+        && value.bytes().all(|b| b.is_ascii_graphic())
+}
+
+pub(crate) fn generate(format: RequestIdFormat, now: SystemTime) -> String {
+    let millis = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let random = fastrand::u128(..);
+    match format {
+        RequestIdFormat::UuidV7 => uuid_v7(millis, random),
+        RequestIdFormat::Ulid => ulid(millis, random),
+    }
+}
+
+/// RFC 9562 layout: 48 bit timestamp, version, 12 random bits, variant and
+/// 62 random bits.
+fn uuid_v7(millis: u64, random: u128) -> String {
+    let rand_a = (random >> 62) & 0xfff;
+    let rand_b = random & ((1 << 62) - 1);
+    let value = (u128::from(millis & 0xffff_ffff_ffff) << 80)
+        | (0x7 << 76)
+        | (rand_a << 64)
+        | (0b10 << 62)
+        | rand_b;
+    let hex = format!("{value:032x}");
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// 48 bit timestamp and 80 random bits in Crockford base32.
+fn ulid(millis: u64, random: u128) -> String {
+    let value = (u128::from(millis & 0xffff_ffff_ffff) << 80) | (random & ((1 << 80) - 1));
+    (0..26)
+        .map(|index| char::from(CROCKFORD[((value >> (125 - 5 * index)) & 0x1f) as usize]))
+        .collect()
+}
+
+/// Downstream module that adds the request ID to every response header,
+/// whichever path wrote the response.
+#[derive(Default)]
+pub(crate) struct RequestIdModule {
+    header: Option<(http::HeaderName, http::HeaderValue)>,
+}
+
+#[async_trait]
+impl HttpModule for RequestIdModule {
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> Result<()> {
+        if let Some((name, value)) = &self.header {
+            resp.insert_header(name.clone(), value)?;
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub(crate) struct RequestIdModuleBuilder;
+
+impl HttpModuleBuilder for RequestIdModuleBuilder {
+    fn init(&self) -> Module {
+        Box::new(RequestIdModule::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::modules::http::HttpModules;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+
+    async fn session_with_id(id: &str) -> (Session, DuplexStream) {
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(
+                format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Trace: {id}\r\n\r\n").as_bytes(),
+            )
+            .await
+            .expect("write request");
+        let mut modules = HttpModules::new();
+        modules.add_module(Box::new(RequestIdModuleBuilder));
+        let mut session = Session::new_h1_with_modules(Box::new(server), &modules);
+        session.read_request().await.expect("read request");
+        (session, client)
+    }
+
+    async fn response_text(session: Session, mut client: DuplexStream) -> String {
+        drop(session);
+        let mut response = String::new();
+        client
+            .read_to_string(&mut response)
+            .await
+            .expect("read response");
+        response.to_ascii_lowercase()
+    }
+
+    fn config() -> RequestId {
+        RequestId {
+            header: "x-trace".into(),
+            trusted: vec!["10.0.0.0/8".parse().unwrap()],
+            format: RequestIdFormat::Ulid,
+        }
+    }
+
+    #[tokio::test]
+    async fn untrusted_peer_gets_a_new_id_in_request_and_error_response() {
+        let (mut session, client) = session_with_id("spoofed").await;
+        let id = assign(&mut session, &config(), Some("192.0.2.1".parse().unwrap())).unwrap();
+        assert_eq!(id.len(), 26);
+        assert_eq!(session.req_header().headers["x-trace"], id.as_str());
+
+        respond_error(&mut session, 403).await.unwrap();
+        let response = response_text(session, client).await;
+        assert!(response.starts_with("http/1.1 403"), "{response}");
+        assert!(
+            response.contains(&format!("x-trace: {}", id.to_ascii_lowercase())),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn trusted_peer_keeps_its_id_on_every_response() {
+        let (mut session, client) = session_with_id("edge-42").await;
+        let id = assign(&mut session, &config(), Some("10.1.2.3".parse().unwrap())).unwrap();
+        assert_eq!(id, "edge-42");
+
+        let mut response = ResponseHeader::build(204, None).unwrap();
+        response.insert_header("x-trace", "from-upstream").unwrap();
+        session
+            .write_response_header(Box::new(response), true)
+            .await
+            .unwrap();
+        let response = response_text(session, client).await;
+        assert!(response.contains("x-trace: edge-42"), "{response}");
+        assert!(!response.contains("from-upstream"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn ipv4_mapped_trusted_peer_keeps_its_id() {
+        let (mut session, _client) = session_with_id("edge-43").await;
+        let peer = "::ffff:10.1.2.3".parse().unwrap();
+        let id = assign(&mut session, &config(), Some(peer)).unwrap();
+        assert_eq!(id, "edge-43");
+    }
+
+    #[test]
+    fn uuid_v7_matches_rfc_layout() {
+        // RFC 9562 appendix A.6.
+        let random = (0xcc3 << 62) | 0x18c4_dc0c_0c07_398f;
+        assert_eq!(
+            uuid_v7(0x017f_22e2_79b0, random),
+            "017f22e2-79b0-7cc3-98c4-dc0c0c07398f"
+        );
+    }
+
+    #[test]
+    fn ulid_encodes_timestamp_and_randomness() {
+        assert_eq!(ulid(0, 0), "00000000000000000000000000");
+        assert_eq!(ulid(u64::MAX, u128::MAX), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert_eq!(&ulid(1_469_918_176_385, 0)[..10], "01ARYZ6S41");
+    }
+
+    #[test]
+    fn generated_ids_sort_by_time() {
+        let earlier = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let later = earlier + Duration::from_millis(1);
+        for format in [RequestIdFormat::UuidV7, RequestIdFormat::Ulid] {
+            let first = generate(format, earlier);
+            assert!(first < generate(format, later), "{format:?}");
+            assert_ne!(first, generate(format, earlier), "{format:?}");
+        }
+        assert_eq!(generate(RequestIdFormat::UuidV7, earlier).len(), 36);
+        assert_eq!(generate(RequestIdFormat::Ulid, earlier).len(), 26);
+    }
+
+    #[test]
+    fn incoming_ids_must_be_short_visible_ascii() {
+        assert!(is_acceptable("0190b5a4-7f3e-7c1a-9d2b-5e6f7a8b9c0d"));
+        assert!(!is_acceptable(""));
+        assert!(!is_acceptable("two words"));
+        assert!(!is_acceptable(&"a".repeat(MAX_INCOMING_LEN + 1)));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::control::RuntimeState;
+use crate::proxy_protocol::{ProxyProtocolListener, ProxyProtocolService};
 use crate::upstreams::{
     CompiledRouter, ListenKey, ListenerProtocolConfig, ListenerTlsConfig, ListenerTlsSettings,
 };
@@ -8,8 +9,19 @@ use ngxora_compile::ir::{
 use pingora::Result;
 use pingora::apps::HttpServerOptions;
 use pingora::listeners::ALPN;
+#[cfg(feature = "openssl")]
+use pingora::listeners::TlsAcceptCallbacks;
 use pingora::listeners::tls::TlsSettings;
+#[cfg(feature = "openssl")]
+use pingora::protocols::Stream;
+#[cfg(feature = "openssl")]
+use pingora::protocols::l4::stream::Stream as L4Stream;
+#[cfg(feature = "openssl")]
+use pingora::protocols::tls::server::handshake_with_callback;
+use pingora::server::configuration::ServerConf;
 use pingora::services::listening::Service;
+#[cfg(feature = "openssl")]
+use pingora::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod};
 use pingora::tls::ssl::{SslVerifyMode, SslVersion};
 use pingora_proxy::{HttpProxy, ProxyHttp};
 use std::net::SocketAddr;
@@ -32,20 +44,99 @@ pub(crate) struct DownstreamTlsInfo {
 #[cfg(feature = "openssl")]
 mod openssl_listener_tls {
     use super::{
-        DownstreamTlsInfo, ListenKey, PemSource, RuntimeState, listener_addr, select_listener_tls,
+        DownstreamTlsInfo, ListenKey, ListenerProtocolConfig, PemSource, RuntimeState,
+        listener_addr, listener_alpn_wire, select_listener_tls,
+    };
+    use crate::le::{
+        ACME_TLS_ALPN_WIRE, TlsAlpnCertificate, TlsAlpnChallenges, lookup_tls_alpn_challenge,
     };
     use async_trait::async_trait;
+    use foreign_types::ForeignTypeRef;
     use ngxora_compile::ir::TlsIdentity;
     use pingora::Result;
     use pingora::listeners::TlsAccept;
     use pingora::protocols::tls::TlsRef;
     use pingora::tls::ext;
     use pingora::tls::pkey::{PKey, Private};
-    use pingora::tls::ssl::{NameType, SslRef};
+    use pingora::tls::ssl::{
+        AlpnError, ClientHelloResponse, NameType, Ssl, SslAcceptorBuilder, SslRef,
+        select_next_proto,
+    };
+    use pingora::tls::ssl_sys;
     use pingora::tls::x509::X509;
     use std::any::Any;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, OnceLock};
+
+    const TLSEXT_TYPE_SERVER_NAME: u32 = 0;
+    const TLSEXT_TYPE_ALPN: u32 = 16;
+
+    /// The pending TLS-ALPN-01 certificate for a ClientHello that offers
+    /// `acme-tls/1` for its SNI.
+    struct AcmeTlsAlpnChallenge(Arc<TlsAlpnCertificate>);
+
+    fn acme_tls_alpn_index() -> openssl::ex_data::Index<Ssl, AcmeTlsAlpnChallenge> {
+        static INDEX: OnceLock<openssl::ex_data::Index<Ssl, AcmeTlsAlpnChallenge>> =
+            OnceLock::new();
+        *INDEX.get_or_init(|| Ssl::new_ex_index().expect("allocate TLS ex_data index"))
+    }
+
+    /// Selects the listener's protocols, or `acme-tls/1` when a TLS-ALPN-01
+    /// challenge is pending for the SNI. With TLS 1.2 the ALPN is only
+    /// negotiated after the certificate callback, so the ClientHello is
+    /// inspected up front.
+    pub(super) fn set_listener_alpn(
+        builder: &mut SslAcceptorBuilder,
+        protocol: &ListenerProtocolConfig,
+        challenges: TlsAlpnChallenges,
+    ) {
+        let alpn = listener_alpn_wire(protocol);
+        builder.set_alpn_select_callback(move |ssl, offered| {
+            ssl.ex_data(acme_tls_alpn_index())
+                .and_then(|_| select_next_proto(ACME_TLS_ALPN_WIRE, offered))
+                .or_else(|| select_next_proto(alpn, offered))
+                .ok_or(AlpnError::NOACK)
+        });
+        builder.set_client_hello_callback(move |ssl, _| {
+            let offered = client_hello_ext(ssl, TLSEXT_TYPE_ALPN)
+                .and_then(|ext| ext.get(2..))
+                .is_some_and(|offered| select_next_proto(ACME_TLS_ALPN_WIRE, offered).is_some());
+            let challenge = offered
+                .then(|| client_hello_server_name(ssl))
+                .flatten()
+                .and_then(|name| lookup_tls_alpn_challenge(&challenges, name));
+            if let Some(challenge) = challenge {
+                ssl.set_ex_data(acme_tls_alpn_index(), AcmeTlsAlpnChallenge(challenge));
+            }
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+    }
+
+    fn client_hello_ext(ssl: &SslRef, ext_type: u32) -> Option<&[u8]> {
+        let mut data = std::ptr::null();
+        let mut len = 0;
+        // SAFETY: only called from the ClientHello callback, while OpenSSL
+        // holds the ClientHello the returned slice points into.
+        unsafe {
+            let found =
+                ssl_sys::SSL_client_hello_get0_ext(ssl.as_ptr(), ext_type, &mut data, &mut len);
+            (found == 1 && !data.is_null()).then(|| std::slice::from_raw_parts(data, len))
+        }
+    }
+
+    // The `host_name` entry of the server_name extension (RFC 6066 §3); the
+    // SNI is not yet available through `SslRef::servername` at this point.
+    fn client_hello_server_name(ssl: &SslRef) -> Option<&str> {
+        let ext = client_hello_ext(ssl, TLSEXT_TYPE_SERVER_NAME)?;
+        let list = ext.get(2..)?;
+        let (&name_type, rest) = list.split_first()?;
+        let len = usize::from(u16::from_be_bytes([*rest.first()?, *rest.get(1)?]));
+        let name = rest.get(2..2 + len)?;
+        if name_type != 0 {
+            return None;
+        }
+        std::str::from_utf8(name).ok()
+    }
 
     #[derive(Debug, Clone, Eq, PartialEq, Hash)]
     enum LoadedPemSourceKey {
@@ -272,6 +363,24 @@ mod openssl_listener_tls {
             Ok(())
         }
 
+        fn install_tls_alpn_challenge(
+            &self,
+            ssl: &mut SslRef,
+            challenge: &TlsAlpnCertificate,
+        ) -> Result<()> {
+            ext::ssl_use_certificate(ssl, &challenge.cert)
+                .and_then(|()| ext::ssl_use_private_key(ssl, &challenge.key))
+                .map_err(|err| {
+                    pingora::Error::explain(
+                        pingora::ErrorType::InternalError,
+                        format!(
+                            "failed to install TLS-ALPN-01 certificate for listener {}: {err}",
+                            listener_addr(&self.listen_key)
+                        ),
+                    )
+                })
+        }
+
         #[cfg(test)]
         pub(super) fn install_selected_identity_for_test(
             &self,
@@ -286,6 +395,16 @@ mod openssl_listener_tls {
     #[async_trait]
     impl TlsAccept for SniCertResolver {
         async fn certificate_callback(&self, ssl: &mut SslRef) {
+            let challenge = ssl
+                .ex_data(acme_tls_alpn_index())
+                .map(|AcmeTlsAlpnChallenge(challenge)| Arc::clone(challenge));
+            if let Some(challenge) = challenge {
+                if let Err(err) = self.install_tls_alpn_challenge(ssl, &challenge) {
+                    eprintln!("{err}");
+                }
+                return;
+            }
+
             let result = self
                 .select(ssl.servername(NameType::HOST_NAME))
                 .and_then(|identity| self.install_identity(ssl, &identity));
@@ -450,7 +569,16 @@ fn select_listener_tls<'a>(
     server_name: Option<&str>,
 ) -> Result<ListenerTlsConfigIdentity<'a>> {
     if let Some(server_name) = server_name {
-        if let Some(identity) = tls.named.get(&server_name.to_ascii_lowercase()) {
+        let server_name = server_name.to_ascii_lowercase();
+        // `*.example.com` covers exactly one label in front of its parent.
+        let wildcard = server_name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+        if let Some(identity) = tls
+            .named
+            .get(&server_name)
+            .or_else(|| wildcard.and_then(|wildcard| tls.named.get(&wildcard)))
+        {
             return Ok(identity);
         }
     }
@@ -492,6 +620,11 @@ fn listener_tls_settings(
         let callbacks = openssl_listener_tls::SniCertResolver::new(state, key.clone());
         let mut settings = TlsSettings::with_callbacks(callbacks)?;
         apply_listener_tls_settings(&mut settings, protocol, &tls.settings)?;
+        openssl_listener_tls::set_listener_alpn(
+            &mut settings,
+            protocol,
+            Arc::clone(state.tls_alpn_challenges()),
+        );
         Ok(settings)
     }
 
@@ -508,6 +641,64 @@ fn listener_tls_settings(
     }
 }
 
+// PROXY protocol listeners terminate TLS outside Pingora's listener stack, so
+// they build their own acceptor from the same listener policy and SNI resolver.
+#[cfg(feature = "openssl")]
+pub(crate) struct ListenerTlsAcceptor {
+    acceptor: SslAcceptor,
+    callbacks: TlsAcceptCallbacks,
+}
+
+#[cfg(feature = "openssl")]
+impl ListenerTlsAcceptor {
+    fn new(
+        key: &ListenKey,
+        tls: &ListenerTlsConfig,
+        protocol: &ListenerProtocolConfig,
+        state: Arc<RuntimeState>,
+    ) -> Result<Self> {
+        let mut builder =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(|err| {
+                pingora::Error::explain(
+                    pingora::ErrorType::InternalError,
+                    format!("failed to create TLS acceptor: {err}"),
+                )
+            })?;
+        openssl_listener_tls::set_listener_alpn(
+            &mut builder,
+            protocol,
+            Arc::clone(state.tls_alpn_challenges()),
+        );
+        if let Some(protocols) = tls.settings.protocols {
+            apply_protocol_bounds(&mut builder, protocols)?;
+        }
+        apply_client_verification(&mut builder, &tls.settings)?;
+
+        Ok(Self {
+            acceptor: builder.build(),
+            callbacks: openssl_listener_tls::SniCertResolver::new(state, key.clone()),
+        })
+    }
+
+    pub(crate) async fn handshake(&self, stream: L4Stream) -> Result<Stream> {
+        let stream = handshake_with_callback(&self.acceptor, stream, &self.callbacks).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+// Wire-format counterpart of `listener_alpn` for acceptors built outside
+// `TlsSettings`.
+#[cfg(feature = "openssl")]
+fn listener_alpn_wire(protocol: &ListenerProtocolConfig) -> &'static [u8] {
+    if protocol.http2_only {
+        b"\x02h2"
+    } else if protocol.http2 {
+        b"\x02h2\x08http/1.1"
+    } else {
+        b"\x08http/1.1"
+    }
+}
+
 fn listener_alpn(protocol: &ListenerProtocolConfig) -> ALPN {
     if protocol.http2_only {
         ALPN::H2
@@ -538,7 +729,10 @@ fn reject_openssl_only_tls_settings(tls: &ListenerTlsSettings) -> Result<()> {
 }
 
 #[cfg(feature = "openssl")]
-fn apply_protocol_bounds(settings: &mut TlsSettings, protocols: TlsProtocolBounds) -> Result<()> {
+fn apply_protocol_bounds(
+    settings: &mut SslAcceptorBuilder,
+    protocols: TlsProtocolBounds,
+) -> Result<()> {
     settings
         .set_min_proto_version(Some(ssl_version(protocols.min)))
         .map_err(|err| {
@@ -559,7 +753,10 @@ fn apply_protocol_bounds(settings: &mut TlsSettings, protocols: TlsProtocolBound
 }
 
 #[cfg(feature = "openssl")]
-fn apply_client_verification(settings: &mut TlsSettings, tls: &ListenerTlsSettings) -> Result<()> {
+fn apply_client_verification(
+    settings: &mut SslAcceptorBuilder,
+    tls: &ListenerTlsSettings,
+) -> Result<()> {
     match tls.verify_client {
         TlsVerifyClient::Off => settings.set_verify(SslVerifyMode::NONE),
         TlsVerifyClient::Optional => settings.set_verify(SslVerifyMode::PEER),
@@ -638,12 +835,23 @@ where
         )
     })?;
 
+    proxy.server_options = Some(http_server_options(router));
+    Ok(())
+}
+
+fn http_server_options(router: &CompiledRouter) -> HttpServerOptions {
     let mut options = HttpServerOptions::default();
     options.h2c = router.http_options.h2c;
     options.allow_connect_method_proxying = router.http_options.allow_connect_method_proxying;
     options.keepalive_request_limit = router.http_options.keepalive_requests;
-    proxy.server_options = Some(options);
-    Ok(())
+    options
+}
+
+fn uses_proxy_protocol(router: &CompiledRouter, key: &ListenKey) -> bool {
+    router
+        .listener_protocols
+        .get(key)
+        .is_some_and(|protocol| protocol.proxy_protocol)
 }
 
 fn sorted_listener_keys(router: &CompiledRouter) -> Vec<ListenKey> {
@@ -697,6 +905,11 @@ where
     configure_proxy_service(svc, router)?;
 
     for key in sorted_listener_keys(router) {
+        // Served by `ProxyProtocolService`; see `bind_proxy_protocol_listeners`.
+        if uses_proxy_protocol(router, &key) {
+            continue;
+        }
+
         let addr = listener_addr(&key);
 
         if key.ssl {
@@ -738,3 +951,87 @@ where
         Arc::new(RuntimeState::bootstrap(router.clone())),
     )
 }
+
+// Bind listeners that expect a PROXY protocol header. The header precedes the
+// TLS handshake, which Pingora's listening service has no hook for, so these
+// sockets are bound here and served by `ProxyProtocolService`.
+fn bind_proxy_protocol_listeners(
+    router: &CompiledRouter,
+    state: Arc<RuntimeState>,
+) -> Result<Vec<ProxyProtocolListener>> {
+    #[cfg(not(feature = "openssl"))]
+    let _ = state;
+    let mut listeners = Vec::new();
+
+    for key in sorted_listener_keys(router) {
+        if !uses_proxy_protocol(router, &key) {
+            continue;
+        }
+
+        let addr = listener_addr(&key);
+        let protocol = listener_protocol(router, &key, &addr)?;
+
+        #[cfg(feature = "openssl")]
+        let tls = if key.ssl {
+            let tls = listener_tls(router, &key, &addr)?;
+            Some(ListenerTlsAcceptor::new(
+                &key,
+                tls,
+                protocol,
+                Arc::clone(&state),
+            )?)
+        } else {
+            None
+        };
+
+        #[cfg(not(feature = "openssl"))]
+        if key.ssl {
+            return Err(pingora::Error::explain(
+                pingora::ErrorType::InternalError,
+                format!(
+                    "ssl listener {addr} with proxy_protocol requires build with feature `openssl`"
+                ),
+            ));
+        }
+
+        let socket_addr = SocketAddr::new(key.addr, key.port);
+        let socket = std::net::TcpListener::bind(socket_addr).map_err(|err| {
+            pingora::Error::explain(
+                pingora::ErrorType::BindError,
+                format!("failed to bind PROXY protocol listener {addr}: {err}"),
+            )
+        })?;
+
+        listeners.push(ProxyProtocolListener {
+            addr: socket.local_addr().unwrap_or(socket_addr),
+            socket,
+            trusted: protocol.proxy_protocol_trusted.clone(),
+            #[cfg(feature = "openssl")]
+            tls,
+        });
+    }
+
+    Ok(listeners)
+}
+
+// Build the accept loop for `listen ... proxy_protocol` sockets around a second
+// instance of the proxy application. Returns `None` when no listener uses the
+// PROXY protocol.
+pub fn proxy_protocol_service_from_state<SV>(
+    conf: &Arc<ServerConf>,
+    inner: SV,
+    state: Arc<RuntimeState>,
+) -> Result<Option<ProxyProtocolService<HttpProxy<SV, ()>>>>
+where
+    SV: ProxyHttp,
+{
+    let snapshot = state.snapshot();
+    let listeners = bind_proxy_protocol_listeners(&snapshot.router, state)?;
+    if listeners.is_empty() {
+        return Ok(None);
+    }
+
+    let mut proxy = pingora_proxy::http_proxy(conf, inner);
+    proxy.server_options = Some(http_server_options(&snapshot.router));
+    Ok(Some(ProxyProtocolService::new(proxy, listeners)))
+}
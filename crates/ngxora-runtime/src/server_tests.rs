@@ -316,6 +316,135 @@ fn sni_resolver_sends_full_certificate_chain() {
     server.join().expect("join test server");
 }
 
+// Serves a TLS listener for `example.com` through `ListenerTlsAcceptor`, with
+// `challenges` pending, and returns its address and configured certificate.
+#[cfg(feature = "openssl")]
+async fn spawn_tls_alpn_listener(
+    challenges: Vec<(&str, crate::le::TlsAlpnCertificate)>,
+) -> (std::net::SocketAddr, Vec<u8>) {
+    use super::ListenerTlsAcceptor;
+    use crate::upstreams::ListenerProtocolConfig;
+
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let cert_path = dir.path().join("fullchain.pem");
+    let key_path = dir.path().join("privkey.pem");
+    write_self_signed_certificate(&cert_path, &key_path, 1);
+    let configured = X509::from_pem(&fs::read(&cert_path).expect("read certificate"))
+        .expect("parse certificate")
+        .to_der()
+        .expect("encode certificate");
+    let identity = TlsIdentity {
+        cert: PemSource::Path(cert_path),
+        key: PemSource::Path(key_path),
+    };
+    let router = router_with_tls_identity(identity);
+    let (listen_key, tls) = router
+        .listener_tls
+        .iter()
+        .next()
+        .map(|(key, tls)| (key.clone(), tls.clone()))
+        .expect("router has a TLS listener");
+    let state = Arc::new(RuntimeState::bootstrap(router));
+    for (domain, challenge) in challenges {
+        state
+            .tls_alpn_challenges()
+            .insert(domain.into(), Arc::new(challenge));
+    }
+
+    let acceptor = Arc::new(
+        ListenerTlsAcceptor::new(&listen_key, &tls, &ListenerProtocolConfig::default(), state)
+            .expect("build TLS acceptor"),
+    );
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .expect("bind test listener");
+    let addr = listener.local_addr().expect("get test listener address");
+    tokio::spawn(async move {
+        let _dir = dir;
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = Arc::clone(&acceptor);
+            tokio::spawn(async move {
+                let _ = acceptor.handshake(stream.into()).await;
+            });
+        }
+    });
+    (addr, configured)
+}
+
+// TLS 1.2 negotiates ALPN after the certificate callback, so this also covers
+// reading the offer from the ClientHello. Returns the server certificate and
+// the negotiated protocol.
+#[cfg(feature = "openssl")]
+async fn tls12_handshake(
+    addr: std::net::SocketAddr,
+    server_name: &'static str,
+    alpn: &'static [u8],
+) -> (Vec<u8>, Option<Vec<u8>>) {
+    use openssl::ssl::SslVersion;
+
+    tokio::task::spawn_blocking(move || {
+        let mut connector =
+            SslConnector::builder(SslMethod::tls_client()).expect("create client TLS connector");
+        connector.set_verify(SslVerifyMode::NONE);
+        connector
+            .set_max_proto_version(Some(SslVersion::TLS1_2))
+            .expect("limit client to TLS 1.2");
+        connector.set_alpn_protos(alpn).expect("set client ALPN");
+        let stream = TcpStream::connect(addr).expect("connect to test listener");
+        let stream = connector
+            .build()
+            .connect(server_name, stream)
+            .expect("connect TLS client");
+        (
+            stream
+                .ssl()
+                .peer_certificate()
+                .expect("server certificate")
+                .to_der()
+                .expect("encode server certificate"),
+            stream.ssl().selected_alpn_protocol().map(<[u8]>::to_vec),
+        )
+    })
+    .await
+    .expect("join client")
+}
+
+#[cfg(feature = "openssl")]
+#[tokio::test]
+async fn listener_answers_tls_alpn_01_with_challenge_certificate() {
+    use crate::le::TlsAlpnCertificate;
+
+    let challenge = TlsAlpnCertificate::new("example.com", &[7; 32]).expect("build challenge");
+    let challenge_der = challenge.cert.to_der().expect("encode challenge");
+    let (addr, configured) = spawn_tls_alpn_listener(vec![("example.com", challenge)]).await;
+
+    let (cert, alpn) = tls12_handshake(addr, "example.com", b"\x0aacme-tls/1").await;
+    assert_eq!(cert, challenge_der);
+    assert_eq!(alpn.as_deref(), Some(&b"acme-tls/1"[..]));
+
+    let (cert, alpn) = tls12_handshake(addr, "example.com", b"\x08http/1.1").await;
+    assert_eq!(cert, configured);
+    assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+}
+
+#[cfg(feature = "openssl")]
+#[tokio::test]
+async fn listener_ignores_acme_tls_alpn_without_a_pending_challenge() {
+    use crate::le::TlsAlpnCertificate;
+
+    let challenge =
+        TlsAlpnCertificate::new("other.example.com", &[7; 32]).expect("build challenge");
+    let (addr, configured) = spawn_tls_alpn_listener(vec![("other.example.com", challenge)]).await;
+
+    let (cert, alpn) = tls12_handshake(addr, "example.com", b"\x0aacme-tls/1\x08http/1.1").await;
+    assert_eq!(cert, configured);
+    assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+
+    let (cert, alpn) = tls12_handshake(addr, "example.com", b"\x0aacme-tls/1").await;
+    assert_eq!(cert, configured);
+    assert_eq!(alpn, None);
+}
+
 #[test]
 fn compiled_router_deduplicates_shared_tls_listener() {
     let shared_tls = tls_identity("/tmp/shared.crt", "/tmp/shared.key");
@@ -445,6 +574,46 @@ fn select_listener_tls_falls_back_to_default() {
     );
 }
 
+#[test]
+fn select_listener_tls_falls_back_to_a_wildcard_of_the_parent() {
+    let key = ListenKey {
+        addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        port: 443,
+        ssl: true,
+    };
+    let tls = ListenerTlsConfig {
+        named: HashMap::from([
+            (
+                "*.example.com".into(),
+                tls_identity("/tmp/wildcard.crt", "/tmp/wildcard.key"),
+            ),
+            (
+                "www.example.com".into(),
+                tls_identity("/tmp/www.crt", "/tmp/www.key"),
+            ),
+        ]),
+        default: Some(tls_identity("/tmp/default.crt", "/tmp/default.key")),
+        ..ListenerTlsConfig::default()
+    };
+    let select = |server_name| select_listener_tls(&key, &tls, Some(server_name)).unwrap();
+
+    assert_eq!(
+        select("API.example.com"),
+        &tls_identity("/tmp/wildcard.crt", "/tmp/wildcard.key")
+    );
+    assert_eq!(
+        select("www.example.com"),
+        &tls_identity("/tmp/www.crt", "/tmp/www.key")
+    );
+    // A wildcard covers a single label.
+    for server_name in ["example.com", "v1.api.example.com"] {
+        assert_eq!(
+            select(server_name),
+            &tls_identity("/tmp/default.crt", "/tmp/default.key")
+        );
+    }
+}
+
 #[test]
 fn default_listener_tls_uses_first_named_when_default_missing() {
     let key = ListenKey {
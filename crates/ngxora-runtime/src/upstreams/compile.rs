@@ -1,17 +1,22 @@
 use super::types::{
     CompiledHealthCheck, CompiledLocation, CompiledMatcher, CompiledRouter, CompiledUpstreamGroup,
     CompiledUpstreamServer, HealthCheckType, HttpRuntimeOptions, ListenKey, ListenerProtocolConfig,
-    ListenerTlsConfig, ListenerTlsSettings, RouteTarget, ServerRoutes,
+    ListenerTlsConfig, ListenerTlsSettings, RouteTarget, ServerRoutes, StaticFallback, StaticFiles,
+    StaticRoot, StaticTryFiles,
 };
 use ngxora_compile::ir::{
-    DownstreamTlsOptions, Http, KeepaliveTimeout, Listen, Location, LocationDirective, PemSource,
-    ProxyPassTarget, Server, SslProvider, Switch, TlsIdentity, UpstreamBlock, UpstreamHealthCheck,
-    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamServer, UpstreamSslOptions,
-    UpstreamTimeouts,
+    AccessLog, AcmeIssuer, DEFAULT_ACME_CACHE_DIR, DEFAULT_LOG_FORMAT, DownstreamTlsOptions,
+    HealthCheckBodyMatch, Http, KeepaliveTimeout, Listen, Location, LocationDirective,
+    LocationMatcher, LogFormat, MAX_UPSTREAM_SERVER_WEIGHT, PemSource, ProxyPassTarget, Server,
+    SslProvider, Switch, TlsIdentity, TryFilesFallback, UpstreamBlock, UpstreamHealthCheck,
+    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamOutlierDetection, UpstreamRetry,
+    UpstreamSelectionPolicy, UpstreamServer, UpstreamSslOptions, UpstreamTimeouts,
 };
+use ngxora_compile::variables::Template;
+use regex::Regex;
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 impl CompiledRouter {
     pub fn from_http(http: &Http) -> Result<Self, String> {
@@ -22,6 +27,7 @@ impl CompiledRouter {
             );
         }
 
+        validate_log_formats(&http.log_formats)?;
         let mut router = Self {
             upstreams: compile_upstreams(&http.upstreams)?,
             http_options: HttpRuntimeOptions {
@@ -31,14 +37,19 @@ impl CompiledRouter {
                 keepalive_requests: http.keepalive_requests,
                 client_max_body_size: http.client_max_body_size,
                 proxy_cache_max_size: http.proxy_cache_max_size,
+                proxy_cache_store: http.proxy_cache_store.clone(),
                 tcp_nodelay: matches!(http.tcp_nodelay, Switch::On),
                 allow_connect_method_proxying: matches!(
                     http.allow_connect_method_proxying,
                     Switch::On
                 ),
                 h2c: matches!(http.h2c, Switch::On),
+                resolver: http.resolver.clone(),
+                metrics_label_limit: http.metrics_label_limit,
+                log_formats: http.log_formats.clone(),
+                access_logs: compile_access_logs(&http.access_logs, &http.log_formats)?,
             },
-            le_config: http.ssl_provider.clone(),
+            acme_issuers: http.acme_issuers.clone(),
             ..Self::default()
         };
         let mut next_route_id = 1;
@@ -51,16 +62,34 @@ impl CompiledRouter {
     }
 
     fn add_server(&mut self, server: &Server, next_route_id: &mut u64) -> Result<(), String> {
-        if matches!(server.tls, Some(SslProvider::LetsEncrypt)) && server.server_names.len() != 1 {
-            return Err(
-                "ssl listener with LetsEncrypt currently supports exactly one server_name; split aliases into separate server blocks or use a manual certificate"
-                    .into(),
-            );
+        if let Some(SslProvider::Acme(issuers)) = &server.tls {
+            if server.server_names.len() != 1 {
+                return Err(
+                    "ssl listener with ACME currently supports exactly one server_name; split aliases into separate server blocks or use a manual certificate"
+                        .into(),
+                );
+            }
+            if let Some(unknown) = issuers
+                .iter()
+                .find(|name| !self.acme_issuers.iter().any(|issuer| &issuer.name == *name))
+            {
+                return Err(format!("unknown ACME issuer `{unknown}`"));
+            }
         }
 
+        let formats = &self.http_options.log_formats;
         let routes = ServerRoutes {
-            locations: compile_locations(&server.locations, &self.upstreams, next_route_id)?,
+            server_name: server.server_names.first().cloned(),
+            locations: compile_locations(
+                &server.locations,
+                &self.upstreams,
+                &compile_access_logs(&server.access_logs, formats)?,
+                formats,
+                next_route_id,
+            )?,
+            request_id: server.request_id.clone(),
         };
+        validate_named_fallbacks(&routes)?;
 
         for listen in &server.listens {
             let listen_key = ListenKey::from(listen);
@@ -92,19 +121,23 @@ impl CompiledRouter {
                 if let Some(provider) = server.tls.as_ref() {
                     let tls_identity = match provider {
                         SslProvider::Custom(tls) => tls.clone(),
-                        SslProvider::LetsEncrypt => {
+                        SslProvider::Acme(issuers) => {
+                            // Certificates live in the primary issuer's cache
+                            // directory whichever issuer obtained them.
                             let cache_dir = self
-                                .le_config
-                                .as_ref()
-                                .and_then(|c| c.cache_dir.clone())
-                                .unwrap_or_else(|| PathBuf::from("/var/lib/ngxora/certs"));
+                                .acme_issuers
+                                .iter()
+                                .find(|issuer| issuers.first() == Some(&issuer.name))
+                                .map(AcmeIssuer::cache_dir_or_default)
+                                .unwrap_or_else(|| PathBuf::from(DEFAULT_ACME_CACHE_DIR));
                             // Use the first server_name as the primary domain.
                             let domain = server.server_names.first().cloned().unwrap_or_default();
+                            self.acme_certificates
+                                .insert(domain.to_ascii_lowercase(), issuers.clone());
+                            let domain_dir = cache_dir.join(acme_certificate_dir(&domain));
                             TlsIdentity {
-                                cert: PemSource::Path(
-                                    cache_dir.join(&domain).join("fullchain.pem"),
-                                ),
-                                key: PemSource::Path(cache_dir.join(&domain).join("privkey.pem")),
+                                cert: PemSource::Path(domain_dir.join("fullchain.pem")),
+                                key: PemSource::Path(domain_dir.join("privkey.pem")),
                             }
                         }
                     };
@@ -132,6 +165,8 @@ impl CompiledRouter {
         let config = ListenerProtocolConfig {
             http2: listen.http2,
             http2_only: listen.http2_only,
+            proxy_protocol: listen.proxy_protocol,
+            proxy_protocol_trusted: listen.proxy_protocol_trusted.clone(),
         };
         if let Some(current) = self.listener_protocols.get(key) {
             if current != &config {
@@ -174,6 +209,16 @@ impl CompiledRouter {
     }
 }
 
+/// Directory of `domain`'s certificate in an ACME cache directory. `*` is
+/// awkward in a path, so `*.example.com` is kept in `_wildcard.example.com`;
+/// `_` never appears in a host name, so this cannot clash with a real one.
+fn acme_certificate_dir(domain: &str) -> String {
+    match domain.strip_prefix("*.") {
+        Some(parent) => format!("_wildcard.{parent}"),
+        None => domain.to_string(),
+    }
+}
+
 fn normalize_upstream_name(name: &str) -> String {
     name.trim_end_matches('.').to_ascii_lowercase()
 }
@@ -188,10 +233,28 @@ fn compile_upstream_server(server: &UpstreamServer) -> Result<CompiledUpstreamSe
             server.host
         ));
     }
+    if !(1..=MAX_UPSTREAM_SERVER_WEIGHT).contains(&server.weight) {
+        return Err(format!(
+            "upstream server `{}:{}` weight must be between 1 and {MAX_UPSTREAM_SERVER_WEIGHT}",
+            server.host, server.port
+        ));
+    }
+    if server.resolve && server.host.parse::<IpAddr>().is_ok() {
+        return Err(format!(
+            "upstream server `{}:{}` uses `resolve` but is not a host name",
+            server.host, server.port
+        ));
+    }
 
     Ok(CompiledUpstreamServer {
         host: server.host.clone(),
         port: server.port,
+        weight: server.weight,
+        resolve: server.resolve,
+        max_conns: server.max_conns,
+        backup: server.backup,
+        down: server.down,
+        slow_start: server.slow_start,
     })
 }
 
@@ -217,6 +280,10 @@ fn compile_upstream_health_check(
             host,
             path,
             use_tls,
+            method,
+            headers,
+            statuses,
+            body,
         } => {
             if host.trim().is_empty() {
                 return Err("health_check http host cannot be empty".into());
@@ -229,16 +296,66 @@ fn compile_upstream_health_check(
                     "health_check path `{path}` must be an origin-form path starting with `/`"
                 ));
             }
+            http::Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("invalid health_check method `{method}`"))?;
+            for (name, value) in headers {
+                if name.eq_ignore_ascii_case("host") {
+                    return Err("health_check header Host is set with host".into());
+                }
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("invalid health_check header name `{name}`"))?;
+                http::HeaderValue::from_str(value)
+                    .map_err(|_| format!("invalid health_check header value for `{name}`"))?;
+            }
+            if statuses.is_empty() {
+                return Err("health_check status needs at least one status or range".into());
+            }
+            if let Some(range) = statuses
+                .iter()
+                .find(|range| range.is_empty() || *range.start() < 100 || *range.end() > 599)
+            {
+                return Err(format!(
+                    "invalid health_check status range {}-{}",
+                    range.start(),
+                    range.end()
+                ));
+            }
+            if let Some(HealthCheckBodyMatch::Regex(pattern)) = body {
+                Regex::new(pattern)
+                    .map_err(|err| format!("invalid health_check body regex `{pattern}`: {err}"))?;
+            }
             HealthCheckType::Http {
                 host: host.clone(),
                 path: path.clone(),
                 use_tls: *use_tls,
+                method: method.clone(),
+                headers: headers.clone(),
+                statuses: statuses.clone(),
+                body: body.clone(),
+            }
+        }
+        UpstreamHealthCheckType::Grpc {
+            service,
+            host,
+            use_tls,
+        } => {
+            if host.as_ref().is_some_and(|host| host.trim().is_empty()) {
+                return Err("health_check grpc host cannot be empty".into());
+            }
+            HealthCheckType::Grpc {
+                service: service.clone(),
+                host: host.clone(),
+                use_tls: *use_tls,
             }
         }
     };
+    if health_check.port == Some(0) {
+        return Err("health_check port must be greater than zero".into());
+    }
 
     Ok(CompiledHealthCheck {
         check_type,
+        port: health_check.port,
         timeout: health_check.timeout,
         interval: health_check.interval,
         consecutive_success: health_check.consecutive_success,
@@ -246,6 +363,28 @@ fn compile_upstream_health_check(
     })
 }
 
+fn compile_upstream_outlier_detection(
+    outlier: &UpstreamOutlierDetection,
+) -> Result<UpstreamOutlierDetection, String> {
+    if outlier.consecutive_failure == 0 {
+        return Err("outlier_detection consecutive_failure must be greater than zero".into());
+    }
+    if outlier.base_ejection_time.is_zero() {
+        return Err("outlier_detection base_ejection_time must be greater than zero".into());
+    }
+    if outlier.max_ejection_time < outlier.base_ejection_time {
+        return Err(
+            "outlier_detection max_ejection_time must not be shorter than base_ejection_time"
+                .into(),
+        );
+    }
+    if !(1..=100).contains(&outlier.max_ejection_percent) {
+        return Err("outlier_detection max_ejection_percent must be between 1 and 100".into());
+    }
+
+    Ok(*outlier)
+}
+
 fn compile_upstreams(
     upstreams: &[UpstreamBlock],
 ) -> Result<HashMap<String, CompiledUpstreamGroup>, String> {
@@ -262,10 +401,22 @@ fn compile_upstreams(
                 upstream.name
             ));
         }
+        // A ramping server would hand part of its keys to other servers.
+        if matches!(upstream.policy, UpstreamSelectionPolicy::Hash { .. })
+            && upstream
+                .servers
+                .iter()
+                .any(|server| server.slow_start.is_some())
+        {
+            return Err(format!(
+                "upstream `{}`: slow_start is not supported with hash",
+                upstream.name
+            ));
+        }
 
         let group = CompiledUpstreamGroup {
             name: upstream.name.clone(),
-            policy: upstream.policy,
+            policy: upstream.policy.clone(),
             servers: upstream
                 .servers
                 .iter()
@@ -276,6 +427,13 @@ fn compile_upstreams(
                 .as_ref()
                 .map(compile_upstream_health_check)
                 .transpose()?,
+            outlier_detection: upstream
+                .outlier_detection
+                .as_ref()
+                .map(compile_upstream_outlier_detection)
+                .transpose()?,
+            keepalive: upstream.keepalive,
+            queue: upstream.queue,
         };
 
         if compiled.insert(name.clone(), group).is_some() {
@@ -338,8 +496,81 @@ fn set_timeout_once(
     Ok(())
 }
 
+fn static_root_path(path: &str, directive: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    if !path.is_absolute() {
+        return Err(format!(
+            "{directive} path `{}` must be absolute",
+            path.display()
+        ));
+    }
+
+    Ok(path.to_path_buf())
+}
+
+fn compile_static_files(location: &Location) -> Result<StaticFiles, String> {
+    let mut root = None;
+    let mut index = None;
+    let mut try_files = None;
+
+    for directive in &location.directives {
+        match directive {
+            LocationDirective::Root(path) => {
+                root = Some(StaticRoot::Root(static_root_path(path, "root")?));
+            }
+            LocationDirective::Alias(path) => {
+                let prefix = match &location.matcher {
+                    LocationMatcher::Prefix(prefix)
+                    | LocationMatcher::PreferPrefix(prefix)
+                    | LocationMatcher::Exact(prefix) => prefix.clone(),
+                    LocationMatcher::Regex { .. } | LocationMatcher::Named(_) => {
+                        return Err(
+                            "alias is only supported in prefix and exact match locations".into(),
+                        );
+                    }
+                };
+                root = Some(StaticRoot::Alias {
+                    path: static_root_path(path, "alias")?,
+                    prefix,
+                });
+            }
+            LocationDirective::Index(_) if index.is_some() => {
+                return Err("index is duplicated in the same location".into());
+            }
+            LocationDirective::Index(files) => index = Some(files.clone()),
+            LocationDirective::TryFiles { files, fallback } => {
+                let compiled = StaticTryFiles {
+                    files: files
+                        .iter()
+                        .map(|file| Template::parse(file))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|err| format!("try_files: {err}"))?,
+                    fallback: match fallback {
+                        TryFilesFallback::Status(status) => StaticFallback::Status(*status),
+                        TryFilesFallback::Named(name) => StaticFallback::Named(name.clone()),
+                        TryFilesFallback::Uri(uri) => StaticFallback::Uri(
+                            Template::parse(uri).map_err(|err| format!("try_files: {err}"))?,
+                        ),
+                    },
+                };
+                if try_files.replace(compiled).is_some() {
+                    return Err("try_files is duplicated in the same location".into());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(StaticFiles {
+        root: root.ok_or_else(|| "static location requires root or alias".to_string())?,
+        index: index.unwrap_or_else(|| vec!["index.html".into()]),
+        try_files,
+    })
+}
+
 fn route_target_from_directive(
     directive: &LocationDirective,
+    location: &Location,
     upstreams: &HashMap<String, CompiledUpstreamGroup>,
 ) -> Result<Option<RouteTarget>, String> {
     match directive {
@@ -385,9 +616,13 @@ fn route_target_from_directive(
 
         LocationDirective::Return { status, location } => Ok(Some(RouteTarget::Return {
             status: *status,
-            location: location.clone(),
+            location: Template::parse(location).map_err(|err| format!("return: {err}"))?,
         })),
 
+        LocationDirective::Root(_) | LocationDirective::Alias(_) => {
+            Ok(Some(RouteTarget::Static(compile_static_files(location)?)))
+        }
+
         _ => Ok(None),
     }
 }
@@ -400,7 +635,7 @@ fn route_target(
         .directives
         .iter()
         .find_map(
-            |directive| match route_target_from_directive(directive, upstreams) {
+            |directive| match route_target_from_directive(directive, location, upstreams) {
                 Ok(Some(target)) => Some(Ok(target)),
                 Ok(None) => None,
                 Err(err) => Some(Err(err)),
@@ -430,6 +665,36 @@ fn compile_upstream_timeouts(location: &Location) -> Result<UpstreamTimeouts, St
     Ok(timeouts)
 }
 
+fn compile_upstream_retry(location: &Location) -> Result<UpstreamRetry, String> {
+    let mut next_upstream = None;
+    let mut tries = None;
+    let mut timeout = None;
+
+    for directive in &location.directives {
+        match directive {
+            LocationDirective::ProxyNextUpstream(_) if next_upstream.is_some() => {
+                return Err("proxy_next_upstream is duplicated in the same location".into());
+            }
+            LocationDirective::ProxyNextUpstream(value) => next_upstream = Some(*value),
+            LocationDirective::ProxyNextUpstreamTries(_) if tries.is_some() => {
+                return Err("proxy_next_upstream_tries is duplicated in the same location".into());
+            }
+            LocationDirective::ProxyNextUpstreamTries(value) => tries = Some(*value),
+            LocationDirective::ProxyNextUpstreamTimeout(value) => {
+                set_timeout_once(&mut timeout, *value, "proxy_next_upstream_timeout")?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(UpstreamRetry {
+        next_upstream: next_upstream.unwrap_or_default(),
+        tries: tries.unwrap_or(0),
+        // Like nginx, a zero timeout means no limit.
+        timeout: timeout.filter(|timeout| !timeout.is_zero()),
+    })
+}
+
 fn compile_upstream_protocol(
     location: &Location,
     target: &RouteTarget,
@@ -437,17 +702,17 @@ fn compile_upstream_protocol(
     let mut protocol = None;
 
     for directive in &location.directives {
-        if let LocationDirective::ProxyUpstreamProtocol(value) = directive {
-            if protocol.replace(*value).is_some() {
-                return Err("proxy_upstream_protocol is duplicated in the same location".into());
-            }
+        if let LocationDirective::ProxyUpstreamProtocol(value) = directive
+            && protocol.replace(*value).is_some()
+        {
+            return Err("proxy_upstream_protocol is duplicated in the same location".into());
         }
     }
 
     if let Some(protocol) = protocol {
         let target_uses_tls = match target {
             RouteTarget::ProxyPass { tls, .. } | RouteTarget::UpstreamGroup { tls, .. } => *tls,
-            RouteTarget::Return { .. } => return Ok(None),
+            RouteTarget::Return { .. } | RouteTarget::Static(_) => return Ok(None),
         };
 
         match protocol {
@@ -521,43 +786,104 @@ fn compile_upstream_ssl_options(location: &Location) -> Result<UpstreamSslOption
     Ok(options)
 }
 
+fn validate_log_formats(formats: &[LogFormat]) -> Result<(), String> {
+    for (index, format) in formats.iter().enumerate() {
+        if format.name == DEFAULT_LOG_FORMAT {
+            return Err(format!(
+                "log_format `{DEFAULT_LOG_FORMAT}` is built in and cannot be redefined"
+            ));
+        }
+        if format.fields.is_empty() {
+            return Err(format!("log_format `{}` has no fields", format.name));
+        }
+        if formats[..index]
+            .iter()
+            .any(|other| other.name == format.name)
+        {
+            return Err(format!("log_format `{}` is duplicated", format.name));
+        }
+    }
+    Ok(())
+}
+
+// `off` has to stand alone, and every named format must exist.
+fn compile_access_logs(
+    logs: &[AccessLog],
+    formats: &[LogFormat],
+) -> Result<Vec<AccessLog>, String> {
+    if logs.len() > 1 && logs.contains(&AccessLog::Off) {
+        return Err("access_log off cannot be combined with other access logs".into());
+    }
+    for log in logs {
+        if let AccessLog::To(target) = log {
+            let name = target.format_name();
+            if name != DEFAULT_LOG_FORMAT && !formats.iter().any(|format| format.name == name) {
+                return Err(format!("access_log uses unknown log_format `{name}`"));
+            }
+        }
+    }
+    Ok(logs.to_vec())
+}
+
 fn compile_location(
     location: &Location,
     upstreams: &HashMap<String, CompiledUpstreamGroup>,
+    server_access_logs: &[AccessLog],
+    formats: &[LogFormat],
     next_route_id: &mut u64,
 ) -> Result<Option<CompiledLocation>, String> {
     let mut action_count = 0;
+    let mut file_option = None;
     for directive in &location.directives {
         match directive {
-            LocationDirective::ProxyPass(_) | LocationDirective::Return { .. } => {
+            LocationDirective::ProxyPass(_)
+            | LocationDirective::Return { .. }
+            | LocationDirective::Root(_)
+            | LocationDirective::Alias(_) => {
                 action_count += 1;
             }
-            LocationDirective::Root(_) => return Err("root is not supported at runtime".into()),
-            LocationDirective::TryFiles(_) => {
-                return Err("try_files is not supported at runtime".into());
-            }
+            LocationDirective::Index(_) => file_option = Some("index"),
+            LocationDirective::TryFiles { .. } => file_option = Some("try_files"),
             _ => {}
         }
     }
     if action_count != 1 {
-        return Err("location must contain exactly one proxy_pass or return directive".into());
+        return Err(
+            "location must contain exactly one proxy_pass, return, root or alias directive".into(),
+        );
     }
 
     let Some(target) = route_target(location, upstreams)? else {
         return Ok(None);
     };
+    if let RouteTarget::Static(_) = &target {
+        if location.cache.is_some() {
+            return Err("proxy_cache is not supported in root or alias locations".into());
+        }
+    } else if let Some(directive) = file_option {
+        return Err(format!("{directive} requires root or alias"));
+    }
     let upstream_protocol = compile_upstream_protocol(location, &target)?;
 
     let compiled = CompiledLocation {
         route_id: *next_route_id,
+        name: location
+            .directives
+            .iter()
+            .find_map(|directive| match directive {
+                LocationDirective::RouteName(name) => Some(name.clone()),
+                _ => None,
+            }),
         matcher: CompiledMatcher::try_from(&location.matcher)?,
         access_rules: location.access_rules.clone(),
         target,
         upstream_timeouts: compile_upstream_timeouts(location)?,
+        upstream_retry: compile_upstream_retry(location)?,
         upstream_protocol,
         upstream_ssl_options: compile_upstream_ssl_options(location)?,
         plugins: location.plugins.clone(),
         cache: location.cache.clone(),
+        access_logs: compile_location_access_logs(location, server_access_logs, formats)?,
     };
     *next_route_id += 1;
     Ok(Some(compiled))
@@ -565,14 +891,43 @@ fn compile_location(
 
 // Only locations with an actionable upstream target are kept. Regex validation
 // also happens here, so broken snapshots fail before they are applied.
+fn compile_location_access_logs(
+    location: &Location,
+    server_access_logs: &[AccessLog],
+    formats: &[LogFormat],
+) -> Result<Vec<AccessLog>, String> {
+    let logs: Vec<AccessLog> = location
+        .directives
+        .iter()
+        .filter_map(|directive| match directive {
+            LocationDirective::AccessLog(log) => Some(log.clone()),
+            _ => None,
+        })
+        .collect();
+    if logs.is_empty() {
+        return Ok(server_access_logs.to_vec());
+    }
+    compile_access_logs(&logs, formats)
+}
+
 fn compile_locations(
     locations: &[Location],
     upstreams: &HashMap<String, CompiledUpstreamGroup>,
+    server_access_logs: &[AccessLog],
+    formats: &[LogFormat],
     next_route_id: &mut u64,
 ) -> Result<Vec<CompiledLocation>, String> {
     locations
         .iter()
-        .map(|location| compile_location(location, upstreams, next_route_id))
+        .map(|location| {
+            compile_location(
+                location,
+                upstreams,
+                server_access_logs,
+                formats,
+                next_route_id,
+            )
+        })
         .filter_map(|result| match result {
             Ok(Some(location)) => Some(Ok(location)),
             Ok(None) => None,
@@ -581,6 +936,35 @@ fn compile_locations(
         .collect()
 }
 
+// `try_files ... @name` resolves within the same server block at request time,
+// so a missing target is rejected while the snapshot is built.
+fn validate_named_fallbacks(routes: &ServerRoutes) -> Result<(), String> {
+    for location in &routes.locations {
+        let RouteTarget::Static(StaticFiles {
+            try_files:
+                Some(StaticTryFiles {
+                    fallback: StaticFallback::Named(name),
+                    ..
+                }),
+            ..
+        }) = &location.target
+        else {
+            continue;
+        };
+
+        let exists = routes.locations.iter().any(
+            |candidate| matches!(&candidate.matcher, CompiledMatcher::Named(named) if named == name),
+        );
+        if !exists {
+            return Err(format!(
+                "try_files references unknown named location `@{name}`"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 pub(crate) fn downstream_keepalive_timeout_secs(timeout: &KeepaliveTimeout) -> Option<u64> {
     match timeout {
         KeepaliveTimeout::Off => None,
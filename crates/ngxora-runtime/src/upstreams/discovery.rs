@@ -0,0 +1,354 @@
+// Backend sets for upstream groups. Static servers map to one backend each;
+// `server <name> resolve;` entries expand into one backend per address the
+// name currently resolves to, refreshed as the DNS records expire.
+
+use super::outlier::OutlierDetector;
+use super::selection::{ActiveRequests, ring_addr};
+use super::slow_start::SlowStart;
+use super::types::CompiledUpstreamServer;
+use async_trait::async_trait;
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::{ResolveError, TokioResolver};
+use ngxora_compile::ir::DnsResolver;
+use pingora::lb::Backend;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::protocols::l4::socket::SocketAddr as PingoraSocketAddr;
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Records with a shorter (or zero) TTL are still only re-resolved this often.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Retry delay after a lookup failed without an answer from DNS.
+const FAILED_LOOKUP_RETRY: Duration = Duration::from_secs(5);
+
+/// Position of a backend in its group, in configuration order.
+#[derive(Clone, Copy)]
+pub(super) struct BackendIndex(pub(super) usize);
+
+/// The `resolve` server name a backend's address came from.
+#[derive(Debug, Clone)]
+pub(super) struct ResolvedFrom(pub(super) String);
+
+/// Latest addresses per `resolve` host name, shared by a group's resolver and
+/// its discovery.
+type ResolvedAddrs = Arc<RwLock<HashMap<String, Vec<IpAddr>>>>;
+
+pub(super) struct UpstreamDiscovery {
+    servers: Vec<CompiledUpstreamServer>,
+    ring: bool,
+    outlier: Option<Arc<OutlierDetector>>,
+    slow_start: Option<Arc<SlowStart>>,
+    resolved: ResolvedAddrs,
+}
+
+impl UpstreamDiscovery {
+    pub(super) fn new(
+        servers: &[CompiledUpstreamServer],
+        ring: bool,
+        outlier: Option<Arc<OutlierDetector>>,
+        slow_start: Option<Arc<SlowStart>>,
+        resolved: ResolvedAddrs,
+    ) -> Self {
+        Self {
+            servers: servers.to_vec(),
+            ring,
+            outlier,
+            slow_start,
+            resolved,
+        }
+    }
+
+    // Backends get synthetic addresses because Pingora only needs them as
+    // identities; the real `host:port` is resolved per peer. Identities must
+    // survive a change in the resolved set, so static servers are keyed by
+    // their position and resolved addresses by the address itself.
+    // Consistent hashing places backends on its ring by address, so there
+    // every backend gets an address derived from the server.
+    pub(super) fn backends(&self) -> BTreeSet<Backend> {
+        let resolved = self
+            .resolved
+            .read()
+            .expect("resolved addresses lock poisoned");
+        let mut occurrences = HashMap::<CompiledUpstreamServer, u16>::new();
+        let mut backends = BTreeSet::new();
+        let mut index = 0;
+
+        for (position, server) in self.servers.iter().enumerate() {
+            let expanded: Vec<(CompiledUpstreamServer, Option<ResolvedFrom>)> = if server.resolve {
+                resolved
+                    .get(&server.host)
+                    .into_iter()
+                    .flatten()
+                    .map(|ip| {
+                        let concrete = CompiledUpstreamServer {
+                            host: ip.to_string(),
+                            port: server.port,
+                            weight: server.weight,
+                            resolve: false,
+                            max_conns: server.max_conns,
+                            backup: server.backup,
+                            down: server.down,
+                            slow_start: server.slow_start,
+                        };
+                        (concrete, Some(ResolvedFrom(server.host.clone())))
+                    })
+                    .collect()
+            } else {
+                vec![(server.clone(), None)]
+            };
+
+            for (concrete, resolved_from) in expanded {
+                let addr = if self.ring || resolved_from.is_some() {
+                    let occurrence = occurrences.entry(concrete.clone()).or_default();
+                    *occurrence += 1;
+                    ring_addr(&concrete, *occurrence - 1)
+                } else {
+                    synthetic_backend_addr(position)
+                };
+
+                let mut ext = http::Extensions::new();
+                ext.insert(BackendIndex(index));
+                ext.insert(ActiveRequests::default());
+                if let Some(state) = self
+                    .outlier
+                    .as_ref()
+                    .map(|outlier| outlier.backend(&concrete))
+                {
+                    ext.insert(state);
+                }
+                if let Some(ramp) = self
+                    .slow_start
+                    .as_ref()
+                    .and_then(|slow_start| slow_start.backend(&concrete))
+                {
+                    ext.insert(ramp);
+                }
+                if let Some(resolved_from) = resolved_from {
+                    ext.insert(resolved_from);
+                }
+                let weight = concrete.weight as usize;
+                ext.insert(concrete);
+                index += 1;
+
+                backends.insert(Backend {
+                    addr: PingoraSocketAddr::Inet(addr),
+                    weight,
+                    ext,
+                });
+            }
+        }
+
+        if let Some(outlier) = &self.outlier {
+            outlier.retain(
+                backends
+                    .iter()
+                    .filter_map(|backend| backend.ext.get::<CompiledUpstreamServer>()),
+            );
+        }
+        if let Some(slow_start) = &self.slow_start {
+            slow_start.retain(
+                backends
+                    .iter()
+                    .filter_map(|backend| backend.ext.get::<CompiledUpstreamServer>()),
+            );
+        }
+        backends
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for UpstreamDiscovery {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        Ok((self.backends(), HashMap::new()))
+    }
+}
+
+fn synthetic_backend_addr(index: usize) -> SocketAddr {
+    let index = u64::try_from(index).unwrap_or(u64::MAX);
+    let ip = Ipv6Addr::new(
+        0xfd00,
+        0,
+        0,
+        0,
+        ((index >> 32) & 0xffff) as u16,
+        ((index >> 16) & 0xffff) as u16,
+        (index & 0xffff) as u16,
+        1,
+    );
+
+    SocketAddr::V6(SocketAddrV6::new(ip, 1, 0, 0))
+}
+
+/// Keeps the addresses of a group's `resolve` servers current.
+pub(super) struct UpstreamResolver {
+    resolver: TokioResolver,
+    /// `resolver ... valid=`; replaces the record TTLs when set.
+    valid: Option<Duration>,
+    upstream: String,
+    names: Vec<String>,
+    resolved: ResolvedAddrs,
+    next_run_at: Mutex<Instant>,
+}
+
+impl UpstreamResolver {
+    /// `None` when the group has no `resolve` servers.
+    pub(super) fn new(
+        upstream: &str,
+        servers: &[CompiledUpstreamServer],
+        config: Option<&DnsResolver>,
+    ) -> Result<Option<Self>, String> {
+        let names: BTreeSet<String> = servers
+            .iter()
+            .filter(|server| server.resolve)
+            .map(|server| server.host.clone())
+            .collect();
+        if names.is_empty() {
+            return Ok(None);
+        }
+
+        let builder = match config {
+            Some(config) => {
+                let name_servers: Vec<_> = config
+                    .addrs
+                    .iter()
+                    .flat_map(|addr| {
+                        [
+                            NameServerConfig::new(*addr, Protocol::Udp),
+                            NameServerConfig::new(*addr, Protocol::Tcp),
+                        ]
+                    })
+                    .collect();
+                TokioResolver::builder_with_config(
+                    ResolverConfig::from_parts(None, Vec::new(), name_servers),
+                    TokioConnectionProvider::default(),
+                )
+            }
+            None => TokioResolver::builder_tokio().map_err(|err| {
+                format!("upstream `{upstream}`: failed to read the system DNS configuration: {err}")
+            })?,
+        };
+        let mut options = ResolverOpts::default();
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // Refreshes are scheduled from the TTLs (or `valid=`) already, and a
+        // cached answer would outlive a shorter `valid=`.
+        options.cache_size = 0;
+
+        Ok(Some(Self {
+            resolver: builder.with_options(options).build(),
+            valid: config.and_then(|config| config.valid),
+            upstream: upstream.to_string(),
+            names: names.into_iter().collect(),
+            resolved: ResolvedAddrs::default(),
+            next_run_at: Mutex::new(Instant::now()),
+        }))
+    }
+
+    pub(super) fn resolved(&self) -> ResolvedAddrs {
+        Arc::clone(&self.resolved)
+    }
+
+    /// Takes over the addresses and refresh schedule of the same group in the
+    /// previous snapshot, so an apply does not empty `resolve` servers until
+    /// the next lookup.
+    pub(super) fn adopt(&self, previous: &Self) {
+        let previous_resolved = previous
+            .resolved
+            .read()
+            .expect("resolved addresses lock poisoned");
+        let mut resolved = self
+            .resolved
+            .write()
+            .expect("resolved addresses lock poisoned");
+        for name in &self.names {
+            if let Some(addrs) = previous_resolved.get(name) {
+                resolved.insert(name.clone(), addrs.clone());
+            }
+        }
+        if self.names.iter().all(|name| resolved.contains_key(name)) {
+            *self.next_run_at.lock().expect("resolver lock poisoned") =
+                *previous.next_run_at.lock().expect("resolver lock poisoned");
+        }
+    }
+
+    /// Re-resolves every name once due. Returns whether any address set
+    /// changed, and when to run next.
+    pub(super) async fn run_due(&self, now: Instant) -> (bool, Instant) {
+        {
+            let next_run_at = self.next_run_at.lock().expect("resolver lock poisoned");
+            if *next_run_at > now {
+                return (false, *next_run_at);
+            }
+        }
+
+        let lookups = futures::future::join_all(
+            self.names
+                .iter()
+                .map(|name| async move { (name, self.resolve(name).await) }),
+        )
+        .await;
+
+        let retry = now + self.valid.unwrap_or(FAILED_LOOKUP_RETRY);
+        let mut next_run: Option<Instant> = None;
+        let mut changed = false;
+        {
+            let mut resolved = self
+                .resolved
+                .write()
+                .expect("resolved addresses lock poisoned");
+            for (name, lookup) in lookups {
+                let refresh_at = match &lookup {
+                    Ok((_, valid_until)) => *valid_until,
+                    Err(_) => retry,
+                };
+                next_run = Some(next_run.map_or(refresh_at, |next| next.min(refresh_at)));
+                let addrs = match lookup {
+                    Ok((addrs, _)) => addrs,
+                    // The name exists no more, or has no addresses left.
+                    Err(err) if err.is_no_records_found() => {
+                        log::warn!(
+                            "upstream `{}`: `{name}` did not resolve to any address: {err}",
+                            self.upstream
+                        );
+                        Vec::new()
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "upstream `{}`: failed to resolve `{name}`, keeping previous addresses: {err}",
+                            self.upstream
+                        );
+                        continue;
+                    }
+                };
+                if resolved.get(name) != Some(&addrs) {
+                    log::info!(
+                        "upstream `{}`: `{name}` resolves to {addrs:?}",
+                        self.upstream
+                    );
+                    resolved.insert(name.clone(), addrs);
+                    changed = true;
+                }
+            }
+        }
+
+        let next_run = next_run.unwrap_or(retry).max(now + MIN_REFRESH_INTERVAL);
+        *self.next_run_at.lock().expect("resolver lock poisoned") = next_run;
+        (changed, next_run)
+    }
+
+    async fn resolve(&self, name: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+        let lookup = self.resolver.lookup_ip(name).await?;
+        let mut addrs: Vec<IpAddr> = lookup.iter().collect();
+        addrs.sort();
+        addrs.dedup();
+        let valid_until = match self.valid {
+            Some(valid) => Instant::now() + valid,
+            None => Instant::from_std(lookup.valid_until()),
+        };
+        Ok((addrs, valid_until))
+    }
+}
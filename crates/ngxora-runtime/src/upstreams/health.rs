@@ -1,11 +1,28 @@
 use super::types::{CompiledHealthCheck, CompiledUpstreamServer, HealthCheckType};
 use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use ngxora_compile::ir::HealthCheckBodyMatch;
 use pingora::connectors::{TransportConnector, http::Connector as HttpConnector};
 use pingora::http::RequestHeader;
 use pingora::lb::Backend;
 use pingora::lb::health_check::HealthCheck;
+use pingora::protocols::http::client::HttpSession;
 use pingora::upstreams::peer::HttpPeer;
+use prost::Message;
+use regex::Regex;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+
+pub(crate) mod grpc_health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+use grpc_health::{HealthCheckRequest, HealthCheckResponse, health_check_response::ServingStatus};
+
+/// How much of a response body is read to match `body` against.
+const MAX_HEALTH_CHECK_BODY: usize = 64 * 1024;
+
+const GRPC_HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
 
 impl CompiledHealthCheck {
     pub fn build(&self) -> Result<Box<dyn HealthCheck + Send + Sync + 'static>, String> {
@@ -14,33 +31,88 @@ impl CompiledHealthCheck {
                 consecutive_success: self.consecutive_success,
                 consecutive_failure: self.consecutive_failure,
                 timeout: self.timeout,
+                port: self.port,
                 connector: TransportConnector::new(None),
             }))),
             HealthCheckType::Http {
                 host,
                 path,
                 use_tls,
+                method,
+                headers,
+                statuses,
+                body,
             } => {
                 let uri = path
                     .parse::<http::Uri>()
                     .map_err(|err| format!("invalid health_check path `{path}`: {err}"))?;
-                let mut request =
-                    RequestHeader::build("GET", path.as_bytes(), None).map_err(|err| {
+                let mut request = RequestHeader::build(method.as_str(), path.as_bytes(), None)
+                    .map_err(|err| {
                         format!("failed to build health_check request for `{path}`: {err}")
                     })?;
                 request.set_uri(uri);
                 request
                     .insert_header("Host", host.as_str())
                     .map_err(|err| format!("failed to build health_check host header: {err}"))?;
+                for (name, value) in headers {
+                    request
+                        .append_header(name.clone(), value.as_str())
+                        .map_err(|err| {
+                            format!("failed to build health_check header `{name}`: {err}")
+                        })?;
+                }
+                let body = match body {
+                    Some(HealthCheckBodyMatch::Contains(text)) => {
+                        Some(BodyMatcher::Contains(text.clone()))
+                    }
+                    Some(HealthCheckBodyMatch::Regex(pattern)) => {
+                        Some(BodyMatcher::Regex(Regex::new(pattern).map_err(|err| {
+                            format!("invalid health_check body regex `{pattern}`: {err}")
+                        })?))
+                    }
+                    None => None,
+                };
 
                 Ok(Box::new(NgxoraHealthCheck::Http(Box::new(
                     NgxoraHttpHealthCheck {
                         consecutive_success: self.consecutive_success,
                         consecutive_failure: self.consecutive_failure,
                         timeout: self.timeout,
+                        port: self.port,
                         host: host.clone(),
                         use_tls: *use_tls,
                         request,
+                        statuses: statuses.clone(),
+                        body,
+                        connector: HttpConnector::new(None),
+                    },
+                ))))
+            }
+            HealthCheckType::Grpc {
+                service,
+                host,
+                use_tls,
+            } => {
+                let mut body = BytesMut::new();
+                let message = HealthCheckRequest {
+                    service: service.clone(),
+                };
+                body.put_u8(0);
+                body.put_u32(message.encoded_len() as u32);
+                message
+                    .encode(&mut body)
+                    .map_err(|err| format!("failed to encode grpc health check request: {err}"))?;
+
+                Ok(Box::new(NgxoraHealthCheck::Grpc(Box::new(
+                    NgxoraGrpcHealthCheck {
+                        consecutive_success: self.consecutive_success,
+                        consecutive_failure: self.consecutive_failure,
+                        timeout: self.timeout,
+                        port: self.port,
+                        service: service.clone(),
+                        host: host.clone(),
+                        use_tls: *use_tls,
+                        body: body.freeze(),
                         connector: HttpConnector::new(None),
                     },
                 ))))
@@ -49,10 +121,25 @@ impl CompiledHealthCheck {
     }
 }
 
+enum BodyMatcher {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl BodyMatcher {
+    fn matches(&self, body: &str) -> bool {
+        match self {
+            Self::Contains(text) => body.contains(text.as_str()),
+            Self::Regex(regex) => regex.is_match(body),
+        }
+    }
+}
+
 struct NgxoraTcpHealthCheck {
     consecutive_success: usize,
     consecutive_failure: usize,
     timeout: std::time::Duration,
+    port: Option<u16>,
     connector: TransportConnector,
 }
 
@@ -60,15 +147,36 @@ struct NgxoraHttpHealthCheck {
     consecutive_success: usize,
     consecutive_failure: usize,
     timeout: std::time::Duration,
+    port: Option<u16>,
     host: String,
     use_tls: bool,
     request: RequestHeader,
+    statuses: Vec<RangeInclusive<u16>>,
+    body: Option<BodyMatcher>,
+    connector: HttpConnector<()>,
+}
+
+struct NgxoraGrpcHealthCheck {
+    consecutive_success: usize,
+    consecutive_failure: usize,
+    timeout: std::time::Duration,
+    port: Option<u16>,
+    service: String,
+    host: Option<String>,
+    use_tls: bool,
+    /// Length-prefixed `HealthCheckRequest` message.
+    body: Bytes,
     connector: HttpConnector<()>,
 }
 
 enum NgxoraHealthCheck {
     Tcp(NgxoraTcpHealthCheck),
     Http(Box<NgxoraHttpHealthCheck>),
+    Grpc(Box<NgxoraGrpcHealthCheck>),
+}
+
+fn health_check_error(message: String) -> Box<pingora::Error> {
+    pingora::Error::explain(pingora::ErrorType::InternalError, message)
 }
 
 fn backend_health_server(target: &Backend) -> pingora::Result<&CompiledUpstreamServer> {
@@ -80,8 +188,11 @@ fn backend_health_server(target: &Backend) -> pingora::Result<&CompiledUpstreamS
     })
 }
 
-async fn resolve_health_check_addr(server: &CompiledUpstreamServer) -> pingora::Result<SocketAddr> {
-    tokio::net::lookup_host((server.host.as_str(), server.port))
+async fn resolve_health_check_addr(
+    server: &CompiledUpstreamServer,
+    port: Option<u16>,
+) -> pingora::Result<SocketAddr> {
+    tokio::net::lookup_host((server.host.as_str(), port.unwrap_or(server.port)))
         .await
         .map_err(|err| {
             pingora::Error::explain(
@@ -104,7 +215,7 @@ async fn resolve_health_check_addr(server: &CompiledUpstreamServer) -> pingora::
 impl NgxoraTcpHealthCheck {
     async fn check_backend(&self, target: &Backend) -> pingora::Result<()> {
         let server = backend_health_server(target)?;
-        let addr = resolve_health_check_addr(server).await?;
+        let addr = resolve_health_check_addr(server, self.port).await?;
         let mut peer = HttpPeer::new(addr, false, String::new());
         peer.options.connection_timeout = Some(self.timeout);
         self.connector.get_stream(&peer).await.map(|_| ())
@@ -120,7 +231,7 @@ impl NgxoraTcpHealthCheck {
 impl NgxoraHttpHealthCheck {
     async fn check_backend(&self, target: &Backend) -> pingora::Result<()> {
         let server = backend_health_server(target)?;
-        let addr = resolve_health_check_addr(server).await?;
+        let addr = resolve_health_check_addr(server, self.port).await?;
         let sni = if self.use_tls {
             self.host.clone()
         } else {
@@ -144,17 +255,30 @@ impl NgxoraHttpHealthCheck {
                 "health check response header is missing after read",
             )
         })?;
-        if response.status != 200 {
-            return Err(pingora::Error::explain(
-                pingora::ErrorType::InternalError,
-                format!(
-                    "http health check to {} returned status {}",
-                    server, response.status
-                ),
-            ));
+        let status = response.status.as_u16();
+        if !self.statuses.iter().any(|range| range.contains(&status)) {
+            return Err(health_check_error(format!(
+                "http health check to {server} returned status {status}"
+            )));
         }
 
-        while session.read_response_body().await?.is_some() {}
+        let Some(matcher) = &self.body else {
+            while session.read_response_body().await?.is_some() {}
+            return Ok(());
+        };
+        let mut body = Vec::new();
+        while body.len() < MAX_HEALTH_CHECK_BODY {
+            let Some(chunk) = session.read_response_body().await? else {
+                break;
+            };
+            body.extend_from_slice(&chunk);
+        }
+        body.truncate(MAX_HEALTH_CHECK_BODY);
+        if !matcher.matches(&String::from_utf8_lossy(&body)) {
+            return Err(health_check_error(format!(
+                "http health check to {server} returned a body that does not match"
+            )));
+        }
 
         Ok(())
     }
@@ -164,7 +288,7 @@ impl NgxoraHttpHealthCheck {
             .map(|server| {
                 format!(
                     "{} via {}://{}{}",
-                    server,
+                    health_check_target(server, self.port),
                     if self.use_tls { "https" } else { "http" },
                     self.host,
                     self.request.uri
@@ -174,6 +298,123 @@ impl NgxoraHttpHealthCheck {
     }
 }
 
+impl NgxoraGrpcHealthCheck {
+    async fn check_backend(&self, target: &Backend) -> pingora::Result<()> {
+        let server = backend_health_server(target)?;
+        let addr = resolve_health_check_addr(server, self.port).await?;
+        let authority = self.host.as_deref().unwrap_or(&server.host);
+        let sni = if self.use_tls {
+            authority.to_string()
+        } else {
+            String::new()
+        };
+        let mut peer = HttpPeer::new(addr, self.use_tls, sni);
+        peer.options.connection_timeout = Some(self.timeout);
+        peer.options.read_timeout = Some(self.timeout);
+        peer.options.set_http_version(2, 2);
+
+        let mut request = RequestHeader::build("POST", GRPC_HEALTH_CHECK_PATH.as_bytes(), None)?;
+        request.insert_header("Host", authority)?;
+        request.insert_header("Content-Type", "application/grpc")?;
+        request.insert_header("TE", "trailers")?;
+
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+        session.set_read_timeout(Some(self.timeout));
+        let HttpSession::H2(h2) = &mut session else {
+            return Err(health_check_error(format!(
+                "grpc health check to {server} did not negotiate HTTP/2"
+            )));
+        };
+        h2.write_request_header(Box::new(request), false)?;
+        h2.write_request_body(self.body.clone(), true).await?;
+        h2.read_response_header().await?;
+
+        let response = h2.response_header().ok_or_else(|| {
+            health_check_error("health check response header is missing after read".into())
+        })?;
+        if response.status != 200 {
+            return Err(health_check_error(format!(
+                "grpc health check to {server} returned HTTP status {}",
+                response.status
+            )));
+        }
+        // A trailers-only response carries grpc-status with the headers.
+        let mut grpc_status = response.headers.get("grpc-status").cloned();
+
+        let mut body = Vec::new();
+        while let Some(chunk) = h2.read_response_body().await? {
+            if body.len() < MAX_HEALTH_CHECK_BODY {
+                body.extend_from_slice(&chunk);
+            }
+        }
+        if grpc_status.is_none() {
+            grpc_status = h2
+                .read_trailers()
+                .await?
+                .and_then(|trailers| trailers.get("grpc-status").cloned());
+        }
+        match grpc_status.as_ref().map(|status| status.as_bytes()) {
+            Some(b"0") => {}
+            Some(status) => {
+                return Err(health_check_error(format!(
+                    "grpc health check to {server} failed with grpc-status {}",
+                    String::from_utf8_lossy(status)
+                )));
+            }
+            None => {
+                return Err(health_check_error(format!(
+                    "grpc health check to {server} returned no grpc-status"
+                )));
+            }
+        }
+
+        let status = decode_grpc_health_response(&body)
+            .ok_or_else(|| {
+                health_check_error(format!(
+                    "grpc health check to {server} returned a malformed response"
+                ))
+            })?
+            .status();
+        if status != ServingStatus::Serving {
+            return Err(health_check_error(format!(
+                "grpc health check to {server} reported {}",
+                status.as_str_name()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn backend_summary(&self, target: &Backend) -> String {
+        backend_health_server(target)
+            .map(|server| {
+                format!(
+                    "{} via grpc health service `{}`",
+                    health_check_target(server, self.port),
+                    self.service
+                )
+            })
+            .unwrap_or_else(|_| format!("{target:?}"))
+    }
+}
+
+/// Decodes one uncompressed, length-prefixed gRPC message.
+fn decode_grpc_health_response(body: &[u8]) -> Option<HealthCheckResponse> {
+    let (&[compressed, a, b, c, d], message) = body.split_first_chunk::<5>()?;
+    let len = u32::from_be_bytes([a, b, c, d]) as usize;
+    if compressed != 0 || message.len() < len {
+        return None;
+    }
+    HealthCheckResponse::decode(&message[..len]).ok()
+}
+
+fn health_check_target(server: &CompiledUpstreamServer, port: Option<u16>) -> String {
+    match port {
+        Some(port) if port != server.port => format!("{server} (port {port})"),
+        _ => server.to_string(),
+    }
+}
+
 #[async_trait]
 impl HealthCheck for NgxoraHealthCheck {
     fn health_threshold(&self, success: bool) -> usize {
@@ -192,6 +433,13 @@ impl HealthCheck for NgxoraHealthCheck {
                     check.consecutive_failure
                 }
             }
+            Self::Grpc(check) => {
+                if success {
+                    check.consecutive_success
+                } else {
+                    check.consecutive_failure
+                }
+            }
         }
     }
 
@@ -199,6 +447,7 @@ impl HealthCheck for NgxoraHealthCheck {
         match self {
             Self::Tcp(check) => check.check_backend(target).await,
             Self::Http(check) => check.check_backend(target).await,
+            Self::Grpc(check) => check.check_backend(target).await,
         }
     }
 
@@ -206,6 +455,7 @@ impl HealthCheck for NgxoraHealthCheck {
         match self {
             Self::Tcp(check) => check.backend_summary(target),
             Self::Http(check) => check.backend_summary(target),
+            Self::Grpc(check) => check.backend_summary(target),
         }
     }
 }
@@ -4,26 +4,39 @@
 //! - `compile`: IR -> `CompiledRouter`
 //! - `routing`: request-time listener/vhost/location selection
 //! - `runtime`: Pingora-facing proxy execution and upstream groups
+//! - `discovery`: backend sets, including DNS-resolved `resolve` servers
 //! - `health`: active upstream health checks
+//! - `outlier`: passive health checks (outlier ejection)
+//! - `pool`: per-group `keepalive_conns` limits on upstream connection reuse
+//! - `selection`: least-connections and consistent-hash backend selection
+//! - `slow_start`: weight ramp-up for servers recovering from failed health checks
+//! - `static_files`: filesystem-backed locations (`root`/`alias`)
 //! - `types`: shared compiled routing model
 
 mod compile;
+mod discovery;
 mod health;
+mod outlier;
+mod pool;
 mod routing;
 mod runtime;
+mod selection;
+mod slow_start;
+mod static_files;
 mod types;
 
-pub use runtime::{DynamicProxy, ProxyContext, RuntimeUpstreamGroup};
+pub use runtime::{BackendStatus, DynamicProxy, ProxyContext, RuntimeUpstreamGroup};
 pub use types::{
     CompiledHealthCheck, CompiledLocation, CompiledMatcher, CompiledRegex, CompiledRouter,
     CompiledUpstreamGroup, CompiledUpstreamServer, CompliedRouter, HealthCheckType,
     HttpRuntimeOptions, ListenKey, ListenerProtocolConfig, ListenerTlsConfig, ListenerTlsSettings,
-    RouteTarget, ServerRoutes, VirtualHostRoutes,
+    RouteTarget, ServerRoutes, StaticFallback, StaticFiles, StaticRoot, StaticTryFiles,
+    VirtualHostRoutes,
 };
 
 pub(crate) use runtime::{
     ClientIdentityKey, RuntimeClientIdentity, RuntimeTrustedCa, build_runtime_client_identities,
-    build_runtime_trusted_cas,
+    build_runtime_trusted_cas, location_labels,
 };
 
 #[cfg(test)]
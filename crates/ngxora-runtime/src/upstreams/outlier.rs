@@ -0,0 +1,287 @@
+// Passive health checking: backends that keep failing real requests are
+// ejected from selection for a while, like Envoy's outlier detection.
+
+use super::types::CompiledUpstreamServer;
+use crate::metrics;
+use ngxora_compile::ir::UpstreamOutlierDetection;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Outcome tracking for one `host:port`, shared by every copy of it in the
+/// group.
+#[derive(Debug, Default)]
+pub(super) struct BackendOutlier {
+    consecutive_failures: AtomicUsize,
+    /// Ejections since the last successful request.
+    ejections: AtomicU32,
+    /// Milliseconds after the detector's epoch until which the backend is
+    /// ejected; `0` when it never was.
+    ejected_until: AtomicU64,
+}
+
+pub(super) struct OutlierDetector {
+    config: UpstreamOutlierDetection,
+    upstream: String,
+    epoch: Instant,
+    /// Follows the group's backend set, which changes with DNS discovery.
+    backends: RwLock<HashMap<(String, u16), Arc<BackendOutlier>>>,
+    /// Serializes ejection decisions so the percentage cap holds.
+    ejecting: Mutex<()>,
+}
+
+impl OutlierDetector {
+    pub(super) fn new(
+        upstream: &str,
+        config: UpstreamOutlierDetection,
+        servers: &[CompiledUpstreamServer],
+    ) -> Self {
+        Self {
+            config,
+            upstream: upstream.to_string(),
+            epoch: Instant::now(),
+            backends: RwLock::new(
+                servers
+                    .iter()
+                    .map(|server| ((server.host.clone(), server.port), Arc::default()))
+                    .collect(),
+            ),
+            ejecting: Mutex::new(()),
+        }
+    }
+
+    pub(super) fn backend(&self, server: &CompiledUpstreamServer) -> Arc<BackendOutlier> {
+        Arc::clone(
+            self.backends
+                .write()
+                .expect("outlier lock poisoned")
+                .entry((server.host.clone(), server.port))
+                .or_default(),
+        )
+    }
+
+    /// Forgets backends that left the group.
+    pub(super) fn retain<'a>(&self, servers: impl Iterator<Item = &'a CompiledUpstreamServer>) {
+        let current: HashSet<(&str, u16)> = servers
+            .map(|server| (server.host.as_str(), server.port))
+            .collect();
+        self.backends
+            .write()
+            .expect("outlier lock poisoned")
+            .retain(|(host, port), _| current.contains(&(host.as_str(), *port)));
+    }
+
+    fn millis(&self, now: Instant) -> u64 {
+        // Offset by one so that `0` keeps meaning "never ejected".
+        u64::try_from(now.saturating_duration_since(self.epoch).as_millis())
+            .unwrap_or(u64::MAX)
+            .saturating_add(1)
+    }
+
+    pub(super) fn is_ejected(&self, backend: &BackendOutlier, now: Instant) -> bool {
+        backend.ejected_until.load(Ordering::Relaxed) > self.millis(now)
+    }
+
+    /// Records the outcome of one request to `host:port`.
+    pub(super) fn report(&self, host: &str, port: u16, success: bool, now: Instant) {
+        let Some(backend) = self
+            .backends
+            .read()
+            .expect("outlier lock poisoned")
+            .get(&(host.to_string(), port))
+            .cloned()
+        else {
+            return;
+        };
+        let backend = backend.as_ref();
+
+        if success {
+            backend.consecutive_failures.store(0, Ordering::Relaxed);
+            if !self.is_ejected(backend, now) {
+                backend.ejections.store(0, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        let failures = backend.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.config.consecutive_failure {
+            return;
+        }
+
+        let _ejecting = self.ejecting.lock().expect("outlier lock poisoned");
+        // Requests that were already in flight keep failing after an ejection.
+        if self.is_ejected(backend, now) {
+            return;
+        }
+        let (ejected, total) = {
+            let backends = self.backends.read().expect("outlier lock poisoned");
+            let ejected = backends
+                .values()
+                .filter(|other| self.is_ejected(other, now))
+                .count();
+            (ejected, backends.len())
+        };
+        if ejected >= self.max_ejected(total) {
+            metrics::record_upstream_ejection_overflow(&self.upstream);
+            return;
+        }
+
+        let ejections = backend.ejections.fetch_add(1, Ordering::Relaxed) + 1;
+        let duration = self.ejection_time(ejections);
+        let until = self
+            .millis(now)
+            .saturating_add(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX));
+        backend.ejected_until.store(until, Ordering::Relaxed);
+        backend.consecutive_failures.store(0, Ordering::Relaxed);
+        metrics::record_upstream_ejection(&self.upstream, &format!("{host}:{port}"));
+        log::warn!(
+            "upstream `{}`: ejecting {host}:{port} for {duration:?} after {failures} consecutive failures",
+            self.upstream
+        );
+    }
+
+    fn max_ejected(&self, total: usize) -> usize {
+        match self.config.max_ejection_percent {
+            0 => 0,
+            percent => (total * usize::from(percent) / 100).max(1),
+        }
+    }
+
+    fn ejection_time(&self, ejections: u32) -> Duration {
+        self.config
+            .base_ejection_time
+            .saturating_mul(ejections)
+            .min(self.config.max_ejection_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(ports: &[u16], config: UpstreamOutlierDetection) -> OutlierDetector {
+        let servers: Vec<_> = ports
+            .iter()
+            .map(|&port| CompiledUpstreamServer {
+                host: "127.0.0.1".into(),
+                port,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
+            })
+            .collect();
+        OutlierDetector::new("backend", config, &servers)
+    }
+
+    fn ejected(detector: &OutlierDetector, port: u16, now: Instant) -> bool {
+        let backend = detector.backends.read().unwrap()[&("127.0.0.1".to_string(), port)].clone();
+        detector.is_ejected(&backend, now)
+    }
+
+    fn config() -> UpstreamOutlierDetection {
+        UpstreamOutlierDetection {
+            consecutive_failure: 3,
+            base_ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(25),
+            max_ejection_percent: 50,
+        }
+    }
+
+    #[test]
+    fn ejects_after_consecutive_failures_for_a_growing_time() {
+        let detector = detector(&[8080, 8081], config());
+        let now = Instant::now();
+
+        detector.report("127.0.0.1", 8080, false, now);
+        detector.report("127.0.0.1", 8080, false, now);
+        detector.report("127.0.0.1", 8080, true, now);
+        detector.report("127.0.0.1", 8080, false, now);
+        detector.report("127.0.0.1", 8080, false, now);
+        assert!(!ejected(&detector, 8080, now));
+
+        detector.report("127.0.0.1", 8080, false, now);
+        assert!(ejected(&detector, 8080, now));
+        assert!(ejected(&detector, 8080, now + Duration::from_secs(9)));
+        assert!(!ejected(&detector, 8080, now + Duration::from_secs(10)));
+
+        // Failing again right after coming back doubles the ejection, and
+        // the ejection time stops growing at `max_ejection_time`.
+        let back = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            detector.report("127.0.0.1", 8080, false, back);
+        }
+        assert!(ejected(&detector, 8080, back + Duration::from_secs(19)));
+        assert!(!ejected(&detector, 8080, back + Duration::from_secs(20)));
+
+        let back = back + Duration::from_secs(20);
+        for _ in 0..3 {
+            detector.report("127.0.0.1", 8080, false, back);
+        }
+        assert!(ejected(&detector, 8080, back + Duration::from_secs(24)));
+        assert!(!ejected(&detector, 8080, back + Duration::from_secs(25)));
+
+        // A success after the ejection ends starts over at the base time.
+        let back = back + Duration::from_secs(25);
+        detector.report("127.0.0.1", 8080, true, back);
+        for _ in 0..3 {
+            detector.report("127.0.0.1", 8080, false, back);
+        }
+        assert!(!ejected(&detector, 8080, back + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn caps_the_ejected_share_of_the_group() {
+        let detector = detector(&[8080, 8081, 8082, 8083], config());
+        let now = Instant::now();
+
+        for port in [8080, 8081, 8082] {
+            for _ in 0..3 {
+                detector.report("127.0.0.1", port, false, now);
+            }
+        }
+        assert!(ejected(&detector, 8080, now));
+        assert!(ejected(&detector, 8081, now));
+        assert!(!ejected(&detector, 8082, now));
+
+        // Once a slot frees up, the next failure ejects the waiting backend.
+        let later = now + Duration::from_secs(10);
+        detector.report("127.0.0.1", 8082, false, later);
+        assert!(ejected(&detector, 8082, later));
+    }
+
+    #[test]
+    fn always_allows_one_ejection_unless_the_percent_is_zero() {
+        let one_percent = detector(
+            &[8080, 8081],
+            UpstreamOutlierDetection {
+                max_ejection_percent: 1,
+                ..config()
+            },
+        );
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            one_percent.report("127.0.0.1", 8080, false, now);
+            one_percent.report("127.0.0.1", 8081, false, now);
+        }
+        assert!(ejected(&one_percent, 8080, now));
+        assert!(!ejected(&one_percent, 8081, now));
+
+        // `0` never ejects, not even the only server of the group.
+        let zero = detector(
+            &[8080],
+            UpstreamOutlierDetection {
+                max_ejection_percent: 0,
+                ..config()
+            },
+        );
+        for _ in 0..3 {
+            zero.report("127.0.0.1", 8080, false, now);
+        }
+        assert!(!ejected(&zero, 8080, now));
+    }
+}
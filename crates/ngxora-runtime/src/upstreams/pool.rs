@@ -0,0 +1,184 @@
+// Per-group connection reuse limits. Pingora keeps one pool of idle
+// upstream connections for the whole process; this tracks the connections
+// of one group so `keepalive_conns` and `keepalive_requests` can decide,
+// request by request, whether a connection goes back to that pool or is
+// closed once the response is done.
+//
+// `keepalive_conns` counts every open connection of the group, busy or idle,
+// which is why it is not called `keepalive`: nginx's directive bounds idle
+// connections only. The choice has to be made when a connection is checked
+// out: a connection that must not be reused is told so with
+// `Connection: close` on the request, and Pingora's pool offers no way to
+// drop a connection when it is released.
+
+use ngxora_compile::ir::UpstreamKeepalive;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Idle timeout for pooled connections when `keepalive_conns` is set without
+/// `keepalive_timeout`, as in nginx.
+pub(super) const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Socket of an upstream connection, unique among open connections.
+pub(crate) type ConnectionKey = u64;
+
+pub(super) struct KeepalivePool {
+    /// Cap on open connections, in use or idle.
+    connections: Option<usize>,
+    requests: Option<usize>,
+    /// Pingora closes connections idle for this long, so they stop counting.
+    idle_timeout: Option<Duration>,
+    open: Mutex<HashMap<ConnectionKey, OpenConnection>>,
+}
+
+struct OpenConnection {
+    requests: usize,
+    /// Since when the connection sits in the pool; `None` while in use.
+    idle_since: Option<Instant>,
+}
+
+impl KeepalivePool {
+    /// `None` unless `keepalive_conns` or `keepalive_requests` is set.
+    pub(super) fn new(config: UpstreamKeepalive) -> Option<Self> {
+        if config.connections.is_none() && config.requests.is_none() {
+            return None;
+        }
+        Some(Self {
+            connections: config.connections,
+            requests: config.requests,
+            idle_timeout: idle_timeout(config),
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Registers a request on connection `key`. Returns whether the
+    /// connection may be reused once the request is done; otherwise the
+    /// request has to close it. A new connection is only kept when fewer
+    /// than `keepalive_conns` connections are open, counting busy ones.
+    pub(super) fn checkout(&self, key: ConnectionKey, reused: bool, now: Instant) -> bool {
+        let mut open = self.open.lock().expect("keepalive pool lock poisoned");
+        if let Some(timeout) = self.idle_timeout {
+            open.retain(|_, connection| {
+                connection
+                    .idle_since
+                    .is_none_or(|since| now.saturating_duration_since(since) < timeout)
+            });
+        }
+        // A new connection on a known socket means the old one is gone.
+        if !reused {
+            open.remove(&key);
+        }
+
+        let full = self.connections.is_some_and(|limit| open.len() >= limit);
+        let requests = match open.get_mut(&key) {
+            Some(connection) => {
+                connection.requests += 1;
+                connection.idle_since = None;
+                connection.requests
+            }
+            None if full => return false,
+            None => {
+                open.insert(
+                    key,
+                    OpenConnection {
+                        requests: 1,
+                        idle_since: None,
+                    },
+                );
+                1
+            }
+        };
+        if self.requests.is_some_and(|limit| requests >= limit) {
+            open.remove(&key);
+            return false;
+        }
+        true
+    }
+
+    /// The request on `key` is done; `reusable` is whether the connection
+    /// went back to Pingora's pool.
+    pub(super) fn release(&self, key: ConnectionKey, reusable: bool, now: Instant) {
+        let mut open = self.open.lock().expect("keepalive pool lock poisoned");
+        if !reusable {
+            open.remove(&key);
+        } else if let Some(connection) = open.get_mut(&key) {
+            connection.idle_since = Some(now);
+        }
+    }
+}
+
+/// Idle timeout applied to the group's peers.
+pub(super) fn idle_timeout(config: UpstreamKeepalive) -> Option<Duration> {
+    config
+        .timeout
+        .or(config.connections.map(|_| DEFAULT_KEEPALIVE_TIMEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(connections: Option<usize>, requests: Option<usize>) -> KeepalivePool {
+        KeepalivePool::new(UpstreamKeepalive {
+            connections,
+            timeout: Some(Duration::from_secs(10)),
+            requests,
+        })
+        .expect("pool is configured")
+    }
+
+    #[test]
+    fn caps_open_connections_including_busy_ones() {
+        let pool = pool(Some(2), None);
+        let now = Instant::now();
+
+        assert!(pool.checkout(1, false, now));
+        assert!(pool.checkout(2, false, now));
+        // Both connections are busy, yet they fill the limit, so a third
+        // concurrent connection serves one request only.
+        assert!(!pool.checkout(3, false, now));
+        pool.release(3, false, now);
+
+        // Going idle keeps a connection counted.
+        pool.release(1, true, now);
+        pool.release(2, true, now);
+        assert!(!pool.checkout(4, false, now));
+        pool.release(4, false, now);
+
+        // Reusing a counted connection is always allowed, and closing one
+        // frees its slot.
+        assert!(pool.checkout(1, true, now));
+        pool.release(1, true, now);
+        assert!(pool.checkout(2, true, now));
+        pool.release(2, false, now);
+        assert!(pool.checkout(4, false, now));
+    }
+
+    #[test]
+    fn forgets_connections_past_the_idle_timeout() {
+        let pool = pool(Some(1), None);
+        let now = Instant::now();
+
+        assert!(pool.checkout(1, false, now));
+        pool.release(1, true, now);
+        assert!(!pool.checkout(2, false, now + Duration::from_secs(9)));
+        assert!(pool.checkout(2, false, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn closes_connections_after_keepalive_requests() {
+        let pool = pool(None, Some(3));
+        let now = Instant::now();
+
+        assert!(pool.checkout(1, false, now));
+        pool.release(1, true, now);
+        assert!(pool.checkout(1, true, now));
+        pool.release(1, true, now);
+        assert!(!pool.checkout(1, true, now));
+        pool.release(1, false, now);
+
+        // The socket number comes back with a fresh connection.
+        assert!(pool.checkout(1, false, now));
+    }
+}
@@ -2,6 +2,7 @@ use super::types::{
     CompiledLocation, CompiledMatcher, CompiledRouter, ListenKey, ServerRoutes, VirtualHostRoutes,
 };
 use crate::server::DownstreamTlsInfo;
+use ngxora_compile::ir::RequestId;
 use pingora::Result as PingoraResult;
 use pingora_proxy::Session;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
@@ -18,15 +19,16 @@ pub(crate) fn select_route_target<'a>(
     for location in &routes.locations {
         match &location.matcher {
             CompiledMatcher::Exact(p) if path == p => return Some(location),
-            CompiledMatcher::Prefix(p) if path.starts_with(p) => {
-                if best_prefix.is_none_or(|(_, len)| p.len() > len) {
-                    best_prefix = Some((location, p.len()));
-                }
+            CompiledMatcher::Prefix(p)
+                if path.starts_with(p) && best_prefix.is_none_or(|(_, len)| p.len() > len) =>
+            {
+                best_prefix = Some((location, p.len()));
             }
-            CompiledMatcher::PreferPrefix(p) if path.starts_with(p) => {
-                if best_prefer_prefix.is_none_or(|(_, len)| p.len() > len) {
-                    best_prefer_prefix = Some((location, p.len()));
-                }
+            CompiledMatcher::PreferPrefix(p)
+                if path.starts_with(p)
+                    && best_prefer_prefix.is_none_or(|(_, len)| p.len() > len) =>
+            {
+                best_prefer_prefix = Some((location, p.len()));
             }
             _ => {}
         }
@@ -37,10 +39,10 @@ pub(crate) fn select_route_target<'a>(
     }
 
     for location in &routes.locations {
-        if let CompiledMatcher::Regex(regex) = &location.matcher {
-            if regex.is_match(path) {
-                return Some(location);
-            }
+        if let CompiledMatcher::Regex(regex) = &location.matcher
+            && regex.is_match(path)
+        {
+            return Some(location);
         }
     }
 
@@ -107,19 +109,19 @@ pub(crate) fn validate_sni_host_consistency(
     host: Option<&str>,
     sni: Option<&str>,
 ) -> PingoraResult<()> {
-    if let (Some(host), Some(sni)) = (host, sni) {
-        if host != sni {
-            return Err(pingora::Error::explain(
-                pingora::ErrorType::HTTPStatus(421),
-                format!("tls sni `{sni}` does not match http host `{host}`"),
-            ));
-        }
+    if let (Some(host), Some(sni)) = (host, sni)
+        && host != sni
+    {
+        return Err(pingora::Error::explain(
+            pingora::ErrorType::HTTPStatus(421),
+            format!("tls sni `{sni}` does not match http host `{host}`"),
+        ));
     }
 
     Ok(())
 }
 
-fn request_is_tls(session: &Session) -> bool {
+pub(super) fn request_is_tls(session: &Session) -> bool {
     session
         .digest()
         .and_then(|digest| digest.ssl_digest.as_ref())
@@ -173,11 +175,20 @@ pub(crate) fn listener_routes<'a>(
     router: &'a CompiledRouter,
     listen_key: &ListenKey,
 ) -> Option<&'a VirtualHostRoutes> {
-    router.listeners.get(listen_key).or_else(|| {
+    matched_listener(router, listen_key).map(|(_, vhosts)| vhosts)
+}
+
+// Returns the configured listener as well, which is the wildcard one when
+// the accepted socket only matched `listen <port>`.
+fn matched_listener<'a>(
+    router: &'a CompiledRouter,
+    listen_key: &ListenKey,
+) -> Option<(&'a ListenKey, &'a VirtualHostRoutes)> {
+    router.listeners.get_key_value(listen_key).or_else(|| {
         let wildcard = wildcard_listen_key(listen_key);
         (wildcard != *listen_key)
             .then_some(wildcard)
-            .and_then(|key| router.listeners.get(&key))
+            .and_then(|key| router.listeners.get_key_value(&key))
     })
 }
 
@@ -185,17 +196,25 @@ pub(crate) fn listener_routes<'a>(
 pub(super) struct ResolvedLocation<'a> {
     pub(super) location: &'a CompiledLocation,
     pub(super) host: Option<String>,
+    pub(super) server_name: Option<&'a str>,
+    pub(super) listener: &'a ListenKey,
+}
+
+struct ResolvedServer<'a> {
+    routes: &'a ServerRoutes,
+    host: Option<String>,
+    listener: &'a ListenKey,
 }
 
 // Route resolution first pins the accepted listener, then enforces TLS
 // authority consistency, and only after that chooses the vhost + location.
-pub(super) fn resolve_route<'a>(
+fn resolve_server_routes<'a>(
     router: &'a CompiledRouter,
     session: &Session,
-) -> PingoraResult<Option<ResolvedLocation<'a>>> {
+) -> PingoraResult<Option<ResolvedServer<'a>>> {
     let listen_key = session_listen_key(session)?;
 
-    let Some(vhosts) = listener_routes(router, &listen_key) else {
+    let Some((listener, vhosts)) = matched_listener(router, &listen_key) else {
         return Ok(None);
     };
 
@@ -205,16 +224,69 @@ pub(super) fn resolve_route<'a>(
 
     let routing_host = host.clone().or(sni);
 
-    let Some(server_routes) = select_server_routes(vhosts, routing_host.as_deref()) else {
+    Ok(
+        select_server_routes(vhosts, routing_host.as_deref()).map(|routes| ResolvedServer {
+            routes,
+            host,
+            listener,
+        }),
+    )
+}
+
+pub(super) fn resolve_route<'a>(
+    router: &'a CompiledRouter,
+    session: &Session,
+) -> PingoraResult<Option<ResolvedLocation<'a>>> {
+    let Some(server) = resolve_server_routes(router, session)? else {
         return Ok(None);
     };
 
     let path = session.req_header().uri.path();
-    let Some(location) = select_route_target(server_routes, path) else {
+    let Some(location) = select_route_target(server.routes, path) else {
         return Ok(None);
     };
 
-    Ok(Some(ResolvedLocation { location, host }))
+    Ok(Some(ResolvedLocation {
+        location,
+        host: server.host,
+        server_name: server.routes.server_name.as_deref(),
+        listener: server.listener,
+    }))
+}
+
+/// `request_id` of the server block the request is routed to. Routing errors
+/// are left for route selection to report.
+pub(super) fn resolve_request_id<'a>(
+    router: &'a CompiledRouter,
+    session: &Session,
+) -> Option<&'a RequestId> {
+    resolve_server_routes(router, session)
+        .ok()
+        .flatten()
+        .and_then(|server| server.routes.request_id.as_ref())
+}
+
+// Named locations (`@name`) are never matched by path; they are only reachable
+// through `try_files` fallbacks within the same server block.
+pub(super) fn resolve_named_route<'a>(
+    router: &'a CompiledRouter,
+    session: &Session,
+    name: &str,
+) -> PingoraResult<Option<ResolvedLocation<'a>>> {
+    let Some(server) = resolve_server_routes(router, session)? else {
+        return Ok(None);
+    };
+
+    let location = server.routes.locations.iter().find(
+        |location| matches!(&location.matcher, CompiledMatcher::Named(named) if named == name),
+    );
+
+    Ok(location.map(|location| ResolvedLocation {
+        location,
+        host: server.host,
+        server_name: server.routes.server_name.as_deref(),
+        listener: server.listener,
+    }))
 }
 
 #[cfg(test)]
@@ -1,22 +1,36 @@
 use super::compile::proxy_pass_sni;
-use super::routing::{ResolvedLocation, listener_routes, resolve_route};
+use super::discovery::{BackendIndex, ResolvedFrom, UpstreamDiscovery, UpstreamResolver};
+use super::outlier::{BackendOutlier, OutlierDetector};
+use super::pool::{self, ConnectionKey, KeepalivePool};
+use super::routing::{
+    ResolvedLocation, listener_routes, request_is_tls, resolve_named_route, resolve_request_id,
+    resolve_route,
+};
+use super::selection::{ActiveRequest, ActiveRequests, LeastConnections};
+use super::slow_start::{BackendRamp, SlowStart};
+use super::static_files::{self, InternalRedirect, StaticLookup, StaticRequest};
 use super::types::{
-    CompiledRouter, CompiledUpstreamGroup, CompiledUpstreamServer, ListenKey, RouteTarget,
-    VirtualHostRoutes,
+    CompiledLocation, CompiledRouter, CompiledUpstreamGroup, CompiledUpstreamServer,
+    HttpRuntimeOptions, ListenKey, RouteTarget, StaticFiles, VirtualHostRoutes,
 };
+use crate::access_log::AccessLogRecord;
 use crate::cache::{
-    CacheBackend, CacheKey, build_cache_key, estimated_headers_size, is_cacheable,
-    is_cacheable_request,
+    CacheBackend, CacheKey, DEFAULT_CACHE_MAX_SIZE, build_cache_key, estimated_headers_size,
+    is_cacheable, is_cacheable_request, route_cache_name,
 };
 use crate::control::{ApplyResult, ConfigSnapshot, RuntimeSnapshot, RuntimeState};
 use crate::le::ChallengeTokens;
+use crate::metrics::RouteLabels;
+use crate::request_id::RequestIdModuleBuilder;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use futures::FutureExt;
 use ngxora_compile::ir::{
-    CacheConfig, PemSource, Switch, UpstreamHttpProtocol, UpstreamSelectionPolicy,
+    AccessLog, CacheConfig, DEFAULT_REQUEST_ID_HEADER, DnsResolver, NextUpstream, PemSource,
+    Switch, UpstreamHttpProtocol, UpstreamQueue, UpstreamRetry, UpstreamSelectionPolicy,
     UpstreamSslOptions, UpstreamTimeouts,
 };
+use ngxora_compile::variables::{Template, VariableSource};
 use ngxora_plugin_api::{
     HeaderMapMut, LocalResponse, PluginError, PluginFlow, PluginState, RequestCtx, ResponseCtx,
     UpstreamRequestCtx,
@@ -24,8 +38,9 @@ use ngxora_plugin_api::{
 use opentelemetry::trace::{Span, TraceContextExt};
 use pingora::Result as PingoraResult;
 use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::lb::{Backend, Backends, LoadBalancer, discovery, selection};
-use pingora::protocols::l4::socket::SocketAddr as PingoraSocketAddr;
+use pingora::lb::{Backend, Backends, LoadBalancer, selection};
+use pingora::modules::http::HttpModules;
+use pingora::modules::http::compression::ResponseCompressionBuilder;
 use pingora::protocols::tls::CaType;
 #[cfg(feature = "openssl")]
 use pingora::tls::pkey::PKey;
@@ -34,13 +49,18 @@ use pingora::tls::x509::X509;
 use pingora::upstreams::peer::HttpPeer;
 use pingora::utils::tls::CertKey;
 use pingora_proxy::{ProxyHttp, Session};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::Notify;
 use tokio::time::Instant;
 
+// Same limit nginx applies to internal redirects within one request.
+const MAX_INTERNAL_REDIRECTS: usize = 10;
+const HASH_ITERATIONS_PER_SERVER: usize = 8;
+
 pub(crate) type RuntimeTrustedCa = Arc<CaType>;
 pub(crate) type RuntimeClientIdentity = Arc<CertKey>;
 
@@ -52,14 +72,62 @@ pub(crate) struct ClientIdentityKey {
 }
 
 pub struct RuntimeUpstreamGroup {
+    name: String,
     selector: RuntimeUpstreamSelector,
-    max_iterations: usize,
+    /// Rendered per request into the selection key of hash policies.
+    hash_key: Option<Template>,
+    outlier: Option<Arc<OutlierDetector>>,
+    slow_start: Option<Arc<SlowStart>>,
+    resolver: Option<UpstreamResolver>,
     health_check: Option<RuntimeHealthCheckSchedule>,
+    /// Some server is a `backup`.
+    has_backup: bool,
+    /// Some server has `max_conns`, so every request is counted.
+    limited: bool,
+    queue: Option<RuntimeUpstreamQueue>,
+    keepalive: Option<KeepalivePool>,
+    /// Idle timeout of pooled connections to this group.
+    idle_timeout: Option<Duration>,
+}
+
+/// One backend of a group as reported by [`RuntimeUpstreamGroup::backend_health`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BackendStatus {
+    pub server: CompiledUpstreamServer,
+    /// Host name of the `resolve` server this address belongs to.
+    pub resolved_from: Option<String>,
+    /// Passing active health checks; always `true` without a health check.
+    pub healthy: bool,
+    /// Currently ejected by outlier detection.
+    pub ejected: bool,
+    /// Still ramping up under `slow_start`.
+    pub ramping: bool,
 }
 
 enum RuntimeUpstreamSelector {
     RoundRobin(LoadBalancer<selection::RoundRobin>),
     Random(LoadBalancer<selection::Random>),
+    LeastConn(LoadBalancer<LeastConnections>),
+    Hash(LoadBalancer<selection::FNVHash>),
+    ConsistentHash(LoadBalancer<selection::Consistent>),
+}
+
+/// A backend picked for one request. Under `least_conn` it holds the
+/// backend's in-flight count until the request is done.
+pub(crate) struct SelectedBackend {
+    pub(crate) server: CompiledUpstreamServer,
+    /// Host name of the `resolve` server, used for SNI instead of the address.
+    pub(crate) resolved_from: Option<String>,
+    pub(crate) active_request: Option<ActiveRequest>,
+}
+
+impl SelectedBackend {
+    fn sni(&self, tls: bool) -> String {
+        proxy_pass_sni(
+            self.resolved_from.as_deref().unwrap_or(&self.server.host),
+            tls,
+        )
+    }
 }
 
 struct RuntimeHealthCheckSchedule {
@@ -67,78 +135,115 @@ struct RuntimeHealthCheckSchedule {
     next_run_at: Mutex<Instant>,
 }
 
-fn synthetic_backends(servers: &[CompiledUpstreamServer]) -> Result<BTreeSet<Backend>, String> {
-    servers
-        .iter()
-        .enumerate()
-        .map(|(index, server)| {
-            let mut ext = http::Extensions::new();
-            ext.insert(server.clone());
-
-            Ok(Backend {
-                addr: PingoraSocketAddr::Inet(synthetic_backend_addr(index)),
-                weight: 1,
-                ext,
-            })
-        })
-        .collect()
-}
-
-fn synthetic_backend_addr(index: usize) -> SocketAddr {
-    let index = u64::try_from(index).unwrap_or(u64::MAX);
-    let ip = Ipv6Addr::new(
-        0xfd00,
-        0,
-        0,
-        0,
-        ((index >> 32) & 0xffff) as u16,
-        ((index >> 16) & 0xffff) as u16,
-        (index & 0xffff) as u16,
-        1,
-    );
-
-    SocketAddr::V6(SocketAddrV6::new(ip, 1, 0, 0))
+struct RuntimeUpstreamQueue {
+    config: UpstreamQueue,
+    waiting: AtomicUsize,
+    /// Woken whenever a request to the group is done.
+    released: Arc<Notify>,
 }
 
 fn build_load_balancer<S>(
-    backends: BTreeSet<Backend>,
+    discovery: UpstreamDiscovery,
     health_check: Option<&super::CompiledHealthCheck>,
 ) -> Result<LoadBalancer<S>, String>
 where
     S: selection::BackendSelection + 'static,
     S::Iter: selection::BackendIter,
 {
-    let discovery = discovery::Static::new(backends);
-    let backends = Backends::new(discovery);
+    let backends = Backends::new(Box::new(discovery));
     let mut lb = LoadBalancer::from_backends(backends);
     if let Some(health_check) = health_check {
         lb.set_health_check(health_check.build()?);
     }
     lb.update()
         .now_or_never()
-        .ok_or_else(|| "static upstream update unexpectedly blocked".to_string())?
+        .ok_or_else(|| "upstream discovery unexpectedly blocked".to_string())?
         .map_err(|err| format!("failed to initialize upstream load balancer: {err}"))?;
     Ok(lb)
 }
 
 impl RuntimeUpstreamSelector {
-    fn select(&self, key: &[u8], max_iterations: usize) -> Option<Backend> {
+    fn build(
+        policy: &UpstreamSelectionPolicy,
+        discovery: UpstreamDiscovery,
+        health_check: Option<&super::CompiledHealthCheck>,
+    ) -> Result<Self, String> {
+        Ok(match policy {
+            UpstreamSelectionPolicy::RoundRobin => {
+                Self::RoundRobin(build_load_balancer(discovery, health_check)?)
+            }
+            UpstreamSelectionPolicy::Random => {
+                Self::Random(build_load_balancer(discovery, health_check)?)
+            }
+            UpstreamSelectionPolicy::LeastConn => {
+                Self::LeastConn(build_load_balancer(discovery, health_check)?)
+            }
+            UpstreamSelectionPolicy::Hash {
+                consistent: false, ..
+            } => Self::Hash(build_load_balancer(discovery, health_check)?),
+            UpstreamSelectionPolicy::Hash {
+                consistent: true, ..
+            } => Self::ConsistentHash(build_load_balancer(discovery, health_check)?),
+        })
+    }
+
+    /// Re-runs discovery and rebuilds the selection when the backend set
+    /// changed. Health of backends that stay is kept.
+    async fn update(&self) {
+        let updated = match self {
+            Self::RoundRobin(lb) => lb.update().await,
+            Self::Random(lb) => lb.update().await,
+            Self::LeastConn(lb) => lb.update().await,
+            Self::Hash(lb) => lb.update().await,
+            Self::ConsistentHash(lb) => lb.update().await,
+        };
+        if let Err(err) = updated {
+            log::warn!("failed to update upstream backends: {err}");
+        }
+    }
+
+    fn select_with(
+        &self,
+        key: &[u8],
+        max_iterations: usize,
+        accept: impl Fn(&Backend, bool) -> bool,
+    ) -> Option<Backend> {
         match self {
-            Self::RoundRobin(lb) => lb.select(key, max_iterations),
-            Self::Random(lb) => lb.select(key, max_iterations),
+            Self::RoundRobin(lb) => lb.select_with(key, max_iterations, accept),
+            Self::Random(lb) => lb.select_with(key, max_iterations, accept),
+            Self::LeastConn(lb) => lb.select_with(key, max_iterations, accept),
+            Self::Hash(lb) => lb.select_with(key, max_iterations, accept),
+            Self::ConsistentHash(lb) => lb.select_with(key, max_iterations, accept),
         }
     }
 
-    async fn run_health_check(&self) {
+    fn backends(&self) -> &Backends {
         match self {
-            Self::RoundRobin(lb) => lb.backends().run_health_check(false).await,
-            Self::Random(lb) => lb.backends().run_health_check(false).await,
+            Self::RoundRobin(lb) => lb.backends(),
+            Self::Random(lb) => lb.backends(),
+            Self::LeastConn(lb) => lb.backends(),
+            Self::Hash(lb) => lb.backends(),
+            Self::ConsistentHash(lb) => lb.backends(),
         }
     }
+
+    async fn run_health_check(&self) {
+        self.backends().run_health_check(false).await;
+    }
 }
 
 impl RuntimeUpstreamGroup {
+    #[cfg(test)]
     pub(crate) fn from_compiled(group: &CompiledUpstreamGroup) -> Result<Self, String> {
+        Self::with_resolver(group, None)
+    }
+
+    /// Builds the group; `resolve` servers are looked up through `resolver`,
+    /// or the system configuration when it is `None`.
+    pub(crate) fn with_resolver(
+        group: &CompiledUpstreamGroup,
+        resolver: Option<&DnsResolver>,
+    ) -> Result<Self, String> {
         if group.servers.is_empty() {
             return Err(format!(
                 "upstream `{}` must define at least one server",
@@ -146,31 +251,307 @@ impl RuntimeUpstreamGroup {
             ));
         }
 
-        let backends = synthetic_backends(&group.servers)?;
-        let selector = match group.policy {
-            UpstreamSelectionPolicy::RoundRobin => RuntimeUpstreamSelector::RoundRobin(
-                build_load_balancer(backends, group.health_check.as_ref())?,
-            ),
-            UpstreamSelectionPolicy::Random => RuntimeUpstreamSelector::Random(
-                build_load_balancer(backends, group.health_check.as_ref())?,
-            ),
+        let (hash_key, ring) = match &group.policy {
+            UpstreamSelectionPolicy::Hash { key, consistent } => (Some(key.clone()), *consistent),
+            _ => (None, false),
         };
+        let outlier = group
+            .outlier_detection
+            .map(|config| Arc::new(OutlierDetector::new(&group.name, config, &group.servers)));
+        let slow_start = SlowStart::new(&group.servers).map(Arc::new);
+        let resolver = UpstreamResolver::new(&group.name, &group.servers, resolver)?;
+        let discovery = UpstreamDiscovery::new(
+            &group.servers,
+            ring,
+            outlier.clone(),
+            slow_start.clone(),
+            resolver
+                .as_ref()
+                .map(UpstreamResolver::resolved)
+                .unwrap_or_default(),
+        );
+        let selector =
+            RuntimeUpstreamSelector::build(&group.policy, discovery, group.health_check.as_ref())?;
 
         Ok(Self {
+            name: group.name.clone(),
             selector,
-            max_iterations: group.servers.len(),
+            hash_key,
+            outlier,
+            slow_start,
+            resolver,
             health_check: group.health_check.as_ref().map(|health_check| {
                 RuntimeHealthCheckSchedule {
                     interval: health_check.interval,
                     next_run_at: Mutex::new(Instant::now()),
                 }
             }),
+            has_backup: group.servers.iter().any(|server| server.backup),
+            limited: group
+                .servers
+                .iter()
+                .any(|server| server.max_conns.is_some()),
+            queue: group.queue.map(|config| RuntimeUpstreamQueue {
+                config,
+                waiting: AtomicUsize::new(0),
+                released: Arc::new(Notify::new()),
+            }),
+            keepalive: KeepalivePool::new(group.keepalive),
+            idle_timeout: pool::idle_timeout(group.keepalive),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Keeps the group's pooled connections apart from other groups', even
+    /// for a backend they share.
+    fn pool_key(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Hash fallbacks walk pseudo-randomly (or around the ring, where a server
+    // owns many adjacent points), so they get more steps to reach a healthy
+    // server than the one-per-server walk of the other policies.
+    fn max_iterations(&self) -> usize {
+        let backends = self.selector.backends().get_backend().len();
+        if self.hash_key.is_some() {
+            backends * HASH_ITERATIONS_PER_SERVER
+        } else {
+            backends
+        }
+    }
+
+    /// Template whose rendering keys the selection; `None` unless the group
+    /// uses a hash policy.
+    pub(crate) fn hash_key(&self) -> Option<&Template> {
+        self.hash_key.as_ref()
+    }
+
+    /// Picks a healthy backend for `key`, from the `backup` servers only when
+    /// no primary one is available. Backends ejected by outlier detection or
+    /// ramping up under `slow_start` are passed over unless nothing else is
+    /// healthy, in which case they are used rather than failing the request.
+    /// Servers that are `down` or at their `max_conns` are always skipped.
+    pub(crate) fn select(&self, key: &[u8]) -> Option<SelectedBackend> {
+        self.select_excluding(key, &[])
+    }
+
+    /// Like [`Self::select`], but when every healthy server is at its
+    /// `max_conns`, waits in the group's `queue` for one to free up.
+    pub(crate) async fn select_queued(&self, key: &[u8]) -> Option<SelectedBackend> {
+        if let Some(backend) = self.select(key) {
+            return Some(backend);
+        }
+        // Queueing only helps when servers are busy rather than down.
+        if !self.limited
+            || self
+                .find(key, |backend| {
+                    backend
+                        .ext
+                        .get::<CompiledUpstreamServer>()
+                        .is_some_and(|server| !server.down)
+                })
+                .is_none()
+        {
+            return None;
+        }
+        let Some(queue) = &self.queue else {
+            crate::metrics::record_upstream_max_conns_rejection(&self.name);
+            return None;
+        };
+
+        if queue.waiting.fetch_add(1, Ordering::Relaxed) >= queue.config.size {
+            queue.waiting.fetch_sub(1, Ordering::Relaxed);
+            crate::metrics::record_upstream_max_conns_rejection(&self.name);
+            return None;
+        }
+        let deadline = Instant::now() + queue.config.timeout;
+        let selected = loop {
+            let released = queue.released.notified();
+            tokio::pin!(released);
+            // Registered before looking, so a release in between is not lost.
+            released.as_mut().enable();
+            if let Some(backend) = self.select(key) {
+                break Some(backend);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                break None;
+            }
+        };
+        queue.waiting.fetch_sub(1, Ordering::Relaxed);
+        if selected.is_none() {
+            crate::metrics::record_upstream_max_conns_rejection(&self.name);
+        }
+        selected
+    }
+
+    /// Like [`Self::select`], but never returns a server in `failed`, so a
+    /// retry lands on another peer.
+    pub(crate) fn select_excluding(
+        &self,
+        key: &[u8],
+        failed: &[(String, u16)],
+    ) -> Option<SelectedBackend> {
+        let allowed = |backend: &Backend| {
+            backend
+                .ext
+                .get::<CompiledUpstreamServer>()
+                .is_some_and(|server| {
+                    !server.down
+                        && !failed
+                            .iter()
+                            .any(|(host, port)| server.host == *host && server.port == *port)
+                        && server.max_conns.is_none_or(|max| {
+                            backend
+                                .ext
+                                .get::<ActiveRequests>()
+                                .is_none_or(|active| active.get() < max)
+                        })
+                })
+        };
+        let now = std::time::Instant::now();
+        let preferred = |backend: &Backend| {
+            allowed(backend)
+                && !self.is_ejected(backend, now)
+                && backend
+                    .ext
+                    .get::<Arc<BackendRamp>>()
+                    .is_none_or(|ramp| ramp.accepts(now))
+        };
+        let is_backup = |backend: &Backend| {
+            backend
+                .ext
+                .get::<CompiledUpstreamServer>()
+                .is_some_and(|server| server.backup)
+        };
+        let tiers: &[bool] = if self.has_backup {
+            &[false, true]
+        } else {
+            &[false]
+        };
+
+        let backend = tiers
+            .iter()
+            .find_map(|&backup| {
+                self.find(key, |backend| {
+                    is_backup(backend) == backup && preferred(backend)
+                })
+            })
+            .or_else(|| {
+                tiers.iter().find_map(|&backup| {
+                    self.find(key, |backend| {
+                        is_backup(backend) == backup && allowed(backend)
+                    })
+                })
+            })?;
+        self.selected_backend(backend)
+    }
+
+    fn is_ejected(&self, backend: &Backend, now: std::time::Instant) -> bool {
+        match (&self.outlier, backend.ext.get::<Arc<BackendOutlier>>()) {
+            (Some(outlier), Some(state)) => outlier.is_ejected(state, now),
+            _ => false,
+        }
+    }
+
+    // Walks the policy's order first. When that only meets rejected
+    // backends, the remaining healthy ones are tried in configuration order.
+    fn find(&self, key: &[u8], accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        self.selector
+            .select_with(key, self.max_iterations(), |backend, healthy| {
+                healthy && accept(backend)
+            })
+            .or_else(|| {
+                let backends = self.selector.backends();
+                backends
+                    .get_backend()
+                    .iter()
+                    .filter(|backend| backends.ready(backend) && accept(backend))
+                    .min_by_key(|backend| backend.ext.get::<BackendIndex>().map(|index| index.0))
+                    .cloned()
+            })
+    }
+
+    /// Feeds the outcome of a proxied request into outlier detection.
+    pub(crate) fn report(&self, host: &str, port: u16, success: bool) {
+        if let Some(outlier) = &self.outlier {
+            outlier.report(host, port, success, std::time::Instant::now());
+        }
+    }
+
+    // A concurrent request may have taken the last slot of a `max_conns`
+    // server since it was picked; the pick then fails like a full server.
+    fn selected_backend(&self, backend: Backend) -> Option<SelectedBackend> {
+        let server = backend.ext.get::<CompiledUpstreamServer>()?.clone();
+        let active =
+            if self.limited || matches!(self.selector, RuntimeUpstreamSelector::LeastConn(_)) {
+                Some(backend.ext.get::<ActiveRequests>()?.acquire(
+                    server.max_conns,
+                    self.queue.as_ref().map(|queue| &queue.released),
+                )?)
+            } else {
+                None
+            };
+        Some(SelectedBackend {
+            server,
+            resolved_from: backend.ext.get::<ResolvedFrom>().map(|name| name.0.clone()),
+            active_request: active,
         })
     }
 
-    pub(crate) fn select(&self, key: &[u8]) -> Option<CompiledUpstreamServer> {
-        let backend = self.selector.select(key, self.max_iterations)?;
-        backend.ext.get::<CompiledUpstreamServer>().cloned()
+    /// Registers a request on an upstream connection of this group and
+    /// returns whether the connection may be reused afterwards.
+    pub(crate) fn checkout_connection(&self, key: ConnectionKey, reused: bool) -> bool {
+        self.keepalive
+            .as_ref()
+            .is_none_or(|pool| pool.checkout(key, reused, std::time::Instant::now()))
+    }
+
+    pub(crate) fn release_connection(&self, key: ConnectionKey, reusable: bool) {
+        if let Some(pool) = &self.keepalive {
+            pool.release(key, reusable, std::time::Instant::now());
+        }
+    }
+
+    /// Backends in configuration order with their current health. Groups
+    /// without a health check always report every backend as healthy.
+    pub fn backend_health(&self) -> Vec<BackendStatus> {
+        let now = std::time::Instant::now();
+        let backends = self.selector.backends().get_backend();
+        let mut health: Vec<_> = backends
+            .iter()
+            .filter_map(|backend| {
+                let index = backend.ext.get::<BackendIndex>()?.0;
+                Some((
+                    index,
+                    BackendStatus {
+                        server: backend.ext.get::<CompiledUpstreamServer>()?.clone(),
+                        resolved_from: backend.ext.get::<ResolvedFrom>().map(|name| name.0.clone()),
+                        healthy: self.selector.backends().ready(backend),
+                        ejected: self.is_ejected(backend, now),
+                        ramping: backend
+                            .ext
+                            .get::<Arc<BackendRamp>>()
+                            .is_some_and(|ramp| ramp.ramping(now)),
+                    },
+                ))
+            })
+            .collect();
+        health.sort_by_key(|(index, _)| *index);
+        health.into_iter().map(|(_, status)| status).collect()
+    }
+
+    pub fn has_health_check(&self) -> bool {
+        self.health_check.is_some()
+    }
+
+    pub fn has_outlier_detection(&self) -> bool {
+        self.outlier.is_some()
     }
 
     pub(crate) async fn run_due_health_check(&self, now: Instant) -> Option<Instant> {
@@ -187,8 +568,45 @@ impl RuntimeUpstreamGroup {
             *next_run_at
         };
         self.selector.run_health_check().await;
+        if self.slow_start.is_some() {
+            let now = std::time::Instant::now();
+            let backends = self.selector.backends();
+            for backend in backends.get_backend().iter() {
+                if let Some(ramp) = backend.ext.get::<Arc<BackendRamp>>() {
+                    ramp.observe(backends.ready(backend), now);
+                }
+            }
+        }
+        Some(next_run_at)
+    }
+
+    /// Re-resolves `resolve` servers once their records expire and swaps in
+    /// the new backend set. New addresses are health-checked right away.
+    pub(crate) async fn run_due_resolve(&self, now: Instant) -> Option<Instant> {
+        let resolver = self.resolver.as_ref()?;
+        let (changed, next_run_at) = resolver.run_due(now).await;
+        if changed {
+            self.selector.update().await;
+            if let Some(schedule) = &self.health_check {
+                *schedule
+                    .next_run_at
+                    .lock()
+                    .expect("health check lock poisoned") = now;
+            }
+        }
         Some(next_run_at)
     }
+
+    /// Carries DNS answers over from the group this one replaces, so a
+    /// snapshot apply does not drop resolved backends until the next lookup.
+    pub(crate) fn adopt_resolved(&self, previous: &Self) {
+        let (Some(resolver), Some(previous)) = (&self.resolver, &previous.resolver) else {
+            return;
+        };
+        resolver.adopt(previous);
+        // Discovery never awaits, so the update completes right here.
+        let _ = self.selector.update().now_or_never();
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -202,12 +620,16 @@ struct SelectedPeer {
 #[derive(Debug, Clone)]
 enum SelectedTarget {
     Upstream(SelectedPeer),
-    Return { status: u16, location: String },
+    Return { status: u16, location: Template },
+    Static(StaticFiles),
 }
 
 #[derive(Clone)]
 pub(crate) struct SelectedRoute {
     route_id: u64,
+    server_name: Option<String>,
+    labels: Arc<RouteLabels>,
+    access_logs: Vec<AccessLog>,
     target: SelectedTarget,
     access_rules: Vec<ngxora_compile::ir::LocationIpRule>,
     upstream_timeouts: UpstreamTimeouts,
@@ -217,6 +639,19 @@ pub(crate) struct SelectedRoute {
     upstream_client_identity: Option<RuntimeClientIdentity>,
     plugins: ngxora_plugin_api::PluginChain,
     cache: Option<CacheConfig>,
+    upstream_retry: UpstreamRetry,
+    /// Group the upstream was picked from, kept so a retry can pick again.
+    upstream_group: Option<SelectedGroup>,
+    /// Keeps the chosen backend's `least_conn` count until the request ends.
+    _active_request: Option<Arc<ActiveRequest>>,
+}
+
+#[derive(Clone)]
+struct SelectedGroup {
+    group: Arc<RuntimeUpstreamGroup>,
+    /// Rendered hash key, so retries walk the same ring.
+    key: String,
+    tls: bool,
 }
 
 impl SelectedRoute {
@@ -225,12 +660,64 @@ impl SelectedRoute {
     }
 }
 
+// Metric labels are fixed when the route is selected; retries only change
+// the backend, which is labelled separately.
+fn route_labels(resolved: &ResolvedLocation<'_>) -> Arc<RouteLabels> {
+    Arc::new(location_labels(
+        resolved.server_name,
+        resolved.listener,
+        resolved.location,
+    ))
+}
+
+/// Metric labels of `location` in the server named `server_name` on
+/// `listener`.
+pub(crate) fn location_labels(
+    server_name: Option<&str>,
+    listener: &ListenKey,
+    location: &CompiledLocation,
+) -> RouteLabels {
+    RouteLabels {
+        server: server_name.unwrap_or("_").to_string(),
+        listener: std::net::SocketAddr::new(listener.addr, listener.port).to_string(),
+        route: location
+            .name
+            .clone()
+            .unwrap_or_else(|| location.matcher.to_string()),
+        upstream: match &location.target {
+            RouteTarget::UpstreamGroup { name, .. } => name.clone(),
+            RouteTarget::ProxyPass { host, port, .. } => format!("{host}:{port}"),
+            RouteTarget::Return { .. } | RouteTarget::Static(_) => String::new(),
+        },
+    }
+}
+
 fn cache_store_allowed(cfg: &CacheConfig, threshold_reached: bool) -> bool {
     cfg.min_uses.unwrap_or(1) <= 1 || threshold_reached
 }
 
 pub struct ProxyContext {
     pub(crate) selected: Option<SelectedRoute>,
+    /// When the first upstream attempt started; bounds
+    /// `proxy_next_upstream_timeout`.
+    pub(crate) upstream_started: Option<std::time::Instant>,
+    /// When the current upstream attempt picked its backend; the start of
+    /// the connect and time-to-first-byte metrics.
+    pub(crate) upstream_attempt_started: Option<std::time::Instant>,
+    /// Metric labels of the last selected route, kept even when a plugin
+    /// answers before the route is committed to `selected`.
+    pub(crate) route_labels: Option<Arc<RouteLabels>>,
+    /// `access_log` of the last selected route; empty uses the http level.
+    pub(crate) route_access_logs: Vec<AccessLog>,
+    /// ID assigned by the server's `request_id`.
+    pub(crate) request_id: Option<String>,
+    /// Status, connect time and time to first byte of the last upstream
+    /// attempt, for the access log.
+    pub(crate) upstream_status: Option<u16>,
+    pub(crate) upstream_connect_time: Option<std::time::Duration>,
+    pub(crate) upstream_ttfb: Option<std::time::Duration>,
+    /// `host:port` of the backends this request already failed on.
+    pub(crate) failed_upstreams: Vec<(String, u16)>,
     pub(crate) plugin_state: PluginState,
     pub(crate) client_max_body_size: Option<u64>,
     pub(crate) received_body_bytes: u64,
@@ -250,12 +737,31 @@ pub struct ProxyContext {
     pub(crate) parent_ctx: opentelemetry::Context,
     /// Trace context derived from the current proxy span for upstream propagation.
     pub(crate) upstream_trace_ctx: opentelemetry::Context,
+    /// Connection of the current upstream attempt, while its group limits
+    /// connection reuse.
+    pub(crate) upstream_connection: Option<UpstreamConnection>,
+}
+
+pub(crate) struct UpstreamConnection {
+    group: Arc<RuntimeUpstreamGroup>,
+    key: ConnectionKey,
+    /// Whether the connection may go back to the pool after this request.
+    reusable: bool,
 }
 
 impl Default for ProxyContext {
     fn default() -> Self {
         Self {
             selected: None,
+            upstream_started: None,
+            upstream_attempt_started: None,
+            route_labels: None,
+            route_access_logs: Vec::new(),
+            request_id: None,
+            upstream_status: None,
+            upstream_connect_time: None,
+            upstream_ttfb: None,
+            failed_upstreams: Vec::new(),
             plugin_state: PluginState::default(),
             client_max_body_size: None,
             received_body_bytes: 0,
@@ -270,6 +776,7 @@ impl Default for ProxyContext {
             span: None,
             parent_ctx: opentelemetry::Context::new(),
             upstream_trace_ctx: opentelemetry::Context::new(),
+            upstream_connection: None,
         }
     }
 }
@@ -363,27 +870,32 @@ impl HeaderMapMut for ResponseHeaderEditor<'_> {
 }
 
 impl SelectedRoute {
-    fn from_resolved(
+    async fn from_resolved(
         snapshot: &RuntimeSnapshot,
+        session: &Session,
         resolved: &ResolvedLocation<'_>,
     ) -> PingoraResult<Self> {
+        let mut active_request = None;
+        let mut upstream_group = None;
         let target = match &resolved.location.target {
             RouteTarget::Return { status, location } => {
-                return Ok(Self {
-                    route_id: resolved.location.route_id,
-                    access_rules: Vec::new(),
-                    target: SelectedTarget::Return {
+                return Ok(Self::local(
+                    snapshot,
+                    resolved,
+                    SelectedTarget::Return {
                         status: *status,
                         location: location.clone(),
                     },
-                    upstream_timeouts: UpstreamTimeouts::default(),
-                    upstream_protocol: None,
-                    upstream_ssl_options: UpstreamSslOptions::default(),
-                    upstream_trusted_ca: None,
-                    upstream_client_identity: None,
-                    plugins: snapshot.plugin_chain(resolved.location.route_id),
-                    cache: resolved.location.cache.clone(),
-                });
+                    Vec::new(),
+                ));
+            }
+            RouteTarget::Static(files) => {
+                return Ok(Self::local(
+                    snapshot,
+                    resolved,
+                    SelectedTarget::Static(files.clone()),
+                    resolved.location.access_rules.clone(),
+                ));
             }
             RouteTarget::ProxyPass {
                 host,
@@ -403,17 +915,40 @@ impl SelectedRoute {
                         format!("compiled upstream group `{name}` is missing at runtime"),
                     )
                 })?;
-                let backend = group.select(b"").ok_or_else(|| {
+                let key = group
+                    .hash_key()
+                    .map(|key| {
+                        key.render(&SessionVariables {
+                            session,
+                            host: resolved.host.as_deref(),
+                            server_name: resolved.server_name,
+                            scheme: if request_is_tls(session) {
+                                "https"
+                            } else {
+                                "http"
+                            },
+                            client_ip: request_client_ip(session),
+                        })
+                    })
+                    .unwrap_or_default();
+                let backend = group.select_queued(key.as_bytes()).await.ok_or_else(|| {
                     pingora::Error::explain(
                         pingora::ErrorType::HTTPStatus(503),
                         format!("upstream `{name}` has no available backends"),
                     )
                 })?;
+                let sni = backend.sni(*tls);
+                active_request = backend.active_request.map(Arc::new);
+                upstream_group = Some(SelectedGroup {
+                    group: Arc::clone(group),
+                    key,
+                    tls: *tls,
+                });
 
                 SelectedTarget::Upstream(SelectedPeer {
-                    sni: proxy_pass_sni(&backend.host, *tls),
-                    host: backend.host,
-                    port: backend.port,
+                    sni,
+                    host: backend.server.host,
+                    port: backend.server.port,
                     tls: *tls,
                 })
             }
@@ -444,6 +979,9 @@ impl SelectedRoute {
 
         Ok(Self {
             route_id: resolved.location.route_id,
+            server_name: resolved.server_name.map(ToString::to_string),
+            labels: route_labels(resolved),
+            access_logs: resolved.location.access_logs.clone(),
             access_rules: resolved.location.access_rules.clone(),
             target,
             upstream_timeouts: resolved.location.upstream_timeouts,
@@ -466,11 +1004,44 @@ impl SelectedRoute {
             upstream_client_identity,
             plugins: snapshot.plugin_chain(resolved.location.route_id),
             cache: resolved.location.cache.clone(),
+            upstream_retry: resolved.location.upstream_retry,
+            upstream_group,
+            _active_request: active_request,
         })
     }
 }
 
-fn select_runtime_route(
+impl SelectedRoute {
+    // Targets answered by the proxy itself never open an upstream connection,
+    // so upstream options keep their defaults.
+    fn local(
+        snapshot: &RuntimeSnapshot,
+        resolved: &ResolvedLocation<'_>,
+        target: SelectedTarget,
+        access_rules: Vec<ngxora_compile::ir::LocationIpRule>,
+    ) -> Self {
+        Self {
+            route_id: resolved.location.route_id,
+            server_name: resolved.server_name.map(ToString::to_string),
+            labels: route_labels(resolved),
+            access_logs: resolved.location.access_logs.clone(),
+            access_rules,
+            target,
+            upstream_timeouts: UpstreamTimeouts::default(),
+            upstream_protocol: None,
+            upstream_ssl_options: UpstreamSslOptions::default(),
+            upstream_trusted_ca: None,
+            upstream_client_identity: None,
+            plugins: snapshot.plugin_chain(resolved.location.route_id),
+            cache: resolved.location.cache.clone(),
+            upstream_retry: UpstreamRetry::default(),
+            upstream_group: None,
+            _active_request: None,
+        }
+    }
+}
+
+async fn select_runtime_route(
     snapshot: &RuntimeSnapshot,
     session: &Session,
 ) -> PingoraResult<Option<(SelectedRoute, Option<String>)>> {
@@ -479,11 +1050,246 @@ fn select_runtime_route(
     };
 
     Ok(Some((
-        SelectedRoute::from_resolved(snapshot, &resolved)?,
+        SelectedRoute::from_resolved(snapshot, session, &resolved).await?,
         resolved.host,
     )))
 }
 
+/// Upstream failure classes `proxy_next_upstream` can move past.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum UpstreamFailure {
+    Error,
+    Timeout,
+    Status(u16),
+}
+
+impl UpstreamFailure {
+    fn of_connect(err: &pingora::Error) -> Self {
+        match err.etype {
+            pingora::ErrorType::ConnectTimedout => Self::Timeout,
+            _ => Self::Error,
+        }
+    }
+
+    fn of_proxy(err: &pingora::Error) -> Self {
+        match err.etype {
+            pingora::ErrorType::ReadTimedout | pingora::ErrorType::WriteTimedout => Self::Timeout,
+            _ => Self::Error,
+        }
+    }
+
+    fn enabled(self, next: &NextUpstream) -> bool {
+        match self {
+            Self::Error => next.error,
+            Self::Timeout => next.timeout,
+            Self::Status(502) => next.http_502,
+            Self::Status(503) => next.http_503,
+            Self::Status(504) => next.http_504,
+            Self::Status(_) => false,
+        }
+    }
+}
+
+/// Points the request at another backend of its upstream group when the
+/// route's `proxy_next_upstream` covers `failure`. Returns `false` when the
+/// failure has to reach the client instead. `request_sent` is whether the
+/// failed backend may have seen the request, which rules out retrying
+/// non-idempotent methods unless explicitly allowed.
+fn next_upstream(
+    ctx: &mut ProxyContext,
+    method: &http::Method,
+    body_truncated: bool,
+    failure: UpstreamFailure,
+    request_sent: bool,
+) -> bool {
+    let Some(selected) = ctx.selected.as_mut() else {
+        return false;
+    };
+    let retry = selected.upstream_retry;
+    let (Some(group), SelectedTarget::Upstream(peer)) =
+        (selected.upstream_group.as_ref(), &selected.target)
+    else {
+        return false;
+    };
+
+    if !failure.enabled(&retry.next_upstream)
+        || (request_sent && !retry.next_upstream.non_idempotent && !method.is_idempotent())
+        || body_truncated
+    {
+        return false;
+    }
+    let tries = ctx.failed_upstreams.len() + 1;
+    if retry.tries != 0 && tries >= retry.tries as usize {
+        return false;
+    }
+    if let (Some(timeout), Some(started)) = (retry.timeout, ctx.upstream_started)
+        && started.elapsed() >= timeout
+    {
+        return false;
+    }
+
+    ctx.failed_upstreams.push((peer.host.clone(), peer.port));
+    let Some(backend) = group
+        .group
+        .select_excluding(group.key.as_bytes(), &ctx.failed_upstreams)
+    else {
+        return false;
+    };
+
+    selected.target = SelectedTarget::Upstream(SelectedPeer {
+        sni: backend.sni(group.tls),
+        host: backend.server.host,
+        port: backend.server.port,
+        tls: group.tls,
+    });
+    selected._active_request = backend.active_request.map(Arc::new);
+    true
+}
+
+/// Answers a request that could not be proxied with the status Pingora
+/// would pick for `error`.
+async fn respond_proxy_error(
+    session: &mut Session,
+    error: &pingora::Error,
+) -> pingora_proxy::FailToProxy {
+    let code = match error.etype() {
+        pingora::ErrorType::HTTPStatus(code) => *code,
+        _ => match error.esource() {
+            pingora::ErrorSource::Upstream => 502,
+            pingora::ErrorSource::Downstream => match error.etype() {
+                // The client connection is already gone.
+                pingora::ErrorType::WriteError
+                | pingora::ErrorType::ReadError
+                | pingora::ErrorType::ConnectionClosed => 0,
+                _ => 400,
+            },
+            pingora::ErrorSource::Internal | pingora::ErrorSource::Unset => 500,
+        },
+    };
+    if code > 0
+        && let Err(err) = crate::request_id::respond_error(session, code).await
+    {
+        log::error!("failed to send error response to downstream: {err}");
+    }
+    pingora_proxy::FailToProxy {
+        can_reuse_downstream: false,
+        error_code: code,
+    }
+}
+
+/// Whether an upstream response leaves its HTTP/1 connection open for the
+/// next request.
+fn upstream_response_keeps_connection(response: &ResponseHeader) -> bool {
+    let has_token = |token: &str| {
+        response
+            .headers
+            .get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if response.status == http::StatusCode::SWITCHING_PROTOCOLS {
+        return false;
+    }
+    if response.version == http::Version::HTTP_10 {
+        has_token("keep-alive")
+    } else {
+        !has_token("close")
+    }
+}
+
+/// Tells the group's outlier detection how the current upstream attempt went.
+fn report_upstream_outcome(ctx: &ProxyContext, success: bool) {
+    let Some(selected) = ctx.selected.as_ref() else {
+        return;
+    };
+    if let (Some(group), SelectedTarget::Upstream(peer)) =
+        (selected.upstream_group.as_ref(), &selected.target)
+    {
+        group.group.report(&peer.host, peer.port, success);
+    }
+}
+
+/// Upstream and backend labels of the current upstream attempt.
+fn upstream_attempt_labels(ctx: &ProxyContext) -> Option<(&str, String)> {
+    let selected = ctx.selected.as_ref()?;
+    let SelectedTarget::Upstream(peer) = &selected.target else {
+        return None;
+    };
+    Some((
+        selected.labels.upstream.as_str(),
+        format!("{}:{}", peer.host, peer.port),
+    ))
+}
+
+/// `kind` is `connect` for failed connection attempts, otherwise `timeout`
+/// or `error`.
+fn record_upstream_error(ctx: &ProxyContext, kind: &str) {
+    if let Some((upstream, backend)) = upstream_attempt_labels(ctx) {
+        crate::metrics::record_upstream_error(upstream, &backend, kind);
+    }
+}
+
+// Fills in what `logging` has not already collected and writes the record
+// to the logs that apply to the request.
+fn write_access_logs(
+    options: &HttpRuntimeOptions,
+    session: &Session,
+    ctx: &ProxyContext,
+    record: AccessLogRecord<'_>,
+) {
+    let logs = crate::access_log::effective(&ctx.route_access_logs, &options.access_logs);
+    if logs.iter().all(|log| matches!(log, AccessLog::Off)) {
+        return;
+    }
+
+    let request = session.req_header();
+    let downstream = session.as_downstream();
+    let tls = downstream
+        .digest()
+        .and_then(|digest| digest.ssl_digest.as_deref());
+    let labels = ctx.route_labels.as_deref();
+    let client_ip = downstream.client_addr().map(ToString::to_string);
+    crate::access_log::write(
+        logs,
+        &options.log_formats,
+        &AccessLogRecord {
+            query: request.uri.query(),
+            protocol: Some(format!("{:?}", request.version)),
+            scheme: if request_is_tls(session) {
+                "https"
+            } else {
+                "http"
+            },
+            host: request
+                .headers
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| request.uri.host()),
+            bytes_sent: Some(downstream.body_bytes_sent() as u64),
+            bytes_received: Some(downstream.body_bytes_read() as u64),
+            client_ip,
+            upstream_status: ctx.upstream_status,
+            upstream_connect: ctx.upstream_connect_time,
+            upstream_ttfb: ctx.upstream_ttfb,
+            tls_version: tls.map(|tls| tls.version.as_ref()),
+            tls_cipher: tls.map(|tls| tls.cipher.as_ref()),
+            server: labels.map(|labels| labels.server.as_str()),
+            route: labels.map(|labels| labels.route.as_str()),
+            request_id: ctx.request_id.as_deref().or_else(|| {
+                request
+                    .headers
+                    .get(DEFAULT_REQUEST_ID_HEADER)
+                    .and_then(|id| id.to_str().ok())
+            }),
+            request_headers: Some(&request.headers),
+            response_headers: session.response_written().map(|response| &response.headers),
+            ..record
+        },
+    );
+}
+
 fn request_client_ip(session: &Session) -> Option<std::net::IpAddr> {
     session
         .downstream_session
@@ -523,6 +1329,41 @@ fn respond_from_plugin_flow(flow: PluginFlow, stage: &str) -> PingoraResult<()>
     }
 }
 
+// Response plugins run in reverse order so they behave like unwind-style
+// middleware around the upstream exchange.
+async fn apply_response_plugins(
+    selected: &SelectedRoute,
+    state: &mut PluginState,
+    response: &mut ResponseHeader,
+) -> PingoraResult<()> {
+    let mut status = response.status;
+    {
+        let mut headers = ResponseHeaderEditor { inner: response };
+
+        for plugin in selected.plugins.iter().rev() {
+            let flow = plugin
+                .on_response(&mut ResponseCtx {
+                    state,
+                    status: &mut status,
+                    headers: &mut headers,
+                })
+                .await
+                .map_err(|err| {
+                    crate::metrics::record_plugin_error(plugin.name(), "response_filter");
+                    map_plugin_error("response_filter", err)
+                })?;
+            respond_from_plugin_flow(flow, "response_filter")?;
+        }
+    }
+
+    response.set_status(status).map_err(|err| {
+        pingora::Error::explain(
+            pingora::ErrorType::InternalError,
+            format!("failed to update response status from plugin chain: {err}"),
+        )
+    })
+}
+
 fn map_plugin_error(stage: &str, err: PluginError) -> Box<pingora::Error> {
     pingora::Error::explain(
         pingora::ErrorType::InternalError,
@@ -792,7 +1633,7 @@ async fn restrict_client_max_body_size(
     ) == Some(true)
     {
         session.set_keepalive(None);
-        session.respond_error(413).await?;
+        crate::request_id::respond_error(session, 413).await?;
         return Ok(true);
     }
 
@@ -861,9 +1702,52 @@ async fn write_cached_response(
     }
 }
 
+// Request variables for templates evaluated directly by the proxy, such as
+// `return` locations.
+struct SessionVariables<'a> {
+    session: &'a Session,
+    host: Option<&'a str>,
+    server_name: Option<&'a str>,
+    scheme: &'a str,
+    client_ip: Option<std::net::IpAddr>,
+}
+
+impl VariableSource for SessionVariables<'_> {
+    fn host(&self) -> Option<&str> {
+        self.host
+    }
+
+    fn path(&self) -> &str {
+        self.session.req_header().uri.path()
+    }
+
+    fn query(&self) -> Option<&str> {
+        self.session.req_header().uri.query()
+    }
+
+    fn scheme(&self) -> &str {
+        self.scheme
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.server_name
+    }
+
+    fn remote_addr(&self) -> Option<std::net::IpAddr> {
+        self.client_ip
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.session.get_header(name)?.to_str().ok()
+    }
+}
+
+// Clones share the runtime state and cache, so several Pingora apps (e.g. the
+// PROXY protocol listeners) can serve the same routes.
+#[derive(Clone)]
 pub struct DynamicProxy {
     state: Arc<RuntimeState>,
-    cache_backend: CacheBackend,
+    cache_backend: Arc<CacheBackend>,
     /// Shared HTTP-01 challenge token store for Let's Encrypt certificate issuance.
     challenge_tokens: ChallengeTokens,
 }
@@ -872,7 +1756,7 @@ impl DynamicProxy {
     pub fn new(state: Arc<RuntimeState>) -> Self {
         Self {
             state,
-            cache_backend: CacheBackend::new(50 * 1024 * 1024), // 50MB default
+            cache_backend: Arc::new(CacheBackend::new(DEFAULT_CACHE_MAX_SIZE)),
             challenge_tokens: Arc::new(dashmap::DashMap::new()),
         }
     }
@@ -880,7 +1764,7 @@ impl DynamicProxy {
     pub fn new_with_cache(state: Arc<RuntimeState>, cache_backend: CacheBackend) -> Self {
         Self {
             state,
-            cache_backend,
+            cache_backend: Arc::new(cache_backend),
             challenge_tokens: Arc::new(dashmap::DashMap::new()),
         }
     }
@@ -898,6 +1782,11 @@ impl DynamicProxy {
         &self.state
     }
 
+    /// Shared response cache, e.g. for the admin API.
+    pub fn cache_backend(&self) -> &Arc<CacheBackend> {
+        &self.cache_backend
+    }
+
     /// Retrieve routes for a specific key (lock-free).
     pub fn get_routes(&self, key: &ListenKey) -> Option<Arc<VirtualHostRoutes>> {
         let snapshot = self.state.snapshot();
@@ -921,6 +1810,13 @@ impl ProxyHttp for DynamicProxy {
         ProxyContext::default()
     }
 
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        // Pingora's default: compression support, disabled until enabled per
+        // request.
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+        modules.add_module(Box::new(RequestIdModuleBuilder));
+    }
+
     // Request plugins run in declaration order and may terminate the request
     // locally before any upstream peer is selected.
     async fn request_filter(
@@ -948,6 +1844,14 @@ impl ProxyHttp for DynamicProxy {
             self.cache_backend.set_default_max_size(global_size);
         }
 
+        if let Some(config) = resolve_request_id(&snapshot.router, session) {
+            let id = crate::request_id::assign(session, config, request_client_ip(session))?;
+            if let Some(span) = ctx.span.as_mut() {
+                span.set_attribute(opentelemetry::KeyValue::new("http.request.id", id.clone()));
+            }
+            ctx.request_id = Some(id);
+        }
+
         if restrict_client_max_body_size(session, ctx).await? {
             return Ok(true);
         }
@@ -958,76 +1862,112 @@ impl ProxyHttp for DynamicProxy {
             .uri
             .path()
             .strip_prefix("/.well-known/acme-challenge/")
+            && !token.is_empty()
+            && !token.contains('/')
+            && let Some(key_auth) = self.challenge_tokens.get(token).map(|v| v.clone())
         {
-            if !token.is_empty() && !token.contains('/') {
-                if let Some(key_auth) = self.challenge_tokens.get(token).map(|v| v.clone()) {
-                    let mut response = LocalResponse::new(http::StatusCode::OK, "");
-                    response.headers.push((
-                        http::header::CONTENT_TYPE,
-                        http::HeaderValue::from_static("text/plain"),
-                    ));
-                    response.body = key_auth.into_bytes().into();
-                    session.set_keepalive(None);
-                    write_local_response(session, response).await?;
-                    return Ok(true);
-                }
-            }
+            let mut response = LocalResponse::new(http::StatusCode::OK, "");
+            response.headers.push((
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/plain"),
+            ));
+            response.body = key_auth.into_bytes().into();
+            session.set_keepalive(None);
+            write_local_response(session, response).await?;
+            return Ok(true);
         }
 
-        let Some((selected, host)) = select_runtime_route(&snapshot, session)? else {
+        let Some((mut selected, host)) = select_runtime_route(&snapshot, session).await? else {
             ctx.selected = None;
             return Ok(false);
         };
 
-        let path = session.req_header().uri.path().to_string();
+        let scheme = if request_is_tls(session) {
+            "https"
+        } else {
+            "http"
+        };
         let method = session.req_header().method.clone();
         let request_was_cacheable = is_cacheable_request(&method, &session.req_header().headers);
         let client_ip = request_client_ip(session);
-        if !location_allows_client(&selected.access_rules, client_ip) {
-            session.set_keepalive(None);
-            session
-                .respond_error(http::StatusCode::FORBIDDEN.into())
-                .await?;
-            return Ok(true);
-        }
-
-        let mut headers = RequestHeaderEditor {
-            inner: session.downstream_session.req_header_mut(),
-        };
-
-        for plugin in selected.plugins.iter() {
-            let flow = plugin
-                .on_request(&mut RequestCtx {
-                    state: &mut ctx.plugin_state,
-                    path: &path,
-                    host: host.as_deref(),
-                    method: &method,
-                    client_ip,
-                    headers: &mut headers,
-                })
-                .await
-                .map_err(|err| map_plugin_error("request_filter", err))?;
-            if let PluginFlow::Respond(response) = flow {
+        let mut plugin_routes = Vec::new();
+        let mut internal_redirects = 0;
+
+        // `try_files` fallbacks re-enter the loop with the route they redirect
+        // to. Every route applies its own access rules, but request plugins run
+        // at most once per route.
+        loop {
+            ctx.route_labels = Some(Arc::clone(&selected.labels));
+            ctx.route_access_logs.clone_from(&selected.access_logs);
+            if !location_allows_client(&selected.access_rules, client_ip) {
                 session.set_keepalive(None);
-                write_local_response(session, response).await?;
+                crate::request_id::respond_error(session, http::StatusCode::FORBIDDEN.into())
+                    .await?;
                 return Ok(true);
             }
-        }
 
-        ctx.selected = Some(selected.clone());
+            if !plugin_routes.contains(&selected.route_id) {
+                plugin_routes.push(selected.route_id);
+
+                let path = session.req_header().uri.path().to_string();
+                let query = session.req_header().uri.query().map(ToString::to_string);
+                let mut headers = RequestHeaderEditor {
+                    inner: session.downstream_session.req_header_mut(),
+                };
+
+                for plugin in selected.plugins.iter() {
+                    let flow = plugin
+                        .on_request(&mut RequestCtx {
+                            state: &mut ctx.plugin_state,
+                            path: &path,
+                            query: query.as_deref(),
+                            scheme,
+                            host: host.as_deref(),
+                            server_name: selected.server_name.as_deref(),
+                            method: &method,
+                            client_ip,
+                            headers: &mut headers,
+                        })
+                        .await
+                        .map_err(|err| {
+                            crate::metrics::record_plugin_error(plugin.name(), "request_filter");
+                            map_plugin_error("request_filter", err)
+                        })?;
+                    if let PluginFlow::Respond(response) = flow {
+                        crate::metrics::record_plugin_response(
+                            plugin.name(),
+                            response.status.as_u16(),
+                            &selected.labels,
+                        );
+                        session.set_keepalive(None);
+                        write_local_response(session, response).await?;
+                        return Ok(true);
+                    }
+                }
+            }
 
-        // Authentication, rate limiting, and other request plugins must run
-        // before a cache hit can terminate the request.
-        if request_was_cacheable
-            && is_cacheable_request(&session.req_header().method, &session.req_header().headers)
-        {
-            if let Some(cache_cfg) = &selected.cache {
-                let full_uri = session.req_header().uri.to_string();
+            ctx.selected = Some(selected.clone());
+
+            // Authentication, rate limiting, and other request plugins must run
+            // before a cache hit can terminate the request.
+            if request_was_cacheable
+                && is_cacheable_request(&session.req_header().method, &session.req_header().headers)
+                && let Some(cache_cfg) = &selected.cache
+            {
+                // Key on the request target: HTTP/2 carries an absolute URI,
+                // and purges match on path and query.
+                let target = session
+                    .req_header()
+                    .uri
+                    .path_and_query()
+                    .map_or("/", |target| target.as_str());
+                let route = route_cache_name(&selected.labels.server, &selected.labels.route);
                 let cache_key = build_cache_key(
                     &session.req_header().method,
-                    &full_uri,
+                    target,
                     snapshot.generation,
                     selected.route_id(),
+                    &route,
                     host.as_deref().unwrap_or(""),
                     cache_cfg,
                 );
@@ -1039,34 +1979,97 @@ impl ProxyHttp for DynamicProxy {
                 ctx.cache_store_allowed = self.cache_backend.record_miss(&cache_key, cache_cfg);
                 ctx.cache_key = Some(cache_key);
             }
-        }
 
-        // Handle return/redirect targets
-        if let SelectedTarget::Return { status, location } = &selected.target {
-            let status_code = http::StatusCode::from_u16(*status).map_err(|_| {
-                pingora::Error::explain(
-                    pingora::ErrorType::InternalError,
-                    format!("invalid redirect status code: {status}"),
-                )
-            })?;
+            let vars = SessionVariables {
+                session,
+                host: host.as_deref(),
+                server_name: selected.server_name.as_deref(),
+                scheme,
+                client_ip,
+            };
 
-            let mut response = LocalResponse::new(status_code, "");
-            response.headers.push((
-                http::header::LOCATION,
-                http::HeaderValue::from_str(location).map_err(|_| {
-                    pingora::Error::explain(
-                        pingora::ErrorType::InternalError,
-                        format!("invalid redirect location: {location}"),
-                    )
-                })?,
-            ));
+            let files = match &selected.target {
+                SelectedTarget::Upstream(_) => return Ok(false),
+                SelectedTarget::Return { status, location } => {
+                    let status_code = http::StatusCode::from_u16(*status).map_err(|_| {
+                        pingora::Error::explain(
+                            pingora::ErrorType::InternalError,
+                            format!("invalid redirect status code: {status}"),
+                        )
+                    })?;
 
-            session.set_keepalive(None);
-            write_local_response(session, response).await?;
-            return Ok(true);
-        }
+                    let location = location.render(&vars);
+                    let mut response = LocalResponse::new(status_code, "");
+                    response.headers.push((
+                        http::header::LOCATION,
+                        http::HeaderValue::from_str(&location).map_err(|_| {
+                            pingora::Error::explain(
+                                pingora::ErrorType::InternalError,
+                                format!("invalid redirect location: {location}"),
+                            )
+                        })?,
+                    ));
+
+                    session.set_keepalive(None);
+                    write_local_response(session, response).await?;
+                    return Ok(true);
+                }
+                SelectedTarget::Static(files) => files,
+            };
 
-        Ok(false)
+            let static_request = StaticRequest::new(files, &vars);
+            let redirect = match static_files::lookup(files, session.req_header(), &static_request)
+                .await?
+            {
+                StaticLookup::Response(mut response) => {
+                    apply_response_plugins(&selected, &mut ctx.plugin_state, &mut response.header)
+                        .await?;
+                    response.write(session).await?;
+                    return Ok(true);
+                }
+                StaticLookup::Redirect(redirect) => redirect,
+            };
+
+            internal_redirects += 1;
+            if internal_redirects > MAX_INTERNAL_REDIRECTS {
+                return Err(pingora::Error::explain(
+                    pingora::ErrorType::InternalError,
+                    "internal redirection cycle while processing try_files",
+                ));
+            }
+
+            selected = match redirect {
+                InternalRedirect::Named(name) => {
+                    let resolved = resolve_named_route(&snapshot.router, session, &name)?
+                        .ok_or_else(|| {
+                            pingora::Error::explain(
+                                pingora::ErrorType::InternalError,
+                                format!("named location `@{name}` is missing at runtime"),
+                            )
+                        })?;
+                    SelectedRoute::from_resolved(&snapshot, session, &resolved).await?
+                }
+                InternalRedirect::Uri(uri) => {
+                    let uri = uri.parse::<http::Uri>().map_err(|_| {
+                        pingora::Error::explain(
+                            pingora::ErrorType::InternalError,
+                            format!("invalid try_files fallback uri `{uri}`"),
+                        )
+                    })?;
+                    session.req_header_mut().set_uri(uri);
+                    let Some((selected, _host)) = select_runtime_route(&snapshot, session).await?
+                    else {
+                        crate::request_id::respond_error(
+                            session,
+                            http::StatusCode::NOT_FOUND.into(),
+                        )
+                        .await?;
+                        return Ok(true);
+                    };
+                    selected
+                }
+            };
+        }
     }
 
     async fn request_body_filter(
@@ -1100,6 +2103,14 @@ impl ProxyHttp for DynamicProxy {
             return Ok(());
         };
 
+        if ctx
+            .upstream_connection
+            .as_ref()
+            .is_some_and(|connection| !connection.reusable)
+        {
+            upstream_request.insert_header(http::header::CONNECTION, "close")?;
+        }
+
         let mut headers = RequestHeaderEditor {
             inner: upstream_request,
         };
@@ -1111,7 +2122,10 @@ impl ProxyHttp for DynamicProxy {
                     headers: &mut headers,
                 })
                 .await
-                .map_err(|err| map_plugin_error("upstream_request_filter", err))?;
+                .map_err(|err| {
+                    crate::metrics::record_plugin_error(plugin.name(), "upstream_request_filter");
+                    map_plugin_error("upstream_request_filter", err)
+                })?;
             respond_from_plugin_flow(flow, "upstream_request_filter")?;
         }
 
@@ -1125,46 +2139,32 @@ impl ProxyHttp for DynamicProxy {
     ///
     /// Only activates when the location has `proxy_cache_stale_if_error`
     /// configured and a cached entry exists (TTL is ignored for stale).
+    /// Otherwise the client gets the error status.
     async fn fail_to_proxy(
         &self,
         session: &mut Session,
-        _error: &pingora::Error,
+        error: &pingora::Error,
         ctx: &mut Self::CTX,
     ) -> pingora_proxy::FailToProxy
     where
         Self::CTX: Send + Sync,
     {
         let Some(selected) = ctx.selected.as_ref() else {
-            return pingora_proxy::FailToProxy {
-                can_reuse_downstream: false,
-                error_code: 502,
-            };
+            return respond_proxy_error(session, error).await;
         };
         let Some(cache_cfg) = selected.cache.as_ref() else {
-            return pingora_proxy::FailToProxy {
-                can_reuse_downstream: false,
-                error_code: 502,
-            };
+            return respond_proxy_error(session, error).await;
         };
         // stale_if_error must be explicitly configured
         if cache_cfg.stale_if_error.is_none() {
-            return pingora_proxy::FailToProxy {
-                can_reuse_downstream: false,
-                error_code: 502,
-            };
+            return respond_proxy_error(session, error).await;
         }
         let Some(cache_key) = &ctx.cache_key else {
-            return pingora_proxy::FailToProxy {
-                can_reuse_downstream: false,
-                error_code: 502,
-            };
+            return respond_proxy_error(session, error).await;
         };
 
         let Some(mut cached) = self.cache_backend.get_stale(cache_key, cache_cfg).await else {
-            return pingora_proxy::FailToProxy {
-                can_reuse_downstream: false,
-                error_code: 502,
-            };
+            return respond_proxy_error(session, error).await;
         };
 
         cached.headers.insert(
@@ -1186,73 +2186,157 @@ impl ProxyHttp for DynamicProxy {
         }
     }
 
-    // Response plugins run in reverse order so they behave like unwind-style
-    // middleware around the upstream exchange.
-    async fn response_filter(
+    /// Hands 502/503/504 responses covered by `proxy_next_upstream` back to
+    /// Pingora as retryable errors before anything reaches the client.
+    async fn upstream_response_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> PingoraResult<()> {
-        let Some(selected) = ctx.selected.as_ref() else {
+        let status = upstream_response.status.as_u16();
+        report_upstream_outcome(ctx, status < 500);
+        ctx.upstream_status = Some(status);
+        ctx.upstream_ttfb = ctx
+            .upstream_attempt_started
+            .map(|started| started.elapsed());
+        if let Some((upstream, backend)) = upstream_attempt_labels(ctx) {
+            crate::metrics::record_upstream_response(upstream, &backend, status, ctx.upstream_ttfb);
+        }
+        if let Some(connection) = ctx.upstream_connection.as_mut()
+            && !upstream_response_keeps_connection(upstream_response)
+        {
+            connection.reusable = false;
+        }
+        if !matches!(status, 502..=504)
+            || !next_upstream(
+                ctx,
+                &session.req_header().method,
+                session.as_ref().retry_buffer_truncated(),
+                UpstreamFailure::Status(status),
+                true,
+            )
+        {
             return Ok(());
-        };
+        }
 
-        let mut status = upstream_response.status;
-        {
-            let mut headers = ResponseHeaderEditor {
-                inner: upstream_response,
-            };
+        let mut err = pingora::Error::explain(
+            pingora::ErrorType::HTTPStatus(status),
+            format!("upstream responded with {status}, trying the next one"),
+        );
+        err.set_retry(true);
+        Err(err)
+    }
 
-            for plugin in selected.plugins.iter().rev() {
-                let flow = plugin
-                    .on_response(&mut ResponseCtx {
-                        state: &mut ctx.plugin_state,
-                        status: &mut status,
-                        headers: &mut headers,
-                    })
-                    .await
-                    .map_err(|err| map_plugin_error("response_filter", err))?;
-                respond_from_plugin_flow(flow, "response_filter")?;
-            }
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        report_upstream_outcome(ctx, false);
+        record_upstream_error(ctx, "connect");
+        if next_upstream(
+            ctx,
+            &session.req_header().method,
+            session.as_ref().retry_buffer_truncated(),
+            UpstreamFailure::of_connect(&e),
+            false,
+        ) {
+            e.set_retry(true);
         }
+        e
+    }
 
-        upstream_response.set_status(status).map_err(|err| {
-            pingora::Error::explain(
-                pingora::ErrorType::InternalError,
-                format!("failed to update response status from plugin chain: {err}"),
+    // Keeps Pingora's retry of stale pooled connections and adds
+    // `proxy_next_upstream` for failures before the response started.
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        let body_truncated = session.as_ref().retry_buffer_truncated();
+        e.retry.decide_reuse(client_reused && !body_truncated);
+
+        // A stale pooled connection is retried as is and says nothing about
+        // the backend; status errors were judged in `upstream_response_filter`.
+        if e.retry()
+            || e.esource != pingora::ErrorSource::Upstream
+            || matches!(e.etype, pingora::ErrorType::HTTPStatus(_))
+        {
+            return e;
+        }
+        report_upstream_outcome(ctx, false);
+        let failure = UpstreamFailure::of_proxy(&e);
+        record_upstream_error(
+            ctx,
+            if failure == UpstreamFailure::Timeout {
+                "timeout"
+            } else {
+                "error"
+            },
+        );
+
+        if session.as_ref().response_written().is_none()
+            && next_upstream(
+                ctx,
+                &session.req_header().method,
+                body_truncated,
+                failure,
+                true,
             )
-        })?;
+        {
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()> {
+        let Some(selected) = ctx.selected.as_ref() else {
+            return Ok(());
+        };
+
+        apply_response_plugins(selected, &mut ctx.plugin_state, upstream_response).await?;
+        let status = upstream_response.status;
 
         // Cacheability must be evaluated against the final response that the
         // client will actually receive after plugins mutate headers/status.
-        if let (Some(_cache_key), Some(cache_cfg)) = (&ctx.cache_key, selected.cache.as_ref()) {
-            if cache_store_allowed(cache_cfg, ctx.cache_store_allowed)
-                && is_cacheable(status, &upstream_response.headers, cache_cfg)
-            {
-                let entry_overhead =
-                    estimated_headers_size(&upstream_response.headers).saturating_add(128);
-                let body_limit = self
-                    .cache_backend
-                    .max_size(cache_cfg)
-                    .saturating_sub(entry_overhead);
-                let content_length_fits = upstream_response
-                    .headers
-                    .get(http::header::CONTENT_LENGTH)
-                    .map(|value| {
-                        value
-                            .to_str()
-                            .ok()
-                            .and_then(|value| value.parse::<u64>().ok())
-                            .is_some_and(|length| length <= body_limit)
-                    })
-                    .unwrap_or(true);
+        if let (Some(_cache_key), Some(cache_cfg)) = (&ctx.cache_key, selected.cache.as_ref())
+            && cache_store_allowed(cache_cfg, ctx.cache_store_allowed)
+            && is_cacheable(status, &upstream_response.headers, cache_cfg)
+        {
+            let entry_overhead =
+                estimated_headers_size(&upstream_response.headers).saturating_add(128);
+            let body_limit = self
+                .cache_backend
+                .max_size(cache_cfg)
+                .saturating_sub(entry_overhead);
+            let content_length_fits = upstream_response
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .is_some_and(|length| length <= body_limit)
+                })
+                .unwrap_or(true);
 
-                if content_length_fits {
-                    ctx.cache_status = Some(status);
-                    ctx.cache_headers = Some(upstream_response.headers.clone());
-                    ctx.cache_body_limit = Some(body_limit);
-                }
+            if content_length_fits {
+                ctx.cache_status = Some(status);
+                ctx.cache_headers = Some(upstream_response.headers.clone());
+                ctx.cache_body_limit = Some(body_limit);
             }
         }
 
@@ -1294,6 +2378,12 @@ impl ProxyHttp for DynamicProxy {
         e: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
+        if let Some(connection) = ctx.upstream_connection.take() {
+            connection
+                .group
+                .release_connection(connection.key, connection.reusable && e.is_none());
+        }
+
         // ── Collect observability data ──
         let method = session.req_header().method.to_string();
         let path = session.req_header().uri.path().to_string();
@@ -1307,7 +2397,7 @@ impl ProxyHttp for DynamicProxy {
         let latency = ctx.start_time.elapsed();
         let upstream = ctx.selected.as_ref().and_then(|s| match &s.target {
             SelectedTarget::Upstream(peer) => Some(format!("{}:{}", peer.host, peer.port)),
-            SelectedTarget::Return { .. } => None,
+            SelectedTarget::Return { .. } | SelectedTarget::Static(_) => None,
         });
         let route_id = ctx.selected.as_ref().map(|s| s.route_id());
 
@@ -1315,7 +2405,7 @@ impl ProxyHttp for DynamicProxy {
         // when a cache key exists but we went upstream, bypass otherwise.
         let had_upstream = !matches!(
             ctx.selected.as_ref().map(|s| &s.target),
-            Some(SelectedTarget::Return { .. }) | None
+            Some(SelectedTarget::Return { .. } | SelectedTarget::Static(_)) | None
         ) && e.is_none();
         let cache_status = if ctx.cache_hit {
             "hit"
@@ -1348,17 +2438,23 @@ impl ProxyHttp for DynamicProxy {
             span.end();
         }
 
-        // ── Write structured JSON access log ──
+        // ── Write access logs ──
         if method != "GET" || path != "/metrics" {
-            crate::metrics::write_access_log(
+            write_access_logs(
+                &self.state.snapshot().router.http_options,
                 session,
-                &method,
-                &path,
-                status,
-                Some(latency),
-                upstream.as_deref(),
-                Some(cache_status),
-                route_id,
+                ctx,
+                AccessLogRecord {
+                    time: Some(std::time::SystemTime::now()),
+                    method: &method,
+                    path: &path,
+                    status,
+                    latency: Some(latency),
+                    upstream: upstream.as_deref(),
+                    cache_status: Some(cache_status),
+                    route_id,
+                    ..AccessLogRecord::default()
+                },
             );
         }
 
@@ -1368,13 +2464,14 @@ impl ProxyHttp for DynamicProxy {
             "miss" => crate::metrics::CacheStatus::Miss,
             _ => crate::metrics::CacheStatus::Bypass,
         };
+        let route_labels = ctx.route_labels.clone().unwrap_or_default();
         crate::metrics::record_metrics(
             &crate::metrics::RequestLabels {
                 method: method.clone(),
                 status: status.to_string(),
                 cache_status: cache_metric_status,
                 has_upstream: had_upstream,
-                route_id: route_id.unwrap_or(0),
+                route: &route_labels,
             },
             latency.as_secs_f64(),
             0,
@@ -1420,6 +2517,64 @@ impl ProxyHttp for DynamicProxy {
         }
     }
 
+    // Counts pool hits per group and applies the group's `keepalive_conns`
+    // limits. Multiplexed (HTTP/2) connections are shared by concurrent
+    // requests and only get the idle timeout.
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        #[cfg(unix)] fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] sock: std::os::windows::io::RawSocket,
+        _digest: Option<&pingora::protocols::Digest>,
+        ctx: &mut Self::CTX,
+    ) -> PingoraResult<()> {
+        #[cfg(unix)]
+        let key = fd as ConnectionKey;
+        #[cfg(windows)]
+        let key = sock as ConnectionKey;
+
+        // A retry replaces the connection of the failed attempt.
+        if let Some(previous) = ctx.upstream_connection.take() {
+            previous.group.release_connection(previous.key, false);
+        }
+        // Reused connections log a zero connect time, as in nginx.
+        ctx.upstream_connect_time = ctx.upstream_attempt_started.map(|started| {
+            if reused {
+                std::time::Duration::ZERO
+            } else {
+                started.elapsed()
+            }
+        });
+        if !reused
+            && let (Some(connect_time), Some((upstream, backend))) =
+                (ctx.upstream_connect_time, upstream_attempt_labels(ctx))
+        {
+            crate::metrics::record_upstream_connect(upstream, &backend, connect_time);
+        }
+        let Some(selected) = ctx.selected.as_ref() else {
+            return Ok(());
+        };
+        let Some(group) = selected.upstream_group.as_ref() else {
+            return Ok(());
+        };
+        crate::metrics::record_upstream_connection(group.group.name(), reused);
+        if matches!(
+            selected.upstream_protocol,
+            Some(UpstreamHttpProtocol::H2 | UpstreamHttpProtocol::H2c)
+        ) {
+            return Ok(());
+        }
+
+        ctx.upstream_connection = Some(UpstreamConnection {
+            group: Arc::clone(&group.group),
+            key,
+            reusable: group.group.checkout_connection(key, reused),
+        });
+        Ok(())
+    }
+
     // Upstream selection is derived from the already resolved route; if the
     // request_filter path did not run, we resolve lazily here as a fallback.
     async fn upstream_peer(
@@ -1431,28 +2586,39 @@ impl ProxyHttp for DynamicProxy {
             selected
         } else {
             let snapshot = self.state.snapshot();
-            let Some((selected, _host)) = select_runtime_route(&snapshot, session)? else {
+            let Some((selected, _host)) = select_runtime_route(&snapshot, session).await? else {
                 return Err(pingora::Error::explain(
                     pingora::ErrorType::HTTPStatus(404),
                     "no location matched",
                 ));
             };
+            ctx.route_labels = Some(Arc::clone(&selected.labels));
+            ctx.route_access_logs.clone_from(&selected.access_logs);
             ctx.selected = Some(selected.clone());
             selected
         };
 
         let peer = match &selected.target {
-            SelectedTarget::Upstream(peer) => peer,
-            SelectedTarget::Return { .. } => {
+            SelectedTarget::Upstream(peer) => {
+                let now = std::time::Instant::now();
+                ctx.upstream_started.get_or_insert(now);
+                ctx.upstream_attempt_started = Some(now);
+                peer
+            }
+            SelectedTarget::Return { .. } | SelectedTarget::Static(_) => {
                 return Err(pingora::Error::explain(
                     pingora::ErrorType::InternalError,
-                    "upstream_peer called on a return target — request_filter should have short-circuited",
+                    "upstream_peer called on a local target — request_filter should have short-circuited",
                 ));
             }
         };
 
         let mut http_peer =
             HttpPeer::new((peer.host.as_str(), peer.port), peer.tls, peer.sni.clone());
+        if let Some(group) = &selected.upstream_group {
+            http_peer.group_key = group.group.pool_key();
+            http_peer.options.idle_timeout = group.group.idle_timeout;
+        }
         apply_upstream_timeouts(&mut http_peer, selected.upstream_timeouts);
         apply_upstream_http_protocol(&mut http_peer, selected.upstream_protocol);
         apply_upstream_ssl_options(
@@ -1471,7 +2637,7 @@ mod tests {
     use super::*;
     use http::StatusCode;
     use ipnet::IpNet;
-    use ngxora_compile::ir::LocationIpRule;
+    use ngxora_compile::ir::{LocationIpRule, UpstreamKeepalive};
     use ngxora_plugin_api::{HttpPlugin, PluginFlow, async_trait, empty_plugin_chain};
     use std::sync::Arc;
     use tokio::io::{AsyncWriteExt, duplex};
@@ -1491,6 +2657,9 @@ mod tests {
     fn cached_route(cache: CacheConfig, plugins: ngxora_plugin_api::PluginChain) -> SelectedRoute {
         SelectedRoute {
             route_id: 1,
+            server_name: None,
+            labels: Default::default(),
+            access_logs: Vec::new(),
             access_rules: Vec::new(),
             target: SelectedTarget::Upstream(SelectedPeer {
                 host: "127.0.0.1".into(),
@@ -1505,6 +2674,9 @@ mod tests {
             upstream_client_identity: None,
             plugins,
             cache: Some(cache),
+            upstream_retry: UpstreamRetry::default(),
+            upstream_group: None,
+            _active_request: None,
         }
     }
 
@@ -1524,6 +2696,35 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn session_variables_render_return_location() {
+        let (mut client, server) = duplex(1024);
+        client
+            .write_all(
+                b"GET /old/page?id=7 HTTP/1.1\r\nHost: Example.com\r\nX-Tenant: acme\r\n\r\n",
+            )
+            .await
+            .expect("write request");
+        let mut session = Session::new_h1(Box::new(server));
+        session.read_request().await.expect("read request");
+
+        let template =
+            Template::parse("$scheme://$host$request_uri?tenant=$http_x_tenant&id=$arg_id")
+                .expect("template parses");
+        let rendered = template.render(&SessionVariables {
+            session: &session,
+            host: Some("example.com"),
+            server_name: Some("example.com"),
+            scheme: "https",
+            client_ip: None,
+        });
+
+        assert_eq!(
+            rendered,
+            "https://example.com/old/page?id=7?tenant=acme&id=7"
+        );
+    }
+
     #[tokio::test]
     async fn response_body_filter_preserves_downstream_body() {
         let proxy = DynamicProxy::from_router(CompiledRouter::default());
@@ -1609,6 +2810,7 @@ mod tests {
             cache_key: Some(CacheKey {
                 generation: 1,
                 route_id: 1,
+                route: "_ /1".into(),
                 host: "localhost".into(),
                 method: "GET".into(),
                 uri: "/".into(),
@@ -1639,6 +2841,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "localhost".into(),
             method: "GET".into(),
             uri: "/partial".into(),
@@ -1656,7 +2859,7 @@ mod tests {
         ProxyHttp::logging(&proxy, &mut session, Some(err.as_ref()), &mut ctx).await;
 
         assert!(proxy.cache_backend.get(&key, &cache_cfg).await.is_none());
-        assert_eq!(proxy.cache_backend.total_entries(), 0);
+        assert_eq!(proxy.cache_backend.total_entries().await, 0);
         assert!(ctx.response_body_buf.is_empty());
     }
 
@@ -1675,6 +2878,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "localhost".into(),
             method: "GET".into(),
             uri: "/redirect".into(),
@@ -1716,6 +2920,7 @@ mod tests {
         let key = CacheKey {
             generation: 1,
             route_id: 1,
+            route: "_ /1".into(),
             host: "localhost".into(),
             method: "GET".into(),
             uri: "/warming".into(),
@@ -1772,4 +2977,379 @@ mod tests {
         let err = pingora::Error::explain(pingora::ErrorType::InternalError, "boom");
         assert!(should_mark_span_as_error(Some(err.as_ref()), 0));
     }
+
+    fn retry_ctx(ports: &[u16], retry: UpstreamRetry) -> ProxyContext {
+        let group = RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+            name: "backend".into(),
+            policy: UpstreamSelectionPolicy::RoundRobin,
+            servers: ports
+                .iter()
+                .map(|&port| CompiledUpstreamServer {
+                    host: "127.0.0.1".into(),
+                    port,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
+                })
+                .collect(),
+            health_check: None,
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
+        })
+        .expect("runtime group builds");
+
+        let mut route = cached_route(CacheConfig::default(), empty_plugin_chain());
+        route.cache = None;
+        route.target = SelectedTarget::Upstream(SelectedPeer {
+            host: "127.0.0.1".into(),
+            port: ports[0],
+            tls: false,
+            sni: String::new(),
+        });
+        route.upstream_retry = retry;
+        route.upstream_group = Some(SelectedGroup {
+            group: Arc::new(group),
+            key: String::new(),
+            tls: false,
+        });
+
+        ProxyContext {
+            selected: Some(route),
+            ..ProxyContext::default()
+        }
+    }
+
+    fn selected_port(ctx: &ProxyContext) -> u16 {
+        match &ctx.selected.as_ref().expect("route").target {
+            SelectedTarget::Upstream(peer) => peer.port,
+            _ => panic!("upstream target expected"),
+        }
+    }
+
+    fn on_errors() -> UpstreamRetry {
+        UpstreamRetry {
+            next_upstream: NextUpstream {
+                error: true,
+                http_502: true,
+                ..NextUpstream::default()
+            },
+            ..UpstreamRetry::default()
+        }
+    }
+
+    #[test]
+    fn next_upstream_moves_to_an_untried_backend() {
+        let mut ctx = retry_ctx(&[8080, 8081, 8082], on_errors());
+        let get = http::Method::GET;
+
+        assert!(next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+        assert_eq!(selected_port(&ctx), 8081);
+        assert!(next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Status(502),
+            true
+        ));
+        assert_eq!(selected_port(&ctx), 8082);
+        assert!(!next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            true
+        ));
+        assert_eq!(
+            ctx.failed_upstreams,
+            vec![
+                ("127.0.0.1".to_string(), 8080),
+                ("127.0.0.1".to_string(), 8081),
+                ("127.0.0.1".to_string(), 8082),
+            ]
+        );
+    }
+
+    #[test]
+    fn next_upstream_only_retries_enabled_failures() {
+        let get = http::Method::GET;
+        for failure in [
+            UpstreamFailure::Timeout,
+            UpstreamFailure::Status(503),
+            UpstreamFailure::Status(500),
+        ] {
+            let mut ctx = retry_ctx(&[8080, 8081], on_errors());
+            assert!(!next_upstream(&mut ctx, &get, false, failure, true));
+            assert_eq!(selected_port(&ctx), 8080);
+        }
+
+        let mut ctx = retry_ctx(&[8080, 8081], UpstreamRetry::default());
+        assert!(!next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+    }
+
+    #[test]
+    fn next_upstream_keeps_sent_non_idempotent_requests() {
+        let post = http::Method::POST;
+
+        let mut ctx = retry_ctx(&[8080, 8081], on_errors());
+        assert!(!next_upstream(
+            &mut ctx,
+            &post,
+            false,
+            UpstreamFailure::Error,
+            true
+        ));
+        // Nothing reached the backend when the connection failed.
+        assert!(next_upstream(
+            &mut ctx,
+            &post,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+
+        let mut retry = on_errors();
+        retry.next_upstream.non_idempotent = true;
+        let mut ctx = retry_ctx(&[8080, 8081], retry);
+        assert!(next_upstream(
+            &mut ctx,
+            &post,
+            false,
+            UpstreamFailure::Error,
+            true
+        ));
+
+        // A body too large to replay cannot be sent again.
+        let mut ctx = retry_ctx(&[8080, 8081], retry);
+        assert!(!next_upstream(
+            &mut ctx,
+            &post,
+            true,
+            UpstreamFailure::Error,
+            false
+        ));
+    }
+
+    #[test]
+    fn next_upstream_stops_at_tries_and_timeout() {
+        let get = http::Method::GET;
+
+        let mut retry = on_errors();
+        retry.tries = 2;
+        let mut ctx = retry_ctx(&[8080, 8081, 8082], retry);
+        assert!(next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+        assert!(!next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+        assert_eq!(selected_port(&ctx), 8081);
+
+        let mut retry = on_errors();
+        retry.timeout = Some(Duration::from_millis(10));
+        let mut ctx = retry_ctx(&[8080, 8081], retry);
+        ctx.upstream_started = Some(std::time::Instant::now() - Duration::from_millis(20));
+        assert!(!next_upstream(
+            &mut ctx,
+            &get,
+            false,
+            UpstreamFailure::Error,
+            false
+        ));
+    }
+
+    /// Serves each `next_upstream` case through a real proxy: `dead` refuses
+    /// connections, `closer` hangs up after reading the request (after
+    /// `delay`), and the `live` backup answers 200 and counts requests.
+    async fn retry_proxy(delay: Duration) -> (u16, Arc<std::sync::atomic::AtomicUsize>) {
+        use pingora::apps::ServerApp;
+        use pingora::protocols::l4::stream::Stream as L4Stream;
+        use pingora::protocols::{GetSocketDigest, SocketDigest};
+        use std::os::fd::AsRawFd;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let closer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closer_port = closer.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, _) = closer.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let _ = tcp.read(&mut [0; 4096]).await;
+                    tokio::time::sleep(delay).await;
+                });
+            }
+        });
+
+        let served = Arc::new(AtomicUsize::new(0));
+        let live = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live_port = live.local_addr().unwrap().port();
+        let counter = Arc::clone(&served);
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, _) = live.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = tcp.read(&mut [0; 4096]).await;
+                    let _ = tcp
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                        )
+                        .await;
+                });
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = format!(
+            r#"
+http {{
+  upstream refused {{
+    server 127.0.0.1:{dead};
+    server 127.0.0.1:{live_port} backup;
+  }}
+  upstream closed {{
+    server 127.0.0.1:{closer_port};
+    server 127.0.0.1:{live_port} backup;
+  }}
+  server {{
+    listen 127.0.0.1:{port};
+    location /refused {{
+      proxy_pass http://refused;
+      proxy_next_upstream error;
+    }}
+    location /refused-once {{
+      proxy_pass http://refused;
+      proxy_next_upstream error;
+      proxy_next_upstream_tries 1;
+    }}
+    location /closed {{
+      proxy_pass http://closed;
+      proxy_next_upstream error;
+      proxy_next_upstream_timeout 100ms;
+    }}
+  }}
+}}
+"#
+        );
+        let ast = ngxora_config::Ast::parse_config(&config).expect("config parses");
+        let ir = ngxora_compile::ir::Ir::from_ast(&ast).expect("config lowers");
+        let router = CompiledRouter::from_http(&ir.http.unwrap()).expect("router compiles");
+        let state = Arc::new(RuntimeState::new(ConfigSnapshot::new("v1", router)));
+        let proxy = Arc::new(pingora_proxy::http_proxy(
+            &Arc::new(pingora::server::configuration::ServerConf::default()),
+            DynamicProxy::new(state),
+        ));
+
+        tokio::spawn(async move {
+            let (_stop, shutdown) = tokio::sync::watch::channel(false);
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                let digest = SocketDigest::from_raw_fd(tcp.as_raw_fd());
+                let mut stream = L4Stream::from(tcp);
+                stream.set_socket_digest(digest);
+                let proxy = Arc::clone(&proxy);
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    proxy.process_new(Box::new(stream), &shutdown).await;
+                });
+            }
+        });
+
+        (port, served)
+    }
+
+    async fn proxied_status(port: u16, request: &str) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut tcp = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        tcp.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        tcp.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn proxy_retries_failed_backends_within_limits() {
+        use std::sync::atomic::Ordering;
+
+        let get = |path: &str| {
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        };
+
+        let (port, served) = retry_proxy(Duration::ZERO).await;
+        // A refused connection moves on to the next backend.
+        assert_eq!(
+            proxied_status(port, &get("/refused")).await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+        // ... unless the tries are used up.
+        assert_eq!(
+            proxied_status(port, &get("/refused-once")).await,
+            "HTTP/1.1 502 Bad Gateway"
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+        // A backend closing mid-request is retried for idempotent requests ...
+        assert_eq!(
+            proxied_status(port, &get("/closed")).await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+        // ... but not once a POST may have been processed.
+        assert_eq!(
+            proxied_status(
+                port,
+                "POST /closed HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello",
+            )
+            .await,
+            "HTTP/1.1 502 Bad Gateway"
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+
+        // Past `proxy_next_upstream_timeout` the failure reaches the client.
+        let (port, served) = retry_proxy(Duration::from_millis(200)).await;
+        assert_eq!(
+            proxied_status(port, &get("/closed")).await,
+            "HTTP/1.1 502 Bad Gateway"
+        );
+        assert_eq!(served.load(Ordering::SeqCst), 0);
+    }
 }
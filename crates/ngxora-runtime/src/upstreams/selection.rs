@@ -0,0 +1,141 @@
+// Upstream selection pieces Pingora does not ship: least-connections
+// balancing and stable ring positions for consistent hashing.
+
+use super::types::CompiledUpstreamServer;
+use pingora::lb::Backend;
+use pingora::lb::selection::{BackendIter, BackendSelection};
+use std::collections::BTreeSet;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Notify;
+
+/// In-flight request counter shared by every copy of one backend.
+#[derive(Debug, Clone, Default)]
+pub(super) struct ActiveRequests(Arc<AtomicUsize>);
+
+impl ActiveRequests {
+    pub(super) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request unless `max` are already in flight. `released` is
+    /// woken when the request is done.
+    pub(super) fn acquire(
+        &self,
+        max: Option<usize>,
+        released: Option<&Arc<Notify>>,
+    ) -> Option<ActiveRequest> {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (max.is_none_or(|max| active < max)).then_some(active + 1)
+            })
+            .ok()?;
+        Some(ActiveRequest {
+            active: Arc::clone(&self.0),
+            released: released.cloned(),
+        })
+    }
+}
+
+/// Counts one request against a backend until it is dropped.
+#[derive(Debug)]
+pub(crate) struct ActiveRequest {
+    active: Arc<AtomicUsize>,
+    released: Option<Arc<Notify>>,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some(released) = &self.released {
+            released.notify_one();
+        }
+    }
+}
+
+fn active_requests(backend: &Backend) -> usize {
+    backend
+        .ext
+        .get::<ActiveRequests>()
+        .map_or(0, ActiveRequests::get)
+}
+
+/// Picks the backend with the fewest in-flight requests per unit of weight.
+/// Ties rotate, so an idle group is walked round-robin.
+pub(super) struct LeastConnections {
+    backends: Box<[Backend]>,
+    next: AtomicUsize,
+}
+
+impl BackendSelection for LeastConnections {
+    type Iter = LeastConnectionsIter;
+    type Config = ();
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self {
+            backends: backends.iter().cloned().collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        let len = self.backends.len().max(1);
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let load: Vec<usize> = self.backends.iter().map(active_requests).collect();
+
+        // Comparing `load[a] / weight[a]` with `load[b] / weight[b]` without
+        // division. The sort is stable, so ties keep the rotated order.
+        let mut order: Vec<usize> = (0..self.backends.len())
+            .map(|offset| (start + offset) % len)
+            .collect();
+        order.sort_by(|&a, &b| {
+            let a_load = load[a].saturating_mul(self.backends[b].weight);
+            let b_load = load[b].saturating_mul(self.backends[a].weight);
+            a_load.cmp(&b_load)
+        });
+
+        LeastConnectionsIter {
+            selection: Arc::clone(self),
+            order: order.into_iter(),
+        }
+    }
+}
+
+pub(super) struct LeastConnectionsIter {
+    selection: Arc<LeastConnections>,
+    order: std::vec::IntoIter<usize>,
+}
+
+impl BackendIter for LeastConnectionsIter {
+    fn next(&mut self) -> Option<&Backend> {
+        let index = self.order.next()?;
+        self.selection.backends.get(index)
+    }
+}
+
+/// Address standing in for `server` on a ketama ring. It is derived from the
+/// server's `host:port` rather than its position in the block, so adding or
+/// removing a server leaves every other server's points in place.
+/// `occurrence` separates servers listed more than once.
+pub(super) fn ring_addr(server: &CompiledUpstreamServer, occurrence: u16) -> SocketAddr {
+    // FNV-1a: stable across builds and replicas, unlike the std hasher.
+    let hash = server
+        .to_string()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    let ip = Ipv6Addr::new(
+        0xfd01,
+        0,
+        0,
+        0,
+        (hash >> 48) as u16,
+        (hash >> 32) as u16,
+        (hash >> 16) as u16,
+        hash as u16,
+    );
+
+    SocketAddr::V6(SocketAddrV6::new(ip, occurrence.saturating_add(1), 0, 0))
+}
@@ -0,0 +1,161 @@
+// Slow start: a server that comes back after failing its health checks gets
+// a growing share of its traffic instead of all of it at once, like nginx's
+// `server ... slow_start=<time>`.
+
+use super::types::CompiledUpstreamServer;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// A server's full share of its traffic, in thousandths.
+const FULL_SHARE: u32 = 1000;
+
+/// Ramp state for one `host:port`, shared by every copy of it in the group.
+#[derive(Debug)]
+pub(super) struct BackendRamp {
+    duration: Duration,
+    /// Health seen by the last health check.
+    healthy: AtomicBool,
+    /// When the server last turned healthy again; `None` once ramped up.
+    recovered_at: Mutex<Option<Instant>>,
+    /// Accumulated share; the server takes a request per `FULL_SHARE`.
+    credit: AtomicU32,
+}
+
+impl BackendRamp {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            healthy: AtomicBool::new(true),
+            recovered_at: Mutex::new(None),
+            credit: AtomicU32::new(0),
+        }
+    }
+
+    /// Records the result of a health check run. A server that turns healthy
+    /// again starts ramping.
+    pub(super) fn observe(&self, healthy: bool, now: Instant) {
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        if healthy && !was_healthy {
+            *self.recovered_at.lock().expect("slow start lock poisoned") = Some(now);
+            self.credit.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the server is still ramping up.
+    pub(super) fn ramping(&self, now: Instant) -> bool {
+        self.share(now).is_some()
+    }
+
+    /// Whether the server takes the request it is offered. While ramping,
+    /// it takes a growing share of the requests: none right after recovering,
+    /// all of them once `slow_start` has passed.
+    pub(super) fn accepts(&self, now: Instant) -> bool {
+        let Some(share) = self.share(now) else {
+            return true;
+        };
+        let mut accepted = false;
+        let _ = self
+            .credit
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |credit| {
+                let credit = credit + share;
+                accepted = credit >= FULL_SHARE;
+                Some(if accepted {
+                    credit - FULL_SHARE
+                } else {
+                    credit
+                })
+            });
+        accepted
+    }
+
+    /// Current share of the full weight in thousandths, `None` once ramped up.
+    fn share(&self, now: Instant) -> Option<u32> {
+        let mut recovered_at = self.recovered_at.lock().expect("slow start lock poisoned");
+        let elapsed = now.saturating_duration_since((*recovered_at)?);
+        if elapsed >= self.duration {
+            *recovered_at = None;
+            return None;
+        }
+        let share = elapsed.as_millis() * u128::from(FULL_SHARE) / self.duration.as_millis().max(1);
+        Some(u32::try_from(share).unwrap_or(FULL_SHARE).max(1))
+    }
+}
+
+/// Ramp state of a group's `slow_start` servers.
+pub(super) struct SlowStart {
+    /// Follows the group's backend set, which changes with DNS discovery.
+    backends: RwLock<HashMap<(String, u16), Arc<BackendRamp>>>,
+}
+
+impl SlowStart {
+    /// `None` unless some server has `slow_start`.
+    pub(super) fn new(servers: &[CompiledUpstreamServer]) -> Option<Self> {
+        servers
+            .iter()
+            .any(|server| server.slow_start.is_some())
+            .then(|| Self {
+                backends: RwLock::default(),
+            })
+    }
+
+    /// Ramp state of `server`, `None` without `slow_start`.
+    pub(super) fn backend(&self, server: &CompiledUpstreamServer) -> Option<Arc<BackendRamp>> {
+        let duration = server.slow_start?;
+        Some(Arc::clone(
+            self.backends
+                .write()
+                .expect("slow start lock poisoned")
+                .entry((server.host.clone(), server.port))
+                .or_insert_with(|| Arc::new(BackendRamp::new(duration))),
+        ))
+    }
+
+    /// Forgets backends that left the group.
+    pub(super) fn retain<'a>(&self, servers: impl Iterator<Item = &'a CompiledUpstreamServer>) {
+        let current: HashSet<(&str, u16)> = servers
+            .map(|server| (server.host.as_str(), server.port))
+            .collect();
+        self.backends
+            .write()
+            .expect("slow start lock poisoned")
+            .retain(|(host, port), _| current.contains(&(host.as_str(), *port)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovered_backend_takes_a_growing_share() {
+        let ramp = BackendRamp::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert!(ramp.accepts(now));
+
+        ramp.observe(false, now);
+        ramp.observe(true, now);
+        assert!(ramp.ramping(now));
+        assert!(!ramp.accepts(now));
+
+        let accepted = |at: Instant| (0..100).filter(|_| ramp.accepts(at)).count();
+        assert_eq!(accepted(now + Duration::from_secs(2)), 20);
+        assert_eq!(accepted(now + Duration::from_secs(5)), 50);
+        assert_eq!(accepted(now + Duration::from_secs(10)), 100);
+        assert!(!ramp.ramping(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn only_a_recovery_starts_the_ramp() {
+        let ramp = BackendRamp::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        ramp.observe(true, now);
+        assert!(!ramp.ramping(now));
+        ramp.observe(false, now);
+        assert!(!ramp.ramping(now));
+        ramp.observe(true, now);
+        assert!(ramp.ramping(now));
+    }
+}
@@ -0,0 +1,809 @@
+use super::types::{StaticFallback, StaticFiles, StaticRoot};
+use bytes::Bytes;
+use http::{HeaderValue, Method, StatusCode, header};
+use ngxora_compile::variables::VariableSource;
+use percent_encoding::percent_decode_str;
+use pingora::Result as PingoraResult;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora_proxy::Session;
+use std::fs::Metadata;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+// Outcome of resolving a request against a static location: either a response
+// ready to be written, or an internal redirect requested by `try_files`.
+pub(super) enum StaticLookup {
+    Response(StaticResponse),
+    Redirect(InternalRedirect),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(super) enum InternalRedirect {
+    Named(String),
+    Uri(String),
+}
+
+// The response header is exposed so the location's response plugins can edit it
+// before anything is written downstream.
+pub(super) struct StaticResponse {
+    pub(super) header: Box<ResponseHeader>,
+    body: Option<FileBody>,
+}
+
+struct FileBody {
+    file: File,
+    offset: u64,
+    len: u64,
+}
+
+impl StaticResponse {
+    pub(super) async fn write(self, session: &mut Session) -> PingoraResult<()> {
+        let Some(mut body) = self.body else {
+            return session.write_response_header(self.header, true).await;
+        };
+
+        session.write_response_header(self.header, false).await?;
+        body.file
+            .seek(SeekFrom::Start(body.offset))
+            .await
+            .map_err(|err| io_error("seek static file", err))?;
+
+        let mut remaining = body.len;
+        while remaining > 0 {
+            let chunk_len = remaining.min(READ_CHUNK_SIZE);
+            let mut chunk = vec![0; chunk_len as usize];
+            body.file
+                .read_exact(&mut chunk)
+                .await
+                .map_err(|err| io_error("read static file", err))?;
+            remaining -= chunk_len;
+            session
+                .write_response_body(Some(Bytes::from(chunk)), remaining == 0)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Request values a static lookup depends on. Templates are rendered up front
+// so the lookup future does not borrow the session.
+pub(super) struct StaticRequest {
+    path: String,
+    query: Option<String>,
+    try_files: Vec<String>,
+    fallback: Option<StaticFallbackAction>,
+}
+
+enum StaticFallbackAction {
+    Status(StatusCode),
+    Redirect(InternalRedirect),
+}
+
+impl StaticRequest {
+    pub(super) fn new(files: &StaticFiles, vars: &dyn VariableSource) -> Self {
+        let try_files = files.try_files.as_ref();
+
+        Self {
+            path: vars.path().to_string(),
+            query: vars.query().map(ToString::to_string),
+            try_files: try_files
+                .map(|try_files| {
+                    try_files
+                        .files
+                        .iter()
+                        .map(|file| file.render(vars))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            fallback: try_files.map(|try_files| match &try_files.fallback {
+                StaticFallback::Status(status) => StaticFallbackAction::Status(
+                    StatusCode::from_u16(*status).unwrap_or(StatusCode::NOT_FOUND),
+                ),
+                StaticFallback::Named(name) => {
+                    StaticFallbackAction::Redirect(InternalRedirect::Named(name.clone()))
+                }
+                StaticFallback::Uri(uri) => {
+                    StaticFallbackAction::Redirect(InternalRedirect::Uri(uri.render(vars)))
+                }
+            }),
+        }
+    }
+}
+
+// Without `try_files` the request path is served directly, with `index` files
+// for directory requests; otherwise each `try_files` entry is tried in order.
+pub(super) async fn lookup(
+    files: &StaticFiles,
+    request: &RequestHeader,
+    static_request: &StaticRequest,
+) -> PingoraResult<StaticLookup> {
+    let Some(fallback) = &static_request.fallback else {
+        return serve_request_path(files, request, static_request).await;
+    };
+
+    for uri in &static_request.try_files {
+        let Some(path) = map_uri(&files.root, uri) else {
+            return status_response(StatusCode::BAD_REQUEST).map(StaticLookup::Response);
+        };
+
+        let found = if uri.ends_with('/') {
+            index_file(files, &path).await
+        } else {
+            regular_file(&path).await.map(|_| path)
+        };
+        if let Some(path) = found {
+            return file_response(request, &path)
+                .await
+                .map(StaticLookup::Response);
+        }
+    }
+
+    match fallback {
+        StaticFallbackAction::Status(status) => {
+            status_response(*status).map(StaticLookup::Response)
+        }
+        StaticFallbackAction::Redirect(redirect) => Ok(StaticLookup::Redirect(redirect.clone())),
+    }
+}
+
+async fn serve_request_path(
+    files: &StaticFiles,
+    request: &RequestHeader,
+    static_request: &StaticRequest,
+) -> PingoraResult<StaticLookup> {
+    let uri = static_request.path.as_str();
+    let Some(path) = map_uri(&files.root, uri) else {
+        return status_response(StatusCode::BAD_REQUEST).map(StaticLookup::Response);
+    };
+
+    if uri.ends_with('/') {
+        if let Some(index) = index_file(files, &path).await {
+            return file_response(request, &index)
+                .await
+                .map(StaticLookup::Response);
+        }
+        // Directory listings are not generated.
+        let status = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => StatusCode::FORBIDDEN,
+            _ => StatusCode::NOT_FOUND,
+        };
+        return status_response(status).map(StaticLookup::Response);
+    }
+
+    match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_file() => file_response(request, &path)
+            .await
+            .map(StaticLookup::Response),
+        Ok(metadata) if metadata.is_dir() => {
+            let mut location = format!("{uri}/");
+            if let Some(query) = &static_request.query {
+                location.push('?');
+                location.push_str(query);
+            }
+            let mut response = status_response(StatusCode::MOVED_PERMANENTLY)?;
+            insert_header(&mut response.header, header::LOCATION, location)?;
+            Ok(StaticLookup::Response(response))
+        }
+        _ => status_response(StatusCode::NOT_FOUND).map(StaticLookup::Response),
+    }
+}
+
+// Percent-decodes and normalizes `uri`, then maps it onto the location root.
+// `None` means the path is malformed or would escape the root.
+pub(super) fn map_uri(root: &StaticRoot, uri: &str) -> Option<PathBuf> {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    if decoded.contains('\0') || !decoded.starts_with('/') {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    match root {
+        StaticRoot::Root(root) => Some(
+            segments
+                .iter()
+                .fold(root.clone(), |path, segment| path.join(segment)),
+        ),
+        StaticRoot::Alias { path, prefix } => {
+            let mut normalized = format!("/{}", segments.join("/"));
+            if decoded.ends_with('/') && !segments.is_empty() {
+                normalized.push('/');
+            }
+            let rest = normalized.strip_prefix(prefix.as_str())?;
+            // The prefix must end on a segment boundary, otherwise `/static..`
+            // or `/staticfoo` would leak into the aliased directory.
+            if !prefix.ends_with('/') && !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            rest.split('/')
+                .filter(|segment| !segment.is_empty())
+                .try_fold(path.clone(), |path, segment| {
+                    (!matches!(segment, "." | "..")).then(|| path.join(segment))
+                })
+        }
+    }
+}
+
+async fn regular_file(path: &Path) -> Option<Metadata> {
+    tokio::fs::metadata(path)
+        .await
+        .ok()
+        .filter(|metadata| metadata.is_file())
+}
+
+async fn index_file(files: &StaticFiles, dir: &Path) -> Option<PathBuf> {
+    for name in &files.index {
+        let candidate = dir.join(name);
+        if regular_file(&candidate).await.is_some() {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+async fn file_response(request: &RequestHeader, path: &Path) -> PingoraResult<StaticResponse> {
+    if request.method != Method::GET && request.method != Method::HEAD {
+        let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED)?;
+        insert_header(&mut response.header, header::ALLOW, "GET, HEAD")?;
+        return Ok(response);
+    }
+
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            return status_response(StatusCode::FORBIDDEN);
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return status_response(StatusCode::NOT_FOUND);
+        }
+        Err(err) => return Err(io_error("open static file", err)),
+    };
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|err| io_error("stat static file", err))?;
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = entity_tag(modified, len);
+    let last_modified = modified.map(httpdate::fmt_http_date);
+
+    let mut response = if not_modified(request, &etag, modified) {
+        status_response(StatusCode::NOT_MODIFIED)?
+    } else {
+        match requested_range(request, &etag, last_modified.as_deref(), len) {
+            ByteRange::Full => body_response(StatusCode::OK, file, 0, len, request)?,
+            ByteRange::Partial { start, end } => {
+                let mut response = body_response(
+                    StatusCode::PARTIAL_CONTENT,
+                    file,
+                    start,
+                    end - start + 1,
+                    request,
+                )?;
+                insert_header(
+                    &mut response.header,
+                    header::CONTENT_RANGE,
+                    format!("bytes {start}-{end}/{len}"),
+                )?;
+                response
+            }
+            ByteRange::Unsatisfiable => {
+                let mut response = status_response(StatusCode::RANGE_NOT_SATISFIABLE)?;
+                insert_header(
+                    &mut response.header,
+                    header::CONTENT_RANGE,
+                    format!("bytes */{len}"),
+                )?;
+                response
+            }
+        }
+    };
+
+    insert_header(&mut response.header, header::ETAG, etag)?;
+    if let Some(last_modified) = last_modified {
+        insert_header(&mut response.header, header::LAST_MODIFIED, last_modified)?;
+    }
+    insert_header(&mut response.header, header::ACCEPT_RANGES, "bytes")?;
+    if response.header.status != StatusCode::NOT_MODIFIED {
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        insert_header(
+            &mut response.header,
+            header::CONTENT_TYPE,
+            content_type.essence_str(),
+        )?;
+    }
+
+    Ok(response)
+}
+
+fn body_response(
+    status: StatusCode,
+    file: File,
+    offset: u64,
+    len: u64,
+    request: &RequestHeader,
+) -> PingoraResult<StaticResponse> {
+    let mut response = status_response(status)?;
+    insert_header(
+        &mut response.header,
+        header::CONTENT_LENGTH,
+        len.to_string(),
+    )?;
+    if request.method != Method::HEAD && len > 0 {
+        response.body = Some(FileBody { file, offset, len });
+    }
+
+    Ok(response)
+}
+
+fn status_response(status: StatusCode) -> PingoraResult<StaticResponse> {
+    let mut header = Box::new(ResponseHeader::build(status, None)?);
+    insert_header(&mut header, header::CONTENT_LENGTH, "0")?;
+
+    Ok(StaticResponse { header, body: None })
+}
+
+// Strong validator in the same shape nginx uses: hex mtime and length.
+fn entity_tag(modified: Option<SystemTime>, len: u64) -> String {
+    let mtime = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs());
+    format!("\"{mtime:x}-{len:x}\"")
+}
+
+// `If-None-Match` takes precedence over `If-Modified-Since` (RFC 9110 13.2.2)
+// and, as RFC 9110 13.1.2 requires, compares entity tags weakly.
+fn not_modified(request: &RequestHeader, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|value| {
+            value.split(',').map(str::trim).any(|candidate| {
+                candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+            })
+        });
+    }
+
+    let Some(since) = request
+        .headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok())
+    else {
+        return false;
+    };
+    // HTTP dates have one-second resolution.
+    modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .zip(since.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|(modified, since)| modified.as_secs() <= since.as_secs())
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+// Only single `bytes=` ranges are honoured; multi-range and malformed requests
+// fall back to the full body, which RFC 9110 allows.
+fn requested_range(
+    request: &RequestHeader,
+    etag: &str,
+    last_modified: Option<&str>,
+    len: u64,
+) -> ByteRange {
+    let Some(range) = request
+        .headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return ByteRange::Full;
+    };
+
+    if let Some(if_range) = request.headers.get(header::IF_RANGE) {
+        let current = if_range
+            .to_str()
+            .is_ok_and(|value| value == etag || Some(value) == last_modified);
+        if !current {
+            return ByteRange::Full;
+        }
+    }
+
+    parse_range(range, len)
+}
+
+fn parse_range(value: &str, len: u64) -> ByteRange {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        _ => return ByteRange::Full,
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial { start, end }
+}
+
+fn insert_header(
+    header: &mut ResponseHeader,
+    name: http::HeaderName,
+    value: impl TryInto<HeaderValue>,
+) -> PingoraResult<()> {
+    let value = value.try_into().map_err(|_| {
+        pingora::Error::explain(
+            pingora::ErrorType::InternalError,
+            format!("invalid static response header `{name}`"),
+        )
+    })?;
+    header.insert_header(name, value)
+}
+
+fn io_error(action: &str, err: std::io::Error) -> Box<pingora::Error> {
+    pingora::Error::explain(
+        pingora::ErrorType::InternalError,
+        format!("failed to {action}: {err}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ByteRange, InternalRedirect, StaticLookup, StaticRequest, StaticResponse, lookup, map_uri,
+        parse_range,
+    };
+    use crate::upstreams::{StaticFallback, StaticFiles, StaticRoot, StaticTryFiles};
+    use http::StatusCode;
+    use ngxora_compile::variables::{Template, VariableSource};
+    use pingora::http::RequestHeader;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    struct Vars<'a> {
+        path: &'a str,
+        query: Option<&'a str>,
+    }
+
+    impl VariableSource for Vars<'_> {
+        fn host(&self) -> Option<&str> {
+            None
+        }
+
+        fn path(&self) -> &str {
+            self.path
+        }
+
+        fn query(&self) -> Option<&str> {
+            self.query
+        }
+
+        fn scheme(&self) -> &str {
+            "http"
+        }
+
+        fn server_name(&self) -> Option<&str> {
+            None
+        }
+
+        fn remote_addr(&self) -> Option<IpAddr> {
+            None
+        }
+
+        fn header(&self, _name: &str) -> Option<&str> {
+            None
+        }
+    }
+
+    fn site() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().expect("tempdir");
+        std::fs::write(dir.path().join("hello.txt"), "hello, static world").unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        dir
+    }
+
+    fn files(root: &std::path::Path, try_files: Option<StaticTryFiles>) -> StaticFiles {
+        StaticFiles {
+            root: StaticRoot::Root(root.to_path_buf()),
+            index: vec!["index.html".into()],
+            try_files,
+        }
+    }
+
+    async fn serve(files: &StaticFiles, request: RequestHeader) -> StaticLookup {
+        let uri = request.uri.clone();
+        let vars = Vars {
+            path: uri.path(),
+            query: uri.query(),
+        };
+        lookup(files, &request, &StaticRequest::new(files, &vars))
+            .await
+            .expect("lookup succeeds")
+    }
+
+    fn get(path: &str) -> RequestHeader {
+        RequestHeader::build("GET", path.as_bytes(), None).unwrap()
+    }
+
+    fn response(lookup: StaticLookup) -> StaticResponse {
+        match lookup {
+            StaticLookup::Response(response) => response,
+            StaticLookup::Redirect(redirect) => panic!("unexpected redirect {redirect:?}"),
+        }
+    }
+
+    fn header(response: &StaticResponse, name: http::header::HeaderName) -> &str {
+        response.header.headers[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_file_with_validators_and_content_type() {
+        let dir = site();
+        let files = files(dir.path(), None);
+
+        let served = response(serve(&files, get("/hello.txt")).await);
+
+        assert_eq!(served.header.status, StatusCode::OK);
+        assert_eq!(header(&served, http::header::CONTENT_TYPE), "text/plain");
+        assert_eq!(header(&served, http::header::CONTENT_LENGTH), "19");
+        assert_eq!(header(&served, http::header::ACCEPT_RANGES), "bytes");
+        assert!(
+            served
+                .header
+                .headers
+                .contains_key(http::header::LAST_MODIFIED)
+        );
+        let etag = header(&served, http::header::ETAG).to_string();
+
+        let mut conditional = get("/hello.txt");
+        conditional
+            .insert_header(http::header::IF_NONE_MATCH, &etag)
+            .unwrap();
+        let not_modified = response(serve(&files, conditional).await);
+        assert_eq!(not_modified.header.status, StatusCode::NOT_MODIFIED);
+        assert!(not_modified.body.is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_byte_ranges() {
+        let dir = site();
+        let files = files(dir.path(), None);
+
+        let mut request = get("/hello.txt");
+        request
+            .insert_header(http::header::RANGE, "bytes=7-12")
+            .unwrap();
+        let partial = response(serve(&files, request).await);
+        assert_eq!(partial.header.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            header(&partial, http::header::CONTENT_RANGE),
+            "bytes 7-12/19"
+        );
+        assert_eq!(header(&partial, http::header::CONTENT_LENGTH), "6");
+
+        let mut request = get("/hello.txt");
+        request
+            .insert_header(http::header::RANGE, "bytes=40-")
+            .unwrap();
+        let unsatisfiable = response(serve(&files, request).await);
+        assert_eq!(
+            unsatisfiable.header.status,
+            StatusCode::RANGE_NOT_SATISFIABLE
+        );
+        assert_eq!(
+            header(&unsatisfiable, http::header::CONTENT_RANGE),
+            "bytes */19"
+        );
+
+        let mut request = get("/hello.txt");
+        request
+            .insert_header(http::header::RANGE, "bytes=0-1")
+            .unwrap();
+        request
+            .insert_header(http::header::IF_RANGE, "\"stale\"")
+            .unwrap();
+        let full = response(serve(&files, request).await);
+        assert_eq!(full.header.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_directory_index_and_redirects_missing_slash() {
+        let dir = site();
+        let files = files(dir.path(), None);
+
+        let index = response(serve(&files, get("/docs/")).await);
+        assert_eq!(index.header.status, StatusCode::OK);
+        assert_eq!(header(&index, http::header::CONTENT_TYPE), "text/html");
+
+        let redirect = response(serve(&files, get("/docs?page=2")).await);
+        assert_eq!(redirect.header.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(header(&redirect, http::header::LOCATION), "/docs/?page=2");
+
+        let listing = response(serve(&files, get("/empty/")).await);
+        assert_eq!(listing.header.status, StatusCode::FORBIDDEN);
+
+        let missing = response(serve(&files, get("/missing.txt")).await);
+        assert_eq!(missing.header.status, StatusCode::NOT_FOUND);
+
+        let traversal = response(serve(&files, get("/%2e%2e/etc/passwd")).await);
+        assert_eq!(traversal.header.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_methods_other_than_get_and_head() {
+        let dir = site();
+        let files = files(dir.path(), None);
+
+        let request = RequestHeader::build("POST", b"/hello.txt", None).unwrap();
+        let rejected = response(serve(&files, request).await);
+        assert_eq!(rejected.header.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(header(&rejected, http::header::ALLOW), "GET, HEAD");
+
+        let request = RequestHeader::build("HEAD", b"/hello.txt", None).unwrap();
+        let head = response(serve(&files, request).await);
+        assert_eq!(head.header.status, StatusCode::OK);
+        assert_eq!(header(&head, http::header::CONTENT_LENGTH), "19");
+        assert!(head.body.is_none());
+    }
+
+    #[tokio::test]
+    async fn try_files_falls_back_in_order() {
+        let dir = site();
+        let try_files = |fallback| StaticTryFiles {
+            files: vec![
+                Template::parse("$uri").unwrap(),
+                Template::parse("$uri/").unwrap(),
+            ],
+            fallback,
+        };
+
+        let named = files(
+            dir.path(),
+            Some(try_files(StaticFallback::Named("app".into()))),
+        );
+        assert_eq!(
+            response(serve(&named, get("/docs")).await).header.status,
+            StatusCode::OK
+        );
+        assert!(matches!(
+            serve(&named, get("/api/users")).await,
+            StaticLookup::Redirect(InternalRedirect::Named(name)) if name == "app"
+        ));
+
+        let uri = files(
+            dir.path(),
+            Some(try_files(StaticFallback::Uri(
+                Template::parse("/index.php?q=$uri&$args").unwrap(),
+            ))),
+        );
+        assert!(matches!(
+            serve(&uri, get("/blog/post?page=2")).await,
+            StaticLookup::Redirect(InternalRedirect::Uri(uri))
+                if uri == "/index.php?q=/blog/post&page=2"
+        ));
+
+        let status = files(dir.path(), Some(try_files(StaticFallback::Status(410))));
+        assert_eq!(
+            response(serve(&status, get("/gone")).await).header.status,
+            StatusCode::GONE
+        );
+    }
+
+    #[test]
+    fn map_uri_joins_normalized_path_onto_root() {
+        let root = StaticRoot::Root(PathBuf::from("/srv/www"));
+
+        assert_eq!(
+            map_uri(&root, "/css/./site.css?v=1"),
+            Some(PathBuf::from("/srv/www/css/site.css"))
+        );
+        assert_eq!(
+            map_uri(&root, "/a/../b%20c.txt"),
+            Some(PathBuf::from("/srv/www/b c.txt"))
+        );
+        assert_eq!(map_uri(&root, "/"), Some(PathBuf::from("/srv/www")));
+    }
+
+    #[test]
+    fn map_uri_rejects_traversal_outside_root() {
+        let root = StaticRoot::Root(PathBuf::from("/srv/www"));
+
+        assert_eq!(map_uri(&root, "/../etc/passwd"), None);
+        assert_eq!(map_uri(&root, "/a/%2e%2e/%2e%2e/etc/passwd"), None);
+        assert_eq!(map_uri(&root, "/a%2f..%2f..%2fetc"), None);
+        assert_eq!(map_uri(&root, "/file%00.txt"), None);
+    }
+
+    #[test]
+    fn map_uri_replaces_alias_prefix() {
+        let alias = StaticRoot::Alias {
+            path: PathBuf::from("/srv/assets"),
+            prefix: "/static/".into(),
+        };
+
+        assert_eq!(
+            map_uri(&alias, "/static/img/logo.png"),
+            Some(PathBuf::from("/srv/assets/img/logo.png"))
+        );
+        assert_eq!(map_uri(&alias, "/static/../secret.txt"), None);
+    }
+
+    #[test]
+    fn map_uri_requires_alias_prefix_to_end_on_segment_boundary() {
+        let alias = StaticRoot::Alias {
+            path: PathBuf::from("/srv/assets"),
+            prefix: "/static".into(),
+        };
+
+        assert_eq!(
+            map_uri(&alias, "/static/img/logo.png"),
+            Some(PathBuf::from("/srv/assets/img/logo.png"))
+        );
+        assert_eq!(
+            map_uri(&alias, "/static"),
+            Some(PathBuf::from("/srv/assets"))
+        );
+        assert_eq!(map_uri(&alias, "/static../secret"), None);
+        assert_eq!(map_uri(&alias, "/static%2e%2e/secret"), None);
+        assert_eq!(map_uri(&alias, "/staticfoo"), None);
+    }
+
+    #[test]
+    fn parse_range_supports_single_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9", 100),
+            ByteRange::Partial { start: 0, end: 9 }
+        );
+        assert_eq!(
+            parse_range("bytes=90-", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            parse_range("bytes=50-500", 100),
+            ByteRange::Partial { start: 50, end: 99 }
+        );
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-1", 100), ByteRange::Full);
+    }
+}
@@ -1,19 +1,22 @@
 use super::{
     CompiledHealthCheck, CompiledLocation, CompiledMatcher, CompiledRegex, CompiledRouter,
-    CompiledUpstreamGroup, CompiledUpstreamServer, HealthCheckType, RouteTarget, ServerRoutes,
-    VirtualHostRoutes, apply_upstream_http_protocol, apply_upstream_ssl_options,
-    apply_upstream_timeouts, content_length_limit_exceeded, downstream_keepalive_timeout_secs,
-    listener_routes, select_route_target, update_received_body_bytes,
-    validate_sni_host_consistency,
+    CompiledUpstreamGroup, CompiledUpstreamServer, HealthCheckType, ListenKey, RouteTarget,
+    ServerRoutes, StaticFallback, StaticRoot, VirtualHostRoutes, apply_upstream_http_protocol,
+    apply_upstream_ssl_options, apply_upstream_timeouts, content_length_limit_exceeded,
+    downstream_keepalive_timeout_secs, listener_routes, select_route_target,
+    update_received_body_bytes, validate_sni_host_consistency,
 };
 use bytes::Bytes;
 use ipnet::IpNet;
 use ngxora_compile::ir::{
-    Http, KeepaliveTimeout, Listen, Location, LocationDirective, LocationIpRule, LocationMatcher,
-    PemSource, ProxyPassTarget, Server, SslProvider, Switch, UpstreamBlock, UpstreamHealthCheck,
-    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamSelectionPolicy, UpstreamServer,
-    UpstreamSslOptions, UpstreamTimeouts,
+    AcmeIssuer, DnsResolver, HealthCheckBodyMatch, Http, KeepaliveTimeout, Listen, Location,
+    LocationDirective, LocationIpRule, LocationMatcher, NextUpstream, PemSource, ProxyPassTarget,
+    Server, SslProvider, Switch, TryFilesFallback, UpstreamBlock, UpstreamHealthCheck,
+    UpstreamHealthCheckType, UpstreamHttpProtocol, UpstreamKeepalive, UpstreamOutlierDetection,
+    UpstreamQueue, UpstreamRetry, UpstreamSelectionPolicy, UpstreamServer, UpstreamSslOptions,
+    UpstreamTimeouts,
 };
+use ngxora_compile::variables::Template;
 use ngxora_plugin_api::PluginSpec;
 use pingora::http::ResponseHeader;
 use pingora::upstreams::peer::HttpPeer;
@@ -21,6 +24,8 @@ use pingora_proxy::{ProxyHttp, Session};
 use serde_json::json;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncWriteExt, duplex};
 
@@ -60,10 +65,13 @@ fn target(id: &str) -> RouteTarget {
 fn location(matcher: CompiledMatcher, id: &str) -> CompiledLocation {
     CompiledLocation {
         route_id: 1,
+        name: None,
+        access_logs: Vec::new(),
         matcher,
         access_rules: Vec::new(),
         target: target(id),
         upstream_timeouts: UpstreamTimeouts::default(),
+        upstream_retry: UpstreamRetry::default(),
         upstream_protocol: None,
         upstream_ssl_options: UpstreamSslOptions::default(),
         plugins: Vec::<PluginSpec>::new(),
@@ -91,11 +99,13 @@ fn selected_host<'a>(routes: &'a ServerRoutes, path: &str) -> Option<&'a str> {
 #[test]
 fn exact_match_wins() {
     let routes = ServerRoutes {
+        server_name: None,
         locations: vec![
             location(CompiledMatcher::Prefix("/".into()), "prefix"),
             location(CompiledMatcher::Exact("/app".into()), "exact"),
             location(regex("^/app$", false), "regex"),
         ],
+        request_id: None,
     };
 
     assert_eq!(selected_host(&routes, "/app"), Some("exact.example.com"));
@@ -104,6 +114,7 @@ fn exact_match_wins() {
 #[test]
 fn prefer_prefix_blocks_regex() {
     let routes = ServerRoutes {
+        server_name: None,
         locations: vec![
             location(
                 CompiledMatcher::PreferPrefix("/images/".into()),
@@ -111,6 +122,7 @@ fn prefer_prefix_blocks_regex() {
             ),
             location(regex("\\.(png|jpg)$", false), "regex"),
         ],
+        request_id: None,
     };
 
     assert_eq!(
@@ -122,11 +134,13 @@ fn prefer_prefix_blocks_regex() {
 #[test]
 fn first_matching_regex_wins_over_plain_prefix() {
     let routes = ServerRoutes {
+        server_name: None,
         locations: vec![
             location(CompiledMatcher::Prefix("/api/".into()), "prefix"),
             location(regex("^/api/v[0-9]+/", false), "regex-1"),
             location(regex("^/api/", false), "regex-2"),
         ],
+        request_id: None,
     };
 
     assert_eq!(
@@ -138,12 +152,14 @@ fn first_matching_regex_wins_over_plain_prefix() {
 #[test]
 fn longest_plain_prefix_is_used_when_no_regex_matches() {
     let routes = ServerRoutes {
+        server_name: None,
         locations: vec![
             location(CompiledMatcher::Prefix("/".into()), "root"),
             location(CompiledMatcher::Prefix("/api/".into()), "api"),
             location(CompiledMatcher::Prefix("/api/internal/".into()), "internal"),
             location(regex("^/admin/", false), "regex"),
         ],
+        request_id: None,
     };
 
     assert_eq!(
@@ -155,10 +171,12 @@ fn longest_plain_prefix_is_used_when_no_regex_matches() {
 #[test]
 fn named_location_is_not_selected_for_request_path() {
     let routes = ServerRoutes {
+        server_name: None,
         locations: vec![
             location(CompiledMatcher::Named("fallback".into()), "named"),
             location(CompiledMatcher::Prefix("/".into()), "prefix"),
         ],
+        request_id: None,
     };
 
     assert_eq!(selected_host(&routes, "/"), Some("prefix.example.com"));
@@ -182,7 +200,9 @@ fn wildcard_listener_routes_match_concrete_local_addr() {
             VirtualHostRoutes {
                 named: HashMap::new(),
                 default: Some(ServerRoutes {
+                    server_name: None,
                     locations: vec![location(CompiledMatcher::Prefix("/".into()), "wildcard")],
+                    request_id: None,
                 }),
             },
         )]),
@@ -266,11 +286,10 @@ fn compiled_router_rejects_invalid_location_regex() {
 #[test]
 fn compiled_router_rejects_letsencrypt_with_multiple_server_names() {
     let http = Http {
-        ssl_provider: Some(ngxora_compile::ir::LetsEncryptConfig {
-            acme_directory: None,
+        acme_issuers: vec![AcmeIssuer {
             email: Some("admin@example.com".into()),
-            cache_dir: None,
-        }),
+            ..AcmeIssuer::new("letsencrypt")
+        }],
         servers: vec![Server {
             listens: vec![Listen {
                 port: 443,
@@ -279,7 +298,7 @@ fn compiled_router_rejects_letsencrypt_with_multiple_server_names() {
                 ..Listen::default()
             }],
             server_names: vec!["example.com".into(), "www.example.com".into()],
-            tls: Some(SslProvider::LetsEncrypt),
+            tls: Some(SslProvider::Acme(vec!["letsencrypt".into()])),
             locations: vec![Location {
                 matcher: LocationMatcher::Prefix("/".into()),
                 directives: vec![LocationDirective::ProxyPass(ProxyPassTarget::Url(
@@ -298,6 +317,76 @@ fn compiled_router_rejects_letsencrypt_with_multiple_server_names() {
     assert!(err.contains("supports exactly one server_name"));
 }
 
+#[test]
+fn compiled_router_stores_acme_certificates_in_the_primary_issuer_cache() {
+    let server = |name: &str, issuers: &[&str]| Server {
+        listens: vec![Listen {
+            port: 443,
+            ssl: true,
+            ..Listen::default()
+        }],
+        server_names: vec![name.into()],
+        tls: Some(SslProvider::Acme(
+            issuers.iter().map(|issuer| issuer.to_string()).collect(),
+        )),
+        locations: vec![Location {
+            matcher: LocationMatcher::Prefix("/".into()),
+            directives: vec![LocationDirective::ProxyPass(ProxyPassTarget::Url(
+                "http://127.0.0.1:8080".parse().unwrap(),
+            ))],
+            access_rules: Vec::new(),
+            plugins: Vec::new(),
+            cache: None,
+        }],
+        ..Server::default()
+    };
+    let mut http = Http {
+        acme_issuers: vec![
+            AcmeIssuer::new("letsencrypt"),
+            AcmeIssuer {
+                acme_directory: Some("https://ca.internal/acme/directory".into()),
+                cache_dir: Some(PathBuf::from("/var/lib/step")),
+                ..AcmeIssuer::new("step")
+            },
+        ],
+        servers: vec![
+            server("shop.example.com", &["step", "letsencrypt"]),
+            server("www.example.com", &["letsencrypt"]),
+            server("*.apps.example.com", &["letsencrypt"]),
+        ],
+        ..Http::default()
+    };
+
+    let router = CompiledRouter::from_http(&http).expect("router compiles");
+    assert_eq!(
+        router.acme_certificates["shop.example.com"],
+        ["step", "letsencrypt"]
+    );
+    let tls = &router.listener_tls[&ListenKey::from(&http.servers[0].listens[0])];
+    assert_eq!(
+        tls.named["shop.example.com"].cert,
+        PemSource::Path(PathBuf::from(
+            "/var/lib/step/shop.example.com/fullchain.pem"
+        ))
+    );
+    assert_eq!(
+        tls.named["www.example.com"].key,
+        PemSource::Path(PathBuf::from(
+            "/var/lib/ngxora/certs/www.example.com/privkey.pem"
+        ))
+    );
+    assert_eq!(
+        tls.named["*.apps.example.com"].cert,
+        PemSource::Path(PathBuf::from(
+            "/var/lib/ngxora/certs/_wildcard.apps.example.com/fullchain.pem"
+        ))
+    );
+
+    http.servers[1] = server("www.example.com", &["zerossl"]);
+    let err = CompiledRouter::from_http(&http).expect_err("unknown issuer");
+    assert!(err.contains("unknown ACME issuer `zerossl`"), "{err}");
+}
+
 #[test]
 fn compiled_router_parses_proxy_timeouts() {
     let http = Http {
@@ -346,6 +435,68 @@ fn compiled_router_parses_proxy_timeouts() {
     );
 }
 
+#[test]
+fn compiled_router_parses_proxy_next_upstream() {
+    let next_upstream = NextUpstream {
+        error: true,
+        http_503: true,
+        ..NextUpstream::default()
+    };
+    let compile = |directives: Vec<LocationDirective>| {
+        let mut directives = directives;
+        directives.push(LocationDirective::ProxyPass(ProxyPassTarget::Url(
+            "http://127.0.0.1:8080".parse().unwrap(),
+        )));
+        CompiledRouter::from_http(&static_server(vec![Location {
+            matcher: LocationMatcher::Prefix("/".into()),
+            directives,
+            access_rules: Vec::new(),
+            plugins: Vec::new(),
+            cache: None,
+        }]))
+    };
+    let retry_of = |router: CompiledRouter| {
+        router
+            .listeners
+            .values()
+            .next()
+            .unwrap()
+            .default
+            .as_ref()
+            .unwrap()
+            .locations[0]
+            .upstream_retry
+    };
+
+    let router = compile(vec![
+        LocationDirective::ProxyNextUpstream(next_upstream),
+        LocationDirective::ProxyNextUpstreamTries(3),
+        LocationDirective::ProxyNextUpstreamTimeout(Duration::from_secs(5)),
+    ])
+    .expect("router compiles");
+    assert_eq!(
+        retry_of(router),
+        UpstreamRetry {
+            next_upstream,
+            tries: 3,
+            timeout: Some(Duration::from_secs(5)),
+        }
+    );
+
+    let router = compile(vec![LocationDirective::ProxyNextUpstreamTimeout(
+        Duration::ZERO,
+    )])
+    .expect("router compiles");
+    assert_eq!(retry_of(router), UpstreamRetry::default());
+
+    let err = compile(vec![
+        LocationDirective::ProxyNextUpstreamTries(3),
+        LocationDirective::ProxyNextUpstreamTries(4),
+    ])
+    .expect_err("duplicate tries are rejected");
+    assert!(err.contains("proxy_next_upstream_tries is duplicated"));
+}
+
 #[test]
 fn compiled_router_parses_proxy_ssl_options() {
     let trusted_certificate = PemSource::Path("/etc/ssl/upstreams/ca.pem".into());
@@ -781,8 +932,10 @@ fn apply_upstream_ssl_options_sets_client_identity() {
 
 #[test]
 fn compiled_router_maps_client_max_body_size_into_runtime_options() {
-    let mut http = Http::default();
-    http.client_max_body_size = Some(10 * 1024 * 1024);
+    let mut http = Http {
+        client_max_body_size: Some(10 * 1024 * 1024),
+        ..Http::default()
+    };
     http.servers.push(Server {
         listens: vec![Listen {
             default_server: true,
@@ -801,8 +954,10 @@ fn compiled_router_maps_client_max_body_size_into_runtime_options() {
 
 #[test]
 fn compiled_router_rejects_tcp_nodelay_off() {
-    let mut http = Http::default();
-    http.tcp_nodelay = Switch::Off;
+    let mut http = Http {
+        tcp_nodelay: Switch::Off,
+        ..Http::default()
+    };
     http.servers.push(Server {
         listens: vec![Listen {
             default_server: true,
@@ -825,13 +980,28 @@ fn compiled_router_maps_named_upstream_groups() {
                 UpstreamServer {
                     host: "127.0.0.1".into(),
                     port: 8080,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
                 },
                 UpstreamServer {
                     host: "127.0.0.1".into(),
                     port: 8081,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
                 },
             ],
             health_check: None,
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
         }],
         servers: vec![Server {
             listens: vec![Listen {
@@ -881,13 +1051,28 @@ fn runtime_upstream_group_round_robins_backends() {
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 8080,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 8081,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
         ],
         health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
     })
     .expect("runtime group builds");
 
@@ -895,9 +1080,9 @@ fn runtime_upstream_group_round_robins_backends() {
     let second = group.select(b"").expect("second backend");
     let third = group.select(b"").expect("third backend");
 
-    assert_eq!(first.port, 8080);
-    assert_eq!(second.port, 8081);
-    assert_eq!(third.port, 8080);
+    assert_eq!(first.server.port, 8080);
+    assert_eq!(second.server.port, 8081);
+    assert_eq!(third.server.port, 8080);
 }
 
 #[test]
@@ -909,18 +1094,333 @@ fn runtime_upstream_group_random_selects_configured_backend() {
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 8080,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 8081,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
         ],
         health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
     })
     .expect("runtime group builds");
 
     let selected = group.select(b"").expect("selected backend");
-    assert!(matches!(selected.port, 8080 | 8081));
+    assert!(matches!(selected.server.port, 8080 | 8081));
+}
+
+fn weighted_servers(servers: &[(u16, u32)]) -> Vec<CompiledUpstreamServer> {
+    servers
+        .iter()
+        .map(|&(port, weight)| CompiledUpstreamServer {
+            host: "127.0.0.1".into(),
+            port,
+            weight,
+            resolve: false,
+            max_conns: None,
+            backup: false,
+            down: false,
+            slow_start: None,
+        })
+        .collect()
+}
+
+fn runtime_group(
+    policy: UpstreamSelectionPolicy,
+    servers: &[(u16, u32)],
+) -> super::RuntimeUpstreamGroup {
+    super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy,
+        servers: weighted_servers(servers),
+        health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    })
+    .expect("runtime group builds")
+}
+
+#[test]
+fn runtime_upstream_group_round_robin_follows_weights() {
+    let group = runtime_group(UpstreamSelectionPolicy::RoundRobin, &[(8080, 3), (8081, 1)]);
+
+    let ports: Vec<u16> = (0..8)
+        .map(|_| group.select(b"").expect("backend").server.port)
+        .collect();
+    assert_eq!(ports.iter().filter(|&&port| port == 8080).count(), 6);
+    assert_eq!(ports.iter().filter(|&&port| port == 8081).count(), 2);
+}
+
+#[test]
+fn runtime_upstream_group_skips_ejected_backends() {
+    let group = super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers: weighted_servers(&[(8080, 1), (8081, 1)]),
+        health_check: None,
+        outlier_detection: Some(UpstreamOutlierDetection {
+            consecutive_failure: 2,
+            max_ejection_percent: 100,
+            ..UpstreamOutlierDetection::default()
+        }),
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    })
+    .expect("runtime group builds");
+
+    group.report("127.0.0.1", 8080, false);
+    group.report("127.0.0.1", 8080, false);
+    for _ in 0..4 {
+        assert_eq!(group.select(b"").expect("backend").server.port, 8081);
+    }
+    assert_eq!(
+        group
+            .backend_health()
+            .iter()
+            .map(|status| status.ejected)
+            .collect::<Vec<_>>(),
+        vec![true, false]
+    );
+
+    // With every backend ejected, selection ignores ejections instead of
+    // failing the request.
+    group.report("127.0.0.1", 8081, false);
+    group.report("127.0.0.1", 8081, false);
+    assert!(group.backend_health().iter().all(|status| status.ejected));
+    assert!(group.select(b"").is_some());
+}
+
+#[test]
+fn runtime_upstream_group_least_conn_prefers_idle_backends() {
+    let group = runtime_group(UpstreamSelectionPolicy::LeastConn, &[(8080, 1), (8081, 1)]);
+
+    let first = group.select(b"").expect("first backend");
+    let second = group.select(b"").expect("second backend");
+    assert_ne!(first.server.port, second.server.port);
+
+    // Both busy once; finishing one request makes its backend the choice.
+    let finished = first.server.port;
+    drop(first);
+    for _ in 0..3 {
+        assert_eq!(group.select(b"").expect("backend").server.port, finished);
+    }
+}
+
+#[test]
+fn runtime_upstream_group_least_conn_scales_by_weight() {
+    let group = runtime_group(UpstreamSelectionPolicy::LeastConn, &[(8080, 3), (8081, 1)]);
+
+    let held: Vec<_> = (0..4)
+        .map(|_| group.select(b"").expect("backend"))
+        .collect();
+    let heavy = held
+        .iter()
+        .filter(|backend| backend.server.port == 8080)
+        .count();
+    assert_eq!(heavy, 3);
+}
+
+fn max_conns_group(queue: Option<UpstreamQueue>) -> super::RuntimeUpstreamGroup {
+    let mut servers = weighted_servers(&[(8080, 1), (8081, 1)]);
+    for server in &mut servers {
+        server.max_conns = Some(1);
+    }
+    super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers,
+        health_check: None,
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue,
+    })
+    .expect("runtime group builds")
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_skips_backends_at_max_conns() {
+    let group = max_conns_group(None);
+
+    let first = group.select_queued(b"").await.expect("first backend");
+    let second = group.select_queued(b"").await.expect("second backend");
+    assert_ne!(first.server.port, second.server.port);
+    // Without a queue, a full group turns the request away.
+    assert!(group.select_queued(b"").await.is_none());
+
+    let finished = first.server.port;
+    drop(first);
+    assert_eq!(
+        group.select_queued(b"").await.expect("backend").server.port,
+        finished
+    );
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_queues_requests_until_a_backend_frees_up() {
+    let group = Arc::new(max_conns_group(Some(UpstreamQueue {
+        size: 1,
+        timeout: Duration::from_millis(100),
+    })));
+    let held = [
+        group.select_queued(b"").await.expect("first backend"),
+        group.select_queued(b"").await.expect("second backend"),
+    ];
+
+    // Nothing is released in time.
+    assert!(group.select_queued(b"").await.is_none());
+
+    let waiting = tokio::spawn({
+        let group = Arc::clone(&group);
+        async move {
+            group
+                .select_queued(b"")
+                .await
+                .map(|backend| backend.server.port)
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    // The only queue slot is taken.
+    assert!(group.select_queued(b"").await.is_none());
+
+    let [first, _second] = held;
+    let released = first.server.port;
+    drop(first);
+    assert_eq!(waiting.await.expect("waiter finishes"), Some(released));
+}
+
+#[test]
+fn runtime_upstream_group_uses_backups_only_without_primaries() {
+    let mut servers = weighted_servers(&[(8080, 1), (8081, 1), (8082, 1)]);
+    servers[1].backup = true;
+    servers[2].down = true;
+    let group = super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers,
+        health_check: None,
+        outlier_detection: Some(UpstreamOutlierDetection {
+            consecutive_failure: 1,
+            max_ejection_percent: 100,
+            ..UpstreamOutlierDetection::default()
+        }),
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    })
+    .expect("runtime group builds");
+
+    for _ in 0..4 {
+        assert_eq!(group.select(b"").expect("backend").server.port, 8080);
+    }
+    group.report("127.0.0.1", 8080, false);
+    for _ in 0..4 {
+        assert_eq!(group.select(b"").expect("backend").server.port, 8081);
+    }
+
+    // A `down` server is not picked even when everything else is ejected.
+    group.report("127.0.0.1", 8081, false);
+    for _ in 0..4 {
+        assert_ne!(group.select(b"").expect("backend").server.port, 8082);
+    }
+}
+
+#[test]
+fn runtime_upstream_group_hash_is_sticky_per_key() {
+    for consistent in [false, true] {
+        let group = runtime_group(
+            UpstreamSelectionPolicy::Hash {
+                key: Template::parse("$cookie_session").unwrap(),
+                consistent,
+            },
+            &[(8080, 1), (8081, 1), (8082, 1)],
+        );
+        assert_eq!(
+            group.hash_key().map(Template::source),
+            Some("$cookie_session")
+        );
+
+        for key in ["alice", "bob", "carol"] {
+            let first = group.select(key.as_bytes()).expect("backend").server;
+            for _ in 0..5 {
+                assert_eq!(group.select(key.as_bytes()).expect("backend").server, first);
+            }
+        }
+    }
+}
+
+#[test]
+fn runtime_upstream_group_consistent_hash_only_remaps_removed_server_keys() {
+    let policy = UpstreamSelectionPolicy::Hash {
+        key: Template::parse("$remote_addr").unwrap(),
+        consistent: true,
+    };
+    let before = runtime_group(policy.clone(), &[(8080, 1), (8081, 1), (8082, 1)]);
+    let after = runtime_group(policy, &[(8080, 1), (8082, 1)]);
+
+    let mut moved = 0;
+    for client in 0..200 {
+        let key = format!("198.51.100.{client}");
+        let old = before.select(key.as_bytes()).expect("backend").server.port;
+        let new = after.select(key.as_bytes()).expect("backend").server.port;
+        if old != 8081 {
+            assert_eq!(old, new, "{key} moved off a remaining server");
+        } else {
+            moved += 1;
+        }
+    }
+    assert!(moved > 0);
+}
+
+#[test]
+fn runtime_upstream_group_select_excluding_skips_failed_backends() {
+    let policies = [
+        UpstreamSelectionPolicy::RoundRobin,
+        UpstreamSelectionPolicy::Random,
+        UpstreamSelectionPolicy::LeastConn,
+        UpstreamSelectionPolicy::Hash {
+            key: Template::parse("$remote_addr").unwrap(),
+            consistent: false,
+        },
+        UpstreamSelectionPolicy::Hash {
+            key: Template::parse("$remote_addr").unwrap(),
+            consistent: true,
+        },
+    ];
+    for policy in policies {
+        let group = runtime_group(policy, &[(8080, 5), (8081, 1), (8082, 1)]);
+        let failed = vec![
+            ("127.0.0.1".to_string(), 8080),
+            ("127.0.0.1".to_string(), 8082),
+        ];
+
+        for key in ["198.51.100.1", "198.51.100.2", "198.51.100.3"] {
+            let backend = group
+                .select_excluding(key.as_bytes(), &failed)
+                .expect("backend left");
+            assert_eq!(backend.server.port, 8081);
+        }
+
+        let all = vec![
+            ("127.0.0.1".to_string(), 8080),
+            ("127.0.0.1".to_string(), 8081),
+            ("127.0.0.1".to_string(), 8082),
+        ];
+        assert!(group.select_excluding(b"", &all).is_none());
+    }
 }
 
 #[test]
@@ -932,18 +1432,32 @@ fn compiled_router_maps_upstream_health_check() {
             servers: vec![UpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 8080,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             }],
             health_check: Some(UpstreamHealthCheck {
                 check_type: UpstreamHealthCheckType::Http {
                     host: "backend.internal".into(),
                     path: "/readyz".into(),
                     use_tls: true,
+                    method: "GET".into(),
+                    headers: Vec::new(),
+                    statuses: vec![200..=200],
+                    body: None,
                 },
+                port: None,
                 timeout: Duration::from_secs(2),
                 interval: Duration::from_secs(10),
                 consecutive_success: 2,
                 consecutive_failure: 3,
             }),
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
         }],
         ..Http::default()
     };
@@ -956,7 +1470,12 @@ fn compiled_router_maps_upstream_health_check() {
                 host: "backend.internal".into(),
                 path: "/readyz".into(),
                 use_tls: true,
+                method: "GET".into(),
+                headers: Vec::new(),
+                statuses: vec![200..=200],
+                body: None,
             },
+            port: None,
             timeout: Duration::from_secs(2),
             interval: Duration::from_secs(10),
             consecutive_success: 2,
@@ -974,19 +1493,35 @@ async fn runtime_upstream_group_tcp_health_check_marks_unreachable_backends_unhe
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 1,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 2,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
         ],
         health_check: Some(CompiledHealthCheck {
             check_type: HealthCheckType::Tcp,
+            port: None,
             timeout: Duration::from_secs(1),
             interval: Duration::from_secs(5),
             consecutive_success: 1,
             consecutive_failure: 1,
         }),
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
     })
     .expect("runtime group builds");
 
@@ -997,6 +1532,67 @@ async fn runtime_upstream_group_tcp_health_check_marks_unreachable_backends_unhe
     assert!(group.select(b"").is_none());
 }
 
+#[tokio::test]
+async fn runtime_upstream_group_ramps_up_recovered_slow_start_backends() {
+    let steady = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("bind steady backend");
+    let recovering_port = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("reserve a port")
+        .local_addr()
+        .expect("local addr")
+        .port();
+    let mut servers = weighted_servers(&[
+        (steady.local_addr().expect("local addr").port(), 1),
+        (recovering_port, 1),
+    ]);
+    servers[1].slow_start = Some(Duration::from_secs(60));
+    let group = super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers,
+        health_check: Some(CompiledHealthCheck {
+            check_type: HealthCheckType::Tcp,
+            port: None,
+            timeout: Duration::from_secs(1),
+            interval: Duration::from_secs(5),
+            consecutive_success: 1,
+            consecutive_failure: 1,
+        }),
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    })
+    .expect("runtime group builds");
+
+    let now = tokio::time::Instant::now();
+    group
+        .run_due_health_check(now)
+        .await
+        .expect("scheduled health check");
+    assert!(!group.backend_health()[1].healthy);
+
+    let _recovered = tokio::net::TcpListener::bind(("127.0.0.1", recovering_port))
+        .await
+        .expect("bind recovering backend");
+    group
+        .run_due_health_check(now + Duration::from_secs(5))
+        .await
+        .expect("scheduled health check");
+    let health = group.backend_health();
+    assert!(health[1].healthy && health[1].ramping);
+    assert!(!health[0].ramping);
+
+    // Right after recovering, the ramping server gets next to nothing.
+    for _ in 0..20 {
+        assert_ne!(
+            group.select(b"").expect("backend").server.port,
+            recovering_port
+        );
+    }
+}
+
 #[tokio::test]
 async fn runtime_upstream_group_http_health_check_marks_unreachable_backends_unhealthy() {
     let group = super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
@@ -1006,10 +1602,22 @@ async fn runtime_upstream_group_http_health_check_marks_unreachable_backends_unh
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 1,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
             CompiledUpstreamServer {
                 host: "127.0.0.1".into(),
                 port: 2,
+                weight: 1,
+                resolve: false,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
             },
         ],
         health_check: Some(CompiledHealthCheck {
@@ -1017,12 +1625,20 @@ async fn runtime_upstream_group_http_health_check_marks_unreachable_backends_unh
                 host: "backend.internal".into(),
                 path: "/readyz".into(),
                 use_tls: false,
+                method: "GET".into(),
+                headers: Vec::new(),
+                statuses: vec![200..=200],
+                body: None,
             },
+            port: None,
             timeout: Duration::from_secs(1),
             interval: Duration::from_secs(5),
             consecutive_success: 1,
             consecutive_failure: 1,
         }),
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
     })
     .expect("runtime group builds");
 
@@ -1033,6 +1649,426 @@ async fn runtime_upstream_group_http_health_check_marks_unreachable_backends_unh
     assert!(group.select(b"").is_none());
 }
 
+/// A group whose only server listens nowhere; checks probe `probe_port`.
+fn probed_group(check_type: HealthCheckType, probe_port: u16) -> super::RuntimeUpstreamGroup {
+    super::RuntimeUpstreamGroup::from_compiled(&CompiledUpstreamGroup {
+        name: "backend".into(),
+        policy: UpstreamSelectionPolicy::RoundRobin,
+        servers: vec![CompiledUpstreamServer {
+            host: "127.0.0.1".into(),
+            port: 1,
+            weight: 1,
+            resolve: false,
+            max_conns: None,
+            backup: false,
+            down: false,
+            slow_start: None,
+        }],
+        health_check: Some(CompiledHealthCheck {
+            check_type,
+            port: Some(probe_port),
+            timeout: Duration::from_secs(1),
+            interval: Duration::from_secs(5),
+            consecutive_success: 1,
+            consecutive_failure: 1,
+        }),
+        outlier_detection: None,
+        keepalive: UpstreamKeepalive::default(),
+        queue: None,
+    })
+    .expect("runtime group builds")
+}
+
+async fn probed_group_is_healthy(check_type: HealthCheckType, probe_port: u16) -> bool {
+    let group = probed_group(check_type, probe_port);
+    group
+        .run_due_health_check(tokio::time::Instant::now())
+        .await
+        .expect("scheduled health check");
+    group.select(b"").is_some()
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_http_health_check_matches_status_headers_and_body() {
+    use tokio::io::AsyncReadExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let probe_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                let response = if request.contains("\r\nx-probe: ngxora\r\n") {
+                    "HTTP/1.1 299 OK\r\ncontent-length: 13\r\nconnection: close\r\n\r\nstatus: ready"
+                } else {
+                    "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    let check = |headers: Vec<(String, String)>, body| HealthCheckType::Http {
+        host: "backend.internal".into(),
+        path: "/readyz".into(),
+        use_tls: false,
+        method: "GET".into(),
+        headers,
+        statuses: vec![200..=299],
+        body,
+    };
+    let probe = || vec![("X-Probe".to_string(), "ngxora".to_string())];
+
+    assert!(
+        probed_group_is_healthy(
+            check(
+                probe(),
+                Some(HealthCheckBodyMatch::Regex("^status: (ready|ok)$".into()))
+            ),
+            probe_port
+        )
+        .await
+    );
+    assert!(
+        !probed_group_is_healthy(
+            check(
+                probe(),
+                Some(HealthCheckBodyMatch::Contains("degraded".into()))
+            ),
+            probe_port
+        )
+        .await
+    );
+    assert!(!probed_group_is_healthy(check(Vec::new(), None), probe_port).await);
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_grpc_health_check_follows_serving_status() {
+    use super::health::grpc_health::{
+        HealthCheckRequest, HealthCheckResponse,
+        health_check_response::ServingStatus,
+        health_server::{Health, HealthServer},
+    };
+
+    struct StubHealth;
+
+    #[tonic::async_trait]
+    impl Health for StubHealth {
+        async fn check(
+            &self,
+            request: tonic::Request<HealthCheckRequest>,
+        ) -> Result<tonic::Response<HealthCheckResponse>, tonic::Status> {
+            let status = match request.into_inner().service.as_str() {
+                "" => ServingStatus::Serving,
+                "api.Down" => ServingStatus::NotServing,
+                _ => return Err(tonic::Status::not_found("unknown service")),
+            };
+            Ok(tonic::Response::new(HealthCheckResponse {
+                status: status as i32,
+            }))
+        }
+
+        type WatchStream = futures::stream::Empty<Result<HealthCheckResponse, tonic::Status>>;
+
+        async fn watch(
+            &self,
+            _request: tonic::Request<HealthCheckRequest>,
+        ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+            Err(tonic::Status::unimplemented("watch"))
+        }
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let probe_port = listener.local_addr().unwrap().port();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(HealthServer::new(StubHealth))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+
+    let check = |service: &str| HealthCheckType::Grpc {
+        service: service.into(),
+        host: None,
+        use_tls: false,
+    };
+    assert!(probed_group_is_healthy(check(""), probe_port).await);
+    assert!(!probed_group_is_healthy(check("api.Down"), probe_port).await);
+    assert!(!probed_group_is_healthy(check("api.Missing"), probe_port).await);
+}
+
+/// Answers A/AAAA queries from `records`; unknown names get NXDOMAIN.
+async fn stub_dns(
+    records: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    ttl: u32,
+) -> std::net::SocketAddr {
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{A, AAAA};
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("bind stub dns");
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        loop {
+            let Ok((len, peer)) = socket.recv_from(&mut buf).await else {
+                return;
+            };
+            let Ok(request) = Message::from_vec(&buf[..len]) else {
+                continue;
+            };
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_desired(request.recursion_desired())
+                .set_recursion_available(true);
+            for query in request.queries() {
+                response.add_query(query.clone());
+                let name = query.name().to_string();
+                let known = records
+                    .lock()
+                    .unwrap()
+                    .get(name.trim_end_matches('.'))
+                    .cloned();
+                let Some(addrs) = known else {
+                    response.set_response_code(ResponseCode::NXDomain);
+                    continue;
+                };
+                for addr in addrs {
+                    let rdata = match (query.query_type(), addr) {
+                        (RecordType::A, IpAddr::V4(ip)) => RData::A(A(ip)),
+                        (RecordType::AAAA, IpAddr::V6(ip)) => RData::AAAA(AAAA(ip)),
+                        _ => continue,
+                    };
+                    response.add_answer(Record::from_rdata(query.name().clone(), ttl, rdata));
+                }
+            }
+            let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+        }
+    });
+    addr
+}
+
+fn resolving_group(
+    dns: std::net::SocketAddr,
+    health_check: Option<CompiledHealthCheck>,
+) -> super::RuntimeUpstreamGroup {
+    super::RuntimeUpstreamGroup::with_resolver(
+        &CompiledUpstreamGroup {
+            name: "backend".into(),
+            policy: UpstreamSelectionPolicy::RoundRobin,
+            servers: vec![
+                CompiledUpstreamServer {
+                    host: "app.test".into(),
+                    port: 8080,
+                    weight: 2,
+                    resolve: true,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
+                },
+                CompiledUpstreamServer {
+                    host: "127.0.0.1".into(),
+                    port: 9090,
+                    weight: 1,
+                    resolve: false,
+                    max_conns: None,
+                    backup: false,
+                    down: false,
+                    slow_start: None,
+                },
+            ],
+            health_check,
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
+        },
+        Some(&DnsResolver {
+            addrs: vec![dns],
+            valid: None,
+        }),
+    )
+    .expect("runtime group builds")
+}
+
+fn backend_addresses(group: &super::RuntimeUpstreamGroup) -> Vec<(String, Option<String>)> {
+    group
+        .backend_health()
+        .into_iter()
+        .map(|status| (status.server.to_string(), status.resolved_from))
+        .collect()
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_expands_and_reconciles_resolved_servers() {
+    let records = Arc::new(Mutex::new(HashMap::from([(
+        "app.test".to_string(),
+        vec![
+            "127.0.0.2".parse::<IpAddr>().unwrap(),
+            "127.0.0.3".parse().unwrap(),
+        ],
+    )])));
+    let dns = stub_dns(Arc::clone(&records), 30).await;
+    let group = resolving_group(dns, None);
+
+    // Nothing is resolved before the first lookup.
+    assert_eq!(
+        backend_addresses(&group),
+        vec![("127.0.0.1:9090".to_string(), None)]
+    );
+
+    let now = tokio::time::Instant::now();
+    let next_run = group.run_due_resolve(now).await.expect("group resolves");
+    assert!(
+        next_run > now + Duration::from_secs(20),
+        "refresh follows the TTL"
+    );
+    let resolved = Some("app.test".to_string());
+    assert_eq!(
+        backend_addresses(&group),
+        vec![
+            ("127.0.0.2:8080".to_string(), resolved.clone()),
+            ("127.0.0.3:8080".to_string(), resolved.clone()),
+            ("127.0.0.1:9090".to_string(), None),
+        ]
+    );
+    let backend = (0..3)
+        .map(|_| group.select(b"").expect("backend"))
+        .find(|backend| backend.server.port == 8080)
+        .expect("a resolved backend is selected");
+    assert_eq!(backend.server.weight, 2);
+    assert_eq!(backend.resolved_from.as_deref(), Some("app.test"));
+
+    // Not due yet: the records are not looked up again.
+    records.lock().unwrap().insert(
+        "app.test".into(),
+        vec!["127.0.0.3".parse().unwrap(), "::1".parse().unwrap()],
+    );
+    assert_eq!(group.run_due_resolve(now).await, Some(next_run));
+    assert_eq!(backend_addresses(&group).len(), 3);
+
+    group.run_due_resolve(next_run).await;
+    assert_eq!(
+        backend_addresses(&group),
+        vec![
+            ("127.0.0.3:8080".to_string(), resolved.clone()),
+            ("::1:8080".to_string(), resolved),
+            ("127.0.0.1:9090".to_string(), None),
+        ]
+    );
+
+    // A name that stops existing drops its backends.
+    records.lock().unwrap().clear();
+    group
+        .run_due_resolve(next_run + Duration::from_secs(60))
+        .await;
+    assert_eq!(
+        backend_addresses(&group),
+        vec![("127.0.0.1:9090".to_string(), None)]
+    );
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_health_checks_resolved_addresses_separately() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let records = Arc::new(Mutex::new(HashMap::from([(
+        "app.test".to_string(),
+        vec![
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            "127.0.0.2".parse().unwrap(),
+        ],
+    )])));
+    let dns = stub_dns(records, 30).await;
+    let group = super::RuntimeUpstreamGroup::with_resolver(
+        &CompiledUpstreamGroup {
+            name: "backend".into(),
+            policy: UpstreamSelectionPolicy::RoundRobin,
+            servers: vec![CompiledUpstreamServer {
+                host: "app.test".into(),
+                port,
+                weight: 1,
+                resolve: true,
+                max_conns: None,
+                backup: false,
+                down: false,
+                slow_start: None,
+            }],
+            health_check: Some(CompiledHealthCheck {
+                check_type: HealthCheckType::Tcp,
+                port: None,
+                timeout: Duration::from_secs(1),
+                interval: Duration::from_secs(5),
+                consecutive_success: 1,
+                consecutive_failure: 1,
+            }),
+            outlier_detection: None,
+            keepalive: UpstreamKeepalive::default(),
+            queue: None,
+        },
+        Some(&DnsResolver {
+            addrs: vec![dns],
+            valid: Some(Duration::from_secs(10)),
+        }),
+    )
+    .expect("runtime group builds");
+
+    let now = tokio::time::Instant::now();
+    let next_run = group.run_due_resolve(now).await.expect("group resolves");
+    assert!(
+        next_run >= now + Duration::from_secs(10),
+        "`valid` overrides the TTL"
+    );
+    assert!(next_run < now + Duration::from_secs(20));
+    group.run_due_health_check(now).await;
+    let health: Vec<_> = group
+        .backend_health()
+        .into_iter()
+        .map(|status| (status.server.host, status.healthy))
+        .collect();
+    assert_eq!(
+        health,
+        vec![
+            ("127.0.0.1".to_string(), true),
+            ("127.0.0.2".to_string(), false)
+        ]
+    );
+    for _ in 0..3 {
+        assert_eq!(group.select(b"").expect("backend").server.host, "127.0.0.1");
+    }
+    drop(listener);
+}
+
+#[tokio::test]
+async fn runtime_upstream_group_adopts_previous_dns_answers() {
+    let records = Arc::new(Mutex::new(HashMap::from([(
+        "app.test".to_string(),
+        vec!["127.0.0.2".parse::<IpAddr>().unwrap()],
+    )])));
+    let dns = stub_dns(records, 30).await;
+    let previous = resolving_group(dns, None);
+    let now = tokio::time::Instant::now();
+    let next_run = previous.run_due_resolve(now).await;
+
+    let next = resolving_group(dns, None);
+    next.adopt_resolved(&previous);
+    assert_eq!(backend_addresses(&next), backend_addresses(&previous));
+    assert_eq!(next.run_due_resolve(now).await, next_run);
+}
+
 #[test]
 fn content_length_limit_exceeded_rejects_large_body() {
     let header = http::HeaderValue::from_static("10485761");
@@ -1195,6 +2231,183 @@ fn compiled_router_preserves_location_plugins() {
     assert_eq!(location.plugins, http.servers[0].locations[0].plugins);
 }
 
+fn return_server(location: &str) -> Http {
+    Http {
+        servers: vec![Server {
+            server_names: vec!["example.com".into(), "www.example.com".into()],
+            listens: vec![Listen::default()],
+            locations: vec![Location {
+                matcher: LocationMatcher::Prefix("/".into()),
+                directives: vec![LocationDirective::Return {
+                    status: 301,
+                    location: location.into(),
+                }],
+                access_rules: Vec::new(),
+                plugins: Vec::new(),
+                cache: None,
+            }],
+            ..Server::default()
+        }],
+        ..Http::default()
+    }
+}
+
+#[test]
+fn compiled_router_compiles_return_templates() {
+    let router = CompiledRouter::from_http(&return_server("https://$host$request_uri"))
+        .expect("router compiles");
+    let routes = &router
+        .listeners
+        .values()
+        .next()
+        .expect("listener present")
+        .named["www.example.com"];
+
+    assert_eq!(routes.server_name.as_deref(), Some("example.com"));
+    match &routes.locations[0].target {
+        RouteTarget::Return { status, location } => {
+            assert_eq!(*status, 301);
+            assert_eq!(location.source(), "https://$host$request_uri");
+            assert!(!location.is_static());
+        }
+        other => panic!("expected return target, got {other:?}"),
+    }
+}
+
+#[test]
+fn compiled_router_rejects_unknown_return_variable() {
+    let err = CompiledRouter::from_http(&return_server("https://$hostname/"))
+        .expect_err("unknown variable must be rejected");
+
+    assert_eq!(err, "return: unknown variable `$hostname`");
+}
+
+fn static_server(locations: Vec<Location>) -> Http {
+    Http {
+        servers: vec![Server {
+            listens: vec![Listen::default()],
+            locations,
+            ..Server::default()
+        }],
+        ..Http::default()
+    }
+}
+
+fn static_location(matcher: LocationMatcher, directives: Vec<LocationDirective>) -> Location {
+    Location {
+        matcher,
+        directives,
+        access_rules: Vec::new(),
+        plugins: Vec::new(),
+        cache: None,
+    }
+}
+
+#[test]
+fn compiled_router_compiles_static_file_locations() {
+    let http = static_server(vec![
+        static_location(
+            LocationMatcher::Prefix("/".into()),
+            vec![
+                LocationDirective::Root("/srv/www".into()),
+                LocationDirective::TryFiles {
+                    files: vec!["$uri".into(), "$uri/".into()],
+                    fallback: TryFilesFallback::Named("app".into()),
+                },
+            ],
+        ),
+        static_location(
+            LocationMatcher::Prefix("/assets/".into()),
+            vec![
+                LocationDirective::Alias("/srv/assets/".into()),
+                LocationDirective::Index(vec!["default.htm".into()]),
+            ],
+        ),
+        static_location(
+            LocationMatcher::Named("app".into()),
+            vec![LocationDirective::ProxyPass(ProxyPassTarget::Url(
+                "http://127.0.0.1:8080".parse().unwrap(),
+            ))],
+        ),
+    ]);
+
+    let router = CompiledRouter::from_http(&http).expect("router compiles");
+    let routes = router
+        .listeners
+        .values()
+        .next()
+        .expect("listener present")
+        .default
+        .as_ref()
+        .expect("default route present");
+
+    match &routes.locations[0].target {
+        RouteTarget::Static(files) => {
+            assert_eq!(files.root, StaticRoot::Root("/srv/www".into()));
+            assert_eq!(files.index, vec!["index.html".to_string()]);
+            let try_files = files.try_files.as_ref().expect("try_files compiled");
+            assert_eq!(try_files.files.len(), 2);
+            assert_eq!(try_files.fallback, StaticFallback::Named("app".into()));
+        }
+        other => panic!("expected static target, got {other:?}"),
+    }
+    match &routes.locations[1].target {
+        RouteTarget::Static(files) => {
+            assert_eq!(
+                files.root,
+                StaticRoot::Alias {
+                    path: "/srv/assets/".into(),
+                    prefix: "/assets/".into(),
+                }
+            );
+            assert_eq!(files.index, vec!["default.htm".to_string()]);
+        }
+        other => panic!("expected static target, got {other:?}"),
+    }
+}
+
+#[test]
+fn compiled_router_rejects_invalid_static_file_locations() {
+    let cases = [
+        (
+            static_location(
+                LocationMatcher::Prefix("/".into()),
+                vec![LocationDirective::Root("srv/www".into())],
+            ),
+            "root path `srv/www` must be absolute",
+        ),
+        (
+            static_location(
+                LocationMatcher::Regex {
+                    pattern: "\\.png$".into(),
+                    case_insensitive: false,
+                },
+                vec![LocationDirective::Alias("/srv/images/".into())],
+            ),
+            "alias is only supported in prefix and exact match locations",
+        ),
+        (
+            static_location(
+                LocationMatcher::Prefix("/".into()),
+                vec![
+                    LocationDirective::Root("/srv/www".into()),
+                    LocationDirective::TryFiles {
+                        files: vec!["$uri".into()],
+                        fallback: TryFilesFallback::Named("missing".into()),
+                    },
+                ],
+            ),
+            "try_files references unknown named location `@missing`",
+        ),
+    ];
+
+    for (location, expected) in cases {
+        let err = CompiledRouter::from_http(&static_server(vec![location]))
+            .expect_err("invalid static location must be rejected");
+        assert!(err.contains(expected), "{err}");
+    }
+}
+
 #[test]
 fn apply_upstream_timeouts_maps_zero_to_none() {
     let mut peer = HttpPeer::new(("127.0.0.1", 8080), false, String::new());
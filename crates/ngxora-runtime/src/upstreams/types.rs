@@ -1,13 +1,19 @@
+use ipnet::IpNet;
 use ngxora_compile::ir::{
-    DownstreamTlsOptions, LetsEncryptConfig, Listen, LocationIpRule, LocationMatcher, PemSource,
-    TlsIdentity, TlsProtocolBounds, TlsVerifyClient, UpstreamHttpProtocol, UpstreamSelectionPolicy,
+    AccessLog, AcmeIssuer, CacheStoreConfig, DnsResolver, DownstreamTlsOptions,
+    HealthCheckBodyMatch, Listen, LocationIpRule, LocationMatcher, LogFormat, PemSource, RequestId,
+    TlsIdentity, TlsProtocolBounds, TlsVerifyClient, UpstreamHttpProtocol, UpstreamKeepalive,
+    UpstreamOutlierDetection, UpstreamQueue, UpstreamRetry, UpstreamSelectionPolicy,
     UpstreamSslOptions, UpstreamTimeouts,
 };
+use ngxora_compile::variables::Template;
 use ngxora_plugin_api::PluginSpec;
 use regex::{Regex, RegexBuilder};
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::time::Duration;
 
 // ListenKey identifies one bound downstream socket after listen directives have
@@ -43,16 +49,54 @@ pub enum RouteTarget {
     },
     Return {
         status: u16,
-        location: String,
+        location: Template,
     },
+    Static(StaticFiles),
+}
+
+// StaticFiles serves a location from the local filesystem (`root`/`alias`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StaticFiles {
+    pub root: StaticRoot,
+    pub index: Vec<String>,
+    pub try_files: Option<StaticTryFiles>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StaticRoot {
+    /// `root`: the full request path is appended to `path`.
+    Root(PathBuf),
+    /// `alias`: `prefix` (the location path) is replaced with `path`.
+    Alias { path: PathBuf, prefix: String },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StaticTryFiles {
+    pub files: Vec<Template>,
+    pub fallback: StaticFallback,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StaticFallback {
+    Status(u16),
+    Named(String),
+    Uri(Template),
 }
 
 // CompiledUpstreamServer is a backend endpoint already validated during
 // snapshot build.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct CompiledUpstreamServer {
     pub host: String,
     pub port: u16,
+    pub weight: u32,
+    /// Re-resolved at runtime; see `server ... resolve;`.
+    pub resolve: bool,
+    /// In-flight request limit; `None` is unlimited.
+    pub max_conns: Option<usize>,
+    pub backup: bool,
+    pub down: bool,
+    pub slow_start: Option<Duration>,
 }
 
 impl Display for CompiledUpstreamServer {
@@ -68,12 +112,24 @@ pub enum HealthCheckType {
         host: String,
         path: String,
         use_tls: bool,
+        method: String,
+        headers: Vec<(String, String)>,
+        statuses: Vec<RangeInclusive<u16>>,
+        body: Option<HealthCheckBodyMatch>,
+    },
+    // grpc.health.v1.Health/Check over h2 (or h2c without TLS).
+    Grpc {
+        service: String,
+        host: Option<String>,
+        use_tls: bool,
     },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CompiledHealthCheck {
     pub check_type: HealthCheckType,
+    // Probe this port instead of each server's traffic port.
+    pub port: Option<u16>,
     pub timeout: Duration,
     pub interval: Duration,
     pub consecutive_success: usize,
@@ -86,6 +142,9 @@ pub struct CompiledUpstreamGroup {
     pub policy: UpstreamSelectionPolicy,
     pub servers: Vec<CompiledUpstreamServer>,
     pub health_check: Option<CompiledHealthCheck>,
+    pub outlier_detection: Option<UpstreamOutlierDetection>,
+    pub keepalive: UpstreamKeepalive,
+    pub queue: Option<UpstreamQueue>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -133,6 +192,20 @@ impl CompiledRegex {
     }
 }
 
+// Written the way the location is declared, e.g. `^~ /static/` or `@fallback`.
+impl Display for CompiledMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prefix(path) => f.write_str(path),
+            Self::Exact(path) => write!(f, "= {path}"),
+            Self::Regex(regex) if regex.case_insensitive => write!(f, "~* {}", regex.pattern),
+            Self::Regex(regex) => write!(f, "~ {}", regex.pattern),
+            Self::PreferPrefix(path) => write!(f, "^~ {path}"),
+            Self::Named(name) => write!(f, "@{name}"),
+        }
+    }
+}
+
 impl TryFrom<&LocationMatcher> for CompiledMatcher {
     type Error = String;
 
@@ -158,14 +231,20 @@ use ngxora_compile::ir::CacheConfig;
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CompiledLocation {
     pub route_id: u64,
+    /// `route_name`, reported instead of the matcher in metrics.
+    pub name: Option<String>,
     pub matcher: CompiledMatcher,
     pub access_rules: Vec<LocationIpRule>,
     pub target: RouteTarget,
     pub upstream_timeouts: UpstreamTimeouts,
+    pub upstream_retry: UpstreamRetry,
     pub upstream_protocol: Option<UpstreamHttpProtocol>,
     pub upstream_ssl_options: UpstreamSslOptions,
     pub plugins: Vec<PluginSpec>,
     pub cache: Option<CacheConfig>,
+    /// `access_log` of the location, or of its server; empty uses the
+    /// http-level logs.
+    pub access_logs: Vec<AccessLog>,
 }
 
 impl CompiledLocation {
@@ -181,7 +260,11 @@ impl CompiledLocation {
 // compilation.
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
 pub struct ServerRoutes {
+    /// First `server_name` of the server block, exposed as `$server_name`.
+    pub server_name: Option<String>,
     pub locations: Vec<CompiledLocation>,
+    /// `request_id` of the server block; `None` leaves request IDs alone.
+    pub request_id: Option<RequestId>,
 }
 
 // VirtualHostRoutes groups named and default virtual servers for one listener.
@@ -196,6 +279,8 @@ pub struct VirtualHostRoutes {
 pub struct ListenerProtocolConfig {
     pub http2: bool,
     pub http2_only: bool,
+    pub proxy_protocol: bool,
+    pub proxy_protocol_trusted: Vec<IpNet>,
 }
 
 // ListenerTlsSettings holds listener-level TLS policy that affects socket
@@ -224,9 +309,16 @@ pub struct HttpRuntimeOptions {
     pub keepalive_requests: Option<u32>,
     pub client_max_body_size: Option<u64>,
     pub proxy_cache_max_size: Option<u64>,
+    /// Read once at startup; changing it requires a restart.
+    pub proxy_cache_store: CacheStoreConfig,
     pub tcp_nodelay: bool,
     pub allow_connect_method_proxying: bool,
     pub h2c: bool,
+    pub resolver: Option<DnsResolver>,
+    pub metrics_label_limit: Option<usize>,
+    pub log_formats: Vec<LogFormat>,
+    /// http-level `access_log`; empty logs to stdout in the `default` format.
+    pub access_logs: Vec<AccessLog>,
 }
 
 // CompiledRouter is the immutable routing model consumed by the dataplane at
@@ -238,8 +330,11 @@ pub struct CompiledRouter {
     pub listener_protocols: HashMap<ListenKey, ListenerProtocolConfig>,
     pub listener_tls: HashMap<ListenKey, ListenerTlsConfig>,
     pub http_options: HttpRuntimeOptions,
-    /// Global Let's Encrypt configuration from `ssl_provider letsencrypt { ... }`.
-    pub le_config: Option<LetsEncryptConfig>,
+    /// ACME issuers from `ssl_provider` blocks, in declaration order.
+    pub acme_issuers: Vec<AcmeIssuer>,
+    /// ACME-managed certificates by lowercased domain, with the issuers to
+    /// try in order.
+    pub acme_certificates: HashMap<String, Vec<String>>,
 }
 
 // Alias to keep compatibility with the misspelled name used in discussion.